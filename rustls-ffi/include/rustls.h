@@ -0,0 +1,97 @@
+/*
+ * C declarations for the `rustls-ffi` crate.
+ *
+ * See the documentation of `rustls_ffi` for the conventions these
+ * functions follow.  Keep this in sync with rustls-ffi/src/lib.rs.
+ */
+#ifndef RUSTLS_H
+#define RUSTLS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef uint32_t rustls_result;
+
+#define RUSTLS_RESULT_OK 0
+#define RUSTLS_RESULT_NULL_PARAMETER 1
+#define RUSTLS_RESULT_INVALID_PARAMETER 2
+#define RUSTLS_RESULT_PLAINTEXT_EMPTY 3
+#define RUSTLS_RESULT_UNEXPECTED_EOF 4
+#define RUSTLS_RESULT_IO 5
+#define RUSTLS_RESULT_PANIC 6
+
+#define RUSTLS_RESULT_GENERAL 100
+#define RUSTLS_RESULT_UNEXPECTED_MESSAGE 101
+#define RUSTLS_RESULT_INVALID_MESSAGE 102
+#define RUSTLS_RESULT_NO_CERTIFICATES_PRESENTED 103
+#define RUSTLS_RESULT_DECRYPT_ERROR 104
+#define RUSTLS_RESULT_PEER_INCOMPATIBLE 105
+#define RUSTLS_RESULT_PEER_MISBEHAVED 106
+#define RUSTLS_RESULT_ALERT_RECEIVED 107
+#define RUSTLS_RESULT_INVALID_CERTIFICATE 108
+#define RUSTLS_RESULT_NO_APPLICATION_PROTOCOL 109
+
+typedef struct rustls_client_config_builder rustls_client_config_builder;
+typedef struct rustls_client_config rustls_client_config;
+typedef struct rustls_server_config_builder rustls_server_config_builder;
+typedef struct rustls_server_config rustls_server_config;
+typedef struct rustls_connection rustls_connection;
+
+void rustls_result_description(uint32_t result, char *buf, size_t len, size_t *out_n);
+
+rustls_client_config_builder *rustls_client_config_builder_new(void);
+rustls_result rustls_client_config_builder_add_root_der(rustls_client_config_builder *builder,
+                                                        const uint8_t *der, size_t len);
+rustls_result rustls_client_config_builder_add_alpn_protocol(rustls_client_config_builder *builder,
+                                                             const uint8_t *protocol, size_t len);
+rustls_result rustls_client_config_builder_build(rustls_client_config_builder *builder,
+                                                 const rustls_client_config **out);
+void rustls_client_config_builder_free(rustls_client_config_builder *builder);
+void rustls_client_config_free(const rustls_client_config *config);
+
+rustls_server_config_builder *rustls_server_config_builder_new(void);
+rustls_result rustls_server_config_builder_add_certificate_der(rustls_server_config_builder *builder,
+                                                               const uint8_t *der, size_t len);
+rustls_result rustls_server_config_builder_set_private_key_der(rustls_server_config_builder *builder,
+                                                               const uint8_t *der, size_t len);
+rustls_result rustls_server_config_builder_add_alpn_protocol(rustls_server_config_builder *builder,
+                                                             const uint8_t *protocol, size_t len);
+rustls_result rustls_server_config_builder_build(rustls_server_config_builder *builder,
+                                                 const rustls_server_config **out);
+void rustls_server_config_builder_free(rustls_server_config_builder *builder);
+void rustls_server_config_free(const rustls_server_config *config);
+
+rustls_result rustls_client_connection_new(const rustls_client_config *config,
+                                           const char *server_name, rustls_connection **out);
+rustls_result rustls_server_connection_new(const rustls_server_config *config,
+                                           rustls_connection **out);
+
+rustls_result rustls_connection_read_tls(rustls_connection *conn, const uint8_t *buf, size_t len,
+                                         size_t *out_n);
+rustls_result rustls_connection_write_tls(rustls_connection *conn, uint8_t *buf, size_t len,
+                                          size_t *out_n);
+rustls_result rustls_connection_process_new_packets(rustls_connection *conn);
+bool rustls_connection_wants_read(const rustls_connection *conn);
+bool rustls_connection_wants_write(const rustls_connection *conn);
+bool rustls_connection_is_handshaking(const rustls_connection *conn);
+rustls_result rustls_connection_read(rustls_connection *conn, uint8_t *buf, size_t len,
+                                     size_t *out_n);
+rustls_result rustls_connection_write(rustls_connection *conn, const uint8_t *buf, size_t len,
+                                      size_t *out_n);
+void rustls_connection_send_close_notify(rustls_connection *conn);
+rustls_result rustls_connection_get_alpn_protocol(const rustls_connection *conn,
+                                                  const uint8_t **out_protocol, size_t *out_len);
+void rustls_connection_last_error(const rustls_connection *conn, char *buf, size_t len,
+                                  size_t *out_n);
+void rustls_connection_free(rustls_connection *conn);
+
+#ifdef __cplusplus
+} /* extern "C" */
+#endif
+
+#endif /* RUSTLS_H */
@@ -0,0 +1,1015 @@
+//! A C ABI for embedding rustls in C programs.
+//!
+//! This covers building client and server configurations, driving a
+//! connection over caller-provided buffers, and reporting errors.  It
+//! always uses the default [`Ring`] crypto provider.  The matching C
+//! declarations are in `include/rustls.h` in the rustls source
+//! distribution.
+//!
+//! This crate builds as a `staticlib` and a `cdylib` to link against.  It
+//! is kept apart from rustls itself so that rustls can forbid `unsafe`
+//! code.
+//!
+//! # Conventions
+//!
+//! - Functions that can fail return a [`rustls_result`], and write any
+//!   other output through pointer arguments.  Output is only written when
+//!   the result is `RUSTLS_RESULT_OK`.
+//! - Objects returned through `out` arguments are owned by the caller, and
+//!   must be released with the matching `_free` function.  Passing NULL to
+//!   a `_free` function does nothing.
+//! - Byte buffers are given as a pointer and a length.  The pointer may be
+//!   NULL only if the length is zero.
+//! - A configuration may be shared between threads and connections, and
+//!   may be freed while connections made from it are still in use.  Other
+//!   objects must not be used from more than one thread at a time.
+//! - Passing a pointer that was not obtained from this module, or that
+//!   has already been freed, is undefined behaviour.
+//!
+//! A panic inside rustls is reported as `RUSTLS_RESULT_PANIC` rather than
+//! unwinding into the caller; the object involved should not be used
+//! afterwards.
+//!
+//! [`Ring`]: rustls::crypto::ring::Ring
+#![allow(non_camel_case_types, clippy::missing_safety_doc)]
+
+use rustls::client::{ClientConfig, ClientConnection, ServerName};
+use rustls::crypto::ring::Ring;
+use rustls::server::{ServerConfig, ServerConnection};
+use rustls::{Certificate, Connection, Error, PrivateKey, RootCertStore};
+
+use std::ffi::CStr;
+use std::io::{self, Read, Write};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::{ptr, slice};
+
+/// The outcome of a call.
+///
+/// Values below 100 describe how the API was used; values from 100
+/// describe TLS errors.  New values may be added in future.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum rustls_result {
+    /// The call succeeded.
+    Ok = 0,
+    /// A required pointer argument was NULL.
+    NullParameter = 1,
+    /// An argument was invalid, for example a malformed certificate.
+    InvalidParameter = 2,
+    /// There is no plaintext available to read yet.
+    PlaintextEmpty = 3,
+    /// The peer closed the connection without sending `close_notify`.
+    UnexpectedEof = 4,
+    /// A buffer was full, or some other I/O condition prevented progress.
+    Io = 5,
+    /// rustls panicked.
+    Panic = 6,
+
+    /// The connection failed with an error not covered by a more specific value.
+    General = 100,
+    /// The peer sent a message that is not allowed at this point.
+    UnexpectedMessage = 101,
+    /// The peer sent a message that could not be decoded.
+    InvalidMessage = 102,
+    /// The peer did not present a certificate.
+    NoCertificatesPresented = 103,
+    /// A received record could not be decrypted.
+    DecryptError = 104,
+    /// The peer does not support anything we require.
+    PeerIncompatible = 105,
+    /// The peer deviated from the standard.
+    PeerMisbehaved = 106,
+    /// The peer sent a fatal alert.
+    AlertReceived = 107,
+    /// The peer's certificate was not accepted.
+    InvalidCertificate = 108,
+    /// No application protocol could be agreed with ALPN.
+    NoApplicationProtocol = 109,
+}
+
+impl rustls_result {
+    fn from_u32(value: u32) -> Option<Self> {
+        let result = match value {
+            0 => Self::Ok,
+            1 => Self::NullParameter,
+            2 => Self::InvalidParameter,
+            3 => Self::PlaintextEmpty,
+            4 => Self::UnexpectedEof,
+            5 => Self::Io,
+            6 => Self::Panic,
+            100 => Self::General,
+            101 => Self::UnexpectedMessage,
+            102 => Self::InvalidMessage,
+            103 => Self::NoCertificatesPresented,
+            104 => Self::DecryptError,
+            105 => Self::PeerIncompatible,
+            106 => Self::PeerMisbehaved,
+            107 => Self::AlertReceived,
+            108 => Self::InvalidCertificate,
+            109 => Self::NoApplicationProtocol,
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Ok => "success",
+            Self::NullParameter => "a required parameter was NULL",
+            Self::InvalidParameter => "a parameter was invalid",
+            Self::PlaintextEmpty => "no plaintext available",
+            Self::UnexpectedEof => "peer closed connection without sending close_notify",
+            Self::Io => "I/O error",
+            Self::Panic => "rustls panicked",
+            Self::General => "TLS error",
+            Self::UnexpectedMessage => "received unexpected message",
+            Self::InvalidMessage => "received corrupt message",
+            Self::NoCertificatesPresented => "peer sent no certificates",
+            Self::DecryptError => "cannot decrypt peer's message",
+            Self::PeerIncompatible => "peer is incompatible",
+            Self::PeerMisbehaved => "peer misbehaved",
+            Self::AlertReceived => "received fatal alert",
+            Self::InvalidCertificate => "invalid peer certificate",
+            Self::NoApplicationProtocol => "no application protocol",
+        }
+    }
+}
+
+impl From<&Error> for rustls_result {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::InappropriateMessage { .. } | Error::InappropriateHandshakeMessage { .. } => {
+                Self::UnexpectedMessage
+            }
+            Error::InvalidMessage(_) => Self::InvalidMessage,
+            Error::NoCertificatesPresented => Self::NoCertificatesPresented,
+            Error::DecryptError => Self::DecryptError,
+            Error::PeerIncompatible(_) => Self::PeerIncompatible,
+            Error::PeerMisbehaved(_) => Self::PeerMisbehaved,
+            Error::AlertReceived(_) => Self::AlertReceived,
+            Error::InvalidCertificate(_) => Self::InvalidCertificate,
+            Error::NoApplicationProtocol => Self::NoApplicationProtocol,
+            _ => Self::General,
+        }
+    }
+}
+
+/// Write a NUL-terminated description of `result` into `buf`.
+///
+/// At most `len` bytes are written, including the terminator; the
+/// description is truncated if necessary.  The number of bytes written,
+/// excluding the terminator, is stored in `out_n`.  Unknown values are
+/// described as such.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_result_description(
+    result: u32,
+    buf: *mut c_char,
+    len: usize,
+    out_n: *mut usize,
+) {
+    let description = rustls_result::from_u32(result)
+        .map(rustls_result::description)
+        .unwrap_or("unknown result");
+    write_c_string(description, buf, len, out_n);
+}
+
+/// Collects the parts of a client configuration.
+pub struct rustls_client_config_builder {
+    roots: RootCertStore,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// An immutable client configuration, shared between connections.
+pub struct rustls_client_config(Arc<ClientConfig<Ring>>);
+
+/// Start building a client configuration that trusts no certificates.
+///
+/// Add trust anchors with [`rustls_client_config_builder_add_root_der`].
+#[no_mangle]
+pub extern "C" fn rustls_client_config_builder_new() -> *mut rustls_client_config_builder {
+    Box::into_raw(Box::new(rustls_client_config_builder {
+        roots: RootCertStore::empty(),
+        alpn_protocols: Vec::new(),
+    }))
+}
+
+/// Trust the DER-encoded certificate `der` as a root.
+///
+/// Returns `RUSTLS_RESULT_INVALID_PARAMETER` if the certificate cannot be parsed.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_builder_add_root_der(
+    builder: *mut rustls_client_config_builder,
+    der: *const u8,
+    len: usize,
+) -> rustls_result {
+    guard(|| {
+        let (builder, der) = match (builder.as_mut(), bytes(der, len)) {
+            (Some(builder), Some(der)) => (builder, der),
+            _ => return rustls_result::NullParameter,
+        };
+        match builder
+            .roots
+            .add(&Certificate(der.to_vec()))
+        {
+            Ok(()) => rustls_result::Ok,
+            Err(_) => rustls_result::InvalidParameter,
+        }
+    })
+}
+
+/// Offer the ALPN protocol `protocol`, after any already added.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_builder_add_alpn_protocol(
+    builder: *mut rustls_client_config_builder,
+    protocol: *const u8,
+    len: usize,
+) -> rustls_result {
+    guard(|| match (builder.as_mut(), bytes(protocol, len)) {
+        (Some(_), Some([])) => rustls_result::InvalidParameter,
+        (Some(builder), Some(protocol)) => {
+            builder
+                .alpn_protocols
+                .push(protocol.to_vec());
+            rustls_result::Ok
+        }
+        _ => rustls_result::NullParameter,
+    })
+}
+
+/// Finish building a client configuration, storing it in `out`.
+///
+/// This frees `builder`, whether or not it succeeds.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_builder_build(
+    builder: *mut rustls_client_config_builder,
+    out: *mut *const rustls_client_config,
+) -> rustls_result {
+    guard(|| {
+        if builder.is_null() {
+            return rustls_result::NullParameter;
+        }
+        let builder = Box::from_raw(builder);
+        if out.is_null() {
+            return rustls_result::NullParameter;
+        }
+
+        let mut config = ClientConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_root_certificates(builder.roots)
+            .with_no_client_auth();
+        config.alpn_protocols = builder.alpn_protocols;
+        *out = Box::into_raw(Box::new(rustls_client_config(Arc::new(config))));
+        rustls_result::Ok
+    })
+}
+
+/// Free a client configuration builder without building it.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_builder_free(
+    builder: *mut rustls_client_config_builder,
+) {
+    if !builder.is_null() {
+        drop(Box::from_raw(builder));
+    }
+}
+
+/// Free a client configuration.
+///
+/// Connections made from it remain usable.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_free(config: *const rustls_client_config) {
+    if !config.is_null() {
+        drop(Box::from_raw(config as *mut rustls_client_config));
+    }
+}
+
+/// Collects the parts of a server configuration.
+pub struct rustls_server_config_builder {
+    cert_chain: Vec<Certificate>,
+    private_key: Option<PrivateKey>,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// An immutable server configuration, shared between connections.
+pub struct rustls_server_config(Arc<ServerConfig<Ring>>);
+
+/// Start building a server configuration.
+///
+/// A certificate chain and private key must be added before it is built.
+#[no_mangle]
+pub extern "C" fn rustls_server_config_builder_new() -> *mut rustls_server_config_builder {
+    Box::into_raw(Box::new(rustls_server_config_builder {
+        cert_chain: Vec::new(),
+        private_key: None,
+        alpn_protocols: Vec::new(),
+    }))
+}
+
+/// Append the DER-encoded certificate `der` to the server's certificate
+/// chain.
+///
+/// The end-entity certificate must be added first, followed by any
+/// intermediates.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_add_certificate_der(
+    builder: *mut rustls_server_config_builder,
+    der: *const u8,
+    len: usize,
+) -> rustls_result {
+    guard(|| match (builder.as_mut(), bytes(der, len)) {
+        (Some(builder), Some(der)) => {
+            builder
+                .cert_chain
+                .push(Certificate(der.to_vec()));
+            rustls_result::Ok
+        }
+        _ => rustls_result::NullParameter,
+    })
+}
+
+/// Set the private key for the server's end-entity certificate.
+///
+/// `der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.  It is
+/// checked when the configuration is built.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_set_private_key_der(
+    builder: *mut rustls_server_config_builder,
+    der: *const u8,
+    len: usize,
+) -> rustls_result {
+    guard(|| match (builder.as_mut(), bytes(der, len)) {
+        (Some(builder), Some(der)) => {
+            builder.private_key = Some(PrivateKey(der.to_vec()));
+            rustls_result::Ok
+        }
+        _ => rustls_result::NullParameter,
+    })
+}
+
+/// Support the ALPN protocol `protocol`, in lower preference than any
+/// already added.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_add_alpn_protocol(
+    builder: *mut rustls_server_config_builder,
+    protocol: *const u8,
+    len: usize,
+) -> rustls_result {
+    guard(|| match (builder.as_mut(), bytes(protocol, len)) {
+        (Some(_), Some([])) => rustls_result::InvalidParameter,
+        (Some(builder), Some(protocol)) => {
+            builder
+                .alpn_protocols
+                .push(protocol.to_vec());
+            rustls_result::Ok
+        }
+        _ => rustls_result::NullParameter,
+    })
+}
+
+/// Finish building a server configuration, storing it in `out`.
+///
+/// Returns `RUSTLS_RESULT_INVALID_PARAMETER` if no certificate or private
+/// key was given, or the private key is invalid.  This frees `builder`,
+/// whether or not it succeeds.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_build(
+    builder: *mut rustls_server_config_builder,
+    out: *mut *const rustls_server_config,
+) -> rustls_result {
+    guard(|| {
+        if builder.is_null() {
+            return rustls_result::NullParameter;
+        }
+        let builder = Box::from_raw(builder);
+        if out.is_null() {
+            return rustls_result::NullParameter;
+        }
+
+        let private_key = match builder.private_key {
+            Some(private_key) if !builder.cert_chain.is_empty() => private_key,
+            _ => return rustls_result::InvalidParameter,
+        };
+        let mut config = match ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(builder.cert_chain, private_key)
+        {
+            Ok(config) => config,
+            Err(_) => return rustls_result::InvalidParameter,
+        };
+        config.alpn_protocols = builder.alpn_protocols;
+        *out = Box::into_raw(Box::new(rustls_server_config(Arc::new(config))));
+        rustls_result::Ok
+    })
+}
+
+/// Free a server configuration builder without building it.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_free(
+    builder: *mut rustls_server_config_builder,
+) {
+    if !builder.is_null() {
+        drop(Box::from_raw(builder));
+    }
+}
+
+/// Free a server configuration.
+///
+/// Connections made from it remain usable.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_free(config: *const rustls_server_config) {
+    if !config.is_null() {
+        drop(Box::from_raw(config as *mut rustls_server_config));
+    }
+}
+
+/// A client or server connection.
+pub struct rustls_connection {
+    conn: Connection,
+    last_error: Option<Error>,
+}
+
+impl rustls_connection {
+    fn new(conn: impl Into<Connection>) -> *mut Self {
+        Box::into_raw(Box::new(Self {
+            conn: conn.into(),
+            last_error: None,
+        }))
+    }
+}
+
+/// Make a client connection to `server_name`, storing it in `out`.
+///
+/// `server_name` is a NUL-terminated DNS name or IP address.  Returns
+/// `RUSTLS_RESULT_INVALID_PARAMETER` if it is neither.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_connection_new(
+    config: *const rustls_client_config,
+    server_name: *const c_char,
+    out: *mut *mut rustls_connection,
+) -> rustls_result {
+    guard(|| {
+        let config = match config.as_ref() {
+            Some(config) if !server_name.is_null() && !out.is_null() => config,
+            _ => return rustls_result::NullParameter,
+        };
+        let server_name = match CStr::from_ptr(server_name)
+            .to_str()
+            .ok()
+            .and_then(|name| ServerName::try_from(name).ok())
+        {
+            Some(server_name) => server_name,
+            None => return rustls_result::InvalidParameter,
+        };
+
+        match ClientConnection::new(Arc::clone(&config.0), server_name) {
+            Ok(conn) => {
+                *out = rustls_connection::new(conn);
+                rustls_result::Ok
+            }
+            Err(err) => rustls_result::from(&err),
+        }
+    })
+}
+
+/// Make a server connection, storing it in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_connection_new(
+    config: *const rustls_server_config,
+    out: *mut *mut rustls_connection,
+) -> rustls_result {
+    guard(|| {
+        let config = match config.as_ref() {
+            Some(config) if !out.is_null() => config,
+            _ => return rustls_result::NullParameter,
+        };
+
+        match ServerConnection::new(Arc::clone(&config.0)) {
+            Ok(conn) => {
+                *out = rustls_connection::new(conn);
+                rustls_result::Ok
+            }
+            Err(err) => rustls_result::from(&err),
+        }
+    })
+}
+
+/// Give the connection up to `len` bytes of TLS data received from the peer.
+///
+/// The number of bytes accepted is stored in `out_n`.  Returns
+/// `RUSTLS_RESULT_IO` if the connection cannot accept any more data until
+/// the plaintext it has received is read.  Follow this with
+/// [`rustls_connection_process_new_packets`].
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_read_tls(
+    conn: *mut rustls_connection,
+    buf: *const u8,
+    len: usize,
+    out_n: *mut usize,
+) -> rustls_result {
+    guard(|| {
+        let (conn, mut buf) = match (conn.as_mut(), bytes(buf, len)) {
+            (Some(conn), Some(buf)) if !out_n.is_null() => (conn, buf),
+            _ => return rustls_result::NullParameter,
+        };
+        match conn.conn.read_tls(&mut buf) {
+            Ok(n) => {
+                *out_n = n;
+                rustls_result::Ok
+            }
+            Err(_) => rustls_result::Io,
+        }
+    })
+}
+
+/// Copy up to `len` bytes of TLS data to send to the peer into `buf`.
+///
+/// The number of bytes written is stored in `out_n`.
+/// [`rustls_connection_wants_write`] says whether there is any to send.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_write_tls(
+    conn: *mut rustls_connection,
+    buf: *mut u8,
+    len: usize,
+    out_n: *mut usize,
+) -> rustls_result {
+    guard(|| {
+        let (conn, mut buf) = match (conn.as_mut(), bytes_mut(buf, len)) {
+            (Some(conn), Some(buf)) if !out_n.is_null() => (conn, buf),
+            _ => return rustls_result::NullParameter,
+        };
+        match conn.conn.write_tls(&mut buf) {
+            Ok(n) => {
+                *out_n = n;
+                rustls_result::Ok
+            }
+            Err(_) => rustls_result::Io,
+        }
+    })
+}
+
+/// Process the TLS data given by [`rustls_connection_read_tls`].
+///
+/// On failure the connection is unusable, and the error is described by
+/// [`rustls_connection_last_error`].  Any TLS data it wants to send,
+/// such as an alert, should still be written to the peer.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_process_new_packets(
+    conn: *mut rustls_connection,
+) -> rustls_result {
+    guard(|| {
+        let conn = match conn.as_mut() {
+            Some(conn) => conn,
+            None => return rustls_result::NullParameter,
+        };
+        match conn.conn.process_new_packets() {
+            Ok(_) => rustls_result::Ok,
+            Err(err) => {
+                let result = rustls_result::from(&err);
+                conn.last_error = Some(err);
+                result
+            }
+        }
+    })
+}
+
+/// Whether the connection wants TLS data from the peer.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_wants_read(conn: *const rustls_connection) -> bool {
+    match conn.as_ref() {
+        Some(conn) => conn.conn.wants_read(),
+        None => false,
+    }
+}
+
+/// Whether the connection has TLS data to send to the peer.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_wants_write(conn: *const rustls_connection) -> bool {
+    match conn.as_ref() {
+        Some(conn) => conn.conn.wants_write(),
+        None => false,
+    }
+}
+
+/// Whether the handshake is still in progress.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_is_handshaking(conn: *const rustls_connection) -> bool {
+    match conn.as_ref() {
+        Some(conn) => conn.conn.is_handshaking(),
+        None => false,
+    }
+}
+
+/// Read up to `len` bytes of plaintext received from the peer into `buf`.
+///
+/// The number of bytes read is stored in `out_n`; zero means the peer
+/// cleanly closed the connection.  Returns `RUSTLS_RESULT_PLAINTEXT_EMPTY`
+/// if no plaintext is available yet, and `RUSTLS_RESULT_UNEXPECTED_EOF`
+/// if the peer closed the connection without sending `close_notify`.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_read(
+    conn: *mut rustls_connection,
+    buf: *mut u8,
+    len: usize,
+    out_n: *mut usize,
+) -> rustls_result {
+    guard(|| {
+        let (conn, buf) = match (conn.as_mut(), bytes_mut(buf, len)) {
+            (Some(conn), Some(buf)) if !out_n.is_null() => (conn, buf),
+            _ => return rustls_result::NullParameter,
+        };
+        match conn.conn.reader().read(buf) {
+            Ok(n) => {
+                *out_n = n;
+                rustls_result::Ok
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => rustls_result::PlaintextEmpty,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => rustls_result::UnexpectedEof,
+            Err(_) => rustls_result::Io,
+        }
+    })
+}
+
+/// Give the connection up to `len` bytes of plaintext to send to the peer.
+///
+/// The number of bytes accepted is stored in `out_n`.  Plaintext written
+/// during the handshake is sent once it completes.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_write(
+    conn: *mut rustls_connection,
+    buf: *const u8,
+    len: usize,
+    out_n: *mut usize,
+) -> rustls_result {
+    guard(|| {
+        let (conn, buf) = match (conn.as_mut(), bytes(buf, len)) {
+            (Some(conn), Some(buf)) if !out_n.is_null() => (conn, buf),
+            _ => return rustls_result::NullParameter,
+        };
+        match conn.conn.writer().write(buf) {
+            Ok(n) => {
+                *out_n = n;
+                rustls_result::Ok
+            }
+            Err(_) => rustls_result::Io,
+        }
+    })
+}
+
+/// Queue a `close_notify` alert, telling the peer no more plaintext will
+/// be sent.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_send_close_notify(conn: *mut rustls_connection) {
+    if let Some(conn) = conn.as_mut() {
+        conn.conn.send_close_notify();
+    }
+}
+
+/// Get the negotiated ALPN protocol.
+///
+/// `out_protocol` is set to point at the protocol, which remains valid
+/// until the connection is freed, and `out_len` to its length.  If no
+/// protocol was negotiated `out_protocol` is set to NULL.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_get_alpn_protocol(
+    conn: *const rustls_connection,
+    out_protocol: *mut *const u8,
+    out_len: *mut usize,
+) -> rustls_result {
+    guard(|| {
+        let conn = match conn.as_ref() {
+            Some(conn) if !out_protocol.is_null() && !out_len.is_null() => conn,
+            _ => return rustls_result::NullParameter,
+        };
+        match conn.conn.alpn_protocol() {
+            Some(protocol) => {
+                *out_protocol = protocol.as_ptr();
+                *out_len = protocol.len();
+            }
+            None => {
+                *out_protocol = ptr::null();
+                *out_len = 0;
+            }
+        }
+        rustls_result::Ok
+    })
+}
+
+/// Write a NUL-terminated description of the error that made
+/// [`rustls_connection_process_new_packets`] fail into `buf`.
+///
+/// This behaves like [`rustls_result_description`], and writes an empty
+/// string if there has been no error.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_last_error(
+    conn: *const rustls_connection,
+    buf: *mut c_char,
+    len: usize,
+    out_n: *mut usize,
+) {
+    let description = conn
+        .as_ref()
+        .and_then(|conn| conn.last_error.as_ref())
+        .map(|err| err.to_string())
+        .unwrap_or_default();
+    write_c_string(&description, buf, len, out_n);
+}
+
+/// Free a connection.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_free(conn: *mut rustls_connection) {
+    if !conn.is_null() {
+        drop(Box::from_raw(conn));
+    }
+}
+
+/// Run `f`, turning any panic into `rustls_result::Panic`.
+fn guard(f: impl FnOnce() -> rustls_result) -> rustls_result {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(rustls_result::Panic)
+}
+
+/// Borrow `len` bytes at `ptr`, which may only be NULL if `len` is zero.
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+/// Mutably borrow `len` bytes at `ptr`, which may only be NULL if `len` is zero.
+unsafe fn bytes_mut<'a>(ptr: *mut u8, len: usize) -> Option<&'a mut [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&mut []),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts_mut(ptr, len)),
+    }
+}
+
+/// Copy as much of `s` as fits into `buf` with a NUL terminator.
+unsafe fn write_c_string(s: &str, buf: *mut c_char, len: usize, out_n: *mut usize) {
+    let n = match len.checked_sub(1) {
+        Some(max) if !buf.is_null() => s.len().min(max),
+        _ => 0,
+    };
+    if !buf.is_null() && len > 0 {
+        ptr::copy_nonoverlapping(s.as_ptr(), buf as *mut u8, n);
+        *buf.add(n) = 0;
+    }
+    if !out_n.is_null() {
+        *out_n = n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_config(alpn: &[u8]) -> *const rustls_client_config {
+        let builder = rustls_client_config_builder_new();
+        let ca = include_bytes!("../../test-ca/rsa/ca.der");
+        let mut config = ptr::null();
+        unsafe {
+            assert_eq!(
+                rustls_client_config_builder_add_root_der(builder, ca.as_ptr(), ca.len()),
+                rustls_result::Ok
+            );
+            assert_eq!(
+                rustls_client_config_builder_add_alpn_protocol(builder, alpn.as_ptr(), alpn.len()),
+                rustls_result::Ok
+            );
+            assert_eq!(
+                rustls_client_config_builder_build(builder, &mut config),
+                rustls_result::Ok
+            );
+        }
+        config
+    }
+
+    fn server_config(alpn: &[u8]) -> *const rustls_server_config {
+        let builder = rustls_server_config_builder_new();
+        let mut config = ptr::null();
+        unsafe {
+            for cert in pem_certs(include_str!("../../test-ca/rsa/end.fullchain")) {
+                assert_eq!(
+                    rustls_server_config_builder_add_certificate_der(
+                        builder,
+                        cert.as_ptr(),
+                        cert.len()
+                    ),
+                    rustls_result::Ok
+                );
+            }
+            let key = pem_key(include_str!("../../test-ca/rsa/end.key"));
+            assert_eq!(
+                rustls_server_config_builder_set_private_key_der(builder, key.as_ptr(), key.len()),
+                rustls_result::Ok
+            );
+            assert_eq!(
+                rustls_server_config_builder_add_alpn_protocol(builder, alpn.as_ptr(), alpn.len()),
+                rustls_result::Ok
+            );
+            assert_eq!(
+                rustls_server_config_builder_build(builder, &mut config),
+                rustls_result::Ok
+            );
+        }
+        config
+    }
+
+    fn pem_certs(pem: &str) -> Vec<Vec<u8>> {
+        rustls_pemfile::certs(&mut pem.as_bytes()).unwrap()
+    }
+
+    fn pem_key(pem: &str) -> Vec<u8> {
+        rustls_pemfile::pkcs8_private_keys(&mut pem.as_bytes())
+            .unwrap()
+            .remove(0)
+    }
+
+    /// Move TLS data from `from` to `to` until `from` has none to send.
+    unsafe fn transfer(from: *mut rustls_connection, to: *mut rustls_connection) {
+        let mut buf = [0u8; 16384];
+        while rustls_connection_wants_write(from) {
+            let (mut written, mut read) = (0, 0);
+            assert_eq!(
+                rustls_connection_write_tls(from, buf.as_mut_ptr(), buf.len(), &mut written),
+                rustls_result::Ok
+            );
+            let mut offs = 0;
+            while offs < written {
+                assert_eq!(
+                    rustls_connection_read_tls(to, buf[offs..].as_ptr(), written - offs, &mut read),
+                    rustls_result::Ok
+                );
+                offs += read;
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_and_exchange_data() {
+        let (client_config, server_config) = (client_config(b"hello"), server_config(b"hello"));
+        let (mut client, mut server) = (ptr::null_mut(), ptr::null_mut());
+
+        unsafe {
+            let name = b"localhost\0";
+            assert_eq!(
+                rustls_client_connection_new(
+                    client_config,
+                    name.as_ptr() as *const c_char,
+                    &mut client
+                ),
+                rustls_result::Ok
+            );
+            assert_eq!(
+                rustls_server_connection_new(server_config, &mut server),
+                rustls_result::Ok
+            );
+            // connections outlive their configuration
+            rustls_client_config_free(client_config);
+            rustls_server_config_free(server_config);
+
+            let mut n = 0;
+            let message = b"hello world";
+            assert_eq!(
+                rustls_connection_write(client, message.as_ptr(), message.len(), &mut n),
+                rustls_result::Ok
+            );
+            assert_eq!(n, message.len());
+
+            while rustls_connection_is_handshaking(client)
+                || rustls_connection_is_handshaking(server)
+            {
+                transfer(client, server);
+                assert_eq!(
+                    rustls_connection_process_new_packets(server),
+                    rustls_result::Ok
+                );
+                transfer(server, client);
+                assert_eq!(
+                    rustls_connection_process_new_packets(client),
+                    rustls_result::Ok
+                );
+            }
+            transfer(client, server);
+            assert_eq!(
+                rustls_connection_process_new_packets(server),
+                rustls_result::Ok
+            );
+
+            let (mut protocol, mut len) = (ptr::null(), 0);
+            assert_eq!(
+                rustls_connection_get_alpn_protocol(client, &mut protocol, &mut len),
+                rustls_result::Ok
+            );
+            assert_eq!(slice::from_raw_parts(protocol, len), b"hello");
+
+            let mut buf = [0u8; 64];
+            assert_eq!(
+                rustls_connection_read(server, buf.as_mut_ptr(), buf.len(), &mut n),
+                rustls_result::Ok
+            );
+            assert_eq!(&buf[..n], message);
+            assert_eq!(
+                rustls_connection_read(server, buf.as_mut_ptr(), buf.len(), &mut n),
+                rustls_result::PlaintextEmpty
+            );
+
+            rustls_connection_send_close_notify(client);
+            transfer(client, server);
+            assert_eq!(
+                rustls_connection_process_new_packets(server),
+                rustls_result::Ok
+            );
+            assert_eq!(
+                rustls_connection_read(server, buf.as_mut_ptr(), buf.len(), &mut n),
+                rustls_result::Ok
+            );
+            assert_eq!(n, 0);
+
+            rustls_connection_free(client);
+            rustls_connection_free(server);
+        }
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let (client_config, server_config) = (client_config(b"hello"), server_config(b"goodbye"));
+        let (mut client, mut server) = (ptr::null_mut(), ptr::null_mut());
+
+        unsafe {
+            let name = b"localhost\0";
+            rustls_client_connection_new(
+                client_config,
+                name.as_ptr() as *const c_char,
+                &mut client,
+            );
+            rustls_server_connection_new(server_config, &mut server);
+
+            transfer(client, server);
+            assert_eq!(
+                rustls_connection_process_new_packets(server),
+                rustls_result::NoApplicationProtocol
+            );
+
+            let mut buf: [c_char; 64] = [0; 64];
+            let mut n = 0;
+            rustls_connection_last_error(server, buf.as_mut_ptr(), buf.len(), &mut n);
+            assert_eq!(
+                CStr::from_ptr(buf.as_ptr()).to_str(),
+                Ok("peer doesn't support any known protocol")
+            );
+            assert_eq!(n, "peer doesn't support any known protocol".len());
+
+            rustls_result_description(
+                rustls_result::NoApplicationProtocol as u32,
+                buf.as_mut_ptr(),
+                4,
+                &mut n,
+            );
+            assert_eq!(CStr::from_ptr(buf.as_ptr()).to_str(), Ok("no "));
+            assert_eq!(n, 3);
+
+            rustls_connection_last_error(client, buf.as_mut_ptr(), buf.len(), &mut n);
+            assert_eq!(n, 0);
+
+            rustls_connection_free(client);
+            rustls_connection_free(server);
+            rustls_client_config_free(client_config);
+            rustls_server_config_free(server_config);
+        }
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        unsafe {
+            let builder = rustls_client_config_builder_new();
+            assert_eq!(
+                rustls_client_config_builder_add_root_der(builder, ptr::null(), 1),
+                rustls_result::NullParameter
+            );
+            assert_eq!(
+                rustls_client_config_builder_add_root_der(builder, b"bad".as_ptr(), 3),
+                rustls_result::InvalidParameter
+            );
+            rustls_client_config_builder_free(builder);
+
+            let builder = rustls_server_config_builder_new();
+            let mut config = ptr::null();
+            assert_eq!(
+                rustls_server_config_builder_build(builder, &mut config),
+                rustls_result::InvalidParameter
+            );
+            assert!(config.is_null());
+
+            let mut conn = ptr::null_mut();
+            assert_eq!(
+                rustls_server_connection_new(ptr::null(), &mut conn),
+                rustls_result::NullParameter
+            );
+        }
+    }
+}
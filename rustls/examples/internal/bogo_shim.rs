@@ -71,6 +71,7 @@ struct Options {
     quic_transport_params: Vec<u8>,
     expect_quic_transport_params: Vec<u8>,
     enable_early_data: bool,
+    send_fallback_scsv: bool,
     expect_ticket_supports_early_data: bool,
     expect_accept_early_data: bool,
     expect_reject_early_data: bool,
@@ -118,6 +119,7 @@ impl Options {
             quic_transport_params: vec![],
             expect_quic_transport_params: vec![],
             enable_early_data: false,
+            send_fallback_scsv: false,
             expect_ticket_supports_early_data: false,
             expect_accept_early_data: false,
             expect_reject_early_data: false,
@@ -559,6 +561,8 @@ fn make_client_cfg(opts: &Options) -> Arc<ClientConfig<Ring>> {
         cfg.enable_early_data = true;
     }
 
+    cfg.send_fallback_scsv = opts.send_fallback_scsv;
+
     Arc::new(cfg)
 }
 
@@ -620,6 +624,12 @@ fn handle_err(err: Error) -> ! {
         Error::PeerMisbehaved(PeerMisbehaved::MissingPskModesExtension) => {
             quit(":MISSING_EXTENSION:")
         }
+        Error::PeerMisbehaved(PeerMisbehaved::InappropriateFallback) => {
+            quit(":INAPPROPRIATE_FALLBACK:")
+        }
+        Error::AlertReceived(AlertDescription::InappropriateFallback) => {
+            quit(":TLSV1_ALERT_INAPPROPRIATE_FALLBACK:")
+        }
         Error::PeerMisbehaved(PeerMisbehaved::TooMuchEarlyDataReceived) => {
             quit(":TOO_MUCH_READ_EARLY_DATA:")
         }
@@ -1045,6 +1055,9 @@ fn main() {
                 opts.tickets = false;
                 opts.enable_early_data = true;
             }
+            "-fallback-scsv" => {
+                opts.send_fallback_scsv = true;
+            }
             "-on-resume-shim-writes-first" => {
                 opts.queue_data_on_resume = true;
             }
@@ -1128,7 +1141,6 @@ fn main() {
             "-psk" |
             "-renegotiate-freely" |
             "-false-start" |
-            "-fallback-scsv" |
             "-fail-early-callback" |
             "-fail-cert-callback" |
             "-install-ddos-callback" |
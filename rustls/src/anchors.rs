@@ -4,6 +4,12 @@ use crate::x509;
 use crate::{key, DistinguishedName};
 use crate::{CertificateError, Error};
 
+use alloc::sync::Arc;
+use core::mem;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{PoisonError, RwLock};
+use std::time::SystemTime;
+
 /// A trust anchor, commonly known as a "Root Certificate."
 #[derive(Debug, Clone)]
 pub struct OwnedTrustAnchor {
@@ -11,6 +17,7 @@ pub struct OwnedTrustAnchor {
     subject_dn: DistinguishedName,
     spki: Vec<u8>,
     name_constraints: Option<Vec<u8>>,
+    distrust_after: Option<SystemTime>,
 }
 
 impl OwnedTrustAnchor {
@@ -55,7 +62,71 @@ impl OwnedTrustAnchor {
             subject_dn,
             spki: spki.into(),
             name_constraints: name_constraints.map(|x| x.into()),
+            distrust_after: None,
+        }
+    }
+
+    /// Replace the [Name Constraints] to apply for this trust anchor.
+    ///
+    /// `name_constraints` is DER-encoded, as for
+    /// [`OwnedTrustAnchor::from_subject_spki_name_constraints`].  Names
+    /// in certificates issued under this anchor are checked against them,
+    /// whether or not the anchor's own certificate has any.
+    ///
+    /// [Name Constraints]: https://datatracker.ietf.org/doc/html/rfc5280#section-4.2.1.10
+    pub fn with_name_constraints(mut self, name_constraints: impl Into<Vec<u8>>) -> Self {
+        self.name_constraints = Some(name_constraints.into());
+        self
+    }
+
+    /// Only trust this anchor for DNS names within `domains`.
+    ///
+    /// This replaces any name constraints with ones permitting each of
+    /// `domains` and its subdomains.  As for any name constraints, other
+    /// kinds of name, such as IP addresses, are not constrained.
+    pub fn with_permitted_dns_names<'a>(self, domains: impl IntoIterator<Item = &'a str>) -> Self {
+        // NameConstraints ::= SEQUENCE {
+        //    permittedSubtrees       [0]     GeneralSubtrees OPTIONAL,
+        //    excludedSubtrees        [1]     GeneralSubtrees OPTIONAL }
+        //
+        // GeneralSubtree ::= SEQUENCE {
+        //    base                    GeneralName,
+        //    ... }
+        let mut permitted = Vec::new();
+        for domain in domains {
+            let mut subtree = domain.as_bytes().to_vec();
+            x509::wrap_in_asn1_len(&mut subtree);
+            subtree.insert(0, x509::DNS_NAME);
+            x509::wrap_in_sequence(&mut subtree);
+            permitted.extend_from_slice(&subtree);
         }
+        x509::wrap_in_asn1_len(&mut permitted);
+        permitted.insert(0, x509::context(0));
+        self.with_name_constraints(permitted)
+    }
+
+    /// Stop trusting certificates issued under this anchor after `time`.
+    ///
+    /// End-entity certificates whose validity period starts after `time` are
+    /// not trusted, while those issued before it remain so until they
+    /// expire.  This allows a CA to be phased out, as has been done for
+    /// those that misissued certificates.
+    pub fn with_distrust_after(mut self, time: SystemTime) -> Self {
+        self.distrust_after = Some(time);
+        self
+    }
+
+    /// Return the DER-encoded name constraints for this trust anchor, if any.
+    pub fn name_constraints(&self) -> Option<&[u8]> {
+        self.name_constraints.as_deref()
+    }
+
+    /// Return the time after which this trust anchor no longer issues
+    /// trusted certificates, if any.
+    ///
+    /// See [`OwnedTrustAnchor::with_distrust_after`].
+    pub fn distrust_after(&self) -> Option<SystemTime> {
+        self.distrust_after
     }
 
     /// Return the subject field including its outer SEQUENCE encoding.
@@ -69,6 +140,13 @@ impl OwnedTrustAnchor {
     pub fn subject(&self) -> &DistinguishedName {
         &self.subject_dn
     }
+
+    /// The DER-encoded `SubjectPublicKeyInfo`, including its outer SEQUENCE.
+    pub(crate) fn subject_public_key_info(&self) -> Vec<u8> {
+        let mut spki = self.spki.clone();
+        x509::wrap_in_sequence(&mut spki);
+        spki
+    }
 }
 
 /// A container for root certificates able to provide a root-of-trust
@@ -95,6 +173,32 @@ impl RootCertStore {
         self.roots.len()
     }
 
+    /// Return an iterator over the trust anchors.
+    pub fn iter(&self) -> impl Iterator<Item = &OwnedTrustAnchor> {
+        self.roots.iter()
+    }
+
+    /// Return the subjects of the trust anchors.
+    ///
+    /// These are suitable for [`ClientConfig::certificate_authorities`].
+    ///
+    /// [`ClientConfig::certificate_authorities`]: crate::ClientConfig::certificate_authorities
+    pub fn subjects(&self) -> Vec<DistinguishedName> {
+        self.roots
+            .iter()
+            .map(|root| root.subject().clone())
+            .collect()
+    }
+
+    /// Remove the trust anchors for which `keep` returns false.
+    ///
+    /// Returns the number of anchors removed.
+    pub fn retain(&mut self, keep: impl FnMut(&OwnedTrustAnchor) -> bool) -> usize {
+        let before = self.roots.len();
+        self.roots.retain(keep);
+        before - self.roots.len()
+    }
+
     /// Add a single DER-encoded certificate to the store.
     ///
     /// This is suitable for a small set of root certificates that are expected to parse
@@ -164,7 +268,203 @@ impl RootCertStore {
     }
 }
 
+/// A [`RootCertStore`] that can be shared between configs, and replaced
+/// while they are in use.
+///
+/// Cloning this gives another handle to the same store.  Replacing the
+/// trust anchors with [`SharedRootCertStore::replace`] affects every config
+/// built with any handle, starting with the next certificate verification:
+/// long-running processes can pick up an updated CA bundle without building
+/// new configs.  Verifications already in progress keep using the roots
+/// they started with.
+///
+/// Use it with [`ConfigBuilder::with_shared_root_certificates`].
+///
+/// [`ConfigBuilder::with_shared_root_certificates`]: crate::ConfigBuilder::with_shared_root_certificates
+#[derive(Debug, Clone)]
+pub struct SharedRootCertStore {
+    current: Arc<RwLock<Arc<RootCertStore>>>,
+}
+
+impl SharedRootCertStore {
+    /// Make a new shared store, initially containing `roots`.
+    pub fn new(roots: RootCertStore) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(roots))),
+        }
+    }
+
+    /// Atomically replace the trust anchors, returning the previous ones.
+    pub fn replace(&self, roots: RootCertStore) -> Arc<RootCertStore> {
+        let mut current = self
+            .current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        mem::replace(&mut *current, Arc::new(roots))
+    }
+
+    /// Return the trust anchors currently in use.
+    ///
+    /// The result is a snapshot: it is unaffected by later calls to
+    /// [`SharedRootCertStore::replace`].
+    pub fn load(&self) -> Arc<RootCertStore> {
+        Arc::clone(
+            &self
+                .current
+                .read()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+}
+
+impl From<RootCertStore> for SharedRootCertStore {
+    fn from(roots: RootCertStore) -> Self {
+        Self::new(roots)
+    }
+}
+
+/// A collection of intermediate CA certificates, used to complete the
+/// chains of servers that do not send all the intermediates they should.
+///
+/// These are not trusted: they only help build a chain to a trust anchor
+/// in a [`RootCertStore`].  A typical source is a preload list of all the
+/// intermediates issued under the trust anchors in use.
+///
+/// Use it with [`ClientConfig::known_intermediates`].
+///
+/// [`ClientConfig::known_intermediates`]: crate::ClientConfig::known_intermediates
+#[derive(Debug, Clone, Default)]
+pub struct KnownIntermediates {
+    /// Certificates, by the encoding of their subject.
+    by_subject: BTreeMap<Vec<u8>, Vec<key::Certificate>>,
+    len: usize,
+}
+
+impl KnownIntermediates {
+    /// Make a new, empty `KnownIntermediates`.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Return true if there are no certificates.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Say how many certificates are in the container.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Add a single DER-encoded certificate.
+    pub fn add(&mut self, der: &key::Certificate) -> Result<(), Error> {
+        let subject = der.details()?.subject();
+        let certs = self
+            .by_subject
+            .entry(subject.as_ref().to_vec())
+            .or_default();
+        if !certs.contains(der) {
+            certs.push(der.clone());
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    /// Parse the given DER-encoded certificates and add all that can be
+    /// parsed in a best-effort fashion.
+    ///
+    /// Returns the number of certificates added, and the number that were ignored.
+    pub fn add_parsable_certificates<C: AsRef<[u8]>>(
+        &mut self,
+        der_certs: impl IntoIterator<Item = C>,
+    ) -> (usize, usize) {
+        let mut valid_count = 0;
+        let mut invalid_count = 0;
+
+        for der_cert in der_certs {
+            #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
+            match self.add(&key::Certificate(der_cert.as_ref().to_vec())) {
+                Ok(_) => valid_count += 1,
+                Err(err) => {
+                    trace!("invalid cert der {:?}", der_cert.as_ref());
+                    debug!("certificate parsing failed: {:?}", err);
+                    invalid_count += 1;
+                }
+            }
+        }
+
+        (valid_count, invalid_count)
+    }
+
+    /// Return `intermediates`, followed by any known certificates that may
+    /// issue `end_entity` or `intermediates` but were not sent, and then by
+    /// any that may issue those, and so on.
+    pub(crate) fn complete_chain(
+        &self,
+        end_entity: &key::Certificate,
+        intermediates: &[key::Certificate],
+    ) -> Vec<key::Certificate> {
+        let mut chain = intermediates.to_vec();
+        if self.is_empty() {
+            return chain;
+        }
+
+        let mut subjects = BTreeSet::new();
+        let mut issuers = Vec::new();
+        for cert in intermediates {
+            if let Ok(details) = cert.details() {
+                subjects.insert(details.subject().as_ref().to_vec());
+                issuers.push(details.issuer().as_ref().to_vec());
+            }
+        }
+        if let Ok(details) = end_entity.details() {
+            issuers.push(details.issuer().as_ref().to_vec());
+        }
+
+        while let Some(issuer) = issuers.pop() {
+            if subjects.contains(&issuer) {
+                continue;
+            }
+            let known = match self.by_subject.get(&issuer) {
+                Some(known) => known,
+                None => continue,
+            };
+            for cert in known {
+                if let Ok(details) = cert.details() {
+                    issuers.push(details.issuer().as_ref().to_vec());
+                }
+                chain.push(cert.clone());
+            }
+            subjects.insert(issuer);
+        }
+
+        chain
+    }
+}
+
 mod tests {
+    #[test]
+    fn shared_root_cert_store_replace_is_visible_through_clones() {
+        let mut roots = super::RootCertStore::empty();
+        roots.add_server_trust_anchors(
+            [super::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                b"subject".to_owned(),
+                b"".to_owned(),
+                None::<Vec<u8>>,
+            )]
+            .into_iter(),
+        );
+
+        let shared = super::SharedRootCertStore::new(super::RootCertStore::empty());
+        let other = shared.clone();
+        let before = shared.load();
+
+        let previous = other.replace(roots);
+        assert!(previous.is_empty());
+        assert!(before.is_empty());
+        assert_eq!(shared.load().len(), 1);
+    }
+
     #[test]
     fn ownedtrustanchor_subject_is_correctly_encoding_dn() {
         let subject = b"subject".to_owned();
@@ -0,0 +1,447 @@
+//! Adapters for driving a connection over an asynchronous transport.
+//!
+//! [`TlsStream`] pairs a rustls connection with a transport implementing
+//! the `AsyncRead` and `AsyncWrite` traits of either the `futures-io` crate
+//! (with the `futures_io` feature) or `tokio` (with the `tokio` feature).
+//! It implements the same traits in turn, reading and writing plaintext.
+//!
+//! The handshake is completed on first use.  Flush the stream to complete
+//! it explicitly, for example to inspect the negotiated ALPN protocol
+//! before sending anything.
+//!
+//! Closing or shutting down a `TlsStream` sends a `close_notify` alert
+//! before closing the transport.
+
+use crate::conn::{ConnectionCommon, SideData};
+
+use core::task::{Context, Poll};
+use std::io::{self, Read, Write};
+
+/// Evaluates to the value of a `Poll::Ready`, or returns `Poll::Pending`.
+macro_rules! ready {
+    ($e:expr) => {
+        match $e {
+            Poll::Ready(t) => t,
+            Poll::Pending => return Poll::Pending,
+        }
+    };
+}
+
+/// A connection `C` and an asynchronous transport `T`, such as a socket.
+///
+/// `C` is a [`ClientConnection`] or [`ServerConnection`], or anything that
+/// dereferences to one, like a `Box`.
+///
+/// [`ClientConnection`]: crate::ClientConnection
+/// [`ServerConnection`]: crate::ServerConnection
+#[derive(Debug)]
+pub struct TlsStream<C, T> {
+    conn: C,
+    io: T,
+    closing: bool,
+}
+
+impl<C, T> TlsStream<C, T> {
+    /// Make a new `TlsStream` from the connection `conn` and transport `io`.
+    /// This does not fail and does no IO.
+    pub fn new(conn: C, io: T) -> Self {
+        Self {
+            conn,
+            io,
+            closing: false,
+        }
+    }
+
+    /// Get a reference to the connection.
+    pub fn get_ref(&self) -> &C {
+        &self.conn
+    }
+
+    /// Get a mutable reference to the connection.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+
+    /// Get a reference to the transport.
+    pub fn get_io(&self) -> &T {
+        &self.io
+    }
+
+    /// Get a mutable reference to the transport.
+    ///
+    /// Reading from or writing to the transport directly will corrupt the
+    /// TLS session.
+    pub fn get_io_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Extract the connection and transport.
+    pub fn into_inner(self) -> (C, T) {
+        (self.conn, self.io)
+    }
+}
+
+fn poll_handshake<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    io: &mut dyn Transport,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    loop {
+        while conn.wants_write() {
+            ready!(poll_write_tls(conn, io, cx))?;
+        }
+
+        if !conn.is_handshaking() {
+            return Poll::Ready(Ok(()));
+        }
+
+        // Our flight must reach the peer before we wait for theirs.
+        ready!(io.poll_flush(cx))?;
+
+        if ready!(poll_read_tls(conn, io, cx))? == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        process_new_packets(conn, io, cx)?;
+    }
+}
+
+fn poll_read<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    io: &mut dyn Transport,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    ready!(poll_handshake(conn, io, cx))?;
+
+    loop {
+        match conn.reader().read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            result => return Poll::Ready(result),
+        }
+
+        // Send anything queued by processing (for example, a key update),
+        // but don't let a slow writer hold up reading.
+        while conn.wants_write() {
+            match poll_write_tls(conn, io, cx) {
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+
+        ready!(poll_read_tls(conn, io, cx))?;
+        process_new_packets(conn, io, cx)?;
+    }
+}
+
+fn poll_write<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    io: &mut dyn Transport,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+) -> Poll<io::Result<usize>> {
+    ready!(poll_handshake(conn, io, cx))?;
+
+    loop {
+        let len = conn.writer().write(buf)?;
+
+        let mut blocked = false;
+        while conn.wants_write() {
+            match poll_write_tls(conn, io, cx) {
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => {
+                    blocked = true;
+                    break;
+                }
+            }
+        }
+
+        // Plaintext accepted into the connection's buffer is as good as
+        // written: it goes out on the next write or flush.
+        if len > 0 || buf.is_empty() {
+            return Poll::Ready(Ok(len));
+        } else if blocked {
+            return Poll::Pending;
+        }
+    }
+}
+
+fn poll_flush<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    io: &mut dyn Transport,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    ready!(poll_handshake(conn, io, cx))?;
+
+    conn.writer().flush()?;
+    while conn.wants_write() {
+        ready!(poll_write_tls(conn, io, cx))?;
+    }
+    io.poll_flush(cx)
+}
+
+fn poll_close<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    closing: &mut bool,
+    io: &mut dyn Transport,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    if !*closing {
+        conn.send_close_notify();
+        *closing = true;
+    }
+    ready!(poll_flush(conn, io, cx))?;
+    io.poll_close(cx)
+}
+
+/// Object-safe view of the transport, whichever crate's traits it implements.
+trait Transport {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>;
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// Presents a [`Transport`] as blocking IO, which fails with
+/// `WouldBlock` where the transport would return `Poll::Pending`.
+struct SyncIo<'a, 'b> {
+    io: &'a mut dyn Transport,
+    cx: &'a mut Context<'b>,
+}
+
+impl Read for SyncIo<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.io.poll_read(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for SyncIo<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.io.poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.io.poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+fn poll_read_tls<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    io: &mut dyn Transport,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<usize>> {
+    match conn.read_tls(&mut SyncIo { io, cx }) {
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        result => Poll::Ready(result),
+    }
+}
+
+/// Like [`poll_read_tls`], but a transport that accepts nothing is an error.
+fn poll_write_tls<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    io: &mut dyn Transport,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<usize>> {
+    match conn.write_tls(&mut SyncIo { io, cx }) {
+        Ok(0) => Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        result => Poll::Ready(result),
+    }
+}
+
+fn process_new_packets<S: SideData>(
+    conn: &mut ConnectionCommon<S>,
+    io: &mut dyn Transport,
+    cx: &mut Context<'_>,
+) -> io::Result<()> {
+    match conn.process_new_packets() {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            // Try to send the alert describing the error, but don't let
+            // that mask it.
+            let _ignored = conn.write_tls(&mut SyncIo { io, cx });
+            Err(io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+    }
+}
+
+#[cfg(feature = "futures_io")]
+mod futures {
+    use super::{TlsStream, Transport};
+    use crate::conn::{ConnectionCommon, SideData};
+
+    use core::ops::{Deref, DerefMut};
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+    use futures_io::{AsyncRead, AsyncWrite};
+    use std::io;
+
+    struct FuturesIo<'a, T>(&'a mut T);
+
+    impl<T: AsyncRead + AsyncWrite + Unpin> Transport for FuturesIo<'_, T> {
+        fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut *self.0).poll_read(cx, buf)
+        }
+
+        fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut *self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.0).poll_flush(cx)
+        }
+
+        fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.0).poll_close(cx)
+        }
+    }
+
+    impl<C, T, S> AsyncRead for TlsStream<C, T>
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<S>> + Unpin,
+        T: AsyncRead + AsyncWrite + Unpin,
+        S: SideData,
+    {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            super::poll_read(&mut this.conn, &mut FuturesIo(&mut this.io), cx, buf)
+        }
+    }
+
+    impl<C, T, S> AsyncWrite for TlsStream<C, T>
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<S>> + Unpin,
+        T: AsyncRead + AsyncWrite + Unpin,
+        S: SideData,
+    {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            super::poll_write(&mut this.conn, &mut FuturesIo(&mut this.io), cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            super::poll_flush(&mut this.conn, &mut FuturesIo(&mut this.io), cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            super::poll_close(
+                &mut this.conn,
+                &mut this.closing,
+                &mut FuturesIo(&mut this.io),
+                cx,
+            )
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod tokio {
+    use super::{TlsStream, Transport};
+    use crate::conn::{ConnectionCommon, SideData};
+
+    use ::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use core::ops::{Deref, DerefMut};
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+    use std::io;
+
+    struct TokioIo<'a, T>(&'a mut T);
+
+    impl<T: AsyncRead + AsyncWrite + Unpin> Transport for TokioIo<'_, T> {
+        fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            let mut buf = ReadBuf::new(buf);
+            match Pin::new(&mut *self.0).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+
+        fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut *self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.0).poll_flush(cx)
+        }
+
+        fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.0).poll_shutdown(cx)
+        }
+    }
+
+    impl<C, T, S> AsyncRead for TlsStream<C, T>
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<S>> + Unpin,
+        T: AsyncRead + AsyncWrite + Unpin,
+        S: SideData,
+    {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            let unfilled = buf.initialize_unfilled();
+            match super::poll_read(&mut this.conn, &mut TokioIo(&mut this.io), cx, unfilled) {
+                Poll::Ready(Ok(len)) => {
+                    buf.advance(len);
+                    Poll::Ready(Ok(()))
+                }
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    impl<C, T, S> AsyncWrite for TlsStream<C, T>
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<S>> + Unpin,
+        T: AsyncRead + AsyncWrite + Unpin,
+        S: SideData,
+    {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            super::poll_write(&mut this.conn, &mut TokioIo(&mut this.io), cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            super::poll_flush(&mut this.conn, &mut TokioIo(&mut this.io), cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            super::poll_close(
+                &mut this.conn,
+                &mut this.closing,
+                &mut TokioIo(&mut this.io),
+                cx,
+            )
+        }
+    }
+}
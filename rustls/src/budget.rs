@@ -0,0 +1,167 @@
+use crate::error::Error;
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A cap on the memory used by connections, shared between them.
+///
+/// Set the same budget as `ClientConfig::memory_budget` or
+/// `ServerConfig::memory_budget` on any number of configs.  Each connection
+/// made from them is charged for its own size plus the data buffered in it,
+/// and the charge is brought up to date whenever
+/// [`Connection::read_tls`], [`Connection::write_tls`] or
+/// [`Connection::process_new_packets`] is called.  The charge is released
+/// when the connection is dropped.
+///
+/// Once the budget is used up, new connections fail with
+/// [`Error::MemoryBudgetExceeded`], and existing ones release buffer space
+/// they are not using.  Existing connections are not otherwise affected,
+/// so usage can exceed the budget by up to the buffer limits of the
+/// connections in progress.
+///
+/// [`Connection::read_tls`]: crate::Connection::read_tls
+/// [`Connection::write_tls`]: crate::Connection::write_tls
+/// [`Connection::process_new_packets`]: crate::Connection::process_new_packets
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    connections: AtomicUsize,
+}
+
+impl MemoryBudget {
+    /// Make a budget of `limit` bytes.
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+        })
+    }
+
+    /// The number of bytes the budget allows.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The number of bytes currently charged to connections.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// The number of connections currently charged to the budget.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.used() >= self.limit
+    }
+
+    /// Admit a new connection of `size` bytes, if the budget is not
+    /// already used up.
+    pub(crate) fn admit(budget: &Arc<Self>, size: usize) -> Result<MemoryCharge, Error> {
+        if budget.is_exhausted() {
+            return Err(Error::MemoryBudgetExceeded);
+        }
+
+        budget
+            .connections
+            .fetch_add(1, Ordering::Relaxed);
+        budget
+            .used
+            .fetch_add(size, Ordering::Relaxed);
+        Ok(MemoryCharge {
+            budget: Arc::clone(budget),
+            charged: size,
+        })
+    }
+}
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("limit", &self.limit)
+            .field("used", &self.used())
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+/// What one connection is charged to a [`MemoryBudget`].
+pub(crate) struct MemoryCharge {
+    budget: Arc<MemoryBudget>,
+    charged: usize,
+}
+
+impl MemoryCharge {
+    /// Change the charge to `size` bytes.  Returns true if the budget is
+    /// used up, so the connection should release what it can.
+    pub(crate) fn update(&mut self, size: usize) -> bool {
+        if size > self.charged {
+            self.budget
+                .used
+                .fetch_add(size - self.charged, Ordering::Relaxed);
+        } else {
+            self.budget
+                .used
+                .fetch_sub(self.charged - size, Ordering::Relaxed);
+        }
+        self.charged = size;
+        self.budget.is_exhausted()
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.budget
+            .used
+            .fetch_sub(self.charged, Ordering::Relaxed);
+        self.budget
+            .connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charges_are_released() {
+        let budget = MemoryBudget::new(100);
+        let mut first = MemoryBudget::admit(&budget, 10).unwrap();
+        let second = MemoryBudget::admit(&budget, 20).unwrap();
+        assert_eq!(budget.used(), 30);
+        assert_eq!(budget.connections(), 2);
+
+        assert!(!first.update(50));
+        assert_eq!(budget.used(), 70);
+        assert!(!first.update(5));
+        assert_eq!(budget.used(), 25);
+
+        drop(second);
+        assert_eq!(budget.used(), 5);
+        assert_eq!(budget.connections(), 1);
+        drop(first);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.connections(), 0);
+    }
+
+    #[test]
+    fn test_exhausted_budget_refuses_connections() {
+        let budget = MemoryBudget::new(100);
+        let mut charge = MemoryBudget::admit(&budget, 60).unwrap();
+        // Admission only checks that some budget is left.
+        let other = MemoryBudget::admit(&budget, 60).unwrap();
+        assert!(matches!(
+            MemoryBudget::admit(&budget, 1),
+            Err(Error::MemoryBudgetExceeded)
+        ));
+        assert!(charge.update(70));
+
+        drop(other);
+        assert!(!charge.update(10));
+        assert!(MemoryBudget::admit(&budget, 1).is_ok());
+    }
+}
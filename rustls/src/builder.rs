@@ -1,7 +1,8 @@
 use crate::crypto::{CryptoProvider, KeyExchange};
+use crate::enums::{ProtocolVersion, SignatureScheme};
 use crate::error::Error;
 use crate::suites::{SupportedCipherSuite, DEFAULT_CIPHER_SUITES};
-use crate::versions;
+use crate::{verify, versions};
 
 use core::fmt;
 use core::marker::PhantomData;
@@ -146,6 +147,7 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsCipherSuites> {
                 cipher_suites: DEFAULT_CIPHER_SUITES.to_vec(),
                 kx_groups: <<S::CryptoProvider as CryptoProvider>::KeyExchange as KeyExchange>::all_kx_groups().to_vec(),
                 versions: versions::EnabledVersions::new(versions::DEFAULT_VERSIONS),
+                signature_schemes: None,
             },
             side: self.side,
         }
@@ -250,10 +252,62 @@ impl<S: ConfigSide, C: CryptoProvider> ConfigBuilder<S, WantsVersions<C>> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: versions::EnabledVersions::new(versions),
+                signature_schemes: None,
             },
             side: self.side,
         })
     }
+
+    /// Use every supported protocol version from `min` to `max`, inclusive.
+    ///
+    /// This is an alternative to [`Self::with_protocol_versions()`] for when the
+    /// versions are only known at runtime, for example when they come from a
+    /// configuration file.  `min` and `max` need not themselves be supported
+    /// by rustls: `TLSv1_0..=TLSv1_3` is the same as `TLSv1_2..=TLSv1_3`.
+    ///
+    /// This fails if `min` or `max` is not an SSL or TLS version (DTLS
+    /// versions are not accepted), if `min` is greater than `max`, if no
+    /// version supported by rustls lies in the range, or if none of the
+    /// chosen cipher suites can be used with the versions in range.
+    pub fn with_protocol_versions_range(
+        self,
+        min: ProtocolVersion,
+        max: ProtocolVersion,
+    ) -> Result<ConfigBuilder<S, WantsVerifier<C>>, Error> {
+        // SSL and TLS versions are all 3.x; anything else cannot be ordered
+        // against them.
+        if let Some(bound) = [min, max]
+            .iter()
+            .find(|bound| bound.get_u16() >> 8 != 0x03)
+        {
+            return Err(Error::General(format!(
+                "{:?} is not a TLS protocol version",
+                bound
+            )));
+        }
+
+        if min.get_u16() > max.get_u16() {
+            return Err(Error::General(format!(
+                "minimum protocol version {:?} is greater than maximum {:?}",
+                min, max
+            )));
+        }
+
+        let versions = versions::ALL_VERSIONS
+            .iter()
+            .copied()
+            .filter(|v| (min.get_u16()..=max.get_u16()).contains(&v.version.get_u16()))
+            .collect::<Vec<_>>();
+
+        if versions.is_empty() {
+            return Err(Error::General(format!(
+                "no supported protocol versions between {:?} and {:?}",
+                min, max
+            )));
+        }
+
+        self.with_protocol_versions(&versions)
+    }
 }
 
 /// Config builder state where the caller must supply a verifier.
@@ -265,6 +319,38 @@ pub struct WantsVerifier<C: CryptoProvider> {
     pub(crate) kx_groups:
         Vec<&'static <<C as CryptoProvider>::KeyExchange as KeyExchange>::SupportedGroup>,
     pub(crate) versions: versions::EnabledVersions,
+    pub(crate) signature_schemes: Option<Vec<SignatureScheme>>,
+}
+
+impl<S: ConfigSide, C: CryptoProvider> ConfigBuilder<S, WantsVerifier<C>> {
+    /// Restrict the signature schemes used with peers.
+    ///
+    /// By default, any scheme supported by the certificate verifier and
+    /// signing keys is used.  With this restriction, only `schemes` are
+    /// advertised to the peer, and the peer must use one of them:
+    ///
+    /// - to sign the handshake, and
+    /// - for the issuers' signatures on the certificates it sends, other than
+    ///   self-signed ones.
+    ///
+    /// Our own handshake signatures also use one of `schemes`.  Certificates
+    /// signed with ECDSA match a scheme by hash alone, as in TLS1.2.
+    ///
+    /// This fails if none of `schemes` is supported by rustls.
+    pub fn with_signature_schemes(mut self, schemes: &[SignatureScheme]) -> Result<Self, Error> {
+        let supported = verify::WebPkiVerifier::verification_schemes();
+        if !schemes
+            .iter()
+            .any(|scheme| supported.contains(scheme))
+        {
+            return Err(Error::General(
+                "no usable signature schemes configured".into(),
+            ));
+        }
+
+        self.state.signature_schemes = Some(schemes.to_vec());
+        Ok(self)
+    }
 }
 
 /// Helper trait to abstract [`ConfigBuilder`] over building a [`ClientConfig`] or [`ServerConfig`].
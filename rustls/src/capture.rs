@@ -0,0 +1,231 @@
+use crate::bs_debug::BsDebug;
+use crate::enums::{CipherSuite, HandshakeType, ProtocolVersion};
+use crate::key::Certificate;
+use crate::msgs::codec::Reader;
+use crate::msgs::enums::NamedGroup;
+use crate::msgs::handshake::{
+    HandshakeMessagePayload, HandshakePayload, HasServerExtensions, KeyExchangeAlgorithm,
+};
+use crate::time_provider::TimeProvider;
+
+use alloc::sync::Arc;
+use core::fmt;
+use std::time::SystemTime;
+
+/// Which way a [`CapturedMessage`] travelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageDirection {
+    /// The message was sent by this end of the connection.
+    Sent,
+    /// The message was received from the peer.
+    Received,
+}
+
+/// A handshake message recorded by a connection with handshake capture enabled.
+///
+/// See [`ClientConfig::capture_handshake`] and [`ServerConfig::capture_handshake`].
+///
+/// [`ClientConfig::capture_handshake`]: crate::ClientConfig::capture_handshake
+/// [`ServerConfig::capture_handshake`]: crate::ServerConfig::capture_handshake
+#[derive(Clone)]
+pub struct CapturedMessage {
+    direction: MessageDirection,
+    typ: HandshakeType,
+    bytes: Vec<u8>,
+    time: Option<SystemTime>,
+}
+
+impl CapturedMessage {
+    /// Whether the message was sent or received.
+    pub fn direction(&self) -> MessageDirection {
+        self.direction
+    }
+
+    /// The handshake message type.
+    pub fn typ(&self) -> HandshakeType {
+        self.typ
+    }
+
+    /// The message's encoding, including its four-byte handshake header.
+    ///
+    /// These are the bytes that enter the handshake transcript hash, so they
+    /// are exactly what a decoder such as Wireshark would show for the
+    /// message, after reassembly and decryption.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// When the message was sent or processed, according to the
+    /// connection's [`TimeProvider`].
+    ///
+    /// This is `None` if the time provider could not supply the time.
+    pub fn time(&self) -> Option<SystemTime> {
+        self.time
+    }
+}
+
+impl fmt::Debug for CapturedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CapturedMessage")
+            .field("direction", &self.direction)
+            .field("typ", &self.typ)
+            .field("bytes", &BsDebug(&self.bytes))
+            .field("time", &self.time)
+            .finish()
+    }
+}
+
+/// The per-connection record of handshake messages.
+pub(crate) struct HandshakeCapture {
+    time_provider: Arc<dyn TimeProvider>,
+    messages: Vec<CapturedMessage>,
+}
+
+impl HandshakeCapture {
+    pub(crate) fn new(time_provider: Arc<dyn TimeProvider>) -> Self {
+        Self {
+            time_provider,
+            messages: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, direction: MessageDirection, typ: HandshakeType, bytes: &[u8]) {
+        self.messages.push(CapturedMessage {
+            direction,
+            typ,
+            bytes: bytes.to_vec(),
+            time: self.time_provider.current_time(),
+        });
+    }
+
+    pub(crate) fn messages(&self) -> &[CapturedMessage] {
+        &self.messages
+    }
+}
+
+/// What a server sent during a handshake, decoded from the messages
+/// captured by a client connection.
+///
+/// This is intended for scanners and diagnostics.  Since the capture
+/// survives handshake failure, the report covers everything the server sent
+/// before the failure: in particular, its certificate chain is reported even
+/// if it could not be verified.
+///
+/// See [`ClientConnection::server_report`].
+///
+/// [`ClientConnection::server_report`]: crate::ClientConnection::server_report
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+pub struct ServerReport {
+    /// The protocol version the server selected.
+    pub version: Option<ProtocolVersion>,
+
+    /// The cipher suite the server selected.
+    pub cipher_suite: Option<CipherSuite>,
+
+    /// The key exchange group the server used.
+    pub kx_group: Option<NamedGroup>,
+
+    /// The group the server asked for in a TLS1.3 `HelloRetryRequest`, if
+    /// it sent one.
+    pub hello_retry_group: Option<NamedGroup>,
+
+    /// The protocol the server selected via ALPN.
+    pub alpn_protocol: Option<Vec<u8>>,
+
+    /// The certificate chain the server sent, end-entity certificate first.
+    pub cert_chain: Vec<Certificate>,
+
+    /// The OCSP response the server stapled to its certificate.
+    pub ocsp_response: Option<Vec<u8>>,
+
+    /// The encoded Signed Certificate Timestamps the server sent, from the
+    /// `signed_certificate_timestamp` extension.
+    pub sct_list: Vec<Vec<u8>>,
+
+    /// Whether the server asked for a client certificate.
+    pub client_auth_requested: bool,
+}
+
+impl ServerReport {
+    /// Decode a report from the messages captured by a client connection.
+    ///
+    /// Messages sent by the client, and messages that cannot be decoded,
+    /// are ignored.
+    pub fn from_capture(messages: &[CapturedMessage]) -> Self {
+        let mut report = Self::default();
+        for message in messages
+            .iter()
+            .filter(|message| message.direction == MessageDirection::Received)
+        {
+            let version = report
+                .version
+                .unwrap_or(ProtocolVersion::TLSv1_2);
+            if let Ok(parsed) =
+                HandshakeMessagePayload::read_version(&mut Reader::init(&message.bytes), version)
+            {
+                report.add(parsed.payload);
+            }
+        }
+        report
+    }
+
+    fn add(&mut self, payload: HandshakePayload) {
+        match payload {
+            HandshakePayload::HelloRetryRequest(hrr) => {
+                self.hello_retry_group = hrr.get_requested_key_share_group();
+                self.cipher_suite = Some(hrr.cipher_suite);
+            }
+            HandshakePayload::ServerHello(hello) => {
+                self.version = Some(
+                    hello
+                        .get_supported_versions()
+                        .unwrap_or(hello.legacy_version),
+                );
+                self.cipher_suite = Some(hello.cipher_suite);
+                self.kx_group = hello
+                    .get_key_share()
+                    .map(|share| share.group);
+                self.add_extensions(&hello);
+            }
+            HandshakePayload::EncryptedExtensions(extensions) => self.add_extensions(&extensions),
+            HandshakePayload::Certificate(chain) => self.cert_chain = chain,
+            HandshakePayload::CertificateTLS13(certificate) => {
+                let ocsp_response = certificate.get_end_entity_ocsp();
+                if !ocsp_response.is_empty() {
+                    self.ocsp_response = Some(ocsp_response);
+                }
+                if let Some(scts) = certificate.get_end_entity_scts() {
+                    self.sct_list = scts
+                        .into_iter()
+                        .map(|sct| sct.0)
+                        .collect();
+                }
+                self.cert_chain = certificate.convert();
+            }
+            HandshakePayload::CertificateStatus(status) => {
+                self.ocsp_response = Some(status.ocsp_response.0);
+            }
+            HandshakePayload::ServerKeyExchange(kx) => {
+                if let Some(ecdhe) = kx.unwrap_given_kxa(KeyExchangeAlgorithm::ECDHE) {
+                    self.kx_group = Some(ecdhe.params.curve_params.named_group);
+                }
+            }
+            HandshakePayload::CertificateRequest(_)
+            | HandshakePayload::CertificateRequestTLS13(_) => self.client_auth_requested = true,
+            _ => {}
+        }
+    }
+
+    fn add_extensions(&mut self, extensions: &impl HasServerExtensions) {
+        if let Some(protocol) = extensions.get_alpn_protocol() {
+            self.alpn_protocol = Some(protocol.to_vec());
+        }
+        if let Some(scts) = extensions.get_sct_list() {
+            self.sct_list = scts
+                .iter()
+                .map(|sct| sct.0.clone())
+                .collect();
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use crate::enums::HandshakeType;
+use crate::error::{CertificateError, Error};
+use crate::key::Certificate;
+use crate::msgs::codec::{u24, Codec, Reader};
+
+/// Limits on the certificate chain a peer may send.
+///
+/// A peer's `Certificate` message is checked against these limits as it
+/// arrives, so an oversized chain is rejected as soon as it is apparent,
+/// without waiting for the rest of it.  This is early rejection only: the
+/// message is still buffered as it arrives, which is why it is always
+/// limited to 64KB.  Each limit is `None` (unlimited) by default.
+///
+/// Failures are reported as [`CertificateError::ChainExceedsLimits`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CertificateChainLimits {
+    /// How many certificates the chain may contain, including the
+    /// end-entity certificate.
+    pub max_certificates: Option<usize>,
+
+    /// How large any one certificate in the chain may be, in bytes.
+    pub max_certificate_size: Option<usize>,
+}
+
+impl CertificateChainLimits {
+    /// Check that the certificate at `index` in a chain, of `len` bytes,
+    /// is within the limits.
+    fn check(&self, index: usize, len: usize) -> Result<(), Error> {
+        let too_many = matches!(self.max_certificates, Some(max) if index >= max);
+        let too_large = matches!(self.max_certificate_size, Some(max) if len > max);
+        match too_many || too_large {
+            true => Err(CertificateError::ChainExceedsLimits.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Checks the certificates in a peer's `Certificate` message one at a time,
+/// as the message arrives.
+///
+/// Each certificate is checked against the [`CertificateChainLimits`] and
+/// given to `check` exactly once, in order, whether it is seen first in a
+/// partly-received message or in the complete one.
+///
+/// Parsing resumes after the last complete certificate entry, so each
+/// fragment only costs the bytes that are new, plus any incomplete entry.
+#[derive(Debug, Default)]
+pub(crate) struct CertificateStream {
+    /// How many certificates have been given to `check` so far.
+    checked: usize,
+    /// How many certificate entries have been completely parsed.
+    parsed: usize,
+    /// Where the entry after the `parsed` ones starts in the message, once
+    /// the message header has been parsed.
+    next_entry: Option<usize>,
+}
+
+impl CertificateStream {
+    /// Check the certificates in `received`, which is the start of a
+    /// handshake message (including its header) that is still arriving.
+    ///
+    /// Each call must be given at least as much of the same message as the
+    /// last.  `tls13` says whether the message uses the TLS1.3 encoding.
+    /// Anything other than a `Certificate` message is ignored.
+    pub(crate) fn check_partial(
+        &mut self,
+        received: &[u8],
+        tls13: bool,
+        limits: &CertificateChainLimits,
+        check: impl Fn(usize, &Certificate) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let start = match self.next_entry {
+            Some(start) => start,
+            None => match first_entry(received, tls13) {
+                Some(start) => start,
+                None => return Ok(()),
+            },
+        };
+        self.next_entry = Some(start);
+
+        let mut r = match received.get(start..) {
+            Some(rest) => Reader::init(rest),
+            None => return Ok(()),
+        };
+        while let Ok(len) = u24::read(&mut r) {
+            let len = usize::from(len);
+            limits.check(self.parsed, len)?;
+
+            let cert = match r.take(len) {
+                Some(cert) => cert,
+                None => break,
+            };
+            if self.parsed >= self.checked {
+                check(self.parsed, &Certificate(cert.to_vec()))?;
+                self.checked = self.parsed + 1;
+            }
+
+            if tls13 {
+                let extensions_len = match u16::read(&mut r) {
+                    Ok(len) => usize::from(len),
+                    Err(_) => break,
+                };
+                if r.take(extensions_len).is_none() {
+                    break;
+                }
+            }
+            self.parsed += 1;
+            self.next_entry = Some(start + r.used());
+        }
+
+        Ok(())
+    }
+
+    /// Check the certificates of `chain`, the complete chain, that were not
+    /// already checked while it was arriving.
+    pub(crate) fn finish(
+        &mut self,
+        chain: &[Certificate],
+        limits: &CertificateChainLimits,
+        check: impl Fn(usize, &Certificate) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        for (index, cert) in chain.iter().enumerate() {
+            limits.check(index, cert.0.len())?;
+            if index >= self.checked {
+                check(index, cert)?;
+            }
+        }
+        self.checked = chain.len();
+        Ok(())
+    }
+}
+
+/// Where the first certificate entry starts in `received`, the start of a
+/// `Certificate` message, if all of the message before it has arrived.
+fn first_entry(received: &[u8], tls13: bool) -> Option<usize> {
+    let mut r = Reader::init(received);
+    let header = r.take(4)?;
+    if HandshakeType::from(header[0]) != HandshakeType::Certificate {
+        return None;
+    }
+
+    if tls13 {
+        let context_len = usize::from(r.take(1)?[0]);
+        r.take(context_len)?;
+    }
+
+    // The length of the whole list is not needed.
+    r.take(3)?;
+    Some(r.used())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgs::handshake::{
+        CertificateEntry, CertificatePayloadTLS13, HandshakeMessagePayload, HandshakePayload,
+    };
+    use std::sync::Mutex;
+
+    fn chain() -> Vec<Certificate> {
+        vec![
+            Certificate(vec![1u8; 100]),
+            Certificate(vec![2u8; 200]),
+            Certificate(vec![3u8; 300]),
+        ]
+    }
+
+    fn encoding(tls13: bool) -> Vec<u8> {
+        let payload = match tls13 {
+            true => HandshakePayload::CertificateTLS13(CertificatePayloadTLS13::new(
+                chain()
+                    .into_iter()
+                    .map(CertificateEntry::new)
+                    .collect(),
+            )),
+            false => HandshakePayload::Certificate(chain()),
+        };
+        let message = HandshakeMessagePayload {
+            typ: HandshakeType::Certificate,
+            payload,
+        };
+        let mut buf = Vec::new();
+        message.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn certificates_are_checked_once_as_they_arrive() {
+        for tls13 in [false, true] {
+            let encoded = encoding(tls13);
+            let seen = Mutex::new(Vec::new());
+            let check = |index: usize, cert: &Certificate| {
+                seen.lock()
+                    .unwrap()
+                    .push((index, cert.0.len()));
+                Ok(())
+            };
+
+            // Stop before the last certificate is complete.
+            let mut stream = CertificateStream::default();
+            for len in 0..encoded.len() - 10 {
+                stream
+                    .check_partial(
+                        &encoded[..len],
+                        tls13,
+                        &CertificateChainLimits::default(),
+                        check,
+                    )
+                    .unwrap();
+            }
+            assert_eq!(*seen.lock().unwrap(), vec![(0, 100), (1, 200)]);
+
+            stream
+                .finish(&chain(), &CertificateChainLimits::default(), check)
+                .unwrap();
+            assert_eq!(*seen.lock().unwrap(), vec![(0, 100), (1, 200), (2, 300)]);
+        }
+    }
+
+    #[test]
+    fn parsing_resumes_after_complete_entries() {
+        let encoded = encoding(false);
+        let accept = |_: usize, _: &Certificate| Ok(());
+        let mut stream = CertificateStream::default();
+
+        // The first certificate, and half of the second.
+        let first_end = 4 + 3 + 3 + 100;
+        stream
+            .check_partial(
+                &encoded[..first_end + 100],
+                false,
+                &CertificateChainLimits::default(),
+                accept,
+            )
+            .unwrap();
+        assert_eq!(stream.parsed, 1);
+        assert_eq!(stream.next_entry, Some(first_end));
+
+        // Bytes before `next_entry` are not looked at again.
+        let mut altered = encoded.clone();
+        altered[4 + 3] = 0xff;
+        stream
+            .check_partial(&altered, false, &CertificateChainLimits::default(), accept)
+            .unwrap();
+        assert_eq!(stream.parsed, 3);
+        assert_eq!(stream.next_entry, Some(encoded.len()));
+    }
+
+    #[test]
+    fn limits_apply_before_certificate_arrives() {
+        let encoded = encoding(false);
+        let accept = |_: usize, _: &Certificate| Ok(());
+
+        let limits = CertificateChainLimits {
+            max_certificates: Some(1),
+            ..Default::default()
+        };
+        // Only the header of the second certificate has arrived.
+        let partial = &encoded[..4 + 3 + 3 + 100 + 3];
+        assert_eq!(
+            CertificateStream::default().check_partial(partial, false, &limits, accept),
+            Err(CertificateError::ChainExceedsLimits.into())
+        );
+
+        let limits = CertificateChainLimits {
+            max_certificate_size: Some(150),
+            ..Default::default()
+        };
+        assert_eq!(
+            CertificateStream::default().check_partial(partial, false, &limits, accept),
+            Err(CertificateError::ChainExceedsLimits.into())
+        );
+        assert_eq!(
+            CertificateStream::default().finish(&chain()[..1], &limits, accept),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn other_messages_are_ignored() {
+        let mut stream = CertificateStream::default();
+        let fail = |_: usize, _: &Certificate| Err(Error::General("called".into()));
+        let finished = [HandshakeType::Finished.get_u8(), 0, 0, 10, 0, 0, 1, 0, 0, 0];
+        assert_eq!(
+            stream.check_partial(&finished, false, &CertificateChainLimits::default(), fail),
+            Ok(())
+        );
+    }
+}
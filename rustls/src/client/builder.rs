@@ -1,13 +1,19 @@
 use crate::builder::{ConfigBuilder, WantsVerifier};
+use crate::cert_stream::CertificateChainLimits;
 use crate::client::handy;
 use crate::client::{ClientConfig, ResolvesClientCert};
+use crate::common_state::{DuplicateExtensionPolicy, RecordLimits, RenegotiationPolicy};
 use crate::crypto::{CryptoProvider, KeyExchange};
+use crate::enums::SignatureScheme;
 use crate::error::Error;
 use crate::key_log::NoKeyLog;
 use crate::suites::SupportedCipherSuite;
+use crate::time_provider::DefaultTimeProvider;
 use crate::verify;
 use crate::{anchors, key, versions};
 
+#[cfg(feature = "dangerous_configuration")]
+use super::client_conn::danger::{NoServerVerification, Unverified};
 use super::client_conn::Resumption;
 
 use alloc::sync::Arc;
@@ -24,12 +30,33 @@ impl<C: CryptoProvider> ConfigBuilder<ClientConfig<C>, WantsVerifier<C>> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
                 verifier: Arc::new(verify::WebPkiVerifier::new(root_store)),
             },
             side: PhantomData,
         }
     }
 
+    /// Choose how to verify server certificates, using trust anchors that
+    /// can be replaced while the config is in use.
+    ///
+    /// See [`anchors::SharedRootCertStore`].
+    pub fn with_shared_root_certificates(
+        self,
+        root_store: anchors::SharedRootCertStore,
+    ) -> ConfigBuilder<ClientConfig<C>, WantsClientCert<C>> {
+        ConfigBuilder {
+            state: WantsClientCert {
+                cipher_suites: self.state.cipher_suites,
+                kx_groups: self.state.kx_groups,
+                versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
+                verifier: Arc::new(verify::WebPkiVerifier::new_with_shared_roots(root_store)),
+            },
+            side: PhantomData,
+        }
+    }
+
     #[cfg(feature = "dangerous_configuration")]
     /// Set a custom certificate verifier.
     pub fn with_custom_certificate_verifier(
@@ -41,11 +68,42 @@ impl<C: CryptoProvider> ConfigBuilder<ClientConfig<C>, WantsVerifier<C>> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
                 verifier,
             },
             side: PhantomData,
         }
     }
+
+    #[cfg(feature = "dangerous_configuration")]
+    /// Do not verify server certificates at all, nor the server's
+    /// handshake signatures.
+    ///
+    /// This is for opportunistic encryption, such as SMTP's STARTTLS, where
+    /// a server that cannot be authenticated is still better talked to
+    /// over TLS than in the clear.  Connections made this way are secure
+    /// against passive eavesdroppers only: **anyone able to intercept them
+    /// can impersonate the server**.
+    ///
+    /// The config that is built is wrapped in [`Unverified`], as are the
+    /// connections made from it, so that code expecting authenticated
+    /// connections cannot be given one of these by mistake.
+    ///
+    /// [`Unverified`]: crate::client::Unverified
+    pub fn with_dangerous_no_verifier(
+        self,
+    ) -> ConfigBuilder<ClientConfig<C>, Unverified<WantsClientCert<C>>> {
+        ConfigBuilder {
+            state: Unverified::new(WantsClientCert {
+                cipher_suites: self.state.cipher_suites,
+                kx_groups: self.state.kx_groups,
+                versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
+                verifier: Arc::new(NoServerVerification),
+            }),
+            side: PhantomData,
+        }
+    }
 }
 
 /// A config builder state where the caller needs to supply whether and how to provide a client
@@ -57,6 +115,7 @@ pub struct WantsClientCert<C: CryptoProvider> {
     cipher_suites: Vec<SupportedCipherSuite>,
     kx_groups: Vec<&'static <<C as CryptoProvider>::KeyExchange as KeyExchange>::SupportedGroup>,
     versions: versions::EnabledVersions,
+    signature_schemes: Option<Vec<SignatureScheme>>,
     verifier: Arc<dyn verify::ServerCertVerifier>,
 }
 
@@ -106,18 +165,76 @@ impl<C: CryptoProvider> ConfigBuilder<ClientConfig<C>, WantsClientCert<C>> {
         ClientConfig {
             cipher_suites: self.state.cipher_suites,
             kx_groups: self.state.kx_groups,
+            key_share_pool: None,
             alpn_protocols: Vec::new(),
             resumption: Resumption::default(),
             max_fragment_size: None,
             client_auth_cert_resolver,
             versions: self.state.versions,
+            signature_schemes: self.state.signature_schemes,
             enable_sni: true,
+            certificate_authorities: Vec::new(),
             verifier: self.state.verifier,
+            known_intermediates: Arc::new(anchors::KnownIntermediates::empty()),
             key_log: Arc::new(NoKeyLog {}),
+            time_provider: Arc::new(DefaultTimeProvider),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: false,
             enable_early_data: false,
+            send_fallback_scsv: false,
+            enable_middlebox_compat: true,
+            pad_client_hello_to: None,
+            capture_handshake: false,
+            handshake_timeout: None,
+            require_ems: false,
+            renegotiation: RenegotiationPolicy::Reject,
+            record_limits: RecordLimits::default(),
+            cert_chain_limits: CertificateChainLimits::default(),
+            duplicate_extensions: DuplicateExtensionPolicy::default(),
+            require_ocsp_staple: None,
+            memory_budget: None,
+            write_pacer: None,
+            offload_verification: false,
             provider: PhantomData,
         }
     }
 }
+
+#[cfg(feature = "dangerous_configuration")]
+impl<C: CryptoProvider> ConfigBuilder<ClientConfig<C>, Unverified<WantsClientCert<C>>> {
+    /// Like [`ConfigBuilder::with_client_auth_cert`], for a config that does
+    /// not verify servers.
+    pub fn with_client_auth_cert(
+        self,
+        cert_chain: Vec<key::Certificate>,
+        key_der: key::PrivateKey,
+    ) -> Result<Unverified<ClientConfig<C>>, Error> {
+        self.unmarked()
+            .with_client_auth_cert(cert_chain, key_der)
+            .map(Unverified::new)
+    }
+
+    /// Do not support client auth.
+    pub fn with_no_client_auth(self) -> Unverified<ClientConfig<C>> {
+        Unverified::new(self.unmarked().with_no_client_auth())
+    }
+
+    /// Sets a custom [`ResolvesClientCert`].
+    pub fn with_client_cert_resolver(
+        self,
+        client_auth_cert_resolver: Arc<dyn ResolvesClientCert>,
+    ) -> Unverified<ClientConfig<C>> {
+        Unverified::new(
+            self.unmarked()
+                .with_client_cert_resolver(client_auth_cert_resolver),
+        )
+    }
+
+    /// The unmarked builder, to finish building the config with.
+    fn unmarked(self) -> ConfigBuilder<ClientConfig<C>, WantsClientCert<C>> {
+        ConfigBuilder {
+            state: self.state.into_inner(),
+            side: PhantomData,
+        }
+    }
+}
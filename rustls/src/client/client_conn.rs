@@ -1,29 +1,47 @@
+use crate::anchors::KnownIntermediates;
+use crate::budget::MemoryBudget;
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
-use crate::common_state::{CommonState, Protocol, Side};
+use crate::capture::ServerReport;
+use crate::cert_stream::CertificateChainLimits;
+use crate::common_state::{
+    CommonState, DuplicateExtensionPolicy, IoState, Negotiated, Protocol, RecordLimits,
+    RenegotiationPolicy, Side,
+};
 use crate::conn::{ConnectionCommon, ConnectionCore};
-use crate::crypto::{CryptoProvider, KeyExchange};
+use crate::crypto::{CryptoProvider, KeyExchange, KeySharePool, SupportedGroup};
 use crate::dns_name::{DnsName, DnsNameRef, InvalidDnsNameError};
 use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
 use crate::error::Error;
+#[cfg(feature = "dangerous_configuration")]
+use crate::handover::{self, TrafficSecrets};
+use crate::key;
 #[cfg(feature = "logging")]
 use crate::log::trace;
 use crate::msgs::enums::NamedGroup;
-use crate::msgs::handshake::ClientExtension;
+use crate::msgs::handshake::{ClientExtension, SctList};
 use crate::msgs::persist;
+use crate::ocsp::OcspStaplePolicy;
+use crate::pacing::WritePacer;
+use crate::sct::{self, SctSource, SignedCertificateTimestamp};
 use crate::sign;
 use crate::suites::SupportedCipherSuite;
+use crate::time_provider::TimeProvider;
 use crate::verify;
 use crate::versions;
+use crate::DistinguishedName;
 #[cfg(feature = "secret_extraction")]
 use crate::ExtractedSecrets;
 use crate::KeyLog;
 
-use super::handy::{ClientSessionMemoryCache, NoClientSessionStorage};
+use super::handy::{ClientSessionMemoryCache, NoClientSessionStorage, NoResumptionObserver};
 use super::hs;
+use super::offload::{CompletedVerification, PendingVerification};
 
 use alloc::sync::Arc;
+use core::any::Any;
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
+use core::time::Duration;
 use core::{fmt, mem};
 use std::io;
 use std::net::IpAddr;
@@ -81,6 +99,34 @@ pub trait ClientSessionStore: Send + Sync {
         &self,
         server_name: &ServerName,
     ) -> Option<persist::Tls13ClientSessionValue>;
+
+    /// Return everything stored, for [`ClientConfig::export_sessions`].
+    ///
+    /// TLS1.3 tickets must be removed from the store as they are returned,
+    /// so that each is still used at most once.
+    ///
+    /// The default implementation returns nothing.
+    fn export_sessions(&self) -> Vec<StoredSessions> {
+        Vec::new()
+    }
+}
+
+/// Everything a [`ClientSessionStore`] remembers about one server.
+///
+/// See [`ClientConfig::export_sessions`].
+#[derive(Debug)]
+pub struct StoredSessions {
+    /// The server the sessions are for.
+    pub server_name: ServerName,
+
+    /// The value last given to [`ClientSessionStore::set_kx_hint`].
+    pub kx_hint: Option<NamedGroup>,
+
+    /// The TLS1.2 session, if any.
+    pub tls12_session: Option<persist::Tls12ClientSessionValue>,
+
+    /// The TLS1.3 tickets, oldest first.
+    pub tls13_tickets: Vec<persist::Tls13ClientSessionValue>,
 }
 
 /// A trait for the ability to choose a certificate chain and
@@ -103,10 +149,117 @@ pub trait ResolvesClientCert: Send + Sync {
         sigschemes: &[SignatureScheme],
     ) -> Option<Arc<sign::CertifiedKey>>;
 
+    /// Like [`ResolvesClientCert::resolve`], but also given the value
+    /// attached to the connection with [`CommonState::set_user_data`].
+    ///
+    /// This is what rustls calls.  The default implementation ignores
+    /// `user_data` and calls [`ResolvesClientCert::resolve`].
+    fn resolve_with_user_data(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+        user_data: Option<&(dyn Any + Send + Sync)>,
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        let _ = user_data;
+        self.resolve(acceptable_issuers, sigschemes)
+    }
+
+    /// Decide what to do when the server asked for a certificate but none
+    /// can be sent.
+    ///
+    /// This is called when [`ResolvesClientCert::resolve_with_user_data`]
+    /// returned `None`, or returned a key that cannot sign with any of
+    /// `sigschemes`.  The other arguments are as given to that method.
+    ///
+    /// Return `Ok(())` to continue the handshake without client
+    /// authentication, or an error to fail the handshake with it instead;
+    /// a `handshake_failure` alert is sent to the server.  The default
+    /// implementation continues.
+    fn no_client_cert(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+        user_data: Option<&(dyn Any + Send + Sync)>,
+    ) -> Result<(), Error> {
+        let _ = (acceptable_issuers, sigschemes, user_data);
+        Ok(())
+    }
+
     /// Return true if any certificates at all are available.
     fn has_certs(&self) -> bool;
 }
 
+/// Receives notifications about session resumption on client connections.
+///
+/// This is intended for monitoring: for example, measuring how often
+/// sessions are offered and how often servers accept them.  Every method
+/// has a default implementation that does nothing.  They are called from
+/// within the handshake, so should return promptly.
+///
+/// Install one with [`Resumption::observer`].
+pub trait ObservesClientResumption: Send + Sync {
+    /// A session received from the server was put in the [`ClientSessionStore`].
+    ///
+    /// For TLS1.3 this happens once per `NewSessionTicket` message.
+    fn session_stored(&self, _server_name: &ServerName, _version: ProtocolVersion) {}
+
+    /// A new connection did not offer to resume a session.
+    fn resumption_not_attempted(&self, _server_name: &ServerName, _reason: ResumptionNotAttempted) {
+    }
+
+    /// A new connection offered to resume a session of the given protocol version.
+    ///
+    /// This is followed by exactly one call to
+    /// [`ObservesClientResumption::resumption_accepted`] or
+    /// [`ObservesClientResumption::resumption_rejected`], unless the
+    /// handshake fails before the server's `ServerHello` is processed.
+    fn resumption_attempted(&self, _server_name: &ServerName, _version: ProtocolVersion) {}
+
+    /// The server accepted the offered session.
+    fn resumption_accepted(&self, _server_name: &ServerName, _version: ProtocolVersion) {}
+
+    /// The server did not resume the offered session, so a full handshake follows.
+    fn resumption_rejected(&self, _server_name: &ServerName, _reason: ResumptionRejected) {}
+}
+
+/// Why a client connection did not offer to resume a session.
+///
+/// See [`ObservesClientResumption::resumption_not_attempted`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResumptionNotAttempted {
+    /// The [`ClientSessionStore`] had no session for the server.
+    NoStoredSession,
+    /// The stored session had expired, or was older than
+    /// [`Resumption::max_session_age`].
+    Expired,
+    /// The stored session's ALPN protocol is no longer configured.
+    ///
+    /// See [`Resumption::require_alpn_match`].
+    AlpnMismatch,
+    /// The stored TLS 1.2 session would be resumed by a mechanism that
+    /// [`Resumption::tls12_resumption`] does not allow.
+    Tls12MechanismDisabled,
+}
+
+/// Why the server did not resume an offered session.
+///
+/// See [`ObservesClientResumption::resumption_rejected`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResumptionRejected {
+    /// The server chose not to resume.  It may not have recognised the
+    /// ticket or session id, for example because it was issued by another
+    /// server or has since been forgotten.
+    ServerDeclined,
+    /// The server negotiated a different protocol version from the one
+    /// the session was established with.
+    VersionMismatch,
+    /// The server selected a cipher suite that cannot be used to resume
+    /// the session.
+    IncompatibleCipherSuite,
+}
+
 /// Common configuration for (typically) all connections made by
 /// a program.
 ///
@@ -133,6 +286,13 @@ pub struct ClientConfig<C: CryptoProvider> {
     /// and in TLS1.3 a key share for it is sent in the client hello.
     pub(super) kx_groups: Vec<&'static <C::KeyExchange as KeyExchange>::SupportedGroup>,
 
+    /// Key shares generated ahead of time, to take from instead of
+    /// generating one during each handshake.  Shares are only taken for
+    /// groups in `kx_groups`.
+    ///
+    /// The default is `None`.
+    pub key_share_pool: Option<Arc<KeySharePool<C::KeyExchange>>>,
+
     /// Which ALPN protocols we include in our client hello.
     /// If empty, no ALPN extension is sent.
     pub alpn_protocols: Vec<Vec<u8>>,
@@ -156,19 +316,57 @@ pub struct ClientConfig<C: CryptoProvider> {
     /// is all supported versions.
     pub(super) versions: versions::EnabledVersions,
 
+    /// The signature schemes allowed with peers, or `None` if any scheme
+    /// supported by the verifier and signing keys may be used.
+    ///
+    /// See [`ConfigBuilder::with_signature_schemes`].
+    ///
+    /// [`ConfigBuilder::with_signature_schemes`]: crate::ConfigBuilder::with_signature_schemes
+    pub(super) signature_schemes: Option<Vec<SignatureScheme>>,
+
     /// Whether to send the Server Name Indication (SNI) extension
     /// during the client handshake.
     ///
     /// The default is true.
     pub enable_sni: bool,
 
+    /// The distinguished names of the certificate authorities we trust,
+    /// sent to the server in the TLS1.3 `certificate_authorities` extension.
+    ///
+    /// Servers with several certificate chains can use these to choose one
+    /// that we can validate.  [`RootCertStore::subjects`] gives the names
+    /// of the roots in a store.
+    ///
+    /// The default is empty, and no extension is sent: the names of a large
+    /// set of roots can make the `ClientHello` very large.
+    ///
+    /// [`RootCertStore::subjects`]: crate::RootCertStore::subjects
+    pub certificate_authorities: Vec<DistinguishedName>,
+
     /// How to verify the server certificate chain.
     pub(super) verifier: Arc<dyn verify::ServerCertVerifier>,
 
+    /// Intermediate certificates to complete the chains of servers that do
+    /// not send all of theirs.
+    ///
+    /// Those that may issue a certificate the server sent, but whose subject
+    /// is not that of any certificate it sent, are added to the end of the
+    /// intermediates given to the certificate verifier.  This avoids
+    /// spurious [`CertificateError::UnknownIssuer`] failures.
+    ///
+    /// The default is empty.
+    ///
+    /// [`CertificateError::UnknownIssuer`]: crate::CertificateError::UnknownIssuer
+    pub known_intermediates: Arc<KnownIntermediates>,
+
     /// How to output key material for debugging.  The default
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
 
+    /// Where to get the current time from.  The default asks the
+    /// operating system.
+    pub time_provider: Arc<dyn TimeProvider>,
+
     /// Allows traffic secrets to be extracted after the handshake,
     /// e.g. for kTLS setup.
     #[cfg(feature = "secret_extraction")]
@@ -181,6 +379,164 @@ pub struct ClientConfig<C: CryptoProvider> {
     /// The default is false.
     pub enable_early_data: bool,
 
+    /// Whether to include `TLS_FALLBACK_SCSV` in the client hello, as
+    /// described in [RFC 7507].
+    ///
+    /// Set this only on the config used for a retry that offers a
+    /// lower maximum protocol version than a previous, failed attempt
+    /// to the same server.  A server that supports a higher version
+    /// will then abort the handshake with an `inappropriate_fallback`
+    /// alert, because the first attempt must have been interfered with.
+    ///
+    /// The default is false.
+    ///
+    /// [RFC 7507]: https://datatracker.ietf.org/doc/html/rfc7507
+    pub send_fallback_scsv: bool,
+
+    /// Whether to use TLS 1.3 "middlebox compatibility mode", described in
+    /// [RFC 8446 appendix D.4].  This makes the handshake look more like a
+    /// TLS 1.2 resumption to middleboxes that inspect it, by sending a
+    /// non-empty legacy session ID and dummy `ChangeCipherSpec` messages.
+    ///
+    /// Turning this off saves a few dozen bytes per handshake, but may
+    /// cause handshake failures on networks with such middleboxes.  It has
+    /// no effect for QUIC, which never uses compatibility mode.
+    ///
+    /// The default is true.
+    ///
+    /// [RFC 8446 appendix D.4]: https://datatracker.ietf.org/doc/html/rfc8446#appendix-D.4
+    pub enable_middlebox_compat: bool,
+
+    /// If set, client hellos shorter than this many bytes are lengthened to
+    /// it with the padding extension described in [RFC 7685].
+    ///
+    /// The length counted is that of the handshake message, including its
+    /// four byte header, but not the record header.  A hello that is up to
+    /// three bytes short is overpadded by a few bytes, because that is the
+    /// size of an empty padding extension.  Hellos that are already long
+    /// enough are left as they are.  Hellos longer than the maximum
+    /// handshake message size of 64KiB cannot be sent, and connection
+    /// creation fails instead.
+    ///
+    /// A common choice is 512, which avoids a bug in some middleboxes that
+    /// hang on hellos between 256 and 511 bytes long.  It also makes
+    /// hellos from differently-configured clients look more alike.
+    ///
+    /// The default is `None`.
+    ///
+    /// [RFC 7685]: https://datatracker.ietf.org/doc/html/rfc7685
+    pub pad_client_hello_to: Option<usize>,
+
+    /// Whether connections made with this config record every handshake
+    /// message they send and receive, for later retrieval with
+    /// [`CommonState::handshake_capture`].
+    ///
+    /// This is intended for debugging: the record can be decoded and attached
+    /// to a bug report without needing a packet capture and key log.  Note
+    /// that it contains everything the peer sent, including certificates.
+    /// [`ClientConnection::server_report`] decodes the server's messages.
+    ///
+    /// The default is false.
+    ///
+    /// [`CommonState::handshake_capture`]: crate::CommonState::handshake_capture
+    pub capture_handshake: bool,
+
+    /// How long a handshake may take, from when the connection is created.
+    ///
+    /// A connection still handshaking after this long fails with
+    /// [`Error::HandshakeTimedOut`] the next time
+    /// [`Connection::process_new_packets`] is called.  Since nothing happens
+    /// until then, use [`CommonState::handshake_deadline`] to know when to
+    /// call it if the peer has gone quiet.
+    ///
+    /// The default is `None`: handshakes may take any amount of time.
+    ///
+    /// [`Error::HandshakeTimedOut`]: crate::Error::HandshakeTimedOut
+    /// [`Connection::process_new_packets`]: crate::Connection::process_new_packets
+    /// [`CommonState::handshake_deadline`]: crate::CommonState::handshake_deadline
+    pub handshake_timeout: Option<Duration>,
+
+    /// Whether to refuse TLS1.2 handshakes with servers that do not
+    /// negotiate the extended master secret extension of [RFC 7627].
+    ///
+    /// Without it, a TLS1.2 session's master secret is not bound to its
+    /// handshake, which allows the "triple handshake" attack.  This has no
+    /// effect on TLS1.3, whose key schedule always includes the handshake.
+    /// Use [`CommonState::extended_master_secret`] to see whether a
+    /// connection used it.
+    ///
+    /// The default is false.
+    ///
+    /// [RFC 7627]: https://datatracker.ietf.org/doc/html/rfc7627
+    /// [`CommonState::extended_master_secret`]: crate::CommonState::extended_master_secret
+    pub require_ems: bool,
+
+    /// How to respond when the server asks to renegotiate an established
+    /// TLS1.2 connection.  Use [`CommonState::secure_renegotiation`] to see
+    /// whether the server supports secure renegotiation.
+    ///
+    /// The default is [`RenegotiationPolicy::Reject`].
+    ///
+    /// [`CommonState::secure_renegotiation`]: crate::CommonState::secure_renegotiation
+    pub renegotiation: RenegotiationPolicy,
+
+    /// Limits on records from the peer that do not move the connection
+    /// forward, such as warning alerts and empty records.
+    ///
+    /// The default has no limits; [`RecordLimits::hardened`] is a good
+    /// choice when peers are untrusted.
+    pub record_limits: RecordLimits,
+
+    /// Limits on the certificate chain the server may send, which are
+    /// enforced as the chain arrives.
+    ///
+    /// The default has no limits beyond the size of a handshake message.
+    pub cert_chain_limits: CertificateChainLimits,
+
+    /// What to do if the server repeats an extension in a handshake message.
+    ///
+    /// The default is [`DuplicateExtensionPolicy::Reject`].
+    pub duplicate_extensions: DuplicateExtensionPolicy,
+
+    /// Requires the server to staple a valid, fresh OCSP response for its
+    /// certificate; see [`OcspStaplePolicy`].
+    ///
+    /// The default is `None`: any stapled response is only given to the
+    /// `ServerCertVerifier`, and is not required.
+    pub require_ocsp_staple: Option<OcspStaplePolicy>,
+
+    /// A cap on memory used by connections made from this config (and any
+    /// others sharing the same budget); see [`MemoryBudget`].
+    ///
+    /// The default is `None`: memory use is not tracked.
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+
+    /// Paces how much application data connections made from this config
+    /// encrypt between calls to [`Connection::write_tls`]; see [`WritePacer`].
+    ///
+    /// The default is `None`: data is encrypted as fast as it is written,
+    /// up to the buffer limit.
+    ///
+    /// [`Connection::write_tls`]: crate::Connection::write_tls
+    pub write_pacer: Option<Arc<dyn WritePacer>>,
+
+    /// Whether to leave verification of the server to the application.
+    ///
+    /// Verifying the server's certificate chain and handshake signature can
+    /// take a while, and normally happens inside
+    /// [`Connection::process_new_packets`].  When this is true, the
+    /// connection instead stops there and yields a [`PendingVerification`]
+    /// from [`ClientConnection::take_pending_verification`], which may be
+    /// run elsewhere (for example on a worker pool) so the thread doing IO
+    /// is not blocked.  Note that the verifier is then not given the
+    /// connection's [`CommonState::user_data`].
+    ///
+    /// The default is false.
+    ///
+    /// [`Connection::process_new_packets`]: crate::Connection::process_new_packets
+    /// [`CommonState::user_data`]: crate::CommonState::user_data
+    pub offload_verification: bool,
+
     pub(crate) provider: PhantomData<C>,
 }
 
@@ -198,6 +554,22 @@ pub enum Tls12Resumption {
     ///
     /// [^1]: <https://words.filippo.io/we-need-to-talk-about-session-tickets/>
     SessionIdOrTickets,
+    /// Support 1.2 resumption using RFC 5077 tickets only.
+    ///
+    /// Session ids from the server are not stored or offered, so the server
+    /// need not keep any state to resume the session.
+    TicketsOnly,
+}
+
+impl Tls12Resumption {
+    #[cfg(feature = "tls12")]
+    pub(super) fn session_ids(self) -> bool {
+        matches!(self, Self::SessionIdOnly | Self::SessionIdOrTickets)
+    }
+
+    pub(super) fn tickets(self) -> bool {
+        matches!(self, Self::SessionIdOrTickets | Self::TicketsOnly)
+    }
 }
 
 impl<C: CryptoProvider> Clone for ClientConfig<C> {
@@ -205,17 +577,36 @@ impl<C: CryptoProvider> Clone for ClientConfig<C> {
         Self {
             cipher_suites: self.cipher_suites.clone(),
             kx_groups: self.kx_groups.clone(),
+            key_share_pool: self.key_share_pool.clone(),
             resumption: self.resumption.clone(),
             alpn_protocols: self.alpn_protocols.clone(),
             max_fragment_size: self.max_fragment_size,
             client_auth_cert_resolver: Arc::clone(&self.client_auth_cert_resolver),
             versions: self.versions,
+            signature_schemes: self.signature_schemes.clone(),
             enable_sni: self.enable_sni,
+            certificate_authorities: self.certificate_authorities.clone(),
             verifier: Arc::clone(&self.verifier),
+            known_intermediates: Arc::clone(&self.known_intermediates),
             key_log: Arc::clone(&self.key_log),
+            time_provider: Arc::clone(&self.time_provider),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: self.enable_secret_extraction,
             enable_early_data: self.enable_early_data,
+            send_fallback_scsv: self.send_fallback_scsv,
+            enable_middlebox_compat: self.enable_middlebox_compat,
+            pad_client_hello_to: self.pad_client_hello_to,
+            capture_handshake: self.capture_handshake,
+            handshake_timeout: self.handshake_timeout,
+            require_ems: self.require_ems,
+            renegotiation: self.renegotiation,
+            record_limits: self.record_limits,
+            cert_chain_limits: self.cert_chain_limits,
+            duplicate_extensions: self.duplicate_extensions.clone(),
+            require_ocsp_staple: self.require_ocsp_staple,
+            memory_budget: self.memory_budget.clone(),
+            write_pacer: self.write_pacer.clone(),
+            offload_verification: self.offload_verification,
             provider: PhantomData,
         }
     }
@@ -228,7 +619,20 @@ impl<C: CryptoProvider> fmt::Debug for ClientConfig<C> {
             .field("resumption", &self.resumption)
             .field("max_fragment_size", &self.max_fragment_size)
             .field("enable_sni", &self.enable_sni)
+            .field("certificate_authorities", &self.certificate_authorities)
             .field("enable_early_data", &self.enable_early_data)
+            .field("send_fallback_scsv", &self.send_fallback_scsv)
+            .field("enable_middlebox_compat", &self.enable_middlebox_compat)
+            .field("pad_client_hello_to", &self.pad_client_hello_to)
+            .field("capture_handshake", &self.capture_handshake)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("require_ems", &self.require_ems)
+            .field("renegotiation", &self.renegotiation)
+            .field("record_limits", &self.record_limits)
+            .field("cert_chain_limits", &self.cert_chain_limits)
+            .field("duplicate_extensions", &self.duplicate_extensions)
+            .field("require_ocsp_staple", &self.require_ocsp_staple)
+            .field("offload_verification", &self.offload_verification)
             .finish_non_exhaustive()
     }
 }
@@ -262,6 +666,78 @@ impl<C: CryptoProvider> ClientConfig<C> {
         danger::DangerousClientConfig { cfg: self }
     }
 
+    /// Take the resumption state from this config's session store, so it
+    /// can be given to another config with [`ClientConfig::import_sessions`].
+    ///
+    /// This allows a replacement config to resume the sessions made with
+    /// this one.  Key exchange hints and TLS1.2 sessions are copied, but
+    /// TLS1.3 tickets are removed from this config's store, since each may
+    /// only be used once.
+    ///
+    /// Only stores which implement [`ClientSessionStore::export_sessions`]
+    /// can be exported from; [`ClientSessionMemoryCache`] does.
+    pub fn export_sessions(&self) -> Vec<StoredSessions> {
+        self.resumption.store.export_sessions()
+    }
+
+    /// Add resumption state taken from another config with
+    /// [`ClientConfig::export_sessions`] to this config's session store.
+    ///
+    /// Sessions this config could not resume are dropped: those using a
+    /// protocol version it does not enable, or a cipher suite it cannot
+    /// resume with.  Key exchange hints for groups it does not support are
+    /// dropped likewise.
+    ///
+    /// Returns how many sessions (TLS1.2 sessions and TLS1.3 tickets) were
+    /// imported.
+    pub fn import_sessions(&self, sessions: Vec<StoredSessions>) -> usize {
+        let store = &self.resumption.store;
+        let mut imported = 0;
+
+        for server in sessions {
+            if let Some(group) = server.kx_hint {
+                if self
+                    .kx_groups
+                    .iter()
+                    .any(|kx| kx.name() == group)
+                {
+                    store.set_kx_hint(&server.server_name, group);
+                }
+            }
+
+            #[cfg(feature = "tls12")]
+            if let Some(session) = server.tls12_session {
+                if self.supports_version(ProtocolVersion::TLSv1_2)
+                    && self
+                        .find_cipher_suite(session.suite().common.suite)
+                        .is_some()
+                {
+                    store.set_tls12_session(&server.server_name, session);
+                    imported += 1;
+                }
+            }
+
+            for ticket in server.tls13_tickets {
+                let resumable = self.supports_version(ProtocolVersion::TLSv1_3)
+                    && self
+                        .cipher_suites
+                        .iter()
+                        .filter_map(|suite| suite.tls13())
+                        .any(|suite| {
+                            suite
+                                .can_resume_from(ticket.suite())
+                                .is_some()
+                        });
+                if resumable {
+                    store.insert_tls13_ticket(&server.server_name, ticket);
+                    imported += 1;
+                }
+            }
+        }
+
+        imported
+    }
+
     pub(super) fn find_cipher_suite(&self, suite: CipherSuite) -> Option<SupportedCipherSuite> {
         self.cipher_suites
             .iter()
@@ -279,6 +755,15 @@ pub struct Resumption {
 
     /// What mechanism is used for resuming a TLS 1.2 session.
     pub(super) tls12_resumption: Tls12Resumption,
+
+    /// Notified as sessions are stored, offered, accepted and rejected.
+    pub(super) observer: Arc<dyn ObservesClientResumption>,
+
+    /// Sessions older than this are not offered.
+    pub(super) max_session_age: Option<Duration>,
+
+    /// Whether to only offer sessions whose ALPN protocol is still configured.
+    pub(super) require_alpn_match: bool,
 }
 
 impl Resumption {
@@ -290,6 +775,9 @@ impl Resumption {
         Self {
             store: Arc::new(ClientSessionMemoryCache::new(num)),
             tls12_resumption: Tls12Resumption::SessionIdOrTickets,
+            observer: Arc::new(NoResumptionObserver),
+            max_session_age: None,
+            require_alpn_match: false,
         }
     }
 
@@ -300,6 +788,9 @@ impl Resumption {
         Self {
             store,
             tls12_resumption: Tls12Resumption::SessionIdOrTickets,
+            observer: Arc::new(NoResumptionObserver),
+            max_session_age: None,
+            require_alpn_match: false,
         }
     }
 
@@ -308,6 +799,9 @@ impl Resumption {
         Self {
             store: Arc::new(NoClientSessionStorage),
             tls12_resumption: Tls12Resumption::Disabled,
+            observer: Arc::new(NoResumptionObserver),
+            max_session_age: None,
+            require_alpn_match: false,
         }
     }
 
@@ -318,12 +812,44 @@ impl Resumption {
         self.tls12_resumption = tls12;
         self
     }
+
+    /// Don't offer sessions that were established more than `max_age` ago.
+    ///
+    /// Sessions are always subject to the lifetime the server gives them;
+    /// this can only shorten that.  By default, there is no extra limit.
+    pub fn max_session_age(mut self, max_age: Duration) -> Self {
+        self.max_session_age = Some(max_age);
+        self
+    }
+
+    /// Configure whether to offer a session whose ALPN protocol is no longer
+    /// in [`ClientConfig::alpn_protocols`].
+    ///
+    /// A server that resumes such a session may select that protocol again
+    /// even though it is not offered, failing the handshake.  If this is
+    /// `true`, those sessions are not offered and a full handshake is done
+    /// instead.  The default is `false`.
+    pub fn require_alpn_match(mut self, require: bool) -> Self {
+        self.require_alpn_match = require;
+        self
+    }
+
+    /// Notify `observer` about the sessions stored and offered by connections
+    /// using this configuration, and whether servers accept them.
+    ///
+    /// By default, nothing is notified.
+    pub fn observer(mut self, observer: Arc<dyn ObservesClientResumption>) -> Self {
+        self.observer = observer;
+        self
+    }
 }
 
 impl fmt::Debug for Resumption {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Resumption")
             .field("tls12_resumption", &self.tls12_resumption)
+            .field("max_session_age", &self.max_session_age)
+            .field("require_alpn_match", &self.require_alpn_match)
             .finish()
     }
 }
@@ -415,10 +941,15 @@ impl TryFrom<&str> for ServerName {
 #[cfg(feature = "dangerous_configuration")]
 pub(super) mod danger {
     use crate::crypto::CryptoProvider;
+    use crate::error::Error;
+    use crate::key::Certificate;
     use alloc::sync::Arc;
+    use std::time::SystemTime;
 
-    use super::verify::ServerCertVerifier;
-    use super::ClientConfig;
+    use super::verify::{
+        DigitallySignedStruct, HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use super::{ClientConfig, ClientConnection, ServerName};
 
     /// Accessor for dangerous configuration options.
     #[derive(Debug)]
@@ -434,6 +965,93 @@ pub(super) mod danger {
             self.cfg.verifier = verifier;
         }
     }
+
+    /// Marks a client config, or a connection made from one, that does not
+    /// authenticate the server.
+    ///
+    /// Built by [`ConfigBuilder::with_dangerous_no_verifier`].  What it
+    /// marks is only reachable through explicitly named methods:
+    /// [`Unverified::get_unverified`] and [`Unverified::get_unverified_mut`]
+    /// to use it in place, and [`Unverified::into_inner`] to remove the
+    /// marker.  This makes each use easy to find, and means an
+    /// `Unverified<ClientConnection>` cannot stand in for a
+    /// `ClientConnection` by accident.
+    ///
+    /// [`ConfigBuilder::with_dangerous_no_verifier`]: crate::ConfigBuilder::with_dangerous_no_verifier
+    #[derive(Clone, Debug)]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+    pub struct Unverified<T>(T);
+
+    impl<T> Unverified<T> {
+        pub(in crate::client) fn new(inner: T) -> Self {
+            Self(inner)
+        }
+
+        /// Borrow what this marks.  It is still not authenticated.
+        pub fn get_unverified(&self) -> &T {
+            &self.0
+        }
+
+        /// Mutably borrow what this marks.  It is still not authenticated.
+        pub fn get_unverified_mut(&mut self) -> &mut T {
+            &mut self.0
+        }
+
+        /// Remove the marker.
+        pub fn into_inner(self) -> T {
+            self.0
+        }
+    }
+
+    impl<C: CryptoProvider> Unverified<ClientConfig<C>> {
+        /// Share the config, to make connections with.
+        pub fn into_shared(self) -> Unverified<Arc<ClientConfig<C>>> {
+            Unverified(Arc::new(self.0))
+        }
+    }
+
+    impl<C: CryptoProvider> Unverified<Arc<ClientConfig<C>>> {
+        /// Make a connection to `name`, which will not be authenticated.
+        ///
+        /// See [`ClientConnection::new`].
+        pub fn connect(&self, name: ServerName) -> Result<Unverified<ClientConnection>, Error> {
+            ClientConnection::new(Arc::clone(&self.0), name).map(Unverified)
+        }
+    }
+
+    /// Accepts any server certificate and handshake signature.
+    pub(in crate::client) struct NoServerVerification;
+
+    impl ServerCertVerifier for NoServerVerification {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &Certificate,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &Certificate,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -616,6 +1234,94 @@ impl ClientConnection {
         self.inner.core.is_early_data_accepted()
     }
 
+    /// Returns the OCSP response the server stapled to its certificate, if any.
+    ///
+    /// This is the DER-encoded `OCSPResponse` that was given to the server
+    /// certificate verifier.  [`OcspResponse::parse`] describes its status
+    /// and validity window.
+    ///
+    /// This is `None` until the server's certificate has been received, if
+    /// the server did not staple a response, and on resumed connections
+    /// (where the server does not send its certificate).
+    ///
+    /// [`OcspResponse::parse`]: crate::OcspResponse::parse
+    pub fn ocsp_response(&self) -> Option<&[u8]> {
+        self.inner
+            .core
+            .data
+            .ocsp_response
+            .as_deref()
+    }
+
+    /// Returns the Signed Certificate Timestamps presented by the server.
+    ///
+    /// These are collected from the `signed_certificate_timestamp` TLS
+    /// extension and from the server's end-entity certificate, in that
+    /// order.  They are not verified: this is so that Certificate
+    /// Transparency monitoring can see them regardless of whether any
+    /// CT policy is enforced.
+    ///
+    /// This is empty until the server's certificate has been received,
+    /// and on resumed connections.
+    pub fn signed_certificate_timestamps(&self) -> &[SignedCertificateTimestamp] {
+        &self.inner.core.data.scts
+    }
+
+    /// Decodes what the server has sent so far into a [`ServerReport`].
+    ///
+    /// This works from the handshake capture, so returns `None` unless
+    /// [`ClientConfig::capture_handshake`] is enabled.  Like the capture,
+    /// it is available after the handshake fails, for example because the
+    /// server's certificate could not be verified.
+    pub fn server_report(&self) -> Option<ServerReport> {
+        self.handshake_capture()
+            .map(ServerReport::from_capture)
+    }
+
+    /// Retrieves what the handshake has negotiated so far.
+    ///
+    /// See [`Negotiated`] for more information.
+    pub fn negotiated(&self) -> Negotiated<'_> {
+        self.inner
+            .core
+            .common_state
+            .negotiated()
+    }
+
+    /// Takes the verification of the server this connection is waiting for.
+    ///
+    /// This only happens when [`ClientConfig::offload_verification`] is
+    /// enabled.  Once the server's certificate and handshake signature have
+    /// arrived, [`Connection::process_new_packets`] stops processing records
+    /// and this returns `Some` (once).  The [`PendingVerification`] can then
+    /// be run on another thread, and its result given back with
+    /// [`ClientConnection::complete_verification`].
+    ///
+    /// [`Connection::process_new_packets`]: crate::Connection::process_new_packets
+    pub fn take_pending_verification(&mut self) -> Option<PendingVerification> {
+        self.inner
+            .core
+            .take_pending_verification()
+    }
+
+    /// Resumes the handshake with the result of a verification taken from
+    /// [`ClientConnection::take_pending_verification`].
+    ///
+    /// Any records received meanwhile are then processed, as if by
+    /// [`Connection::process_new_packets`].  If the verification failed,
+    /// the connection fails with its error and queues an alert for the
+    /// server.
+    ///
+    /// [`Connection::process_new_packets`]: crate::Connection::process_new_packets
+    pub fn complete_verification(
+        &mut self,
+        completed: CompletedVerification,
+    ) -> Result<IoState, Error> {
+        self.inner
+            .core
+            .complete_verification(completed)
+    }
+
     fn write_early_data(&mut self, data: &[u8]) -> io::Result<usize> {
         self.inner
             .core
@@ -634,6 +1340,60 @@ impl ClientConnection {
     pub fn extract_secrets(self) -> Result<ExtractedSecrets, Error> {
         self.inner.extract_secrets()
     }
+
+    /// Export this connection, so that it can be carried on elsewhere -- in
+    /// another process, for example -- with [`ClientConnection::dangerous_import`].
+    ///
+    /// This is only possible once the handshake is complete, and not for
+    /// QUIC.  The result includes any plaintext and TLS data buffered by the
+    /// connection, so the transport must be handed over with it, and nothing
+    /// more read from or written to it here.
+    ///
+    /// The result contains the connection's traffic secrets: anyone who
+    /// obtains it can read and forge the connection's data.  Protect it
+    /// accordingly, and never import it more than once.
+    #[cfg(feature = "dangerous_configuration")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+    pub fn dangerous_export(self) -> Result<Vec<u8>, Error> {
+        handover::export(self.inner.core, None)
+    }
+
+    /// Carry on a connection exported by [`ClientConnection::dangerous_export`].
+    ///
+    /// `config` must enable the connection's cipher suite.  The server is
+    /// not authenticated again: that happened in the original handshake.
+    /// Tickets received from now on go to `config`'s session store.
+    #[cfg(feature = "dangerous_configuration")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+    pub fn dangerous_import<C: CryptoProvider>(
+        config: Arc<ClientConfig<C>>,
+        exported: &[u8],
+    ) -> Result<Self, Error> {
+        let core = handover::import::<C, _>(
+            exported,
+            Side::Client,
+            &config.cipher_suites,
+            |common, _, secrets| {
+                common.set_max_fragment_size(config.max_fragment_size)?;
+                common.renegotiation_policy = config.renegotiation;
+                #[cfg(feature = "secret_extraction")]
+                {
+                    common.enable_secret_extraction = config.enable_secret_extraction;
+                }
+                let state = match secrets {
+                    #[cfg(feature = "tls12")]
+                    TrafficSecrets::Tls12(secrets) => {
+                        super::tls12::restore_traffic(secrets, common)
+                    }
+                    TrafficSecrets::Tls13(secrets) => {
+                        super::tls13::restore_traffic(&config, secrets, common)?
+                    }
+                };
+                Ok((state, ClientConnectionData::new()))
+            },
+        )?;
+        Ok(Self { inner: core.into() })
+    }
 }
 
 impl Deref for ClientConnection {
@@ -679,10 +1439,18 @@ impl ConnectionCore<ClientConnectionData> {
         let mut common_state = CommonState::new(Side::Client);
         common_state.set_max_fragment_size(config.max_fragment_size)?;
         common_state.protocol = proto;
+        common_state.renegotiation_policy = config.renegotiation;
         #[cfg(feature = "secret_extraction")]
         {
             common_state.enable_secret_extraction = config.enable_secret_extraction;
         }
+        if config.capture_handshake {
+            common_state.enable_handshake_capture(Arc::clone(&config.time_provider));
+        }
+        common_state.set_handshake_timeout(config.handshake_timeout, &config.time_provider)?;
+        common_state.set_record_limits(config.record_limits);
+        common_state.set_memory_budget(config.memory_budget.as_ref(), Self::base_size())?;
+        common_state.set_write_pacer(config.write_pacer.as_ref());
         let mut data = ClientConnectionData::new();
 
         let mut cx = hs::ClientContext {
@@ -703,6 +1471,8 @@ impl ConnectionCore<ClientConnectionData> {
 pub struct ClientConnectionData {
     pub(super) early_data: EarlyData,
     pub(super) resumption_ciphersuite: Option<SupportedCipherSuite>,
+    pub(super) ocsp_response: Option<Vec<u8>>,
+    pub(super) scts: Vec<SignedCertificateTimestamp>,
 }
 
 impl ClientConnectionData {
@@ -710,6 +1480,23 @@ impl ClientConnectionData {
         Self {
             early_data: EarlyData::new(),
             resumption_ciphersuite: None,
+            ocsp_response: None,
+            scts: Vec::new(),
+        }
+    }
+
+    /// Collect SCTs sent in a TLS extension.  Malformed lists are ignored.
+    pub(super) fn received_sct_list(&mut self, sct_list: SctList) {
+        if let Some(scts) = sct::from_sct_list(sct_list, SctSource::TlsExtension) {
+            self.scts.extend(scts);
+        }
+    }
+
+    /// Collect SCTs embedded in the server's end-entity certificate.
+    pub(super) fn received_end_entity(&mut self, cert: Option<&key::Certificate>) {
+        if let Some(cert) = cert {
+            self.scts
+                .extend(sct::embedded_scts(cert));
         }
     }
 }
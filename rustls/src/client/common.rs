@@ -1,4 +1,5 @@
 use super::ResolvesClientCert;
+use crate::error::Error;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
 use crate::msgs::enums::ExtensionType;
@@ -7,6 +8,7 @@ use crate::msgs::handshake::{CertificatePayload, DistinguishedName};
 use crate::{sign, SignatureScheme};
 
 use alloc::sync::Arc;
+use core::any::Any;
 
 #[derive(Debug)]
 pub(super) struct ServerCertDetails {
@@ -69,25 +71,29 @@ impl ClientAuthDetails {
         canames: Option<&[DistinguishedName]>,
         sigschemes: &[SignatureScheme],
         auth_context_tls13: Option<Vec<u8>>,
-    ) -> Self {
+        user_data: Option<&(dyn Any + Send + Sync)>,
+    ) -> Result<Self, Error> {
         let acceptable_issuers = canames
             .unwrap_or_default()
             .iter()
             .map(|p| p.as_ref())
             .collect::<Vec<&[u8]>>();
 
-        if let Some(certkey) = resolver.resolve(&acceptable_issuers, sigschemes) {
+        if let Some(certkey) =
+            resolver.resolve_with_user_data(&acceptable_issuers, sigschemes, user_data)
+        {
             if let Some(signer) = certkey.key.choose_scheme(sigschemes) {
                 debug!("Attempting client auth");
-                return Self::Verify {
+                return Ok(Self::Verify {
                     certkey,
                     signer,
                     auth_context_tls13,
-                };
+                });
             }
         }
 
         debug!("Client auth requested but no cert/sigscheme available");
-        Self::Empty { auth_context_tls13 }
+        resolver.no_client_cert(&acceptable_issuers, sigschemes, user_data)?;
+        Ok(Self::Empty { auth_context_tls13 })
     }
 }
@@ -10,7 +10,7 @@ use crate::ServerName;
 
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard};
 
 /// An implementer of `ClientSessionStore` which does nothing.
 pub(super) struct NoClientSessionStorage;
@@ -39,6 +39,7 @@ impl client::ClientSessionStore for NoClientSessionStorage {
 
 const MAX_TLS13_TICKETS_PER_SERVER: usize = 8;
 
+#[derive(Default)]
 struct ServerData {
     kx_hint: Option<NamedGroup>,
 
@@ -46,19 +47,26 @@ struct ServerData {
     #[cfg(feature = "tls12")]
     tls12: Option<persist::Tls12ClientSessionValue>,
 
-    // Up to MAX_TLS13_TICKETS_PER_SERVER TLS1.3 tickets, oldest first.
+    // Up to `tickets_per_server` TLS1.3 tickets, oldest first.
     tls13: VecDeque<persist::Tls13ClientSessionValue>,
 }
 
-impl Default for ServerData {
-    fn default() -> Self {
-        Self {
-            kx_hint: None,
-            #[cfg(feature = "tls12")]
-            tls12: None,
-            tls13: VecDeque::with_capacity(MAX_TLS13_TICKETS_PER_SERVER),
-        }
-    }
+/// How a [`ClientSessionMemoryCache`] chooses which server to forget
+/// when it is full.
+///
+/// To stop using sessions after a fixed time, regardless of how full the
+/// cache is, see [`Resumption::max_session_age`].
+///
+/// [`Resumption::max_session_age`]: crate::client::Resumption::max_session_age
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientSessionEviction {
+    /// Forget the server which was first added to the cache.
+    ///
+    /// This is the default.
+    OldestInserted,
+    /// Forget the server whose sessions were least recently stored or used.
+    LeastRecentlyUsed,
 }
 
 /// An implementer of `ClientSessionStore` that stores everything
@@ -67,6 +75,8 @@ impl Default for ServerData {
 /// It enforces a limit on the number of entries to bound memory usage.
 pub struct ClientSessionMemoryCache {
     servers: Mutex<limited_cache::LimitedCache<ServerName, ServerData>>,
+    tickets_per_server: usize,
+    eviction: ClientSessionEviction,
 }
 
 impl ClientSessionMemoryCache {
@@ -75,24 +85,49 @@ impl ClientSessionMemoryCache {
     pub fn new(size: usize) -> Self {
         let max_servers =
             size.saturating_add(MAX_TLS13_TICKETS_PER_SERVER - 1) / MAX_TLS13_TICKETS_PER_SERVER;
+        Self::with_limits(max_servers, MAX_TLS13_TICKETS_PER_SERVER)
+    }
+
+    /// Make a new ClientSessionMemoryCache that remembers sessions for
+    /// up to `max_servers` servers, and keeps at most `tickets_per_server`
+    /// TLS1.3 tickets for each.
+    ///
+    /// In addition to its tickets, one TLS1.2 session is kept per server.
+    pub fn with_limits(max_servers: usize, tickets_per_server: usize) -> Self {
         Self {
             servers: Mutex::new(limited_cache::LimitedCache::new(max_servers)),
+            tickets_per_server,
+            eviction: ClientSessionEviction::OldestInserted,
         }
     }
+
+    /// Choose which server to forget when the cache is full.
+    pub fn eviction(mut self, eviction: ClientSessionEviction) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    /// Lock the cache for an operation on `server_name`'s sessions.
+    fn servers_for(
+        &self,
+        server_name: &ServerName,
+    ) -> MutexGuard<'_, limited_cache::LimitedCache<ServerName, ServerData>> {
+        let mut servers = self.servers.lock().unwrap();
+        if self.eviction == ClientSessionEviction::LeastRecentlyUsed {
+            servers.touch(server_name);
+        }
+        servers
+    }
 }
 
 impl client::ClientSessionStore for ClientSessionMemoryCache {
     fn set_kx_hint(&self, server_name: &ServerName, group: NamedGroup) {
-        self.servers
-            .lock()
-            .unwrap()
+        self.servers_for(server_name)
             .get_or_insert_default_and_edit(server_name.clone(), |data| data.kx_hint = Some(group));
     }
 
     fn kx_hint(&self, server_name: &ServerName) -> Option<NamedGroup> {
-        self.servers
-            .lock()
-            .unwrap()
+        self.servers_for(server_name)
             .get(server_name)
             .and_then(|sd| sd.kx_hint)
     }
@@ -103,9 +138,7 @@ impl client::ClientSessionStore for ClientSessionMemoryCache {
         _value: persist::Tls12ClientSessionValue,
     ) {
         #[cfg(feature = "tls12")]
-        self.servers
-            .lock()
-            .unwrap()
+        self.servers_for(_server_name)
             .get_or_insert_default_and_edit(_server_name.clone(), |data| data.tls12 = Some(_value));
     }
 
@@ -114,18 +147,14 @@ impl client::ClientSessionStore for ClientSessionMemoryCache {
         return None;
 
         #[cfg(feature = "tls12")]
-        self.servers
-            .lock()
-            .unwrap()
+        self.servers_for(_server_name)
             .get(_server_name)
             .and_then(|sd| sd.tls12.as_ref().cloned())
     }
 
     fn remove_tls12_session(&self, _server_name: &ServerName) {
         #[cfg(feature = "tls12")]
-        self.servers
-            .lock()
-            .unwrap()
+        self.servers_for(_server_name)
             .get_mut(_server_name)
             .and_then(|data| data.tls12.take());
     }
@@ -135,11 +164,12 @@ impl client::ClientSessionStore for ClientSessionMemoryCache {
         server_name: &ServerName,
         value: persist::Tls13ClientSessionValue,
     ) {
-        self.servers
-            .lock()
-            .unwrap()
+        self.servers_for(server_name)
             .get_or_insert_default_and_edit(server_name.clone(), |data| {
-                if data.tls13.len() == data.tls13.capacity() {
+                if self.tickets_per_server == 0 {
+                    return;
+                }
+                while data.tls13.len() >= self.tickets_per_server {
                     data.tls13.pop_front();
                 }
                 data.tls13.push_back(value);
@@ -150,14 +180,38 @@ impl client::ClientSessionStore for ClientSessionMemoryCache {
         &self,
         server_name: &ServerName,
     ) -> Option<persist::Tls13ClientSessionValue> {
-        self.servers
-            .lock()
-            .unwrap()
+        self.servers_for(server_name)
             .get_mut(server_name)
             .and_then(|data| data.tls13.pop_back())
     }
+
+    fn export_sessions(&self) -> Vec<client::StoredSessions> {
+        let mut servers = self.servers.lock().unwrap();
+        let names: Vec<ServerName> = servers.keys().cloned().collect();
+
+        names
+            .into_iter()
+            .filter_map(|server_name| {
+                let data = servers.get_mut(&server_name)?;
+                Some(client::StoredSessions {
+                    kx_hint: data.kx_hint,
+                    #[cfg(feature = "tls12")]
+                    tls12_session: data.tls12.clone(),
+                    #[cfg(not(feature = "tls12"))]
+                    tls12_session: None,
+                    tls13_tickets: data.tls13.drain(..).collect(),
+                    server_name,
+                })
+            })
+            .collect()
+    }
 }
 
+/// Something which ignores all resumption events.
+pub(super) struct NoResumptionObserver;
+
+impl client::ObservesClientResumption for NoResumptionObserver {}
+
 pub(super) struct FailResolveClientCert {}
 
 impl client::ResolvesClientCert for FailResolveClientCert {
@@ -203,7 +257,7 @@ impl client::ResolvesClientCert for AlwaysResolvesClientCert {
 
 #[cfg(test)]
 mod test {
-    use super::NoClientSessionStorage;
+    use super::{ClientSessionEviction, ClientSessionMemoryCache, NoClientSessionStorage};
     use crate::client::ClientSessionStore;
     use crate::msgs::enums::NamedGroup;
     #[cfg(feature = "tls12")]
@@ -266,4 +320,67 @@ mod test {
         );
         assert!(c.take_tls13_ticket(&name).is_none());
     }
+
+    fn ticket(value: u8) -> Tls13ClientSessionValue {
+        let tls13_suite = match crate::cipher_suite::TLS13_AES_256_GCM_SHA384 {
+            SupportedCipherSuite::Tls13(inner) => inner,
+            #[cfg(feature = "tls12")]
+            _ => unreachable!(),
+        };
+        Tls13ClientSessionValue::new(
+            tls13_suite,
+            vec![value],
+            Vec::new(),
+            Vec::new(),
+            crate::ticketer::TimeBase::now().unwrap(),
+            0,
+            0,
+            0,
+        )
+    }
+
+    #[test]
+    fn test_memory_cache_limits_tickets_per_server() {
+        let c = ClientSessionMemoryCache::with_limits(4, 2);
+        let name = "example.com".try_into().unwrap();
+
+        for i in 1..=3 {
+            c.insert_tls13_ticket(&name, ticket(i));
+        }
+
+        assert_eq!(
+            c.take_tls13_ticket(&name)
+                .unwrap()
+                .ticket(),
+            &[3]
+        );
+        assert_eq!(
+            c.take_tls13_ticket(&name)
+                .unwrap()
+                .ticket(),
+            &[2]
+        );
+        assert!(c.take_tls13_ticket(&name).is_none());
+    }
+
+    #[test]
+    fn test_memory_cache_evicts_least_recently_used() {
+        let a = "a.example.com".try_into().unwrap();
+        let b = "b.example.com".try_into().unwrap();
+        let c = "c.example.com".try_into().unwrap();
+
+        for (eviction, survivor, evicted) in [
+            (ClientSessionEviction::OldestInserted, &b, &a),
+            (ClientSessionEviction::LeastRecentlyUsed, &a, &b),
+        ] {
+            let cache = ClientSessionMemoryCache::with_limits(3, 4).eviction(eviction);
+            cache.set_kx_hint(&a, NamedGroup::X25519);
+            cache.set_kx_hint(&b, NamedGroup::X25519);
+            assert_eq!(cache.kx_hint(&a), Some(NamedGroup::X25519));
+            cache.set_kx_hint(&c, NamedGroup::X25519);
+
+            assert_eq!(cache.kx_hint(survivor), Some(NamedGroup::X25519));
+            assert_eq!(cache.kx_hint(evicted), None);
+        }
+    }
 }
@@ -1,36 +1,40 @@
 #[cfg(feature = "logging")]
 use crate::bs_debug;
+use crate::cert_stream::CertificateStream;
 use crate::check::inappropriate_handshake_message;
 use crate::common_state::{CommonState, State};
 use crate::conn::ConnectionRandoms;
-use crate::crypto::{CryptoProvider, KeyExchange, KeyExchangeError, SupportedGroup};
+use crate::crypto::{self, hash, CryptoProvider, KeyExchange, KeyExchangeError, SupportedGroup};
 use crate::enums::{AlertDescription, CipherSuite, ContentType, HandshakeType, ProtocolVersion};
 use crate::error::{Error, PeerIncompatible, PeerMisbehaved};
 use crate::hash_hs::HandshakeHashBuffer;
+use crate::key::Certificate;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
 use crate::msgs::base::Payload;
+use crate::msgs::codec::Codec;
+use crate::msgs::deframer::MAX_HANDSHAKE_SIZE;
 use crate::msgs::enums::{Compression, ExtensionType};
 use crate::msgs::enums::{ECPointFormat, PSKKeyExchangeMode};
 use crate::msgs::handshake::ConvertProtocolNameList;
 use crate::msgs::handshake::{CertificateStatusRequest, ClientSessionTicket};
-use crate::msgs::handshake::{ClientExtension, HasServerExtensions};
+use crate::msgs::handshake::{ClientExtension, HasServerExtensions, ServerExtension};
 use crate::msgs::handshake::{ClientHelloPayload, HandshakeMessagePayload, HandshakePayload};
-use crate::msgs::handshake::{HelloRetryRequest, KeyShareEntry};
+use crate::msgs::handshake::{HelloRetryExtension, HelloRetryRequest, KeyShareEntry};
 use crate::msgs::handshake::{Random, SessionId};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 use crate::rand::GetRandomFailed;
 use crate::ticketer::TimeBase;
 use crate::tls13::key_schedule::KeyScheduleEarly;
+use crate::verify;
 use crate::SupportedCipherSuite;
 
 #[cfg(feature = "tls12")]
 use super::tls12;
-use super::Tls12Resumption;
 use crate::client::client_conn::ClientConnectionData;
 use crate::client::common::ClientHelloDetails;
-use crate::client::{tls13, ClientConfig, ServerName};
+use crate::client::{tls13, ClientConfig, ResumptionNotAttempted, ResumptionRejected, ServerName};
 
 use alloc::sync::Arc;
 use core::ops::Deref;
@@ -45,7 +49,7 @@ fn find_session(
     #[cfg(feature = "quic")] cx: &mut ClientContext<'_>,
 ) -> Option<persist::Retrieved<ClientSessionValue>> {
     #[allow(clippy::let_and_return, clippy::unnecessary_lazy_evaluations)]
-    let found = config
+    let stored = config
         .resumption
         .store
         .take_tls13_ticket(server_name)
@@ -62,19 +66,77 @@ fn find_session(
 
             #[cfg(not(feature = "tls12"))]
             None
-        })
-        .and_then(|resuming| {
-            let retrieved = persist::Retrieved::new(resuming, TimeBase::now().ok()?);
-            match retrieved.has_expired() {
-                false => Some(retrieved),
-                true => None,
-            }
-        })
-        .or_else(|| {
-            debug!("No cached session for {:?}", server_name);
-            None
         });
 
+    let observer = &config.resumption.observer;
+    if stored.is_none() {
+        debug!("No cached session for {:?}", server_name);
+        observer.resumption_not_attempted(server_name, ResumptionNotAttempted::NoStoredSession);
+    }
+
+    let found = stored.and_then(|resuming| {
+        let retrieved = persist::Retrieved::new(
+            resuming,
+            TimeBase::from_provider(&*config.time_provider).ok()?,
+        );
+        let too_old = config
+            .resumption
+            .max_session_age
+            .map_or(false, |max_age| retrieved.age_secs() > max_age.as_secs());
+        if retrieved.has_expired() || too_old {
+            debug!("Cached session for {:?} has expired", server_name);
+            observer.resumption_not_attempted(server_name, ResumptionNotAttempted::Expired);
+            return None;
+        }
+
+        #[cfg(feature = "tls12")]
+        if let ClientSessionValue::Tls12(inner) = &retrieved.value {
+            let mechanism = &config.resumption.tls12_resumption;
+            let allowed = if inner.ticket().is_empty() {
+                mechanism.session_ids()
+            } else {
+                mechanism.tickets()
+            };
+            if !allowed {
+                debug!(
+                    "Cached session for {:?} uses a disabled TLS1.2 mechanism",
+                    server_name
+                );
+                observer.resumption_not_attempted(
+                    server_name,
+                    ResumptionNotAttempted::Tls12MechanismDisabled,
+                );
+                return None;
+            }
+        }
+
+        if config.resumption.require_alpn_match {
+            if let Some(alpn_protocol) = retrieved.alpn_protocol() {
+                if !config
+                    .alpn_protocols
+                    .iter()
+                    .any(|proto| proto == alpn_protocol)
+                {
+                    debug!(
+                        "Cached session for {:?} used another ALPN protocol",
+                        server_name
+                    );
+                    observer.resumption_not_attempted(
+                        server_name,
+                        ResumptionNotAttempted::AlpnMismatch,
+                    );
+                    return None;
+                }
+            }
+        }
+
+        Some(retrieved)
+    });
+
+    if let Some(resuming) = &found {
+        observer.resumption_attempted(server_name, resuming.version());
+    }
+
     #[cfg(feature = "quic")]
     if let Some(resuming) = &found {
         if cx.common.is_quic() {
@@ -139,12 +201,16 @@ pub(super) fn start_handshake<C: CryptoProvider>(
         Some(session_id) => session_id,
         None if cx.common.is_quic() => SessionId::empty(),
         None if !config.supports_version(ProtocolVersion::TLSv1_3) => SessionId::empty(),
+        None if !config.enable_middlebox_compat => SessionId::empty(),
         None => SessionId::random::<C>()?,
     };
 
+    // Without middlebox compatibility, act as if the fake CCS was already sent.
+    let sent_tls13_fake_ccs = !config.enable_middlebox_compat;
+
     let random = Random::new::<C>()?;
 
-    Ok(emit_client_hello_for_retry::<C>(
+    emit_client_hello_for_retry::<C>(
         transcript_buffer,
         None,
         key_share,
@@ -156,13 +222,13 @@ pub(super) fn start_handshake<C: CryptoProvider>(
             random,
             #[cfg(feature = "tls12")]
             using_ems: false,
-            sent_tls13_fake_ccs: false,
+            sent_tls13_fake_ccs,
             hello: ClientHelloDetails::new(),
             session_id,
             server_name,
         },
         cx,
-    ))
+    )
 }
 
 struct ExpectServerHello<C: CryptoProvider> {
@@ -198,7 +264,7 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
     suite: Option<SupportedCipherSuite>,
     mut input: ClientHelloInput<C>,
     cx: &mut ClientContext<'_>,
-) -> NextState {
+) -> Result<NextState, Error> {
     let config = &input.config;
     let support_tls12 = config.supports_version(ProtocolVersion::TLSv1_2) && !cx.common.is_quic();
     let support_tls13 = config.supports_version(ProtocolVersion::TLSv1_3);
@@ -225,13 +291,15 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
                 .map(|skxg| skxg.name())
                 .collect(),
         ),
-        ClientExtension::SignatureAlgorithms(
+        ClientExtension::SignatureAlgorithms(verify::allowed_signature_schemes(
+            config.signature_schemes.as_deref(),
             config
                 .verifier
                 .supported_verify_schemes(),
-        ),
+        )),
         ClientExtension::ExtendedMasterSecretRequest,
         ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
+        ClientExtension::SignedCertificateTimestampRequest,
     ];
 
     if let (Some(sni_name), true) = (input.server_name.for_sni(), config.enable_sni) {
@@ -248,6 +316,16 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
         exts.push(ClientExtension::Cookie(cookie.clone()));
     }
 
+    if support_tls13
+        && !config
+            .certificate_authorities
+            .is_empty()
+    {
+        exts.push(ClientExtension::AuthorityNames(
+            config.certificate_authorities.clone(),
+        ));
+    }
+
     if support_tls13 {
         // We could support PSK_KE here too. Such connections don't
         // have forward secrecy, and are similar to TLS1.2 resumption.
@@ -284,6 +362,9 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
         .collect();
     // We don't do renegotiation at all, in fact.
     cipher_suites.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
+    if config.send_fallback_scsv {
+        cipher_suites.push(CipherSuite::TLS_FALLBACK_SCSV);
+    }
 
     let mut chp = HandshakeMessagePayload {
         typ: HandshakeType::ClientHello,
@@ -297,9 +378,28 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
         }),
     };
 
+    // Padding must be added before the PSK binder is computed, since the binder covers it.
+    if let Some(target) = config.pad_client_hello_to {
+        let len = chp.get_encoding().len();
+        if let (true, HandshakePayload::ClientHello(ch)) = (len < target, &mut chp.payload) {
+            ch.add_padding(target - len);
+        }
+    }
+
+    // Peers (including us) refuse handshake message bodies larger than this, so fail early
+    // rather than sending a hello that is certain to be rejected.  The encoding includes
+    // the four byte handshake header.
+    if chp.get_encoding().len() > 4 + MAX_HANDSHAKE_SIZE as usize {
+        return Err(Error::General(
+            "ClientHello exceeds the maximum handshake message size".into(),
+        ));
+    }
+
     let early_key_schedule = if let Some(resuming) = tls13_session {
-        let schedule = tls13::fill_in_psk_binder(&resuming, &transcript_buffer, &mut chp);
-        Some((resuming.suite(), schedule))
+        let suite_hash = hash::for_transcript::<C>(resuming.suite().hash_algorithm())?;
+        let schedule =
+            tls13::fill_in_psk_binder::<C>(&resuming, suite_hash, &transcript_buffer, &mut chp)?;
+        Some((suite_hash, schedule))
     } else {
         None
     };
@@ -328,7 +428,7 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
     cx.common.send_msg(ch, false);
 
     // Calculate the hash of ClientHello and use it to derive EarlyTrafficSecret
-    let early_key_schedule = early_key_schedule.map(|(resuming_suite, schedule)| {
+    let early_key_schedule = early_key_schedule.map(|(suite_hash, schedule)| {
         if !cx.data.early_data.is_enabled() {
             return schedule;
         }
@@ -336,7 +436,7 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
         tls13::derive_early_traffic_secret(
             &*config.key_log,
             cx,
-            resuming_suite,
+            suite_hash,
             &schedule,
             &mut input.sent_tls13_fake_ccs,
             &transcript_buffer,
@@ -354,9 +454,12 @@ fn emit_client_hello_for_retry<C: CryptoProvider>(
     };
 
     if support_tls13 && retryreq.is_none() {
-        Box::new(ExpectServerHelloOrHelloRetryRequest { next, extra_exts })
+        Ok(Box::new(ExpectServerHelloOrHelloRetryRequest {
+            next,
+            extra_exts,
+        }))
     } else {
-        Box::new(next)
+        Ok(Box::new(next))
     }
 }
 
@@ -385,7 +488,10 @@ fn prepare_resumption<'a>(
         Some(resuming) if !resuming.ticket().is_empty() => resuming,
         _ => {
             if config.supports_version(ProtocolVersion::TLSv1_3)
-                || config.resumption.tls12_resumption == Tls12Resumption::SessionIdOrTickets
+                || config
+                    .resumption
+                    .tls12_resumption
+                    .tickets()
             {
                 // If we don't have a ticket, request one.
                 exts.push(ClientExtension::SessionTicket(ClientSessionTicket::Request));
@@ -399,7 +505,10 @@ fn prepare_resumption<'a>(
         None => {
             // TLS 1.2; send the ticket if we have support this protocol version
             if config.supports_version(ProtocolVersion::TLSv1_2)
-                && config.resumption.tls12_resumption == Tls12Resumption::SessionIdOrTickets
+                && config
+                    .resumption
+                    .tls12_resumption
+                    .tickets()
             {
                 exts.push(ClientExtension::SessionTicket(ClientSessionTicket::Offer(
                     Payload::new(resuming.ticket()),
@@ -430,6 +539,41 @@ fn prepare_resumption<'a>(
     Some(tls13)
 }
 
+/// Check the certificates of the server's chain that have arrived so far,
+/// if `received` is the start of its `Certificate` message.
+pub(super) fn precheck_partial_chain(
+    common: &mut CommonState,
+    config: &ClientConfig<impl CryptoProvider>,
+    cert_stream: &mut CertificateStream,
+    received: &[u8],
+    tls13: bool,
+) -> Result<(), Error> {
+    cert_stream
+        .check_partial(received, tls13, &config.cert_chain_limits, |index, cert| {
+            config
+                .verifier
+                .precheck_certificate(index, cert)
+        })
+        .map_err(|err| common.send_cert_verify_error_alert(err))
+}
+
+/// Check the certificates of the server's complete `chain` that were not
+/// checked while it was arriving.
+pub(super) fn precheck_chain(
+    common: &mut CommonState,
+    config: &ClientConfig<impl CryptoProvider>,
+    cert_stream: &mut CertificateStream,
+    chain: &[Certificate],
+) -> Result<(), Error> {
+    cert_stream
+        .finish(chain, &config.cert_chain_limits, |index, cert| {
+            config
+                .verifier
+                .precheck_certificate(index, cert)
+        })
+        .map_err(|err| common.send_cert_verify_error_alert(err))
+}
+
 pub(super) fn process_alpn_protocol(
     common: &mut CommonState,
     config: &ClientConfig<impl CryptoProvider>,
@@ -536,7 +680,18 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerHello<C> {
             });
         }
 
-        if server_hello.has_duplicate_extension() {
+        if server_hello.has_duplicate_extension()
+            && !self
+                .input
+                .config
+                .duplicate_extensions
+                .tolerates(
+                    server_hello
+                        .extensions
+                        .iter()
+                        .map(ServerExtension::get_type),
+                )
+        {
             return Err(cx.common.send_fatal_alert(
                 AlertDescription::DecodeError,
                 PeerMisbehaved::DuplicateServerHelloExtensions,
@@ -607,10 +762,24 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerHello<C> {
             }
         }
 
+        if let Some(resuming) = &self.input.resuming {
+            if resuming.version() != version {
+                debug!("Server negotiated a different version to our session; not resuming");
+                config
+                    .resumption
+                    .observer
+                    .resumption_rejected(
+                        &self.input.server_name,
+                        ResumptionRejected::VersionMismatch,
+                    );
+            }
+        }
+
         // Start our handshake hash, and input the server-hello.
+        let suite_hash = hash::for_transcript::<C>(suite.hash_algorithm())?;
         let mut transcript = self
             .transcript_buffer
-            .start_hash(suite.hash_algorithm());
+            .start_hash(suite_hash);
         transcript.add_message(&m);
 
         let randoms = ConnectionRandoms::new(self.input.random, server_hello.random);
@@ -686,6 +855,7 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
         trace!("Got HRR {:?}", hrr);
 
         cx.common.check_aligned_handshake()?;
+        cx.common.hello_retried = true;
 
         let cookie = hrr.get_cookie();
         let req_group = hrr.get_requested_key_share_group();
@@ -725,7 +895,18 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
         }
 
         // Or has the same extensions more than once
-        if hrr.has_duplicate_extension() {
+        if hrr.has_duplicate_extension()
+            && !self
+                .next
+                .input
+                .config
+                .duplicate_extensions
+                .tolerates(
+                    hrr.extensions
+                        .iter()
+                        .map(HelloRetryExtension::get_type),
+                )
+        {
             return Err({
                 cx.common.send_fatal_alert(
                     AlertDescription::IllegalParameter,
@@ -802,7 +983,7 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
         let transcript = self
             .next
             .transcript_buffer
-            .start_hash(cs.hash_algorithm());
+            .start_hash(hash::for_transcript::<C>(cs.hash_algorithm())?);
         let mut transcript_buffer = transcript.into_hrr_buffer();
         transcript_buffer.add_message(&m);
 
@@ -813,7 +994,7 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
 
         let key_share = match req_group {
             Some(group) if group != offered_key_share.group() => {
-                match KeyExchange::start(group, &config.kx_groups) {
+                match crypto::start_kx(config.key_share_pool.as_deref(), group, &config.kx_groups) {
                     Ok(kx) => kx,
                     Err(KeyExchangeError::UnsupportedGroup) => {
                         return Err(cx.common.send_fatal_alert(
@@ -827,7 +1008,7 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
             _ => offered_key_share,
         };
 
-        Ok(emit_client_hello_for_retry::<C>(
+        emit_client_hello_for_retry::<C>(
             transcript_buffer,
             Some(hrr),
             Some(key_share),
@@ -835,7 +1016,7 @@ impl<C: CryptoProvider> ExpectServerHelloOrHelloRetryRequest<C> {
             Some(cs),
             self.next.input,
             cx,
-        ))
+        )
     }
 }
 
@@ -884,6 +1065,14 @@ impl ClientSessionValue {
         }
     }
 
+    fn version(&self) -> ProtocolVersion {
+        match self {
+            Self::Tls13(_) => ProtocolVersion::TLSv1_3,
+            #[cfg(feature = "tls12")]
+            Self::Tls12(_) => ProtocolVersion::TLSv1_2,
+        }
+    }
+
     fn tls13(&self) -> Option<&persist::Tls13ClientSessionValue> {
         match self {
             Self::Tls13(v) => Some(v),
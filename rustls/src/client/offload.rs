@@ -0,0 +1,265 @@
+use crate::client::{ClientConfig, ServerName};
+use crate::common_state::State;
+use crate::crypto::hash::{Hash, HashAlgorithm};
+use crate::crypto::CryptoProvider;
+use crate::enums::{ProtocolVersion, SignatureScheme};
+use crate::error::Error;
+use crate::key::Certificate;
+
+use crate::msgs::message::Message;
+use crate::ocsp::OcspStaplePolicy;
+use crate::time_provider;
+use crate::verify::{
+    self, DigitallySignedStruct, HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+
+use super::client_conn::ClientConnectionData;
+use super::common::ServerCertDetails;
+use super::hs::{ClientContext, NextStateOrError};
+
+use alloc::sync::Arc;
+use core::any::Any;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// The results of verifying the server's certificate and handshake signature.
+pub(super) type ServerVerified = (ServerCertVerified, HandshakeSignatureValid);
+
+/// Verification of a server's certificate chain and handshake signature,
+/// waiting to be run.
+///
+/// A client connection whose config has
+/// [`ClientConfig::offload_verification`] enabled stops when it needs to
+/// verify the server, and yields one of these from
+/// [`ClientConnection::take_pending_verification`].  It holds everything
+/// the verification needs, so can be sent to another thread to run there.
+/// The result is given back to the connection with
+/// [`ClientConnection::complete_verification`].
+///
+/// [`ClientConnection::take_pending_verification`]: crate::ClientConnection::take_pending_verification
+/// [`ClientConnection::complete_verification`]: crate::ClientConnection::complete_verification
+pub struct PendingVerification {
+    id: usize,
+    verifier: Arc<dyn ServerCertVerifier>,
+    end_entity: Certificate,
+    intermediates: Vec<Certificate>,
+    server_name: ServerName,
+    ocsp_response: Vec<u8>,
+    ocsp_policy: Option<OcspStaplePolicy>,
+    hash: fn(HashAlgorithm) -> Option<&'static dyn Hash>,
+    now: SystemTime,
+    signature_schemes: Option<Vec<SignatureScheme>>,
+    version: ProtocolVersion,
+    signed_message: Vec<u8>,
+    signature: DigitallySignedStruct,
+}
+
+impl PendingVerification {
+    /// Gather what is needed to verify `server_cert`, and the signature
+    /// `signature` over `signed_message` made with its key.
+    pub(super) fn new<C: CryptoProvider>(
+        config: &ClientConfig<C>,
+        server_name: &ServerName,
+        server_cert: &ServerCertDetails,
+        version: ProtocolVersion,
+        signed_message: Vec<u8>,
+        signature: DigitallySignedStruct,
+    ) -> Result<Self, Error> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let (end_entity, intermediates) = server_cert
+            .cert_chain
+            .split_first()
+            .ok_or(Error::NoCertificatesPresented)?;
+        let now = time_provider::current_time(&*config.time_provider)?;
+        let intermediates = config
+            .known_intermediates
+            .complete_chain(end_entity, intermediates);
+
+        Ok(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            verifier: Arc::clone(&config.verifier),
+            end_entity: end_entity.clone(),
+            intermediates,
+            server_name: server_name.clone(),
+            ocsp_response: server_cert.ocsp_response.clone(),
+            ocsp_policy: config.require_ocsp_staple,
+            hash: C::hash,
+            now,
+            signature_schemes: config.signature_schemes.clone(),
+            version,
+            signed_message,
+            signature,
+        })
+    }
+
+    /// The name of the server being verified.
+    pub fn server_name(&self) -> &ServerName {
+        &self.server_name
+    }
+
+    /// Run the verification.
+    ///
+    /// The certificate verifier is not given the connection's user data.
+    pub fn verify(self) -> CompletedVerification {
+        CompletedVerification {
+            id: self.id,
+            result: self.run(None),
+        }
+    }
+
+    /// Verify the certificate chain with the config's verifier, check its
+    /// signature schemes and any required OCSP staple, then verify the
+    /// handshake signature.
+    pub(super) fn run(
+        &self,
+        user_data: Option<&(dyn Any + Send + Sync)>,
+    ) -> Result<ServerVerified, Error> {
+        let cert_verified = self
+            .verifier
+            .verify_server_cert_with_user_data(
+                &self.end_entity,
+                &self.intermediates,
+                &self.server_name,
+                &self.ocsp_response,
+                self.now,
+                user_data,
+            )?;
+        verify::check_certificate_signatures(
+            self.signature_schemes.as_deref(),
+            &self.end_entity,
+            &self.intermediates,
+        )?;
+        if let Some(policy) = &self.ocsp_policy {
+            policy.check(
+                &self.ocsp_response,
+                &self.end_entity,
+                &self.intermediates,
+                self.verifier.trust_anchors().as_deref(),
+                self.hash,
+                self.now,
+            )?;
+        }
+
+        verify::check_signature_scheme(self.signature_schemes.as_deref(), self.signature.scheme)?;
+        let sig_verified = match self.version {
+            ProtocolVersion::TLSv1_3 => self.verifier.verify_tls13_signature(
+                &self.signed_message,
+                &self.end_entity,
+                &self.signature,
+            ),
+            _ => self.verifier.verify_tls12_signature(
+                &self.signed_message,
+                &self.end_entity,
+                &self.signature,
+            ),
+        }?;
+
+        Ok((cert_verified, sig_verified))
+    }
+}
+
+impl fmt::Debug for PendingVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingVerification")
+            .field("server_name", &self.server_name)
+            .field("version", &self.version)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The outcome of a [`PendingVerification`], to be given back to the
+/// connection it came from.
+pub struct CompletedVerification {
+    id: usize,
+    result: Result<ServerVerified, Error>,
+}
+
+impl CompletedVerification {
+    /// The error the verification failed with, if it did.
+    pub fn error(&self) -> Option<&Error> {
+        self.result.as_ref().err()
+    }
+}
+
+impl fmt::Debug for CompletedVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompletedVerification")
+            .field("error", &self.error())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A handshake state that stopped to have the server verified elsewhere.
+pub(super) trait ResumesAfterVerification: Send + Sync {
+    /// Continue handling `message`, which needed the server to be verified.
+    fn resume(
+        self: Box<Self>,
+        cx: &mut ClientContext<'_>,
+        verified: ServerVerified,
+        message: Message,
+    ) -> NextStateOrError;
+}
+
+/// Waits for a [`PendingVerification`] to be completed.
+///
+/// Further messages stay in the deframer until then.
+pub(super) struct AwaitVerification {
+    id: usize,
+    pending: Option<PendingVerification>,
+    state: Box<dyn ResumesAfterVerification>,
+    message: Message,
+}
+
+impl AwaitVerification {
+    pub(super) fn new(
+        pending: PendingVerification,
+        state: Box<dyn ResumesAfterVerification>,
+        message: Message,
+    ) -> Box<Self> {
+        Box::new(Self {
+            id: pending.id,
+            pending: Some(pending),
+            state,
+            message,
+        })
+    }
+}
+
+impl State<ClientConnectionData> for AwaitVerification {
+    fn handle(self: Box<Self>, _cx: &mut ClientContext<'_>, _m: Message) -> NextStateOrError {
+        Err(Error::General(
+            "message received while verification is pending".into(),
+        ))
+    }
+
+    fn is_pending(&self) -> bool {
+        true
+    }
+
+    fn take_pending_verification(&mut self) -> Option<PendingVerification> {
+        self.pending.take()
+    }
+
+    fn complete_verification(
+        self: Box<Self>,
+        cx: &mut ClientContext<'_>,
+        completed: CompletedVerification,
+    ) -> NextStateOrError {
+        if completed.id != self.id {
+            return Err(Error::General(
+                "verification completed for another connection".into(),
+            ));
+        }
+
+        match completed.result {
+            Ok(verified) => self
+                .state
+                .resume(cx, verified, self.message),
+            Err(err) => Err(cx
+                .common
+                .send_cert_verify_error_alert(err)),
+        }
+    }
+}
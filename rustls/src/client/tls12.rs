@@ -1,10 +1,13 @@
+use crate::cert_stream::CertificateStream;
 use crate::check::{inappropriate_handshake_message, inappropriate_message};
 use crate::common_state::{CommonState, Side, State};
 use crate::conn::ConnectionRandoms;
-use crate::crypto::{CryptoProvider, KeyExchange, KeyExchangeError};
+use crate::crypto::{self, hmac, CryptoProvider, KeyExchange, KeyExchangeError};
 use crate::enums::ProtocolVersion;
 use crate::enums::{AlertDescription, ContentType, HandshakeType};
-use crate::error::{Error, InvalidMessage, PeerMisbehaved};
+use crate::error::{Error, InvalidMessage, PeerIncompatible, PeerMisbehaved};
+#[cfg(feature = "dangerous_configuration")]
+use crate::handover::TrafficSecrets;
 use crate::hash_hs::HandshakeHash;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
@@ -27,9 +30,12 @@ use crate::verify::{self, DigitallySignedStruct};
 
 use super::client_conn::ClientConnectionData;
 use super::hs::ClientContext;
+use super::offload::{
+    AwaitVerification, PendingVerification, ResumesAfterVerification, ServerVerified,
+};
 use crate::client::common::ClientAuthDetails;
 use crate::client::common::ServerCertDetails;
-use crate::client::{hs, ClientConfig, ServerName};
+use crate::client::{hs, ClientConfig, ResumptionRejected, ServerName};
 use crate::rand::GetRandomFailed;
 
 use subtle::ConstantTimeEq;
@@ -82,6 +88,14 @@ mod server_hello {
 
             // Doing EMS?
             self.using_ems = server_hello.ems_support_acked();
+            if self.config.require_ems && !self.using_ems {
+                return Err(cx.common.send_fatal_alert(
+                    AlertDescription::HandshakeFailure,
+                    PeerIncompatible::ExtendedMasterSecretExtensionRequired,
+                ));
+            }
+            cx.common.using_ems = self.using_ems;
+            cx.common.secure_renegotiation = server_hello.secure_renegotiation_acked();
 
             // Might the server send a ticket?
             let must_issue_new_ticket = if server_hello
@@ -103,6 +117,13 @@ mod server_hello {
                 debug!("Server may staple OCSP response");
             }
 
+            // Save any sent SCTs.
+            if let Some(sct_list) = server_hello.get_sct_list() {
+                debug!("Server sent {} SCTs", sct_list.len());
+                cx.data
+                    .received_sct_list(sct_list.clone());
+            }
+
             // See if we're successfully resuming.
             if let Some(ref resuming) = self.resuming_session {
                 if resuming.session_id == server_hello.session_id {
@@ -118,8 +139,18 @@ mod server_hello {
                         return Err(PeerMisbehaved::ResumptionOfferedWithVariedEms.into());
                     }
 
-                    let secrets =
-                        ConnectionSecrets::new_resume(self.randoms, suite, resuming.secret());
+                    self.config
+                        .resumption
+                        .observer
+                        .resumption_accepted(&self.server_name, ProtocolVersion::TLSv1_2);
+                    cx.common.resumed = true;
+
+                    let secrets = ConnectionSecrets::new_resume(
+                        self.randoms,
+                        suite,
+                        hmac::for_suite::<C>(suite.hash_algorithm())?,
+                        resuming.secret(),
+                    );
                     self.config.key_log.log(
                         "CLIENT_RANDOM",
                         &secrets.randoms.client,
@@ -163,6 +194,12 @@ mod server_hello {
                         }))
                     };
                 }
+
+                debug!("Server declined to resume");
+                self.config
+                    .resumption
+                    .observer
+                    .resumption_rejected(&self.server_name, ResumptionRejected::ServerDeclined);
             }
 
             Ok(Box::new(ExpectCertificate {
@@ -176,6 +213,7 @@ mod server_hello {
                 suite,
                 may_send_cert_status,
                 must_issue_new_ticket,
+                cert_stream: CertificateStream::default(),
             }))
         }
     }
@@ -192,20 +230,25 @@ struct ExpectCertificate<C: CryptoProvider> {
     pub(super) suite: &'static Tls12CipherSuite,
     may_send_cert_status: bool,
     must_issue_new_ticket: bool,
+    cert_stream: CertificateStream,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
-    fn handle(
-        mut self: Box<Self>,
-        _cx: &mut ClientContext<'_>,
-        m: Message,
-    ) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         self.transcript.add_message(&m);
         let server_cert_chain = require_handshake_msg_move!(
             m,
             HandshakeType::Certificate,
             HandshakePayload::Certificate
         )?;
+        hs::precheck_chain(
+            cx.common,
+            &self.config,
+            &mut self.cert_stream,
+            &server_cert_chain,
+        )?;
+        cx.data
+            .received_end_entity(server_cert_chain.first());
 
         if self.may_send_cert_status {
             Ok(Box::new(ExpectCertificateStatusOrServerKx {
@@ -237,6 +280,20 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
             }))
         }
     }
+
+    fn handle_partial_handshake(
+        &mut self,
+        cx: &mut ClientContext<'_>,
+        received: &[u8],
+    ) -> Result<(), Error> {
+        hs::precheck_partial_chain(
+            cx.common,
+            &self.config,
+            &mut self.cert_stream,
+            received,
+            false,
+        )
+    }
 }
 
 struct ExpectCertificateStatusOrServerKx<C: CryptoProvider> {
@@ -321,11 +378,7 @@ struct ExpectCertificateStatus<C: CryptoProvider> {
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateStatus<C> {
-    fn handle(
-        mut self: Box<Self>,
-        _cx: &mut ClientContext<'_>,
-        m: Message,
-    ) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         self.transcript.add_message(&m);
         let server_cert_ocsp_response = require_handshake_msg_move!(
             m,
@@ -339,6 +392,9 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateStatus<
             &server_cert_ocsp_response
         );
 
+        if !server_cert_ocsp_response.is_empty() {
+            cx.data.ocsp_response = Some(server_cert_ocsp_response.clone());
+        }
         let server_cert = ServerCertDetails::new(self.server_cert_chain, server_cert_ocsp_response);
 
         Ok(Box::new(ExpectServerKx {
@@ -577,6 +633,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDoneOrCertRe
                 server_kx: self.server_kx,
                 client_auth: None,
                 must_issue_new_ticket: self.must_issue_new_ticket,
+                verified: None,
             })
             .handle(cx, m)
         }
@@ -598,11 +655,7 @@ struct ExpectCertificateRequest<C: CryptoProvider> {
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest<C> {
-    fn handle(
-        mut self: Box<Self>,
-        _cx: &mut ClientContext<'_>,
-        m: Message,
-    ) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         let certreq = require_handshake_msg!(
             m,
             HandshakeType::CertificateRequest,
@@ -617,15 +670,25 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
         // We ignore certreq.certtypes as a result, since the information it contains
         // is entirely duplicated in certreq.sigschemes.
 
+        let sigschemes = verify::allowed_signature_schemes(
+            self.config.signature_schemes.as_deref(),
+            certreq.sigschemes.clone(),
+        );
+
         const NO_CONTEXT: Option<Vec<u8>> = None; // TLS 1.2 doesn't use a context.
         let client_auth = ClientAuthDetails::resolve(
             self.config
                 .client_auth_cert_resolver
                 .as_ref(),
             Some(&certreq.canames),
-            &certreq.sigschemes,
+            &sigschemes,
             NO_CONTEXT,
-        );
+            cx.common.user_data(),
+        )
+        .map_err(|err| {
+            cx.common
+                .send_fatal_alert(AlertDescription::HandshakeFailure, err)
+        })?;
 
         Ok(Box::new(ExpectServerDone {
             config: self.config,
@@ -640,6 +703,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
             server_kx: self.server_kx,
             client_auth: Some(client_auth),
             must_issue_new_ticket: self.must_issue_new_ticket,
+            verified: None,
         }))
     }
 }
@@ -657,10 +721,11 @@ struct ExpectServerDone<C: CryptoProvider> {
     server_kx: ServerKxDetails,
     client_auth: Option<ClientAuthDetails>,
     must_issue_new_ticket: bool,
+    verified: Option<ServerVerified>,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
-    fn handle(self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         match m.payload {
             MessagePayload::Handshake {
                 parsed:
@@ -679,15 +744,12 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
             }
         }
 
-        let mut st = *self;
-        st.transcript.add_message(&m);
-
         cx.common.check_aligned_handshake()?;
 
-        trace!("Server cert is {:?}", st.server_cert.cert_chain);
-        debug!("Server DNS name is {:?}", st.server_name);
+        trace!("Server cert is {:?}", self.server_cert.cert_chain);
+        debug!("Server DNS name is {:?}", self.server_name);
 
-        let suite = st.suite;
+        let suite = self.suite;
 
         // 1. Verify the cert chain.
         // 2. Verify any SCTs provided with the certificate.
@@ -701,57 +763,56 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
         //    e) derive the shared keys, and start encryption
         // 6. emit a Finished, our first encrypted message under the new keys.
 
-        // 1.
-        let (end_entity, intermediates) = st
-            .server_cert
-            .cert_chain
-            .split_first()
-            .ok_or(Error::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
-        let cert_verified = st
-            .config
-            .verifier
-            .verify_server_cert(
-                end_entity,
-                intermediates,
-                &st.server_name,
-                &st.server_cert.ocsp_response,
-                now,
-            )
-            .map_err(|err| {
-                cx.common
-                    .send_cert_verify_error_alert(err)
-            })?;
-
-        // 3.
-        // Build up the contents of the signed message.
-        // It's ClientHello.random || ServerHello.random || ServerKeyExchange.params
-        let sig_verified = {
-            let mut message = Vec::new();
-            message.extend_from_slice(&st.randoms.client);
-            message.extend_from_slice(&st.randoms.server);
-            message.extend_from_slice(&st.server_kx.kx_params);
-
-            // Check the signature is compatible with the ciphersuite.
-            let sig = &st.server_kx.kx_sig;
-            if !SupportedCipherSuite::from(suite).usable_for_signature_algorithm(sig.scheme.sign())
-            {
-                warn!(
-                    "peer signed kx with wrong algorithm (got {:?} expect {:?})",
-                    sig.scheme.sign(),
-                    suite.sign
-                );
-                return Err(PeerMisbehaved::SignedKxWithWrongAlgorithm.into());
-            }
+        // Check the signature is compatible with the ciphersuite.
+        let sig = &self.server_kx.kx_sig;
+        if !SupportedCipherSuite::from(suite).usable_for_signature_algorithm(sig.scheme.sign()) {
+            warn!(
+                "peer signed kx with wrong algorithm (got {:?} expect {:?})",
+                sig.scheme.sign(),
+                suite.sign
+            );
+            return Err(PeerMisbehaved::SignedKxWithWrongAlgorithm.into());
+        }
 
-            st.config
-                .verifier
-                .verify_tls12_signature(&message, &st.server_cert.cert_chain[0], sig)
+        // 1 and 3.
+        let (cert_verified, sig_verified) = match self.verified.take() {
+            Some(verified) => verified,
+            None => {
+                // Build up the contents of the signed message.
+                // It's ClientHello.random || ServerHello.random || ServerKeyExchange.params
+                let mut message = Vec::new();
+                message.extend_from_slice(&self.randoms.client);
+                message.extend_from_slice(&self.randoms.server);
+                message.extend_from_slice(&self.server_kx.kx_params);
+
+                let pending = PendingVerification::new(
+                    &self.config,
+                    &self.server_name,
+                    &self.server_cert,
+                    ProtocolVersion::TLSv1_2,
+                    message,
+                    sig.clone(),
+                )
                 .map_err(|err| {
                     cx.common
                         .send_cert_verify_error_alert(err)
-                })?
+                })?;
+
+                if self.config.offload_verification {
+                    return Ok(AwaitVerification::new(pending, self, m));
+                }
+
+                pending
+                    .run(cx.common.user_data())
+                    .map_err(|err| {
+                        cx.common
+                            .send_cert_verify_error_alert(err)
+                    })?
+            }
         };
+
+        let mut st = *self;
+        st.transcript.add_message(&m);
         cx.common.peer_certificates = Some(st.server_cert.cert_chain);
 
         // 4.
@@ -767,14 +828,18 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
         let ecdh_params =
             tls12::decode_ecdh_params::<ServerECDHParams>(cx.common, &st.server_kx.kx_params)?;
         let named_group = ecdh_params.curve_params.named_group;
-        let kx =
-            match <<C as CryptoProvider>::KeyExchange>::start(named_group, &st.config.kx_groups) {
-                Ok(kx) => kx,
-                Err(KeyExchangeError::UnsupportedGroup) => {
-                    return Err(PeerMisbehaved::SelectedUnofferedKxGroup.into())
-                }
-                Err(KeyExchangeError::GetRandomFailed) => return Err(GetRandomFailed.into()),
-            };
+        let kx = match crypto::start_kx(
+            st.config.key_share_pool.as_deref(),
+            named_group,
+            &st.config.kx_groups,
+        ) {
+            Ok(kx) => kx,
+            Err(KeyExchangeError::UnsupportedGroup) => {
+                return Err(PeerMisbehaved::SelectedUnofferedKxGroup.into())
+            }
+            Err(KeyExchangeError::GetRandomFailed) => return Err(GetRandomFailed.into()),
+        };
+        cx.common.kx_group = Some(named_group);
 
         // 5b.
         let mut transcript = st.transcript;
@@ -799,6 +864,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
             ems_seed,
             st.randoms,
             suite,
+            hmac::for_suite::<C>(suite.hash_algorithm())?,
         )?;
 
         st.config.key_log.log(
@@ -846,6 +912,18 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectServerDone<C> {
     }
 }
 
+impl<C: CryptoProvider> ResumesAfterVerification for ExpectServerDone<C> {
+    fn resume(
+        mut self: Box<Self>,
+        cx: &mut ClientContext<'_>,
+        verified: ServerVerified,
+        message: Message,
+    ) -> hs::NextStateOrError {
+        self.verified = Some(verified);
+        self.handle(cx, message)
+    }
+}
+
 struct ExpectNewTicket<C: CryptoProvider> {
     config: Arc<ClientConfig<C>>,
     secrets: ConnectionSecrets,
@@ -975,7 +1053,18 @@ impl<C: CryptoProvider> ExpectFinished<C> {
             return;
         }
 
-        let time_now = match TimeBase::now() {
+        if ticket.is_empty()
+            && !self
+                .config
+                .resumption
+                .tls12_resumption
+                .session_ids()
+        {
+            debug!("Session not saved: resumption by session id is disabled");
+            return;
+        }
+
+        let time_now = match TimeBase::from_provider(&*self.config.time_provider) {
             Ok(time_now) => time_now,
             #[allow(unused_variables)]
             Err(e) => {
@@ -984,7 +1073,7 @@ impl<C: CryptoProvider> ExpectFinished<C> {
             }
         };
 
-        let session_value = persist::Tls12ClientSessionValue::new(
+        let mut session_value = persist::Tls12ClientSessionValue::new(
             self.secrets.suite(),
             self.session_id,
             ticket,
@@ -997,11 +1086,18 @@ impl<C: CryptoProvider> ExpectFinished<C> {
             lifetime,
             self.using_ems,
         );
+        session_value
+            .common
+            .set_alpn_protocol(cx.common.alpn_protocol.clone());
 
         self.config
             .resumption
             .store
             .set_tls12_session(&self.server_name, session_value);
+        self.config
+            .resumption
+            .observer
+            .session_stored(&self.server_name, ProtocolVersion::TLSv1_2);
     }
 }
 
@@ -1092,4 +1188,30 @@ impl State<ClientConnectionData> for ExpectTraffic {
         self.secrets
             .extract_secrets(Side::Client)
     }
+
+    #[cfg(feature = "dangerous_configuration")]
+    fn export_traffic_secrets(self: Box<Self>) -> Result<TrafficSecrets, Error> {
+        Ok(TrafficSecrets::Tls12(self.secrets))
+    }
+}
+
+/// Carry on an exported connection, whose keys are `secrets`.
+#[cfg(feature = "dangerous_configuration")]
+pub(super) fn restore_traffic(
+    secrets: ConnectionSecrets,
+    common: &mut CommonState,
+) -> Box<dyn State<ClientConnectionData>> {
+    let (dec, enc) = secrets.make_cipher_pair(Side::Client);
+    common
+        .record_layer
+        .set_message_encrypter(enc);
+    common
+        .record_layer
+        .set_message_decrypter(dec);
+    Box::new(ExpectTraffic {
+        secrets,
+        _cert_verified: verify::ServerCertVerified::assertion(),
+        _sig_verified: verify::HandshakeSignatureValid::assertion(),
+        _fin_verified: verify::FinishedMessageVerified::assertion(),
+    })
 }
@@ -1,15 +1,18 @@
+use crate::cert_stream::CertificateStream;
 use crate::check::inappropriate_handshake_message;
 #[cfg(feature = "quic")]
 use crate::common_state::Protocol;
 #[cfg(feature = "secret_extraction")]
 use crate::common_state::Side;
-use crate::common_state::{CommonState, State};
+use crate::common_state::{CommonState, DuplicateExtensionPolicy, State};
 use crate::conn::ConnectionRandoms;
-use crate::crypto::{CryptoProvider, KeyExchange, SupportedGroup};
+use crate::crypto::{self, hash, hmac, CryptoProvider, KeyExchange, SupportedGroup};
 use crate::enums::{
     AlertDescription, ContentType, HandshakeType, ProtocolVersion, SignatureScheme,
 };
 use crate::error::{Error, InvalidMessage, PeerIncompatible, PeerMisbehaved};
+#[cfg(feature = "dangerous_configuration")]
+use crate::handover::{Tls13ClientResumption, Tls13TrafficSecrets, TrafficSecrets};
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
@@ -17,11 +20,11 @@ use crate::msgs::base::{Payload, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::enums::ExtensionType;
 use crate::msgs::enums::KeyUpdateRequest;
-use crate::msgs::handshake::NewSessionTicketPayloadTLS13;
-use crate::msgs::handshake::{CertificateEntry, CertificatePayloadTLS13};
+use crate::msgs::handshake::{CertificateEntry, CertificateExtension, CertificatePayloadTLS13};
 use crate::msgs::handshake::{ClientExtension, ServerExtension};
 use crate::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
 use crate::msgs::handshake::{HasServerExtensions, ServerHelloPayload};
+use crate::msgs::handshake::{NewSessionTicketExtension, NewSessionTicketPayloadTLS13};
 use crate::msgs::handshake::{PresharedKeyIdentity, PresharedKeyOffer};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
@@ -36,11 +39,16 @@ use crate::{sign, KeyLog};
 
 use super::client_conn::ClientConnectionData;
 use super::hs::ClientContext;
+use super::offload::{
+    AwaitVerification, PendingVerification, ResumesAfterVerification, ServerVerified,
+};
 use crate::client::common::ServerCertDetails;
 use crate::client::common::{ClientAuthDetails, ClientHelloDetails};
-use crate::client::{hs, ClientConfig, ClientSessionStore, ServerName};
+use crate::client::{hs, ClientConfig, ClientSessionStore, ObservesClientResumption};
+use crate::client::{ResumptionRejected, ServerName};
 
 use crate::ticketer::TimeBase;
+use crate::time_provider::TimeProvider;
 use subtle::ConstantTimeEq;
 
 use crate::sign::{CertifiedKey, Signer};
@@ -60,6 +68,7 @@ static DISALLOWED_TLS13_EXTS: &[ExtensionType] = &[
     ExtensionType::SessionTicket,
     ExtensionType::RenegotiationInfo,
     ExtensionType::ExtendedMasterSecret,
+    ExtensionType::SCT,
 ];
 
 pub(super) fn handle_server_hello<C: CryptoProvider>(
@@ -95,7 +104,11 @@ pub(super) fn handle_server_hello<C: CryptoProvider>(
             )
         });
     }
+    cx.common.kx_group = Some(their_key_share.group);
 
+    // We only lack an early key schedule for a session we found if the
+    // suite chosen in a HelloRetryRequest can't resume it.
+    let offered_psk = early_key_schedule.is_some();
     let key_schedule_pre_handshake = if let (Some(selected_psk), Some(early_key_schedule)) =
         (server_hello.get_psk_index(), early_key_schedule)
     {
@@ -133,6 +146,11 @@ pub(super) fn handle_server_hello<C: CryptoProvider>(
             }
 
             debug!("Resuming using PSK");
+            config
+                .resumption
+                .observer
+                .resumption_accepted(&server_name, ProtocolVersion::TLSv1_3);
+            cx.common.resumed = true;
             // The key schedule has been initialized and set in fill_in_psk_binder()
         } else {
             return Err(PeerMisbehaved::SelectedUnofferedPsk.into());
@@ -140,11 +158,21 @@ pub(super) fn handle_server_hello<C: CryptoProvider>(
         KeySchedulePreHandshake::from(early_key_schedule)
     } else {
         debug!("Not resuming");
+        if resuming_session.is_some() {
+            let reason = match offered_psk {
+                true => ResumptionRejected::ServerDeclined,
+                false => ResumptionRejected::IncompatibleCipherSuite,
+            };
+            config
+                .resumption
+                .observer
+                .resumption_rejected(&server_name, reason);
+        }
         // Discard the early data key schedule.
         cx.data.early_data.rejected();
         cx.common.early_traffic = false;
         resuming_session.take();
-        KeySchedulePreHandshake::new(suite)
+        KeySchedulePreHandshake::new::<C>(suite)?
     };
 
     let key_schedule = our_key_share.complete(&their_key_share.payload.0, |secret| {
@@ -223,19 +251,20 @@ pub(super) fn initial_key_share<C: CryptoProvider>(
         })
         .name();
 
-    KeyExchange::start(group, &config.kx_groups).map_err(|_| Error::FailedToGetRandomBytes)
+    crypto::start_kx(config.key_share_pool.as_deref(), group, &config.kx_groups)
+        .map_err(|_| Error::FailedToGetRandomBytes)
 }
 
 /// This implements the horrifying TLS1.3 hack where PSK binders have a
 /// data dependency on the message they are contained within.
-pub(super) fn fill_in_psk_binder(
+pub(super) fn fill_in_psk_binder<C: CryptoProvider>(
     resuming: &persist::Tls13ClientSessionValue,
+    suite_hash: &'static dyn hash::Hash,
     transcript: &HandshakeHashBuffer,
     hmp: &mut HandshakeMessagePayload,
-) -> KeyScheduleEarly {
+) -> Result<KeyScheduleEarly, Error> {
     // We need to know the hash function of the suite we're trying to resume into.
     let suite = resuming.suite();
-    let suite_hash = suite.hash_algorithm();
 
     // The binder is calculated over the clienthello, but doesn't include itself or its
     // length, or the length of its container.
@@ -244,14 +273,14 @@ pub(super) fn fill_in_psk_binder(
 
     // Run a fake key_schedule to simulate what the server will do if it chooses
     // to resume.
-    let key_schedule = KeyScheduleEarly::new(suite, resuming.secret());
+    let key_schedule = KeyScheduleEarly::new::<C>(suite, resuming.secret())?;
     let real_binder = key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
 
     if let HandshakePayload::ClientHello(ref mut ch) = hmp.payload {
         ch.set_psk_binder(real_binder.as_ref());
     };
 
-    key_schedule
+    Ok(key_schedule)
 }
 
 pub(super) fn prepare_resumption(
@@ -295,7 +324,7 @@ pub(super) fn prepare_resumption(
 pub(super) fn derive_early_traffic_secret(
     key_log: &dyn KeyLog,
     cx: &mut ClientContext<'_>,
-    resuming_suite: &'static Tls13CipherSuite,
+    suite_hash: &'static dyn hash::Hash,
     early_key_schedule: &KeyScheduleEarly,
     sent_tls13_fake_ccs: &mut bool,
     transcript_buffer: &HandshakeHashBuffer,
@@ -304,7 +333,7 @@ pub(super) fn derive_early_traffic_secret(
     // For middlebox compatibility
     emit_fake_ccs(sent_tls13_fake_ccs, cx.common);
 
-    let client_hello_hash = transcript_buffer.get_hash_given(resuming_suite.hash_algorithm(), &[]);
+    let client_hello_hash = transcript_buffer.get_hash_given(suite_hash, &[]);
     early_key_schedule.client_early_traffic_secret(
         &client_hello_hash,
         key_log,
@@ -336,9 +365,15 @@ pub(super) fn emit_fake_ccs(sent_tls13_fake_ccs: &mut bool, common: &mut CommonS
 fn validate_encrypted_extensions(
     common: &mut CommonState,
     hello: &ClientHelloDetails,
+    policy: &DuplicateExtensionPolicy,
     exts: &Vec<ServerExtension>,
 ) -> Result<(), Error> {
-    if exts.has_duplicate_extension() {
+    if exts.has_duplicate_extension()
+        && !policy.tolerates(
+            exts.iter()
+                .map(ServerExtension::get_type),
+        )
+    {
         return Err(common.send_fatal_alert(
             AlertDescription::DecodeError,
             PeerMisbehaved::DuplicateEncryptedExtensions,
@@ -387,7 +422,12 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectEncryptedExtension
         debug!("TLS1.3 encrypted extensions: {:?}", exts);
         self.transcript.add_message(&m);
 
-        validate_encrypted_extensions(cx.common, &self.hello, exts)?;
+        validate_encrypted_extensions(
+            cx.common,
+            &self.hello,
+            &self.config.duplicate_extensions,
+            exts,
+        )?;
         hs::process_alpn_protocol(cx.common, &self.config, exts.get_alpn_protocol())?;
 
         #[cfg(feature = "quic")]
@@ -454,6 +494,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectEncryptedExtension
                 suite: self.suite,
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
+                cert_stream: CertificateStream::default(),
             }))
         }
     }
@@ -466,6 +507,7 @@ struct ExpectCertificateOrCertReq<C: CryptoProvider> {
     suite: &'static Tls13CipherSuite,
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
+    cert_stream: CertificateStream,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateOrCertReq<C> {
@@ -486,6 +528,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateOrCertR
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 client_auth: None,
+                cert_stream: self.cert_stream,
             })
             .handle(cx, m),
             MessagePayload::Handshake {
@@ -514,6 +557,20 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateOrCertR
             )),
         }
     }
+
+    fn handle_partial_handshake(
+        &mut self,
+        cx: &mut ClientContext<'_>,
+        received: &[u8],
+    ) -> Result<(), Error> {
+        hs::precheck_partial_chain(
+            cx.common,
+            &self.config,
+            &mut self.cert_stream,
+            received,
+            true,
+        )
+    }
 }
 
 // TLS1.3 version of CertificateRequest handling.  We then move to expecting the server
@@ -559,6 +616,10 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
             .cloned()
             .filter(|scheme| tls13_sign_schemes.contains(scheme))
             .collect::<Vec<SignatureScheme>>();
+        let compat_sigschemes = verify::allowed_signature_schemes(
+            self.config.signature_schemes.as_deref(),
+            compat_sigschemes,
+        );
 
         if compat_sigschemes.is_empty() {
             return Err(cx.common.send_fatal_alert(
@@ -574,7 +635,12 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
             certreq.get_authorities_extension(),
             &compat_sigschemes,
             Some(certreq.context.0.clone()),
-        );
+            cx.common.user_data(),
+        )
+        .map_err(|err| {
+            cx.common
+                .send_fatal_alert(AlertDescription::HandshakeFailure, err)
+        })?;
 
         Ok(Box::new(ExpectCertificate {
             config: self.config,
@@ -584,6 +650,7 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateRequest
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             client_auth: Some(client_auth),
+            cert_stream: CertificateStream::default(),
         }))
     }
 }
@@ -596,6 +663,7 @@ struct ExpectCertificate<C: CryptoProvider> {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     client_auth: Option<ClientAuthDetails>,
+    cert_stream: CertificateStream,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
@@ -615,9 +683,19 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
             ));
         }
 
-        if cert_chain.any_entry_has_duplicate_extension()
-            || cert_chain.any_entry_has_unknown_extension()
-        {
+        let duplicate_extensions = cert_chain.entries.iter().any(|entry| {
+            entry.has_duplicate_extension()
+                && !self
+                    .config
+                    .duplicate_extensions
+                    .tolerates(
+                        entry
+                            .exts
+                            .iter()
+                            .map(CertificateExtension::get_type),
+                    )
+        });
+        if duplicate_extensions || cert_chain.any_entry_has_unknown_extension() {
             return Err(cx.common.send_fatal_alert(
                 AlertDescription::UnsupportedExtension,
                 PeerMisbehaved::BadCertChainExtensions,
@@ -626,6 +704,20 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
 
         let server_cert =
             ServerCertDetails::new(cert_chain.convert(), cert_chain.get_end_entity_ocsp());
+        hs::precheck_chain(
+            cx.common,
+            &self.config,
+            &mut self.cert_stream,
+            &server_cert.cert_chain,
+        )?;
+        if !server_cert.ocsp_response.is_empty() {
+            cx.data.ocsp_response = Some(server_cert.ocsp_response.clone());
+        }
+        if let Some(sct_list) = cert_chain.get_end_entity_scts() {
+            cx.data.received_sct_list(sct_list);
+        }
+        cx.data
+            .received_end_entity(server_cert.cert_chain.first());
 
         Ok(Box::new(ExpectCertificateVerify {
             config: self.config,
@@ -636,8 +728,23 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificate<C> {
             key_schedule: self.key_schedule,
             server_cert,
             client_auth: self.client_auth,
+            verified: None,
         }))
     }
+
+    fn handle_partial_handshake(
+        &mut self,
+        cx: &mut ClientContext<'_>,
+        received: &[u8],
+    ) -> Result<(), Error> {
+        hs::precheck_partial_chain(
+            cx.common,
+            &self.config,
+            &mut self.cert_stream,
+            received,
+            true,
+        )
+    }
 }
 
 // --- TLS1.3 CertificateVerify ---
@@ -650,6 +757,7 @@ struct ExpectCertificateVerify<C: CryptoProvider> {
     key_schedule: KeyScheduleHandshake,
     server_cert: ServerCertDetails,
     client_auth: Option<ClientAuthDetails>,
+    verified: Option<ServerVerified>,
 }
 
 impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateVerify<C> {
@@ -662,42 +770,36 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateVerify<
 
         trace!("Server cert is {:?}", self.server_cert.cert_chain);
 
-        // 1. Verify the certificate chain.
-        let (end_entity, intermediates) = self
-            .server_cert
-            .cert_chain
-            .split_first()
-            .ok_or(Error::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
-        let cert_verified = self
-            .config
-            .verifier
-            .verify_server_cert(
-                end_entity,
-                intermediates,
-                &self.server_name,
-                &self.server_cert.ocsp_response,
-                now,
-            )
-            .map_err(|err| {
-                cx.common
-                    .send_cert_verify_error_alert(err)
-            })?;
-
-        // 2. Verify their signature on the handshake.
-        let handshake_hash = self.transcript.get_current_hash();
-        let sig_verified = self
-            .config
-            .verifier
-            .verify_tls13_signature(
-                &verify::construct_tls13_server_verify_message(&handshake_hash),
-                &self.server_cert.cert_chain[0],
-                cert_verify,
-            )
-            .map_err(|err| {
-                cx.common
-                    .send_cert_verify_error_alert(err)
-            })?;
+        // Verify the certificate chain, and their signature on the handshake.
+        let (cert_verified, sig_verified) = match self.verified.take() {
+            Some(verified) => verified,
+            None => {
+                let handshake_hash = self.transcript.get_current_hash();
+                let pending = PendingVerification::new(
+                    &self.config,
+                    &self.server_name,
+                    &self.server_cert,
+                    ProtocolVersion::TLSv1_3,
+                    verify::construct_tls13_server_verify_message(&handshake_hash),
+                    cert_verify.clone(),
+                )
+                .map_err(|err| {
+                    cx.common
+                        .send_cert_verify_error_alert(err)
+                })?;
+
+                if self.config.offload_verification {
+                    return Ok(AwaitVerification::new(pending, self, m));
+                }
+
+                pending
+                    .run(cx.common.user_data())
+                    .map_err(|err| {
+                        cx.common
+                            .send_cert_verify_error_alert(err)
+                    })?
+            }
+        };
 
         cx.common.peer_certificates = Some(self.server_cert.cert_chain);
         self.transcript.add_message(&m);
@@ -716,6 +818,18 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectCertificateVerify<
     }
 }
 
+impl<C: CryptoProvider> ResumesAfterVerification for ExpectCertificateVerify<C> {
+    fn resume(
+        mut self: Box<Self>,
+        cx: &mut ClientContext<'_>,
+        verified: ServerVerified,
+        message: Message,
+    ) -> hs::NextStateOrError {
+        self.verified = Some(verified);
+        self.handle(cx, message)
+    }
+}
+
 fn emit_certificate_tls13(
     transcript: &mut HandshakeHash,
     certkey: Option<&CertifiedKey>,
@@ -774,7 +888,7 @@ fn emit_certverify_tls13(
 
 fn emit_finished_tls13(
     transcript: &mut HandshakeHash,
-    verify_data: ring::hmac::Tag,
+    verify_data: hmac::Tag,
     common: &mut CommonState,
 ) {
     let verify_data_payload = Payload::new(verify_data.as_ref());
@@ -903,9 +1017,13 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectFinished<C> {
 
         let st = ExpectTraffic {
             session_storage: Arc::clone(&st.config.resumption.store),
+            resumption_observer: Arc::clone(&st.config.resumption.observer),
+            time_provider: Arc::clone(&st.config.time_provider),
+            duplicate_extensions: st.config.duplicate_extensions.clone(),
             server_name: st.server_name,
             suite: st.suite,
-            transcript: st.transcript,
+            resumption_master_secret: key_schedule_traffic
+                .resumption_master_secret(&st.transcript.get_current_hash()),
             key_schedule: key_schedule_traffic,
             _cert_verified: st.cert_verified,
             _sig_verified: st.sig_verified,
@@ -926,9 +1044,12 @@ impl<C: CryptoProvider> State<ClientConnectionData> for ExpectFinished<C> {
 // and application data.
 struct ExpectTraffic {
     session_storage: Arc<dyn ClientSessionStore>,
+    resumption_observer: Arc<dyn ObservesClientResumption>,
+    time_provider: Arc<dyn TimeProvider>,
+    duplicate_extensions: DuplicateExtensionPolicy,
     server_name: ServerName,
     suite: &'static Tls13CipherSuite,
-    transcript: HandshakeHash,
+    resumption_master_secret: Vec<u8>,
     key_schedule: KeyScheduleTraffic,
     _cert_verified: verify::ServerCertVerified,
     _sig_verified: verify::HandshakeSignatureValid,
@@ -942,19 +1063,24 @@ impl ExpectTraffic {
         cx: &mut ClientContext<'_>,
         nst: &NewSessionTicketPayloadTLS13,
     ) -> Result<(), Error> {
-        if nst.has_duplicate_extension() {
+        if nst.has_duplicate_extension()
+            && !self.duplicate_extensions.tolerates(
+                nst.exts
+                    .iter()
+                    .map(NewSessionTicketExtension::get_type),
+            )
+        {
             return Err(cx.common.send_fatal_alert(
                 AlertDescription::IllegalParameter,
                 PeerMisbehaved::DuplicateNewSessionTicketExtensions,
             ));
         }
 
-        let handshake_hash = self.transcript.get_current_hash();
         let secret = self
             .key_schedule
-            .resumption_master_secret_and_derive_ticket_psk(&handshake_hash, &nst.nonce.0);
+            .derive_ticket_psk(&self.resumption_master_secret, &nst.nonce.0);
 
-        let time_now = match TimeBase::now() {
+        let time_now = match TimeBase::from_provider(&*self.time_provider) {
             Ok(t) => t,
             #[allow(unused_variables)]
             Err(e) => {
@@ -963,7 +1089,6 @@ impl ExpectTraffic {
             }
         };
 
-        #[allow(unused_mut)]
         let mut value = persist::Tls13ClientSessionValue::new(
             self.suite,
             nst.ticket.0.clone(),
@@ -979,6 +1104,10 @@ impl ExpectTraffic {
                 .unwrap_or_default(),
         );
 
+        value
+            .common
+            .set_alpn_protocol(cx.common.alpn_protocol.clone());
+
         #[cfg(feature = "quic")]
         if cx.common.is_quic() {
             if let Some(sz) = nst.get_max_early_data_size() {
@@ -994,6 +1123,8 @@ impl ExpectTraffic {
 
         self.session_storage
             .insert_tls13_ticket(&self.server_name, value);
+        self.resumption_observer
+            .session_stored(&self.server_name, ProtocolVersion::TLSv1_3);
         Ok(())
     }
 
@@ -1076,6 +1207,42 @@ impl State<ClientConnectionData> for ExpectTraffic {
         self.key_schedule
             .extract_secrets(Side::Client)
     }
+
+    #[cfg(feature = "dangerous_configuration")]
+    fn export_traffic_secrets(self: Box<Self>) -> Result<TrafficSecrets, Error> {
+        Ok(TrafficSecrets::Tls13(self.key_schedule.export_secrets(
+            Some(Tls13ClientResumption {
+                server_name: self.server_name,
+                resumption_master_secret: self.resumption_master_secret,
+            }),
+        )))
+    }
+}
+
+/// Carry on an exported connection, whose keys are `secrets`.
+#[cfg(feature = "dangerous_configuration")]
+pub(super) fn restore_traffic<C: CryptoProvider>(
+    config: &ClientConfig<C>,
+    secrets: Tls13TrafficSecrets,
+    common: &mut CommonState,
+) -> Result<Box<dyn State<ClientConnectionData>>, Error> {
+    let key_schedule = KeyScheduleTraffic::restore::<C>(&secrets, common)?;
+    let resumption = secrets
+        .resumption
+        .ok_or_else(|| Error::General("exported connection is not a client".into()))?;
+    Ok(Box::new(ExpectTraffic {
+        session_storage: Arc::clone(&config.resumption.store),
+        resumption_observer: Arc::clone(&config.resumption.observer),
+        time_provider: Arc::clone(&config.time_provider),
+        duplicate_extensions: config.duplicate_extensions.clone(),
+        server_name: resumption.server_name,
+        suite: secrets.suite,
+        resumption_master_secret: resumption.resumption_master_secret,
+        key_schedule,
+        _cert_verified: verify::ServerCertVerified::assertion(),
+        _sig_verified: verify::HandshakeSignatureValid::assertion(),
+        _fin_verified: verify::FinishedMessageVerified::assertion(),
+    }))
 }
 
 #[cfg(feature = "quic")]
@@ -1,37 +1,61 @@
+use crate::budget::{MemoryBudget, MemoryCharge};
+use crate::capture::{CapturedMessage, HandshakeCapture, MessageDirection};
+use crate::client::{CompletedVerification, PendingVerification};
 use crate::enums::{AlertDescription, ContentType, HandshakeType, ProtocolVersion};
-use crate::error::{Error, InvalidMessage, PeerMisbehaved};
+use crate::error::{Error, InvalidMessage, PeerIncompatible, PeerMisbehaved};
+#[cfg(feature = "dangerous_configuration")]
+use crate::handover::TrafficSecrets;
 use crate::key;
 #[cfg(feature = "logging")]
 use crate::log::{debug, warn};
 use crate::msgs::alert::AlertMessagePayload;
 use crate::msgs::base::Payload;
-use crate::msgs::enums::{AlertLevel, KeyUpdateRequest};
+use crate::msgs::enums::{AlertLevel, ExtensionType, KeyUpdateRequest, NamedGroup};
 use crate::msgs::fragmenter::MessageFragmenter;
-#[cfg(feature = "quic")]
-use crate::msgs::message::MessagePayload;
-use crate::msgs::message::{BorrowedPlainMessage, Message, OpaqueMessage, PlainMessage};
+use crate::msgs::message::{
+    BorrowedPlainMessage, Message, MessagePayload, OpaqueMessage, PlainMessage,
+};
+use crate::pacing::{WritePacer, WritePacing};
 #[cfg(feature = "quic")]
 use crate::quic;
 use crate::record_layer;
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
 use crate::suites::SupportedCipherSuite;
+use crate::time_provider::{self, TimeProvider};
 #[cfg(feature = "tls12")]
 use crate::tls12::ConnectionSecrets;
 use crate::vecbuf::ChunkVecBuffer;
 
+use alloc::sync::Arc;
+use core::any::Any;
+use core::mem;
+use core::time::Duration;
+use std::io;
+use std::time::SystemTime;
+
 /// Connection state common to both client and server connections.
 pub struct CommonState {
     pub(crate) negotiated_version: Option<ProtocolVersion>,
     pub(crate) side: Side,
     pub(crate) record_layer: record_layer::RecordLayer,
     pub(crate) suite: Option<SupportedCipherSuite>,
+    pub(crate) kx_group: Option<NamedGroup>,
     pub(crate) alpn_protocol: Option<Vec<u8>>,
     pub(crate) aligned_handshake: bool,
     pub(crate) may_send_application_data: bool,
     pub(crate) may_receive_application_data: bool,
     pub(crate) early_traffic: bool,
-    sent_fatal_alert: bool,
+    /// Whether a TLS1.2 connection negotiated extended master secret.
+    pub(crate) using_ems: bool,
+    /// Whether the handshake resumed an earlier session.
+    pub(crate) resumed: bool,
+    /// Whether the handshake included a TLS1.3 `HelloRetryRequest`.
+    pub(crate) hello_retried: bool,
+    /// Whether a TLS1.2 peer signalled support for secure renegotiation.
+    pub(crate) secure_renegotiation: bool,
+    pub(crate) renegotiation_policy: RenegotiationPolicy,
+    sent_fatal_alert: Option<AlertDescription>,
     /// If the peer has signaled end of stream.
     pub(crate) has_received_close_notify: bool,
     pub(crate) has_seen_eof: bool,
@@ -42,6 +66,11 @@ pub struct CommonState {
     sendable_plaintext: ChunkVecBuffer,
     pub(crate) sendable_tls: ChunkVecBuffer,
     queued_key_update_message: Option<Vec<u8>>,
+    handshake_capture: Option<HandshakeCapture>,
+    /// The handshake deadline and record limits, if configured.
+    limits: Option<Box<ConnectionLimits>>,
+    sent_messages: Option<Vec<PlainMessage>>,
+    pub(crate) user_data: Option<Box<dyn Any + Send + Sync>>,
 
     #[allow(dead_code)] // only read for QUIC
     /// Protocol whose key schedule should be used. Unused for TLS < 1.3.
@@ -59,12 +88,18 @@ impl CommonState {
             side,
             record_layer: record_layer::RecordLayer::new(),
             suite: None,
+            kx_group: None,
             alpn_protocol: None,
             aligned_handshake: true,
             may_send_application_data: false,
             may_receive_application_data: false,
             early_traffic: false,
-            sent_fatal_alert: false,
+            using_ems: false,
+            resumed: false,
+            hello_retried: false,
+            secure_renegotiation: false,
+            renegotiation_policy: RenegotiationPolicy::Reject,
+            sent_fatal_alert: None,
             has_received_close_notify: false,
             has_seen_eof: false,
             received_middlebox_ccs: 0,
@@ -74,6 +109,10 @@ impl CommonState {
             sendable_plaintext: ChunkVecBuffer::new(Some(DEFAULT_BUFFER_LIMIT)),
             sendable_tls: ChunkVecBuffer::new(Some(DEFAULT_BUFFER_LIMIT)),
             queued_key_update_message: None,
+            handshake_capture: None,
+            limits: None,
+            sent_messages: None,
+            user_data: None,
 
             protocol: Protocol::Tcp,
             #[cfg(feature = "quic")]
@@ -143,6 +182,273 @@ impl CommonState {
         self.negotiated_version
     }
 
+    /// Retrieves the fatal alert sent to the peer when this connection
+    /// failed, if any.
+    ///
+    /// Along with [`Error::code`], this describes how rustls rejected
+    /// input from the peer.
+    pub fn sent_fatal_alert(&self) -> Option<AlertDescription> {
+        self.sent_fatal_alert
+    }
+
+    /// Retrieves whether the connection's secrets are bound to its handshake,
+    /// using the extended master secret extension of [RFC 7627] for TLS1.2.
+    ///
+    /// This is always true for TLS1.3, and returns `None` until the version
+    /// is agreed.
+    ///
+    /// [RFC 7627]: https://datatracker.ietf.org/doc/html/rfc7627
+    pub fn extended_master_secret(&self) -> Option<bool> {
+        match self.negotiated_version? {
+            ProtocolVersion::TLSv1_3 => Some(true),
+            _ => Some(self.using_ems),
+        }
+    }
+
+    /// Retrieves whether the peer signalled support for the secure
+    /// renegotiation extension of [RFC 5746] in a TLS1.2 handshake.
+    ///
+    /// rustls never renegotiates, but this shows whether the peer has the
+    /// fix for the renegotiation attack that the extension addresses.
+    /// This returns `None` until the version is agreed, and for TLS1.3,
+    /// which has no renegotiation.
+    ///
+    /// [RFC 5746]: https://datatracker.ietf.org/doc/html/rfc5746
+    pub fn secure_renegotiation(&self) -> Option<bool> {
+        match self.negotiated_version? {
+            ProtocolVersion::TLSv1_3 => None,
+            _ => Some(self.secure_renegotiation),
+        }
+    }
+
+    /// The negotiated parameters, without the server name.
+    pub(crate) fn negotiated(&self) -> Negotiated<'_> {
+        Negotiated {
+            version: self.negotiated_version,
+            cipher_suite: self.suite,
+            kx_group: self.kx_group,
+            alpn_protocol: self.get_alpn_protocol(),
+            server_name: None,
+            resumed: self.resumed,
+            hello_retried: self.hello_retried,
+            peer_certificates: self.peer_certificates(),
+            extended_master_secret: self.extended_master_secret(),
+        }
+    }
+
+    /// Retrieves the handshake messages recorded so far, if handshake capture
+    /// was enabled in the connection's config.
+    ///
+    /// The record survives handshake failure, so this can be called after
+    /// [`Connection::process_new_packets()`] returns an error to see how far
+    /// the handshake got.
+    ///
+    /// [`Connection::process_new_packets()`]: crate::Connection::process_new_packets
+    pub fn handshake_capture(&self) -> Option<&[CapturedMessage]> {
+        self.handshake_capture
+            .as_ref()
+            .map(HandshakeCapture::messages)
+    }
+
+    /// Attaches an application-defined value to this connection, replacing
+    /// any previous one.
+    ///
+    /// rustls does nothing with the value except hand it to the callbacks it
+    /// makes on behalf of this connection, so they can tell connections apart
+    /// without keeping a side table:
+    ///
+    /// - [`ClientHello::user_data`], for server certificate resolvers,
+    /// - [`ResolvesClientCert::resolve_with_user_data`],
+    /// - `ServerCertVerifier::verify_server_cert_with_user_data`, and
+    /// - `ClientCertVerifier::verify_client_cert_with_user_data`.
+    ///
+    /// Recover the value with `downcast_ref`.
+    ///
+    /// [`ClientHello::user_data`]: crate::server::ClientHello::user_data
+    /// [`ResolvesClientCert::resolve_with_user_data`]: crate::client::ResolvesClientCert::resolve_with_user_data
+    pub fn set_user_data(&mut self, data: Box<dyn Any + Send + Sync>) {
+        self.user_data = Some(data);
+    }
+
+    /// Returns the value attached with [`CommonState::set_user_data`], if any.
+    pub fn user_data(&self) -> Option<&(dyn Any + Send + Sync)> {
+        self.user_data.as_deref()
+    }
+
+    /// The time by which the handshake must complete, if the config
+    /// set a `handshake_timeout` and the handshake is still underway.
+    ///
+    /// The deadline is only enforced when [`Connection::process_new_packets`]
+    /// is called, so an application waiting for the peer should arrange to
+    /// call it at this time.
+    ///
+    /// [`Connection::process_new_packets`]: crate::Connection::process_new_packets
+    pub fn handshake_deadline(&self) -> Option<SystemTime> {
+        match self.limits.as_deref() {
+            Some(ConnectionLimits {
+                handshake_deadline: Some(deadline),
+                ..
+            }) if self.is_handshaking() => Some(deadline.deadline),
+            _ => None,
+        }
+    }
+
+    fn limits(&mut self) -> &mut ConnectionLimits {
+        self.limits
+            .get_or_insert_with(Box::default)
+    }
+
+    /// Require the handshake to complete within `timeout` from now.
+    pub(crate) fn set_handshake_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+        time_provider: &Arc<dyn TimeProvider>,
+    ) -> Result<(), Error> {
+        let deadline = match timeout {
+            Some(timeout) => time_provider::current_time(&**time_provider)?.checked_add(timeout),
+            None => None,
+        };
+        if let Some(deadline) = deadline {
+            self.limits().handshake_deadline = Some(HandshakeDeadline {
+                time_provider: Arc::clone(time_provider),
+                deadline,
+            });
+        }
+        Ok(())
+    }
+
+    /// Charge this connection, of `size` bytes, to `budget`.
+    pub(crate) fn set_memory_budget(
+        &mut self,
+        budget: Option<&Arc<MemoryBudget>>,
+        size: usize,
+    ) -> Result<(), Error> {
+        if let Some(budget) = budget {
+            self.limits().memory = Some(MemoryBudget::admit(budget, size)?);
+        }
+        Ok(())
+    }
+
+    /// Bring the memory budget charge up to date, given the connection's
+    /// `size` excluding buffered data.  Returns true if the budget is used
+    /// up, so the connection should release what it can.
+    pub(crate) fn update_memory_charge(&mut self, size: usize) -> bool {
+        let buffered =
+            self.received_plaintext.len() + self.sendable_plaintext.len() + self.sendable_tls.len();
+        match self
+            .limits
+            .as_mut()
+            .and_then(|limits| limits.memory.as_mut())
+        {
+            Some(charge) => charge.update(size + buffered),
+            None => false,
+        }
+    }
+
+    /// Pace application data encrypted from now on with `pacer`.
+    pub(crate) fn set_write_pacer(&mut self, pacer: Option<&Arc<dyn WritePacer>>) {
+        if let Some(pacer) = pacer {
+            self.limits().pacing = Some(WritePacing::new(pacer));
+        }
+    }
+
+    /// Note the outcome `res` of writing `pending` bytes of TLS data,
+    /// and start a new pacing cycle.
+    pub(crate) fn note_write_tls(&mut self, pending: usize, res: &io::Result<usize>) {
+        let pacing = match self
+            .limits
+            .as_mut()
+            .and_then(|limits| limits.pacing.as_mut())
+        {
+            Some(pacing) => pacing,
+            None => return,
+        };
+
+        let stalled = match res {
+            Ok(0) => pending > 0,
+            Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        };
+        if stalled {
+            pacing.peer_stalled(pending);
+        }
+        pacing.refresh();
+    }
+
+    /// Enforce `limits` on records received from now on.
+    pub(crate) fn set_record_limits(&mut self, limits: RecordLimits) {
+        if limits != RecordLimits::default() {
+            self.limits().records = Some(RecordCounter {
+                limits,
+                ..RecordCounter::default()
+            });
+        }
+    }
+
+    /// Count a received record against the configured [`RecordLimits`],
+    /// failing if one is exceeded.
+    pub(crate) fn count_received_record(&mut self, record: ReceivedRecord) -> Result<(), Error> {
+        let counter = match self
+            .limits
+            .as_mut()
+            .and_then(|limits| limits.records.as_mut())
+        {
+            Some(counter) => counter,
+            None => return Ok(()),
+        };
+
+        match counter.count(record) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(self.send_fatal_alert(AlertDescription::UnexpectedMessage, err)),
+        }
+    }
+
+    /// Fail if the handshake is still underway after its deadline.
+    pub(crate) fn check_handshake_deadline(&mut self) -> Result<(), Error> {
+        let is_handshaking = self.is_handshaking();
+        let deadline = match self.limits.as_mut() {
+            Some(limits) => match &limits.handshake_deadline {
+                Some(deadline) if is_handshaking => deadline,
+                Some(_) => {
+                    // The handshake completed in time.
+                    limits.handshake_deadline = None;
+                    return Ok(());
+                }
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        if time_provider::current_time(&*deadline.time_provider)? >= deadline.deadline {
+            return Err(Error::HandshakeTimedOut);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn enable_handshake_capture(&mut self, time_provider: Arc<dyn TimeProvider>) {
+        self.handshake_capture = Some(HandshakeCapture::new(time_provider));
+    }
+
+    pub(crate) fn capture_handshake_message(&mut self, direction: MessageDirection, msg: &Message) {
+        if let (Some(capture), MessagePayload::Handshake { parsed, encoded }) =
+            (&mut self.handshake_capture, &msg.payload)
+        {
+            capture.record(direction, parsed.typ, &encoded.0);
+        }
+    }
+
+    pub(crate) fn record_sent_messages(&mut self) {
+        self.sent_messages
+            .get_or_insert_with(Vec::new);
+    }
+
+    pub(crate) fn take_sent_messages(&mut self) -> Vec<PlainMessage> {
+        self.sent_messages
+            .as_mut()
+            .map(mem::take)
+            .unwrap_or_default()
+    }
+
     pub(crate) fn is_tls13(&self) -> bool {
         matches!(self.negotiated_version, Some(ProtocolVersion::TLSv1_3))
     }
@@ -161,7 +467,20 @@ impl CommonState {
                 Side::Server => HandshakeType::ClientHello,
             };
             if msg.is_handshake_type(reject_ty) {
-                self.send_warning_alert(AlertDescription::NoRenegotiation);
+                match self.renegotiation_policy {
+                    RenegotiationPolicy::Ignore => {
+                        debug!("Ignoring renegotiation request");
+                    }
+                    RenegotiationPolicy::Reject => {
+                        self.send_warning_alert(AlertDescription::NoRenegotiation);
+                    }
+                    RenegotiationPolicy::Close => {
+                        return Err(self.send_fatal_alert(
+                            AlertDescription::HandshakeFailure,
+                            PeerIncompatible::RenegotiationRequested,
+                        ));
+                    }
+                }
                 return Ok(state);
             }
         }
@@ -235,9 +554,19 @@ impl CommonState {
         // be out by whatever the cipher+record overhead is.  That's a
         // constant and predictable amount, so it's not a terrible issue.
         let len = match limit {
-            Limit::Yes => self
-                .sendable_tls
-                .apply_limit(payload.len()),
+            Limit::Yes => {
+                let len = self
+                    .sendable_tls
+                    .apply_limit(payload.len());
+                match self
+                    .limits
+                    .as_mut()
+                    .and_then(|limits| limits.pacing.as_mut())
+                {
+                    Some(pacing) => pacing.take(len),
+                    None => len,
+                }
+            }
             Limit::No => payload.len(),
         };
 
@@ -380,6 +709,17 @@ impl CommonState {
 
     /// Send a raw TLS message, fragmenting it if needed.
     pub(crate) fn send_msg(&mut self, m: Message, must_encrypt: bool) {
+        self.capture_handshake_message(MessageDirection::Sent, &m);
+        if let Some(sent) = &mut self.sent_messages {
+            let mut payload = Vec::new();
+            m.payload.encode(&mut payload);
+            sent.push(PlainMessage {
+                typ: m.payload.content_type(),
+                version: m.version,
+                payload: Payload(payload),
+            });
+        }
+
         #[cfg(feature = "quic")]
         {
             if let Protocol::Quic = self.protocol {
@@ -459,7 +799,7 @@ impl CommonState {
                 return Err(self.send_fatal_alert(AlertDescription::DecodeError, err));
             } else {
                 warn!("TLS alert warning received: {:#?}", alert);
-                return Ok(());
+                return self.count_received_record(ReceivedRecord::WarningAlert);
             }
         }
 
@@ -482,10 +822,10 @@ impl CommonState {
         desc: AlertDescription,
         err: impl Into<Error>,
     ) -> Error {
-        debug_assert!(!self.sent_fatal_alert);
+        debug_assert!(self.sent_fatal_alert.is_none());
         let m = Message::build_alert(AlertLevel::Fatal, desc);
         self.send_msg(m, self.record_layer.is_encrypting());
-        self.sent_fatal_alert = true;
+        self.sent_fatal_alert = Some(desc);
         err.into()
     }
 
@@ -583,6 +923,122 @@ impl CommonState {
     }
 }
 
+/// Limits on a connection that are seldom configured, so kept out of line.
+#[derive(Default)]
+struct ConnectionLimits {
+    handshake_deadline: Option<HandshakeDeadline>,
+    records: Option<RecordCounter>,
+    memory: Option<MemoryCharge>,
+    pacing: Option<WritePacing>,
+}
+
+/// When a handshake must be complete by, and the clock to check it against.
+struct HandshakeDeadline {
+    time_provider: Arc<dyn TimeProvider>,
+    deadline: SystemTime,
+}
+
+/// Limits on records that do not move a connection forward.
+///
+/// A peer can keep a connection busy indefinitely without making progress,
+/// for example by sending TLS1.2 warning alerts or empty records over and
+/// over.  These limits abort such connections with a fatal alert.  Each
+/// limit is `None` (unlimited) by default; [`RecordLimits::hardened`] sets
+/// them all.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RecordLimits {
+    /// How many warning alerts may be received over the connection's
+    /// lifetime.  `close_notify` is not counted.
+    ///
+    /// Exceeding this fails with [`PeerMisbehaved::TooManyWarningAlertsReceived`].
+    pub max_warning_alerts: Option<u32>,
+
+    /// How many empty records may be received over the connection's
+    /// lifetime.
+    ///
+    /// Exceeding this fails with [`PeerMisbehaved::TooManyEmptyRecords`].
+    pub max_empty_records: Option<u32>,
+
+    /// How many records in a row may be received that deliver no data and
+    /// no handshake message: warning alerts, empty records, and ignored
+    /// `ChangeCipherSpec` messages.
+    ///
+    /// Exceeding this fails with [`PeerMisbehaved::TooManyRecordsWithoutProgress`].
+    pub max_consecutive_non_advancing: Option<u32>,
+}
+
+impl RecordLimits {
+    /// Limits suitable for a connection terminator exposed to untrusted
+    /// peers.
+    pub fn hardened() -> Self {
+        Self {
+            max_warning_alerts: Some(4),
+            max_empty_records: Some(32),
+            max_consecutive_non_advancing: Some(32),
+        }
+    }
+}
+
+/// What a received record did, for [`RecordLimits`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ReceivedRecord {
+    WarningAlert,
+    Empty,
+    Ignored,
+    Advancing,
+}
+
+/// Counts received records against [`RecordLimits`].
+#[derive(Default)]
+struct RecordCounter {
+    limits: RecordLimits,
+    warning_alerts: u32,
+    empty_records: u32,
+    non_advancing: u32,
+}
+
+impl RecordCounter {
+    fn count(&mut self, record: ReceivedRecord) -> Result<(), PeerMisbehaved> {
+        fn bump(
+            count: &mut u32,
+            limit: Option<u32>,
+            err: PeerMisbehaved,
+        ) -> Result<(), PeerMisbehaved> {
+            *count = count.saturating_add(1);
+            match limit {
+                Some(limit) if *count > limit => Err(err),
+                _ => Ok(()),
+            }
+        }
+
+        match record {
+            ReceivedRecord::Advancing => {
+                self.non_advancing = 0;
+                return Ok(());
+            }
+            ReceivedRecord::WarningAlert => bump(
+                &mut self.warning_alerts,
+                self.limits.max_warning_alerts,
+                PeerMisbehaved::TooManyWarningAlertsReceived,
+            )?,
+            ReceivedRecord::Empty => bump(
+                &mut self.empty_records,
+                self.limits.max_empty_records,
+                PeerMisbehaved::TooManyEmptyRecords,
+            )?,
+            ReceivedRecord::Ignored => {}
+        }
+
+        bump(
+            &mut self.non_advancing,
+            self.limits
+                .max_consecutive_non_advancing,
+            PeerMisbehaved::TooManyRecordsWithoutProgress,
+        )
+    }
+}
+
 /// Values of this structure are returned from [`Connection::process_new_packets`]
 /// and tell the caller the current I/O state of the TLS connection.
 ///
@@ -642,6 +1098,42 @@ pub(crate) trait State<Data>: Send + Sync {
     fn extract_secrets(&self) -> Result<PartiallyExtractedSecrets, Error> {
         Err(Error::HandshakeNotComplete)
     }
+
+    #[cfg(feature = "dangerous_configuration")]
+    fn export_traffic_secrets(self: Box<Self>) -> Result<TrafficSecrets, Error> {
+        Err(Error::HandshakeNotComplete)
+    }
+
+    /// Look at `received`, the start of a handshake message (including its
+    /// header) that is still arriving.
+    ///
+    /// This may be called several times for the same message, with more of
+    /// it each time, before the complete message is given to `handle`.
+    fn handle_partial_handshake(
+        &mut self,
+        _cx: &mut Context<'_, Data>,
+        _received: &[u8],
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether the state is waiting for something other than a message,
+    /// so no messages should be given to it.
+    fn is_pending(&self) -> bool {
+        false
+    }
+
+    fn take_pending_verification(&mut self) -> Option<PendingVerification> {
+        None
+    }
+
+    fn complete_verification(
+        self: Box<Self>,
+        _cx: &mut Context<'_, Data>,
+        _completed: CompletedVerification,
+    ) -> Result<Box<dyn State<Data>>, Error> {
+        Err(Error::General("no verification is pending".into()))
+    }
 }
 
 pub(crate) struct Context<'a, Data> {
@@ -649,6 +1141,50 @@ pub(crate) struct Context<'a, Data> {
     pub(crate) data: &'a mut Data,
 }
 
+/// What a connection's handshake has negotiated so far, in one place.
+///
+/// This is returned from [`Connection::negotiated`], and is intended for
+/// logging and auditing.  Each value is `None` or false until it is agreed,
+/// so a snapshot taken once the handshake is complete gives the full
+/// picture.
+///
+/// [`Connection::negotiated`]: crate::Connection::negotiated
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct Negotiated<'a> {
+    /// The protocol version.
+    pub version: Option<ProtocolVersion>,
+
+    /// The cipher suite.
+    pub cipher_suite: Option<SupportedCipherSuite>,
+
+    /// The key exchange group, for a handshake that exchanged keys.
+    ///
+    /// This is `None` for a resumed TLS1.2 handshake.
+    pub kx_group: Option<NamedGroup>,
+
+    /// The protocol agreed via ALPN.
+    pub alpn_protocol: Option<&'a [u8]>,
+
+    /// The server name the client sent via SNI.  This is only reported by
+    /// servers.
+    pub server_name: Option<&'a str>,
+
+    /// Whether the handshake resumed an earlier session.
+    pub resumed: bool,
+
+    /// Whether the handshake included a TLS1.3 `HelloRetryRequest`.
+    pub hello_retried: bool,
+
+    /// The peer's certificate chain, as from
+    /// [`CommonState::peer_certificates`].
+    pub peer_certificates: Option<&'a [key::Certificate]>,
+
+    /// Whether the connection's secrets are bound to its handshake, as from
+    /// [`CommonState::extended_master_secret`].
+    pub extended_master_secret: Option<bool>,
+}
+
 /// Side of the connection.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Side {
@@ -667,6 +1203,79 @@ impl Side {
     }
 }
 
+/// How to respond to a TLS1.2 peer's request to renegotiate an established
+/// connection: a `HelloRequest` from a server, or a `ClientHello` from a
+/// client.
+///
+/// rustls never renegotiates.  TLS1.3 has no renegotiation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RenegotiationPolicy {
+    /// Discard the request without telling the peer.
+    ///
+    /// A client that asked to renegotiate will wait for a reply that never
+    /// comes, so this only suits peers that carry on regardless.
+    Ignore,
+    /// Send a `no_renegotiation` warning alert, and carry on with the
+    /// connection.  This is the default.
+    Reject,
+    /// Send a fatal alert, and fail the connection with
+    /// [`PeerIncompatible::RenegotiationRequested`].
+    Close,
+}
+
+/// How to respond when a peer sends the same extension more than once in
+/// one handshake message.
+///
+/// This is forbidden by the TLS specifications, so by default it is a
+/// fatal error.  Some peers nonetheless repeat harmless extensions, and
+/// `Tolerate` allows talking to them.
+///
+/// `pre_shared_key` is never tolerated, and must still be the last extension
+/// in a `ClientHello`: the PSK binder depends on both.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DuplicateExtensionPolicy {
+    /// Fail the handshake with a fatal alert.  This is the default.
+    Reject,
+    /// Log a warning and carry on if every repeated extension is one of
+    /// these; otherwise, fail the handshake as for `Reject`.
+    ///
+    /// The first instance of a repeated extension is used, and the others
+    /// are ignored.
+    Tolerate(Vec<ExtensionType>),
+}
+
+impl DuplicateExtensionPolicy {
+    /// Whether the extensions of a message, in the order given, are
+    /// acceptable.  The caller has already found there are duplicates.
+    pub(crate) fn tolerates(&self, extensions: impl Iterator<Item = ExtensionType>) -> bool {
+        let allowed = match self {
+            Self::Reject => return false,
+            Self::Tolerate(allowed) => allowed,
+        };
+
+        let mut seen = Vec::new();
+        for typ in extensions {
+            if !seen.contains(&typ) {
+                seen.push(typ);
+                continue;
+            }
+
+            if typ == ExtensionType::PreSharedKey || !allowed.contains(&typ) {
+                return false;
+            }
+            warn!("Tolerating duplicate {:?} extension", typ);
+        }
+
+        true
+    }
+}
+
+impl Default for DuplicateExtensionPolicy {
+    fn default() -> Self {
+        Self::Reject
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub(crate) enum Protocol {
     Tcp,
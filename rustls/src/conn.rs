@@ -1,4 +1,6 @@
-use crate::common_state::{CommonState, Context, IoState, State};
+use crate::capture::MessageDirection;
+use crate::client::{CompletedVerification, PendingVerification};
+use crate::common_state::{CommonState, Context, IoState, Negotiated, ReceivedRecord, Side, State};
 use crate::enums::{AlertDescription, ContentType};
 use crate::error::{Error, PeerMisbehaved};
 #[cfg(feature = "logging")]
@@ -10,8 +12,8 @@ use crate::msgs::message::{Message, MessagePayload, PlainMessage};
 use crate::suites::{ExtractedSecrets, PartiallyExtractedSecrets};
 use crate::vecbuf::ChunkVecBuffer;
 
-use core::fmt::Debug;
-use core::mem;
+use core::fmt::{self, Debug};
+use core::mem::{self, size_of};
 use core::ops::{Deref, DerefMut};
 use std::io;
 
@@ -39,7 +41,10 @@ impl Connection {
     ///
     /// See [`ConnectionCommon::write_tls()`] for more information.
     pub fn write_tls(&mut self, wr: &mut dyn io::Write) -> Result<usize, io::Error> {
-        self.sendable_tls.write_to(wr)
+        match self {
+            Self::Client(conn) => conn.write_tls(wr),
+            Self::Server(conn) => conn.write_tls(wr),
+        }
     }
 
     /// Returns an object that allows reading plaintext.
@@ -83,6 +88,27 @@ impl Connection {
         }
     }
 
+    /// Returns an identifier for this connection, which the peer can also
+    /// compute.
+    ///
+    /// See [`ConnectionCommon::connection_id()`] for more information.
+    pub fn connection_id(&self) -> Result<ConnectionId, Error> {
+        match self {
+            Self::Client(conn) => conn.connection_id(),
+            Self::Server(conn) => conn.connection_id(),
+        }
+    }
+
+    /// Retrieves what the handshake has negotiated so far.
+    ///
+    /// See [`Negotiated`] for more information.
+    pub fn negotiated(&self) -> Negotiated<'_> {
+        match self {
+            Self::Client(conn) => conn.negotiated(),
+            Self::Server(conn) => conn.negotiated(),
+        }
+    }
+
     /// Extract secrets, to set up kTLS for example
     #[cfg(feature = "secret_extraction")]
     #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
@@ -93,6 +119,19 @@ impl Connection {
         }
     }
 
+    /// Export this connection, so that it can be carried on elsewhere.
+    ///
+    /// See [`crate::ClientConnection::dangerous_export()`] and
+    /// [`crate::ServerConnection::dangerous_export()`].
+    #[cfg(feature = "dangerous_configuration")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+    pub fn dangerous_export(self) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Client(conn) => conn.dangerous_export(),
+            Self::Server(conn) => conn.dangerous_export(),
+        }
+    }
+
     /// This function uses `io` to complete any outstanding IO for this connection.
     ///
     /// See [`ConnectionCommon::complete_io()`] for more information.
@@ -128,13 +167,29 @@ impl DerefMut for Connection {
     }
 }
 
-/// A structure that implements [`std::io::Read`] for reading plaintext.
+/// A structure that implements [`std::io::Read`] and [`std::io::BufRead`]
+/// for reading plaintext.
 pub struct Reader<'a> {
     received_plaintext: &'a mut ChunkVecBuffer,
     peer_cleanly_closed: bool,
     has_seen_eof: bool,
 }
 
+impl<'a> Reader<'a> {
+    /// Explain why there is no plaintext to read.
+    fn check_no_bytes_state(&self) -> io::Result<()> {
+        match (self.peer_cleanly_closed, self.has_seen_eof) {
+            // cleanly closed; don't care about TCP EOF: express this as Ok(0)
+            (true, _) => Ok(()),
+            // unclean closure
+            (false, true) => Err(io::ErrorKind::UnexpectedEof.into()),
+            // connection still going, but need more data: signal `WouldBlock` so that
+            // the caller knows this
+            (false, false) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
 impl<'a> io::Read for Reader<'a> {
     /// Obtain plaintext data received from the peer over this TLS connection.
     ///
@@ -161,15 +216,7 @@ impl<'a> io::Read for Reader<'a> {
 
         if len == 0 && !buf.is_empty() {
             // No bytes available:
-            match (self.peer_cleanly_closed, self.has_seen_eof) {
-                // cleanly closed; don't care about TCP EOF: express this as Ok(0)
-                (true, _) => {}
-                // unclean closure
-                (false, true) => return Err(io::ErrorKind::UnexpectedEof.into()),
-                // connection still going, but need more data: signal `WouldBlock` so that
-                // the caller knows this
-                (false, false) => return Err(io::ErrorKind::WouldBlock.into()),
-            }
+            self.check_no_bytes_state()?;
         }
 
         Ok(len)
@@ -205,21 +252,45 @@ impl<'a> io::Read for Reader<'a> {
 
         if len == 0 && cursor.capacity() > 0 {
             // No bytes available:
-            match (self.peer_cleanly_closed, self.has_seen_eof) {
-                // cleanly closed; don't care about TCP EOF: express this as Ok(0)
-                (true, _) => {}
-                // unclean closure
-                (false, true) => return Err(io::ErrorKind::UnexpectedEof.into()),
-                // connection still going, but need more data: signal `WouldBlock` so that
-                // the caller knows this
-                (false, false) => return Err(io::ErrorKind::WouldBlock.into()),
-            }
+            self.check_no_bytes_state()?;
         }
 
         Ok(())
     }
 }
 
+impl<'a> io::BufRead for Reader<'a> {
+    /// Borrow plaintext data received from the peer over this TLS connection,
+    /// without copying it.
+    ///
+    /// This returns some or all of the plaintext that is available.  Call
+    /// [`consume`] to say how much of it was used; the rest is returned
+    /// again next time.
+    ///
+    /// If no plaintext is available, this behaves as [`Reader::read`]
+    /// does: it returns an empty slice if the peer closed the TLS session
+    /// cleanly, `Err(ErrorKind::UnexpectedEof.into())` if it did not, and
+    /// otherwise `Err(ErrorKind::WouldBlock.into())`.
+    ///
+    /// [`consume`]: io::BufRead::consume
+    /// [`Reader::read`]: io::Read::read
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.received_plaintext.is_empty() {
+            self.check_no_bytes_state()?;
+            return Ok(&[]);
+        }
+
+        Ok(self
+            .received_plaintext
+            .chunk()
+            .unwrap_or_default())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.received_plaintext.consume(amt);
+    }
+}
+
 /// Internal trait implemented by the [`ServerConnection`]/[`ClientConnection`]
 /// allowing them to be the subject of a [`Writer`].
 pub(crate) trait PlaintextSink {
@@ -284,6 +355,33 @@ impl<'a> io::Write for Writer<'a> {
     }
 }
 
+/// An identifier for a connection, shared by both ends of it.
+///
+/// Get one from [`ConnectionCommon::connection_id`].  It displays as 16 hex
+/// digits.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ConnectionId([u8; 8]);
+
+impl ConnectionId {
+    /// The identifier as bytes.
+    pub fn as_bytes(&self) -> &[u8; 8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The exporter label used to derive a [`ConnectionId`].  As it is not
+/// registered with IANA, it must start with "EXPERIMENTAL" (RFC5705).
+const CONNECTION_ID_LABEL: &[u8] = b"EXPERIMENTAL rustls connection id";
+
 #[derive(Debug)]
 pub(crate) struct ConnectionRandoms {
     pub(crate) client: [u8; 32],
@@ -506,6 +604,7 @@ impl<Data> ConnectionCommon<Data> {
         if let Ok(0) = res {
             self.has_seen_eof = true;
         }
+        self.core.update_memory_charge();
         res
     }
 
@@ -516,8 +615,18 @@ impl<Data> ConnectionCommon<Data> {
     ///
     /// After this function returns, the connection buffer may not yet be fully flushed. The
     /// [`CommonState::wants_write`] function can be used to check if the output buffer is empty.
+    ///
+    /// If a [`WritePacer`] is configured, this starts a new pacing cycle.
+    ///
+    /// [`WritePacer`]: crate::WritePacer
     pub fn write_tls(&mut self, wr: &mut dyn io::Write) -> Result<usize, io::Error> {
-        self.sendable_tls.write_to(wr)
+        let pending = self.sendable_tls.len();
+        let res = self.sendable_tls.write_to(wr);
+        self.core
+            .common_state
+            .note_write_tls(pending, &res);
+        self.core.update_memory_charge();
+        res
     }
 
     /// Derives key material from the agreed connection secrets.
@@ -546,6 +655,23 @@ impl<Data> ConnectionCommon<Data> {
             .export_keying_material(output, label, context)
     }
 
+    /// Returns an identifier for this connection, which the peer can also
+    /// compute.
+    ///
+    /// The identifier is derived with [`Self::export_keying_material`]
+    /// using a fixed label, so both ends of a connection get the same value
+    /// and each connection (including a resumed one) gets a different value.
+    /// It reveals nothing about the connection's secrets, so it is suitable
+    /// for logging: for example, to match up client and server logs of the
+    /// same connection.
+    ///
+    /// Like [`Self::export_keying_material`], this fails if called before
+    /// the handshake completes.
+    pub fn connection_id(&self) -> Result<ConnectionId, Error> {
+        self.export_keying_material([0u8; 8], CONNECTION_ID_LABEL, None)
+            .map(ConnectionId)
+    }
+
     /// Extract secrets, so they can be used when configuring kTLS, for example.
     #[cfg(feature = "secret_extraction")]
     #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
@@ -620,7 +746,26 @@ impl<Data> ConnectionCore<Data> {
             }
         };
 
-        while let Some(msg) = self.deframe()? {
+        if let Err(e) = self
+            .common_state
+            .check_handshake_deadline()
+        {
+            self.state = Err(e.clone());
+            return Err(e);
+        }
+
+        while !state.is_pending() {
+            let msg = match self.deframe()? {
+                Some(msg) => msg,
+                None => {
+                    if let Err(e) = self.process_partial_handshake(&mut *state) {
+                        self.state = Err(e.clone());
+                        return Err(e);
+                    }
+                    break;
+                }
+            };
+
             match self.process_msg(msg, state) {
                 Ok(new) => state = new,
                 Err(e) => {
@@ -631,9 +776,104 @@ impl<Data> ConnectionCore<Data> {
         }
 
         self.state = Ok(state);
+        self.update_memory_charge();
         Ok(self.common_state.current_io_state())
     }
 
+    /// Give the start of a handshake message that is still arriving, if
+    /// any, to `state`.
+    fn process_partial_handshake(&mut self, state: &mut dyn State<Data>) -> Result<(), Error> {
+        let received = match self
+            .message_deframer
+            .partial_handshake()
+        {
+            Some(received) => received,
+            None => return Ok(()),
+        };
+        let mut cx = Context {
+            common: &mut self.common_state,
+            data: &mut self.data,
+        };
+        state.handle_partial_handshake(&mut cx, received)
+    }
+
+    /// Bring this connection's charge to its memory budget up to date,
+    /// releasing the deframer's buffer if the budget is used up.
+    pub(crate) fn update_memory_charge(&mut self) {
+        if self
+            .common_state
+            .update_memory_charge(Self::base_size() + self.message_deframer.capacity())
+        {
+            self.message_deframer.shrink();
+            self.common_state
+                .update_memory_charge(Self::base_size() + self.message_deframer.capacity());
+        }
+    }
+
+    /// The size of a connection, excluding its buffers.
+    pub(crate) fn base_size() -> usize {
+        size_of::<Self>()
+    }
+
+    /// Like `process_new_packets()`, but for a single message that bypasses the deframer.
+    pub(crate) fn process_injected_message(&mut self, msg: PlainMessage) -> Result<IoState, Error> {
+        let state = match mem::replace(&mut self.state, Err(Error::HandshakeNotComplete)) {
+            Ok(state) => state,
+            Err(e) => {
+                self.state = Err(e.clone());
+                return Err(e);
+            }
+        };
+
+        match self.process_msg(msg, state) {
+            Ok(new) => {
+                self.state = Ok(new);
+                Ok(self.common_state.current_io_state())
+            }
+            Err(e) => {
+                self.state = Err(e.clone());
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn take_pending_verification(&mut self) -> Option<PendingVerification> {
+        self.state
+            .as_mut()
+            .ok()?
+            .take_pending_verification()
+    }
+
+    /// Give the result of a verification to the state waiting for it, then
+    /// process any messages that arrived meanwhile.
+    pub(crate) fn complete_verification(
+        &mut self,
+        completed: CompletedVerification,
+    ) -> Result<IoState, Error> {
+        let state = match mem::replace(&mut self.state, Err(Error::HandshakeNotComplete)) {
+            Ok(state) => state,
+            Err(e) => {
+                self.state = Err(e.clone());
+                return Err(e);
+            }
+        };
+
+        let mut cx = Context {
+            common: &mut self.common_state,
+            data: &mut self.data,
+        };
+        match state.complete_verification(&mut cx, completed) {
+            Ok(new) => {
+                self.state = Ok(new);
+                self.process_new_packets()
+            }
+            Err(e) => {
+                self.state = Err(e.clone());
+                Err(e)
+            }
+        }
+    }
+
     /// Pull a message out of the deframer and send any messages that need to be sent as a result.
     fn deframe(&mut self) -> Result<Option<PlainMessage>, Error> {
         match self
@@ -688,12 +928,25 @@ impl<Data> ConnectionCore<Data> {
         msg: PlainMessage,
         state: Box<dyn State<Data>>,
     ) -> Result<Box<dyn State<Data>>, Error> {
-        // Drop CCS messages during handshake in TLS1.3
+        let is_empty = msg.payload.0.is_empty();
+        if is_empty {
+            self.common_state
+                .count_received_record(ReceivedRecord::Empty)?;
+        }
+
+        // Drop CCS messages during handshake in TLS1.3.  A server that has not yet
+        // seen a ClientHello may be picking up after a stateless HelloRetryRequest,
+        // which the client will have followed with a CCS.
         if msg.typ == ContentType::ChangeCipherSpec
             && !self
                 .common_state
                 .may_receive_application_data
-            && self.common_state.is_tls13()
+            && (self.common_state.is_tls13()
+                || (self.common_state.side == Side::Server
+                    && self
+                        .common_state
+                        .negotiated_version
+                        .is_none()))
         {
             if !is_valid_ccs(&msg)
                 || self.common_state.received_middlebox_ccs > TLS13_MAX_DROPPED_CCS
@@ -708,6 +961,8 @@ impl<Data> ConnectionCore<Data> {
             } else {
                 self.common_state.received_middlebox_ccs += 1;
                 trace!("Dropping CCS");
+                self.common_state
+                    .count_received_record(ReceivedRecord::Ignored)?;
                 return Ok(state);
             }
         }
@@ -722,12 +977,20 @@ impl<Data> ConnectionCore<Data> {
             }
         };
 
+        self.common_state
+            .capture_handshake_message(MessageDirection::Received, &msg);
+
         // For alerts, we have separate logic.
         if let MessagePayload::Alert(alert) = &msg.payload {
             self.common_state.process_alert(alert)?;
             return Ok(state);
         }
 
+        if !is_empty {
+            self.common_state
+                .count_received_record(ReceivedRecord::Advancing)?;
+        }
+
         self.common_state
             .process_main_protocol(msg, state, &mut self.data)
     }
@@ -0,0 +1,248 @@
+//! A crypto provider and clock for reproducible handshakes.
+//!
+//! **These are for testing only.**  Nothing produced using them is secret:
+//! anyone who knows the seed can recompute every "random" value, including
+//! ephemeral private keys.
+//!
+//! [`Deterministic`] behaves like [`Ring`], except all the randomness it
+//! hands out -- client and server randoms, session ids, key shares -- comes
+//! from a seeded HMAC-DRBG ([NIST SP 800-90A]).  The DRBG state is per-thread;
+//! call [`Deterministic::reseed`] before building a connection to pin the
+//! bytes it will produce.  Pair it with [`FixedTimeProvider`] as the config's
+//! [`TimeProvider`] so that time-dependent values are also stable.
+//!
+//! With both in place, a `ClientHello` (and, given a deterministic client,
+//! the `ServerHello`) is byte-for-byte identical between runs, which allows
+//! golden-transcript tests:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use std::time::{Duration, SystemTime};
+//! use rustls::crypto::deterministic::{Deterministic, FixedTimeProvider};
+//! use rustls::{ClientConfig, ClientConnection, RootCertStore};
+//!
+//! let mut config = ClientConfig::<Deterministic>::builder()
+//!     .with_safe_defaults()
+//!     .with_root_certificates(RootCertStore::empty())
+//!     .with_no_client_auth();
+//! config.time_provider = Arc::new(FixedTimeProvider(
+//!     SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+//! ));
+//! let config = Arc::new(config);
+//!
+//! let mut hello = || {
+//!     Deterministic::reseed(b"golden transcript");
+//!     let mut conn =
+//!         ClientConnection::new(Arc::clone(&config), "example.com".try_into().unwrap()).unwrap();
+//!     let mut bytes = Vec::new();
+//!     conn.write_tls(&mut bytes).unwrap();
+//!     bytes
+//! };
+//!
+//! assert_eq!(hello(), hello());
+//! ```
+//!
+//! Note that signatures made by the server are not covered: *ring* draws
+//! ECDSA nonces from the operating system.
+//!
+//! [`Ring`]: super::ring::Ring
+//! [`TimeProvider`]: crate::TimeProvider
+//! [NIST SP 800-90A]: https://csrc.nist.gov/publications/detail/sp/800-90a/rev-1/final
+
+use crate::crypto::hash::{self, HashAlgorithm};
+use crate::crypto::{self, CryptoProvider, KeyExchangeError};
+use crate::error::Error;
+use crate::msgs::enums::NamedGroup;
+use crate::rand::GetRandomFailed;
+use crate::server::ProducesTickets;
+use crate::time_provider::TimeProvider;
+
+use super::ring::{self as ring_provider, SupportedKxGroup};
+
+use ring::hmac;
+use ring::test::rand::FixedSliceRandom;
+
+use core::cell::RefCell;
+use std::time::SystemTime;
+
+/// A [`CryptoProvider`] whose randomness comes from a seeded, per-thread DRBG.
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Deterministic;
+
+impl Deterministic {
+    /// Reset this thread's DRBG to a state derived solely from `seed`.
+    ///
+    /// Before the first call, each thread behaves as if seeded with the
+    /// empty string.
+    pub fn reseed(seed: &[u8]) {
+        DRBG.with(|drbg| *drbg.borrow_mut() = HmacDrbg::new(seed));
+    }
+}
+
+impl CryptoProvider for Deterministic {
+    type KeyExchange = KeyExchange;
+
+    fn fill_random(buf: &mut [u8]) -> Result<(), GetRandomFailed> {
+        DRBG.with(|drbg| drbg.borrow_mut().generate(buf));
+        Ok(())
+    }
+
+    fn transcript_hash(algorithm: HashAlgorithm) -> Option<&'static dyn hash::Hash> {
+        ring_provider::Ring::transcript_hash(algorithm)
+    }
+
+    fn hash(algorithm: HashAlgorithm) -> Option<&'static dyn hash::Hash> {
+        ring_provider::Ring::hash(algorithm)
+    }
+
+    fn hmac(algorithm: HashAlgorithm) -> Option<&'static dyn crypto::hmac::Hmac> {
+        ring_provider::Ring::hmac(algorithm)
+    }
+
+    /// Tickets are encrypted by *ring*, with keys and nonces from this
+    /// provider.
+    fn ticket_encrypter() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+        ring_provider::make_ticket_encrypter::<Self>()
+    }
+}
+
+/// An in-progress key exchange whose private key is drawn from [`Deterministic`].
+#[derive(Debug)]
+pub struct KeyExchange(ring_provider::KeyExchange);
+
+impl crypto::KeyExchange for KeyExchange {
+    type SupportedGroup = SupportedKxGroup;
+
+    fn start(
+        name: NamedGroup,
+        supported: &[&'static SupportedKxGroup],
+    ) -> Result<Self, KeyExchangeError> {
+        let group = supported
+            .iter()
+            .find(|group| group.name == name)
+            .ok_or(KeyExchangeError::UnsupportedGroup)?;
+
+        let mut seed = vec![0u8; group.private_key_len];
+        Deterministic::fill_random(&mut seed).map_err(|_| KeyExchangeError::GetRandomFailed)?;
+        ring_provider::KeyExchange::start_with_rng(
+            name,
+            supported,
+            &FixedSliceRandom { bytes: &seed },
+        )
+        .map(Self)
+    }
+
+    fn complete<T>(self, peer: &[u8], f: impl FnOnce(&[u8]) -> Result<T, ()>) -> Result<T, Error> {
+        self.0.complete(peer, f)
+    }
+
+    fn group(&self) -> NamedGroup {
+        self.0.group()
+    }
+
+    fn pub_key(&self) -> &[u8] {
+        self.0.pub_key()
+    }
+
+    fn all_kx_groups() -> &'static [&'static Self::SupportedGroup] {
+        ring_provider::KeyExchange::all_kx_groups()
+    }
+}
+
+/// A [`TimeProvider`] that always reports the same time.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedTimeProvider(pub SystemTime);
+
+impl TimeProvider for FixedTimeProvider {
+    fn current_time(&self) -> Option<SystemTime> {
+        Some(self.0)
+    }
+}
+
+std::thread_local! {
+    static DRBG: RefCell<HmacDrbg> = RefCell::new(HmacDrbg::new(b""));
+}
+
+/// HMAC_DRBG with SHA-256, as described in NIST SP 800-90A section 10.1.2.
+///
+/// Reseeding and prediction resistance are deliberately absent: the point
+/// is to be reproducible, not secure.
+struct HmacDrbg {
+    key: hmac::Key,
+    value: [u8; 32],
+}
+
+impl HmacDrbg {
+    fn new(seed: &[u8]) -> Self {
+        let mut drbg = Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &[0x00; 32]),
+            value: [0x01; 32],
+        };
+        drbg.update(seed);
+        drbg
+    }
+
+    fn update(&mut self, provided: &[u8]) {
+        for round in [0x00, 0x01] {
+            if round == 0x01 && provided.is_empty() {
+                break;
+            }
+
+            let mut ctx = hmac::Context::with_key(&self.key);
+            ctx.update(&self.value);
+            ctx.update(&[round]);
+            ctx.update(provided);
+            self.key = hmac::Key::new(hmac::HMAC_SHA256, ctx.sign().as_ref());
+            self.next_value();
+        }
+    }
+
+    fn generate(&mut self, out: &mut [u8]) {
+        for chunk in out.chunks_mut(32) {
+            self.next_value();
+            chunk.copy_from_slice(&self.value[..chunk.len()]);
+        }
+        self.update(&[]);
+    }
+
+    fn next_value(&mut self) {
+        let tag = hmac::sign(&self.key, &self.value);
+        self.value.copy_from_slice(tag.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reseed_repeats_output() {
+        let mut first = [0u8; 100];
+        let mut second = [0u8; 100];
+
+        Deterministic::reseed(b"seed");
+        Deterministic::fill_random(&mut first).unwrap();
+        Deterministic::reseed(b"seed");
+        Deterministic::fill_random(&mut second).unwrap();
+        assert_eq!(first, second);
+
+        Deterministic::reseed(b"other seed");
+        Deterministic::fill_random(&mut second).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn key_exchange_is_reproducible() {
+        use crate::crypto::KeyExchange as _;
+
+        for group in KeyExchange::all_kx_groups() {
+            Deterministic::reseed(b"kx");
+            let first = KeyExchange::start(group.name, &[group]).unwrap();
+            Deterministic::reseed(b"kx");
+            let second = KeyExchange::start(group.name, &[group]).unwrap();
+            assert_eq!(first.pub_key(), second.pub_key());
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use super::CryptoProvider;
+use crate::error::Error;
+pub use crate::msgs::enums::HashAlgorithm;
+
+/// Describes a single cryptographic hash function.
+///
+/// This interface can do both one-shot and incremental hashing, using
+/// [`Hash::hash()`] and [`Hash::start()`] respectively.
+pub trait Hash: Send + Sync {
+    /// Start an incremental hash computation.
+    fn start(&self) -> Box<dyn Context>;
+
+    /// Return the output of this hash function with input `data`.
+    fn hash(&self, data: &[u8]) -> Output;
+
+    /// Which hash function this is, eg, `HashAlgorithm::SHA256`.
+    fn algorithm(&self) -> HashAlgorithm;
+
+    /// The length in bytes of this hash function's output.
+    fn output_len(&self) -> usize;
+}
+
+/// How to incrementally compute a hash.
+pub trait Context: Send + Sync {
+    /// Finish the computation, returning the resulting output.
+    ///
+    /// The computation remains valid, and more data can be added later with
+    /// [`Context::update()`].
+    fn fork_finish(&self) -> Output;
+
+    /// Fork the computation, producing another context that has the
+    /// same prefix as this one.
+    fn fork(&self) -> Box<dyn Context>;
+
+    /// Terminate and finish the computation, returning the resulting output.
+    fn finish(self: Box<Self>) -> Output;
+
+    /// Add `data` to computation.
+    fn update(&mut self, data: &[u8]);
+}
+
+/// A hash output, stored as a value.
+#[derive(Clone, Copy)]
+pub struct Output {
+    buf: [u8; Self::MAX_LEN],
+    used: usize,
+}
+
+impl Output {
+    /// Build a `hash::Output` from a slice of no more than `Output::MAX_LEN` bytes.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut output = Self {
+            buf: [0u8; Self::MAX_LEN],
+            used: bytes.len(),
+        };
+        debug_assert!(bytes.len() <= Self::MAX_LEN);
+        output.buf[..bytes.len()].copy_from_slice(bytes);
+        output
+    }
+
+    /// Maximum supported hash output size: supports up to SHA512.
+    pub const MAX_LEN: usize = 64;
+}
+
+impl AsRef<[u8]> for Output {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+/// The provider's hash function for the transcript of a handshake using a
+/// cipher suite whose hash is `suite_hash`.
+pub(crate) fn for_transcript<C: CryptoProvider>(
+    suite_hash: &'static ring::digest::Algorithm,
+) -> Result<&'static dyn Hash, Error> {
+    let algorithm = algorithm_of(suite_hash);
+    C::transcript_hash(algorithm).ok_or_else(|| {
+        Error::General(format!(
+            "crypto provider has no {:?} transcript hash",
+            algorithm
+        ))
+    })
+}
+
+/// The `HashAlgorithm` naming the *ring* digest `suite_hash`.
+pub(crate) fn algorithm_of(suite_hash: &'static ring::digest::Algorithm) -> HashAlgorithm {
+    if suite_hash == &ring::digest::SHA256 {
+        HashAlgorithm::SHA256
+    } else if suite_hash == &ring::digest::SHA384 {
+        HashAlgorithm::SHA384
+    } else if suite_hash == &ring::digest::SHA512 {
+        HashAlgorithm::SHA512
+    } else {
+        HashAlgorithm::Unknown(0)
+    }
+}
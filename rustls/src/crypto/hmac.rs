@@ -0,0 +1,63 @@
+use super::hash::{self, HashAlgorithm};
+use super::CryptoProvider;
+use crate::error::Error;
+
+/// A concrete HMAC implementation, for a single cryptographic hash function.
+///
+/// You should have one object that implements this trait for HMAC-SHA256,
+/// another for HMAC-SHA384, etc.
+pub trait Hmac: Send + Sync {
+    /// Prepare to use `key` as a HMAC key.
+    fn with_key(&self, key: &[u8]) -> Box<dyn Key>;
+
+    /// The length in bytes of the underlying hash function's output.
+    fn hash_output_len(&self) -> usize;
+}
+
+/// A HMAC tag, stored as a value.
+#[derive(Clone)]
+pub struct Tag {
+    buf: [u8; Self::MAX_LEN],
+    used: usize,
+}
+
+impl Tag {
+    /// Build a tag by copying a byte slice of no more than `Tag::MAX_LEN` bytes.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut tag = Self {
+            buf: [0u8; Self::MAX_LEN],
+            used: bytes.len(),
+        };
+        debug_assert!(bytes.len() <= Self::MAX_LEN);
+        tag.buf[..bytes.len()].copy_from_slice(bytes);
+        tag
+    }
+
+    /// Maximum supported HMAC tag size: supports up to SHA512.
+    pub const MAX_LEN: usize = hash::Output::MAX_LEN;
+}
+
+impl AsRef<[u8]> for Tag {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+/// A HMAC key that is ready for use.
+pub trait Key: Send + Sync {
+    /// Calculate a tag over the concatenation of the slices in `data`.
+    fn sign(&self, data: &[&[u8]]) -> Tag;
+
+    /// The length in bytes of the tags this key produces.
+    fn tag_len(&self) -> usize;
+}
+
+/// The provider's HMAC for the key schedule of a cipher suite whose
+/// hash is `suite_hash`.
+pub(crate) fn for_suite<C: CryptoProvider>(
+    suite_hash: &'static ring::digest::Algorithm,
+) -> Result<&'static dyn Hmac, Error> {
+    let algorithm: HashAlgorithm = hash::algorithm_of(suite_hash);
+    C::hmac(algorithm)
+        .ok_or_else(|| Error::General(format!("crypto provider has no HMAC-{:?}", algorithm)))
+}
@@ -1,4 +1,5 @@
-use crate::rand::GetRandomFailed;
+pub use crate::rand::GetRandomFailed;
+use crate::server::ProducesTickets;
 use crate::{Error, NamedGroup};
 
 use core::fmt::Debug;
@@ -6,6 +7,24 @@ use core::fmt::Debug;
 /// *ring* based CryptoProvider.
 pub mod ring;
 
+#[cfg(feature = "deterministic_testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "deterministic_testing")))]
+pub mod deterministic;
+
+#[cfg(feature = "provider_conformance")]
+#[cfg_attr(docsrs, doc(cfg(feature = "provider_conformance")))]
+pub mod provider_conformance;
+
+/// Hash function interfaces.
+pub mod hash;
+
+/// HMAC interfaces.
+pub mod hmac;
+
+mod pool;
+pub(crate) use pool::start_kx;
+pub use pool::KeySharePool;
+
 /// Pluggable crypto galore.
 pub trait CryptoProvider: Send + Sync + 'static {
     /// KeyExchange operations that are supported by the provider.
@@ -13,6 +32,39 @@ pub trait CryptoProvider: Send + Sync + 'static {
 
     /// Fill the given buffer with random bytes.
     fn fill_random(buf: &mut [u8]) -> Result<(), GetRandomFailed>;
+
+    /// The hash function for the transcripts of handshakes using cipher
+    /// suites whose hash is `algorithm`, or `None` if there is none.
+    ///
+    /// Every hash of the handshake transcript is computed with this: for
+    /// the `Finished` messages, handshake signatures, PSK binders and the
+    /// TLS1.3 key schedule.  A handshake fails if the negotiated cipher
+    /// suite's hash is not available.
+    fn transcript_hash(algorithm: hash::HashAlgorithm) -> Option<&'static dyn hash::Hash>;
+
+    /// The hash function `algorithm` for uses other than the handshake
+    /// transcript, or `None` if there is none.
+    ///
+    /// This identifies certificates in OCSP responses, which commonly use
+    /// SHA-1.  The default implementation offers only the transcript hashes.
+    fn hash(algorithm: hash::HashAlgorithm) -> Option<&'static dyn hash::Hash> {
+        Self::transcript_hash(algorithm)
+    }
+
+    /// HMAC using the hash function `algorithm`, or `None` if there is none.
+    ///
+    /// This is used for all key derivation: HKDF in the TLS1.3 key
+    /// schedule and the PRF in TLS1.2.  A handshake fails if the negotiated
+    /// cipher suite's hash is not available.
+    fn hmac(algorithm: hash::HashAlgorithm) -> Option<&'static dyn hmac::Hmac>;
+
+    /// Make a ticket encrypter with a new, random key.
+    ///
+    /// This is suitable as the `generator` of a [`TicketSwitcher`], which
+    /// rotates between such encrypters.
+    ///
+    /// [`TicketSwitcher`]: crate::TicketSwitcher
+    fn ticket_encrypter() -> Result<Box<dyn ProducesTickets>, GetRandomFailed>;
 }
 
 /// An in-progress key exchange over a [SupportedGroup].
@@ -40,6 +92,22 @@ pub trait KeyExchange: Sized + Send + Sync + 'static {
         supported: &[&'static Self::SupportedGroup],
     ) -> Result<Self, KeyExchangeError>;
 
+    /// Start `count` key exchanges using the [NamedGroup], as [KeyExchange#start]
+    /// would.
+    ///
+    /// This is used to fill a [KeySharePool].  The default implementation calls
+    /// [KeyExchange#start] `count` times; providers that can amortise key
+    /// generation across many keys should override it.
+    fn start_batch(
+        name: NamedGroup,
+        supported: &[&'static Self::SupportedGroup],
+        count: usize,
+    ) -> Result<Vec<Self>, KeyExchangeError> {
+        (0..count)
+            .map(|_| Self::start(name, supported))
+            .collect()
+    }
+
     /// Completes the key exchange, given the peer's public key.
     ///
     /// The shared secret is passed into the closure passed down in `f`, and the result of calling
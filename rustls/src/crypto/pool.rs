@@ -0,0 +1,186 @@
+use super::{KeyExchange, KeyExchangeError, SupportedGroup};
+use crate::msgs::enums::NamedGroup;
+
+use alloc::sync::Arc;
+use core::fmt;
+use std::sync::Mutex;
+
+/// A pool of key shares generated ahead of time.
+///
+/// Generating an ephemeral key share is one of the more expensive parts of
+/// a handshake.  Setting a pool as `ServerConfig::key_share_pool` or
+/// `ClientConfig::key_share_pool` lets connections take a ready-made share
+/// instead, and [`KeySharePool::refill`] can be called from a background
+/// thread to generate them in bulk with [`KeyExchange::start_batch`].
+///
+/// Connections fall back to generating a share as usual when the pool has
+/// none left for the group they need.  Each share is used at most once.
+pub struct KeySharePool<K: KeyExchange> {
+    groups: Vec<&'static K::SupportedGroup>,
+    size: usize,
+    shares: Mutex<Vec<(NamedGroup, Vec<K>)>>,
+}
+
+impl<K: KeyExchange> KeySharePool<K> {
+    /// Make an empty pool that holds up to `size` shares for each of
+    /// `groups`.  Call [`KeySharePool::refill`] to fill it.
+    pub fn new(groups: &[&'static K::SupportedGroup], size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            groups: groups.to_vec(),
+            size,
+            shares: Mutex::new(
+                groups
+                    .iter()
+                    .map(|group| (group.name(), Vec::new()))
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Generate shares until each group has as many as the pool holds.
+    ///
+    /// Returns how many shares were generated.  Generation happens without
+    /// holding the pool's lock, so connections can keep taking shares
+    /// meanwhile.
+    pub fn refill(&self) -> Result<usize, KeyExchangeError> {
+        let mut generated = 0;
+        for group in &self.groups {
+            let wanted = self
+                .size
+                .saturating_sub(self.available(group.name()));
+            if wanted == 0 {
+                continue;
+            }
+
+            let mut fresh = K::start_batch(group.name(), &self.groups, wanted)?;
+            generated += fresh.len();
+            if let Some((_, shares)) = self
+                .shares
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .find(|(name, _)| *name == group.name())
+            {
+                shares.append(&mut fresh);
+                shares.truncate(self.size);
+            }
+        }
+        Ok(generated)
+    }
+
+    /// How many shares for `group` are ready.
+    pub fn available(&self, group: NamedGroup) -> usize {
+        self.shares
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| *name == group)
+            .map_or(0, |(_, shares)| shares.len())
+    }
+
+    /// Take a share for `name` if `supported` allows it, generating one
+    /// if the pool has none.
+    pub(crate) fn start(
+        &self,
+        name: NamedGroup,
+        supported: &[&'static K::SupportedGroup],
+    ) -> Result<K, KeyExchangeError> {
+        if supported
+            .iter()
+            .any(|group| group.name() == name)
+        {
+            let taken = self
+                .shares
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .find(|(group, _)| *group == name)
+                .and_then(|(_, shares)| shares.pop());
+            if let Some(kx) = taken {
+                return Ok(kx);
+            }
+        }
+
+        K::start(name, supported)
+    }
+}
+
+impl<K: KeyExchange> fmt::Debug for KeySharePool<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeySharePool")
+            .field("groups", &self.groups)
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Start a key exchange for `name`, from `pool` if there is one.
+pub(crate) fn start_kx<K: KeyExchange>(
+    pool: Option<&KeySharePool<K>>,
+    name: NamedGroup,
+    supported: &[&'static K::SupportedGroup],
+) -> Result<K, KeyExchangeError> {
+    match pool {
+        Some(pool) => pool.start(name, supported),
+        None => K::start(name, supported),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ring;
+
+    #[test]
+    fn test_refill_and_take() {
+        let pool = KeySharePool::<ring::KeyExchange>::new(&[&ring::X25519, &ring::SECP256R1], 3);
+        assert_eq!(pool.available(NamedGroup::X25519), 0);
+        assert_eq!(pool.refill().unwrap(), 6);
+        assert_eq!(pool.refill().unwrap(), 0);
+        assert_eq!(pool.available(NamedGroup::X25519), 3);
+
+        let kx = pool
+            .start(NamedGroup::X25519, &[&ring::X25519])
+            .unwrap();
+        assert_eq!(kx.group(), NamedGroup::X25519);
+        assert_eq!(pool.available(NamedGroup::X25519), 2);
+        assert_eq!(pool.available(NamedGroup::secp256r1), 3);
+        assert_eq!(pool.refill().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_shares_are_not_reused() {
+        let pool = KeySharePool::<ring::KeyExchange>::new(&[&ring::X25519], 2);
+        pool.refill().unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..3)
+            .map(|_| {
+                pool.start(NamedGroup::X25519, &[&ring::X25519])
+                    .unwrap()
+                    .pub_key()
+                    .to_vec()
+            })
+            .collect();
+        assert_eq!(pool.available(NamedGroup::X25519), 0);
+        assert_ne!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    #[test]
+    fn test_take_respects_supported_groups() {
+        let pool = KeySharePool::<ring::KeyExchange>::new(&[&ring::X25519], 1);
+        pool.refill().unwrap();
+
+        assert!(matches!(
+            pool.start(NamedGroup::X25519, &[&ring::SECP256R1]),
+            Err(KeyExchangeError::UnsupportedGroup)
+        ));
+        assert_eq!(pool.available(NamedGroup::X25519), 1);
+
+        let kx = pool
+            .start(NamedGroup::secp256r1, &[&ring::SECP256R1])
+            .unwrap();
+        assert_eq!(kx.group(), NamedGroup::secp256r1);
+        assert_eq!(pool.available(NamedGroup::X25519), 1);
+    }
+}
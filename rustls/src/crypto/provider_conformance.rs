@@ -0,0 +1,690 @@
+//! Checks that a [`CryptoProvider`] behaves as rustls expects.
+//!
+//! This is for authors of providers: it exercises a provider without
+//! needing a copy of rustls' own tests.  Each function here runs a group
+//! of checks and returns every [`Failure`], so a single test can report
+//! everything that is wrong:
+//!
+//! ```
+//! use rustls::crypto::provider_conformance;
+//! use rustls::crypto::ring::Ring;
+//!
+//! // In the provider's tests, with its own type in place of `Ring`:
+//! provider_conformance::check_all::<Ring>().unwrap();
+//! ```
+//!
+//! The groups are:
+//!
+//! - [`known_answers`]: the provider's hashes and HMACs against published
+//!   test vectors, and its random numbers, key exchanges and ticket
+//!   encryption against themselves;
+//! - [`interop_handshakes`]: full and resumed handshakes, with TLS1.2 and
+//!   TLS1.3, between a client or server using the provider and a peer
+//!   using [`Ring`], for every cipher suite and key exchange group the
+//!   provider supports;
+//! - [`negative_cases`]: inputs the provider must reject.
+//!
+//! Hash functions and HMACs the provider does not offer are skipped, as
+//! are the cipher suites that need them, but SHA-256 is required.
+//!
+//! Handshakes use a built-in ECDSA certificate chain, and verify it as if
+//! the time were the start of 2024, so these checks do not depend on the
+//! clock.
+
+use crate::anchors::RootCertStore;
+use crate::client::{ClientConfig, ClientConnection, ServerName};
+use crate::conn::{ConnectionCommon, SideData};
+use crate::crypto::hash::{self, HashAlgorithm};
+use crate::crypto::ring::{Ring, SupportedKxGroup, ALL_KX_GROUPS};
+use crate::crypto::{CryptoProvider, KeyExchange, KeyExchangeError, SupportedGroup};
+use crate::enums::SignatureAlgorithm;
+use crate::error::Error;
+use crate::key::{Certificate, PrivateKey};
+use crate::msgs::enums::NamedGroup;
+use crate::rand::GetRandomFailed;
+use crate::server::{ProducesTickets, ServerConfig, ServerConnection};
+use crate::suites::{SupportedCipherSuite, ALL_CIPHER_SUITES};
+use crate::time_provider::TimeProvider;
+use crate::versions::{SupportedProtocolVersion, ALL_VERSIONS};
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::time::Duration;
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+/// A check that a provider did not pass.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    /// Which check failed.
+    pub check: &'static str,
+    /// What went wrong.
+    pub detail: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.check, self.detail)
+    }
+}
+
+/// Run all the checks on `C`.
+pub fn check_all<C: CryptoProvider>() -> Result<(), Vec<Failure>> {
+    let mut failures = Vec::new();
+    for group in [
+        known_answers::<C>,
+        interop_handshakes::<C>,
+        negative_cases::<C>,
+    ] {
+        if let Err(mut more) = group() {
+            failures.append(&mut more);
+        }
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(failures),
+    }
+}
+
+/// Check `C`'s primitives: its hashes and HMACs against known answers, and
+/// its random numbers, key exchanges and ticket encryption against
+/// themselves.
+pub fn known_answers<C: CryptoProvider>() -> Result<(), Vec<Failure>> {
+    run(&[
+        ("random", random::<C>),
+        ("hash", hashes::<C>),
+        ("hmac", hmacs::<C>),
+        ("key exchange", key_exchange::<C>),
+        ("ticket encryption", ticket_encryption::<C>),
+    ])
+}
+
+/// Check `C` can complete handshakes with [`Ring`], as a client and as a
+/// server.
+pub fn interop_handshakes<C: CryptoProvider>() -> Result<(), Vec<Failure>> {
+    run(&[
+        ("client handshake", client_handshakes::<C>),
+        ("server handshake", server_handshakes::<C>),
+    ])
+}
+
+/// Check `C` rejects what it should.
+pub fn negative_cases<C: CryptoProvider>() -> Result<(), Vec<Failure>> {
+    run(&[
+        ("key exchange rejection", key_exchange_rejections::<C>),
+        ("ticket rejection", ticket_rejections::<C>),
+        ("altered handshake", altered_handshakes::<C>),
+    ])
+}
+
+/// A check, which returns what went wrong if it fails.
+type Check = fn() -> Result<(), String>;
+
+fn run(checks: &[(&'static str, Check)]) -> Result<(), Vec<Failure>> {
+    let failures = checks
+        .iter()
+        .filter_map(|&(check, run)| {
+            run()
+                .err()
+                .map(|detail| Failure { check, detail })
+        })
+        .collect::<Vec<_>>();
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(failures),
+    }
+}
+
+fn random<C: CryptoProvider>() -> Result<(), String> {
+    let mut first = [0u8; 32];
+    let mut second = [0u8; 32];
+    C::fill_random(&mut first).map_err(|_| "fill_random failed")?;
+    C::fill_random(&mut second).map_err(|_| "fill_random failed")?;
+    match first == second || first == [0u8; 32] {
+        true => Err("fill_random produced repeated or zero output".into()),
+        false => Ok(()),
+    }
+}
+
+fn hashes<C: CryptoProvider>() -> Result<(), String> {
+    for (algorithm, expected) in HASH_ABC {
+        match C::transcript_hash(*algorithm) {
+            Some(hash) => check_hash(hash, *algorithm, expected)?,
+            None if *algorithm == HashAlgorithm::SHA256 => {
+                return Err("no SHA256 transcript hash".into())
+            }
+            None => {}
+        }
+        if let Some(hash) = C::hash(*algorithm) {
+            check_hash(hash, *algorithm, expected)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_hash(
+    hash: &dyn hash::Hash,
+    algorithm: HashAlgorithm,
+    expected: &[u8],
+) -> Result<(), String> {
+    if hash.algorithm() != algorithm || hash.output_len() != expected.len() {
+        return Err(format!("{:?} describes itself wrongly", algorithm));
+    }
+    if hash.hash(b"abc").as_ref() != expected {
+        return Err(format!("{:?} of \"abc\" is wrong", algorithm));
+    }
+
+    let mut context = hash.start();
+    context.update(b"a");
+    let a = context.fork().finish();
+    context.update(b"bc");
+    let abc = context.fork_finish();
+    context.update(b"d");
+    let abcd = context.finish();
+    if a.as_ref() != hash.hash(b"a").as_ref()
+        || abc.as_ref() != expected
+        || abcd.as_ref() != hash.hash(b"abcd").as_ref()
+    {
+        return Err(format!("incremental {:?} is wrong", algorithm));
+    }
+    Ok(())
+}
+
+fn hmacs<C: CryptoProvider>() -> Result<(), String> {
+    // RFC 4231, test case 2.
+    let key = b"Jefe";
+    let data = b"what do ya want for nothing?";
+    for (algorithm, expected) in HMAC_JEFE {
+        let hmac = match C::hmac(*algorithm) {
+            Some(hmac) => hmac,
+            None if *algorithm == HashAlgorithm::SHA256 => return Err("no HMAC-SHA256".into()),
+            None => continue,
+        };
+        let key = hmac.with_key(key);
+        if hmac.hash_output_len() != expected.len() || key.tag_len() != expected.len() {
+            return Err(format!("HMAC-{:?} has the wrong length", algorithm));
+        }
+        if key.sign(&[data]).as_ref() != *expected {
+            return Err(format!("HMAC-{:?} is wrong", algorithm));
+        }
+        if key
+            .sign(&[&data[..4], &[], &data[4..]])
+            .as_ref()
+            != *expected
+        {
+            return Err(format!("HMAC-{:?} over several slices is wrong", algorithm));
+        }
+    }
+    Ok(())
+}
+
+fn key_exchange<C: CryptoProvider>() -> Result<(), String> {
+    let groups = C::KeyExchange::all_kx_groups();
+    if groups.is_empty() {
+        return Err("no key exchange groups".into());
+    }
+
+    for group in groups {
+        let name = group.name();
+        let start = || {
+            C::KeyExchange::start(name, &[*group])
+                .map_err(|err| format!("{:?} cannot start: {:?}", name, err))
+        };
+        let (ours, theirs) = (start()?, start()?);
+        if ours.group() != name || ours.pub_key() == theirs.pub_key() {
+            return Err(format!("{:?} key shares are wrong", name));
+        }
+
+        let their_key = theirs.pub_key().to_vec();
+        let our_key = ours.pub_key().to_vec();
+        let ours = ours
+            .complete(&their_key, |secret| Ok(secret.to_vec()))
+            .map_err(|err| format!("{:?} cannot complete: {:?}", name, err))?;
+        let theirs = theirs
+            .complete(&our_key, |secret| Ok(secret.to_vec()))
+            .map_err(|err| format!("{:?} cannot complete: {:?}", name, err))?;
+        if ours != theirs {
+            return Err(format!("{:?} does not agree on a secret", name));
+        }
+    }
+    Ok(())
+}
+
+fn ticket_encryption<C: CryptoProvider>() -> Result<(), String> {
+    let ticketer = C::ticket_encrypter().map_err(|_| "cannot make a ticket encrypter")?;
+    if !ticketer.enabled() {
+        return Err("ticket encrypter is not enabled".into());
+    }
+    let plain = b"ticket plaintext";
+    let ticket = ticketer
+        .encrypt(plain)
+        .ok_or("cannot encrypt a ticket")?;
+    if ticket
+        .windows(plain.len())
+        .any(|w| w == plain)
+    {
+        return Err("ticket is not encrypted".into());
+    }
+    match ticketer.decrypt(&ticket) {
+        Some(decrypted) if decrypted == plain => Ok(()),
+        _ => Err("ticket does not decrypt".into()),
+    }
+}
+
+fn key_exchange_rejections<C: CryptoProvider>() -> Result<(), String> {
+    let groups = C::KeyExchange::all_kx_groups();
+    if let Some(unsupported) = [
+        NamedGroup::X25519,
+        NamedGroup::secp256r1,
+        NamedGroup::secp384r1,
+    ]
+    .iter()
+    .find(|name| {
+        !groups
+            .iter()
+            .any(|group| group.name() == **name)
+    }) {
+        match C::KeyExchange::start(*unsupported, groups) {
+            Err(KeyExchangeError::UnsupportedGroup) => {}
+            _ => return Err(format!("{:?} started, but is not supported", unsupported)),
+        }
+    }
+
+    for group in groups {
+        let name = group.name();
+        for peer in [&[][..], &[0xff; 3][..]] {
+            let kx = C::KeyExchange::start(name, &[*group])
+                .map_err(|err| format!("{:?} cannot start: {:?}", name, err))?;
+            if kx
+                .complete(peer, |secret| Ok(secret.to_vec()))
+                .is_ok()
+            {
+                return Err(format!("{:?} accepted a malformed peer key", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn ticket_rejections<C: CryptoProvider>() -> Result<(), String> {
+    let ticketer = C::ticket_encrypter().map_err(|_| "cannot make a ticket encrypter")?;
+    let mut ticket = ticketer
+        .encrypt(b"ticket plaintext")
+        .ok_or("cannot encrypt a ticket")?;
+    for i in 0..ticket.len() {
+        ticket[i] ^= 1;
+        if ticketer.decrypt(&ticket).is_some() {
+            return Err(format!("altered ticket (byte {}) decrypts", i));
+        }
+        ticket[i] ^= 1;
+    }
+    if ticketer
+        .decrypt(&ticket[..ticket.len() - 1])
+        .is_some()
+    {
+        return Err("truncated ticket decrypts".into());
+    }
+
+    let other = C::ticket_encrypter().map_err(|_| "cannot make a ticket encrypter")?;
+    match other.decrypt(&ticket) {
+        Some(_) => Err("ticket decrypts with another encrypter's key".into()),
+        None => Ok(()),
+    }
+}
+
+fn client_handshakes<C: CryptoProvider>() -> Result<(), String> {
+    for (version, suite, group) in combinations::<C>() {
+        let client = client_config::<C>(version, suite, &[group])?;
+        let server = server_config::<Ring>(version, Ring::ticket_encrypter)?;
+        handshake_and_resume(&client, &server)
+            .map_err(|err| format!("{:?} {:?} {:?}: {}", version.version, suite, group, err))?;
+    }
+    Ok(())
+}
+
+fn server_handshakes<C: CryptoProvider>() -> Result<(), String> {
+    for (version, suite, group) in combinations::<C>() {
+        let client = client_config::<Ring>(version, suite, ring_group_named(group.name()))?;
+        let server = server_config::<C>(version, C::ticket_encrypter)?;
+        handshake_and_resume(&client, &server)
+            .map_err(|err| format!("{:?} {:?} {:?}: {}", version.version, suite, group, err))?;
+    }
+    Ok(())
+}
+
+fn altered_handshakes<C: CryptoProvider>() -> Result<(), String> {
+    for &version in ALL_VERSIONS {
+        let suite = match usable_suites::<C>()
+            .into_iter()
+            .find(|suite| suite.version() == version)
+        {
+            Some(suite) => suite,
+            None => continue,
+        };
+        let client = client_config::<C>(version, suite, C::KeyExchange::all_kx_groups())?;
+        let server = server_config::<Ring>(version, Ring::ticket_encrypter)?;
+
+        // Alter the first byte of the `ServerHello`'s random, after the
+        // record and handshake message headers and the version.
+        let (mut client, mut server) = connect(&client, &server)?;
+        transfer(&mut client, &mut server, |_| {})?;
+        transfer(&mut server, &mut client, |tls| tls[5 + 4 + 2] ^= 1)
+            .and_then(|_| handshake(&mut client, &mut server))
+            .map_or(Ok(()), |_| {
+                Err(format!(
+                    "{:?} handshake succeeded with an altered transcript",
+                    version.version
+                ))
+            })?;
+    }
+    Ok(())
+}
+
+/// A protocol version, cipher suite and key exchange group to handshake with.
+type Combination<C> = (
+    &'static SupportedProtocolVersion,
+    SupportedCipherSuite,
+    &'static <<C as CryptoProvider>::KeyExchange as KeyExchange>::SupportedGroup,
+);
+
+/// Every protocol version, usable cipher suite and key exchange group of
+/// `C`.
+fn combinations<C: CryptoProvider>() -> Vec<Combination<C>> {
+    let mut combinations = Vec::new();
+    for &version in ALL_VERSIONS {
+        for suite in usable_suites::<C>() {
+            if suite.version() != version {
+                continue;
+            }
+            for &group in C::KeyExchange::all_kx_groups() {
+                combinations.push((version, suite, group));
+            }
+        }
+    }
+    combinations
+}
+
+/// The cipher suites that `C` has the hash for, and that can be used with
+/// the built-in certificate.
+fn usable_suites<C: CryptoProvider>() -> Vec<SupportedCipherSuite> {
+    ALL_CIPHER_SUITES
+        .iter()
+        .copied()
+        .filter(|suite| {
+            let algorithm = hash::algorithm_of(suite.hash_algorithm());
+            C::transcript_hash(algorithm).is_some()
+                && C::hmac(algorithm).is_some()
+                && suite.usable_for_signature_algorithm(SignatureAlgorithm::ECDSA)
+        })
+        .collect()
+}
+
+/// [`Ring`]'s group called `name`, if it has one.
+fn ring_group_named(name: NamedGroup) -> &'static [&'static SupportedKxGroup] {
+    match ALL_KX_GROUPS
+        .iter()
+        .position(|group| group.name == name)
+    {
+        Some(i) => &ALL_KX_GROUPS[i..i + 1],
+        None => &[],
+    }
+}
+
+fn client_config<C: CryptoProvider>(
+    version: &'static SupportedProtocolVersion,
+    suite: SupportedCipherSuite,
+    groups: &[&'static <C::KeyExchange as KeyExchange>::SupportedGroup],
+) -> Result<Arc<ClientConfig<C>>, String> {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(CA.to_vec()))
+        .map_err(|err| format!("cannot trust built-in CA: {}", err))?;
+    let mut config = ClientConfig::<C>::builder()
+        .with_cipher_suites(&[suite])
+        .with_kx_groups(groups)
+        .with_protocol_versions(&[version])
+        .map_err(|err| err.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.time_provider = Arc::new(FixedTime);
+    Ok(Arc::new(config))
+}
+
+fn server_config<C: CryptoProvider>(
+    version: &'static SupportedProtocolVersion,
+    ticketer: fn() -> Result<Box<dyn ProducesTickets>, GetRandomFailed>,
+) -> Result<Arc<ServerConfig<C>>, String> {
+    let mut config = ServerConfig::<C>::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[version])
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![
+                Certificate(END_ENTITY.to_vec()),
+                Certificate(INTERMEDIATE.to_vec()),
+            ],
+            PrivateKey(END_ENTITY_KEY.to_vec()),
+        )
+        .map_err(|err| err.to_string())?;
+    config.ticketer = ticketer()
+        .map_err(|_| "cannot make a ticket encrypter")?
+        .into();
+    Ok(Arc::new(config))
+}
+
+fn connect<C: CryptoProvider, S: CryptoProvider>(
+    client: &Arc<ClientConfig<C>>,
+    server: &Arc<ServerConfig<S>>,
+) -> Result<(ClientConnection, ServerConnection), String> {
+    let name = ServerName::try_from("testserver.com").map_err(|err| err.to_string())?;
+    Ok((
+        ClientConnection::new(Arc::clone(client), name).map_err(|err| err.to_string())?,
+        ServerConnection::new(Arc::clone(server)).map_err(|err| err.to_string())?,
+    ))
+}
+
+/// Complete a handshake and exchange some data, then do it again, checking
+/// the second handshake resumes the first.
+fn handshake_and_resume<C: CryptoProvider, S: CryptoProvider>(
+    client: &Arc<ClientConfig<C>>,
+    server: &Arc<ServerConfig<S>>,
+) -> Result<(), String> {
+    for resuming in [false, true] {
+        let (mut client, mut server) = connect(client, server)?;
+        handshake(&mut client, &mut server)?;
+        exchange_data(&mut client, &mut server)?;
+        if server
+            .received_resumption_data()
+            .is_some()
+            != resuming
+        {
+            return Err(match resuming {
+                true => "second handshake did not resume".into(),
+                false => "first handshake resumed".into(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn handshake(client: &mut ClientConnection, server: &mut ServerConnection) -> Result<(), String> {
+    while client.is_handshaking() || server.is_handshaking() {
+        let moved = transfer(client, server, |_| {})? + transfer(server, client, |_| {})?;
+        if moved == 0 {
+            return Err("handshake stalled".into());
+        }
+    }
+    Ok(())
+}
+
+fn exchange_data(
+    client: &mut ClientConnection,
+    server: &mut ServerConnection,
+) -> Result<(), String> {
+    let message = b"conformance";
+    let mut received = [0u8; 11];
+
+    client
+        .writer()
+        .write_all(message)
+        .map_err(|err| err.to_string())?;
+    transfer(client, server, |_| {})?;
+    match server
+        .reader()
+        .read_exact(&mut received)
+    {
+        Ok(()) if &received == message => {}
+        _ => return Err("server did not receive data".into()),
+    }
+
+    server
+        .writer()
+        .write_all(message)
+        .map_err(|err| err.to_string())?;
+    transfer(server, client, |_| {})?;
+    match client
+        .reader()
+        .read_exact(&mut received)
+    {
+        Ok(()) if &received == message => Ok(()),
+        _ => Err("client did not receive data".into()),
+    }
+}
+
+/// Move everything `from` has to write to `to`, letting `alter` change it
+/// first, and have `to` process it.  Returns how many bytes were moved.
+fn transfer<A: SideData, B: SideData>(
+    from: &mut ConnectionCommon<A>,
+    to: &mut ConnectionCommon<B>,
+    alter: impl FnOnce(&mut [u8]),
+) -> Result<usize, String> {
+    let mut tls = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut tls)
+            .map_err(|err| err.to_string())?;
+    }
+    if tls.is_empty() {
+        return Ok(0);
+    }
+    alter(&mut tls);
+
+    let mut input = &tls[..];
+    while !input.is_empty() {
+        to.read_tls(&mut input)
+            .map_err(|err| err.to_string())?;
+        to.process_new_packets()
+            .map_err(|err: Error| err.to_string())?;
+    }
+    Ok(tls.len())
+}
+
+/// The start of 2024, when the built-in certificates are valid.
+#[derive(Debug)]
+struct FixedTime;
+
+impl TimeProvider for FixedTime {
+    fn current_time(&self) -> Option<SystemTime> {
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_067_200))
+    }
+}
+
+/// The output of each hash function for the input "abc" (FIPS 180-2).
+static HASH_ABC: &[(HashAlgorithm, &[u8])] = &[
+    (
+        HashAlgorithm::SHA1,
+        &[
+            0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+            0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+        ],
+    ),
+    (
+        HashAlgorithm::SHA256,
+        &[
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+            0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+            0xf2, 0x00, 0x15, 0xad,
+        ],
+    ),
+    (
+        HashAlgorithm::SHA384,
+        &[
+            0xcb, 0x00, 0x75, 0x3f, 0x45, 0xa3, 0x5e, 0x8b, 0xb5, 0xa0, 0x3d, 0x69, 0x9a, 0xc6,
+            0x50, 0x07, 0x27, 0x2c, 0x32, 0xab, 0x0e, 0xde, 0xd1, 0x63, 0x1a, 0x8b, 0x60, 0x5a,
+            0x43, 0xff, 0x5b, 0xed, 0x80, 0x86, 0x07, 0x2b, 0xa1, 0xe7, 0xcc, 0x23, 0x58, 0xba,
+            0xec, 0xa1, 0x34, 0xc8, 0x25, 0xa7,
+        ],
+    ),
+];
+
+/// The HMAC of RFC 4231's test case 2, for each hash function.
+static HMAC_JEFE: &[(HashAlgorithm, &[u8])] = &[
+    (
+        HashAlgorithm::SHA256,
+        &[
+            0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95,
+            0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9,
+            0x64, 0xec, 0x38, 0x43,
+        ],
+    ),
+    (
+        HashAlgorithm::SHA384,
+        &[
+            0xaf, 0x45, 0xd2, 0xe3, 0x76, 0x48, 0x40, 0x31, 0x61, 0x7f, 0x78, 0xd2, 0xb5, 0x8a,
+            0x6b, 0x1b, 0x9c, 0x7e, 0xf4, 0x64, 0xf5, 0xa0, 0x1b, 0x47, 0xe4, 0x2e, 0xc3, 0x73,
+            0x63, 0x22, 0x44, 0x5e, 0x8e, 0x22, 0x40, 0xca, 0x5e, 0x69, 0xe2, 0xc7, 0x8b, 0x32,
+            0x39, 0xec, 0xfa, 0xb2, 0x16, 0x49,
+        ],
+    ),
+];
+
+// A copy of test-ca/ecdsa, in DER.
+static CA: &[u8] = include_bytes!("../testdata/conformance-ca.der");
+static INTERMEDIATE: &[u8] = include_bytes!("../testdata/conformance-inter.der");
+static END_ENTITY: &[u8] = include_bytes!("../testdata/conformance-end.der");
+static END_ENTITY_KEY: &[u8] = include_bytes!("../testdata/conformance-end.key.der");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hmac::Hmac;
+
+    #[test]
+    fn ring_conforms() {
+        if let Err(failures) = check_all::<Ring>() {
+            panic!("{:#?}", failures);
+        }
+    }
+
+    /// Ring, but with a broken random number generator.
+    struct ZeroRandom;
+
+    impl CryptoProvider for ZeroRandom {
+        type KeyExchange = <Ring as CryptoProvider>::KeyExchange;
+
+        fn fill_random(buf: &mut [u8]) -> Result<(), GetRandomFailed> {
+            buf.fill(0);
+            Ok(())
+        }
+
+        fn transcript_hash(algorithm: HashAlgorithm) -> Option<&'static dyn hash::Hash> {
+            Ring::transcript_hash(algorithm)
+        }
+
+        fn hmac(algorithm: HashAlgorithm) -> Option<&'static dyn Hmac> {
+            Ring::hmac(algorithm)
+        }
+
+        fn ticket_encrypter() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+            Ring::ticket_encrypter()
+        }
+    }
+
+    #[test]
+    fn broken_provider_fails() {
+        let failures = known_answers::<ZeroRandom>().unwrap_err();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].check, "random");
+    }
+}
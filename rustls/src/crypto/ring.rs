@@ -1,15 +1,21 @@
+use crate::crypto::hash::{self, HashAlgorithm};
+use crate::crypto::hmac;
 use crate::crypto::{CryptoProvider, KeyExchangeError, SupportedGroup};
 use crate::error::{Error, PeerMisbehaved};
 use crate::msgs::enums::NamedGroup;
 use crate::rand::GetRandomFailed;
 use crate::server::ProducesTickets;
+use crate::time_provider::{DefaultTimeProvider, TimeProvider};
 
 use ring::aead;
 use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey};
+use ring::digest;
+use ring::hmac as ring_hmac;
 use ring::rand::{SecureRandom, SystemRandom};
 
 use alloc::sync::Arc;
 use core::fmt;
+use core::marker::PhantomData;
 
 /// Default crypto provider.
 #[derive(Debug)]
@@ -23,6 +29,119 @@ impl CryptoProvider for Ring {
             .fill(buf)
             .map_err(|_| GetRandomFailed)
     }
+
+    fn transcript_hash(algorithm: HashAlgorithm) -> Option<&'static dyn hash::Hash> {
+        match algorithm {
+            HashAlgorithm::SHA256 => Some(&SHA256),
+            HashAlgorithm::SHA384 => Some(&SHA384),
+            HashAlgorithm::SHA512 => Some(&SHA512),
+            _ => None,
+        }
+    }
+
+    fn hash(algorithm: HashAlgorithm) -> Option<&'static dyn hash::Hash> {
+        match algorithm {
+            HashAlgorithm::SHA1 => Some(&SHA1),
+            _ => Self::transcript_hash(algorithm),
+        }
+    }
+
+    fn hmac(algorithm: HashAlgorithm) -> Option<&'static dyn hmac::Hmac> {
+        match algorithm {
+            HashAlgorithm::SHA256 => Some(&HMAC_SHA256),
+            HashAlgorithm::SHA384 => Some(&HMAC_SHA384),
+            HashAlgorithm::SHA512 => Some(&HMAC_SHA512),
+            _ => None,
+        }
+    }
+
+    fn ticket_encrypter() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+        make_ticket_encrypter::<Self>()
+    }
+}
+
+static SHA1: Hash = Hash(&digest::SHA1_FOR_LEGACY_USE_ONLY, HashAlgorithm::SHA1);
+static SHA256: Hash = Hash(&digest::SHA256, HashAlgorithm::SHA256);
+static SHA384: Hash = Hash(&digest::SHA384, HashAlgorithm::SHA384);
+static SHA512: Hash = Hash(&digest::SHA512, HashAlgorithm::SHA512);
+
+/// A hash function from *ring*.
+struct Hash(&'static digest::Algorithm, HashAlgorithm);
+
+impl hash::Hash for Hash {
+    fn start(&self) -> Box<dyn hash::Context> {
+        Box::new(Context(digest::Context::new(self.0)))
+    }
+
+    fn hash(&self, data: &[u8]) -> hash::Output {
+        hash::Output::new(digest::digest(self.0, data).as_ref())
+    }
+
+    fn algorithm(&self) -> HashAlgorithm {
+        self.1
+    }
+
+    fn output_len(&self) -> usize {
+        self.0.output_len
+    }
+}
+
+/// An incremental hash computation with *ring*.
+struct Context(digest::Context);
+
+impl hash::Context for Context {
+    fn fork_finish(&self) -> hash::Output {
+        hash::Output::new(self.0.clone().finish().as_ref())
+    }
+
+    fn fork(&self) -> Box<dyn hash::Context> {
+        Box::new(Self(self.0.clone()))
+    }
+
+    fn finish(self: Box<Self>) -> hash::Output {
+        hash::Output::new(self.0.finish().as_ref())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
+static HMAC_SHA256: Hmac = Hmac(ring_hmac::HMAC_SHA256);
+static HMAC_SHA384: Hmac = Hmac(ring_hmac::HMAC_SHA384);
+static HMAC_SHA512: Hmac = Hmac(ring_hmac::HMAC_SHA512);
+
+/// HMAC from *ring*.
+struct Hmac(ring_hmac::Algorithm);
+
+impl hmac::Hmac for Hmac {
+    fn with_key(&self, key: &[u8]) -> Box<dyn hmac::Key> {
+        Box::new(HmacKey(ring_hmac::Key::new(self.0, key)))
+    }
+
+    fn hash_output_len(&self) -> usize {
+        self.0.digest_algorithm().output_len
+    }
+}
+
+/// A HMAC key from *ring*.
+struct HmacKey(ring_hmac::Key);
+
+impl hmac::Key for HmacKey {
+    fn sign(&self, data: &[&[u8]]) -> hmac::Tag {
+        let mut ctx = ring_hmac::Context::with_key(&self.0);
+        for d in data {
+            ctx.update(d);
+        }
+        hmac::Tag::new(ctx.sign().as_ref())
+    }
+
+    fn tag_len(&self) -> usize {
+        self.0
+            .algorithm()
+            .digest_algorithm()
+            .output_len
+    }
 }
 
 /// An in-progress key exchange.  This has the algorithm,
@@ -41,30 +160,7 @@ impl super::KeyExchange for KeyExchange {
         name: NamedGroup,
         supported: &[&'static SupportedKxGroup],
     ) -> Result<Self, KeyExchangeError> {
-        let group = match supported
-            .iter()
-            .find(|group| group.name == name)
-        {
-            Some(group) => group,
-            None => return Err(KeyExchangeError::UnsupportedGroup),
-        };
-
-        let rng = SystemRandom::new();
-        let priv_key = match EphemeralPrivateKey::generate(group.agreement_algorithm, &rng) {
-            Ok(priv_key) => priv_key,
-            Err(_) => return Err(KeyExchangeError::GetRandomFailed),
-        };
-
-        let pub_key = match priv_key.compute_public_key() {
-            Ok(pub_key) => pub_key,
-            Err(_) => return Err(KeyExchangeError::GetRandomFailed),
-        };
-
-        Ok(Self {
-            group,
-            priv_key,
-            pub_key,
-        })
+        Self::start_with_rng(name, supported, &SystemRandom::new())
     }
 
     /// Completes the key exchange, given the peer's public key.
@@ -93,6 +189,39 @@ impl super::KeyExchange for KeyExchange {
     }
 }
 
+impl KeyExchange {
+    /// Start a key exchange, drawing the ephemeral private key from `rng`.
+    pub(crate) fn start_with_rng(
+        name: NamedGroup,
+        supported: &[&'static SupportedKxGroup],
+        rng: &dyn SecureRandom,
+    ) -> Result<Self, KeyExchangeError> {
+        let group = match supported
+            .iter()
+            .find(|group| group.name == name)
+        {
+            Some(group) => group,
+            None => return Err(KeyExchangeError::UnsupportedGroup),
+        };
+
+        let priv_key = match EphemeralPrivateKey::generate(group.agreement_algorithm, rng) {
+            Ok(priv_key) => priv_key,
+            Err(_) => return Err(KeyExchangeError::GetRandomFailed),
+        };
+
+        let pub_key = match priv_key.compute_public_key() {
+            Ok(pub_key) => pub_key,
+            Err(_) => return Err(KeyExchangeError::GetRandomFailed),
+        };
+
+        Ok(Self {
+            group,
+            priv_key,
+            pub_key,
+        })
+    }
+}
+
 /// A key-exchange group supported by *ring*.
 ///
 /// All possible instances of this class are provided by the library in
@@ -103,6 +232,10 @@ pub struct SupportedKxGroup {
 
     /// The corresponding ring agreement::Algorithm
     agreement_algorithm: &'static ring::agreement::Algorithm,
+
+    /// How many random bytes *ring* consumes to generate a private key
+    #[cfg_attr(not(feature = "deterministic_testing"), allow(dead_code))]
+    pub(crate) private_key_len: usize,
 }
 
 impl SupportedGroup for SupportedKxGroup {
@@ -121,18 +254,21 @@ impl fmt::Debug for SupportedKxGroup {
 pub static X25519: SupportedKxGroup = SupportedKxGroup {
     name: NamedGroup::X25519,
     agreement_algorithm: &ring::agreement::X25519,
+    private_key_len: 32,
 };
 
 /// Ephemeral ECDH on secp256r1 (aka NIST-P256)
 pub static SECP256R1: SupportedKxGroup = SupportedKxGroup {
     name: NamedGroup::secp256r1,
     agreement_algorithm: &ring::agreement::ECDH_P256,
+    private_key_len: 32,
 };
 
 /// Ephemeral ECDH on secp384r1 (aka NIST-P384)
 pub static SECP384R1: SupportedKxGroup = SupportedKxGroup {
     name: NamedGroup::secp384r1,
     agreement_algorithm: &ring::agreement::ECDH_P384,
+    private_key_len: 48,
 };
 
 /// A list of all the key exchange groups supported by rustls.
@@ -148,6 +284,9 @@ pub mod kx_group {
 }
 
 /// A concrete, safe ticket creation mechanism.
+///
+/// Tickets are encrypted with *ring*.  To encrypt them with another
+/// provider `C`, use `TicketSwitcher::new(lifetime, C::ticket_encrypter)`.
 pub struct Ticketer {}
 
 impl Ticketer {
@@ -156,24 +295,39 @@ impl Ticketer {
     ///
     /// The encryption mechanism used is Chacha20Poly1305.
     pub fn new() -> Result<Arc<dyn ProducesTickets>, Error> {
-        Ok(Arc::new(crate::ticketer::TicketSwitcher::new(
-            6 * 60 * 60,
-            make_ticket_generator,
-        )?))
+        Self::new_with_time_provider(Arc::new(DefaultTimeProvider))
+    }
+
+    /// Like [`Ticketer::new`], but keys are rotated according to the
+    /// time given by `time_provider`.
+    pub fn new_with_time_provider(
+        time_provider: Arc<dyn TimeProvider>,
+    ) -> Result<Arc<dyn ProducesTickets>, Error> {
+        Ok(Arc::new(
+            crate::ticketer::TicketSwitcher::new_with_time_provider(
+                6 * 60 * 60,
+                Ring::ticket_encrypter,
+                time_provider,
+            )?,
+        ))
     }
 }
 
-fn make_ticket_generator() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+/// Make a ticket encrypter using Chacha20Poly1305, with keys and nonces
+/// from `C`'s random number generator.
+pub(crate) fn make_ticket_encrypter<C: CryptoProvider>(
+) -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
     let mut key = [0u8; 32];
-    Ring::fill_random(&mut key)?;
+    C::fill_random(&mut key)?;
 
     let alg = &aead::CHACHA20_POLY1305;
     let key = aead::UnboundKey::new(alg, &key).unwrap();
 
-    Ok(Box::new(AeadTicketer {
+    Ok(Box::new(AeadTicketer::<C> {
         alg,
         key: aead::LessSafeKey::new(key),
         lifetime: 60 * 60 * 12,
+        _provider: PhantomData,
     }))
 }
 
@@ -181,13 +335,14 @@ fn make_ticket_generator() -> Result<Box<dyn ProducesTickets>, GetRandomFailed>
 /// any *ring* `aead::Algorithm` to encrypt and authentication
 /// the ticket payload.  It does not enforce any lifetime
 /// constraint.
-struct AeadTicketer {
+struct AeadTicketer<C> {
     alg: &'static aead::Algorithm,
     key: aead::LessSafeKey,
     lifetime: u32,
+    _provider: PhantomData<fn() -> C>,
 }
 
-impl ProducesTickets for AeadTicketer {
+impl<C: CryptoProvider> ProducesTickets for AeadTicketer<C> {
     fn enabled(&self) -> bool {
         true
     }
@@ -199,7 +354,7 @@ impl ProducesTickets for AeadTicketer {
     fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
         // Random nonce, because a counter is a privacy leak.
         let mut nonce_buf = [0u8; 12];
-        Ring::fill_random(&mut nonce_buf).ok()?;
+        C::fill_random(&mut nonce_buf).ok()?;
         let nonce = aead::Nonce::assume_unique_for_key(nonce_buf);
         let aad = ring::aead::Aad::empty();
 
@@ -252,7 +407,7 @@ fn basic_pairwise_test() {
 
 #[test]
 fn ticketswitcher_switching_test() {
-    let t = Arc::new(crate::ticketer::TicketSwitcher::new(1, make_ticket_generator).unwrap());
+    let t = Arc::new(crate::ticketer::TicketSwitcher::new(1, Ring::ticket_encrypter).unwrap());
     let now = TimeBase::now().unwrap();
     let cipher1 = t.encrypt(b"ticket 1").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
@@ -280,7 +435,7 @@ fn fail_generator() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
 
 #[test]
 fn ticketswitcher_recover_test() {
-    let mut t = crate::ticketer::TicketSwitcher::new(1, make_ticket_generator).unwrap();
+    let mut t = crate::ticketer::TicketSwitcher::new(1, Ring::ticket_encrypter).unwrap();
     let now = TimeBase::now().unwrap();
     let cipher1 = t.encrypt(b"ticket 1").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
@@ -289,7 +444,7 @@ fn ticketswitcher_recover_test() {
         // Failed new ticketer
         t.maybe_roll(TimeBase(now.0 + core::time::Duration::from_secs(10)));
     }
-    t.generator = make_ticket_generator;
+    t.generator = Ring::ticket_encrypter;
     let cipher2 = t.encrypt(b"ticket 2").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
     assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
@@ -302,3 +457,20 @@ fn ticketswitcher_recover_test() {
     assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
     assert_eq!(t.decrypt(&cipher3).unwrap(), b"ticket 3");
 }
+
+#[test]
+fn ticketer_uses_time_provider() {
+    #[derive(Debug)]
+    struct NoTime;
+
+    impl TimeProvider for NoTime {
+        fn current_time(&self) -> Option<std::time::SystemTime> {
+            None
+        }
+    }
+
+    assert_eq!(
+        Ticketer::new_with_time_provider(Arc::new(NoTime)).err(),
+        Some(Error::FailedToGetCurrentTime)
+    );
+}
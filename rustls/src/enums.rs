@@ -308,6 +308,7 @@ enum_builder! {
         TLS13_CHACHA20_POLY1305_SHA256 => 0x1303,
         TLS13_AES_128_CCM_SHA256 => 0x1304,
         TLS13_AES_128_CCM_8_SHA256 => 0x1305,
+        TLS_FALLBACK_SCSV => 0x5600,
         TLS_ECDH_ECDSA_WITH_NULL_SHA => 0xc001,
         TLS_ECDH_ECDSA_WITH_RC4_128_SHA => 0xc002,
         TLS_ECDH_ECDSA_WITH_3DES_EDE_CBC_SHA => 0xc003,
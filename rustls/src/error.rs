@@ -82,6 +82,17 @@ pub enum Error {
     /// is complete.
     HandshakeNotComplete,
 
+    /// The handshake was still underway when its deadline passed.
+    ///
+    /// See `ClientConfig::handshake_timeout` and `ServerConfig::handshake_timeout`.
+    HandshakeTimedOut,
+
+    /// A connection could not be made because its config's memory budget
+    /// is used up.
+    ///
+    /// See [`MemoryBudget`](crate::MemoryBudget).
+    MemoryBudgetExceeded,
+
     /// The peer sent an oversized record/fragment.
     PeerSentOversizedRecord,
 
@@ -140,6 +151,37 @@ pub enum InvalidMessage {
     UnsupportedKeyExchangeAlgorithm(KeyExchangeAlgorithm),
 }
 
+impl InvalidMessage {
+    /// A stable number identifying this error, from 1000 to 1999.
+    ///
+    /// See [`Error::code`].
+    pub fn code(&self) -> u16 {
+        // New variants take the next unused number; never renumber.
+        match self {
+            Self::HandshakePayloadTooLarge => 1001,
+            Self::InvalidCcs => 1002,
+            Self::InvalidContentType => 1003,
+            Self::InvalidCertificateStatusType => 1004,
+            Self::InvalidCertRequest => 1005,
+            Self::InvalidDhParams => 1006,
+            Self::InvalidEmptyPayload => 1007,
+            Self::InvalidKeyUpdate => 1008,
+            Self::InvalidServerName => 1009,
+            Self::MessageTooLarge => 1010,
+            Self::MessageTooShort => 1011,
+            Self::MissingData(_) => 1012,
+            Self::MissingKeyExchange => 1013,
+            Self::NoSignatureSchemes => 1014,
+            Self::TrailingData(_) => 1015,
+            Self::UnexpectedMessage(_) => 1016,
+            Self::UnknownProtocolVersion => 1017,
+            Self::UnsupportedCompression => 1018,
+            Self::UnsupportedCurveType => 1019,
+            Self::UnsupportedKeyExchangeAlgorithm(_) => 1020,
+        }
+    }
+}
+
 impl From<InvalidMessage> for Error {
     #[inline]
     fn from(e: InvalidMessage) -> Self {
@@ -156,7 +198,8 @@ impl From<InvalidMessage> for Error {
 /// This is `non_exhaustive`: we might add or stop using items here in minor
 /// versions.  We also don't document what they mean.  Generally a user of
 /// rustls shouldn't vary its behaviour on these error codes, and there is
-/// nothing it can do to improve matters.
+/// nothing it can do to improve matters.  Tools which need to tell them
+/// apart across versions should use [`PeerMisbehaved::code`].
 ///
 /// Please file a bug against rustls if you see `Error::PeerMisbehaved` in
 /// the wild.
@@ -183,7 +226,9 @@ pub enum PeerMisbehaved {
     IllegalHelloRetryRequestWithWrongSessionId,
     IllegalMiddleboxChangeCipherSpec,
     IllegalTlsInnerPlaintext,
+    InappropriateFallback,
     IncorrectBinder,
+    InvalidHelloRetryCookie,
     InvalidMaxEarlyDataSize,
     InvalidKeyShare,
     KeyEpochWithPendingFragment,
@@ -219,6 +264,9 @@ pub enum PeerMisbehaved {
     ServerNameMustContainOneHostName,
     SignedKxWithWrongAlgorithm,
     SignedHandshakeWithUnadvertisedSigScheme,
+    TooManyEmptyRecords,
+    TooManyRecordsWithoutProgress,
+    TooManyWarningAlertsReceived,
     TooMuchEarlyDataReceived,
     UnexpectedCleartextExtension,
     UnsolicitedCertExtension,
@@ -228,6 +276,87 @@ pub enum PeerMisbehaved {
     WrongGroupForKeyShare,
 }
 
+impl PeerMisbehaved {
+    /// A stable number identifying this error, from 2000 to 2999.
+    ///
+    /// See [`Error::code`].
+    pub fn code(&self) -> u16 {
+        // New variants take the next unused number; never renumber.
+        match self {
+            Self::AttemptedDowngradeToTls12WhenTls13IsSupported => 2001,
+            Self::BadCertChainExtensions => 2002,
+            Self::DisallowedEncryptedExtension => 2003,
+            Self::DuplicateClientHelloExtensions => 2004,
+            Self::DuplicateEncryptedExtensions => 2005,
+            Self::DuplicateHelloRetryRequestExtensions => 2006,
+            Self::DuplicateNewSessionTicketExtensions => 2007,
+            Self::DuplicateServerHelloExtensions => 2008,
+            Self::DuplicateServerNameTypes => 2009,
+            Self::EarlyDataAttemptedInSecondClientHello => 2010,
+            Self::EarlyDataExtensionWithoutResumption => 2011,
+            Self::EarlyDataOfferedWithVariedCipherSuite => 2012,
+            Self::HandshakeHashVariedAfterRetry => 2013,
+            Self::IllegalHelloRetryRequestWithEmptyCookie => 2014,
+            Self::IllegalHelloRetryRequestWithNoChanges => 2015,
+            Self::IllegalHelloRetryRequestWithOfferedGroup => 2016,
+            Self::IllegalHelloRetryRequestWithUnofferedCipherSuite => 2017,
+            Self::IllegalHelloRetryRequestWithUnofferedNamedGroup => 2018,
+            Self::IllegalHelloRetryRequestWithUnsupportedVersion => 2019,
+            Self::IllegalHelloRetryRequestWithWrongSessionId => 2020,
+            Self::IllegalMiddleboxChangeCipherSpec => 2021,
+            Self::IllegalTlsInnerPlaintext => 2022,
+            Self::InappropriateFallback => 2023,
+            Self::IncorrectBinder => 2024,
+            Self::InvalidHelloRetryCookie => 2025,
+            Self::InvalidMaxEarlyDataSize => 2026,
+            Self::InvalidKeyShare => 2027,
+            Self::KeyEpochWithPendingFragment => 2028,
+            Self::KeyUpdateReceivedInQuicConnection => 2029,
+            Self::MessageInterleavedWithHandshakeMessage => 2030,
+            Self::MissingBinderInPskExtension => 2031,
+            Self::MissingKeyShare => 2032,
+            Self::MissingPskModesExtension => 2033,
+            Self::MissingQuicTransportParameters => 2034,
+            Self::OfferedDuplicateKeyShares => 2035,
+            Self::OfferedEarlyDataWithOldProtocolVersion => 2036,
+            Self::OfferedEmptyApplicationProtocol => 2037,
+            Self::OfferedIncorrectCompressions => 2038,
+            Self::PskExtensionMustBeLast => 2039,
+            Self::PskExtensionWithMismatchedIdsAndBinders => 2040,
+            Self::RefusedToFollowHelloRetryRequest => 2041,
+            Self::RejectedEarlyDataInterleavedWithHandshakeMessage => 2042,
+            Self::ResumptionAttemptedWithVariedEms => 2043,
+            Self::ResumptionOfferedWithVariedCipherSuite => 2044,
+            Self::ResumptionOfferedWithVariedEms => 2045,
+            Self::ResumptionOfferedWithIncompatibleCipherSuite => 2046,
+            Self::SelectedDifferentCipherSuiteAfterRetry => 2047,
+            Self::SelectedInvalidPsk => 2048,
+            Self::SelectedTls12UsingTls13VersionExtension => 2049,
+            Self::SelectedUnofferedApplicationProtocol => 2050,
+            Self::SelectedUnofferedCipherSuite => 2051,
+            Self::SelectedUnofferedCompression => 2052,
+            Self::SelectedUnofferedKxGroup => 2053,
+            Self::SelectedUnofferedPsk => 2054,
+            Self::SelectedUnusableCipherSuiteForVersion => 2055,
+            Self::ServerHelloMustOfferUncompressedEcPoints => 2056,
+            Self::ServerNameDifferedOnRetry => 2057,
+            Self::ServerNameMustContainOneHostName => 2058,
+            Self::SignedKxWithWrongAlgorithm => 2059,
+            Self::SignedHandshakeWithUnadvertisedSigScheme => 2060,
+            Self::TooManyEmptyRecords => 2061,
+            Self::TooManyRecordsWithoutProgress => 2062,
+            Self::TooManyWarningAlertsReceived => 2063,
+            Self::TooMuchEarlyDataReceived => 2064,
+            Self::UnexpectedCleartextExtension => 2065,
+            Self::UnsolicitedCertExtension => 2066,
+            Self::UnsolicitedEncryptedExtension => 2067,
+            Self::UnsolicitedSctList => 2068,
+            Self::UnsolicitedServerHelloExtension => 2069,
+            Self::WrongGroupForKeyShare => 2070,
+        }
+    }
+}
+
 impl From<PeerMisbehaved> for Error {
     #[inline]
     fn from(e: PeerMisbehaved) -> Self {
@@ -245,6 +374,7 @@ impl From<PeerMisbehaved> for Error {
 /// versions.
 pub enum PeerIncompatible {
     EcPointsExtensionRequired,
+    ExtendedMasterSecretExtensionRequired,
     KeyShareExtensionRequired,
     NamedGroupsExtensionRequired,
     NoCertificateRequestSignatureSchemesInCommon,
@@ -253,7 +383,10 @@ pub enum PeerIncompatible {
     NoKxGroupsInCommon,
     NoSignatureSchemesInCommon,
     NullCompressionRequired,
+    RenegotiationRequested,
     ServerDoesNotSupportTls12Or13,
+    ServerNameExtensionRequired,
+    ServerNameRefused,
     ServerSentHelloRetryRequestWithUnknownExtension,
     ServerTlsVersionIsDisabledByOurConfig,
     SignatureAlgorithmsExtensionRequired,
@@ -262,6 +395,7 @@ pub enum PeerIncompatible {
     Tls12NotOfferedOrEnabled,
     Tls13RequiredForQuic,
     UncompressedEcPointsRequired,
+    UsableKeyShareRequired,
 }
 
 impl From<PeerIncompatible> for Error {
@@ -311,10 +445,45 @@ pub enum CertificateError {
     /// The certificate is being used for a different purpose than allowed.
     InvalidPurpose,
 
+    /// The certificate's public key is of a kind, size or curve that is
+    /// not allowed by the verifier's [`CertificateRequirements`].
+    ///
+    /// [`CertificateRequirements`]: crate::CertificateRequirements
+    UnacceptableKey,
+
+    /// The certificate was signed using an algorithm that is not allowed
+    /// by the verifier's [`CertificateRequirements`].
+    ///
+    /// [`CertificateRequirements`]: crate::CertificateRequirements
+    UnacceptableSignatureAlgorithm,
+
     /// The certificate is valid, but the handshake is rejected for other
     /// reasons.
     ApplicationVerificationFailure,
 
+    /// The peer's certificate chain is longer, or contains a larger
+    /// certificate, than its [`CertificateChainLimits`] allow.
+    ///
+    /// [`CertificateChainLimits`]: crate::CertificateChainLimits
+    ChainExceedsLimits,
+
+    /// The server stapled no OCSP response, but its config's
+    /// [`ClientConfig::require_ocsp_staple`] requires one.
+    ///
+    /// [`ClientConfig::require_ocsp_staple`]: crate::ClientConfig::require_ocsp_staple
+    MissingOcspResponse,
+
+    /// The stapled OCSP response is malformed, is not for the end-entity
+    /// certificate, is not correctly signed by its issuer, or does not know
+    /// the certificate's status.
+    InvalidOcspResponse,
+
+    /// The stapled OCSP response is outside the freshness window allowed
+    /// by the config's [`OcspStaplePolicy`].
+    ///
+    /// [`OcspStaplePolicy`]: crate::OcspStaplePolicy
+    StaleOcspResponse,
+
     /// Any other error.
     ///
     /// This can be used by custom verifiers to expose the underlying error
@@ -342,7 +511,13 @@ impl PartialEq<Self> for CertificateError {
             (BadSignature, BadSignature) => true,
             (NotValidForName, NotValidForName) => true,
             (InvalidPurpose, InvalidPurpose) => true,
+            (UnacceptableKey, UnacceptableKey) => true,
+            (UnacceptableSignatureAlgorithm, UnacceptableSignatureAlgorithm) => true,
             (ApplicationVerificationFailure, ApplicationVerificationFailure) => true,
+            (ChainExceedsLimits, ChainExceedsLimits) => true,
+            (MissingOcspResponse, MissingOcspResponse) => true,
+            (InvalidOcspResponse, InvalidOcspResponse) => true,
+            (StaleOcspResponse, StaleOcspResponse) => true,
             _ => false,
         }
     }
@@ -355,7 +530,9 @@ impl From<CertificateError> for AlertDescription {
     fn from(e: CertificateError) -> Self {
         use CertificateError::*;
         match e {
-            BadEncoding | UnhandledCriticalExtension | NotValidForName => Self::BadCertificate,
+            BadEncoding | UnhandledCriticalExtension | NotValidForName | ChainExceedsLimits => {
+                Self::BadCertificate
+            }
             // RFC 5246/RFC 8446
             // certificate_expired
             //  A certificate has expired or **is not currently valid**.
@@ -363,8 +540,13 @@ impl From<CertificateError> for AlertDescription {
             Revoked => Self::CertificateRevoked,
             UnknownIssuer => Self::UnknownCA,
             BadSignature => Self::DecryptError,
-            InvalidPurpose => Self::UnsupportedCertificate,
+            InvalidPurpose | UnacceptableKey | UnacceptableSignatureAlgorithm => {
+                Self::UnsupportedCertificate
+            }
             ApplicationVerificationFailure => Self::AccessDenied,
+            MissingOcspResponse | InvalidOcspResponse | StaleOcspResponse => {
+                Self::BadCertificateStatusResponse
+            }
             // RFC 5246/RFC 8446
             // certificate_unknown
             //  Some other (unspecified) issue arose in processing the
@@ -521,6 +703,8 @@ impl fmt::Display for Error {
             Self::EncryptError => write!(f, "cannot encrypt message"),
             Self::PeerSentOversizedRecord => write!(f, "peer sent excess record size"),
             Self::HandshakeNotComplete => write!(f, "handshake not complete"),
+            Self::HandshakeTimedOut => write!(f, "handshake did not complete in time"),
+            Self::MemoryBudgetExceeded => write!(f, "memory budget exceeded"),
             Self::NoApplicationProtocol => write!(f, "peer doesn't support any known protocol"),
             Self::FailedToGetCurrentTime => write!(f, "failed to get current time"),
             Self::FailedToGetRandomBytes => write!(f, "failed to get random bytes"),
@@ -532,6 +716,40 @@ impl fmt::Display for Error {
     }
 }
 
+impl Error {
+    /// A stable number identifying an error caused by what the peer sent,
+    /// or `None` for other errors.
+    ///
+    /// These are for tools, such as fuzzers and log analysis, which need to
+    /// tell errors apart across rustls versions.  Variant names and
+    /// `Display` output may change between versions, but a number is never
+    /// reused or given a different meaning.  The numbers are:
+    ///
+    /// - 1 to 999 for errors in the record layer or message sequence:
+    ///   - 1: [`Error::InappropriateMessage`]
+    ///   - 2: [`Error::InappropriateHandshakeMessage`]
+    ///   - 3: [`Error::DecryptError`]
+    ///   - 4: [`Error::PeerSentOversizedRecord`]
+    /// - 1000 to 1999 for [`Error::InvalidMessage`]; see [`InvalidMessage::code`].
+    /// - 2000 to 2999 for [`Error::PeerMisbehaved`]; see [`PeerMisbehaved::code`].
+    ///
+    /// The alert sent to the peer because of the error is available from
+    /// [`CommonState::sent_fatal_alert`].
+    ///
+    /// [`CommonState::sent_fatal_alert`]: crate::CommonState::sent_fatal_alert
+    pub fn code(&self) -> Option<u16> {
+        match self {
+            Self::InappropriateMessage { .. } => Some(1),
+            Self::InappropriateHandshakeMessage { .. } => Some(2),
+            Self::DecryptError => Some(3),
+            Self::PeerSentOversizedRecord => Some(4),
+            Self::InvalidMessage(err) => Some(err.code()),
+            Self::PeerMisbehaved(err) => Some(err.code()),
+            _ => None,
+        }
+    }
+}
+
 impl From<SystemTimeError> for Error {
     #[inline]
     fn from(_: SystemTimeError) -> Self {
@@ -564,15 +782,59 @@ mod tests {
         assert_eq!(BadSignature, BadSignature);
         assert_eq!(NotValidForName, NotValidForName);
         assert_eq!(InvalidPurpose, InvalidPurpose);
+        assert_eq!(UnacceptableKey, UnacceptableKey);
+        assert_eq!(
+            UnacceptableSignatureAlgorithm,
+            UnacceptableSignatureAlgorithm
+        );
         assert_eq!(
             ApplicationVerificationFailure,
             ApplicationVerificationFailure
         );
+        assert_eq!(ChainExceedsLimits, ChainExceedsLimits);
+        assert_eq!(MissingOcspResponse, MissingOcspResponse);
+        assert_eq!(InvalidOcspResponse, InvalidOcspResponse);
+        assert_eq!(StaleOcspResponse, StaleOcspResponse);
         let other = Other(alloc::sync::Arc::from(Box::from("")));
         assert_ne!(other, other);
         assert_ne!(BadEncoding, Expired);
     }
 
+    #[test]
+    fn error_codes_are_stable() {
+        use super::PeerMisbehaved;
+        use crate::enums::ContentType;
+        use crate::msgs::handshake::KeyExchangeAlgorithm;
+
+        let inappropriate = Error::InappropriateMessage {
+            expect_types: vec![ContentType::Handshake],
+            got_type: ContentType::ApplicationData,
+        };
+        assert_eq!(inappropriate.code(), Some(1));
+        assert_eq!(Error::PeerSentOversizedRecord.code(), Some(4));
+        assert_eq!(
+            Error::from(InvalidMessage::HandshakePayloadTooLarge).code(),
+            Some(1001)
+        );
+        assert_eq!(
+            Error::from(InvalidMessage::UnsupportedKeyExchangeAlgorithm(
+                KeyExchangeAlgorithm::DHE
+            ))
+            .code(),
+            Some(1020)
+        );
+        assert_eq!(
+            Error::from(PeerMisbehaved::AttemptedDowngradeToTls12WhenTls13IsSupported).code(),
+            Some(2001)
+        );
+        assert_eq!(
+            Error::from(PeerMisbehaved::WrongGroupForKeyShare).code(),
+            Some(2070)
+        );
+        assert_eq!(Error::HandshakeNotComplete.code(), None);
+        assert_eq!(Error::General("other".into()).code(), None);
+    }
+
     #[test]
     fn crl_error_equality() {
         use super::CertRevocationListError::*;
@@ -670,6 +932,8 @@ mod tests {
             Error::FailedToGetCurrentTime,
             Error::FailedToGetRandomBytes,
             Error::HandshakeNotComplete,
+            Error::HandshakeTimedOut,
+            Error::MemoryBudgetExceeded,
             Error::PeerSentOversizedRecord,
             Error::NoApplicationProtocol,
             Error::BadMaxFragmentSize,
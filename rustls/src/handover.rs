@@ -0,0 +1,346 @@
+use crate::client::ServerName;
+use crate::common_state::{CommonState, Side, State};
+use crate::conn::ConnectionCore;
+#[cfg(feature = "tls12")]
+use crate::conn::ConnectionRandoms;
+#[cfg(feature = "tls12")]
+use crate::crypto::hmac;
+use crate::crypto::CryptoProvider;
+use crate::enums::{CipherSuite, ProtocolVersion};
+use crate::error::{Error, InvalidMessage};
+use crate::msgs::base::PayloadU8;
+use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::handshake::CertificatePayload;
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::Random;
+use crate::suites::SupportedCipherSuite;
+#[cfg(feature = "tls12")]
+use crate::tls12::ConnectionSecrets;
+use crate::tls13::Tls13CipherSuite;
+
+use ring::hkdf::KeyType as _;
+
+/// Identifies the encoding below; bump it whenever that changes.
+const FORMAT_VERSION: u8 = 1;
+
+/// The secrets a TLS1.3 connection needs once the handshake is complete.
+pub(crate) struct Tls13TrafficSecrets {
+    pub(crate) suite: &'static Tls13CipherSuite,
+    pub(crate) client: Vec<u8>,
+    pub(crate) server: Vec<u8>,
+    pub(crate) exporter: Vec<u8>,
+    /// Only kept by clients.
+    pub(crate) resumption: Option<Tls13ClientResumption>,
+}
+
+/// What a TLS1.3 client needs to accept new tickets.
+pub(crate) struct Tls13ClientResumption {
+    pub(crate) server_name: ServerName,
+    pub(crate) resumption_master_secret: Vec<u8>,
+}
+
+/// The secrets of an established connection.
+pub(crate) enum TrafficSecrets {
+    #[cfg(feature = "tls12")]
+    Tls12(ConnectionSecrets),
+    Tls13(Tls13TrafficSecrets),
+}
+
+/// Encode the established connection `core`, consuming it.
+///
+/// `sni` is the server name a server received.
+pub(crate) fn export<Data>(
+    core: ConnectionCore<Data>,
+    sni: Option<&str>,
+) -> Result<Vec<u8>, Error> {
+    let ConnectionCore {
+        state,
+        mut common_state,
+        message_deframer,
+        ..
+    } = core;
+
+    let secrets = state?.export_traffic_secrets()?;
+    let received_tls = message_deframer
+        .unprocessed()
+        .ok_or_else(|| {
+            Error::General("cannot export part way through a handshake message".into())
+        })?;
+    let (version, suite) = match (common_state.negotiated_version, common_state.suite) {
+        (Some(version), Some(suite)) => (version, suite.suite()),
+        _ => return Err(Error::HandshakeNotComplete),
+    };
+
+    // A key update is encrypted as soon as it is queued, so it must go out
+    // before anything encrypted by the importer.
+    common_state.perhaps_write_key_update();
+
+    let mut bytes = Vec::new();
+    FORMAT_VERSION.encode(&mut bytes);
+    match common_state.side {
+        Side::Client => 0u8,
+        Side::Server => 1u8,
+    }
+    .encode(&mut bytes);
+    version.encode(&mut bytes);
+    suite.encode(&mut bytes);
+    encode_optional(sni.map(|name| PayloadU8::new(name.into())), &mut bytes);
+    encode_optional(
+        common_state
+            .alpn_protocol
+            .take()
+            .map(PayloadU8::new),
+        &mut bytes,
+    );
+    encode_optional(common_state.peer_certificates.take(), &mut bytes);
+
+    match secrets {
+        #[cfg(feature = "tls12")]
+        TrafficSecrets::Tls12(secrets) => {
+            PayloadU8::new(secrets.master_secret.to_vec()).encode(&mut bytes);
+            Random(secrets.randoms.client).encode(&mut bytes);
+            Random(secrets.randoms.server).encode(&mut bytes);
+        }
+        TrafficSecrets::Tls13(secrets) => {
+            PayloadU8::new(secrets.client).encode(&mut bytes);
+            PayloadU8::new(secrets.server).encode(&mut bytes);
+            PayloadU8::new(secrets.exporter).encode(&mut bytes);
+            match secrets.resumption {
+                Some(resumption) => {
+                    let server_name = match resumption.server_name {
+                        ServerName::DnsName(name) => name.as_ref().to_string(),
+                        ServerName::IpAddress(ip) => ip.to_string(),
+                    };
+                    1u8.encode(&mut bytes);
+                    PayloadU8::new(server_name.into_bytes()).encode(&mut bytes);
+                    PayloadU8::new(resumption.resumption_master_secret).encode(&mut bytes);
+                }
+                None => 0u8.encode(&mut bytes),
+            }
+        }
+    }
+
+    common_state
+        .record_layer
+        .write_seq()
+        .encode(&mut bytes);
+    common_state
+        .record_layer
+        .read_seq()
+        .encode(&mut bytes);
+    u8::from(common_state.has_received_close_notify).encode(&mut bytes);
+    u8::from(common_state.has_seen_eof).encode(&mut bytes);
+    u8::from(common_state.using_ems).encode(&mut bytes);
+    u8::from(common_state.secure_renegotiation).encode(&mut bytes);
+
+    let mut received_plaintext = Vec::new();
+    while let Some(chunk) = common_state.received_plaintext.pop() {
+        received_plaintext.extend_from_slice(&chunk);
+    }
+    let mut sendable_tls = Vec::new();
+    while let Some(chunk) = common_state.sendable_tls.pop() {
+        sendable_tls.extend_from_slice(&chunk);
+    }
+    encode_buffer(&received_plaintext, &mut bytes);
+    encode_buffer(&sendable_tls, &mut bytes);
+    encode_buffer(received_tls, &mut bytes);
+
+    Ok(bytes)
+}
+
+/// Decode a connection encoded by [`export`], to continue it on `side`.
+///
+/// The cipher suite must be one of `cipher_suites`.  `restore` installs
+/// the traffic keys in the given `CommonState`, and returns the state
+/// machine and side-specific data for the connection; it is given the
+/// exported SNI and secrets.
+pub(crate) fn import<C: CryptoProvider, Data>(
+    bytes: &[u8],
+    side: Side,
+    cipher_suites: &[SupportedCipherSuite],
+    restore: impl FnOnce(
+        &mut CommonState,
+        Option<&str>,
+        TrafficSecrets,
+    ) -> Result<(Box<dyn State<Data>>, Data), Error>,
+) -> Result<ConnectionCore<Data>, Error> {
+    let mut r = Reader::init(bytes);
+    let invalid = |_| Error::General("invalid exported connection".into());
+
+    if u8::read(&mut r).map_err(invalid)? != FORMAT_VERSION {
+        return Err(Error::General(
+            "unsupported exported connection format".into(),
+        ));
+    }
+    let exported_side = match u8::read(&mut r).map_err(invalid)? {
+        0 => Side::Client,
+        1 => Side::Server,
+        _ => return Err(invalid(InvalidMessage::InvalidContentType)),
+    };
+    if exported_side != side {
+        return Err(Error::General(format!(
+            "exported connection is for a {:?}",
+            exported_side
+        )));
+    }
+
+    let version = ProtocolVersion::read(&mut r).map_err(invalid)?;
+    let suite_id = CipherSuite::read(&mut r).map_err(invalid)?;
+    let suite = cipher_suites
+        .iter()
+        .find(|suite| suite.suite() == suite_id && suite.version().version == version)
+        .copied()
+        .ok_or_else(|| {
+            Error::General(format!(
+                "exported connection uses {:?}, which is not enabled",
+                suite_id
+            ))
+        })?;
+    let sni = read_optional::<PayloadU8>(&mut r)
+        .map_err(invalid)?
+        .map(|name| String::from_utf8(name.0))
+        .transpose()
+        .map_err(|_| invalid(InvalidMessage::InvalidServerName))?;
+    let alpn_protocol = read_optional::<PayloadU8>(&mut r).map_err(invalid)?;
+    let peer_certificates = read_optional::<CertificatePayload>(&mut r).map_err(invalid)?;
+
+    let secrets = match suite {
+        #[cfg(feature = "tls12")]
+        SupportedCipherSuite::Tls12(suite) => {
+            let master_secret = PayloadU8::read(&mut r).map_err(invalid)?;
+            if master_secret.0.len() != 48 {
+                return Err(invalid(InvalidMessage::MessageTooShort));
+            }
+            let randoms = ConnectionRandoms::new(
+                Random::read(&mut r).map_err(invalid)?,
+                Random::read(&mut r).map_err(invalid)?,
+            );
+            TrafficSecrets::Tls12(ConnectionSecrets::new_resume(
+                randoms,
+                suite,
+                hmac::for_suite::<C>(suite.hash_algorithm())?,
+                &master_secret.0,
+            ))
+        }
+        SupportedCipherSuite::Tls13(suite) => {
+            let secrets = Tls13TrafficSecrets {
+                suite,
+                client: PayloadU8::read(&mut r)
+                    .map_err(invalid)?
+                    .0,
+                server: PayloadU8::read(&mut r)
+                    .map_err(invalid)?
+                    .0,
+                exporter: PayloadU8::read(&mut r)
+                    .map_err(invalid)?
+                    .0,
+                resumption: match u8::read(&mut r).map_err(invalid)? {
+                    1 => Some(read_client_resumption(&mut r).map_err(invalid)?),
+                    _ => None,
+                },
+            };
+            let secret_len = suite.hkdf_algorithm.len();
+            if [&secrets.client, &secrets.server, &secrets.exporter]
+                .iter()
+                .any(|secret| secret.len() != secret_len)
+            {
+                return Err(invalid(InvalidMessage::MessageTooShort));
+            }
+            TrafficSecrets::Tls13(secrets)
+        }
+    };
+
+    let write_seq = u64::read(&mut r).map_err(invalid)?;
+    let read_seq = u64::read(&mut r).map_err(invalid)?;
+    let has_received_close_notify = u8::read(&mut r).map_err(invalid)? == 1;
+    let has_seen_eof = u8::read(&mut r).map_err(invalid)? == 1;
+    let using_ems = u8::read(&mut r).map_err(invalid)? == 1;
+    let secure_renegotiation = u8::read(&mut r).map_err(invalid)? == 1;
+    let received_plaintext = read_buffer(&mut r).map_err(invalid)?;
+    let sendable_tls = read_buffer(&mut r).map_err(invalid)?;
+    let received_tls = read_buffer(&mut r).map_err(invalid)?;
+    r.expect_empty("exported connection")
+        .map_err(invalid)?;
+
+    let mut common_state = CommonState::new(side);
+    common_state.negotiated_version = Some(version);
+    common_state.suite = Some(suite);
+    common_state.alpn_protocol = alpn_protocol.map(|protocol| protocol.0);
+    common_state.peer_certificates = peer_certificates;
+    common_state.has_received_close_notify = has_received_close_notify;
+    common_state.has_seen_eof = has_seen_eof;
+    common_state.using_ems = using_ems;
+    common_state.secure_renegotiation = secure_renegotiation;
+
+    let (state, data) = restore(&mut common_state, sni.as_deref(), secrets)?;
+    common_state
+        .record_layer
+        .restore_sequence_numbers(write_seq, read_seq);
+    common_state.start_traffic();
+    common_state
+        .received_plaintext
+        .append(received_plaintext);
+    common_state
+        .sendable_tls
+        .append(sendable_tls);
+
+    let mut core = ConnectionCore::new(state, data, common_state);
+    let mut received_tls = &received_tls[..];
+    while !received_tls.is_empty() {
+        match core
+            .message_deframer
+            .read(&mut received_tls)
+        {
+            Ok(0) | Err(_) => {
+                return Err(Error::General(
+                    "exported connection has too much buffered data".into(),
+                ))
+            }
+            Ok(_) => {}
+        }
+    }
+    Ok(core)
+}
+
+fn read_client_resumption(r: &mut Reader) -> Result<Tls13ClientResumption, InvalidMessage> {
+    let server_name = PayloadU8::read(r)?;
+    let server_name = core::str::from_utf8(&server_name.0)
+        .ok()
+        .and_then(|name| ServerName::try_from(name).ok())
+        .ok_or(InvalidMessage::InvalidServerName)?;
+    Ok(Tls13ClientResumption {
+        server_name,
+        resumption_master_secret: PayloadU8::read(r)?.0,
+    })
+}
+
+fn encode_optional<T: Codec>(value: Option<T>, bytes: &mut Vec<u8>) {
+    match value {
+        Some(value) => {
+            1u8.encode(bytes);
+            value.encode(bytes);
+        }
+        None => 0u8.encode(bytes),
+    }
+}
+
+fn read_optional<T: Codec>(r: &mut Reader) -> Result<Option<T>, InvalidMessage> {
+    match u8::read(r)? {
+        1 => Ok(Some(T::read(r)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Buffers are not limited to the lengths of TLS encodings, so have a
+/// 32-bit length prefix.
+fn encode_buffer(buffer: &[u8], bytes: &mut Vec<u8>) {
+    (buffer.len() as u32).encode(bytes);
+    bytes.extend_from_slice(buffer);
+}
+
+fn read_buffer(r: &mut Reader) -> Result<Vec<u8>, InvalidMessage> {
+    let len = u32::read(r)? as usize;
+    r.take(len)
+        .map(<[u8]>::to_vec)
+        .ok_or(InvalidMessage::MessageTooShort)
+}
@@ -1,8 +1,8 @@
+use crate::crypto::hash;
 use crate::msgs::codec::Codec;
 use crate::msgs::handshake::HandshakeMessagePayload;
 use crate::msgs::message::{Message, MessagePayload};
 use core::mem;
-use ring::digest;
 
 /// Early stage buffering of handshake payloads.
 ///
@@ -45,20 +45,21 @@ impl HandshakeHashBuffer {
     /// Get the hash value if we were to hash `extra` too.
     pub(crate) fn get_hash_given(
         &self,
-        hash: &'static digest::Algorithm,
+        provider: &'static dyn hash::Hash,
         extra: &[u8],
-    ) -> digest::Digest {
-        let mut ctx = digest::Context::new(hash);
+    ) -> hash::Output {
+        let mut ctx = provider.start();
         ctx.update(&self.buffer);
         ctx.update(extra);
         ctx.finish()
     }
 
     /// We now know what hash function the verify_data will use.
-    pub(crate) fn start_hash(self, alg: &'static digest::Algorithm) -> HandshakeHash {
-        let mut ctx = digest::Context::new(alg);
+    pub(crate) fn start_hash(self, provider: &'static dyn hash::Hash) -> HandshakeHash {
+        let mut ctx = provider.start();
         ctx.update(&self.buffer);
         HandshakeHash {
+            provider,
             ctx,
             client_auth: match self.client_auth_enabled {
                 true => Some(self.buffer),
@@ -76,8 +77,8 @@ impl HandshakeHashBuffer {
 /// For client auth, we also need to buffer all the messages.
 /// This is disabled in cases where client auth is not possible.
 pub(crate) struct HandshakeHash {
-    /// None before we know what hash function we're using
-    ctx: digest::Context,
+    provider: &'static dyn hash::Hash,
+    ctx: Box<dyn hash::Context>,
 
     /// buffer for client-auth.
     client_auth: Option<Vec<u8>>,
@@ -111,8 +112,8 @@ impl HandshakeHash {
 
     /// Get the hash value if we were to hash `extra` too,
     /// using hash function `hash`.
-    pub(crate) fn get_hash_given(&self, extra: &[u8]) -> digest::Digest {
-        let mut ctx = self.ctx.clone();
+    pub(crate) fn get_hash_given(&self, extra: &[u8]) -> hash::Output {
+        let mut ctx = self.ctx.fork();
         ctx.update(extra);
         ctx.finish()
     }
@@ -132,19 +133,24 @@ impl HandshakeHash {
     /// 'handshake_hash' handshake message.  Start this hash
     /// again, with that message at the front.
     pub(crate) fn rollup_for_hrr(&mut self) {
-        let ctx = &mut self.ctx;
-
-        let old_ctx = mem::replace(ctx, digest::Context::new(ctx.algorithm()));
+        let old_ctx = mem::replace(&mut self.ctx, self.provider.start());
         let old_hash = old_ctx.finish();
-        let old_handshake_hash_msg =
-            HandshakeMessagePayload::build_handshake_hash(old_hash.as_ref());
+        self.add_hrr_rollup(old_hash.as_ref());
+    }
 
+    /// Add the 'handshake_hash' message that `rollup_for_hrr` would have
+    /// produced from a transcript hashing to `old_hash`.
+    ///
+    /// This lets a server reconstruct its transcript after a stateless
+    /// retry, having kept only the hash of the first `ClientHello`.
+    pub(crate) fn add_hrr_rollup(&mut self, old_hash: &[u8]) {
+        let old_handshake_hash_msg = HandshakeMessagePayload::build_handshake_hash(old_hash);
         self.update_raw(&old_handshake_hash_msg.get_encoding());
     }
 
     /// Get the current hash value.
-    pub(crate) fn get_current_hash(&self) -> digest::Digest {
-        self.ctx.clone().finish()
+    pub(crate) fn get_current_hash(&self) -> hash::Output {
+        self.ctx.fork_finish()
     }
 
     /// Takes this object's buffer containing all handshake messages
@@ -155,23 +161,25 @@ impl HandshakeHash {
         self.client_auth.take()
     }
 
-    /// The digest algorithm
-    pub(crate) fn algorithm(&self) -> &'static digest::Algorithm {
-        self.ctx.algorithm()
+    /// The hash algorithm
+    pub(crate) fn algorithm(&self) -> hash::HashAlgorithm {
+        self.provider.algorithm()
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::HandshakeHashBuffer;
-    use ring::digest;
+    use crate::crypto::hash::HashAlgorithm;
+    use crate::crypto::ring::Ring;
+    use crate::crypto::CryptoProvider;
 
     #[test]
     fn hashes_correctly() {
         let mut hhb = HandshakeHashBuffer::new();
         hhb.update_raw(b"hello");
         assert_eq!(hhb.buffer.len(), 5);
-        let mut hh = hhb.start_hash(&digest::SHA256);
+        let mut hh = hhb.start_hash(Ring::transcript_hash(HashAlgorithm::SHA256).unwrap());
         assert!(hh.client_auth.is_none());
         hh.update_raw(b"world");
         let h = hh.get_current_hash();
@@ -189,7 +197,7 @@ mod test {
         hhb.set_client_auth_enabled();
         hhb.update_raw(b"hello");
         assert_eq!(hhb.buffer.len(), 5);
-        let mut hh = hhb.start_hash(&digest::SHA256);
+        let mut hh = hhb.start_hash(Ring::transcript_hash(HashAlgorithm::SHA256).unwrap());
         assert_eq!(
             hh.client_auth
                 .as_ref()
@@ -219,7 +227,7 @@ mod test {
         hhb.set_client_auth_enabled();
         hhb.update_raw(b"hello");
         assert_eq!(hhb.buffer.len(), 5);
-        let mut hh = hhb.start_hash(&digest::SHA256);
+        let mut hh = hhb.start_hash(Ring::transcript_hash(HashAlgorithm::SHA256).unwrap());
         assert_eq!(
             hh.client_auth
                 .as_ref()
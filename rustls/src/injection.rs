@@ -0,0 +1,43 @@
+use crate::common_state::IoState;
+use crate::conn::ConnectionCommon;
+use crate::error::Error;
+use crate::msgs::message::PlainMessage;
+
+/// Process `msg` as if it had just been received and decrypted.
+///
+/// The message skips the deframer and record layer, so it is handled
+/// regardless of whether it would have been correctly framed or protected
+/// on the wire: this allows messages to be delivered out of order, with
+/// the wrong content type for the current epoch, with payloads that do
+/// not parse, and so on.  Everything after that -- dropping middlebox
+/// `ChangeCipherSpec`s, alert processing, and the handshake state
+/// machine -- behaves exactly as for a message read with `read_tls`.
+///
+/// As with [`ConnectionCommon::process_new_packets`], an error is fatal
+/// and is returned again by all later calls.
+pub fn inject_message<Data>(
+    conn: &mut ConnectionCommon<Data>,
+    msg: PlainMessage,
+) -> Result<IoState, Error> {
+    conn.core.process_injected_message(msg)
+}
+
+/// Start keeping a copy of every message the connection sends, before
+/// fragmentation and encryption.
+///
+/// Collect them with [`take_sent_messages`].  Messages sent before this is
+/// called (such as the client's initial `ClientHello`) are not recorded.
+pub fn record_sent_messages<Data>(conn: &mut ConnectionCommon<Data>) {
+    conn.core
+        .common_state
+        .record_sent_messages();
+}
+
+/// Return the messages recorded since the last call, oldest first.
+///
+/// This is empty unless [`record_sent_messages`] was called.
+pub fn take_sent_messages<Data>(conn: &mut ConnectionCommon<Data>) -> Vec<PlainMessage> {
+    conn.core
+        .common_state
+        .take_sent_messages()
+}
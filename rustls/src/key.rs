@@ -1,5 +1,7 @@
 use core::fmt;
+use std::time::SystemTime;
 
+use crate::x509::CertificateDetails;
 use crate::Error;
 
 /// This type contains a private key by value.
@@ -84,6 +86,18 @@ pub struct PrivateKey(pub Vec<u8>);
 #[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Certificate(pub Vec<u8>);
 
+impl Certificate {
+    /// Parse the certificate's subject, issuer, validity period and
+    /// other commonly-needed fields.
+    ///
+    /// The certificate is parsed on every call, so keep the result if
+    /// several fields are needed.  An error is returned if the certificate
+    /// is malformed.
+    pub fn details(&self) -> Result<CertificateDetails<'_>, Error> {
+        CertificateDetails::parse(&self.0)
+    }
+}
+
 impl AsRef<[u8]> for Certificate {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -102,14 +116,22 @@ impl fmt::Debug for Certificate {
 /// wrapper around internal representation of a parsed certificate. This is used in order to avoid parsing twice when specifying custom verification
 #[cfg_attr(not(feature = "dangerous_configuration"), allow(unreachable_pub))]
 #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
-pub struct ParsedCertificate<'a>(pub(crate) webpki::EndEntityCert<'a>);
+pub struct ParsedCertificate<'a>(
+    pub(crate) webpki::EndEntityCert<'a>,
+    /// The start of the validity period, if we could parse it.
+    pub(crate) Option<SystemTime>,
+);
 
 impl<'a> TryFrom<&'a Certificate> for ParsedCertificate<'a> {
     type Error = Error;
     fn try_from(value: &'a Certificate) -> Result<ParsedCertificate<'a>, Self::Error> {
+        let not_before = value
+            .details()
+            .ok()
+            .map(|details| details.not_before());
         webpki::EndEntityCert::try_from(value.0.as_ref())
             .map_err(crate::verify::pki_error)
-            .map(ParsedCertificate)
+            .map(|cert| ParsedCertificate(cert, not_before))
     }
 }
 
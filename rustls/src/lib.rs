@@ -63,6 +63,24 @@
 //!
 //! Rustls requires Rust 1.60 or later.
 //!
+//! #### WebAssembly
+//!
+//! Rustls builds for `wasm32-unknown-unknown`, for example to run TLS in a
+//! browser over a WebSocket or WebTransport tunnel.  Rustls does no IO of its
+//! own, so only two things need the host's help:
+//!
+//! - Randomness comes from the configured `CryptoProvider`.  *ring* obtains it
+//!   from the browser's `crypto.getRandomValues()`.
+//! - Time comes from the configured [`TimeProvider`].  There is no system clock
+//!   on this target, so either enable the `wasm32_unknown_unknown_js` feature
+//!   to have [`DefaultTimeProvider`] use `Date.now()`, or supply your own
+//!   provider with `ClientConfig::time_provider`.  The default ticketer on
+//!   servers uses [`DefaultTimeProvider`] too; see
+//!   `Ticketer::new_with_time_provider`.
+//!
+//! [`KeyLogFile`] reads an environment variable and writes to a file, and
+//! so does nothing on this target.
+//!
 //! ## Design Overview
 //! ### Rustls does not take care of network IO
 //! It doesn't make or accept TCP connections, or do DNS, or read or write files.
@@ -239,8 +257,11 @@
 //!
 //! - `dangerous_configuration`: this feature enables a `dangerous()` method on
 //!   `ClientConfig` and `ServerConfig` that allows setting inadvisable options,
-//!   such as replacing the certificate verification process.  Applications
-//!   requesting this feature should be reviewed carefully.
+//!   such as replacing the certificate verification process.  It also
+//!   enables exporting an established connection, with its secrets, to carry
+//!   it on elsewhere, and building clients that do not verify servers at
+//!   all, for opportunistic encryption.  Applications requesting this
+//!   feature should be reviewed carefully.
 //!
 //! - `quic`: this feature exposes additional constructors and functions
 //!   for using rustls as a TLS library for QUIC.  See the `quic` module for
@@ -256,6 +277,24 @@
 //! - `read_buf`: When building with Rust Nightly, adds support for the unstable
 //!   `std::io::ReadBuf` and related APIs. This reduces costs from initializing
 //!   buffers. Will do nothing on non-Nightly releases.
+//!
+//! - `deterministic_testing`: exposes `crypto::deterministic`, a crypto provider
+//!   with seeded randomness and a fixed clock, so handshakes can be reproduced
+//!   byte-for-byte in tests.  Never use this outside of tests: nothing it
+//!   produces is secret.
+//!
+//! - `provider_conformance`: exposes `crypto::provider_conformance`, checks
+//!   that a `CryptoProvider` gives known answers, interoperates with the
+//!   *ring* provider, and rejects bad inputs.  This is for testing
+//!   third-party providers.
+//!
+//! - `wasm32_unknown_unknown_js`: on `wasm32-unknown-unknown`, makes
+//!   [`DefaultTimeProvider`] ask the JavaScript host for the time.  This
+//!   does nothing on other targets.
+//!
+//! - `futures_io`, `tokio`: implement the `AsyncRead` and `AsyncWrite` traits
+//!   of the `futures-io` and `tokio` crates for `async_io::TlsStream`, which
+//!   drives a connection over an asynchronous transport.
 
 // Require docs for public APIs, deny unsafe code, etc.
 #![forbid(unsafe_code, unused_must_use)]
@@ -325,6 +364,9 @@ mod log {
 #[macro_use]
 mod msgs;
 mod anchors;
+mod budget;
+mod capture;
+mod cert_stream;
 mod cipher;
 mod common_state;
 mod conn;
@@ -332,11 +374,18 @@ mod conn;
 pub mod crypto;
 mod dns_name;
 mod error;
+#[cfg(feature = "dangerous_configuration")]
+mod handover;
 mod hash_hs;
+mod injection;
 mod limited_cache;
+mod ocsp;
+mod pacing;
 mod rand;
 mod record_layer;
+mod sct;
 mod stream;
+mod time_provider;
 #[cfg(feature = "tls12")]
 mod tls12;
 mod tls13;
@@ -372,15 +421,28 @@ pub mod internal {
     pub mod record_layer {
         pub use crate::record_layer::{Decrypted, RecordLayer};
     }
+    /// Direct access to a connection's state machine, for conformance and
+    /// negative testing.
+    pub mod injection {
+        pub use crate::injection::{inject_message, record_sent_messages, take_sent_messages};
+    }
 }
 
 // The public interface is:
-pub use crate::anchors::{OwnedTrustAnchor, RootCertStore};
+pub use crate::anchors::{
+    KnownIntermediates, OwnedTrustAnchor, RootCertStore, SharedRootCertStore,
+};
+pub use crate::budget::MemoryBudget;
 pub use crate::builder::{
     ConfigBuilder, ConfigSide, WantsCipherSuites, WantsKxGroups, WantsVerifier, WantsVersions,
 };
-pub use crate::common_state::{CommonState, IoState, Side};
-pub use crate::conn::{Connection, ConnectionCommon, Reader, SideData, Writer};
+pub use crate::capture::{CapturedMessage, MessageDirection, ServerReport};
+pub use crate::cert_stream::CertificateChainLimits;
+pub use crate::common_state::{
+    CommonState, DuplicateExtensionPolicy, IoState, Negotiated, RecordLimits, RenegotiationPolicy,
+    Side,
+};
+pub use crate::conn::{Connection, ConnectionCommon, ConnectionId, Reader, SideData, Writer};
 pub use crate::crypto::ring::Ticketer;
 pub use crate::crypto::ring::{SupportedKxGroup, ALL_KX_GROUPS};
 pub use crate::enums::{
@@ -394,9 +456,12 @@ pub use crate::error::{
 pub use crate::key::{Certificate, PrivateKey};
 pub use crate::key_log::{KeyLog, NoKeyLog};
 pub use crate::key_log_file::KeyLogFile;
-pub use crate::msgs::enums::NamedGroup;
+pub use crate::msgs::enums::{ExtensionType, NamedGroup};
 pub use crate::msgs::handshake::DistinguishedName;
-pub use crate::stream::{Stream, StreamOwned};
+pub use crate::ocsp::{OcspCertStatus, OcspResponse, OcspStaplePolicy};
+pub use crate::pacing::WritePacer;
+pub use crate::sct::{SctSource, SignedCertificateTimestamp};
+pub use crate::stream::{NonBlockingStream, Stream, StreamOwned};
 pub use crate::suites::{
     BulkAlgorithm, SupportedCipherSuite, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
 };
@@ -404,11 +469,13 @@ pub use crate::suites::{
 #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
 pub use crate::suites::{ConnectionTrafficSecrets, ExtractedSecrets};
 pub use crate::ticketer::TicketSwitcher;
+pub use crate::time_provider::{DefaultTimeProvider, TimeProvider};
 #[cfg(feature = "tls12")]
 pub use crate::tls12::Tls12CipherSuite;
 pub use crate::tls13::Tls13CipherSuite;
-pub use crate::verify::DigitallySignedStruct;
+pub use crate::verify::{CertificateRequirements, DigitallySignedStruct};
 pub use crate::versions::{SupportedProtocolVersion, ALL_VERSIONS, DEFAULT_VERSIONS};
+pub use crate::x509::{CertificateDetails, SubjectAltName};
 
 /// Items for use in a client.
 pub mod client {
@@ -417,6 +484,7 @@ pub mod client {
     mod common;
     pub(super) mod handy;
     mod hs;
+    mod offload;
     #[cfg(feature = "tls12")]
     mod tls12;
     mod tls13;
@@ -425,17 +493,20 @@ pub mod client {
     pub use builder::WantsClientCert;
     pub use client_conn::{
         ClientConfig, ClientConnection, ClientConnectionData, ClientSessionStore,
-        ResolvesClientCert, Resumption, ServerName, Tls12Resumption, WriteEarlyData,
+        ObservesClientResumption, ResolvesClientCert, Resumption, ResumptionNotAttempted,
+        ResumptionRejected, ServerName, StoredSessions, Tls12Resumption, WriteEarlyData,
     };
-    pub use handy::ClientSessionMemoryCache;
+    pub use handy::{ClientSessionEviction, ClientSessionMemoryCache};
+    pub use offload::{CompletedVerification, PendingVerification};
 
     #[cfg(feature = "dangerous_configuration")]
     pub use crate::verify::{
-        verify_server_cert_signed_by_trust_anchor, verify_server_name, HandshakeSignatureValid,
-        ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
+        verify_server_cert_signed_by_trust_anchor, verify_server_name, FetchesIntermediates,
+        HandshakeSignatureValid, ServerCertPolicy, ServerCertVerified, ServerCertVerifier,
+        ServerNamePolicy, WebPkiVerifier, WebPkiVerifierBuilder,
     };
     #[cfg(feature = "dangerous_configuration")]
-    pub use client_conn::danger::DangerousClientConfig;
+    pub use client_conn::danger::{DangerousClientConfig, Unverified};
 
     pub use crate::msgs::persist::Tls12ClientSessionValue;
     pub use crate::msgs::persist::Tls13ClientSessionValue;
@@ -460,12 +531,21 @@ pub mod server {
     };
     pub use builder::WantsServerCert;
     pub use handy::ResolvesServerCertUsingSni;
-    pub use handy::{NoServerSessionStorage, ServerSessionMemoryCache};
-    pub use server_conn::StoresServerSessions;
+    pub use handy::ServerNameAllowList;
+    pub use handy::UsedTicketMemoryCache;
+    pub use handy::{HelloRetryCounter, NoServerSessionStorage, ServerSessionMemoryCache};
+    pub use server_conn::AcceptsServerName;
     pub use server_conn::{
-        Accepted, Acceptor, ReadEarlyData, ServerConfig, ServerConnection, ServerConnectionData,
+        Accepted, Acceptor, NegotiationPreview, ReadEarlyData, ServerConfig, ServerConnection,
+        ServerConnectionData,
     };
+    pub use server_conn::{CertSelection, CertSelectionReason};
     pub use server_conn::{ClientHello, ProducesTickets, ResolvesServerCert};
+    pub use server_conn::{EarlyDataClass, GatesEarlyData};
+    pub use server_conn::{HelloRetryPolicy, LimitsHelloRetries};
+    pub use server_conn::{ObservesServerResumption, ProducesResumptionData, TicketRejected};
+    pub use server_conn::{ResumingSession, ValidatesResumption};
+    pub use server_conn::{StoresServerSessions, TracksUsedTickets};
 
     #[cfg(feature = "dangerous_configuration")]
     pub use crate::dns_name::DnsName;
@@ -473,6 +553,8 @@ pub mod server {
     pub use crate::key::ParsedCertificate;
     #[cfg(feature = "dangerous_configuration")]
     pub use crate::verify::{ClientCertVerified, ClientCertVerifier};
+    #[cfg(feature = "dangerous_configuration")]
+    pub use server_conn::ResolvesClientCertVerifier;
 }
 
 pub use server::{ServerConfig, ServerConnection};
@@ -519,5 +601,9 @@ pub mod sign;
 /// APIs for implementing QUIC TLS
 pub mod quic;
 
+#[cfg(any(feature = "futures_io", feature = "tokio"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "futures_io", feature = "tokio"))))]
+pub mod async_io;
+
 /// This is the rustls manual.
 pub mod manual;
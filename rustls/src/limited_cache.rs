@@ -94,6 +94,31 @@ where
         self.map.get_mut(k)
     }
 
+    /// Make `k` the newest item, so that it is evicted last.
+    ///
+    /// This does nothing if `k` is not present.
+    pub(crate) fn touch<Q: ?Sized>(&mut self, k: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        // O(N) search, followed by O(N) removal
+        if let Some(index) = self
+            .oldest
+            .iter()
+            .position(|item| item.borrow() == k)
+        {
+            if let Some(key) = self.oldest.remove(index) {
+                self.oldest.push_back(key);
+            }
+        }
+    }
+
+    /// The keys, oldest first.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &K> {
+        self.oldest.iter()
+    }
+
     pub(crate) fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
     where
         K: Borrow<Q>,
@@ -139,6 +164,21 @@ mod test {
         assert_eq!(t.get("ghi"), Some(&3));
     }
 
+    #[test]
+    fn test_touched_item_is_evicted_last() {
+        let mut t = Test::new(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+
+        t.touch("abc");
+        t.touch("xyz");
+        t.insert("ghi".into(), 3);
+
+        assert_eq!(t.get("abc"), Some(&1));
+        assert_eq!(t.get("def"), None);
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
+
     #[test]
     fn test_evicts_second_oldest_item_if_first_removed() {
         let mut t = Test::new(3);
@@ -118,8 +118,10 @@ rustls naturally does not support SSLv2, but most importantly does not support R
 is an attack against CBC mode ciphersuites in SSLv3.  This was possible in most cases because some clients willingly
 downgraded to SSLv3 after failed handshakes for later versions.
 
-rustls does not support CBC mode ciphersuites, or SSLv3.  Note that rustls does not need to implement `TLS_FALLBACK_SCSV`
-introduced as a countermeasure because it contains no ability to downgrade to earlier protocol versions.
+rustls does not support CBC mode ciphersuites, or SSLv3.  rustls itself never downgrades to earlier protocol versions
+after a failed handshake, but applications that do so can mark their retries with `TLS_FALLBACK_SCSV`
+([RFC7507](https://tools.ietf.org/html/rfc7507)) by setting `ClientConfig::send_fallback_scsv`.  rustls servers
+reject such a retry with an `inappropriate_fallback` alert if it could have negotiated a higher version.
 
 ## GCM nonces
 
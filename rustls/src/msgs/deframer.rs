@@ -297,9 +297,14 @@ impl MessageDeframer {
         // larger buffer size. Once the large message and any following handshake messages in
         // the same flight have been consumed, `pop()` will call `discard()` to reset `used`.
         // At this point, the buffer resizing logic below should reduce the buffer size.
-        let allow_max = match self.joining_hs {
-            Some(_) => MAX_HANDSHAKE_SIZE as usize,
-            None => OpaqueMessage::MAX_WIRE_SIZE,
+        //
+        // A large plaintext handshake message (such as a `ClientHello` carrying post-quantum
+        // key shares) may also arrive spread over several records before `pop()` has had a
+        // chance to start joining it, so the larger limit applies as soon as the buffer
+        // starts with a handshake record.
+        let allow_max = match self.joining_hs.is_some() || self.starts_with_handshake_record() {
+            true => MAX_HANDSHAKE_SIZE as usize,
+            false => OpaqueMessage::MAX_WIRE_SIZE,
         };
 
         if self.used >= allow_max {
@@ -322,6 +327,33 @@ impl MessageDeframer {
         Ok(())
     }
 
+    /// The size of the buffer, in bytes.
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Release the buffer if nothing is buffered in it.
+    pub fn shrink(&mut self) {
+        if self.used == 0 {
+            self.buf = Vec::new();
+        }
+    }
+
+    /// Returns true if the first buffered record is a (plaintext) handshake record.
+    fn starts_with_handshake_record(&self) -> bool {
+        self.used > 0 && ContentType::from(self.buf[0]) == ContentType::Handshake
+    }
+
+    /// The start of the handshake message being joined, including its
+    /// header, if it has not all arrived yet.
+    pub fn partial_handshake(&self) -> Option<&[u8]> {
+        let meta = self.joining_hs.as_ref()?;
+        match meta.expected_len {
+            Some(len) if len <= meta.payload.len() => None,
+            _ => Some(&self.buf[meta.payload.clone()]),
+        }
+    }
+
     /// Returns true if we have messages for the caller
     /// to process, either whole messages in our output
     /// queue or partial messages in our buffer.
@@ -329,6 +361,16 @@ impl MessageDeframer {
         self.used > 0
     }
 
+    /// The buffered bytes that are yet to be deframed, or `None` if some
+    /// have been decrypted already, as part of a handshake message.
+    #[cfg(feature = "dangerous_configuration")]
+    pub(crate) fn unprocessed(&self) -> Option<&[u8]> {
+        match self.joining_hs {
+            Some(_) => None,
+            None => Some(&self.buf[..self.used]),
+        }
+    }
+
     /// Discard `taken` bytes from the start of our buffer.
     fn discard(&mut self, taken: usize) {
         #[allow(clippy::comparison_chain)]
@@ -424,7 +466,7 @@ const HEADER_SIZE: usize = 1 + 3;
 /// TLS allows for handshake messages of up to 16MB.  We
 /// restrict that to 64KB to limit potential for denial-of-
 /// service.
-const MAX_HANDSHAKE_SIZE: u32 = 0xffff;
+pub(crate) const MAX_HANDSHAKE_SIZE: u32 = 0xffff;
 
 const READ_SIZE: usize = 4096;
 
@@ -732,4 +774,68 @@ mod tests {
         );
         assert!(input_bytes(&mut d, &message).is_err());
     }
+
+    /// Encode a handshake message with a `len`-byte body, split into plaintext
+    /// records of at most `fragment` bytes each.
+    fn fragmented_handshake(len: u32, fragment: usize) -> Vec<u8> {
+        let mut payload = vec![0x01]; // ClientHello
+        payload.extend(&len.to_be_bytes()[1..]);
+        payload.resize(payload.len() + len as usize, 0xaa);
+
+        let mut records = Vec::new();
+        for chunk in payload.chunks(fragment) {
+            records.push(0x16); // Handshake
+            records.extend(&[0x03, 0x01]); // ProtocolVersion
+            records.extend((chunk.len() as u16).to_be_bytes());
+            records.extend(chunk);
+        }
+        records
+    }
+
+    #[test]
+    fn test_handshake_spanning_records_read_before_pop() {
+        const LEN: u32 = 40_000;
+        let records = fragmented_handshake(LEN, 16_384);
+        assert!(records.len() > OpaqueMessage::MAX_WIRE_SIZE);
+
+        let mut d = MessageDeframer::default();
+        let mut rd = io::Cursor::new(&records);
+        while (rd.position() as usize) < records.len() {
+            d.read(&mut rd).unwrap();
+        }
+
+        let mut rl = RecordLayer::new();
+        let m = d.pop(&mut rl).unwrap().unwrap().message;
+        assert_eq!(m.typ, ContentType::Handshake);
+        assert_eq!(m.payload.0.len(), 4 + LEN as usize);
+        assert!(!d.has_pending());
+    }
+
+    #[test]
+    fn test_handshake_spanning_small_records() {
+        const LEN: u32 = 1_000;
+        let records = fragmented_handshake(LEN, 7);
+
+        let mut d = MessageDeframer::default();
+        assert_len(records.len(), input_bytes(&mut d, &records));
+
+        let mut rl = RecordLayer::new();
+        let m = d.pop(&mut rl).unwrap().unwrap().message;
+        assert_eq!(m.payload.0.len(), 4 + LEN as usize);
+        assert!(!d.has_pending());
+    }
+
+    #[test]
+    fn test_oversized_handshake_errors() {
+        let records = fragmented_handshake(super::MAX_HANDSHAKE_SIZE + 1, 1_000);
+
+        let mut d = MessageDeframer::default();
+        assert_len(4096, input_bytes(&mut d, &records));
+
+        let mut rl = RecordLayer::new();
+        assert_eq!(
+            d.pop(&mut rl).unwrap_err(),
+            Error::InvalidMessage(InvalidMessage::HandshakePayloadTooLarge)
+        );
+    }
 }
@@ -519,6 +519,13 @@ impl CertificateStatusRequest {
 
 // ---
 
+/// A `SignedCertificateTimestampList`, from RFC6962: each SCT is opaque.
+pub type SctList = Vec<PayloadU16>;
+
+impl TlsListElement for PayloadU16 {
+    const SIZE_LEN: ListLength = ListLength::U16;
+}
+
 impl TlsListElement for PSKKeyExchangeMode {
     const SIZE_LEN: ListLength = ListLength::U8;
 }
@@ -546,9 +553,11 @@ pub enum ClientExtension {
     Cookie(PayloadU16),
     ExtendedMasterSecretRequest,
     CertificateStatusRequest(CertificateStatusRequest),
+    SignedCertificateTimestampRequest,
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    AuthorityNames(Vec<DistinguishedName>),
     Unknown(UnknownExtension),
 }
 
@@ -568,9 +577,11 @@ impl ClientExtension {
             Self::Cookie(_) => ExtensionType::Cookie,
             Self::ExtendedMasterSecretRequest => ExtensionType::ExtendedMasterSecret,
             Self::CertificateStatusRequest(_) => ExtensionType::StatusRequest,
+            Self::SignedCertificateTimestampRequest => ExtensionType::SCT,
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             Self::EarlyData => ExtensionType::EarlyData,
+            Self::AuthorityNames(_) => ExtensionType::CertificateAuthorities,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -588,6 +599,7 @@ impl Codec for ClientExtension {
             Self::ServerName(ref r) => r.encode(&mut sub),
             Self::SessionTicket(ClientSessionTicket::Request)
             | Self::ExtendedMasterSecretRequest
+            | Self::SignedCertificateTimestampRequest
             | Self::EarlyData => {}
             Self::SessionTicket(ClientSessionTicket::Offer(ref r)) => r.encode(&mut sub),
             Self::Protocols(ref r) => r.encode(&mut sub),
@@ -600,6 +612,7 @@ impl Codec for ClientExtension {
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r);
             }
+            Self::AuthorityNames(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -638,11 +651,13 @@ impl Codec for ClientExtension {
                 let csr = CertificateStatusRequest::read(&mut sub)?;
                 Self::CertificateStatusRequest(csr)
             }
+            ExtensionType::SCT if !sub.any_left() => Self::SignedCertificateTimestampRequest,
             ExtensionType::TransportParameters => Self::TransportParameters(sub.rest().to_vec()),
             ExtensionType::TransportParametersDraft => {
                 Self::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData if !sub.any_left() => Self::EarlyData,
+            ExtensionType::CertificateAuthorities => Self::AuthorityNames(Vec::read(&mut sub)?),
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -676,6 +691,14 @@ impl ClientExtension {
 
         Self::ServerName(vec![name])
     }
+
+    /// Make a padding extension (RFC 7685) with `len` bytes of content.
+    pub fn make_padding(len: usize) -> Self {
+        Self::Unknown(UnknownExtension {
+            typ: ExtensionType::Padding,
+            payload: Payload::new(vec![0; len]),
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -695,6 +718,7 @@ pub enum ServerExtension {
     PresharedKey(u16),
     ExtendedMasterSecretAck,
     CertificateStatusAck,
+    SignedCertificateTimestamp(SctList),
     SupportedVersions(ProtocolVersion),
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
@@ -714,6 +738,7 @@ impl ServerExtension {
             Self::PresharedKey(_) => ExtensionType::PreSharedKey,
             Self::ExtendedMasterSecretAck => ExtensionType::ExtendedMasterSecret,
             Self::CertificateStatusAck => ExtensionType::StatusRequest,
+            Self::SignedCertificateTimestamp(_) => ExtensionType::SCT,
             Self::SupportedVersions(_) => ExtensionType::SupportedVersions,
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
@@ -739,6 +764,7 @@ impl Codec for ServerExtension {
             Self::Protocols(ref r) => r.encode(&mut sub),
             Self::KeyShare(ref r) => r.encode(&mut sub),
             Self::PresharedKey(r) => r.encode(&mut sub),
+            Self::SignedCertificateTimestamp(ref r) => r.encode(&mut sub),
             Self::SupportedVersions(ref r) => r.encode(&mut sub),
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r);
@@ -765,6 +791,7 @@ impl Codec for ServerExtension {
             ExtensionType::KeyShare => Self::KeyShare(KeyShareEntry::read(&mut sub)?),
             ExtensionType::PreSharedKey => Self::PresharedKey(u16::read(&mut sub)?),
             ExtensionType::ExtendedMasterSecret => Self::ExtendedMasterSecretAck,
+            ExtensionType::SCT => Self::SignedCertificateTimestamp(Vec::read(&mut sub)?),
             ExtensionType::SupportedVersions => {
                 Self::SupportedVersions(ProtocolVersion::read(&mut sub)?)
             }
@@ -786,6 +813,13 @@ impl ServerExtension {
         Self::Protocols(Vec::from_slices(proto))
     }
 
+    /// Returns `None` if `sct_list` is not a valid `SignedCertificateTimestampList`.
+    pub fn make_sct(sct_list: &[u8]) -> Option<Self> {
+        SctList::read_bytes(sct_list)
+            .ok()
+            .map(Self::SignedCertificateTimestamp)
+    }
+
     pub fn make_empty_renegotiation_info() -> Self {
         let empty = Vec::new();
         Self::RenegotiationInfo(PayloadU8::new(empty))
@@ -982,6 +1016,14 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_cookie(&self) -> Option<&PayloadU16> {
+        let ext = self.find_extension(ExtensionType::Cookie)?;
+        match *ext {
+            ClientExtension::Cookie(ref ck) => Some(ck),
+            _ => None,
+        }
+    }
+
     pub fn psk_mode_offered(&self, mode: PSKKeyExchangeMode) -> bool {
         self.get_psk_modes()
             .map(|modes| modes.contains(&mode))
@@ -995,15 +1037,47 @@ impl ClientHelloPayload {
         }
     }
 
+    /// Lengthen the encoding of this hello by `len` bytes using a padding
+    /// extension.
+    ///
+    /// An extension has a four byte header, so if `len` is less than four
+    /// the encoding grows by four bytes instead.  The padding goes before
+    /// any PSK extension, which must remain last.
+    pub fn add_padding(&mut self, len: usize) {
+        let padding = ClientExtension::make_padding(len.saturating_sub(4));
+        let position = match self.extensions.last() {
+            Some(ClientExtension::PresharedKey(_)) => self.extensions.len() - 1,
+            _ => self.extensions.len(),
+        };
+        self.extensions
+            .insert(position, padding);
+    }
+
     pub fn ems_support_offered(&self) -> bool {
         self.find_extension(ExtensionType::ExtendedMasterSecret)
             .is_some()
     }
 
+    pub fn secure_renegotiation_offered(&self) -> bool {
+        self.find_extension(ExtensionType::RenegotiationInfo)
+            .is_some()
+            || self
+                .cipher_suites
+                .contains(&CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV)
+    }
+
     pub fn early_data_extension_offered(&self) -> bool {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    pub fn get_authorities_extension(&self) -> Option<&[DistinguishedName]> {
+        let ext = self.find_extension(ExtensionType::CertificateAuthorities)?;
+        match *ext {
+            ClientExtension::AuthorityNames(ref names) => Some(names),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1243,6 +1317,11 @@ impl ServerHelloPayload {
             .is_some()
     }
 
+    pub fn secure_renegotiation_acked(&self) -> bool {
+        self.find_extension(ExtensionType::RenegotiationInfo)
+            .is_some()
+    }
+
     pub fn get_supported_versions(&self) -> Option<ProtocolVersion> {
         let ext = self.find_extension(ExtensionType::SupportedVersions)?;
         match *ext {
@@ -1265,6 +1344,7 @@ impl TlsListElement for key::Certificate {
 #[derive(Debug)]
 pub enum CertificateExtension {
     CertificateStatus(CertificateStatus),
+    SignedCertificateTimestamp(SctList),
     Unknown(UnknownExtension),
 }
 
@@ -1272,16 +1352,31 @@ impl CertificateExtension {
     pub fn get_type(&self) -> ExtensionType {
         match *self {
             Self::CertificateStatus(_) => ExtensionType::StatusRequest,
+            Self::SignedCertificateTimestamp(_) => ExtensionType::SCT,
             Self::Unknown(ref r) => r.typ,
         }
     }
 
+    /// Returns `None` if `sct_list` is not a valid `SignedCertificateTimestampList`.
+    pub fn make_sct(sct_list: &[u8]) -> Option<Self> {
+        SctList::read_bytes(sct_list)
+            .ok()
+            .map(Self::SignedCertificateTimestamp)
+    }
+
     pub fn get_cert_status(&self) -> Option<&Vec<u8>> {
         match *self {
             Self::CertificateStatus(ref cs) => Some(&cs.ocsp_response.0),
             _ => None,
         }
     }
+
+    pub fn get_sct_list(&self) -> Option<&SctList> {
+        match *self {
+            Self::SignedCertificateTimestamp(ref sctl) => Some(sctl),
+            _ => None,
+        }
+    }
 }
 
 impl Codec for CertificateExtension {
@@ -1291,6 +1386,7 @@ impl Codec for CertificateExtension {
         let mut sub: Vec<u8> = Vec::new();
         match *self {
             Self::CertificateStatus(ref r) => r.encode(&mut sub),
+            Self::SignedCertificateTimestamp(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1308,6 +1404,7 @@ impl Codec for CertificateExtension {
                 let st = CertificateStatus::read(&mut sub)?;
                 Self::CertificateStatus(st)
             }
+            ExtensionType::SCT => Self::SignedCertificateTimestamp(Vec::read(&mut sub)?),
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -1364,9 +1461,9 @@ impl CertificateEntry {
     }
 
     pub fn has_unknown_extension(&self) -> bool {
-        self.exts
-            .iter()
-            .any(|ext| ext.get_type() != ExtensionType::StatusRequest)
+        self.exts.iter().any(|ext| {
+            ext.get_type() != ExtensionType::StatusRequest && ext.get_type() != ExtensionType::SCT
+        })
     }
 
     pub fn get_ocsp_response(&self) -> Option<&Vec<u8>> {
@@ -1375,6 +1472,13 @@ impl CertificateEntry {
             .find(|ext| ext.get_type() == ExtensionType::StatusRequest)
             .and_then(CertificateExtension::get_cert_status)
     }
+
+    pub fn get_scts(&self) -> Option<&SctList> {
+        self.exts
+            .iter()
+            .find(|ext| ext.get_type() == ExtensionType::SCT)
+            .and_then(CertificateExtension::get_sct_list)
+    }
 }
 
 impl TlsListElement for CertificateEntry {
@@ -1409,16 +1513,6 @@ impl CertificatePayloadTLS13 {
         }
     }
 
-    pub fn any_entry_has_duplicate_extension(&self) -> bool {
-        for entry in &self.entries {
-            if entry.has_duplicate_extension() {
-                return true;
-            }
-        }
-
-        false
-    }
-
     pub fn any_entry_has_unknown_extension(&self) -> bool {
         for entry in &self.entries {
             if entry.has_unknown_extension() {
@@ -1447,6 +1541,13 @@ impl CertificatePayloadTLS13 {
             .unwrap_or_default()
     }
 
+    pub fn get_end_entity_scts(&self) -> Option<SctList> {
+        self.entries
+            .first()
+            .and_then(CertificateEntry::get_scts)
+            .cloned()
+    }
+
     pub fn convert(&self) -> CertificatePayload {
         let mut ret = Vec::new();
         for entry in &self.entries {
@@ -1647,6 +1748,14 @@ pub trait HasServerExtensions {
         }
     }
 
+    fn get_sct_list(&self) -> Option<&SctList> {
+        let ext = self.find_extension(ExtensionType::SCT)?;
+        match *ext {
+            ServerExtension::SignedCertificateTimestamp(ref sctl) => Some(sctl),
+            _ => None,
+        }
+    }
+
     fn get_quic_params_extension(&self) -> Option<Vec<u8>> {
         let ext = self
             .find_extension(ExtensionType::TransportParameters)
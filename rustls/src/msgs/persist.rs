@@ -4,6 +4,7 @@ use crate::error::InvalidMessage;
 use crate::key;
 use crate::msgs::base::{PayloadU16, PayloadU8};
 use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::enums::NamedGroup;
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::SessionId;
 use crate::ticketer::TimeBase;
@@ -56,6 +57,12 @@ impl<T: core::ops::Deref<Target = ClientSessionCommon>> Retrieved<T> {
                 .saturating_add(u64::from(common.lifetime_secs))
                 < self.retrieved_at.as_secs()
     }
+
+    pub fn age_secs(&self) -> u64 {
+        self.retrieved_at
+            .as_secs()
+            .saturating_sub(self.value.epoch)
+    }
 }
 
 impl<T> core::ops::Deref for Retrieved<T> {
@@ -210,6 +217,7 @@ pub struct ClientSessionCommon {
     epoch: u64,
     lifetime_secs: u32,
     server_cert_chain: CertificatePayload,
+    alpn_protocol: Option<PayloadU8>,
 }
 
 impl ClientSessionCommon {
@@ -226,9 +234,21 @@ impl ClientSessionCommon {
             epoch: time_now.as_secs(),
             lifetime_secs: cmp::min(lifetime_secs, MAX_TICKET_LIFETIME),
             server_cert_chain,
+            alpn_protocol: None,
         }
     }
 
+    pub(crate) fn set_alpn_protocol(&mut self, alpn_protocol: Option<Vec<u8>>) {
+        self.alpn_protocol = alpn_protocol.map(PayloadU8::new);
+    }
+
+    /// The ALPN protocol negotiated by the connection that created the session.
+    pub(crate) fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol
+            .as_ref()
+            .map(|proto| proto.0.as_ref())
+    }
+
     pub(crate) fn server_cert_chain(&self) -> &[key::Certificate] {
         self.server_cert_chain.as_ref()
     }
@@ -402,6 +422,55 @@ impl ServerSessionValue {
     }
 }
 
+/// Server state carried across a stateless `HelloRetryRequest`, inside its cookie.
+#[derive(Debug)]
+pub struct HelloRetryCookie {
+    pub sni: Option<DnsName>,
+    pub cipher_suite: CipherSuite,
+    pub group: NamedGroup,
+    /// Hash of the first `ClientHello`, with the suite's hash function.
+    pub client_hello_hash: PayloadU8,
+    /// When the cookie was issued, in seconds since the UNIX epoch.
+    pub issued_at: u64,
+}
+
+impl Codec for HelloRetryCookie {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        if let Some(ref sni) = self.sni {
+            1u8.encode(bytes);
+            let sni_bytes: &str = sni.as_ref();
+            PayloadU8::new(Vec::from(sni_bytes)).encode(bytes);
+        } else {
+            0u8.encode(bytes);
+        }
+        self.cipher_suite.encode(bytes);
+        self.group.encode(bytes);
+        self.client_hello_hash.encode(bytes);
+        self.issued_at.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self, InvalidMessage> {
+        let sni = match u8::read(r)? {
+            1 => {
+                let dns_name = PayloadU8::read(r)?;
+                match DnsName::try_from_ascii(&dns_name.0) {
+                    Ok(dns_name) => Some(dns_name),
+                    Err(_) => return Err(InvalidMessage::InvalidServerName),
+                }
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            sni,
+            cipher_suite: CipherSuite::read(r)?,
+            group: NamedGroup::read(r)?,
+            client_hello_hash: PayloadU8::read(r)?,
+            issued_at: u64::read(r)?,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -446,4 +515,22 @@ mod tests {
         let ssv = ServerSessionValue::read(&mut rd).unwrap();
         assert_eq!(ssv.get_encoding(), bytes);
     }
+
+    #[test]
+    fn helloretrycookie_roundtrips() {
+        for sni in [None, Some(DnsName::try_from_ascii(b"example.com").unwrap())] {
+            let cookie = HelloRetryCookie {
+                sni,
+                cipher_suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
+                group: NamedGroup::X25519,
+                client_hello_hash: PayloadU8::new(vec![0xab; 32]),
+                issued_at: 1_700_000_000,
+            };
+            let bytes = cookie.get_encoding();
+            let decoded = HelloRetryCookie::read_bytes(&bytes).unwrap();
+            assert_eq!(decoded.get_encoding(), bytes);
+            assert_eq!(decoded.sni, cookie.sni);
+            assert_eq!(decoded.issued_at, cookie.issued_at);
+        }
+    }
 }
@@ -0,0 +1,430 @@
+use crate::anchors::RootCertStore;
+use crate::crypto::hash::{Hash, HashAlgorithm};
+use crate::error::{CertificateError, Error};
+use crate::key::Certificate;
+use crate::x509::{
+    self, context, generalized_time, CertificateDetails, Der, BIT_STRING, ENUMERATED,
+    GENERALIZED_TIME, ID_SHA256, INTEGER, OCTET_STRING, OID, SEQUENCE,
+};
+
+use core::time::Duration;
+use std::time::SystemTime;
+
+/// The status and validity window of a DER-encoded OCSP response ([RFC 6960]).
+///
+/// This is meant for monitoring, for example recording how fresh the
+/// responses stapled by servers are.  Parsing a response does **not**
+/// check its signature, nor that it refers to any particular certificate:
+/// that is the job of the `ServerCertVerifier`.
+///
+/// Only the first `SingleResponse` is described.  Stapled responses
+/// normally contain exactly one, for the server's end-entity certificate.
+///
+/// [RFC 6960]: https://www.rfc-editor.org/rfc/rfc6960
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OcspResponse {
+    status: OcspCertStatus,
+    produced_at: SystemTime,
+    this_update: SystemTime,
+    next_update: Option<SystemTime>,
+}
+
+impl OcspResponse {
+    /// Parse a DER-encoded `OCSPResponse`.
+    ///
+    /// An error is returned if `der` is malformed, or its `responseStatus`
+    /// is not `successful`.
+    pub fn parse(der: &[u8]) -> Result<Self, Error> {
+        parse_ocsp_response(der)
+            .map(|basic| basic.response)
+            .ok_or_else(|| Error::General("invalid OCSP response".into()))
+    }
+
+    /// The status of the certificate.
+    pub fn status(&self) -> OcspCertStatus {
+        self.status
+    }
+
+    /// When the responder signed the response.
+    pub fn produced_at(&self) -> SystemTime {
+        self.produced_at
+    }
+
+    /// The time at which the status was known to be correct.
+    pub fn this_update(&self) -> SystemTime {
+        self.this_update
+    }
+
+    /// The time at or before which newer information will be available,
+    /// if the responder said.
+    pub fn next_update(&self) -> Option<SystemTime> {
+        self.next_update
+    }
+}
+
+/// A certificate's status, as reported by an [`OcspResponse`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OcspCertStatus {
+    /// The certificate is not revoked.
+    Good,
+    /// The certificate was revoked at the given time.
+    Revoked(SystemTime),
+    /// The responder doesn't know about the certificate.
+    Unknown,
+}
+
+/// What a client requires of the OCSP response stapled by a server.
+///
+/// Set this as [`ClientConfig::require_ocsp_staple`] to reject servers
+/// that do not staple a response, whether or not their certificate has the
+/// "must staple" extension.  The response must:
+///
+/// - be a successful, basic OCSP response for the end-entity certificate;
+/// - be signed directly by the certificate's issuer.  This is found in the
+///   chain the server sent, the config's known intermediates, or the trust
+///   anchors of the config's `ServerCertVerifier` (which the default
+///   verifier reports from its `trust_anchors` method).  Responses signed
+///   by a delegated responder are not accepted;
+/// - say the certificate is good;
+/// - be fresh: its `thisUpdate` must not be in the future and its
+///   `nextUpdate`, if any, must not be in the past, each give or take
+///   `allowed_skew`.
+///
+/// This is checked after, and in addition to, the config's
+/// `ServerCertVerifier`, for TLS1.2 and TLS1.3 alike.  Resumed sessions
+/// are not checked, since no certificate is sent.
+///
+/// [`ClientConfig::require_ocsp_staple`]: crate::ClientConfig::require_ocsp_staple
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OcspStaplePolicy {
+    /// How long after its `thisUpdate` a response is accepted, even if its
+    /// `nextUpdate` is later.  `None` (the default) relies on `nextUpdate`
+    /// alone.
+    pub max_age: Option<Duration>,
+
+    /// How far the client's and responder's clocks may disagree.  The
+    /// default is five minutes.
+    pub allowed_skew: Duration,
+}
+
+impl Default for OcspStaplePolicy {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            allowed_skew: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl OcspStaplePolicy {
+    /// Check the OCSP response `der` stapled by a server, at `now`, for
+    /// the already-verified chain of `end_entity` and `intermediates`.
+    ///
+    /// The issuer of `end_entity` is looked for among `intermediates`,
+    /// then `trust_anchors`.  `hash` is the crypto provider's source of
+    /// hash functions, for the response's `CertID`.
+    pub(crate) fn check(
+        &self,
+        der: &[u8],
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        trust_anchors: Option<&RootCertStore>,
+        hash: fn(HashAlgorithm) -> Option<&'static dyn Hash>,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        if der.is_empty() {
+            return Err(CertificateError::MissingOcspResponse.into());
+        }
+        let basic = parse_ocsp_response(der).ok_or(CertificateError::InvalidOcspResponse)?;
+        let end_entity = end_entity.details()?;
+
+        // The responder must be the issuer, so the issuer's key is needed
+        // to check both the `CertID` and the signature.  A matching name is
+        // not enough: anyone can make a certificate with that name.
+        let issuer_name = end_entity.issuer_der();
+        let from_chain = intermediates
+            .iter()
+            .filter_map(|cert| cert.details().ok())
+            .filter(|details| details.subject_der() == issuer_name)
+            .map(|details| {
+                details
+                    .subject_public_key_info()
+                    .to_vec()
+            });
+        let from_anchors = trust_anchors
+            .iter()
+            .flat_map(|store| &store.roots)
+            .filter(|anchor| anchor.subject().as_ref() == issuer_name)
+            .map(|anchor| anchor.subject_public_key_info());
+        let issuer_spki = from_chain
+            .chain(from_anchors)
+            .find(|spki| end_entity.is_signed_by(spki))
+            .ok_or(CertificateError::InvalidOcspResponse)?;
+        if !basic
+            .cert_id
+            .matches(&end_entity, &issuer_spki, hash)
+        {
+            return Err(CertificateError::InvalidOcspResponse.into());
+        }
+
+        let scheme = x509::signature_scheme(basic.signature_algorithm, basic.signature_parameters)
+            .ok_or(CertificateError::InvalidOcspResponse)?;
+        if !x509::verify_signature(
+            &issuer_spki,
+            scheme,
+            basic.tbs_response_data,
+            basic.signature,
+        ) {
+            return Err(CertificateError::InvalidOcspResponse.into());
+        }
+
+        match basic.response.status {
+            OcspCertStatus::Good => {}
+            OcspCertStatus::Revoked(_) => return Err(CertificateError::Revoked.into()),
+            OcspCertStatus::Unknown => return Err(CertificateError::InvalidOcspResponse.into()),
+        }
+
+        // Each bound is a time plus some allowance, which may be too far in
+        // the future to represent: such a bound is never passed.
+        let passed = |bound: Option<SystemTime>| bound.map_or(false, |bound| bound < now);
+        let this_update = basic.response.this_update;
+        let not_yet_valid = now
+            .checked_add(self.allowed_skew)
+            .map_or(false, |latest| this_update > latest);
+        let expired = basic
+            .response
+            .next_update
+            .map_or(false, |next_update| {
+                passed(next_update.checked_add(self.allowed_skew))
+            });
+        let too_old = self.max_age.map_or(false, |max_age| {
+            passed(
+                this_update
+                    .checked_add(max_age)
+                    .and_then(|time| time.checked_add(self.allowed_skew)),
+            )
+        });
+        match not_yet_valid || expired || too_old {
+            true => Err(CertificateError::StaleOcspResponse.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// The parts of a `BasicOCSPResponse` needed to check it.
+struct BasicResponse<'a> {
+    response: OcspResponse,
+    cert_id: CertId<'a>,
+    /// The whole DER encoding of `tbsResponseData`, which is what is signed.
+    tbs_response_data: &'a [u8],
+    signature_algorithm: &'a [u8],
+    signature_parameters: &'a [u8],
+    signature: &'a [u8],
+}
+
+/// Identifies the certificate a `SingleResponse` is about.
+struct CertId<'a> {
+    hash_algorithm: &'a [u8],
+    issuer_name_hash: &'a [u8],
+    issuer_key_hash: &'a [u8],
+    serial_number: &'a [u8],
+}
+
+impl CertId<'_> {
+    /// Whether this identifies `cert`, issued by the holder of the
+    /// DER-encoded `SubjectPublicKeyInfo` `issuer_spki`, using hash
+    /// functions from `hash`.
+    fn matches(
+        &self,
+        cert: &CertificateDetails<'_>,
+        issuer_spki: &[u8],
+        hash: fn(HashAlgorithm) -> Option<&'static dyn Hash>,
+    ) -> bool {
+        let algorithm = match self.hash_algorithm {
+            ID_SHA1 => HashAlgorithm::SHA1,
+            ID_SHA256 => HashAlgorithm::SHA256,
+            _ => return false,
+        };
+        let (hash, issuer_key) = match (hash(algorithm), x509::subject_public_key(issuer_spki)) {
+            (Some(hash), Some(key)) => (hash, key),
+            _ => return false,
+        };
+
+        self.serial_number == cert.serial_number()
+            && self.issuer_name_hash == hash.hash(cert.issuer_der()).as_ref()
+            && self.issuer_key_hash == hash.hash(issuer_key).as_ref()
+    }
+}
+
+fn parse_ocsp_response(der: &[u8]) -> Option<BasicResponse<'_>> {
+    // OCSPResponse ::= SEQUENCE {
+    //    responseStatus         OCSPResponseStatus,
+    //    responseBytes          [0] EXPLICIT ResponseBytes OPTIONAL }
+    let mut ocsp_response = Der::new(der).nested(SEQUENCE)?;
+    if ocsp_response.read(ENUMERATED)? != [SUCCESSFUL] {
+        return None;
+    }
+
+    // ResponseBytes ::= SEQUENCE {
+    //    responseType   OBJECT IDENTIFIER,
+    //    response       OCTET STRING }
+    let mut response_bytes = ocsp_response
+        .nested(context(0))?
+        .nested(SEQUENCE)?;
+    if response_bytes.read(OID)? != ID_PKIX_OCSP_BASIC {
+        return None;
+    }
+
+    // BasicOCSPResponse ::= SEQUENCE {
+    //    tbsResponseData      ResponseData,
+    //    signatureAlgorithm   AlgorithmIdentifier,
+    //    signature            BIT STRING,
+    //    ... }
+    let mut basic_response = Der::new(response_bytes.read(OCTET_STRING)?).nested(SEQUENCE)?;
+    let tbs_response_data = basic_response.read_element(SEQUENCE)?;
+    let mut signature_algorithm = basic_response.nested(SEQUENCE)?;
+    let signature = match basic_response
+        .read(BIT_STRING)?
+        .split_first()
+    {
+        Some((0, signature)) => signature,
+        _ => return None,
+    };
+    let mut response_data = Der::new(tbs_response_data).nested(SEQUENCE)?;
+
+    // ResponseData ::= SEQUENCE {
+    //    version              [0] EXPLICIT Version DEFAULT v1,
+    //    responderID              ResponderID,
+    //    producedAt               GeneralizedTime,
+    //    responses                SEQUENCE OF SingleResponse,
+    //    ... }
+    if response_data.peek() == Some(context(0)) {
+        response_data.read(context(0))?;
+    }
+    response_data.read_any()?;
+    let produced_at = generalized_time(response_data.read(GENERALIZED_TIME)?)?;
+
+    // SingleResponse ::= SEQUENCE {
+    //    certID                       CertID,
+    //    certStatus                   CertStatus,
+    //    thisUpdate                   GeneralizedTime,
+    //    nextUpdate         [0]       EXPLICIT GeneralizedTime OPTIONAL,
+    //    ... }
+    let mut single_response = response_data
+        .nested(SEQUENCE)?
+        .nested(SEQUENCE)?;
+
+    // CertID ::= SEQUENCE {
+    //    hashAlgorithm       AlgorithmIdentifier,
+    //    issuerNameHash      OCTET STRING,
+    //    issuerKeyHash       OCTET STRING,
+    //    serialNumber        CertificateSerialNumber }
+    let mut cert_id = single_response.nested(SEQUENCE)?;
+    let cert_id = CertId {
+        hash_algorithm: cert_id.nested(SEQUENCE)?.read(OID)?,
+        issuer_name_hash: cert_id.read(OCTET_STRING)?,
+        issuer_key_hash: cert_id.read(OCTET_STRING)?,
+        serial_number: cert_id.read(INTEGER)?,
+    };
+
+    // CertStatus ::= CHOICE {
+    //    good        [0]     IMPLICIT NULL,
+    //    revoked     [1]     IMPLICIT RevokedInfo,
+    //    unknown     [2]     IMPLICIT NULL }
+    let status = match single_response.read_any()? {
+        (GOOD, _) => OcspCertStatus::Good,
+        (REVOKED, revoked_info) => {
+            // RevokedInfo ::= SEQUENCE {
+            //    revocationTime              GeneralizedTime,
+            //    revocationReason    [0]     EXPLICIT CRLReason OPTIONAL }
+            OcspCertStatus::Revoked(generalized_time(
+                Der::new(revoked_info).read(GENERALIZED_TIME)?,
+            )?)
+        }
+        (UNKNOWN, _) => OcspCertStatus::Unknown,
+        _ => return None,
+    };
+
+    let this_update = generalized_time(single_response.read(GENERALIZED_TIME)?)?;
+    let next_update = match single_response.peek() {
+        Some(tag) if tag == context(0) => Some(generalized_time(
+            single_response
+                .nested(context(0))?
+                .read(GENERALIZED_TIME)?,
+        )?),
+        _ => None,
+    };
+
+    Some(BasicResponse {
+        response: OcspResponse {
+            status,
+            produced_at,
+            this_update,
+            next_update,
+        },
+        cert_id,
+        tbs_response_data,
+        signature_algorithm: signature_algorithm.read(OID)?,
+        signature_parameters: signature_algorithm.0,
+        signature,
+    })
+}
+
+const GOOD: u8 = 0x80;
+const REVOKED: u8 = 0xa1;
+const UNKNOWN: u8 = 0x82;
+
+const SUCCESSFUL: u8 = 0;
+
+/// id-pkix-ocsp-basic, 1.3.6.1.5.5.7.48.1.1
+const ID_PKIX_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+/// id-sha1, 1.3.14.3.2.26
+const ID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::time::Duration;
+
+    fn unix_time(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn parses_good_response() {
+        let response = OcspResponse::parse(include_bytes!("testdata/ocsp-good.der")).unwrap();
+        assert_eq!(response.status(), OcspCertStatus::Good);
+        assert_eq!(response.produced_at(), response.this_update());
+        assert_eq!(
+            response.next_update(),
+            Some(response.this_update() + Duration::from_secs(7 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn parses_revoked_response() {
+        let response = OcspResponse::parse(include_bytes!("testdata/ocsp-revoked.der")).unwrap();
+        assert_eq!(
+            response.status(),
+            OcspCertStatus::Revoked(unix_time(1_700_000_000))
+        );
+        assert_eq!(response.next_update(), None);
+    }
+
+    #[test]
+    fn rejects_truncated_response() {
+        let der = include_bytes!("testdata/ocsp-good.der");
+        for len in [0, 1, 10, der.len() - 1] {
+            assert!(OcspResponse::parse(&der[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_unsuccessful_response() {
+        // OCSPResponse { responseStatus tryLater(3) }
+        assert!(OcspResponse::parse(&[0x30, 0x03, 0x0a, 0x01, 0x03]).is_err());
+    }
+}
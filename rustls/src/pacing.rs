@@ -0,0 +1,123 @@
+use core::cmp;
+
+use alloc::sync::Arc;
+
+/// Paces how much application data a connection encrypts.
+///
+/// Set a pacer as `ClientConfig::write_pacer` or `ServerConfig::write_pacer`.
+/// Each connection made from those configs then works in *cycles*: a cycle
+/// starts when the connection is made and each time
+/// [`Connection::write_tls`] is called.  At the start of each cycle the
+/// connection asks [`WritePacer::write_allowance`] how many plaintext bytes
+/// it may encrypt and queue, and [`Connection::writer`] accepts no more
+/// than that until the next cycle.  Once the allowance is used up, writes
+/// are short (or return `Ok(0)`), just as they are when the buffer limit set
+/// by [`CommonState::set_buffer_limit`] is reached.
+///
+/// Since the same pacer can be shared by any number of connections, it
+/// can be used to share out bandwidth fairly between them.
+///
+/// The allowance applies to application data encrypted once the
+/// handshake is complete, and to early data.  Data written during the
+/// handshake is buffered, and is subject only to the buffer limit.
+///
+/// [`Connection::write_tls`]: crate::Connection::write_tls
+/// [`Connection::writer`]: crate::Connection::writer
+/// [`CommonState::set_buffer_limit`]: crate::CommonState::set_buffer_limit
+pub trait WritePacer: Send + Sync {
+    /// How many plaintext bytes a connection may encrypt in the cycle
+    /// that is starting.  `None` means no limit.
+    fn write_allowance(&self) -> Option<usize>;
+
+    /// Called when [`Connection::write_tls`] could not write any of the
+    /// `pending` bytes of TLS data queued by the connection, because the
+    /// writer returned `Ok(0)` or an [`io::ErrorKind::WouldBlock`] error.
+    /// This usually means the peer has stopped reading.
+    ///
+    /// The default implementation does nothing.
+    ///
+    /// [`Connection::write_tls`]: crate::Connection::write_tls
+    /// [`io::ErrorKind::WouldBlock`]: std::io::ErrorKind::WouldBlock
+    fn peer_stalled(&self, pending: usize) {
+        let _ = pending;
+    }
+}
+
+/// A connection's [`WritePacer`], and what remains of its allowance
+/// for the current cycle.
+pub(crate) struct WritePacing {
+    pacer: Arc<dyn WritePacer>,
+    remaining: Option<usize>,
+}
+
+impl WritePacing {
+    pub(crate) fn new(pacer: &Arc<dyn WritePacer>) -> Self {
+        Self {
+            pacer: Arc::clone(pacer),
+            remaining: pacer.write_allowance(),
+        }
+    }
+
+    /// Start a new cycle.
+    pub(crate) fn refresh(&mut self) {
+        self.remaining = self.pacer.write_allowance();
+    }
+
+    /// How much of `len` bytes may be encrypted now.  The result is
+    /// deducted from the allowance.
+    pub(crate) fn take(&mut self, len: usize) -> usize {
+        match self.remaining.as_mut() {
+            Some(remaining) => {
+                let len = cmp::min(len, *remaining);
+                *remaining -= len;
+                len
+            }
+            None => len,
+        }
+    }
+
+    pub(crate) fn peer_stalled(&self, pending: usize) {
+        self.pacer.peer_stalled(pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Fixed(AtomicUsize);
+
+    impl WritePacer for Fixed {
+        fn write_allowance(&self) -> Option<usize> {
+            Some(self.0.fetch_add(1, Ordering::Relaxed) * 10)
+        }
+    }
+
+    #[test]
+    fn allowance_is_used_up_and_refreshed() {
+        let pacer: Arc<dyn WritePacer> = Arc::new(Fixed(AtomicUsize::new(1)));
+        let mut pacing = WritePacing::new(&pacer);
+        assert_eq!(pacing.take(4), 4);
+        assert_eq!(pacing.take(8), 6);
+        assert_eq!(pacing.take(1), 0);
+
+        pacing.refresh();
+        assert_eq!(pacing.take(100), 20);
+    }
+
+    struct Unlimited;
+
+    impl WritePacer for Unlimited {
+        fn write_allowance(&self) -> Option<usize> {
+            None
+        }
+    }
+
+    #[test]
+    fn no_allowance_means_no_limit() {
+        let pacer: Arc<dyn WritePacer> = Arc::new(Unlimited);
+        let mut pacing = WritePacing::new(&pacer);
+        assert_eq!(pacing.take(usize::MAX), usize::MAX);
+    }
+}
@@ -53,12 +53,12 @@ impl RecordLayer {
         self.encrypt_state == DirectionState::Active
     }
 
-    #[cfg(feature = "secret_extraction")]
+    #[cfg(any(feature = "secret_extraction", feature = "dangerous_configuration"))]
     pub(crate) fn write_seq(&self) -> u64 {
         self.write_seq
     }
 
-    #[cfg(feature = "secret_extraction")]
+    #[cfg(any(feature = "secret_extraction", feature = "dangerous_configuration"))]
     pub(crate) fn read_seq(&self) -> u64 {
         self.read_seq
     }
@@ -78,6 +78,13 @@ impl RecordLayer {
 
     /// Prepare to use the given `MessageEncrypter` for future message encryption.
     /// It is not used until you call `start_encrypting`.
+    /// Carry on from the sequence numbers of an exported connection.
+    #[cfg(feature = "dangerous_configuration")]
+    pub(crate) fn restore_sequence_numbers(&mut self, write_seq: u64, read_seq: u64) {
+        self.write_seq = write_seq;
+        self.read_seq = read_seq;
+    }
+
     pub(crate) fn prepare_message_encrypter(&mut self, cipher: Box<dyn MessageEncrypter>) {
         self.message_encrypter = cipher;
         self.write_seq = 0;
@@ -0,0 +1,249 @@
+use crate::key;
+use crate::msgs::codec::Codec;
+use crate::msgs::handshake::SctList;
+use crate::x509::{context, Der, BOOLEAN, OCTET_STRING, OID, SEQUENCE};
+
+use core::time::Duration;
+use std::time::SystemTime;
+
+/// A Signed Certificate Timestamp ([RFC 6962]) presented by a server.
+///
+/// This is a promise by a Certificate Transparency log to include the
+/// server's certificate.  SCTs are collected whether or not they were
+/// checked: nothing here verifies the log's signature, nor that the log
+/// is known or trusted.
+///
+/// [RFC 6962]: https://www.rfc-editor.org/rfc/rfc6962
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedCertificateTimestamp {
+    source: SctSource,
+    bytes: Vec<u8>,
+}
+
+impl SignedCertificateTimestamp {
+    /// How the SCT was delivered.
+    pub fn source(&self) -> SctSource {
+        self.source
+    }
+
+    /// The TLS encoding of the SCT: the contents of a `SerializedSCT`,
+    /// without its length prefix.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The SHA-256 hash of the issuing log's public key.
+    ///
+    /// This is `None` if the SCT is not version 1 or is truncated.
+    pub fn log_id(&self) -> Option<&[u8]> {
+        self.v1_body()
+            .map(|body| &body[..LOG_ID_LEN])
+    }
+
+    /// When the log issued the SCT.
+    ///
+    /// This is `None` if the SCT is not version 1 or is truncated.
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        let body = self.v1_body()?;
+        let millis = body[LOG_ID_LEN..LOG_ID_LEN + 8]
+            .iter()
+            .fold(0u64, |acc, byte| acc << 8 | u64::from(*byte));
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+    }
+
+    /// The log ID and timestamp, after the version byte.
+    fn v1_body(&self) -> Option<&[u8]> {
+        match self.bytes.split_first() {
+            Some((&V1, body)) if body.len() >= LOG_ID_LEN + 8 => Some(body),
+            _ => None,
+        }
+    }
+}
+
+/// Where a [`SignedCertificateTimestamp`] came from.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SctSource {
+    /// The `signed_certificate_timestamp` TLS extension.
+    TlsExtension,
+    /// An X.509v3 extension in the server's end-entity certificate.
+    Certificate,
+}
+
+/// Split a `SignedCertificateTimestampList` into its SCTs.
+///
+/// Returns `None` if the list is malformed or empty, or any SCT in it is empty.
+pub(crate) fn parse_sct_list(
+    list: &[u8],
+    source: SctSource,
+) -> Option<Vec<SignedCertificateTimestamp>> {
+    from_sct_list(SctList::read_bytes(list).ok()?, source)
+}
+
+/// Like [`parse_sct_list`], for a list that was decoded along with its
+/// handshake message.
+pub(crate) fn from_sct_list(
+    scts: SctList,
+    source: SctSource,
+) -> Option<Vec<SignedCertificateTimestamp>> {
+    if scts.is_empty() || scts.iter().any(|sct| sct.0.is_empty()) {
+        return None;
+    }
+
+    Some(
+        scts.into_iter()
+            .map(|sct| SignedCertificateTimestamp {
+                source,
+                bytes: sct.0,
+            })
+            .collect(),
+    )
+}
+
+/// Return the SCTs embedded in `cert`.
+///
+/// Certificates without the SCT list extension, or where it is malformed,
+/// yield nothing.
+pub(crate) fn embedded_scts(cert: &key::Certificate) -> Vec<SignedCertificateTimestamp> {
+    embedded_sct_list(&cert.0)
+        .and_then(|list| parse_sct_list(list, SctSource::Certificate))
+        .unwrap_or_default()
+}
+
+fn embedded_sct_list(cert: &[u8]) -> Option<&[u8]> {
+    // Certificate ::= SEQUENCE {
+    //    tbsCertificate       TBSCertificate,
+    //    ... }
+    let mut tbs_certificate = Der::new(cert)
+        .nested(SEQUENCE)?
+        .nested(SEQUENCE)?;
+
+    // TBSCertificate ::= SEQUENCE {
+    //    ...
+    //    extensions      [3]  EXPLICIT Extensions OPTIONAL }
+    let mut extensions = loop {
+        match tbs_certificate.read_any()? {
+            (tag, extensions) if tag == context(3) => {
+                break Der::new(extensions).nested(SEQUENCE)?
+            }
+            _ => continue,
+        }
+    };
+
+    // Extension ::= SEQUENCE {
+    //    extnID      OBJECT IDENTIFIER,
+    //    critical    BOOLEAN DEFAULT FALSE,
+    //    extnValue   OCTET STRING }
+    while let Some(mut extension) = extensions.nested(SEQUENCE) {
+        if extension.read(OID)? != ID_CT_PRECERT_SCTS {
+            continue;
+        }
+        if extension.peek() == Some(BOOLEAN) {
+            extension.read(BOOLEAN)?;
+        }
+
+        // The extension's value is itself an OCTET STRING holding the
+        // TLS-encoded `SignedCertificateTimestampList`.
+        return Der::new(extension.read(OCTET_STRING)?).read(OCTET_STRING);
+    }
+
+    None
+}
+
+const V1: u8 = 0;
+const LOG_ID_LEN: usize = 32;
+
+/// Embedded SCT list, 1.3.6.1.4.1.11129.2.4.2
+const ID_CT_PRECERT_SCTS: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x02];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sct(log_id: u8, millis: u64) -> Vec<u8> {
+        let mut sct = vec![V1];
+        sct.extend_from_slice(&[log_id; LOG_ID_LEN]);
+        sct.extend_from_slice(&millis.to_be_bytes());
+        // No extensions, and a made-up signature.
+        sct.extend_from_slice(&[0x00, 0x00, 0x04, 0x03, 0x00, 0x01, 0xff]);
+        sct
+    }
+
+    fn sct_list(scts: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for sct in scts {
+            body.extend_from_slice(&(sct.len() as u16).to_be_bytes());
+            body.extend_from_slice(sct);
+        }
+        let mut list = (body.len() as u16)
+            .to_be_bytes()
+            .to_vec();
+        list.extend(body);
+        list
+    }
+
+    #[test]
+    fn parses_sct_list() {
+        let (first, second) = (sct(0x11, 1_700_000_000_000), sct(0x22, 1_700_000_000_001));
+        let scts = parse_sct_list(&sct_list(&[&first, &second]), SctSource::TlsExtension).unwrap();
+        assert_eq!(scts.len(), 2);
+        assert_eq!(scts[0].source(), SctSource::TlsExtension);
+        assert_eq!(scts[0].bytes(), &first[..]);
+        assert_eq!(scts[0].log_id(), Some(&[0x11; LOG_ID_LEN][..]));
+        assert_eq!(
+            scts[1].timestamp(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_001))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_sct_lists() {
+        assert_eq!(parse_sct_list(&[], SctSource::TlsExtension), None);
+        assert_eq!(
+            parse_sct_list(&sct_list(&[]), SctSource::TlsExtension),
+            None
+        );
+        assert_eq!(
+            parse_sct_list(&sct_list(&[&[]]), SctSource::TlsExtension),
+            None
+        );
+        let mut truncated = sct_list(&[&sct(0x11, 0)]);
+        truncated.pop();
+        assert_eq!(parse_sct_list(&truncated, SctSource::TlsExtension), None);
+    }
+
+    #[test]
+    fn unknown_sct_versions_have_no_fields() {
+        let sct = SignedCertificateTimestamp {
+            source: SctSource::TlsExtension,
+            bytes: vec![1; 64],
+        };
+        assert_eq!(sct.log_id(), None);
+        assert_eq!(sct.timestamp(), None);
+    }
+
+    #[test]
+    fn finds_embedded_scts() {
+        let cert = key::Certificate(include_bytes!("testdata/cert-arstechnica.0.der").to_vec());
+        let scts = embedded_scts(&cert);
+        assert_eq!(scts.len(), 3);
+        assert!(scts
+            .iter()
+            .all(|sct| sct.source() == SctSource::Certificate));
+        assert_eq!(
+            scts[0].log_id().map(|id| &id[..4]),
+            Some(&[0x29, 0x79, 0xbe, 0xf0][..])
+        );
+        // Nov 29 00:17:15.180 2021 GMT
+        assert_eq!(
+            scts[0].timestamp(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_638_145_035_180))
+        );
+    }
+
+    #[test]
+    fn certificates_without_scts_have_none() {
+        let cert = key::Certificate(include_bytes!("testdata/cert-arstechnica.1.der").to_vec());
+        assert!(embedded_scts(&cert).is_empty());
+    }
+}
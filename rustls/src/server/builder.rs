@@ -1,10 +1,14 @@
 use crate::builder::{ConfigBuilder, WantsVerifier};
+use crate::cert_stream::CertificateChainLimits;
+use crate::common_state::{DuplicateExtensionPolicy, RecordLimits, RenegotiationPolicy};
 use crate::crypto::{CryptoProvider, KeyExchange};
+use crate::enums::SignatureScheme;
 use crate::error::Error;
 use crate::key;
 use crate::server::handy;
-use crate::server::{ResolvesServerCert, ServerConfig};
+use crate::server::{HelloRetryPolicy, ResolvesServerCert, ServerConfig};
 use crate::suites::SupportedCipherSuite;
+use crate::time_provider::DefaultTimeProvider;
 use crate::verify;
 use crate::versions;
 use crate::NoKeyLog;
@@ -23,6 +27,7 @@ impl<C: CryptoProvider> ConfigBuilder<ServerConfig<C>, WantsVerifier<C>> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
                 verifier: client_cert_verifier,
             },
             side: PhantomData,
@@ -44,6 +49,7 @@ pub struct WantsServerCert<C: CryptoProvider> {
     cipher_suites: Vec<SupportedCipherSuite>,
     kx_groups: Vec<&'static <C::KeyExchange as KeyExchange>::SupportedGroup>,
     versions: versions::EnabledVersions,
+    signature_schemes: Option<Vec<SignatureScheme>>,
     verifier: Arc<dyn verify::ClientCertVerifier>,
 }
 
@@ -70,8 +76,8 @@ impl<C: CryptoProvider> ConfigBuilder<ServerConfig<C>, WantsServerCert<C>> {
         Ok(self.with_cert_resolver(Arc::new(resolver)))
     }
 
-    /// Sets a single certificate chain, matching private key, OCSP
-    /// response and SCTs.  This certificate and key is used for all
+    /// Sets a single certificate chain, matching private key and OCSP
+    /// response.  This certificate and key is used for all
     /// subsequent connections, irrespective of things like SNI hostname.
     ///
     /// `cert_chain` is a vector of DER-encoded certificates.
@@ -85,7 +91,29 @@ impl<C: CryptoProvider> ConfigBuilder<ServerConfig<C>, WantsServerCert<C>> {
         key_der: key::PrivateKey,
         ocsp: Vec<u8>,
     ) -> Result<ServerConfig<C>, Error> {
-        let resolver = handy::AlwaysResolvesChain::new_with_extras(cert_chain, &key_der, ocsp)?;
+        self.with_single_cert_with_ocsp_and_sct(cert_chain, key_der, ocsp, Vec::new())
+    }
+
+    /// Sets a single certificate chain, matching private key, OCSP
+    /// response and SCTs.  This certificate and key is used for all
+    /// subsequent connections, irrespective of things like SNI hostname.
+    ///
+    /// `cert_chain` is a vector of DER-encoded certificates.
+    /// `key_der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.
+    /// `ocsp` is a DER-encoded OCSP response.  Ignored if zero length.
+    /// `scts` is an `SignedCertificateTimestampList` encoding (see RFC6962)
+    /// and is ignored if empty.
+    ///
+    /// This function fails if `key_der` or `scts` are invalid.
+    pub fn with_single_cert_with_ocsp_and_sct(
+        self,
+        cert_chain: Vec<key::Certificate>,
+        key_der: key::PrivateKey,
+        ocsp: Vec<u8>,
+        scts: Vec<u8>,
+    ) -> Result<ServerConfig<C>, Error> {
+        let resolver =
+            handy::AlwaysResolvesChain::new_with_extras(cert_chain, &key_der, ocsp, scts)?;
         Ok(self.with_cert_resolver(Arc::new(resolver)))
     }
 
@@ -94,20 +122,46 @@ impl<C: CryptoProvider> ConfigBuilder<ServerConfig<C>, WantsServerCert<C>> {
         ServerConfig {
             cipher_suites: self.state.cipher_suites,
             kx_groups: self.state.kx_groups,
+            key_share_pool: None,
             verifier: self.state.verifier,
             cert_resolver,
+            require_sni: false,
+            server_names: None,
+            client_auth_resolver: None,
+            client_auth_root_hints: None,
             ignore_client_order: false,
             max_fragment_size: None,
             session_storage: handy::ServerSessionMemoryCache::new(256),
+            tls12_session_storage: None,
             ticketer: Arc::new(handy::NeverProducesTickets {}),
+            single_use_tickets: None,
+            hello_retry_cookies: Arc::new(handy::NeverProducesTickets {}),
+            resumption_observer: Arc::new(handy::NoResumptionObserver),
+            resumption_data: Arc::new(handy::DefaultResumptionData),
+            resumption_validator: None,
             alpn_protocols: Vec::new(),
+            hello_retry: HelloRetryPolicy::default(),
+            hello_retry_limit: None,
             versions: self.state.versions,
+            signature_schemes: self.state.signature_schemes,
             key_log: Arc::new(NoKeyLog {}),
+            time_provider: Arc::new(DefaultTimeProvider),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: false,
             max_early_data_size: 0,
+            early_data_gate: None,
             send_half_rtt_data: false,
             send_tls13_tickets: 4,
+            enable_middlebox_compat: true,
+            capture_handshake: false,
+            handshake_timeout: None,
+            require_ems: false,
+            renegotiation: RenegotiationPolicy::Reject,
+            record_limits: RecordLimits::default(),
+            cert_chain_limits: CertificateChainLimits::default(),
+            duplicate_extensions: DuplicateExtensionPolicy::default(),
+            memory_budget: None,
+            write_pacer: None,
             provider: PhantomData,
         }
     }
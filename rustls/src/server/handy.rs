@@ -2,13 +2,17 @@ use crate::dns_name::DnsNameRef;
 use crate::error::Error;
 use crate::key;
 use crate::limited_cache;
+use crate::msgs::enums::NamedGroup;
 use crate::server;
-use crate::server::ClientHello;
+use crate::server::{CertSelection, CertSelectionReason, ClientHello};
 use crate::sign;
 
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
 use std::collections;
 use std::sync::Mutex;
+use std::time::SystemTime;
 
 /// Something which never stores sessions.
 pub struct NoServerSessionStorage {}
@@ -72,6 +76,153 @@ impl server::StoresServerSessions for ServerSessionMemoryCache {
     }
 }
 
+/// An implementer of `TracksUsedTickets` that remembers tickets
+/// in memory.
+///
+/// Each ticket is remembered for `ticket_lifetime` seconds after its first
+/// use, which should be the [`ProducesTickets::lifetime`] of the ticketer:
+/// after that, the ticketer will no longer accept it anyway.  Once `size`
+/// unexpired tickets are remembered, further tickets are refused rather
+/// than evicting any, so a flood of tickets cannot make one usable again;
+/// choose a size that covers the number of resumptions expected within a
+/// ticket lifetime.
+///
+/// [`ProducesTickets::lifetime`]: crate::server::ProducesTickets::lifetime
+pub struct UsedTicketMemoryCache {
+    size: usize,
+    ticket_lifetime: Duration,
+    used: Mutex<UsedTickets>,
+}
+
+#[derive(Default)]
+struct UsedTickets {
+    first_used: collections::HashMap<Vec<u8>, SystemTime>,
+    oldest_first: collections::VecDeque<(SystemTime, Vec<u8>)>,
+}
+
+impl UsedTicketMemoryCache {
+    /// Make a new UsedTicketMemoryCache.  `size` is the maximum
+    /// number of remembered tickets, and `ticket_lifetime` is how
+    /// long, in seconds, each is remembered.
+    pub fn new(size: usize, ticket_lifetime: u32) -> Arc<Self> {
+        Arc::new(Self {
+            size,
+            ticket_lifetime: Duration::from_secs(u64::from(ticket_lifetime)),
+            used: Mutex::new(UsedTickets::default()),
+        })
+    }
+}
+
+impl server::TracksUsedTickets for UsedTicketMemoryCache {
+    fn mark_used(&self, ticket: &[u8], now: SystemTime) -> bool {
+        let mut used = self.used.lock().unwrap();
+        let used = &mut *used;
+
+        while let Some((first_used, _)) = used.oldest_first.front() {
+            let expired = match first_used.checked_add(self.ticket_lifetime) {
+                Some(expiry) => expiry <= now,
+                None => false,
+            };
+            if !expired {
+                break;
+            }
+            if let Some((_, ticket)) = used.oldest_first.pop_front() {
+                used.first_used.remove(&ticket);
+            }
+        }
+
+        if used.first_used.contains_key(ticket) || used.first_used.len() >= self.size {
+            return false;
+        }
+
+        used.first_used
+            .insert(ticket.to_vec(), now);
+        used.oldest_first
+            .push_back((now, ticket.to_vec()));
+        true
+    }
+}
+
+/// Counts the `HelloRetryRequest`s a server sends, and optionally refuses
+/// them once a limit is reached.
+///
+/// Use this as [`ServerConfig::hello_retry_limit`].  The limit applies
+/// until [`HelloRetryCounter::reset`] is called, so resetting periodically
+/// gives a rate limit.
+///
+/// [`ServerConfig::hello_retry_limit`]: crate::ServerConfig::hello_retry_limit
+pub struct HelloRetryCounter {
+    limit: Option<usize>,
+    sent: AtomicUsize,
+    refused: AtomicUsize,
+}
+
+impl HelloRetryCounter {
+    /// Make a new HelloRetryCounter which counts, but never refuses,
+    /// `HelloRetryRequest`s.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::with_optional_limit(None))
+    }
+
+    /// Make a new HelloRetryCounter which refuses `HelloRetryRequest`s once
+    /// `limit` have been sent.
+    pub fn with_limit(limit: usize) -> Arc<Self> {
+        Arc::new(Self::with_optional_limit(Some(limit)))
+    }
+
+    fn with_optional_limit(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            sent: AtomicUsize::new(0),
+            refused: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of `HelloRetryRequest`s permitted since the last reset.
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// The number of `HelloRetryRequest`s refused since the last reset.
+    pub fn refused(&self) -> usize {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    /// Zero both counts.
+    pub fn reset(&self) {
+        self.sent.store(0, Ordering::Relaxed);
+        self.refused.store(0, Ordering::Relaxed);
+    }
+}
+
+impl server::LimitsHelloRetries for HelloRetryCounter {
+    fn permit(&self, _server_name: Option<&str>, _group: NamedGroup) -> bool {
+        let permitted = match self.limit {
+            Some(limit) => self
+                .sent
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sent| {
+                    if sent < limit {
+                        Some(sent + 1)
+                    } else {
+                        None
+                    }
+                })
+                .is_ok(),
+            None => {
+                self.sent
+                    .fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        };
+
+        if !permitted {
+            self.refused
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        permitted
+    }
+}
+
 /// Something which never produces tickets.
 pub(super) struct NeverProducesTickets {}
 
@@ -90,6 +241,16 @@ impl server::ProducesTickets for NeverProducesTickets {
     }
 }
 
+/// Something which ignores all resumption events.
+pub(super) struct NoResumptionObserver;
+
+impl server::ObservesServerResumption for NoResumptionObserver {}
+
+/// Uses the data set on each connection with `set_resumption_data`.
+pub(super) struct DefaultResumptionData;
+
+impl server::ProducesResumptionData for DefaultResumptionData {}
+
 /// Something which always resolves to the same cert chain.
 pub(super) struct AlwaysResolvesChain(Arc<sign::CertifiedKey>);
 
@@ -113,6 +274,7 @@ impl AlwaysResolvesChain {
         chain: Vec<key::Certificate>,
         priv_key: &key::PrivateKey,
         ocsp: Vec<u8>,
+        scts: Vec<u8>,
     ) -> Result<Self, Error> {
         let mut r = Self::new(chain, priv_key)?;
 
@@ -121,6 +283,12 @@ impl AlwaysResolvesChain {
             if !ocsp.is_empty() {
                 cert.ocsp = Some(ocsp);
             }
+            if !scts.is_empty() {
+                cert.sct_list = Some(scts);
+                if cert.sct_list_is_invalid() {
+                    return Err(Error::General("invalid SCT list".into()));
+                }
+            }
         }
 
         Ok(r)
@@ -131,6 +299,59 @@ impl server::ResolvesServerCert for AlwaysResolvesChain {
     fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
         Some(Arc::clone(&self.0))
     }
+
+    fn resolve_with_reason(&self, client_hello: ClientHello) -> Option<CertSelection> {
+        self.resolve(client_hello)
+            .map(|certified_key| CertSelection {
+                certified_key,
+                reason: CertSelectionReason::Fallback,
+            })
+    }
+}
+
+/// Accepts a fixed set of server names.
+///
+/// Use this as [`ServerConfig::server_names`].  Besides exact names, a name
+/// of the form `*.example.com` accepts any name with exactly one more label,
+/// like `www.example.com` but not `example.com` or `a.b.example.com`.
+///
+/// [`ServerConfig::server_names`]: crate::ServerConfig::server_names
+#[derive(Debug)]
+pub struct ServerNameAllowList {
+    exact: collections::HashSet<String>,
+    wildcards: collections::HashSet<String>,
+}
+
+impl ServerNameAllowList {
+    /// Make a list accepting `names`.
+    ///
+    /// This fails if a name, without any leading `*.`, is not a valid DNS
+    /// name.
+    pub fn new<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Arc<Self>, Error> {
+        let mut exact = collections::HashSet::new();
+        let mut wildcards = collections::HashSet::new();
+        for name in names {
+            let (set, name) = match name.strip_prefix("*.") {
+                Some(parent) => (&mut wildcards, parent),
+                None => (&mut exact, name),
+            };
+            let name = DnsNameRef::try_from(name)
+                .map_err(|_| Error::General("Bad DNS name".into()))?
+                .to_lowercase_owned();
+            let as_str: &str = name.as_ref();
+            set.insert(as_str.to_string());
+        }
+        Ok(Arc::new(Self { exact, wildcards }))
+    }
+}
+
+impl server::AcceptsServerName for ServerNameAllowList {
+    fn accepts(&self, server_name: &str) -> bool {
+        self.exact.contains(server_name)
+            || server_name
+                .split_once('.')
+                .map_or(false, |(_, parent)| self.wildcards.contains(parent))
+    }
 }
 
 /// Something that resolves do different cert chains/keys based
@@ -209,14 +430,25 @@ impl server::ResolvesServerCert for ResolvesServerCertUsingSni {
             None
         }
     }
+
+    fn resolve_with_reason(&self, client_hello: ClientHello) -> Option<CertSelection> {
+        self.resolve(client_hello)
+            .map(|certified_key| CertSelection {
+                certified_key,
+                reason: CertSelectionReason::ServerName,
+            })
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::server::AcceptsServerName;
+    use crate::server::LimitsHelloRetries;
     use crate::server::ProducesTickets;
     use crate::server::ResolvesServerCert;
     use crate::server::StoresServerSessions;
+    use crate::server::TracksUsedTickets;
 
     #[test]
     fn test_noserversessionstorage_drops_put() {
@@ -281,6 +513,67 @@ mod test {
         assert!(count < 5);
     }
 
+    #[test]
+    fn test_usedticketmemorycache_rejects_reuse() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let c = UsedTicketMemoryCache::new(4, 60);
+        assert!(c.mark_used(&[0x01], now));
+        assert!(c.mark_used(&[0x02], now));
+        assert!(!c.mark_used(&[0x01], now));
+        assert!(!c.mark_used(&[0x02], now + Duration::from_secs(59)));
+    }
+
+    #[test]
+    fn test_usedticketmemorycache_refuses_when_full() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let c = UsedTicketMemoryCache::new(2, 60);
+        assert!(c.mark_used(&[0x01], now));
+        assert!(c.mark_used(&[0x02], now));
+        // neither a new ticket, nor one which would have been evicted
+        assert!(!c.mark_used(&[0x03], now + Duration::from_secs(1)));
+        assert!(!c.mark_used(&[0x01], now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_usedticketmemorycache_forgets_expired_tickets() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let c = UsedTicketMemoryCache::new(2, 60);
+        assert!(c.mark_used(&[0x01], now));
+        assert!(c.mark_used(&[0x02], now + Duration::from_secs(30)));
+        assert!(!c.mark_used(&[0x03], now + Duration::from_secs(59)));
+
+        // 0x01 has expired, making room
+        let later = now + Duration::from_secs(60);
+        assert!(c.mark_used(&[0x03], later));
+        assert!(!c.mark_used(&[0x02], later));
+        assert!(!c.mark_used(&[0x04], later));
+    }
+
+    #[test]
+    fn test_helloretrycounter_counts() {
+        let c = HelloRetryCounter::new();
+        for _ in 0..3 {
+            assert!(c.permit(None, NamedGroup::X25519));
+        }
+        assert_eq!(c.sent(), 3);
+        assert_eq!(c.refused(), 0);
+    }
+
+    #[test]
+    fn test_helloretrycounter_applies_limit_until_reset() {
+        let c = HelloRetryCounter::with_limit(2);
+        assert!(c.permit(Some("a"), NamedGroup::X25519));
+        assert!(c.permit(Some("b"), NamedGroup::secp256r1));
+        assert!(!c.permit(None, NamedGroup::X25519));
+        assert_eq!(c.sent(), 2);
+        assert_eq!(c.refused(), 1);
+
+        c.reset();
+        assert_eq!(c.refused(), 0);
+        assert!(c.permit(None, NamedGroup::X25519));
+        assert_eq!(c.sent(), 1);
+    }
+
     #[test]
     fn test_neverproducestickets_does_nothing() {
         let npt = NeverProducesTickets {};
@@ -294,7 +587,7 @@ mod test {
     fn test_resolvesservercertusingsni_requires_sni() {
         let rscsni = ResolvesServerCertUsingSni::new();
         assert!(rscsni
-            .resolve(ClientHello::new(&None, &[], None, &[]))
+            .resolve(ClientHello::new(&None, &[], None, &[], None))
             .is_none());
     }
 
@@ -305,7 +598,23 @@ mod test {
             .unwrap()
             .to_owned();
         assert!(rscsni
-            .resolve(ClientHello::new(&Some(name), &[], None, &[]))
+            .resolve(ClientHello::new(&Some(name), &[], None, &[], None))
             .is_none());
     }
+
+    #[test]
+    fn test_server_name_allow_list() {
+        let list = ServerNameAllowList::new(["Example.com", "*.example.org"]).unwrap();
+        assert!(list.accepts("example.com"));
+        assert!(!list.accepts("www.example.com"));
+        assert!(list.accepts("www.example.org"));
+        assert!(!list.accepts("example.org"));
+        assert!(!list.accepts("a.b.example.org"));
+    }
+
+    #[test]
+    fn test_server_name_allow_list_rejects_bad_names() {
+        assert!(ServerNameAllowList::new(["not a name"]).is_err());
+        assert!(ServerNameAllowList::new(["*.*.example.com"]).is_err());
+    }
 }
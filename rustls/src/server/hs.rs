@@ -1,23 +1,32 @@
-use crate::common_state::State;
+use crate::cert_stream::{CertificateChainLimits, CertificateStream};
+use crate::common_state::{CommonState, DuplicateExtensionPolicy, State};
 use crate::conn::ConnectionRandoms;
-use crate::crypto::CryptoProvider;
-use crate::dns_name::DnsName;
 #[cfg(feature = "tls12")]
-use crate::enums::CipherSuite;
-use crate::enums::{AlertDescription, HandshakeType, ProtocolVersion, SignatureScheme};
+use crate::crypto::SupportedGroup;
+use crate::crypto::{hash, CryptoProvider};
+use crate::dns_name::DnsName;
+use crate::enums::{
+    AlertDescription, CipherSuite, HandshakeType, ProtocolVersion, SignatureAlgorithm,
+    SignatureScheme,
+};
 use crate::error::{Error, PeerIncompatible, PeerMisbehaved};
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
+use crate::key::Certificate;
 #[cfg(feature = "logging")]
-use crate::log::{debug, trace};
-use crate::msgs::enums::{Compression, ExtensionType};
+use crate::log::{debug, trace, warn};
+use crate::msgs::codec::Codec;
+use crate::msgs::enums::{Compression, ExtensionType, PSKKeyExchangeMode};
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::ClientSessionTicket;
 #[cfg(feature = "tls12")]
 use crate::msgs::handshake::SessionId;
-use crate::msgs::handshake::{ClientHelloPayload, Random, ServerExtension};
+use crate::msgs::handshake::{ClientExtension, ClientHelloPayload, Random, ServerExtension};
 use crate::msgs::handshake::{ConvertProtocolNameList, ConvertServerNameList, HandshakePayload};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
-use crate::server::{ClientHello, ServerConfig};
+use crate::server::{ClientHello, NegotiationPreview, ResumingSession, ServerConfig};
 use crate::suites;
+use crate::verify::{self, ClientCertVerifier};
 use crate::SupportedCipherSuite;
 
 use super::server_conn::ServerConnectionData;
@@ -27,6 +36,7 @@ use crate::server::common::ActiveCertifiedKey;
 use crate::server::tls13;
 
 use alloc::sync::Arc;
+use core::any::Any;
 
 pub(super) type NextState = Box<dyn State<ServerConnectionData>>;
 pub(super) type NextStateOrError = Result<NextState, Error>;
@@ -50,6 +60,279 @@ pub(super) fn can_resume(
         && &resumedata.sni == sni
 }
 
+/// Whether `config.resumption_validator` allows `resumedata` to be resumed.
+pub(super) fn resumption_permitted<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    cx: &ServerContext<'_>,
+    client_hello: &ClientHelloPayload,
+    sig_schemes: &[SignatureScheme],
+    resumedata: &persist::ServerSessionValue,
+) -> bool {
+    if config.resumption_validator.is_none() {
+        return true;
+    }
+
+    let hello = ClientHello::new(
+        &cx.data.sni,
+        sig_schemes,
+        client_hello.get_alpn_extension(),
+        &client_hello.cipher_suites,
+        client_hello.get_authorities_extension(),
+    )
+    .with_user_data(cx.common.user_data());
+    validate_resumption(config, &hello, resumedata)
+}
+
+fn validate_resumption<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    client_hello: &ClientHello<'_>,
+    resumedata: &persist::ServerSessionValue,
+) -> bool {
+    match &config.resumption_validator {
+        Some(validator) => validator.validate(client_hello, &ResumingSession::new(resumedata)),
+        None => true,
+    }
+}
+
+/// Works out what would be negotiated with a client, without any of the
+/// side effects of doing so.
+///
+/// See [`crate::server::Accepted::preview`].
+pub(super) fn preview<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    client_hello: &ClientHelloPayload,
+    sig_schemes: Vec<SignatureScheme>,
+    sni: &Option<DnsName>,
+    user_data: Option<&(dyn Any + Send + Sync)>,
+) -> Result<NegotiationPreview, Error> {
+    check_server_name(config, sni)?;
+    let version = choose_version(config, client_hello, false)?;
+    let sig_schemes = usable_sig_schemes(config, client_hello, sig_schemes);
+    let make_hello = || {
+        ClientHello::new(
+            sni,
+            &sig_schemes,
+            client_hello.get_alpn_extension(),
+            &client_hello.cipher_suites,
+            client_hello.get_authorities_extension(),
+        )
+        .with_user_data(user_data)
+    };
+
+    let certified_key = config
+        .cert_resolver
+        .resolve_with_reason(make_hello())
+        .map(|selection| selection.certified_key)
+        .ok_or_else(|| Error::General("no server certificate chain resolved".to_owned()))?;
+    let suite = choose_suite(config, client_hello, certified_key.key.algorithm(), version)
+        .ok_or(PeerIncompatible::NoCipherSuitesInCommon)?;
+    let groups_ext = client_hello
+        .get_namedgroups_extension()
+        .ok_or(PeerIncompatible::NamedGroupsExtensionRequired)?;
+
+    let resumable = |plain: Option<Vec<u8>>, using_ems: bool| {
+        plain
+            .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain).ok())
+            .map_or(false, |resumedata| {
+                can_resume(suite, sni, using_ems, &resumedata)
+                    && validate_resumption(config, &make_hello(), &resumedata)
+            })
+    };
+
+    let (kx_group, hello_retry, resumption) = match suite {
+        SupportedCipherSuite::Tls13(_) => {
+            let shares_ext = client_hello
+                .get_keyshare_extension()
+                .ok_or(PeerIncompatible::KeyShareExtensionRequired)?;
+            let (group, hello_retry) =
+                match tls13::choose_key_share(config, groups_ext, shares_ext, false) {
+                    tls13::KeyShareChoice::Accept(share) => (share.group, false),
+                    tls13::KeyShareChoice::Retry { group, .. } => (group, true),
+                    tls13::KeyShareChoice::NoGroupsInCommon => {
+                        return Err(PeerIncompatible::NoKxGroupsInCommon.into());
+                    }
+                };
+
+            // Sessions are looked up without being taken from the store.
+            let resumption = client_hello.psk_mode_offered(PSKKeyExchangeMode::PSK_DHE_KE)
+                && client_hello
+                    .get_psk()
+                    .map_or(false, |offer| {
+                        offer.identities.iter().any(|id| {
+                            let plain = if config.ticketer.enabled() {
+                                config.ticketer.decrypt(&id.identity.0)
+                            } else {
+                                config
+                                    .session_storage
+                                    .get(&id.identity.0)
+                            };
+                            resumable(plain, false)
+                        })
+                    });
+            (group, hello_retry, resumption)
+        }
+        #[cfg(feature = "tls12")]
+        SupportedCipherSuite::Tls12(_) => {
+            let using_ems = client_hello.ems_support_offered();
+            if config.require_ems && !using_ems {
+                return Err(PeerIncompatible::ExtendedMasterSecretExtensionRequired.into());
+            }
+
+            let group = config
+                .kx_groups
+                .iter()
+                .map(|group| group.name())
+                .find(|name| groups_ext.contains(name))
+                .ok_or(PeerIncompatible::NoKxGroupsInCommon)?;
+
+            let plain = match client_hello.get_ticket_extension() {
+                Some(ClientExtension::SessionTicket(ClientSessionTicket::Offer(ticket))) => {
+                    config.ticketer.decrypt(&ticket.0)
+                }
+                _ if !client_hello.session_id.is_empty() => config
+                    .tls12_session_storage()
+                    .get(&client_hello.session_id.get_encoding()),
+                _ => None,
+            };
+            (group, false, resumable(plain, using_ems))
+        }
+    };
+
+    Ok(NegotiationPreview {
+        version,
+        cipher_suite: suite,
+        kx_group,
+        hello_retry,
+        resumption,
+        certified_key,
+    })
+}
+
+/// Checks the server name a client sent against the config's SNI policy.
+fn check_server_name<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    sni: &Option<DnsName>,
+) -> Result<(), PeerIncompatible> {
+    match (sni, &config.server_names) {
+        (None, _) if config.require_sni => Err(PeerIncompatible::ServerNameExtensionRequired),
+        (Some(name), Some(server_names)) if !server_names.accepts(name.as_ref()) => {
+            Err(PeerIncompatible::ServerNameRefused)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks for repeated extensions in a client's hello against `policy`.
+fn check_duplicate_extensions(
+    policy: &DuplicateExtensionPolicy,
+    client_hello: &ClientHelloPayload,
+) -> Result<(), PeerMisbehaved> {
+    if client_hello.has_duplicate_extension()
+        && !policy.tolerates(
+            client_hello
+                .extensions
+                .iter()
+                .map(ClientExtension::get_type),
+        )
+    {
+        return Err(PeerMisbehaved::DuplicateClientHelloExtensions);
+    }
+    Ok(())
+}
+
+/// Chooses the protocol version to use with a client.
+pub(super) fn choose_version<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    client_hello: &ClientHelloPayload,
+    is_quic: bool,
+) -> Result<ProtocolVersion, Error> {
+    let tls13_enabled = config.supports_version(ProtocolVersion::TLSv1_3);
+    let tls12_enabled = config.supports_version(ProtocolVersion::TLSv1_2);
+
+    // Are we doing TLS1.3?
+    let version = if let Some(versions) = client_hello.get_versions_extension() {
+        if versions.contains(&ProtocolVersion::TLSv1_3) && tls13_enabled {
+            ProtocolVersion::TLSv1_3
+        } else if !versions.contains(&ProtocolVersion::TLSv1_2) || !tls12_enabled {
+            return Err(PeerIncompatible::Tls12NotOfferedOrEnabled.into());
+        } else if is_quic {
+            return Err(PeerIncompatible::Tls13RequiredForQuic.into());
+        } else {
+            ProtocolVersion::TLSv1_2
+        }
+    } else if client_hello.client_version.get_u16() < ProtocolVersion::TLSv1_2.get_u16() {
+        return Err(PeerIncompatible::Tls12NotOffered.into());
+    } else if !tls12_enabled && tls13_enabled {
+        return Err(PeerIncompatible::SupportedVersionsExtensionRequired.into());
+    } else if is_quic {
+        return Err(PeerIncompatible::Tls13RequiredForQuic.into());
+    } else {
+        ProtocolVersion::TLSv1_2
+    };
+
+    // RFC 7507: a client signalling a fallback must not end up with
+    // less than our highest version.
+    if version == ProtocolVersion::TLSv1_2
+        && tls13_enabled
+        && client_hello
+            .cipher_suites
+            .contains(&CipherSuite::TLS_FALLBACK_SCSV)
+    {
+        return Err(PeerMisbehaved::InappropriateFallback.into());
+    }
+
+    Ok(version)
+}
+
+/// Reduces the signature schemes offered by a client to those usable with
+/// the cipher suites we have in common, and allowed by `config`.
+pub(super) fn usable_sig_schemes<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    client_hello: &ClientHelloPayload,
+    mut sig_schemes: Vec<SignatureScheme>,
+) -> Vec<SignatureScheme> {
+    // We communicate to the upper layer what kind of key they should choose
+    // via the sigschemes value.  Clients tend to treat this extension
+    // orthogonally to offered ciphersuites (even though, in TLS1.2 it is not).
+    // So: reduce the offered sigschemes to those compatible with the
+    // intersection of ciphersuites.
+    let client_suites = config
+        .cipher_suites
+        .iter()
+        .copied()
+        .filter(|scs| {
+            client_hello
+                .cipher_suites
+                .contains(&scs.suite())
+        })
+        .collect::<Vec<_>>();
+
+    sig_schemes.retain(|scheme| suites::compatible_sigscheme_for_suites(*scheme, &client_suites));
+    verify::allowed_signature_schemes(config.signature_schemes.as_deref(), sig_schemes)
+}
+
+/// Chooses the cipher suite to use with a client, given the algorithm of
+/// the chosen certificate's key.
+pub(super) fn choose_suite<C: CryptoProvider>(
+    config: &ServerConfig<C>,
+    client_hello: &ClientHelloPayload,
+    sig_algorithm: SignatureAlgorithm,
+    version: ProtocolVersion,
+) -> Option<SupportedCipherSuite> {
+    // Reduce our supported ciphersuites by the certificate.
+    // (no-op for TLS1.3)
+    let suitable_suites = suites::reduce_given_sigalg(&config.cipher_suites, sig_algorithm);
+
+    // And version
+    let suitable_suites = suites::reduce_given_version(&suitable_suites, version);
+
+    if config.ignore_client_order {
+        suites::choose_ciphersuite_preferring_server(&client_hello.cipher_suites, &suitable_suites)
+    } else {
+        suites::choose_ciphersuite_preferring_client(&client_hello.cipher_suites, &suitable_suites)
+    }
+}
+
 #[derive(Default)]
 pub(super) struct ExtensionProcessing {
     // extensions to reply with
@@ -68,6 +351,7 @@ impl ExtensionProcessing {
         config: &ServerConfig<C>,
         cx: &mut ServerContext<'_>,
         ocsp_response: &mut Option<&[u8]>,
+        sct_list: &mut Option<&[u8]>,
         hello: &ClientHelloPayload,
         resumedata: Option<&persist::ServerSessionValue>,
         extra_exts: Vec<ServerExtension>,
@@ -156,6 +440,25 @@ impl ExtensionProcessing {
             ocsp_response.take();
         }
 
+        // Likewise for SCTs: TLS1.2 sends them in ServerHello, and TLS1.3
+        // alongside the end-entity certificate.
+        if !for_resume
+            && hello
+                .find_extension(ExtensionType::SCT)
+                .is_some()
+        {
+            if !cx.common.is_tls13() {
+                if let Some(sct_list) = sct_list.take() {
+                    match ServerExtension::make_sct(sct_list) {
+                        Some(ext) => self.exts.push(ext),
+                        None => warn!("Not sending invalid SCT list"),
+                    }
+                }
+            }
+        } else {
+            sct_list.take();
+        }
+
         self.exts.extend(extra_exts);
 
         Ok(())
@@ -170,14 +473,7 @@ impl ExtensionProcessing {
     ) {
         // Renegotiation.
         // (We don't do reneg at all, but would support the secure version if we did.)
-        let secure_reneg_offered = hello
-            .find_extension(ExtensionType::RenegotiationInfo)
-            .is_some()
-            || hello
-                .cipher_suites
-                .contains(&CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
-
-        if secure_reneg_offered {
+        if hello.secure_renegotiation_offered() {
             self.exts
                 .push(ServerExtension::make_empty_renegotiation_info());
         }
@@ -219,7 +515,8 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
     pub(super) fn new(config: Arc<ServerConfig<C>>, extra_exts: Vec<ServerExtension>) -> Self {
         let mut transcript_buffer = HandshakeHashBuffer::new();
 
-        if config.verifier.offer_client_auth() {
+        // A verifier chosen per connection may want client auth.
+        if config.verifier.offer_client_auth() || config.client_auth_resolver.is_some() {
             transcript_buffer.set_client_auth_enabled();
         }
 
@@ -239,119 +536,76 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
     /// Continues handling of a `ClientHello` message once config and certificate are available.
     pub(super) fn with_certified_key(
         self,
-        mut sig_schemes: Vec<SignatureScheme>,
+        sig_schemes: Vec<SignatureScheme>,
         client_hello: &ClientHelloPayload,
         m: &Message,
         cx: &mut ServerContext<'_>,
     ) -> NextStateOrError {
-        let tls13_enabled = self
-            .config
-            .supports_version(ProtocolVersion::TLSv1_3);
-        let tls12_enabled = self
-            .config
-            .supports_version(ProtocolVersion::TLSv1_2);
-
-        // Are we doing TLS1.3?
-        let maybe_versions_ext = client_hello.get_versions_extension();
-        let version = if let Some(versions) = maybe_versions_ext {
-            if versions.contains(&ProtocolVersion::TLSv1_3) && tls13_enabled {
-                ProtocolVersion::TLSv1_3
-            } else if !versions.contains(&ProtocolVersion::TLSv1_2) || !tls12_enabled {
-                return Err(cx.common.send_fatal_alert(
-                    AlertDescription::ProtocolVersion,
-                    PeerIncompatible::Tls12NotOfferedOrEnabled,
-                ));
-            } else if cx.common.is_quic() {
-                return Err(cx.common.send_fatal_alert(
-                    AlertDescription::ProtocolVersion,
-                    PeerIncompatible::Tls13RequiredForQuic,
-                ));
-            } else {
-                ProtocolVersion::TLSv1_2
-            }
-        } else if client_hello.client_version.get_u16() < ProtocolVersion::TLSv1_2.get_u16() {
-            return Err(cx.common.send_fatal_alert(
-                AlertDescription::ProtocolVersion,
-                PeerIncompatible::Tls12NotOffered,
-            ));
-        } else if !tls12_enabled && tls13_enabled {
-            return Err(cx.common.send_fatal_alert(
-                AlertDescription::ProtocolVersion,
-                PeerIncompatible::SupportedVersionsExtensionRequired,
-            ));
-        } else if cx.common.is_quic() {
-            return Err(cx.common.send_fatal_alert(
-                AlertDescription::ProtocolVersion,
-                PeerIncompatible::Tls13RequiredForQuic,
-            ));
-        } else {
-            ProtocolVersion::TLSv1_2
-        };
+        check_server_name(&self.config, &cx.data.sni).map_err(|err| {
+            let description = match err {
+                PeerIncompatible::ServerNameRefused => AlertDescription::UnrecognisedName,
+                _ => AlertDescription::HandshakeFailure,
+            };
+            cx.common
+                .send_fatal_alert(description, err)
+        })?;
 
-        cx.common.negotiated_version = Some(version);
+        let version =
+            choose_version(&self.config, client_hello, cx.common.is_quic()).map_err(|err| {
+                let description = match err {
+                    Error::PeerMisbehaved(_) => AlertDescription::InappropriateFallback,
+                    _ => AlertDescription::ProtocolVersion,
+                };
+                cx.common
+                    .send_fatal_alert(description, err)
+            })?;
 
-        // We communicate to the upper layer what kind of key they should choose
-        // via the sigschemes value.  Clients tend to treat this extension
-        // orthogonally to offered ciphersuites (even though, in TLS1.2 it is not).
-        // So: reduce the offered sigschemes to those compatible with the
-        // intersection of ciphersuites.
-        let client_suites = self
-            .config
-            .cipher_suites
-            .iter()
-            .copied()
-            .filter(|scs| {
-                client_hello
-                    .cipher_suites
-                    .contains(&scs.suite())
-            })
-            .collect::<Vec<_>>();
+        cx.common.negotiated_version = Some(version);
 
-        sig_schemes
-            .retain(|scheme| suites::compatible_sigscheme_for_suites(*scheme, &client_suites));
+        let sig_schemes = usable_sig_schemes(&self.config, client_hello, sig_schemes);
 
-        // Choose a certificate.
-        let certkey = {
+        // Choose a certificate, and how to authenticate the client.
+        let (certkey, client_auth) = {
             let client_hello = ClientHello::new(
                 &cx.data.sni,
                 &sig_schemes,
                 client_hello.get_alpn_extension(),
                 &client_hello.cipher_suites,
-            );
+                client_hello.get_authorities_extension(),
+            )
+            .with_user_data(cx.common.user_data());
+
+            let client_auth = self
+                .config
+                .client_auth_resolver
+                .as_ref()
+                .and_then(|resolver| resolver.resolve(&client_hello))
+                .unwrap_or_else(|| Arc::clone(&self.config.verifier));
 
-            let certkey = self
+            let selection = self
                 .config
                 .cert_resolver
-                .resolve(client_hello);
+                .resolve_with_reason(client_hello);
 
-            certkey.ok_or_else(|| {
+            let selection = selection.ok_or_else(|| {
                 cx.common.send_fatal_alert(
                     AlertDescription::AccessDenied,
                     Error::General("no server certificate chain resolved".to_owned()),
                 )
-            })?
+            })?;
+            debug!("chose server certificate: {:?}", selection.reason);
+            let certkey = Arc::clone(&selection.certified_key);
+            cx.data.cert_selection = Some(Box::new(selection));
+            (certkey, client_auth)
         };
         let certkey = ActiveCertifiedKey::from_certified_key(&certkey);
 
-        // Reduce our supported ciphersuites by the certificate.
-        // (no-op for TLS1.3)
-        let suitable_suites =
-            suites::reduce_given_sigalg(&self.config.cipher_suites, certkey.get_key().algorithm());
-
-        // And version
-        let suitable_suites = suites::reduce_given_version(&suitable_suites, version);
-
-        let suite = if self.config.ignore_client_order {
-            suites::choose_ciphersuite_preferring_server(
-                &client_hello.cipher_suites,
-                &suitable_suites,
-            )
-        } else {
-            suites::choose_ciphersuite_preferring_client(
-                &client_hello.cipher_suites,
-                &suitable_suites,
-            )
-        }
+        let suite = choose_suite(
+            &self.config,
+            client_hello,
+            certkey.get_key().algorithm(),
+            version,
+        )
         .ok_or_else(|| {
             cx.common.send_fatal_alert(
                 AlertDescription::HandshakeFailure,
@@ -363,10 +617,17 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
         cx.common.suite = Some(suite);
 
         // Start handshake hash.
-        let starting_hash = suite.hash_algorithm();
+        let starting_hash = hash::for_transcript::<C>(suite.hash_algorithm()).map_err(|err| {
+            cx.common
+                .send_fatal_alert(AlertDescription::InternalError, err)
+        })?;
         let transcript = match self.transcript {
             HandshakeHashOrBuffer::Buffer(inner) => inner.start_hash(starting_hash),
-            HandshakeHashOrBuffer::Hash(inner) if inner.algorithm() == starting_hash => inner,
+            HandshakeHashOrBuffer::Hash(inner)
+                if inner.algorithm() == starting_hash.algorithm() =>
+            {
+                inner
+            }
             _ => {
                 return Err(cx.common.send_fatal_alert(
                     AlertDescription::IllegalParameter,
@@ -380,6 +641,7 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
         match suite {
             SupportedCipherSuite::Tls13(suite) => tls13::CompleteClientHelloHandling {
                 config: self.config,
+                client_auth,
                 transcript,
                 suite,
                 randoms,
@@ -389,35 +651,75 @@ impl<C: CryptoProvider> ExpectClientHello<C> {
             }
             .handle_client_hello(cx, certkey, m, client_hello, sig_schemes),
             #[cfg(feature = "tls12")]
-            SupportedCipherSuite::Tls12(suite) => tls12::CompleteClientHelloHandling {
-                config: self.config,
-                transcript,
-                session_id: self.session_id,
-                suite,
-                using_ems: self.using_ems,
-                randoms,
-                send_ticket: self.send_tickets > 0,
-                extra_exts: self.extra_exts,
+            SupportedCipherSuite::Tls12(suite) => {
+                let tls13_enabled = self
+                    .config
+                    .supports_version(ProtocolVersion::TLSv1_3);
+                tls12::CompleteClientHelloHandling {
+                    config: self.config,
+                    client_auth,
+                    transcript,
+                    session_id: self.session_id,
+                    suite,
+                    using_ems: self.using_ems,
+                    randoms,
+                    send_ticket: self.send_tickets > 0,
+                    extra_exts: self.extra_exts,
+                }
+                .handle_client_hello(
+                    cx,
+                    certkey,
+                    m,
+                    client_hello,
+                    sig_schemes,
+                    tls13_enabled,
+                )
             }
-            .handle_client_hello(
-                cx,
-                certkey,
-                m,
-                client_hello,
-                sig_schemes,
-                tls13_enabled,
-            ),
         }
     }
 }
 
 impl<C: CryptoProvider> State<ServerConnectionData> for ExpectClientHello<C> {
     fn handle(self: Box<Self>, cx: &mut ServerContext<'_>, m: Message) -> NextStateOrError {
-        let (client_hello, sig_schemes) = process_client_hello(&m, self.done_retry, cx)?;
+        let (client_hello, sig_schemes) =
+            process_client_hello(&m, self.done_retry, &self.config.duplicate_extensions, cx)?;
         self.with_certified_key(sig_schemes, client_hello, &m, cx)
     }
 }
 
+/// Check the certificates of the client's chain that have arrived so far,
+/// if `received` is the start of its `Certificate` message.
+pub(super) fn precheck_partial_chain(
+    common: &mut CommonState,
+    verifier: &dyn ClientCertVerifier,
+    limits: &CertificateChainLimits,
+    cert_stream: &mut CertificateStream,
+    received: &[u8],
+    tls13: bool,
+) -> Result<(), Error> {
+    cert_stream
+        .check_partial(received, tls13, limits, |index, cert| {
+            verifier.precheck_certificate(index, cert)
+        })
+        .map_err(|err| common.send_cert_verify_error_alert(err))
+}
+
+/// Check the certificates of the client's complete `chain` that were not
+/// checked while it was arriving.
+pub(super) fn precheck_chain(
+    common: &mut CommonState,
+    verifier: &dyn ClientCertVerifier,
+    limits: &CertificateChainLimits,
+    cert_stream: &mut CertificateStream,
+    chain: &[Certificate],
+) -> Result<(), Error> {
+    cert_stream
+        .finish(chain, limits, |index, cert| {
+            verifier.precheck_certificate(index, cert)
+        })
+        .map_err(|err| common.send_cert_verify_error_alert(err))
+}
+
 /// Configuration-independent validation of a `ClientHello` message.
 ///
 /// This represents the first part of the `ClientHello` handling, where we do all validation that
@@ -428,6 +730,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectClientHello<C> {
 pub(super) fn process_client_hello<'a>(
     m: &'a Message,
     done_retry: bool,
+    duplicate_extensions: &DuplicateExtensionPolicy,
     cx: &mut ServerContext,
 ) -> Result<(&'a ClientHelloPayload, Vec<SignatureScheme>), Error> {
     let client_hello =
@@ -444,12 +747,10 @@ pub(super) fn process_client_hello<'a>(
         ));
     }
 
-    if client_hello.has_duplicate_extension() {
-        return Err(cx.common.send_fatal_alert(
-            AlertDescription::DecodeError,
-            PeerMisbehaved::DuplicateClientHelloExtensions,
-        ));
-    }
+    check_duplicate_extensions(duplicate_extensions, client_hello).map_err(|err| {
+        cx.common
+            .send_fatal_alert(AlertDescription::DecodeError, err)
+    })?;
 
     // No handshake messages should follow this one in this flight.
     cx.common.check_aligned_handshake()?;
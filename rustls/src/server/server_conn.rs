@@ -1,19 +1,33 @@
+use crate::budget::MemoryBudget;
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
-use crate::common_state::{CommonState, Context, Side, State};
+use crate::capture::MessageDirection;
+use crate::cert_stream::CertificateChainLimits;
+use crate::common_state::{
+    CommonState, Context, DuplicateExtensionPolicy, Negotiated, RecordLimits, RenegotiationPolicy,
+    Side, State,
+};
 use crate::conn::{ConnectionCommon, ConnectionCore};
-use crate::crypto::{CryptoProvider, KeyExchange};
+use crate::crypto::{CryptoProvider, KeyExchange, KeySharePool};
 use crate::dns_name::DnsName;
 use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
 use crate::error::Error;
+#[cfg(feature = "dangerous_configuration")]
+use crate::handover::{self, TrafficSecrets};
+use crate::key;
 #[cfg(feature = "logging")]
-use crate::log::trace;
+use crate::log::{trace, warn};
 use crate::msgs::base::Payload;
+use crate::msgs::enums::NamedGroup;
 use crate::msgs::handshake::{ClientHelloPayload, ProtocolName, ServerExtension};
 use crate::msgs::message::Message;
+use crate::msgs::persist;
+use crate::pacing::WritePacer;
 use crate::sign;
 use crate::suites::SupportedCipherSuite;
+use crate::time_provider::TimeProvider;
 use crate::vecbuf::ChunkVecBuffer;
 use crate::verify;
+use crate::DistinguishedName;
 #[cfg(feature = "secret_extraction")]
 use crate::ExtractedSecrets;
 use crate::KeyLog;
@@ -21,10 +35,14 @@ use crate::KeyLog;
 use super::hs;
 
 use alloc::sync::Arc;
+use core::any::Any;
 use core::fmt;
 use core::marker::PhantomData;
+use core::mem;
 use core::ops::{Deref, DerefMut};
+use core::time::Duration;
 use std::io;
+use std::time::SystemTime;
 
 /// A trait for the ability to store server session data.
 ///
@@ -97,6 +115,283 @@ pub trait ProducesTickets: Send + Sync {
     fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>>;
 }
 
+/// A trait for the ability to remember which TLS1.3 tickets have been used.
+///
+/// See [`ServerConfig::single_use_tickets`].  Tickets are opaque and
+/// attacker-visible: they are not secret, but an implementation shared
+/// between servers must be consistent, or a ticket can be used once with
+/// each of them.
+///
+/// Implementations may forget a ticket once it has outlived
+/// [`ProducesTickets::lifetime`], because the ticketer will no longer
+/// decrypt it.  Forgetting one any sooner allows it to be used again, so
+/// an implementation which runs out of space should refuse new tickets
+/// rather than forget old ones.
+pub trait TracksUsedTickets: Send + Sync {
+    /// Record that `ticket` is being used to resume a session at `now`,
+    /// according to [`ServerConfig::time_provider`].
+    ///
+    /// Returns `false` if it had already been recorded, or cannot be
+    /// recorded, in which case the ticket is rejected and a full handshake
+    /// follows.
+    fn mark_used(&self, ticket: &[u8], now: SystemTime) -> bool;
+}
+
+/// Receives notifications about the lifecycle of resumable sessions.
+///
+/// This is intended for monitoring: for example, counting how often clients
+/// come back with a ticket this server can't use.  Every method has a default
+/// implementation that does nothing.  They are called from within the
+/// handshake, so should return promptly.
+///
+/// Here "ticket" covers both stateless tickets from [`ServerConfig::ticketer`]
+/// and session ids or TLS1.3 tickets that refer to [`ServerConfig::session_storage`].
+pub trait ObservesServerResumption: Send + Sync {
+    /// A ticket for a session of protocol `version` was issued to the client.
+    ///
+    /// The server name is the SNI the client sent, if any.
+    fn ticket_issued(&self, _server_name: Option<&str>, _version: ProtocolVersion) {}
+
+    /// A ticket offered by the client was accepted, and the session resumed.
+    fn ticket_redeemed(&self, _server_name: Option<&str>, _version: ProtocolVersion) {}
+
+    /// A ticket offered by the client could not be used, so a full
+    /// handshake follows.
+    fn ticket_rejected(
+        &self,
+        _server_name: Option<&str>,
+        _version: ProtocolVersion,
+        _reason: TicketRejected,
+    ) {
+    }
+}
+
+/// Why a ticket offered by a client was not redeemed.
+///
+/// See [`ObservesServerResumption::ticket_rejected`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TicketRejected {
+    /// The ticket did not decrypt, or its session is not (or no longer) in
+    /// the session store.
+    ///
+    /// Clients that support TLS1.3 may send a random session id for
+    /// middlebox compatibility, so an unknown session id from such a client
+    /// is not reported.
+    Unrecognised,
+    /// The session was found, but cannot resume this connection: its cipher
+    /// suite, server name or extended master secret use differs.
+    Incompatible,
+    /// The ticket had been used before, or could not be recorded as used,
+    /// and [`ServerConfig::single_use_tickets`] is configured.
+    Reused,
+    /// The session was refused by [`ServerConfig::resumption_validator`].
+    Refused,
+}
+
+/// A session that a client is attempting to resume.
+///
+/// This is recovered from the ticket or session id the client sent: when
+/// tickets come from [`ServerConfig::ticketer`], everything here travelled
+/// (encrypted) in the ticket, so is available to any server sharing the
+/// ticket keys.
+pub struct ResumingSession<'a> {
+    value: &'a persist::ServerSessionValue,
+}
+
+impl<'a> ResumingSession<'a> {
+    pub(super) fn new(value: &'a persist::ServerSessionValue) -> Self {
+        Self { value }
+    }
+
+    /// The server name the client sent when the session was established.
+    pub fn server_name(&self) -> Option<&str> {
+        self.value
+            .sni
+            .as_ref()
+            .map(|name| name.as_ref())
+    }
+
+    /// The protocol version of the session.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.value.version
+    }
+
+    /// The cipher suite of the session.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.value.cipher_suite
+    }
+
+    /// The protocol agreed via ALPN when the session was established.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.value
+            .alpn
+            .as_ref()
+            .map(|protocol| protocol.0.as_slice())
+    }
+
+    /// The certificate chain the client authenticated with, if any.
+    pub fn peer_certificates(&self) -> Option<&[key::Certificate]> {
+        self.value.client_cert_chain.as_deref()
+    }
+
+    /// The application data embedded in the ticket.
+    ///
+    /// See [`ProducesResumptionData`].
+    pub fn resumption_data(&self) -> &[u8] {
+        &self.value.application_data.0
+    }
+
+    /// When the session was established, in seconds since the Unix epoch.
+    pub fn creation_time_sec(&self) -> u64 {
+        self.value.creation_time_sec
+    }
+}
+
+/// Decides whether a client may resume a session.
+///
+/// This is consulted once rustls has found a session which could resume
+/// the connection: its cipher suite and server name are compatible, and
+/// for TLS1.3 the client has proved it knows the session's secret.  It
+/// allows applications to enforce their own constraints, for example on
+/// the resumption data or the age of the session.
+pub trait ValidatesResumption: Send + Sync {
+    /// Return true to resume `session` for a client that sent `client_hello`.
+    ///
+    /// Returning false causes a full handshake, and is reported to
+    /// [`ServerConfig::resumption_observer`] as [`TicketRejected::Refused`].
+    fn validate(&self, client_hello: &ClientHello<'_>, session: &ResumingSession<'_>) -> bool;
+}
+
+/// Decides which server names, sent by clients via SNI, a server accepts.
+///
+/// [`ServerNameAllowList`] is a suitable implementation for a fixed set of
+/// names.
+///
+/// [`ServerNameAllowList`]: crate::server::ServerNameAllowList
+pub trait AcceptsServerName: Send + Sync {
+    /// Return true to continue a handshake for `server_name`.
+    ///
+    /// `server_name` is a valid DNS name, in lowercase.  Returning false
+    /// fails the handshake with an `unrecognized_name` alert, before a
+    /// certificate is chosen.
+    fn accepts(&self, server_name: &str) -> bool;
+}
+
+/// How a TLS1.3 server chooses between the key shares a client sent and
+/// asking for another with a `HelloRetryRequest`.
+///
+/// A `HelloRetryRequest` costs a round trip, so by default one is sent only
+/// when the client sent no key share the server can use.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct HelloRetryPolicy {
+    /// Whether to accept a key share for any of the server's key exchange
+    /// groups, as set with [`ConfigBuilder::with_kx_groups`].
+    ///
+    /// If this is false, the server sends a `HelloRetryRequest` unless the
+    /// client sent a key share for the group that would be requested (see
+    /// `retry_groups`), even if it sent a share for another supported group.
+    /// The default is true.
+    ///
+    /// [`ConfigBuilder::with_kx_groups`]: crate::ConfigBuilder::with_kx_groups
+    pub accept_any_key_share: bool,
+
+    /// The groups to request in a `HelloRetryRequest`, most preferred first.
+    ///
+    /// The first of these supported by both the client and the server's
+    /// key exchange groups is requested.  If there is none, or this is empty
+    /// (the default), the server's own order of groups is used.
+    pub retry_groups: Vec<NamedGroup>,
+}
+
+impl Default for HelloRetryPolicy {
+    fn default() -> Self {
+        Self {
+            accept_any_key_share: true,
+            retry_groups: Vec::new(),
+        }
+    }
+}
+
+/// Decides whether a TLS1.3 server may send a `HelloRetryRequest`.
+///
+/// This is called before each `HelloRetryRequest` would be sent, so is also
+/// a convenient place to count them.  See [`HelloRetryCounter`] for an
+/// implementation.
+///
+/// [`HelloRetryCounter`]: crate::server::HelloRetryCounter
+pub trait LimitsHelloRetries: Send + Sync {
+    /// Return true to send a `HelloRetryRequest` asking for `group`.
+    ///
+    /// `server_name` is the SNI the client sent, if any.  If this returns
+    /// false the handshake continues with a key share the client did send,
+    /// if the server supports any, and otherwise fails.
+    fn permit(&self, server_name: Option<&str>, group: NamedGroup) -> bool;
+}
+
+/// Decides which early data may be processed before the handshake completes.
+///
+/// Early data can be replayed by an attacker, so [RFC 8470] asks HTTP
+/// servers to only act on requests that are safe to replay (such as `GET`
+/// without side effects) before the handshake completes.  Set an
+/// implementation as [`ServerConfig::early_data_gate`] and
+/// [`ServerConnection::early_data`] only yields early data it found safe.
+/// The rest is held back, then delivered through the connection's normal
+/// reader, ahead of any later data, once the handshake completes.
+///
+/// [RFC 8470]: https://datatracker.ietf.org/doc/html/rfc8470
+pub trait GatesEarlyData: Send + Sync {
+    /// Classify the start of `data`, the early data received so far that
+    /// has not yet been classified.
+    ///
+    /// Return [`EarlyDataClass::Safe`] with the length of a safe prefix
+    /// (for example, some complete idempotent requests); the remainder is
+    /// offered again, with anything received later appended.  A length of
+    /// zero means more data is needed to decide.  Once this returns
+    /// [`EarlyDataClass::Unsafe`] it is not called again for the
+    /// connection, and all remaining early data is held back.
+    fn classify(&self, data: &[u8]) -> EarlyDataClass;
+}
+
+/// The verdict of [`GatesEarlyData::classify`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EarlyDataClass {
+    /// This many leading bytes may be processed straight away.
+    Safe(usize),
+    /// The data starts with something that must wait for the handshake
+    /// to complete.
+    Unsafe,
+}
+
+/// Supplies application data to embed in resumption tickets.
+///
+/// The data is recovered when a client resumes with the ticket, and is
+/// then available from [`ServerConnection::received_resumption_data`].
+/// Its integrity is assured by rustls, but it is visible to the client:
+/// encrypt it separately if secrecy is needed.
+///
+/// Here "ticket" has the same meaning as for [`ObservesServerResumption`].
+pub trait ProducesResumptionData: Send + Sync {
+    /// Return the data to embed in a ticket about to be issued.
+    ///
+    /// `server_name` and `alpn_protocol` are those negotiated for the
+    /// connection.  `received` is the data carried by the ticket this
+    /// connection resumed with, if any.
+    ///
+    /// Return `None` to use the data given to
+    /// [`ServerConnection::set_resumption_data`].  Data must be less than
+    /// 2^15 bytes long; longer data is ignored, as if `None` were returned.
+    fn resumption_data(
+        &self,
+        _server_name: Option<&str>,
+        _alpn_protocol: Option<&[u8]>,
+        _received: Option<&[u8]>,
+    ) -> Option<Vec<u8>> {
+        None
+    }
+}
+
 /// How to choose a certificate chain and signing key for use
 /// in server authentication.
 pub trait ResolvesServerCert: Send + Sync {
@@ -105,6 +400,79 @@ pub trait ResolvesServerCert: Send + Sync {
     ///
     /// Return `None` to abort the handshake.
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>>;
+
+    /// Like [`ResolvesServerCert::resolve`], but also say why the
+    /// certificate was chosen.  The result is available from
+    /// [`ServerConnection::cert_selection`].
+    ///
+    /// Resolvers holding several certificates should implement this.  The
+    /// default implementation calls `resolve` and gives
+    /// [`CertSelectionReason::Unspecified`].
+    fn resolve_with_reason(&self, client_hello: ClientHello) -> Option<CertSelection> {
+        self.resolve(client_hello)
+            .map(|certified_key| CertSelection {
+                certified_key,
+                reason: CertSelectionReason::Unspecified,
+            })
+    }
+}
+
+/// The certificate a [`ResolvesServerCert`] chose for a connection, and why.
+///
+/// This is intended for logging, to help explain which certificate a
+/// client was sent.
+#[derive(Clone)]
+pub struct CertSelection {
+    /// The certificate chain and key chosen.
+    pub certified_key: Arc<sign::CertifiedKey>,
+
+    /// Why it was chosen.
+    pub reason: CertSelectionReason,
+}
+
+impl fmt::Debug for CertSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertSelection")
+            .field("end_entity", &self.certified_key.cert.first())
+            .field("reason", &self.reason)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Why a [`ResolvesServerCert`] chose the certificate it did.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CertSelectionReason {
+    /// The certificate is the one for the server name the client sent.
+    ServerName,
+
+    /// The certificate's key can make a signature using a scheme the client
+    /// supports, where other candidates' keys could not.
+    SignatureScheme,
+
+    /// The resolver used its default certificate, either because it has
+    /// only one or because no other candidate suited the client.
+    Fallback,
+
+    /// The resolver did not say why.
+    Unspecified,
+}
+
+/// How to choose the client authentication policy for a connection,
+/// given simplified ClientHello information.
+///
+/// This allows one [`ServerConfig`] to require client certificates for
+/// some server names, accept them optionally for others, and not ask for
+/// them at all for the rest.  See [`ServerConfig::client_auth_resolver`].
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+#[cfg_attr(not(feature = "dangerous_configuration"), allow(unreachable_pub))]
+pub trait ResolvesClientCertVerifier: Send + Sync {
+    /// Choose the verifier for this connection.
+    ///
+    /// Whether client authentication is requested at all, and whether it is
+    /// mandatory, is up to the verifier returned.  Return `None` to use
+    /// the verifier the [`ServerConfig`] was built with.
+    fn resolve(&self, client_hello: &ClientHello) -> Option<Arc<dyn verify::ClientCertVerifier>>;
 }
 
 /// A struct representing the received Client Hello
@@ -113,6 +481,8 @@ pub struct ClientHello<'a> {
     signature_schemes: &'a [SignatureScheme],
     alpn: Option<&'a Vec<ProtocolName>>,
     cipher_suites: &'a [CipherSuite],
+    certificate_authorities: Option<&'a [DistinguishedName]>,
+    user_data: Option<&'a (dyn Any + Send + Sync)>,
 }
 
 impl<'a> ClientHello<'a> {
@@ -122,20 +492,29 @@ impl<'a> ClientHello<'a> {
         signature_schemes: &'a [SignatureScheme],
         alpn: Option<&'a Vec<ProtocolName>>,
         cipher_suites: &'a [CipherSuite],
+        certificate_authorities: Option<&'a [DistinguishedName]>,
     ) -> Self {
         trace!("sni {:?}", server_name);
         trace!("sig schemes {:?}", signature_schemes);
         trace!("alpn protocols {:?}", alpn);
         trace!("cipher suites {:?}", cipher_suites);
+        trace!("certificate authorities {:?}", certificate_authorities);
 
         ClientHello {
             server_name,
             signature_schemes,
             alpn,
             cipher_suites,
+            certificate_authorities,
+            user_data: None,
         }
     }
 
+    pub(super) fn with_user_data(mut self, user_data: Option<&'a (dyn Any + Send + Sync)>) -> Self {
+        self.user_data = user_data;
+        self
+    }
+
     /// Get the server name indicator.
     ///
     /// Returns `None` if the client did not supply a SNI.
@@ -145,6 +524,14 @@ impl<'a> ClientHello<'a> {
             .map(<DnsName as AsRef<str>>::as_ref)
     }
 
+    /// Get the value attached to the connection with [`CommonState::set_user_data`]
+    /// (or [`Accepted::set_user_data`]), if any.
+    ///
+    /// [`CommonState::set_user_data`]: crate::CommonState::set_user_data
+    pub fn user_data(&self) -> Option<&'a (dyn Any + Send + Sync)> {
+        self.user_data
+    }
+
     /// Get the compatible signature schemes.
     ///
     /// Returns standard-specified default if the client omitted this extension.
@@ -181,6 +568,16 @@ impl<'a> ClientHello<'a> {
     pub fn cipher_suites(&self) -> &[CipherSuite] {
         self.cipher_suites
     }
+
+    /// Get the distinguished names of the certificate authorities the
+    /// client trusts, from its `certificate_authorities` extension.
+    ///
+    /// Returns `None` if the client did not send this extension.  A
+    /// [`ResolvesServerCert`] with several certificate chains can use these
+    /// to choose one the client can validate.
+    pub fn certificate_authorities(&self) -> Option<&'a [DistinguishedName]> {
+        self.certificate_authorities
+    }
 }
 
 /// Common configuration for a set of server sessions.
@@ -207,6 +604,13 @@ pub struct ServerConfig<C: CryptoProvider> {
     /// offered to the client in this order.
     pub(super) kx_groups: Vec<&'static <C::KeyExchange as KeyExchange>::SupportedGroup>,
 
+    /// Key shares generated ahead of time, to take from instead of
+    /// generating one during each handshake.  Shares are only taken for
+    /// groups in `kx_groups`.
+    ///
+    /// The default is `None`.
+    pub key_share_pool: Option<Arc<KeySharePool<C::KeyExchange>>>,
+
     /// Ignore the client's ciphersuite order. Instead,
     /// choose the top ciphersuite in the server list
     /// which is supported by the client.
@@ -224,20 +628,143 @@ pub struct ServerConfig<C: CryptoProvider> {
     /// How to store client sessions.
     pub session_storage: Arc<dyn StoresServerSessions + Send + Sync>,
 
+    /// How to store TLS1.2 sessions for resumption by session id, if not in
+    /// [`ServerConfig::session_storage`].
+    ///
+    /// This controls session-id resumption independently of tickets and
+    /// TLS1.3.  Set it to [`NoServerSessionStorage`] to turn session ids
+    /// off -- no session id is issued, and those offered by clients are
+    /// not looked up -- or to a [`ServerSessionMemoryCache`] of its own
+    /// size to cap how many are kept.
+    ///
+    /// The default is `None`: TLS1.2 sessions are kept in
+    /// [`ServerConfig::session_storage`].
+    ///
+    /// [`NoServerSessionStorage`]: crate::server::NoServerSessionStorage
+    /// [`ServerSessionMemoryCache`]: crate::server::ServerSessionMemoryCache
+    pub tls12_session_storage: Option<Arc<dyn StoresServerSessions + Send + Sync>>,
+
     /// How to produce tickets.
     pub ticketer: Arc<dyn ProducesTickets>,
 
+    /// Where to record which TLS1.3 tickets from [`ServerConfig::ticketer`]
+    /// have been used, so each can resume at most one connection.
+    ///
+    /// Stateless tickets can otherwise be used any number of times until
+    /// they expire.  Enforcing single use means a stolen ticket cannot be
+    /// replayed once its legitimate owner has used it, and brings stateless
+    /// tickets in line with those from [`ServerConfig::session_storage`],
+    /// which are always single-use in TLS1.3.  A ticket is only recorded
+    /// once its binder has been checked.  TLS1.2 tickets are unaffected.
+    ///
+    /// [`UsedTicketMemoryCache`] is a suitable implementation for a single
+    /// server.  The default is `None`: tickets may be reused.
+    ///
+    /// [`UsedTicketMemoryCache`]: crate::server::UsedTicketMemoryCache
+    pub single_use_tickets: Option<Arc<dyn TracksUsedTickets>>,
+
+    /// How to protect the cookie sent in a TLS1.3 `HelloRetryRequest`.
+    ///
+    /// When this is enabled, the cookie carries (encrypted) everything the
+    /// server needs to continue the handshake after the retry, so the second
+    /// `ClientHello` can be handled by a brand new [`ServerConnection`] --
+    /// the one which sent the `HelloRetryRequest` can be dropped as soon as
+    /// it has been written out.  A new connection that receives a second
+    /// `ClientHello` whose cookie does not decrypt, or which was issued more
+    /// than 30 seconds ago according to [`ServerConfig::time_provider`],
+    /// aborts the handshake.
+    ///
+    /// [`Ticketer`] is a suitable implementation; use a separate instance
+    /// from [`ServerConfig::ticketer`].  The default never produces cookies,
+    /// so the `HelloRetryRequest` has to be followed up by the same
+    /// connection.
+    ///
+    /// [`Ticketer`]: crate::Ticketer
+    pub hello_retry_cookies: Arc<dyn ProducesTickets>,
+
+    /// Notified as tickets are issued, redeemed and rejected.
+    ///
+    /// The default does nothing.
+    pub resumption_observer: Arc<dyn ObservesServerResumption>,
+
+    /// Supplies the application data embedded in issued tickets.
+    ///
+    /// The default uses the data given to
+    /// [`ServerConnection::set_resumption_data`].
+    pub resumption_data: Arc<dyn ProducesResumptionData>,
+
+    /// Decides whether each session offered by a client may be resumed.
+    ///
+    /// The default is `None`: any compatible session is resumed.
+    pub resumption_validator: Option<Arc<dyn ValidatesResumption>>,
+
     /// How to choose a server cert and key.
     pub cert_resolver: Arc<dyn ResolvesServerCert>,
 
+    /// Whether to refuse clients that do not send a server name via SNI.
+    ///
+    /// Handshakes with such clients fail with a `handshake_failure` alert,
+    /// before a certificate is chosen.  The default is false.
+    pub require_sni: bool,
+
+    /// Decides which server names sent via SNI are acceptable.
+    ///
+    /// This allows virtual hosting to deny unknown names up front, rather
+    /// than when [`ServerConfig::cert_resolver`] finds no certificate.
+    /// Clients that send no server name are not affected: see
+    /// [`ServerConfig::require_sni`] for those.
+    ///
+    /// The default is `None`: any server name is accepted.
+    pub server_names: Option<Arc<dyn AcceptsServerName>>,
+
+    /// How to choose the client certificate verifier for each connection,
+    /// based on its `ClientHello`.
+    ///
+    /// Implementing a resolver requires the `dangerous_configuration`
+    /// feature.  The default is `None`: every connection uses the verifier
+    /// given when building this config.  The same can be achieved by choosing
+    /// between several configs with an [`Acceptor`]; this avoids needing
+    /// a config per client authentication policy.
+    pub client_auth_resolver: Option<Arc<dyn ResolvesClientCertVerifier>>,
+
+    /// The distinguished names of certificate authorities sent to the client
+    /// when requesting client authentication.
+    ///
+    /// Clients use these to choose which certificate to present: the
+    /// TLS1.2 `certificate_authorities` field and the TLS1.3
+    /// `certificate_authorities` extension of `CertificateRequest`.
+    ///
+    /// The default is `None`: send the names given by the client
+    /// certificate verifier, usually the subjects of its trust anchors.
+    /// An empty list sends no names, which hides which authorities this
+    /// server trusts.
+    pub client_auth_root_hints: Option<Vec<DistinguishedName>>,
+
     /// Protocol names we support, most preferred first.
     /// If empty we don't do ALPN at all.
     pub alpn_protocols: Vec<Vec<u8>>,
 
+    /// How to choose between accepting a TLS1.3 key share and sending a
+    /// `HelloRetryRequest`.
+    pub hello_retry: HelloRetryPolicy,
+
+    /// Decides whether each `HelloRetryRequest` may be sent.
+    ///
+    /// The default is `None`: they are always allowed.
+    pub hello_retry_limit: Option<Arc<dyn LimitsHelloRetries>>,
+
     /// Supported protocol versions, in no particular order.
     /// The default is all supported versions.
     pub(super) versions: crate::versions::EnabledVersions,
 
+    /// The signature schemes allowed with peers, or `None` if any scheme
+    /// supported by the verifier and signing keys may be used.
+    ///
+    /// See [`ConfigBuilder::with_signature_schemes`].
+    ///
+    /// [`ConfigBuilder::with_signature_schemes`]: crate::ConfigBuilder::with_signature_schemes
+    pub(super) signature_schemes: Option<Vec<SignatureScheme>>,
+
     /// How to verify client certificates.
     pub(super) verifier: Arc<dyn verify::ClientCertVerifier>,
 
@@ -245,6 +772,10 @@ pub struct ServerConfig<C: CryptoProvider> {
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
 
+    /// Where to get the current time from.  The default asks the
+    /// operating system.
+    pub time_provider: Arc<dyn TimeProvider>,
+
     /// Allows traffic secrets to be extracted after the handshake,
     /// e.g. for kTLS setup.
     #[cfg(feature = "secret_extraction")]
@@ -264,6 +795,12 @@ pub struct ServerConfig<C: CryptoProvider> {
     /// expansion in the latter case.
     pub max_early_data_size: u32,
 
+    /// Decides which early data [`ServerConnection::early_data`] yields
+    /// before the handshake completes; see [`GatesEarlyData`].
+    ///
+    /// The default is `None`: all accepted early data is yielded.
+    pub early_data_gate: Option<Arc<dyn GatesEarlyData>>,
+
     /// Whether the server should send "0.5RTT" data.  This means the server
     /// sends data after its first flight of handshake messages, without
     /// waiting for the client to complete the handshake.
@@ -297,6 +834,115 @@ pub struct ServerConfig<C: CryptoProvider> {
     /// do any resumption.
     pub send_tls13_tickets: usize,
 
+    /// Whether to send a dummy `ChangeCipherSpec` message after the
+    /// first TLS 1.3 handshake message, as part of "middlebox compatibility
+    /// mode" described in [RFC 8446 appendix D.4].
+    ///
+    /// Turning this off saves a few bytes per handshake, but may
+    /// cause handshake failures on networks with middleboxes that
+    /// inspect TLS.  The server's `legacy_session_id_echo` always
+    /// mirrors the client, so clients control the other half of
+    /// compatibility mode.  This has no effect for QUIC.
+    ///
+    /// The default is true.
+    ///
+    /// [RFC 8446 appendix D.4]: https://datatracker.ietf.org/doc/html/rfc8446#appendix-D.4
+    pub enable_middlebox_compat: bool,
+
+    /// Whether connections made with this config record every handshake
+    /// message they send and receive, for later retrieval with
+    /// [`CommonState::handshake_capture`].
+    ///
+    /// This is intended for debugging: the record can be decoded and attached
+    /// to a bug report without needing a packet capture and key log.  Note
+    /// that it contains everything the peer sent, including certificates.
+    ///
+    /// The default is false.
+    ///
+    /// [`CommonState::handshake_capture`]: crate::CommonState::handshake_capture
+    pub capture_handshake: bool,
+
+    /// How long a handshake may take, from when the connection is created.
+    ///
+    /// For connections made with an [`Acceptor`], this is measured from
+    /// [`Accepted::into_connection`]: time spent reading the `ClientHello`
+    /// beforehand is not counted.
+    ///
+    /// A connection still handshaking after this long fails with
+    /// [`Error::HandshakeTimedOut`] the next time
+    /// [`Connection::process_new_packets`] is called.  Since nothing happens
+    /// until then, use [`CommonState::handshake_deadline`] to know when to
+    /// call it if the peer has gone quiet.
+    ///
+    /// The default is `None`: handshakes may take any amount of time.
+    ///
+    /// [`Error::HandshakeTimedOut`]: crate::Error::HandshakeTimedOut
+    /// [`Connection::process_new_packets`]: crate::Connection::process_new_packets
+    /// [`CommonState::handshake_deadline`]: crate::CommonState::handshake_deadline
+    pub handshake_timeout: Option<Duration>,
+
+    /// Whether to refuse TLS1.2 handshakes with clients that do not offer
+    /// the extended master secret extension of [RFC 7627].
+    ///
+    /// Without it, a TLS1.2 session's master secret is not bound to its
+    /// handshake, which allows the "triple handshake" attack.  This has no
+    /// effect on TLS1.3, whose key schedule always includes the handshake.
+    /// Use [`CommonState::extended_master_secret`] to see whether a
+    /// connection used it.
+    ///
+    /// The default is false.
+    ///
+    /// [RFC 7627]: https://datatracker.ietf.org/doc/html/rfc7627
+    /// [`CommonState::extended_master_secret`]: crate::CommonState::extended_master_secret
+    pub require_ems: bool,
+
+    /// How to respond when the client asks to renegotiate an established
+    /// TLS1.2 connection.  Use [`CommonState::secure_renegotiation`] to see
+    /// whether the client supports secure renegotiation.
+    ///
+    /// The default is [`RenegotiationPolicy::Reject`].
+    ///
+    /// [`CommonState::secure_renegotiation`]: crate::CommonState::secure_renegotiation
+    pub renegotiation: RenegotiationPolicy,
+
+    /// Limits on records from the peer that do not move the connection
+    /// forward, such as warning alerts and empty records.
+    ///
+    /// The default has no limits; [`RecordLimits::hardened`] is a good
+    /// choice when peers are untrusted.
+    pub record_limits: RecordLimits,
+
+    /// Limits on the certificate chain the client may send, which are
+    /// enforced as the chain arrives.
+    ///
+    /// The default has no limits beyond the size of a handshake message.
+    pub cert_chain_limits: CertificateChainLimits,
+
+    /// What to do if the client repeats an extension in a handshake message.
+    ///
+    /// The default is [`DuplicateExtensionPolicy::Reject`].
+    ///
+    /// A `ClientHello` read by an [`Acceptor`] has already been checked
+    /// against the acceptor's policy, so this applies to it only if it is
+    /// repeated after a `HelloRetryRequest`.  See
+    /// [`Acceptor::with_duplicate_extensions`].
+    pub duplicate_extensions: DuplicateExtensionPolicy,
+
+    /// A cap on memory used by connections made from this config (and any
+    /// others sharing the same budget); see [`MemoryBudget`].
+    ///
+    /// The default is `None`: memory use is not tracked.
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+
+    /// Paces how much application data connections made from this config
+    /// encrypt between calls to [`Connection::write_tls`]; see [`WritePacer`].
+    ///
+    /// The default is `None`: data is encrypted as fast as it is written,
+    /// up to the buffer limit.
+    ///
+    /// [`Connection::write_tls`]: crate::Connection::write_tls
+    pub write_pacer: Option<Arc<dyn WritePacer>>,
+
     pub(crate) provider: PhantomData<C>,
 }
 
@@ -306,20 +952,46 @@ impl<C: CryptoProvider> Clone for ServerConfig<C> {
         Self {
             cipher_suites: self.cipher_suites.clone(),
             kx_groups: self.kx_groups.clone(),
+            key_share_pool: self.key_share_pool.clone(),
             ignore_client_order: self.ignore_client_order,
             max_fragment_size: self.max_fragment_size,
             session_storage: Arc::clone(&self.session_storage),
+            tls12_session_storage: self.tls12_session_storage.clone(),
             ticketer: Arc::clone(&self.ticketer),
+            single_use_tickets: self.single_use_tickets.clone(),
+            hello_retry_cookies: Arc::clone(&self.hello_retry_cookies),
+            resumption_observer: Arc::clone(&self.resumption_observer),
+            resumption_data: Arc::clone(&self.resumption_data),
+            resumption_validator: self.resumption_validator.clone(),
             cert_resolver: Arc::clone(&self.cert_resolver),
+            require_sni: self.require_sni,
+            server_names: self.server_names.clone(),
+            client_auth_resolver: self.client_auth_resolver.clone(),
+            client_auth_root_hints: self.client_auth_root_hints.clone(),
             alpn_protocols: self.alpn_protocols.clone(),
+            hello_retry: self.hello_retry.clone(),
+            hello_retry_limit: self.hello_retry_limit.clone(),
             versions: self.versions,
+            signature_schemes: self.signature_schemes.clone(),
             verifier: Arc::clone(&self.verifier),
             key_log: Arc::clone(&self.key_log),
+            time_provider: Arc::clone(&self.time_provider),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: self.enable_secret_extraction,
             max_early_data_size: self.max_early_data_size,
+            early_data_gate: self.early_data_gate.clone(),
             send_half_rtt_data: self.send_half_rtt_data,
             send_tls13_tickets: self.send_tls13_tickets,
+            enable_middlebox_compat: self.enable_middlebox_compat,
+            capture_handshake: self.capture_handshake,
+            handshake_timeout: self.handshake_timeout,
+            require_ems: self.require_ems,
+            renegotiation: self.renegotiation,
+            record_limits: self.record_limits,
+            cert_chain_limits: self.cert_chain_limits,
+            duplicate_extensions: self.duplicate_extensions.clone(),
+            memory_budget: self.memory_budget.clone(),
+            write_pacer: self.write_pacer.clone(),
             provider: PhantomData,
         }
     }
@@ -330,10 +1002,21 @@ impl<C: CryptoProvider> fmt::Debug for ServerConfig<C> {
         f.debug_struct("ServerConfig")
             .field("ignore_client_order", &self.ignore_client_order)
             .field("max_fragment_size", &self.max_fragment_size)
+            .field("client_auth_root_hints", &self.client_auth_root_hints)
             .field("alpn_protocols", &self.alpn_protocols)
+            .field("require_sni", &self.require_sni)
+            .field("hello_retry", &self.hello_retry)
             .field("max_early_data_size", &self.max_early_data_size)
             .field("send_half_rtt_data", &self.send_half_rtt_data)
             .field("send_tls13_tickets", &self.send_tls13_tickets)
+            .field("enable_middlebox_compat", &self.enable_middlebox_compat)
+            .field("capture_handshake", &self.capture_handshake)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("require_ems", &self.require_ems)
+            .field("renegotiation", &self.renegotiation)
+            .field("record_limits", &self.record_limits)
+            .field("cert_chain_limits", &self.cert_chain_limits)
+            .field("duplicate_extensions", &self.duplicate_extensions)
             .finish_non_exhaustive()
     }
 }
@@ -349,6 +1032,15 @@ impl<C: CryptoProvider> ServerConfig<C> {
         }
     }
 
+    /// Where TLS1.2 sessions are stored for resumption by session id.
+    #[cfg(feature = "tls12")]
+    pub(crate) fn tls12_session_storage(&self) -> &dyn StoresServerSessions {
+        match &self.tls12_session_storage {
+            Some(storage) => storage.as_ref(),
+            None => self.session_storage.as_ref(),
+        }
+    }
+
     /// We support a given TLS version if it's quoted in the configured
     /// versions *and* at least one ciphersuite for this version is
     /// also configured.
@@ -359,6 +1051,18 @@ impl<C: CryptoProvider> ServerConfig<C> {
                 .iter()
                 .any(|cs| cs.version().version == v)
     }
+
+    /// The names to send in `CertificateRequest`, when `client_auth`
+    /// is the verifier for this connection.
+    pub(crate) fn root_hints_for<'a>(
+        &'a self,
+        client_auth: &'a dyn verify::ClientCertVerifier,
+    ) -> &'a [DistinguishedName] {
+        match &self.client_auth_root_hints {
+            Some(hints) => hints,
+            None => client_auth.client_auth_root_subjects(),
+        }
+    }
 }
 
 /// Allows reading of early data in resumed TLS1.3 connections.
@@ -429,9 +1133,39 @@ impl ServerConnection {
         self.inner.core.get_sni_str()
     }
 
+    /// The certificate chosen by [`ServerConfig::cert_resolver`] for this
+    /// connection, and why.
+    ///
+    /// This is `None` until the certificate is chosen.  A certificate is
+    /// chosen even if the handshake goes on to resume a session, in which
+    /// case it is not sent.
+    pub fn cert_selection(&self) -> Option<&CertSelection> {
+        self.inner
+            .core
+            .data
+            .cert_selection
+            .as_deref()
+    }
+
+    /// Retrieves what the handshake has negotiated so far, including the
+    /// server name.
+    ///
+    /// See [`Negotiated`] for more information.
+    pub fn negotiated(&self) -> Negotiated<'_> {
+        Negotiated {
+            server_name: self.server_name(),
+            ..self
+                .inner
+                .core
+                .common_state
+                .negotiated()
+        }
+    }
+
     /// Application-controlled portion of the resumption ticket supplied by the client, if any.
     ///
-    /// Recovered from the prior session's `set_resumption_data`. Integrity is guaranteed by rustls.
+    /// Recovered from the prior session's `set_resumption_data`, or from
+    /// [`ServerConfig::resumption_data`]. Integrity is guaranteed by rustls.
     ///
     /// Returns `Some` iff a valid resumption ticket has been received from the client.
     pub fn received_resumption_data(&self) -> Option<&[u8]> {
@@ -490,6 +1224,70 @@ impl ServerConnection {
     pub fn extract_secrets(self) -> Result<ExtractedSecrets, Error> {
         self.inner.extract_secrets()
     }
+
+    /// Export this connection, so that it can be carried on elsewhere -- in
+    /// another process, for example -- with [`ServerConnection::dangerous_import`].
+    ///
+    /// This is only possible once the handshake is complete, and not for
+    /// QUIC.  The result includes any plaintext and TLS data buffered by the
+    /// connection, so the transport must be handed over with it, and nothing
+    /// more read from or written to it here.
+    ///
+    /// The result contains the connection's traffic secrets: anyone who
+    /// obtains it can read and forge the connection's data.  Protect it
+    /// accordingly, and never import it more than once.
+    #[cfg(feature = "dangerous_configuration")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+    pub fn dangerous_export(self) -> Result<Vec<u8>, Error> {
+        let sni = self.server_name().map(str::to_owned);
+        handover::export(self.inner.core, sni.as_deref())
+    }
+
+    /// Carry on a connection exported by [`ServerConnection::dangerous_export`].
+    ///
+    /// `config` must enable the connection's cipher suite.  The client is
+    /// not authenticated again: that happened in the original handshake.
+    #[cfg(feature = "dangerous_configuration")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+    pub fn dangerous_import<C: CryptoProvider>(
+        config: Arc<ServerConfig<C>>,
+        exported: &[u8],
+    ) -> Result<Self, Error> {
+        let core = handover::import::<C, _>(
+            exported,
+            Side::Server,
+            &config.cipher_suites,
+            |common, sni, secrets| {
+                common.set_max_fragment_size(config.max_fragment_size)?;
+                common.renegotiation_policy = config.renegotiation;
+                #[cfg(feature = "secret_extraction")]
+                {
+                    common.enable_secret_extraction = config.enable_secret_extraction;
+                }
+                let sni = sni
+                    .map(|name| DnsName::try_from_ascii(name.as_bytes()))
+                    .transpose()
+                    .map_err(|_| Error::General("invalid exported connection".into()))?;
+                let state = match secrets {
+                    #[cfg(feature = "tls12")]
+                    TrafficSecrets::Tls12(secrets) => {
+                        super::tls12::restore_traffic(secrets, common)
+                    }
+                    TrafficSecrets::Tls13(secrets) => {
+                        super::tls13::restore_traffic::<C>(secrets, common)?
+                    }
+                };
+                Ok((
+                    state,
+                    ServerConnectionData {
+                        sni,
+                        ..Default::default()
+                    },
+                ))
+            },
+        )?;
+        Ok(Self { inner: core.into() })
+    }
 }
 
 impl fmt::Debug for ServerConnection {
@@ -519,6 +1317,49 @@ impl From<ServerConnection> for crate::Connection {
     }
 }
 
+/// What a server would negotiate with a client.
+///
+/// See [`Accepted::preview`].
+#[non_exhaustive]
+pub struct NegotiationPreview {
+    /// The protocol version.
+    pub version: ProtocolVersion,
+
+    /// The cipher suite.
+    pub cipher_suite: SupportedCipherSuite,
+
+    /// The key exchange group.
+    ///
+    /// For TLS1.3 this is the group of the key share used, or requested in a
+    /// `HelloRetryRequest`.
+    pub kx_group: NamedGroup,
+
+    /// Whether a TLS1.3 `HelloRetryRequest` would be sent.
+    ///
+    /// [`ServerConfig::hello_retry_limit`] is not consulted.
+    pub hello_retry: bool,
+
+    /// Whether a session offered by the client would be resumed.
+    ///
+    /// [`ServerConfig::single_use_tickets`] is not consulted.
+    pub resumption: bool,
+
+    /// The certificate chain and key chosen by [`ServerConfig::cert_resolver`].
+    pub certified_key: Arc<sign::CertifiedKey>,
+}
+
+impl fmt::Debug for NegotiationPreview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NegotiationPreview")
+            .field("version", &self.version)
+            .field("cipher_suite", &self.cipher_suite)
+            .field("kx_group", &self.kx_group)
+            .field("hello_retry", &self.hello_retry)
+            .field("resumption", &self.resumption)
+            .finish_non_exhaustive()
+    }
+}
+
 /// Handle on a server-side connection before configuration is available.
 ///
 /// `Acceptor` allows the caller to choose a [`ServerConfig`] after reading
@@ -529,7 +1370,9 @@ impl From<ServerConnection> for crate::Connection {
 /// and don't want to use the blocking interface provided by
 /// [`ResolvesServerCert`].
 ///
-/// Create an Acceptor with [`Acceptor::default()`].
+/// Create an Acceptor with [`Acceptor::default()`], or with
+/// [`Acceptor::with_duplicate_extensions()`] to tolerate repeated extensions
+/// in the `ClientHello`.
 ///
 /// # Example
 ///
@@ -565,11 +1408,26 @@ impl From<ServerConnection> for crate::Connection {
 //// ```
 pub struct Acceptor {
     inner: Option<ConnectionCommon<ServerConnectionData>>,
+    duplicate_extensions: DuplicateExtensionPolicy,
 }
 
 impl Default for Acceptor {
     /// Return an empty Acceptor, ready to receive bytes from a new client connection.
+    ///
+    /// A `ClientHello` that repeats an extension is rejected.
     fn default() -> Self {
+        Self::with_duplicate_extensions(DuplicateExtensionPolicy::Reject)
+    }
+}
+
+impl Acceptor {
+    /// Return an empty Acceptor that applies `policy` to repeated extensions
+    /// in the `ClientHello`.
+    ///
+    /// The `ClientHello` is checked before [`Acceptor::accept()`] returns it,
+    /// so before any [`ServerConfig`] is chosen.  A server that sets
+    /// [`ServerConfig::duplicate_extensions`] should use the same policy here.
+    pub fn with_duplicate_extensions(policy: DuplicateExtensionPolicy) -> Self {
         Self {
             inner: Some(
                 ConnectionCore::new(
@@ -579,11 +1437,10 @@ impl Default for Acceptor {
                 )
                 .into(),
             ),
+            duplicate_extensions: policy,
         }
     }
-}
 
-impl Acceptor {
     /// Read TLS content from `rd`.
     ///
     /// Returns an error if this `Acceptor` has already yielded an [`Accepted`]. For more details,
@@ -625,8 +1482,12 @@ impl Acceptor {
             }
         };
 
-        let (_, sig_schemes) =
-            hs::process_client_hello(&message, false, &mut Context::from(&mut connection))?;
+        let (_, sig_schemes) = hs::process_client_hello(
+            &message,
+            false,
+            &self.duplicate_extensions,
+            &mut Context::from(&mut connection),
+        )?;
 
         Ok(Some(Accepted {
             connection,
@@ -654,6 +1515,54 @@ impl Accepted {
             &self.sig_schemes,
             payload.get_alpn_extension(),
             &payload.cipher_suites,
+            payload.get_authorities_extension(),
+        )
+        .with_user_data(self.connection.user_data())
+    }
+
+    /// Attach an application-defined value to the connection.
+    ///
+    /// This is available to the callbacks made by [`Accepted::into_connection`],
+    /// and afterwards.  See [`CommonState::set_user_data`] for details.
+    ///
+    /// [`CommonState::set_user_data`]: crate::CommonState::set_user_data
+    pub fn set_user_data(&mut self, data: Box<dyn Any + Send + Sync>) {
+        self.connection.set_user_data(data);
+    }
+
+    /// Report what would be negotiated if this connection continued with `config`.
+    ///
+    /// This makes the same choices as [`Accepted::into_connection`] would,
+    /// including applying [`ServerConfig::require_sni`] and
+    /// [`ServerConfig::server_names`] and calling
+    /// [`ServerConfig::cert_resolver`] and
+    /// [`ServerConfig::resumption_validator`], and returns the error the
+    /// handshake would fail with if no choice is possible.  Otherwise it has
+    /// no effect: no keys are generated and sessions stay in
+    /// [`ServerConfig::session_storage`].
+    ///
+    /// Policies that record what they decide are not consulted, because
+    /// doing so would count against the real handshake:
+    ///
+    /// - [`ServerConfig::hello_retry_limit`]: `hello_retry` reports whether
+    ///   a `HelloRetryRequest` is wanted, even if the limit would refuse it.
+    /// - [`ServerConfig::single_use_tickets`]: `resumption` can be `true`
+    ///   for a ticket that has already been used, and would be rejected.
+    /// - [`ServerConfig::resumption_observer`] is not notified.
+    ///
+    /// The handshake can also still turn out differently for other reasons:
+    /// for example, a TLS1.3 ticket's binder could be wrong, or the handshake
+    /// could fail after the `ClientHello`.
+    pub fn preview<C: CryptoProvider>(
+        &self,
+        config: &ServerConfig<C>,
+    ) -> Result<NegotiationPreview, Error> {
+        hs::preview(
+            config,
+            Self::client_hello_payload(&self.message),
+            self.sig_schemes.clone(),
+            &self.connection.core.data.sni,
+            self.connection.user_data(),
         )
     }
 
@@ -668,12 +1577,32 @@ impl Accepted {
     ) -> Result<ServerConnection, Error> {
         self.connection
             .set_max_fragment_size(config.max_fragment_size)?;
+        self.connection.renegotiation_policy = config.renegotiation;
 
         #[cfg(feature = "secret_extraction")]
         {
             self.connection.enable_secret_extraction = config.enable_secret_extraction;
         }
 
+        if config.capture_handshake {
+            // The ClientHello was read before we knew whether to capture it.
+            self.connection
+                .enable_handshake_capture(Arc::clone(&config.time_provider));
+            self.connection
+                .capture_handshake_message(MessageDirection::Received, &self.message);
+        }
+
+        self.connection
+            .set_handshake_timeout(config.handshake_timeout, &config.time_provider)?;
+        self.connection
+            .set_record_limits(config.record_limits);
+        self.connection.set_memory_budget(
+            config.memory_budget.as_ref(),
+            ConnectionCore::<ServerConnectionData>::base_size(),
+        )?;
+        self.connection
+            .set_write_pacer(config.write_pacer.as_ref());
+
         let state = hs::ExpectClientHello::new(config, Vec::new());
         let mut cx = hs::ServerContext::from(&mut self.connection);
 
@@ -717,6 +1646,7 @@ impl State<ServerConnectionData> for Accepting {
 pub(super) enum EarlyDataState {
     New,
     Accepted(ChunkVecBuffer),
+    Gated(Box<GatedEarlyData>),
     Rejected,
 }
 
@@ -731,30 +1661,47 @@ impl EarlyDataState {
         *self = Self::Rejected;
     }
 
-    pub(super) fn accept(&mut self, max_size: usize) {
-        *self = Self::Accepted(ChunkVecBuffer::new(Some(max_size)));
+    pub(super) fn accept(&mut self, max_size: usize, gate: Option<Arc<dyn GatesEarlyData>>) {
+        let received = ChunkVecBuffer::new(Some(max_size));
+        *self = match gate {
+            Some(gate) => Self::Gated(Box::new(GatedEarlyData {
+                gate,
+                received,
+                held: Vec::new(),
+                deferring: false,
+            })),
+            None => Self::Accepted(received),
+        };
     }
 
     fn was_accepted(&self) -> bool {
-        matches!(self, Self::Accepted(_))
+        matches!(self, Self::Accepted(_) | Self::Gated(_))
     }
 
     pub(super) fn was_rejected(&self) -> bool {
         matches!(self, Self::Rejected)
     }
 
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    fn received(&mut self) -> Option<&mut ChunkVecBuffer> {
         match self {
-            Self::Accepted(ref mut received) => received.read(buf),
-            _ => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            Self::Accepted(ref mut received) => Some(received),
+            Self::Gated(ref mut gated) => Some(&mut gated.received),
+            _ => None,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.received() {
+            Some(received) => received.read(buf),
+            None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
         }
     }
 
     #[cfg(read_buf)]
     fn read_buf(&mut self, cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
-        match self {
-            Self::Accepted(ref mut received) => received.read_buf(cursor),
-            _ => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        match self.received() {
+            Some(received) => received.read_buf(cursor),
+            None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
         }
     }
 
@@ -765,9 +1712,62 @@ impl EarlyDataState {
                 received.append(bytes.0);
                 true
             }
+            Self::Gated(ref mut gated) => gated.take(bytes.0),
             _ => false,
         }
     }
+
+    /// Early data that was held back by [`ServerConfig::early_data_gate`],
+    /// now that the handshake is complete.
+    pub(super) fn take_deferred(&mut self) -> Option<Vec<u8>> {
+        match self {
+            Self::Gated(ref mut gated) if !gated.held.is_empty() => {
+                Some(mem::take(&mut gated.held))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Accepted early data, sorted by [`ServerConfig::early_data_gate`].
+pub(super) struct GatedEarlyData {
+    gate: Arc<dyn GatesEarlyData>,
+    /// Early data found to be safe.
+    received: ChunkVecBuffer,
+    /// Early data not yet found to be safe.
+    held: Vec<u8>,
+    /// Whether unsafe early data has been found, so everything from
+    /// then on waits for the handshake to complete.
+    deferring: bool,
+}
+
+impl GatedEarlyData {
+    fn take(&mut self, bytes: Vec<u8>) -> bool {
+        let available = self.held.len() + bytes.len();
+        if self.received.apply_limit(available) != available {
+            return false;
+        }
+
+        match self.held.is_empty() {
+            true => self.held = bytes,
+            false => self.held.extend_from_slice(&bytes),
+        }
+
+        while !self.deferring && !self.held.is_empty() {
+            match self.gate.classify(&self.held) {
+                EarlyDataClass::Safe(0) => break,
+                EarlyDataClass::Safe(len) => {
+                    let rest = self
+                        .held
+                        .split_off(len.min(self.held.len()));
+                    self.received
+                        .append(mem::replace(&mut self.held, rest));
+                }
+                EarlyDataClass::Unsafe => self.deferring = true,
+            }
+        }
+        true
+    }
 }
 
 // these branches not reachable externally, unless something else goes wrong.
@@ -803,6 +1803,14 @@ impl ConnectionCore<ServerConnectionData> {
         {
             common.enable_secret_extraction = config.enable_secret_extraction;
         }
+        common.renegotiation_policy = config.renegotiation;
+        if config.capture_handshake {
+            common.enable_handshake_capture(Arc::clone(&config.time_provider));
+        }
+        common.set_handshake_timeout(config.handshake_timeout, &config.time_provider)?;
+        common.set_record_limits(config.record_limits);
+        common.set_memory_budget(config.memory_budget.as_ref(), Self::base_size())?;
+        common.set_write_pacer(config.write_pacer.as_ref());
         Ok(Self::new(
             Box::new(hs::ExpectClientHello::new(config, extra_exts)),
             ServerConnectionData::default(),
@@ -830,12 +1838,33 @@ pub struct ServerConnectionData {
     pub(super) received_resumption_data: Option<Vec<u8>>,
     pub(super) resumption_data: Vec<u8>,
     pub(super) early_data: EarlyDataState,
+    pub(super) cert_selection: Option<Box<CertSelection>>,
 }
 
 impl ServerConnectionData {
     pub(super) fn get_sni_str(&self) -> Option<&str> {
         self.sni.as_ref().map(AsRef::as_ref)
     }
+
+    /// The application data to embed in a ticket issued now.
+    pub(super) fn ticket_resumption_data(
+        &self,
+        producer: &dyn ProducesResumptionData,
+        alpn_protocol: Option<&[u8]>,
+    ) -> Vec<u8> {
+        match producer.resumption_data(
+            self.get_sni_str(),
+            alpn_protocol,
+            self.received_resumption_data.as_deref(),
+        ) {
+            Some(data) if data.len() < 2usize.pow(15) => data,
+            Some(_) => {
+                warn!("ignoring oversized resumption data");
+                self.resumption_data.clone()
+            }
+            None => self.resumption_data.clone(),
+        }
+    }
 }
 
 impl crate::conn::SideData for ServerConnectionData {}
@@ -1,10 +1,13 @@
+use crate::cert_stream::CertificateStream;
 use crate::check::inappropriate_message;
 use crate::common_state::{CommonState, Side, State};
 use crate::conn::ConnectionRandoms;
-use crate::crypto::CryptoProvider;
+use crate::crypto::{hmac, CryptoProvider};
 use crate::enums::ProtocolVersion;
 use crate::enums::{AlertDescription, ContentType, HandshakeType};
 use crate::error::{Error, PeerIncompatible, PeerMisbehaved};
+#[cfg(feature = "dangerous_configuration")]
+use crate::handover::TrafficSecrets;
 use crate::hash_hs::HandshakeHash;
 use crate::key::Certificate;
 #[cfg(feature = "logging")]
@@ -19,12 +22,16 @@ use crate::msgs::persist;
 use crate::rand::GetRandomFailed;
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
+use crate::time_provider;
 use crate::tls12::{self, ConnectionSecrets, Tls12CipherSuite};
+use crate::verify::ClientCertVerifier;
 use crate::{ticketer, verify};
 
 use super::common::ActiveCertifiedKey;
 use super::hs::{self, ServerContext};
-use super::server_conn::{ProducesTickets, ServerConfig, ServerConnectionData};
+use super::server_conn::{
+    ProducesResumptionData, ServerConfig, ServerConnectionData, TicketRejected,
+};
 
 use subtle::ConstantTimeEq;
 
@@ -33,7 +40,7 @@ use alloc::sync::Arc;
 pub(super) use client_hello::CompleteClientHelloHandling;
 
 mod client_hello {
-    use crate::crypto::{KeyExchange, SupportedGroup};
+    use crate::crypto::{self, KeyExchange, SupportedGroup};
     use crate::enums::SignatureScheme;
     use crate::msgs::enums::ECPointFormat;
     use crate::msgs::enums::NamedGroup;
@@ -51,6 +58,7 @@ mod client_hello {
 
     pub(in crate::server) struct CompleteClientHelloHandling<C: CryptoProvider> {
         pub(in crate::server) config: Arc<ServerConfig<C>>,
+        pub(in crate::server) client_auth: Arc<dyn ClientCertVerifier>,
         pub(in crate::server) transcript: HandshakeHash,
         pub(in crate::server) session_id: SessionId,
         pub(in crate::server) suite: &'static Tls12CipherSuite,
@@ -75,7 +83,14 @@ mod client_hello {
 
             if client_hello.ems_support_offered() {
                 self.using_ems = true;
+            } else if self.config.require_ems {
+                return Err(cx.common.send_fatal_alert(
+                    AlertDescription::HandshakeFailure,
+                    PeerIncompatible::ExtendedMasterSecretExtensionRequired,
+                ));
             }
+            cx.common.using_ems = self.using_ems;
+            cx.common.secure_renegotiation = client_hello.secure_renegotiation_offered();
 
             let groups_ext = client_hello
                 .get_namedgroups_extension()
@@ -147,13 +162,52 @@ mod client_hello {
                     }
 
                     self.config
-                        .session_storage
+                        .tls12_session_storage()
                         .get(&client_hello.session_id.get_encoding())
                 })
-                .and_then(|x| persist::ServerSessionValue::read_bytes(&x).ok())
-                .filter(|resumedata| {
-                    hs::can_resume(self.suite.into(), &cx.data.sni, self.using_ems, resumedata)
-                });
+                .and_then(|x| persist::ServerSessionValue::read_bytes(&x).ok());
+
+            // Clients that support TLS1.3 send a random session id for middlebox
+            // compatibility, so only report session ids from TLS1.2-only clients
+            // as rejected.
+            let offered = ticket_received
+                || (!client_hello.session_id.is_empty()
+                    && !client_hello
+                        .get_versions_extension()
+                        .map_or(false, |versions| {
+                            versions.contains(&ProtocolVersion::TLSv1_3)
+                        }));
+            let resume_data = match resume_data {
+                Some(resumedata)
+                    if !hs::can_resume(
+                        self.suite.into(),
+                        &cx.data.sni,
+                        self.using_ems,
+                        &resumedata,
+                    ) =>
+                {
+                    self.reject_ticket(cx, TicketRejected::Incompatible);
+                    None
+                }
+                Some(resumedata)
+                    if !hs::resumption_permitted(
+                        &self.config,
+                        cx,
+                        client_hello,
+                        &sigschemes_ext,
+                        &resumedata,
+                    ) =>
+                {
+                    self.reject_ticket(cx, TicketRejected::Refused);
+                    None
+                }
+                Some(resumedata) => Some(resumedata),
+                None if offered => {
+                    self.reject_ticket(cx, TicketRejected::Unrecognised);
+                    None
+                }
+                None => None,
+            };
 
             if let Some(data) = resume_data {
                 return self.start_resumption(cx, client_hello, &client_hello.session_id, data);
@@ -199,9 +253,14 @@ mod client_hello {
             debug_assert_eq!(ecpoint, ECPointFormat::Uncompressed);
 
             let mut ocsp_response = server_key.get_ocsp();
+            let mut sct_list = server_key.get_sct_list();
 
             // If we're not offered a ticket or a potential session ID, allocate a session ID.
-            if !self.config.session_storage.can_cache() {
+            if !self
+                .config
+                .tls12_session_storage()
+                .can_cache()
+            {
                 self.session_id = SessionId::empty();
             } else if self.session_id.is_empty() && !ticket_received {
                 self.session_id = SessionId::random::<C>()?;
@@ -215,6 +274,7 @@ mod client_hello {
                 self.suite,
                 self.using_ems,
                 &mut ocsp_response,
+                &mut sct_list,
                 client_hello,
                 None,
                 &self.randoms,
@@ -233,12 +293,14 @@ mod client_hello {
                 server_key.get_key(),
                 &self.randoms,
             )?;
-            let doing_client_auth = emit_certificate_req(&self.config, &mut self.transcript, cx)?;
+            let doing_client_auth =
+                emit_certificate_req(&self.config, &*self.client_auth, &mut self.transcript, cx)?;
             emit_server_hello_done(&mut self.transcript, cx.common);
 
             if doing_client_auth {
                 Ok(Box::new(ExpectCertificate {
                     config: self.config,
+                    client_auth: self.client_auth,
                     transcript: self.transcript,
                     randoms: self.randoms,
                     session_id: self.session_id,
@@ -246,10 +308,12 @@ mod client_hello {
                     using_ems: self.using_ems,
                     server_kx,
                     send_ticket: self.send_ticket,
+                    cert_stream: CertificateStream::default(),
                 }))
             } else {
                 Ok(Box::new(ExpectClientKx {
                     config: self.config,
+                    client_auth: self.client_auth,
                     transcript: self.transcript,
                     randoms: self.randoms,
                     session_id: self.session_id,
@@ -262,6 +326,12 @@ mod client_hello {
             }
         }
 
+        fn reject_ticket(&self, cx: &ServerContext<'_>, reason: TicketRejected) {
+            self.config
+                .resumption_observer
+                .ticket_rejected(cx.data.get_sni_str(), ProtocolVersion::TLSv1_2, reason);
+        }
+
         fn start_resumption(
             mut self,
             cx: &mut ServerContext<'_>,
@@ -278,6 +348,12 @@ mod client_hello {
                 ));
             }
 
+            self.config
+                .resumption_observer
+                .ticket_redeemed(cx.data.get_sni_str(), ProtocolVersion::TLSv1_2);
+            cx.common.resumed = true;
+            cx.data.received_resumption_data = Some(resumedata.application_data.0.clone());
+
             self.session_id = *id;
             self.send_ticket = emit_server_hello(
                 &self.config,
@@ -287,15 +363,21 @@ mod client_hello {
                 self.suite,
                 self.using_ems,
                 &mut None,
+                &mut None,
                 client_hello,
                 Some(&resumedata),
                 &self.randoms,
                 self.extra_exts,
             )?;
 
+            let hmac = hmac::for_suite::<C>(self.suite.hash_algorithm()).map_err(|err| {
+                cx.common
+                    .send_fatal_alert(AlertDescription::InternalError, err)
+            })?;
             let secrets = ConnectionSecrets::new_resume(
                 self.randoms,
                 self.suite,
+                hmac,
                 &resumedata.master_secret.0,
             );
             self.config.key_log.log(
@@ -313,7 +395,7 @@ mod client_hello {
                     &mut self.transcript,
                     self.using_ems,
                     cx,
-                    &*self.config.ticketer,
+                    &self.config,
                 )?;
             }
             emit_ccs(cx.common);
@@ -342,13 +424,22 @@ mod client_hello {
         suite: &'static Tls12CipherSuite,
         using_ems: bool,
         ocsp_response: &mut Option<&[u8]>,
+        sct_list: &mut Option<&[u8]>,
         hello: &ClientHelloPayload,
         resumedata: Option<&persist::ServerSessionValue>,
         randoms: &ConnectionRandoms,
         extra_exts: Vec<ServerExtension>,
     ) -> Result<bool, Error> {
         let mut ep = hs::ExtensionProcessing::new();
-        ep.process_common(config, cx, ocsp_response, hello, resumedata, extra_exts)?;
+        ep.process_common(
+            config,
+            cx,
+            ocsp_response,
+            sct_list,
+            hello,
+            resumedata,
+            extra_exts,
+        )?;
         ep.process_tls12(config, hello, using_ems);
 
         let sh = Message {
@@ -413,7 +504,8 @@ mod client_hello {
         signing_key: &dyn sign::SigningKey,
         randoms: &ConnectionRandoms,
     ) -> Result<C::KeyExchange, Error> {
-        let kx = match <<C as CryptoProvider>::KeyExchange as KeyExchange>::start(
+        let kx = match crypto::start_kx(
+            config.key_share_pool.as_deref(),
             selected_group,
             &config.kx_groups,
         ) {
@@ -422,6 +514,7 @@ mod client_hello {
                 return Err(GetRandomFailed.into());
             }
         };
+        common.kx_group = Some(selected_group);
         let secdh = ServerECDHParams::new(selected_group, kx.pub_key());
 
         let mut msg = Vec::new();
@@ -455,20 +548,22 @@ mod client_hello {
 
     fn emit_certificate_req<C: CryptoProvider>(
         config: &ServerConfig<C>,
+        client_auth: &dyn ClientCertVerifier,
         transcript: &mut HandshakeHash,
         cx: &mut ServerContext<'_>,
     ) -> Result<bool, Error> {
-        let client_auth = &config.verifier;
-
         if !client_auth.offer_client_auth() {
+            transcript.abandon_client_auth();
             return Ok(false);
         }
 
-        let verify_schemes = client_auth.supported_verify_schemes();
+        let verify_schemes = verify::allowed_signature_schemes(
+            config.signature_schemes.as_deref(),
+            client_auth.supported_verify_schemes(),
+        );
 
         let names = config
-            .verifier
-            .client_auth_root_subjects()
+            .root_hints_for(client_auth)
             .to_vec();
 
         let cr = CertificateRequestPayload {
@@ -511,6 +606,7 @@ mod client_hello {
 // --- Process client's Certificate for client auth ---
 struct ExpectCertificate<C: CryptoProvider> {
     config: Arc<ServerConfig<C>>,
+    client_auth: Arc<dyn ClientCertVerifier>,
     transcript: HandshakeHash,
     randoms: ConnectionRandoms,
     session_id: SessionId,
@@ -518,6 +614,7 @@ struct ExpectCertificate<C: CryptoProvider> {
     using_ems: bool,
     server_kx: C::KeyExchange,
     send_ticket: bool,
+    cert_stream: CertificateStream,
 }
 
 impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
@@ -528,12 +625,16 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
             HandshakeType::Certificate,
             HandshakePayload::Certificate
         )?;
+        hs::precheck_chain(
+            cx.common,
+            &*self.client_auth,
+            &self.config.cert_chain_limits,
+            &mut self.cert_stream,
+            &cert_chain,
+        )?;
 
         // If we can't determine if the auth is mandatory, abort
-        let mandatory = self
-            .config
-            .verifier
-            .client_auth_mandatory();
+        let mandatory = self.client_auth.client_auth_mandatory();
 
         trace!("certs {:?}", cert_chain);
 
@@ -550,10 +651,21 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
                 None
             }
             Some((end_entity, intermediates)) => {
-                let now = std::time::SystemTime::now();
-                self.config
-                    .verifier
-                    .verify_client_cert(end_entity, intermediates, now)
+                let now = time_provider::current_time(&*self.config.time_provider)?;
+                self.client_auth
+                    .verify_client_cert_with_user_data(
+                        end_entity,
+                        intermediates,
+                        now,
+                        cx.common.user_data(),
+                    )
+                    .and_then(|_| {
+                        verify::check_certificate_signatures(
+                            self.config.signature_schemes.as_deref(),
+                            end_entity,
+                            intermediates,
+                        )
+                    })
                     .map_err(|err| {
                         cx.common
                             .send_cert_verify_error_alert(err)
@@ -565,6 +677,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
 
         Ok(Box::new(ExpectClientKx {
             config: self.config,
+            client_auth: self.client_auth,
             transcript: self.transcript,
             randoms: self.randoms,
             session_id: self.session_id,
@@ -575,11 +688,27 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
             send_ticket: self.send_ticket,
         }))
     }
+
+    fn handle_partial_handshake(
+        &mut self,
+        cx: &mut ServerContext<'_>,
+        received: &[u8],
+    ) -> Result<(), Error> {
+        hs::precheck_partial_chain(
+            cx.common,
+            &*self.client_auth,
+            &self.config.cert_chain_limits,
+            &mut self.cert_stream,
+            received,
+            false,
+        )
+    }
 }
 
 // --- Process client's KeyExchange ---
 struct ExpectClientKx<C: CryptoProvider> {
     config: Arc<ServerConfig<C>>,
+    client_auth: Arc<dyn ClientCertVerifier>,
     transcript: HandshakeHash,
     randoms: ConnectionRandoms,
     session_id: SessionId,
@@ -606,12 +735,17 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectClientKx<C> {
         // resulting premaster secret.
         let peer_kx_params =
             tls12::decode_ecdh_params::<ClientECDHParams>(cx.common, &client_kx.0)?;
+        let hmac = hmac::for_suite::<C>(self.suite.hash_algorithm()).map_err(|err| {
+            cx.common
+                .send_fatal_alert(AlertDescription::InternalError, err)
+        })?;
         let secrets = ConnectionSecrets::from_key_exchange(
             self.server_kx,
             &peer_kx_params.public.0,
             ems_seed,
             self.randoms,
             self.suite,
+            hmac,
         )?;
 
         self.config.key_log.log(
@@ -625,6 +759,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectClientKx<C> {
         if let Some(client_cert) = self.client_cert {
             Ok(Box::new(ExpectCertificateVerify {
                 config: self.config,
+                client_auth: self.client_auth,
                 secrets,
                 transcript: self.transcript,
                 session_id: self.session_id,
@@ -649,6 +784,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectClientKx<C> {
 // --- Process client's certificate proof ---
 struct ExpectCertificateVerify<C: CryptoProvider> {
     config: Arc<ServerConfig<C>>,
+    client_auth: Arc<dyn ClientCertVerifier>,
     secrets: ConnectionSecrets,
     transcript: HandshakeHash,
     session_id: SessionId,
@@ -669,9 +805,14 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificateVerify<
             match self.transcript.take_handshake_buf() {
                 Some(msgs) => {
                     let certs = &self.client_cert;
-                    self.config
-                        .verifier
-                        .verify_tls12_signature(&msgs, &certs[0], sig)
+                    verify::check_signature_scheme(
+                        self.config.signature_schemes.as_deref(),
+                        sig.scheme,
+                    )
+                    .and_then(|()| {
+                        self.client_auth
+                            .verify_tls12_signature(&msgs, &certs[0], sig)
+                    })
                 }
                 None => {
                     // This should be unreachable; the handshake buffer was initialized with
@@ -756,6 +897,7 @@ fn get_server_connection_value_tls12(
     secrets: &ConnectionSecrets,
     using_ems: bool,
     cx: &ServerContext<'_>,
+    resumption_data: &dyn ProducesResumptionData,
     time_now: ticketer::TimeBase,
 ) -> persist::ServerSessionValue {
     let version = ProtocolVersion::TLSv1_2;
@@ -768,7 +910,8 @@ fn get_server_connection_value_tls12(
         secret,
         cx.common.peer_certificates.clone(),
         cx.common.alpn_protocol.clone(),
-        cx.data.resumption_data.clone(),
+        cx.data
+            .ticket_resumption_data(resumption_data, cx.common.alpn_protocol.as_deref()),
         time_now,
         0,
     );
@@ -785,17 +928,30 @@ fn emit_ticket(
     transcript: &mut HandshakeHash,
     using_ems: bool,
     cx: &mut ServerContext<'_>,
-    ticketer: &dyn ProducesTickets,
+    config: &ServerConfig<impl CryptoProvider>,
 ) -> Result<(), Error> {
-    let time_now = ticketer::TimeBase::now()?;
-    let plain = get_server_connection_value_tls12(secrets, using_ems, cx, time_now).get_encoding();
+    let time_now = ticketer::TimeBase::from_provider(&*config.time_provider)?;
+    let plain = get_server_connection_value_tls12(
+        secrets,
+        using_ems,
+        cx,
+        &*config.resumption_data,
+        time_now,
+    )
+    .get_encoding();
 
     // If we can't produce a ticket for some reason, we can't
     // report an error. Send an empty one.
-    let ticket = ticketer
+    let ticket = config
+        .ticketer
         .encrypt(&plain)
         .unwrap_or_default();
-    let ticket_lifetime = ticketer.lifetime();
+    let ticket_lifetime = config.ticketer.lifetime();
+    if !ticket.is_empty() {
+        config
+            .resumption_observer
+            .ticket_issued(cx.data.get_sni_str(), ProtocolVersion::TLSv1_2);
+    }
 
     let m = Message {
         version: ProtocolVersion::TLSv1_2,
@@ -875,16 +1031,24 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
 
         // Save connection, perhaps
         if !self.resuming && !self.session_id.is_empty() {
-            let time_now = ticketer::TimeBase::now()?;
-            let value =
-                get_server_connection_value_tls12(&self.secrets, self.using_ems, cx, time_now);
+            let time_now = ticketer::TimeBase::from_provider(&*self.config.time_provider)?;
+            let value = get_server_connection_value_tls12(
+                &self.secrets,
+                self.using_ems,
+                cx,
+                &*self.config.resumption_data,
+                time_now,
+            );
 
             let worked = self
                 .config
-                .session_storage
+                .tls12_session_storage()
                 .put(self.session_id.get_encoding(), value.get_encoding());
             if worked {
                 debug!("Session saved");
+                self.config
+                    .resumption_observer
+                    .ticket_issued(cx.data.get_sni_str(), ProtocolVersion::TLSv1_2);
             } else {
                 debug!("Session not saved");
             }
@@ -899,7 +1063,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
                     &mut self.transcript,
                     self.using_ems,
                     cx,
-                    &*self.config.ticketer,
+                    &self.config,
                 )?;
             }
             emit_ccs(cx.common);
@@ -957,4 +1121,28 @@ impl State<ServerConnectionData> for ExpectTraffic {
         self.secrets
             .extract_secrets(Side::Server)
     }
+
+    #[cfg(feature = "dangerous_configuration")]
+    fn export_traffic_secrets(self: Box<Self>) -> Result<TrafficSecrets, Error> {
+        Ok(TrafficSecrets::Tls12(self.secrets))
+    }
+}
+
+/// Carry on an exported connection, whose keys are `secrets`.
+#[cfg(feature = "dangerous_configuration")]
+pub(super) fn restore_traffic(
+    secrets: ConnectionSecrets,
+    common: &mut CommonState,
+) -> Box<dyn State<ServerConnectionData>> {
+    let (dec, enc) = secrets.make_cipher_pair(Side::Server);
+    common
+        .record_layer
+        .set_message_encrypter(enc);
+    common
+        .record_layer
+        .set_message_decrypter(dec);
+    Box::new(ExpectTraffic {
+        secrets,
+        _fin_verified: verify::FinishedMessageVerified::assertion(),
+    })
 }
@@ -1,3 +1,4 @@
+use crate::cert_stream::CertificateStream;
 use crate::check::inappropriate_handshake_message;
 #[cfg(feature = "quic")]
 use crate::check::inappropriate_message;
@@ -11,6 +12,8 @@ use crate::crypto::CryptoProvider;
 use crate::enums::ProtocolVersion;
 use crate::enums::{AlertDescription, ContentType, HandshakeType};
 use crate::error::{Error, PeerIncompatible, PeerMisbehaved};
+#[cfg(feature = "dangerous_configuration")]
+use crate::handover::{Tls13TrafficSecrets, TrafficSecrets};
 use crate::hash_hs::HandshakeHash;
 use crate::key::Certificate;
 #[cfg(feature = "logging")]
@@ -23,13 +26,14 @@ use crate::msgs::handshake::{NewSessionTicketExtension, NewSessionTicketPayloadT
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 use crate::rand;
-use crate::server::ServerConfig;
+use crate::server::{ProducesResumptionData, ServerConfig, TicketRejected};
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
 use crate::ticketer;
+use crate::time_provider;
 use crate::tls13::key_schedule::{KeyScheduleTraffic, KeyScheduleTrafficWithClientFinishedPending};
 use crate::tls13::Tls13CipherSuite;
-use crate::verify;
+use crate::verify::{self, ClientCertVerifier};
 
 use super::hs::{self, HandshakeHashOrBuffer, ServerContext};
 use super::server_conn::ServerConnectionData;
@@ -38,12 +42,12 @@ use alloc::sync::Arc;
 
 use subtle::ConstantTimeEq;
 
-pub(super) use client_hello::CompleteClientHelloHandling;
+pub(super) use client_hello::{choose_key_share, CompleteClientHelloHandling, KeyShareChoice};
 
 mod client_hello {
-    use crate::crypto::{KeyExchange, SupportedGroup};
+    use crate::crypto::{self, KeyExchange, SupportedGroup};
     use crate::enums::SignatureScheme;
-    use crate::msgs::base::{Payload, PayloadU8};
+    use crate::msgs::base::{Payload, PayloadU16, PayloadU8};
     use crate::msgs::ccs::ChangeCipherSpecPayload;
     use crate::msgs::enums::NamedGroup;
     use crate::msgs::enums::{Compression, PSKKeyExchangeMode};
@@ -70,6 +74,9 @@ mod client_hello {
 
     use super::*;
 
+    /// How long a client has to answer a `HelloRetryRequest` sent with a cookie.
+    const RETRY_COOKIE_LIFETIME_SECS: u64 = 30;
+
     #[derive(PartialEq)]
     pub(super) enum EarlyDataDecision {
         Disabled,
@@ -77,8 +84,72 @@ mod client_hello {
         Accepted,
     }
 
+    /// The outcome of choosing a key share from a TLS1.3 `ClientHello`.
+    pub(in crate::server) enum KeyShareChoice<'a> {
+        /// Use a key share the client sent.
+        Accept(&'a KeyShareEntry),
+        /// Ask for a key share for `group` with a `HelloRetryRequest`.
+        ///
+        /// `fallback` is a key share the client sent that could be used
+        /// instead, if the `HelloRetryRequest` is not permitted.
+        Retry {
+            group: NamedGroup,
+            fallback: Option<&'a KeyShareEntry>,
+        },
+        NoGroupsInCommon,
+    }
+
+    pub(in crate::server) fn choose_key_share<'a, C: CryptoProvider>(
+        config: &ServerConfig<C>,
+        groups_ext: &[NamedGroup],
+        shares_ext: &'a [KeyShareEntry],
+        done_retry: bool,
+    ) -> KeyShareChoice<'a> {
+        // The group we would ask for in a HelloRetryRequest.
+        let retry_group = config
+            .hello_retry
+            .retry_groups
+            .iter()
+            .filter_map(|name| {
+                config
+                    .kx_groups
+                    .iter()
+                    .find(|group| group.name() == *name)
+            })
+            .chain(config.kx_groups.iter())
+            .map(|group| group.name())
+            .find(|name| groups_ext.contains(name));
+
+        // choose a share that we support
+        let usable_share = config
+            .kx_groups
+            .iter()
+            .find_map(|group| {
+                shares_ext
+                    .iter()
+                    .find(|share| share.group == group.name())
+            });
+
+        let chosen_share = match retry_group {
+            Some(group) if !done_retry && !config.hello_retry.accept_any_key_share => shares_ext
+                .iter()
+                .find(|share| share.group == group),
+            _ => usable_share,
+        };
+
+        match (chosen_share, retry_group) {
+            (Some(share), _) => KeyShareChoice::Accept(share),
+            (None, Some(group)) => KeyShareChoice::Retry {
+                group,
+                fallback: usable_share,
+            },
+            (None, None) => KeyShareChoice::NoGroupsInCommon,
+        }
+    }
+
     pub(in crate::server) struct CompleteClientHelloHandling<C: CryptoProvider> {
         pub(in crate::server) config: Arc<ServerConfig<C>>,
+        pub(in crate::server) client_auth: Arc<dyn ClientCertVerifier>,
         pub(in crate::server) transcript: HandshakeHash,
         pub(in crate::server) suite: &'static Tls13CipherSuite,
         pub(in crate::server) randoms: ConnectionRandoms,
@@ -109,7 +180,7 @@ mod client_hello {
             client_hello: &Message,
             psk: &[u8],
             binder: &[u8],
-        ) -> bool {
+        ) -> Result<bool, Error> {
             let binder_plaintext = match &client_hello.payload {
                 MessagePayload::Handshake { parsed, .. } => {
                     parsed.get_encoding_for_binder_signing()
@@ -121,11 +192,11 @@ mod client_hello {
                 .transcript
                 .get_hash_given(&binder_plaintext);
 
-            let key_schedule = KeyScheduleEarly::new(suite, psk);
+            let key_schedule = KeyScheduleEarly::new::<C>(suite, psk)?;
             let real_binder =
                 key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
 
-            ConstantTimeEq::ct_eq(real_binder.as_ref(), binder).into()
+            Ok(ConstantTimeEq::ct_eq(real_binder.as_ref(), binder).into())
         }
 
         fn attempt_tls13_ticket_decryption(
@@ -145,6 +216,135 @@ mod client_hello {
             }
         }
 
+        /// Returns the cookie to send in a `HelloRetryRequest`, if we are configured to.
+        ///
+        /// This must be called once the first `ClientHello` is in the transcript.
+        fn encrypt_retry_cookie(
+            &self,
+            cx: &ServerContext<'_>,
+            group: NamedGroup,
+        ) -> Result<Option<Vec<u8>>, Error> {
+            if !self
+                .config
+                .hello_retry_cookies
+                .enabled()
+            {
+                return Ok(None);
+            }
+
+            let cookie = persist::HelloRetryCookie {
+                sni: cx.data.sni.clone(),
+                cipher_suite: self.suite.common.suite,
+                group,
+                client_hello_hash: PayloadU8::new(
+                    self.transcript
+                        .get_current_hash()
+                        .as_ref()
+                        .to_vec(),
+                ),
+                issued_at: ticketer::TimeBase::from_provider(&*self.config.time_provider)?
+                    .as_secs(),
+            };
+            Ok(self
+                .config
+                .hello_retry_cookies
+                .encrypt(&cookie.get_encoding()))
+        }
+
+        /// Rebuild the transcript up to and including the `HelloRetryRequest` which
+        /// carried `cookie`, as if this connection had sent it.
+        fn restore_from_retry_cookie(
+            &mut self,
+            cx: &mut ServerContext<'_>,
+            cookie: &[u8],
+            session_id: SessionId,
+        ) -> Result<(), Error> {
+            let now = ticketer::TimeBase::from_provider(&*self.config.time_provider)?.as_secs();
+            let restored = self
+                .config
+                .hello_retry_cookies
+                .decrypt(cookie)
+                .and_then(|plain| persist::HelloRetryCookie::read_bytes(&plain).ok())
+                .filter(|restored| {
+                    restored.cipher_suite == self.suite.common.suite
+                        && restored.client_hello_hash.0.len()
+                            == self.suite.hash_algorithm().output_len
+                        && restored.issued_at <= now
+                        && now - restored.issued_at <= RETRY_COOKIE_LIFETIME_SECS
+                })
+                .ok_or_else(|| {
+                    cx.common.send_fatal_alert(
+                        AlertDescription::IllegalParameter,
+                        PeerMisbehaved::InvalidHelloRetryCookie,
+                    )
+                })?;
+
+            if restored.sni != cx.data.sni {
+                return Err(cx.common.send_fatal_alert(
+                    AlertDescription::IllegalParameter,
+                    PeerMisbehaved::ServerNameDifferedOnRetry,
+                ));
+            }
+
+            self.transcript
+                .add_hrr_rollup(&restored.client_hello_hash.0);
+            self.transcript
+                .add_message(&hello_retry_request(
+                    self.suite,
+                    session_id,
+                    restored.group,
+                    Some(cookie.to_vec()),
+                ));
+            self.done_retry = true;
+            Ok(())
+        }
+
+        fn retry(
+            mut self,
+            cx: &mut ServerContext<'_>,
+            chm: &Message,
+            client_hello: &ClientHelloPayload,
+            group: NamedGroup,
+        ) -> hs::NextStateOrError {
+            self.transcript.add_message(chm);
+
+            let cookie = self.encrypt_retry_cookie(cx, group)?;
+            emit_hello_retry_request(
+                &mut self.transcript,
+                self.suite,
+                client_hello.session_id,
+                cx.common,
+                group,
+                cookie,
+            );
+            if self.config.enable_middlebox_compat {
+                emit_fake_ccs(cx.common);
+            }
+
+            let skip_early_data = max_early_data_size(self.config.max_early_data_size);
+
+            let next = Box::new(hs::ExpectClientHello {
+                config: self.config,
+                transcript: HandshakeHashOrBuffer::Hash(self.transcript),
+                #[cfg(feature = "tls12")]
+                session_id: SessionId::empty(),
+                #[cfg(feature = "tls12")]
+                using_ems: false,
+                done_retry: true,
+                send_tickets: self.send_tickets,
+                extra_exts: self.extra_exts,
+            });
+
+            if client_hello.early_data_extension_offered() {
+                Ok(Box::new(ExpectAndSkipRejectedEarlyData {
+                    skip_data_left: skip_early_data,
+                    next,
+                }))
+            } else {
+                Ok(next)
+            }
+        }
+
         pub(in crate::server) fn handle_client_hello(
             mut self,
             cx: &mut ServerContext<'_>,
@@ -188,6 +388,18 @@ mod client_hello {
                 ));
             }
 
+            // A cookie in what looks like a first ClientHello means we are continuing
+            // from a HelloRetryRequest sent by another connection.
+            if let (false, Some(cookie)) = (self.done_retry, client_hello.get_cookie()) {
+                if self
+                    .config
+                    .hello_retry_cookies
+                    .enabled()
+                {
+                    self.restore_from_retry_cookie(cx, &cookie.0, client_hello.session_id)?;
+                }
+            }
+
             let early_data_requested = client_hello.early_data_extension_offered();
 
             // EarlyData extension is illegal in second ClientHello
@@ -200,82 +412,46 @@ mod client_hello {
                 });
             }
 
-            // choose a share that we support
-            let chosen_share = self
-                .config
-                .kx_groups
-                .iter()
-                .find_map(|group| {
-                    shares_ext
-                        .iter()
-                        .find(|share| share.group == group.name())
-                });
+            let chosen_share =
+                match choose_key_share(&self.config, groups_ext, shares_ext, self.done_retry) {
+                    KeyShareChoice::Accept(share) => share,
+                    KeyShareChoice::Retry { .. } if self.done_retry => {
+                        return Err(cx.common.send_fatal_alert(
+                            AlertDescription::IllegalParameter,
+                            PeerMisbehaved::RefusedToFollowHelloRetryRequest,
+                        ));
+                    }
+                    KeyShareChoice::Retry { group, fallback } => {
+                        let permitted = match &self.config.hello_retry_limit {
+                            Some(limit) => limit.permit(cx.data.get_sni_str(), group),
+                            None => true,
+                        };
 
-            let chosen_share = match chosen_share {
-                Some(s) => s,
-                None => {
-                    // We don't have a suitable key share.  Choose a suitable group and
-                    // send a HelloRetryRequest.
-                    let retry_group_maybe = self
-                        .config
-                        .kx_groups
-                        .iter()
-                        .find(|group| groups_ext.contains(&group.name()))
-                        .cloned();
-
-                    self.transcript.add_message(chm);
-
-                    if let Some(group) = retry_group_maybe {
-                        if self.done_retry {
-                            return Err(cx.common.send_fatal_alert(
-                                AlertDescription::IllegalParameter,
-                                PeerMisbehaved::RefusedToFollowHelloRetryRequest,
-                            ));
+                        match (permitted, fallback) {
+                            (true, _) => {
+                                // Send a HelloRetryRequest for a more suitable key share.
+                                return self.retry(cx, chm, client_hello, group);
+                            }
+                            (false, Some(share)) => share,
+                            (false, None) => {
+                                return Err(cx.common.send_fatal_alert(
+                                    AlertDescription::HandshakeFailure,
+                                    PeerIncompatible::UsableKeyShareRequired,
+                                ));
+                            }
                         }
-
-                        emit_hello_retry_request(
-                            &mut self.transcript,
-                            self.suite,
-                            client_hello.session_id,
-                            cx.common,
-                            group.name(),
-                        );
-                        emit_fake_ccs(cx.common);
-
-                        let skip_early_data = max_early_data_size(self.config.max_early_data_size);
-
-                        let next = Box::new(hs::ExpectClientHello {
-                            config: self.config,
-                            transcript: HandshakeHashOrBuffer::Hash(self.transcript),
-                            #[cfg(feature = "tls12")]
-                            session_id: SessionId::empty(),
-                            #[cfg(feature = "tls12")]
-                            using_ems: false,
-                            done_retry: true,
-                            send_tickets: self.send_tickets,
-                            extra_exts: self.extra_exts,
-                        });
-
-                        return if early_data_requested {
-                            Ok(Box::new(ExpectAndSkipRejectedEarlyData {
-                                skip_data_left: skip_early_data,
-                                next,
-                            }))
-                        } else {
-                            Ok(next)
-                        };
                     }
-
-                    return Err(cx.common.send_fatal_alert(
-                        AlertDescription::HandshakeFailure,
-                        PeerIncompatible::NoKxGroupsInCommon,
-                    ));
-                }
-            };
+                    KeyShareChoice::NoGroupsInCommon => {
+                        return Err(cx.common.send_fatal_alert(
+                            AlertDescription::HandshakeFailure,
+                            PeerIncompatible::NoKxGroupsInCommon,
+                        ));
+                    }
+                };
 
             let mut chosen_psk_index = None;
             let mut resumedata = None;
-            let time_now = ticketer::TimeBase::now()?;
+            let time_now = ticketer::TimeBase::from_provider(&*self.config.time_provider)?;
 
             if let Some(psk_offer) = client_hello.get_psk() {
                 if !client_hello.check_psk_ext_is_last() {
@@ -315,26 +491,83 @@ mod client_hello {
                         .attempt_tls13_ticket_decryption(&psk_id.identity.0)
                         .map(|resumedata| {
                             resumedata.set_freshness(psk_id.obfuscated_ticket_age, time_now)
-                        })
-                        .filter(|resumedata| {
-                            hs::can_resume(self.suite.into(), &cx.data.sni, false, resumedata)
                         }) {
                         Some(resume) => resume,
-                        None => continue,
+                        None => {
+                            self.config
+                                .resumption_observer
+                                .ticket_rejected(
+                                    cx.data.get_sni_str(),
+                                    ProtocolVersion::TLSv1_3,
+                                    TicketRejected::Unrecognised,
+                                );
+                            continue;
+                        }
                     };
 
-                    if !self.check_binder(
-                        self.suite,
-                        chm,
-                        &resume.master_secret.0,
-                        psk_offer.binders[i].as_ref(),
-                    ) {
+                    if !hs::can_resume(self.suite.into(), &cx.data.sni, false, &resume) {
+                        self.config
+                            .resumption_observer
+                            .ticket_rejected(
+                                cx.data.get_sni_str(),
+                                ProtocolVersion::TLSv1_3,
+                                TicketRejected::Incompatible,
+                            );
+                        continue;
+                    }
+
+                    let binder_ok = self
+                        .check_binder(
+                            self.suite,
+                            chm,
+                            &resume.master_secret.0,
+                            psk_offer.binders[i].as_ref(),
+                        )
+                        .map_err(|err| {
+                            cx.common
+                                .send_fatal_alert(AlertDescription::InternalError, err)
+                        })?;
+                    if !binder_ok {
                         return Err(cx.common.send_fatal_alert(
                             AlertDescription::DecryptError,
                             PeerMisbehaved::IncorrectBinder,
                         ));
                     }
 
+                    if !hs::resumption_permitted(
+                        &self.config,
+                        cx,
+                        client_hello,
+                        &sigschemes_ext,
+                        &resume,
+                    ) {
+                        self.config
+                            .resumption_observer
+                            .ticket_rejected(
+                                cx.data.get_sni_str(),
+                                ProtocolVersion::TLSv1_3,
+                                TicketRejected::Refused,
+                            );
+                        continue;
+                    }
+
+                    // Tickets from `session_storage` were removed by `take`.
+                    if let Some(used_tickets) = &self.config.single_use_tickets {
+                        let now = time_provider::current_time(&*self.config.time_provider)?;
+                        if self.config.ticketer.enabled()
+                            && !used_tickets.mark_used(&psk_id.identity.0, now)
+                        {
+                            self.config
+                                .resumption_observer
+                                .ticket_rejected(
+                                    cx.data.get_sni_str(),
+                                    ProtocolVersion::TLSv1_3,
+                                    TicketRejected::Reused,
+                                );
+                            continue;
+                        }
+                    }
+
                     chosen_psk_index = Some(i);
                     resumedata = Some(resume);
                     break;
@@ -351,6 +584,10 @@ mod client_hello {
             }
 
             if let Some(ref resume) = resumedata {
+                self.config
+                    .resumption_observer
+                    .ticket_redeemed(cx.data.get_sni_str(), ProtocolVersion::TLSv1_3);
+                cx.common.resumed = true;
                 cx.data.received_resumption_data = Some(resume.application_data.0.clone());
                 cx.common.peer_certificates = resume.client_cert_chain.clone();
             }
@@ -370,16 +607,20 @@ mod client_hello {
                     .map(|x| &x.master_secret.0[..]),
                 &self.config,
             )?;
-            if !self.done_retry {
+            cx.common.kx_group = Some(chosen_share.group);
+            cx.common.hello_retried = self.done_retry;
+            if !self.done_retry && self.config.enable_middlebox_compat {
                 emit_fake_ccs(cx.common);
             }
 
             let mut ocsp_response = server_key.get_ocsp();
+            let mut sct_list = server_key.get_sct_list();
             let doing_early_data = emit_encrypted_extensions(
                 &mut self.transcript,
                 self.suite,
                 cx,
                 &mut ocsp_response,
+                &mut sct_list,
                 client_hello,
                 resumedata.as_ref(),
                 self.extra_exts,
@@ -387,13 +628,18 @@ mod client_hello {
             )?;
 
             let doing_client_auth = if full_handshake {
-                let client_auth =
-                    emit_certificate_req_tls13(&mut self.transcript, cx, &self.config)?;
+                let client_auth = emit_certificate_req_tls13(
+                    &mut self.transcript,
+                    cx,
+                    &self.config,
+                    &*self.client_auth,
+                )?;
                 emit_certificate_tls13(
                     &mut self.transcript,
                     cx.common,
                     server_key.get_cert(),
                     ocsp_response,
+                    sct_list,
                 );
                 emit_certificate_verify_tls13(
                     &mut self.transcript,
@@ -422,9 +668,10 @@ mod client_hello {
                     cx.data.early_data.reject();
                 }
                 EarlyDataDecision::Accepted => {
-                    cx.data
-                        .early_data
-                        .accept(self.config.max_early_data_size as usize);
+                    cx.data.early_data.accept(
+                        self.config.max_early_data_size as usize,
+                        self.config.early_data_gate.clone(),
+                    );
                 }
             }
 
@@ -447,10 +694,12 @@ mod client_hello {
             if doing_client_auth {
                 Ok(Box::new(ExpectCertificate {
                     config: self.config,
+                    client_auth: self.client_auth,
                     transcript: self.transcript,
                     suite: self.suite,
                     key_schedule: key_schedule_traffic,
                     send_tickets: self.send_tickets,
+                    cert_stream: CertificateStream::default(),
                 }))
             } else if doing_early_data == EarlyDataDecision::Accepted && !cx.common.is_quic() {
                 // Not used for QUIC: RFC 9001 §8.3: Clients MUST NOT send the EndOfEarlyData
@@ -489,7 +738,8 @@ mod client_hello {
         let mut extensions = Vec::new();
 
         // Prepare key exchange; the caller ascertained that the `share.group` is supported
-        let kx = <<C as CryptoProvider>::KeyExchange as KeyExchange>::start(
+        let kx = crypto::start_kx(
+            config.key_share_pool.as_deref(),
             share.group,
             &config.kx_groups,
         )
@@ -527,8 +777,12 @@ mod client_hello {
         cx.common.send_msg(sh, false);
 
         // Start key schedule
+        let internal_error = |common: &mut CommonState, err| {
+            common.send_fatal_alert(AlertDescription::InternalError, err)
+        };
         let key_schedule_pre_handshake = if let Some(psk) = resuming_psk {
-            let early_key_schedule = KeyScheduleEarly::new(suite, psk);
+            let early_key_schedule = KeyScheduleEarly::new::<C>(suite, psk)
+                .map_err(|err| internal_error(cx.common, err))?;
             early_key_schedule.client_early_traffic_secret(
                 &client_hello_hash,
                 &*config.key_log,
@@ -538,7 +792,8 @@ mod client_hello {
 
             KeySchedulePreHandshake::from(early_key_schedule)
         } else {
-            KeySchedulePreHandshake::new(suite)
+            KeySchedulePreHandshake::new::<C>(suite)
+                .map_err(|err| internal_error(cx.common, err))?
         };
 
         // Do key exchange
@@ -574,7 +829,22 @@ mod client_hello {
         session_id: SessionId,
         common: &mut CommonState,
         group: NamedGroup,
+        cookie: Option<Vec<u8>>,
     ) {
+        let m = hello_retry_request(suite, session_id, group, cookie);
+
+        trace!("Requesting retry {:?}", m);
+        transcript.rollup_for_hrr();
+        transcript.add_message(&m);
+        common.send_msg(m, false);
+    }
+
+    fn hello_retry_request(
+        suite: &'static Tls13CipherSuite,
+        session_id: SessionId,
+        group: NamedGroup,
+        cookie: Option<Vec<u8>>,
+    ) -> Message {
         let mut req = HelloRetryRequest {
             legacy_version: ProtocolVersion::TLSv1_2,
             session_id,
@@ -588,19 +858,18 @@ mod client_hello {
             .push(HelloRetryExtension::SupportedVersions(
                 ProtocolVersion::TLSv1_3,
             ));
+        if let Some(cookie) = cookie {
+            req.extensions
+                .push(HelloRetryExtension::Cookie(PayloadU16::new(cookie)));
+        }
 
-        let m = Message {
+        Message {
             version: ProtocolVersion::TLSv1_2,
             payload: MessagePayload::handshake(HandshakeMessagePayload {
                 typ: HandshakeType::HelloRetryRequest,
                 payload: HandshakePayload::HelloRetryRequest(req),
             }),
-        };
-
-        trace!("Requesting retry {:?}", m);
-        transcript.rollup_for_hrr();
-        transcript.add_message(&m);
-        common.send_msg(m, false);
+        }
     }
 
     #[allow(clippy::needless_pass_by_ref_mut)] // cx only mutated if cfg(feature = "quic")
@@ -669,13 +938,22 @@ mod client_hello {
         suite: &'static Tls13CipherSuite,
         cx: &mut ServerContext<'_>,
         ocsp_response: &mut Option<&[u8]>,
+        sct_list: &mut Option<&[u8]>,
         hello: &ClientHelloPayload,
         resumedata: Option<&persist::ServerSessionValue>,
         extra_exts: Vec<ServerExtension>,
         config: &ServerConfig<C>,
     ) -> Result<EarlyDataDecision, Error> {
         let mut ep = hs::ExtensionProcessing::new();
-        ep.process_common(config, cx, ocsp_response, hello, resumedata, extra_exts)?;
+        ep.process_common(
+            config,
+            cx,
+            ocsp_response,
+            sct_list,
+            hello,
+            resumedata,
+            extra_exts,
+        )?;
 
         let early_data = decide_if_early_data_allowed(cx, hello, resumedata, suite, config);
         if early_data == EarlyDataDecision::Accepted {
@@ -700,8 +978,10 @@ mod client_hello {
         transcript: &mut HandshakeHash,
         cx: &mut ServerContext<'_>,
         config: &ServerConfig<C>,
+        client_auth: &dyn ClientCertVerifier,
     ) -> Result<bool, Error> {
-        if !config.verifier.offer_client_auth() {
+        if !client_auth.offer_client_auth() {
+            transcript.abandon_client_auth();
             return Ok(false);
         }
 
@@ -710,15 +990,15 @@ mod client_hello {
             extensions: Vec::new(),
         };
 
-        let schemes = config
-            .verifier
-            .supported_verify_schemes();
+        let schemes = verify::allowed_signature_schemes(
+            config.signature_schemes.as_deref(),
+            client_auth.supported_verify_schemes(),
+        );
         cr.extensions
             .push(CertReqExtension::SignatureAlgorithms(schemes.to_vec()));
 
         let names = config
-            .verifier
-            .client_auth_root_subjects()
+            .root_hints_for(client_auth)
             .to_vec();
 
         if !names.is_empty() {
@@ -745,6 +1025,7 @@ mod client_hello {
         common: &mut CommonState,
         cert_chain: &[Certificate],
         ocsp_response: Option<&[u8]>,
+        sct_list: Option<&[u8]>,
     ) {
         let mut cert_entries = vec![];
         for cert in cert_chain {
@@ -765,6 +1046,14 @@ mod client_hello {
                     .exts
                     .push(CertificateExtension::CertificateStatus(cst));
             }
+
+            // Likewise, SCT
+            if let Some(sct_list) = sct_list {
+                match CertificateExtension::make_sct(sct_list) {
+                    Some(ext) => end_entity_cert.exts.push(ext),
+                    None => warn!("Not sending invalid SCT list"),
+                }
+            }
         }
 
         let cert_body = CertificatePayloadTLS13::new(cert_entries);
@@ -876,10 +1165,12 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectAndSkipRejectedEar
 
 struct ExpectCertificate<C: CryptoProvider> {
     config: Arc<ServerConfig<C>>,
+    client_auth: Arc<dyn ClientCertVerifier>,
     transcript: HandshakeHash,
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     send_tickets: usize,
+    cert_stream: CertificateStream,
 }
 
 impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
@@ -898,11 +1189,15 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
         }
 
         let client_cert = certp.convert();
+        hs::precheck_chain(
+            cx.common,
+            &*self.client_auth,
+            &self.config.cert_chain_limits,
+            &mut self.cert_stream,
+            &client_cert,
+        )?;
 
-        let mandatory = self
-            .config
-            .verifier
-            .client_auth_mandatory();
+        let mandatory = self.client_auth.client_auth_mandatory();
 
         let (end_entity, intermediates) = match client_cert.split_first() {
             None => {
@@ -926,10 +1221,21 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
             Some(chain) => chain,
         };
 
-        let now = std::time::SystemTime::now();
-        self.config
-            .verifier
-            .verify_client_cert(end_entity, intermediates, now)
+        let now = time_provider::current_time(&*self.config.time_provider)?;
+        self.client_auth
+            .verify_client_cert_with_user_data(
+                end_entity,
+                intermediates,
+                now,
+                cx.common.user_data(),
+            )
+            .and_then(|_| {
+                verify::check_certificate_signatures(
+                    self.config.signature_schemes.as_deref(),
+                    end_entity,
+                    intermediates,
+                )
+            })
             .map_err(|err| {
                 cx.common
                     .send_cert_verify_error_alert(err)
@@ -937,6 +1243,7 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
 
         Ok(Box::new(ExpectCertificateVerify {
             config: self.config,
+            client_auth: self.client_auth,
             suite: self.suite,
             transcript: self.transcript,
             key_schedule: self.key_schedule,
@@ -944,10 +1251,26 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificate<C> {
             send_tickets: self.send_tickets,
         }))
     }
+
+    fn handle_partial_handshake(
+        &mut self,
+        cx: &mut ServerContext<'_>,
+        received: &[u8],
+    ) -> Result<(), Error> {
+        hs::precheck_partial_chain(
+            cx.common,
+            &*self.client_auth,
+            &self.config.cert_chain_limits,
+            &mut self.cert_stream,
+            received,
+            true,
+        )
+    }
 }
 
 struct ExpectCertificateVerify<C: CryptoProvider> {
     config: Arc<ServerConfig<C>>,
+    client_auth: Arc<dyn ClientCertVerifier>,
     transcript: HandshakeHash,
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
@@ -968,9 +1291,11 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectCertificateVerify<
             let certs = &self.client_cert;
             let msg = verify::construct_tls13_client_verify_message(&handshake_hash);
 
-            self.config
-                .verifier
-                .verify_tls13_signature(&msg, &certs[0], sig)
+            verify::check_signature_scheme(self.config.signature_schemes.as_deref(), sig.scheme)
+                .and_then(|()| {
+                    self.client_auth
+                        .verify_tls13_signature(&msg, &certs[0], sig)
+                })
         };
 
         if let Err(e) = rc {
@@ -1054,6 +1379,7 @@ fn get_server_session_value(
     suite: &'static Tls13CipherSuite,
     key_schedule: &KeyScheduleTraffic,
     cx: &ServerContext<'_>,
+    resumption_data: &dyn ProducesResumptionData,
     nonce: &[u8],
     time_now: ticketer::TimeBase,
     age_obfuscation_offset: u32,
@@ -1071,7 +1397,8 @@ fn get_server_session_value(
         secret,
         cx.common.peer_certificates.clone(),
         cx.common.alpn_protocol.clone(),
-        cx.data.resumption_data.clone(),
+        cx.data
+            .ticket_resumption_data(resumption_data, cx.common.alpn_protocol.as_deref()),
         time_now,
         age_obfuscation_offset,
     )
@@ -1094,11 +1421,19 @@ impl<C: CryptoProvider> ExpectFinished<C> {
         config: &ServerConfig<C>,
     ) -> Result<(), Error> {
         let nonce = rand::random_vec::<C>(32)?;
-        let now = ticketer::TimeBase::now()?;
+        let now = ticketer::TimeBase::from_provider(&*config.time_provider)?;
         let age_add = rand::random_u32::<C>()?;
-        let plain =
-            get_server_session_value(transcript, suite, key_schedule, cx, &nonce, now, age_add)
-                .get_encoding();
+        let plain = get_server_session_value(
+            transcript,
+            suite,
+            key_schedule,
+            cx,
+            &*config.resumption_data,
+            &nonce,
+            now,
+            age_add,
+        )
+        .get_encoding();
 
         let stateless = config.ticketer.enabled();
         let (ticket, lifetime) = if stateless {
@@ -1146,6 +1481,9 @@ impl<C: CryptoProvider> ExpectFinished<C> {
 
         trace!("sending new ticket {:?} (stateless: {})", m, stateless);
         cx.common.send_msg(m, true);
+        config
+            .resumption_observer
+            .ticket_issued(cx.data.get_sni_str(), ProtocolVersion::TLSv1_3);
         Ok(())
     }
 }
@@ -1187,6 +1525,11 @@ impl<C: CryptoProvider> State<ServerConnectionData> for ExpectFinished<C> {
 
         // Application data may now flow, even if we have client auth enabled.
         cx.common.start_traffic();
+        if let Some(deferred) = cx.data.early_data.take_deferred() {
+            cx.common
+                .received_plaintext
+                .append(deferred);
+        }
 
         #[cfg(feature = "quic")]
         {
@@ -1282,6 +1625,25 @@ impl State<ServerConnectionData> for ExpectTraffic {
         self.key_schedule
             .extract_secrets(Side::Server)
     }
+
+    #[cfg(feature = "dangerous_configuration")]
+    fn export_traffic_secrets(self: Box<Self>) -> Result<TrafficSecrets, Error> {
+        Ok(TrafficSecrets::Tls13(
+            self.key_schedule.export_secrets(None),
+        ))
+    }
+}
+
+/// Carry on an exported connection, whose keys are `secrets`.
+#[cfg(feature = "dangerous_configuration")]
+pub(super) fn restore_traffic<C: CryptoProvider>(
+    secrets: Tls13TrafficSecrets,
+    common: &mut CommonState,
+) -> Result<Box<dyn State<ServerConnectionData>>, Error> {
+    Ok(Box::new(ExpectTraffic {
+        key_schedule: KeyScheduleTraffic::restore::<C>(&secrets, common)?,
+        _fin_verified: verify::FinishedMessageVerified::assertion(),
+    }))
 }
 
 #[cfg(feature = "quic")]
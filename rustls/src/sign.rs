@@ -1,6 +1,7 @@
 use crate::enums::{SignatureAlgorithm, SignatureScheme};
 use crate::error::Error;
 use crate::key;
+use crate::sct;
 use crate::x509::{wrap_in_asn1_len, wrap_in_sequence};
 
 use ring::io::der;
@@ -44,6 +45,12 @@ pub struct CertifiedKey {
     /// An optional OCSP response from the certificate issuer,
     /// attesting to its continued validity.
     pub ocsp: Option<Vec<u8>>,
+
+    /// An optional collection of SCTs from CT logs, proving the
+    /// certificate is included on those logs.  This must be
+    /// a `SignedCertificateTimestampList` encoding; see RFC6962.
+    /// A list which does not parse is not sent.
+    pub sct_list: Option<Vec<u8>>,
 }
 
 impl CertifiedKey {
@@ -56,6 +63,18 @@ impl CertifiedKey {
             cert,
             key,
             ocsp: None,
+            sct_list: None,
+        }
+    }
+
+    /// Whether `sct_list` is present but not a valid
+    /// `SignedCertificateTimestampList`.
+    pub(crate) fn sct_list_is_invalid(&self) -> bool {
+        match self.sct_list {
+            Some(ref sct_list) => {
+                sct::parse_sct_list(sct_list, sct::SctSource::TlsExtension).is_none()
+            }
+            None => false,
         }
     }
 
@@ -1,7 +1,7 @@
 use crate::conn::{ConnectionCommon, SideData};
 
 use core::ops::{Deref, DerefMut};
-use std::io::{IoSlice, Read, Result, Write};
+use std::io::{ErrorKind, IoSlice, Read, Result, Write};
 
 /// This type implements `io::Read` and `io::Write`, encapsulating
 /// a Connection `C` and an underlying transport `T`, such as a socket.
@@ -214,9 +214,191 @@ where
     }
 }
 
+/// This type implements `io::Read` and `io::Write`, encapsulating
+/// and owning a Connection `C` and an underlying non-blocking transport
+/// `T`, such as a socket in non-blocking mode.
+///
+/// Unlike [`StreamOwned`], this never waits for the transport.  Where it
+/// cannot make progress without doing so it fails with
+/// `io::ErrorKind::WouldBlock`, and [`NonBlockingStream::wants_read`] and
+/// [`NonBlockingStream::wants_write`] say which readiness to wait for
+/// before trying again.  This suits event loops built on `mio` or `poll`:
+///
+/// - `read` completes the handshake, then returns plaintext.  It reads
+///   from the transport until plaintext is available or the transport
+///   would block, so after a readiness notification keep calling it until
+///   it fails with `WouldBlock`.
+/// - `write` buffers plaintext in the connection, then writes as much
+///   buffered TLS data as the transport accepts.  It fails with
+///   `WouldBlock` only if the connection's buffer is full.  During the
+///   handshake, written data is held until the handshake completes.
+/// - `flush` fails with `WouldBlock` if buffered TLS data remains unsent.
+///
+/// Errors from the transport other than `WouldBlock` and `Interrupted`
+/// are returned as-is; TLS errors are returned as `InvalidData`.
+#[derive(Debug)]
+pub struct NonBlockingStream<C: Sized, T: Read + Write + Sized> {
+    /// Our connection
+    pub conn: C,
+
+    /// The underlying transport, like a socket
+    pub sock: T,
+}
+
+impl<C, T, S> NonBlockingStream<C, T>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: Read + Write,
+    S: SideData,
+{
+    /// Make a new NonBlockingStream taking the Connection `conn` and
+    /// socket-like object `sock`.  This does not fail and does no IO.
+    pub fn new(conn: C, sock: T) -> Self {
+        Self { conn, sock }
+    }
+
+    /// Get a reference to the underlying socket
+    pub fn get_ref(&self) -> &T {
+        &self.sock
+    }
+
+    /// Get a mutable reference to the underlying socket
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.sock
+    }
+
+    /// Whether the connection can make progress once the transport is
+    /// readable.
+    pub fn wants_read(&self) -> bool {
+        self.conn.wants_read()
+    }
+
+    /// Whether the connection has TLS data to write once the transport is
+    /// writable.
+    pub fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+
+    /// Make as much progress with the handshake as is possible without
+    /// waiting.
+    ///
+    /// This returns `Ok(())` once the handshake is complete and all our
+    /// handshake messages are written, and fails with `WouldBlock` before
+    /// then.
+    pub fn handshake(&mut self) -> Result<()> {
+        loop {
+            self.write_buffered_tls()?;
+            match (self.conn.is_handshaking(), self.conn.wants_write()) {
+                (false, false) => return Ok(()),
+                (false, true) => return Err(ErrorKind::WouldBlock.into()),
+                (true, _) if !self.conn.wants_read() => return Err(ErrorKind::WouldBlock.into()),
+                (true, _) => {}
+            }
+
+            if self.read_tls()? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Write buffered TLS data until there is none, or the transport would
+    /// block.
+    fn write_buffered_tls(&mut self) -> Result<()> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut self.sock) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read once from the transport, and process what was read.
+    fn read_tls(&mut self) -> Result<usize> {
+        let len = loop {
+            match self.conn.read_tls(&mut self.sock) {
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                result => break result?,
+            }
+        };
+
+        if let Err(err) = self.conn.process_new_packets() {
+            // In case we have an alert to send describing this error,
+            // try a last-gasp write -- but don't predate the primary
+            // error.
+            let _ignored = self.conn.write_tls(&mut self.sock);
+            return Err(std::io::Error::new(ErrorKind::InvalidData, err));
+        }
+
+        Ok(len)
+    }
+}
+
+impl<C, T, S> Read for NonBlockingStream<C, T>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: Read + Write,
+    S: SideData,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            // Anything processing queued, like handshake messages or a key
+            // update, must go out before the peer will say more.
+            self.write_buffered_tls()?;
+            if !self.conn.wants_read() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+
+            // A return of zero means EOF, which the reader reports next
+            // time round.
+            self.read_tls()?;
+        }
+    }
+}
+
+impl<C, T, S> Write for NonBlockingStream<C, T>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: Read + Write,
+    S: SideData,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.write_buffered_tls()?;
+
+        let len = self.conn.writer().write(buf)?;
+
+        // Try to write the underlying transport here, but don't let
+        // any errors mask the fact we've consumed `len` bytes.
+        // Callers will learn of permanent errors on the next call.
+        let _ = self.write_buffered_tls();
+
+        if len == 0 && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.conn.writer().flush()?;
+        self.write_buffered_tls()?;
+        if self.conn.wants_write() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        self.sock.flush()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Stream, StreamOwned};
+    use super::{NonBlockingStream, Stream, StreamOwned};
     use crate::client::ClientConnection;
     use crate::server::ServerConnection;
     use std::net::TcpStream;
@@ -235,4 +417,9 @@ mod tests {
     fn streamowned_can_be_created_for_server_and_tcpstream() {
         type _Test = StreamOwned<ServerConnection, TcpStream>;
     }
+
+    #[test]
+    fn nonblockingstream_can_be_created_for_client_and_tcpstream() {
+        type _Test = NonBlockingStream<ClientConnection, TcpStream>;
+    }
 }
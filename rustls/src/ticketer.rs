@@ -1,10 +1,11 @@
 use crate::rand;
 use crate::server::ProducesTickets;
+use crate::time_provider::{self, DefaultTimeProvider, TimeProvider};
 use crate::Error;
 
 use core::mem;
 use core::time::Duration;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time;
 
 /// The timebase for expiring and rolling tickets and ticketing
@@ -15,11 +16,17 @@ use std::time;
 pub struct TimeBase(pub(crate) Duration);
 
 impl TimeBase {
-    #[inline]
-    pub fn now() -> Result<Self, time::SystemTimeError> {
-        Ok(Self(
-            time::SystemTime::now().duration_since(time::UNIX_EPOCH)?,
-        ))
+    #[cfg(test)]
+    pub(crate) fn now() -> Result<Self, Error> {
+        Self::from_provider(&DefaultTimeProvider)
+    }
+
+    /// Read the current time from `time_provider`.
+    pub(crate) fn from_provider(time_provider: &dyn TimeProvider) -> Result<Self, Error> {
+        time_provider::current_time(time_provider)?
+            .duration_since(time::UNIX_EPOCH)
+            .map(Self)
+            .map_err(Error::from)
     }
 
     #[inline]
@@ -41,6 +48,7 @@ pub(crate) struct TicketSwitcherState {
 pub struct TicketSwitcher {
     pub(crate) generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
     lifetime: u32,
+    time_provider: Arc<dyn TimeProvider>,
     state: Mutex<TicketSwitcherState>,
 }
 
@@ -52,14 +60,27 @@ impl TicketSwitcher {
     /// is used to generate new tickets.  Tickets are accepted for no
     /// longer than twice this duration.  `generator` produces a new
     /// `ProducesTickets` implementation.
+    ///
+    /// The passage of time is measured with [`DefaultTimeProvider`].
     pub fn new(
         lifetime: u32,
         generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
     ) -> Result<Self, Error> {
-        let now = TimeBase::now()?;
+        Self::new_with_time_provider(lifetime, generator, Arc::new(DefaultTimeProvider))
+    }
+
+    /// Like [`TicketSwitcher::new`], but measures the passage of time
+    /// with `time_provider`.
+    pub fn new_with_time_provider(
+        lifetime: u32,
+        generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
+        time_provider: Arc<dyn TimeProvider>,
+    ) -> Result<Self, Error> {
+        let now = TimeBase::from_provider(&*time_provider)?;
         Ok(Self {
             generator,
             lifetime,
+            time_provider,
             state: Mutex::new(TicketSwitcherState {
                 next: Some(generator()?),
                 current: generator()?,
@@ -162,13 +183,13 @@ impl ProducesTickets for TicketSwitcher {
     }
 
     fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
-        let state = self.maybe_roll(TimeBase::now().ok()?)?;
+        let state = self.maybe_roll(TimeBase::from_provider(&*self.time_provider).ok()?)?;
 
         state.current.encrypt(message)
     }
 
     fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
-        let state = self.maybe_roll(TimeBase::now().ok()?)?;
+        let state = self.maybe_roll(TimeBase::from_provider(&*self.time_provider).ok()?)?;
 
         // Decrypt with the current key; if that fails, try with the previous.
         state
@@ -0,0 +1,65 @@
+//! The library's source of wall-clock time.
+
+use crate::error::Error;
+
+use core::fmt::Debug;
+use std::time::SystemTime;
+
+/// An abstract source of the current wall-clock time.
+///
+/// Everywhere rustls needs to know the time -- verifying certificates,
+/// expiring and ageing session tickets -- it asks the `TimeProvider`
+/// configured on the [`ClientConfig`] or [`ServerConfig`].
+///
+/// [`ClientConfig`]: crate::ClientConfig
+/// [`ServerConfig`]: crate::ServerConfig
+pub trait TimeProvider: Debug + Send + Sync {
+    /// Returns the current wall time.
+    ///
+    /// This is not required to be monotonic.
+    ///
+    /// Return `None` if unable to retrieve the time.
+    fn current_time(&self) -> Option<SystemTime>;
+}
+
+/// The default [`TimeProvider`], which asks the operating system
+/// via [`SystemTime::now`].
+///
+/// On `wasm32-unknown-unknown` there is no operating system to ask, and
+/// `SystemTime::now` panics.  There this asks the JavaScript host via
+/// `Date.now()` if the `wasm32_unknown_unknown_js` feature is enabled,
+/// and otherwise always fails: configure a `TimeProvider` of your own.
+#[derive(Debug)]
+pub struct DefaultTimeProvider;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+impl TimeProvider for DefaultTimeProvider {
+    fn current_time(&self) -> Option<SystemTime> {
+        Some(SystemTime::now())
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl TimeProvider for DefaultTimeProvider {
+    #[cfg(feature = "wasm32_unknown_unknown_js")]
+    fn current_time(&self) -> Option<SystemTime> {
+        // `Date.now()` is in milliseconds since the UNIX epoch.
+        let millis = js_sys::Date::now();
+        if !millis.is_finite() || millis < 0.0 {
+            return None;
+        }
+        SystemTime::UNIX_EPOCH.checked_add(core::time::Duration::from_millis(millis as u64))
+    }
+
+    #[cfg(not(feature = "wasm32_unknown_unknown_js"))]
+    fn current_time(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+/// Ask `time_provider` for the current time, mapping failure to an [`Error`].
+pub(crate) fn current_time(time_provider: &dyn TimeProvider) -> Result<SystemTime, Error> {
+    time_provider
+        .current_time()
+        .ok_or(Error::FailedToGetCurrentTime)
+}
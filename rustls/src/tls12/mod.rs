@@ -10,8 +10,8 @@ use crate::suites::{BulkAlgorithm, CipherSuiteCommon, SupportedCipherSuite};
 #[cfg(feature = "secret_extraction")]
 use crate::suites::{ConnectionTrafficSecrets, PartiallyExtractedSecrets};
 
+use crate::crypto::{hash, hmac};
 use ring::aead;
-use ring::digest::Digest;
 
 use core::fmt;
 
@@ -201,6 +201,7 @@ impl fmt::Debug for Tls12CipherSuite {
 pub(crate) struct ConnectionSecrets {
     pub(crate) randoms: ConnectionRandoms,
     suite: &'static Tls12CipherSuite,
+    hmac: &'static dyn hmac::Hmac,
     pub(crate) master_secret: [u8; 48],
 }
 
@@ -208,13 +209,15 @@ impl ConnectionSecrets {
     pub(crate) fn from_key_exchange(
         kx: impl crypto::KeyExchange,
         peer_pub_key: &[u8],
-        ems_seed: Option<Digest>,
+        ems_seed: Option<hash::Output>,
         randoms: ConnectionRandoms,
         suite: &'static Tls12CipherSuite,
+        hmac: &'static dyn hmac::Hmac,
     ) -> Result<Self, Error> {
         let mut ret = Self {
             randoms,
             suite,
+            hmac,
             master_secret: [0u8; 48],
         };
 
@@ -229,7 +232,7 @@ impl ConnectionSecrets {
         kx.complete(peer_pub_key, |secret| {
             prf::prf(
                 &mut ret.master_secret,
-                suite.hmac_algorithm,
+                hmac,
                 secret,
                 label.as_bytes(),
                 seed.as_ref(),
@@ -243,11 +246,13 @@ impl ConnectionSecrets {
     pub(crate) fn new_resume(
         randoms: ConnectionRandoms,
         suite: &'static Tls12CipherSuite,
+        hmac: &'static dyn hmac::Hmac,
         master_secret: &[u8],
     ) -> Self {
         let mut ret = Self {
             randoms,
             suite,
+            hmac,
             master_secret: [0u8; 48],
         };
         ret.master_secret
@@ -321,7 +326,7 @@ impl ConnectionSecrets {
         let randoms = join_randoms(&self.randoms.server, &self.randoms.client);
         prf::prf(
             &mut out,
-            self.suite.hmac_algorithm,
+            self.hmac,
             &self.master_secret,
             b"key expansion",
             &randoms,
@@ -340,13 +345,13 @@ impl ConnectionSecrets {
         ret
     }
 
-    fn make_verify_data(&self, handshake_hash: &Digest, label: &[u8]) -> Vec<u8> {
+    fn make_verify_data(&self, handshake_hash: &hash::Output, label: &[u8]) -> Vec<u8> {
         let mut out = Vec::new();
         out.resize(12, 0u8);
 
         prf::prf(
             &mut out,
-            self.suite.hmac_algorithm,
+            self.hmac,
             &self.master_secret,
             label,
             handshake_hash.as_ref(),
@@ -354,11 +359,11 @@ impl ConnectionSecrets {
         out
     }
 
-    pub(crate) fn client_verify_data(&self, handshake_hash: &Digest) -> Vec<u8> {
+    pub(crate) fn client_verify_data(&self, handshake_hash: &hash::Output) -> Vec<u8> {
         self.make_verify_data(handshake_hash, b"client finished")
     }
 
-    pub(crate) fn server_verify_data(&self, handshake_hash: &Digest) -> Vec<u8> {
+    pub(crate) fn server_verify_data(&self, handshake_hash: &hash::Output) -> Vec<u8> {
         self.make_verify_data(handshake_hash, b"server finished")
     }
 
@@ -377,13 +382,7 @@ impl ConnectionSecrets {
             randoms.extend_from_slice(context);
         }
 
-        prf::prf(
-            output,
-            self.suite.hmac_algorithm,
-            &self.master_secret,
-            label,
-            &randoms,
-        );
+        prf::prf(output, self.hmac, &self.master_secret, label, &randoms);
     }
 
     #[cfg(feature = "secret_extraction")]
@@ -472,7 +471,7 @@ impl ConnectionSecrets {
 }
 
 enum Seed {
-    Ems(Digest),
+    Ems(hash::Output),
     Randoms([u8; 64]),
 }
 
@@ -1,43 +1,27 @@
-use ring::hmac;
+use crate::crypto::hmac;
 
-fn concat_sign(key: &hmac::Key, a: &[u8], b: &[u8]) -> hmac::Tag {
-    let mut ctx = hmac::Context::with_key(key);
-    ctx.update(a);
-    ctx.update(b);
-    ctx.sign()
-}
-
-fn p(out: &mut [u8], alg: hmac::Algorithm, secret: &[u8], seed: &[u8]) {
-    let hmac_key = hmac::Key::new(alg, secret);
+/// The TLS1.2 PRF (RFC5246 section 5): P_hash with `label` + `seed` as its seed.
+pub(crate) fn prf(out: &mut [u8], hmac: &dyn hmac::Hmac, secret: &[u8], label: &[u8], seed: &[u8]) {
+    let hmac_key = hmac.with_key(secret);
 
     // A(1)
-    let mut current_a = hmac::sign(&hmac_key, seed);
-    let chunk_size = alg.digest_algorithm().output_len;
+    let mut current_a = hmac_key.sign(&[label, seed]);
+    let chunk_size = hmac.hash_output_len();
     for chunk in out.chunks_mut(chunk_size) {
         // P_hash[i] = HMAC_hash(secret, A(i) + seed)
-        let p_term = concat_sign(&hmac_key, current_a.as_ref(), seed);
+        let p_term = hmac_key.sign(&[current_a.as_ref(), label, seed]);
         chunk.copy_from_slice(&p_term.as_ref()[..chunk.len()]);
 
         // A(i+1) = HMAC_hash(secret, A(i))
-        current_a = hmac::sign(&hmac_key, current_a.as_ref());
+        current_a = hmac_key.sign(&[current_a.as_ref()]);
     }
 }
 
-fn concat(a: &[u8], b: &[u8]) -> Vec<u8> {
-    let mut ret = Vec::new();
-    ret.extend_from_slice(a);
-    ret.extend_from_slice(b);
-    ret
-}
-
-pub(crate) fn prf(out: &mut [u8], alg: hmac::Algorithm, secret: &[u8], label: &[u8], seed: &[u8]) {
-    let joined_seed = concat(label, seed);
-    p(out, alg, secret, &joined_seed);
-}
-
 #[cfg(test)]
 mod tests {
-    use ring::hmac::{HMAC_SHA256, HMAC_SHA512};
+    use crate::crypto::hash::HashAlgorithm;
+    use crate::crypto::ring::Ring;
+    use crate::crypto::CryptoProvider;
 
     #[test]
     fn check_sha256() {
@@ -47,7 +31,8 @@ mod tests {
         let expect = include_bytes!("../testdata/prf-result.1.bin");
         let mut output = [0u8; 100];
 
-        super::prf(&mut output, HMAC_SHA256, secret, label, seed);
+        let hmac = Ring::hmac(HashAlgorithm::SHA256).unwrap();
+        super::prf(&mut output, hmac, secret, label, seed);
         assert_eq!(expect.len(), output.len());
         assert_eq!(expect.to_vec(), output.to_vec());
     }
@@ -60,7 +45,8 @@ mod tests {
         let expect = include_bytes!("../testdata/prf-result.2.bin");
         let mut output = [0u8; 196];
 
-        super::prf(&mut output, HMAC_SHA512, secret, label, seed);
+        let hmac = Ring::hmac(HashAlgorithm::SHA512).unwrap();
+        super::prf(&mut output, hmac, secret, label, seed);
         assert_eq!(expect.len(), output.len());
         assert_eq!(expect.to_vec(), output.to_vec());
     }
@@ -68,15 +54,20 @@ mod tests {
 
 #[cfg(bench)]
 mod benchmarks {
+    use crate::crypto::hash::HashAlgorithm;
+    use crate::crypto::ring::Ring;
+    use crate::crypto::CryptoProvider;
+
     #[bench]
     fn bench_sha256(b: &mut test::Bencher) {
         let label = &b"extended master secret"[..];
         let seed = [0u8; 32];
         let key = &b"secret"[..];
+        let hmac = Ring::hmac(HashAlgorithm::SHA256).unwrap();
 
         b.iter(|| {
             let mut out = [0u8; 48];
-            super::prf(&mut out, ring::hmac::HMAC_SHA256, key, &label, &seed);
+            super::prf(&mut out, hmac, key, &label, &seed);
             test::black_box(out);
         });
     }
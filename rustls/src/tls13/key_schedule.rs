@@ -1,7 +1,9 @@
-use crate::cipher::{Iv, IvLen, MessageDecrypter};
+use crate::cipher::{Iv, MessageDecrypter};
 use crate::common_state::{CommonState, Side};
+use crate::crypto::{hash, hmac, CryptoProvider};
 use crate::error::Error;
-use crate::msgs::base::PayloadU8;
+#[cfg(feature = "dangerous_configuration")]
+use crate::handover::{Tls13ClientResumption, Tls13TrafficSecrets};
 #[cfg(feature = "quic")]
 use crate::quic;
 #[cfg(feature = "secret_extraction")]
@@ -9,15 +11,12 @@ use crate::suites::{ConnectionTrafficSecrets, PartiallyExtractedSecrets};
 use crate::{KeyLog, Tls13CipherSuite};
 
 /// Key schedule maintenance for TLS1.3
-use ring::{
-    aead,
-    digest::{self, Digest},
-    hkdf::{self, KeyType as _},
-    hmac,
-};
+use ring::aead;
 
 use super::{Tls13MessageDecrypter, Tls13MessageEncrypter};
 
+use core::fmt;
+
 /// The kinds of secret we can extract from `KeySchedule`.
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum SecretKind {
@@ -68,8 +67,9 @@ impl SecretKind {
 /// the type of hash.  This isn't used directly; but only through the
 /// typestates.
 struct KeySchedule {
-    current: hkdf::Prk,
+    current: Prk,
     suite: &'static Tls13CipherSuite,
+    hash: &'static dyn hash::Hash,
 }
 
 // We express the state of a contained KeySchedule using these
@@ -84,15 +84,19 @@ pub(crate) struct KeyScheduleEarly {
 }
 
 impl KeyScheduleEarly {
-    pub(crate) fn new(suite: &'static Tls13CipherSuite, secret: &[u8]) -> Self {
-        Self {
-            ks: KeySchedule::new(suite, secret),
-        }
+    pub(crate) fn new<C: CryptoProvider>(
+        suite: &'static Tls13CipherSuite,
+        secret: &[u8],
+    ) -> Result<Self, Error> {
+        let (hash, hmac) = KeySchedule::provider_for::<C>(suite)?;
+        Ok(Self {
+            ks: KeySchedule::new(suite, hash, hmac, secret),
+        })
     }
 
     pub(crate) fn client_early_traffic_secret(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -123,7 +127,7 @@ impl KeyScheduleEarly {
 
     pub(crate) fn resumption_psk_binder_key_and_sign_verify_data(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
     ) -> hmac::Tag {
         let resumption_psk_binder_key = self
             .ks
@@ -142,10 +146,11 @@ pub(crate) struct KeySchedulePreHandshake {
 }
 
 impl KeySchedulePreHandshake {
-    pub(crate) fn new(suite: &'static Tls13CipherSuite) -> Self {
-        Self {
-            ks: KeySchedule::new_with_empty_secret(suite),
-        }
+    pub(crate) fn new<C: CryptoProvider>(suite: &'static Tls13CipherSuite) -> Result<Self, Error> {
+        let (hash, hmac) = KeySchedule::provider_for::<C>(suite)?;
+        Ok(Self {
+            ks: KeySchedule::new_with_empty_secret(suite, hash, hmac),
+        })
     }
 
     pub(crate) fn into_handshake(mut self, secret: &[u8]) -> KeyScheduleHandshakeStart {
@@ -169,7 +174,7 @@ impl KeyScheduleHandshakeStart {
     pub(crate) fn derive_client_handshake_secrets(
         mut self,
         early_data_enabled: bool,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         suite: &'static Tls13CipherSuite,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
@@ -195,7 +200,7 @@ impl KeyScheduleHandshakeStart {
 
     pub(crate) fn derive_server_handshake_secrets(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -213,7 +218,7 @@ impl KeyScheduleHandshakeStart {
 
     fn into_handshake(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         _common: &mut CommonState,
@@ -254,12 +259,12 @@ impl KeyScheduleHandshakeStart {
 
 pub(crate) struct KeyScheduleHandshake {
     ks: KeySchedule,
-    client_handshake_traffic_secret: hkdf::Prk,
-    server_handshake_traffic_secret: hkdf::Prk,
+    client_handshake_traffic_secret: Prk,
+    server_handshake_traffic_secret: Prk,
 }
 
 impl KeyScheduleHandshake {
-    pub(crate) fn sign_server_finish(&self, hs_hash: &Digest) -> hmac::Tag {
+    pub(crate) fn sign_server_finish(&self, hs_hash: &hash::Output) -> hmac::Tag {
         self.ks
             .sign_finish(&self.server_handshake_traffic_secret, hs_hash)
     }
@@ -291,7 +296,7 @@ impl KeyScheduleHandshake {
 
     pub(crate) fn into_traffic_with_client_finished_pending(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -327,8 +332,8 @@ impl KeyScheduleHandshake {
 
     pub(crate) fn into_pre_finished_client_traffic(
         self,
-        pre_finished_hash: Digest,
-        handshake_hash: Digest,
+        pre_finished_hash: hash::Output,
+        handshake_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
     ) -> (KeyScheduleClientBeforeFinished, hmac::Tag) {
@@ -382,7 +387,7 @@ impl KeyScheduleClientBeforeFinished {
 /// finished verify_data. The traffic stage key schedule can be extracted from it
 /// through signing the client finished hash.
 pub(crate) struct KeyScheduleTrafficWithClientFinishedPending {
-    handshake_client_traffic_secret: hkdf::Prk,
+    handshake_client_traffic_secret: Prk,
     traffic: KeyScheduleTraffic,
 }
 
@@ -396,7 +401,7 @@ impl KeyScheduleTrafficWithClientFinishedPending {
 
     pub(crate) fn sign_client_finish(
         self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         common: &mut CommonState,
     ) -> (KeyScheduleTraffic, hmac::Tag) {
         debug_assert_eq!(common.side, Side::Server);
@@ -421,15 +426,15 @@ impl KeyScheduleTrafficWithClientFinishedPending {
 /// to be available.
 pub(crate) struct KeyScheduleTraffic {
     ks: KeySchedule,
-    current_client_traffic_secret: hkdf::Prk,
-    current_server_traffic_secret: hkdf::Prk,
-    current_exporter_secret: hkdf::Prk,
+    current_client_traffic_secret: Prk,
+    current_server_traffic_secret: Prk,
+    current_exporter_secret: Prk,
 }
 
 impl KeyScheduleTraffic {
     fn new(
         mut ks: KeySchedule,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
     ) -> Self {
@@ -475,27 +480,47 @@ impl KeyScheduleTraffic {
         self.ks.set_decrypter(&secret, common);
     }
 
-    pub(crate) fn next_application_traffic_secret(&mut self, side: Side) -> hkdf::Prk {
+    pub(crate) fn next_application_traffic_secret(&mut self, side: Side) -> Prk {
         let current = match side {
             Side::Client => &mut self.current_client_traffic_secret,
             Side::Server => &mut self.current_server_traffic_secret,
         };
 
-        let secret = self.ks.derive_next(current);
-        *current = secret.clone();
-        secret
+        let secret = self
+            .ks
+            .derive_next_traffic_secret(current);
+        *current = secret;
+        current.clone()
+    }
+
+    /// Derive the resumption master secret, given the hash of the
+    /// handshake up to the client's Finished message.
+    pub(crate) fn resumption_master_secret(&self, hs_hash: &hash::Output) -> Vec<u8> {
+        self.ks
+            .derive(SecretKind::ResumptionMasterSecret, hs_hash.as_ref())
+            .as_bytes()
+            .to_vec()
+    }
+
+    /// Derive the PSK for a ticket from `resumption_master_secret`.
+    pub(crate) fn derive_ticket_psk(
+        &self,
+        resumption_master_secret: &[u8],
+        nonce: &[u8],
+    ) -> Vec<u8> {
+        let resumption_master_secret = Prk::new_less_safe(self.ks.hmac(), resumption_master_secret);
+        self.ks
+            .derive_ticket_psk(&resumption_master_secret, nonce)
     }
 
     pub(crate) fn resumption_master_secret_and_derive_ticket_psk(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         nonce: &[u8],
     ) -> Vec<u8> {
-        let resumption_master_secret = self.ks.derive(
-            self.ks.algorithm(),
-            SecretKind::ResumptionMasterSecret,
-            hs_hash.as_ref(),
-        );
+        let resumption_master_secret = self
+            .ks
+            .derive(SecretKind::ResumptionMasterSecret, hs_hash.as_ref());
         self.ks
             .derive_ticket_psk(&resumption_master_secret, nonce)
     }
@@ -510,23 +535,76 @@ impl KeyScheduleTraffic {
             .export_keying_material(&self.current_exporter_secret, out, label, context)
     }
 
+    /// Rebuild the key schedule of an exported connection, and install its
+    /// current traffic keys in `common`.
+    #[cfg(feature = "dangerous_configuration")]
+    pub(crate) fn restore<C: CryptoProvider>(
+        secrets: &Tls13TrafficSecrets,
+        common: &mut CommonState,
+    ) -> Result<Self, Error> {
+        let (hash, hmac) = KeySchedule::provider_for::<C>(secrets.suite)?;
+        // The master secret is not exported: after the handshake it is only
+        // needed for the resumption master secret, which a client keeps
+        // separately and a server no longer uses.
+        let traffic = Self {
+            ks: KeySchedule::new_with_empty_secret(secrets.suite, hash, hmac),
+            current_client_traffic_secret: Prk::new_less_safe(hmac, &secrets.client),
+            current_server_traffic_secret: Prk::new_less_safe(hmac, &secrets.server),
+            current_exporter_secret: Prk::new_less_safe(hmac, &secrets.exporter),
+        };
+
+        let (ours, theirs) = match common.side {
+            Side::Client => (
+                &traffic.current_client_traffic_secret,
+                &traffic.current_server_traffic_secret,
+            ),
+            Side::Server => (
+                &traffic.current_server_traffic_secret,
+                &traffic.current_client_traffic_secret,
+            ),
+        };
+        traffic.ks.set_encrypter(ours, common);
+        traffic.ks.set_decrypter(theirs, common);
+        Ok(traffic)
+    }
+
+    /// The current secrets, for exporting the connection.
+    #[cfg(feature = "dangerous_configuration")]
+    pub(crate) fn export_secrets(
+        &self,
+        resumption: Option<Tls13ClientResumption>,
+    ) -> Tls13TrafficSecrets {
+        Tls13TrafficSecrets {
+            suite: self.ks.suite,
+            client: self
+                .current_client_traffic_secret
+                .as_bytes()
+                .to_vec(),
+            server: self
+                .current_server_traffic_secret
+                .as_bytes()
+                .to_vec(),
+            exporter: self
+                .current_exporter_secret
+                .as_bytes()
+                .to_vec(),
+            resumption,
+        }
+    }
+
     #[cfg(feature = "secret_extraction")]
     pub(crate) fn extract_secrets(&self, side: Side) -> Result<PartiallyExtractedSecrets, Error> {
         fn expand<const KEY_LEN: usize, const IV_LEN: usize>(
-            secret: &hkdf::Prk,
+            secret: &Prk,
         ) -> Result<([u8; KEY_LEN], [u8; IV_LEN]), Error> {
             let mut key = [0u8; KEY_LEN];
             let mut iv = [0u8; IV_LEN];
 
-            hkdf_expand_info(secret, PayloadU8Len(key.len()), b"key", &[], |okm| {
-                okm.fill(&mut key)
-            })
-            .map_err(|_| Error::General("hkdf_expand_info failed".to_string()))?;
+            hkdf_expand_label(secret, b"key", &[], &mut key)
+                .map_err(|_| Error::General("hkdf_expand_label failed".to_string()))?;
 
-            hkdf_expand_info(secret, PayloadU8Len(iv.len()), b"iv", &[], |okm| {
-                okm.fill(&mut iv)
-            })
-            .map_err(|_| Error::General("hkdf_expand_info failed".to_string()))?;
+            hkdf_expand_label(secret, b"iv", &[], &mut iv)
+                .map_err(|_| Error::General("hkdf_expand_label failed".to_string()))?;
 
             Ok((key, iv))
         }
@@ -536,7 +614,7 @@ impl KeyScheduleTraffic {
 
         let algo = self.ks.suite.common.aead_algorithm;
         if algo == &ring::aead::AES_128_GCM {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
+            let extract = |secret: &Prk| -> Result<ConnectionTrafficSecrets, Error> {
                 let (key, iv_in) = expand::<16, 12>(secret)?;
 
                 let mut salt = [0u8; 4];
@@ -551,7 +629,7 @@ impl KeyScheduleTraffic {
             client_secrets = extract(&self.current_client_traffic_secret)?;
             server_secrets = extract(&self.current_server_traffic_secret)?;
         } else if algo == &ring::aead::AES_256_GCM {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
+            let extract = |secret: &Prk| -> Result<ConnectionTrafficSecrets, Error> {
                 let (key, iv_in) = expand::<32, 12>(secret)?;
 
                 let mut salt = [0u8; 4];
@@ -566,7 +644,7 @@ impl KeyScheduleTraffic {
             client_secrets = extract(&self.current_client_traffic_secret)?;
             server_secrets = extract(&self.current_server_traffic_secret)?;
         } else if algo == &ring::aead::CHACHA20_POLY1305 {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
+            let extract = |secret: &Prk| -> Result<ConnectionTrafficSecrets, Error> {
                 let (key, iv) = expand::<32, 12>(secret)?;
                 Ok(ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv })
             };
@@ -589,16 +667,31 @@ impl KeyScheduleTraffic {
 }
 
 impl KeySchedule {
-    fn new(suite: &'static Tls13CipherSuite, secret: &[u8]) -> Self {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        let salt = hkdf::Salt::new(suite.hkdf_algorithm, &zeroes[..suite.hkdf_algorithm.len()]);
+    /// The provider's hash and HMAC for `suite`.
+    fn provider_for<C: CryptoProvider>(
+        suite: &'static Tls13CipherSuite,
+    ) -> Result<(&'static dyn hash::Hash, &'static dyn hmac::Hmac), Error> {
+        Ok((
+            hash::for_transcript::<C>(suite.hash_algorithm())?,
+            hmac::for_suite::<C>(suite.hash_algorithm())?,
+        ))
+    }
+
+    fn new(
+        suite: &'static Tls13CipherSuite,
+        hash: &'static dyn hash::Hash,
+        hmac: &'static dyn hmac::Hmac,
+        secret: &[u8],
+    ) -> Self {
+        let zeroes = [0u8; hash::Output::MAX_LEN];
         Self {
-            current: salt.extract(secret),
+            current: Prk::extract(hmac, &zeroes[..hmac.hash_output_len()], secret),
             suite,
+            hash,
         }
     }
 
-    fn set_encrypter(&self, secret: &hkdf::Prk, common: &mut CommonState) {
+    fn set_encrypter(&self, secret: &Prk, common: &mut CommonState) {
         let key = derive_traffic_key(secret, self.suite.common.aead_algorithm);
         let iv = derive_traffic_iv(secret);
 
@@ -610,13 +703,13 @@ impl KeySchedule {
             }));
     }
 
-    fn set_decrypter(&self, secret: &hkdf::Prk, common: &mut CommonState) {
+    fn set_decrypter(&self, secret: &Prk, common: &mut CommonState) {
         common
             .record_layer
             .set_message_decrypter(self.derive_decrypter(secret));
     }
 
-    fn derive_decrypter(&self, secret: &hkdf::Prk) -> Box<dyn MessageDecrypter> {
+    fn derive_decrypter(&self, secret: &Prk) -> Box<dyn MessageDecrypter> {
         let key = derive_traffic_key(secret, self.suite.common.aead_algorithm);
         let iv = derive_traffic_iv(secret);
         Box::new(Tls13MessageDecrypter {
@@ -626,34 +719,34 @@ impl KeySchedule {
     }
 
     #[inline]
-    fn algorithm(&self) -> hkdf::Algorithm {
-        self.suite.hkdf_algorithm
+    fn hmac(&self) -> &'static dyn hmac::Hmac {
+        self.current.hmac
     }
 
-    fn new_with_empty_secret(suite: &'static Tls13CipherSuite) -> Self {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        Self::new(suite, &zeroes[..suite.hkdf_algorithm.len()])
+    fn new_with_empty_secret(
+        suite: &'static Tls13CipherSuite,
+        hash: &'static dyn hash::Hash,
+        hmac: &'static dyn hmac::Hmac,
+    ) -> Self {
+        let zeroes = [0u8; hash::Output::MAX_LEN];
+        Self::new(suite, hash, hmac, &zeroes[..hmac.hash_output_len()])
     }
 
     /// Input the empty secret.
     fn input_empty(&mut self) {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        self.input_secret(&zeroes[..self.suite.hkdf_algorithm.len()]);
+        let zeroes = [0u8; hash::Output::MAX_LEN];
+        self.input_secret(&zeroes[..self.hmac().hash_output_len()]);
     }
 
     /// Input the given secret.
     fn input_secret(&mut self, secret: &[u8]) {
-        let salt: hkdf::Salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
-        self.current = salt.extract(secret);
+        let salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
+        self.current = Prk::extract(self.hmac(), salt.as_bytes(), secret);
     }
 
     /// Derive a secret of given `kind`, using current handshake hash `hs_hash`.
-    fn derive<T, L>(&self, key_type: L, kind: SecretKind, hs_hash: &[u8]) -> T
-    where
-        T: for<'a> From<hkdf::Okm<'a, L>>,
-        L: hkdf::KeyType,
-    {
-        hkdf_expand(&self.current, key_type, kind.to_bytes(), hs_hash)
+    fn derive(&self, kind: SecretKind, hs_hash: &[u8]) -> Prk {
+        hkdf_expand_label_prk(&self.current, kind.to_bytes(), hs_hash)
     }
 
     fn derive_logged_secret(
@@ -662,131 +755,148 @@ impl KeySchedule {
         hs_hash: &[u8],
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
-    ) -> hkdf::Prk {
+    ) -> Prk {
         let log_label = kind
             .log_label()
             .expect("not a loggable secret");
+        let secret = self.derive(kind, hs_hash);
         if key_log.will_log(log_label) {
-            let secret = self
-                .derive::<PayloadU8, _>(
-                    PayloadU8Len(self.suite.hkdf_algorithm.len()),
-                    kind,
-                    hs_hash,
-                )
-                .into_inner();
-            key_log.log(log_label, client_random, &secret);
+            key_log.log(log_label, client_random, secret.as_bytes());
         }
-        self.derive(self.suite.hkdf_algorithm, kind, hs_hash)
+        secret
     }
 
     /// Derive a secret of given `kind` using the hash of the empty string
     /// for the handshake hash.  Useful only for
     /// `SecretKind::ResumptionPSKBinderKey` and
     /// `SecretKind::DerivedSecret`.
-    fn derive_for_empty_hash<T>(&self, kind: SecretKind) -> T
-    where
-        T: for<'a> From<hkdf::Okm<'a, hkdf::Algorithm>>,
-    {
-        let digest_alg = self
-            .suite
-            .hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm();
-        let empty_hash = digest::digest(digest_alg, &[]);
-        self.derive(self.suite.hkdf_algorithm, kind, empty_hash.as_ref())
+    fn derive_for_empty_hash(&self, kind: SecretKind) -> Prk {
+        let empty_hash = self.hash.hash(&[]);
+        self.derive(kind, empty_hash.as_ref())
     }
 
     /// Sign the finished message consisting of `hs_hash` using a current
     /// traffic secret.
-    fn sign_finish(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
+    fn sign_finish(&self, base_key: &Prk, hs_hash: &hash::Output) -> hmac::Tag {
         self.sign_verify_data(base_key, hs_hash)
     }
 
     /// Sign the finished message consisting of `hs_hash` using the key material
     /// `base_key`.
-    fn sign_verify_data(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
-        let hmac_alg = self
-            .suite
-            .hkdf_algorithm
-            .hmac_algorithm();
-        let hmac_key = hkdf_expand(base_key, hmac_alg, b"finished", &[]);
-        hmac::sign(&hmac_key, hs_hash.as_ref())
+    fn sign_verify_data(&self, base_key: &Prk, hs_hash: &hash::Output) -> hmac::Tag {
+        let finished_key = hkdf_expand_label_prk(base_key, b"finished", &[]);
+        self.hmac()
+            .with_key(finished_key.as_bytes())
+            .sign(&[hs_hash.as_ref()])
     }
 
     /// Derive the next application traffic secret, returning it.
-    fn derive_next(&self, base_key: &hkdf::Prk) -> hkdf::Prk {
-        hkdf_expand(base_key, self.suite.hkdf_algorithm, b"traffic upd", &[])
+    fn derive_next_traffic_secret(&self, base_key: &Prk) -> Prk {
+        hkdf_expand_label_prk(base_key, b"traffic upd", &[])
     }
 
     /// Derive the PSK to use given a resumption_master_secret and
     /// ticket_nonce.
-    fn derive_ticket_psk(&self, rms: &hkdf::Prk, nonce: &[u8]) -> Vec<u8> {
-        let payload: PayloadU8 = hkdf_expand(
-            rms,
-            PayloadU8Len(self.suite.hkdf_algorithm.len()),
-            b"resumption",
-            nonce,
-        );
-        payload.into_inner()
+    fn derive_ticket_psk(&self, rms: &Prk, nonce: &[u8]) -> Vec<u8> {
+        hkdf_expand_label_prk(rms, b"resumption", nonce)
+            .as_bytes()
+            .to_vec()
     }
 
     fn export_keying_material(
         &self,
-        current_exporter_secret: &hkdf::Prk,
+        current_exporter_secret: &Prk,
         out: &mut [u8],
         label: &[u8],
         context: Option<&[u8]>,
     ) -> Result<(), Error> {
-        let digest_alg = self
-            .suite
-            .hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm();
-
-        let h_empty = digest::digest(digest_alg, &[]);
-        let secret: hkdf::Prk = hkdf_expand(
-            current_exporter_secret,
-            self.suite.hkdf_algorithm,
-            label,
-            h_empty.as_ref(),
-        );
+        let h_empty = self.hash.hash(&[]);
+        let secret = hkdf_expand_label_prk(current_exporter_secret, label, h_empty.as_ref());
 
-        let h_context = digest::digest(digest_alg, context.unwrap_or(&[]));
+        let h_context = self.hash.hash(context.unwrap_or(&[]));
 
         // TODO: Test what happens when this fails
-        hkdf_expand_info(
-            &secret,
-            PayloadU8Len(out.len()),
-            b"exporter",
-            h_context.as_ref(),
-            |okm| okm.fill(out),
-        )
-        .map_err(|_| Error::General("exporting too much".to_string()))
+        hkdf_expand_label(&secret, b"exporter", h_context.as_ref(), out)
+            .map_err(|_| Error::General("exporting too much".to_string()))
+    }
+}
+
+/// A HKDF pseudorandom key (RFC5869), used with the crypto provider's HMAC.
+#[derive(Clone)]
+pub(crate) struct Prk {
+    hmac: &'static dyn hmac::Hmac,
+    secret: hmac::Tag,
+}
+
+impl Prk {
+    /// HKDF-Extract: the key for input keying material `ikm` and `salt`.
+    pub(crate) fn extract(hmac: &'static dyn hmac::Hmac, salt: &[u8], ikm: &[u8]) -> Self {
+        Self {
+            hmac,
+            secret: hmac.with_key(salt).sign(&[ikm]),
+        }
+    }
+
+    /// Use `secret` as the key directly, without extraction.
+    pub(crate) fn new_less_safe(hmac: &'static dyn hmac::Hmac, secret: &[u8]) -> Self {
+        Self {
+            hmac,
+            secret: hmac::Tag::new(secret),
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.secret.as_ref()
+    }
+
+    /// HKDF-Expand: fill `out` with output keying material for `info`,
+    /// which is the concatenation of its slices.
+    ///
+    /// Fails if `out` is longer than HKDF allows.
+    fn expand(&self, info: &[&[u8]], out: &mut [u8]) -> Result<(), ()> {
+        let key = self.hmac.with_key(self.as_bytes());
+        let block_len = key.tag_len();
+        if out.len() > 255 * block_len {
+            return Err(());
+        }
+
+        let mut previous: Option<hmac::Tag> = None;
+        for (i, block) in out.chunks_mut(block_len).enumerate() {
+            let counter = [i as u8 + 1];
+            let mut data = Vec::with_capacity(info.len() + 2);
+            if let Some(previous) = &previous {
+                data.push(previous.as_ref());
+            }
+            data.extend_from_slice(info);
+            data.push(&counter[..]);
+
+            let t = key.sign(&data);
+            block.copy_from_slice(&t.as_ref()[..block.len()]);
+            previous = Some(t);
+        }
+        Ok(())
     }
 }
 
-pub(crate) fn hkdf_expand<T, L>(secret: &hkdf::Prk, key_type: L, label: &[u8], context: &[u8]) -> T
-where
-    T: for<'a> From<hkdf::Okm<'a, L>>,
-    L: hkdf::KeyType,
-{
-    hkdf_expand_info(secret, key_type, label, context, |okm| okm.into())
+impl fmt::Debug for Prk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Prk")
+            .finish_non_exhaustive()
+    }
 }
 
-fn hkdf_expand_info<F, T, L>(
-    secret: &hkdf::Prk,
-    key_type: L,
+/// HKDF-Expand-Label (RFC8446 section 7.1), filling `out`.
+///
+/// Fails if `out` is longer than HKDF allows.
+pub(crate) fn hkdf_expand_label(
+    secret: &Prk,
     label: &[u8],
     context: &[u8],
-    f: F,
-) -> T
-where
-    F: for<'b> FnOnce(hkdf::Okm<'b, L>) -> T,
-    L: hkdf::KeyType,
-{
+    out: &mut [u8],
+) -> Result<(), ()> {
     const LABEL_PREFIX: &[u8] = b"tls13 ";
 
-    let output_len = u16::to_be_bytes(key_type.len() as u16);
+    let output_len = u16::to_be_bytes(out.len() as u16);
     let label_len = u8::to_be_bytes((LABEL_PREFIX.len() + label.len()) as u8);
     let context_len = u8::to_be_bytes(context.len() as u8);
 
@@ -798,40 +908,54 @@ where
         &context_len[..],
         context,
     ];
-    let okm = secret.expand(info, key_type).unwrap();
-
-    f(okm)
+    secret.expand(info, out)
 }
 
-pub(crate) struct PayloadU8Len(pub(crate) usize);
-impl hkdf::KeyType for PayloadU8Len {
-    fn len(&self) -> usize {
-        self.0
-    }
+/// As `hkdf_expand_label`, producing a new secret as long as the hash.
+pub(crate) fn hkdf_expand_label_prk(secret: &Prk, label: &[u8], context: &[u8]) -> Prk {
+    let mut buf = [0u8; hmac::Tag::MAX_LEN];
+    let out = &mut buf[..secret.hmac.hash_output_len()];
+    hkdf_expand_label(secret, label, context, out).unwrap();
+    Prk::new_less_safe(secret.hmac, out)
 }
 
-impl From<hkdf::Okm<'_, PayloadU8Len>> for PayloadU8 {
-    fn from(okm: hkdf::Okm<PayloadU8Len>) -> Self {
-        let mut r = vec![0u8; okm.len().0];
-        okm.fill(&mut r[..]).unwrap();
-        Self::new(r)
-    }
+/// As `hkdf_expand_label`, producing a `len`-byte key.
+pub(crate) fn hkdf_expand_label_key(
+    secret: &Prk,
+    label: &[u8],
+    context: &[u8],
+    len: usize,
+) -> Vec<u8> {
+    let mut key = vec![0u8; len];
+    hkdf_expand_label(secret, label, context, &mut key).unwrap();
+    key
 }
 
 pub(crate) fn derive_traffic_key(
-    secret: &hkdf::Prk,
+    secret: &Prk,
     aead_algorithm: &'static aead::Algorithm,
 ) -> aead::UnboundKey {
-    hkdf_expand(secret, aead_algorithm, b"key", &[])
+    let key = hkdf_expand_label_key(secret, b"key", &[], aead_algorithm.key_len());
+    aead::UnboundKey::new(aead_algorithm, &key).unwrap()
+}
+
+pub(crate) fn derive_traffic_iv(secret: &Prk) -> Iv {
+    derive_iv(secret, b"iv")
 }
 
-pub(crate) fn derive_traffic_iv(secret: &hkdf::Prk) -> Iv {
-    hkdf_expand(secret, IvLen, b"iv", &[])
+/// Derive a write IV from `secret` using `label`.
+pub(crate) fn derive_iv(secret: &Prk, label: &[u8]) -> Iv {
+    let mut iv = Iv::default();
+    hkdf_expand_label(secret, label, &[], &mut iv.0).unwrap();
+    iv
 }
 
 #[cfg(test)]
 mod test {
     use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, SecretKind};
+    use crate::crypto::hash::HashAlgorithm;
+    use crate::crypto::ring::Ring;
+    use crate::crypto::CryptoProvider;
     use crate::tls13::TLS13_CHACHA20_POLY1305_SHA256_INTERNAL;
     use crate::KeyLog;
     use ring::aead;
@@ -917,7 +1041,11 @@ mod test {
             0x0d, 0xb2, 0x8f, 0x98, 0x85, 0x86, 0xa1, 0xb7, 0xe4, 0xd5, 0xc6, 0x9c,
         ];
 
-        let mut ks = KeySchedule::new_with_empty_secret(TLS13_CHACHA20_POLY1305_SHA256_INTERNAL);
+        let mut ks = KeySchedule::new_with_empty_secret(
+            TLS13_CHACHA20_POLY1305_SHA256_INTERNAL,
+            Ring::transcript_hash(HashAlgorithm::SHA256).unwrap(),
+            Ring::hmac(HashAlgorithm::SHA256).unwrap(),
+        );
         ks.input_secret(&ecdhe_secret);
 
         assert_traffic_secret(
@@ -1007,6 +1135,9 @@ mod benchmarks {
     #[bench]
     fn bench_sha256(b: &mut test::Bencher) {
         use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, SecretKind};
+        use crate::crypto::hash::HashAlgorithm;
+        use crate::crypto::ring::Ring;
+        use crate::crypto::CryptoProvider;
         use crate::tls13::TLS13_CHACHA20_POLY1305_SHA256_INTERNAL;
         use crate::KeyLog;
         use ring::aead;
@@ -1026,8 +1157,11 @@ mod benchmarks {
         }
 
         b.iter(|| {
-            let mut ks =
-                KeySchedule::new_with_empty_secret(TLS13_CHACHA20_POLY1305_SHA256_INTERNAL);
+            let mut ks = KeySchedule::new_with_empty_secret(
+                TLS13_CHACHA20_POLY1305_SHA256_INTERNAL,
+                Ring::transcript_hash(HashAlgorithm::SHA256).unwrap(),
+                Ring::hmac(HashAlgorithm::SHA256).unwrap(),
+            );
             ks.input_secret(&[0u8; 32]);
 
             extract_traffic_secret(&ks, SecretKind::ClientHandshakeTrafficSecret);
@@ -8,15 +8,19 @@ use std::io::Read;
 /// appending a new byte vector, at the expense of
 /// more complexity when reading out.
 pub(crate) struct ChunkVecBuffer {
+    /// How many bytes of the first chunk have already been read out.
+    prefix_used: usize,
     chunks: VecDeque<Vec<u8>>,
-    limit: Option<usize>,
+    /// The most bytes we may store; `usize::MAX` for no limit.
+    limit: usize,
 }
 
 impl ChunkVecBuffer {
     pub(crate) fn new(limit: Option<usize>) -> Self {
         Self {
+            prefix_used: 0,
             chunks: VecDeque::new(),
-            limit,
+            limit: limit.unwrap_or(usize::MAX),
         }
     }
 
@@ -28,7 +32,7 @@ impl ChunkVecBuffer {
     ///
     /// A [`None`] limit is interpreted as no limit.
     pub(crate) fn set_limit(&mut self, new_limit: Option<usize>) {
-        self.limit = new_limit;
+        self.limit = new_limit.unwrap_or(usize::MAX);
     }
 
     /// If we're empty
@@ -37,9 +41,7 @@ impl ChunkVecBuffer {
     }
 
     pub(crate) fn is_full(&self) -> bool {
-        self.limit
-            .map(|limit| self.len() > limit)
-            .unwrap_or_default()
+        self.len() > self.limit
     }
 
     /// How many bytes we're storing
@@ -48,19 +50,15 @@ impl ChunkVecBuffer {
         for ch in &self.chunks {
             len += ch.len();
         }
-        len
+        len - self.prefix_used
     }
 
     /// For a proposed append of `len` bytes, how many
     /// bytes should we actually append to adhere to the
     /// currently set `limit`?
     pub(crate) fn apply_limit(&self, len: usize) -> usize {
-        if let Some(limit) = self.limit {
-            let space = limit.saturating_sub(self.len());
-            cmp::min(len, space)
-        } else {
-            len
-        }
+        let space = self.limit.saturating_sub(self.len());
+        cmp::min(len, space)
     }
 
     /// Append a copy of `bytes`, perhaps a prefix if
@@ -85,7 +83,18 @@ impl ChunkVecBuffer {
     /// Take one of the chunks from this object.  This
     /// function panics if the object `is_empty`.
     pub(crate) fn pop(&mut self) -> Option<Vec<u8>> {
-        self.chunks.pop_front()
+        let mut first = self.chunks.pop_front();
+        if let Some(first) = &mut first {
+            first.drain(..core::mem::take(&mut self.prefix_used));
+        }
+        first
+    }
+
+    /// The unread part of the first chunk, if any.
+    pub(crate) fn chunk(&self) -> Option<&[u8]> {
+        self.chunks
+            .front()
+            .map(|chunk| &chunk[self.prefix_used..])
     }
 
     /// Read data out of this object, writing it into `buf`
@@ -93,10 +102,12 @@ impl ChunkVecBuffer {
     pub(crate) fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let mut offs = 0;
 
-        while offs < buf.len() && !self.is_empty() {
-            let used = self.chunks[0]
-                .as_slice()
-                .read(&mut buf[offs..])?;
+        while offs < buf.len() {
+            let mut chunk = match self.chunk() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let used = chunk.read(&mut buf[offs..])?;
 
             self.consume(used);
             offs += used;
@@ -108,8 +119,11 @@ impl ChunkVecBuffer {
     #[cfg(read_buf)]
     /// Read data out of this object, writing it into `cursor`.
     pub(crate) fn read_buf(&mut self, mut cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
-        while !self.is_empty() && cursor.capacity() > 0 {
-            let chunk = self.chunks[0].as_slice();
+        while cursor.capacity() > 0 {
+            let chunk = match self.chunk() {
+                Some(chunk) => chunk,
+                None => break,
+            };
             let used = core::cmp::min(chunk.len(), cursor.capacity());
             cursor.append(&chunk[..used]);
             self.consume(used);
@@ -118,16 +132,17 @@ impl ChunkVecBuffer {
         Ok(())
     }
 
-    fn consume(&mut self, mut used: usize) {
-        while let Some(mut buf) = self.chunks.pop_front() {
-            if used < buf.len() {
-                self.chunks
-                    .push_front(buf.split_off(used));
-                break;
-            } else {
-                used -= buf.len();
+    /// Discard the first `used` bytes.
+    pub(crate) fn consume(&mut self, used: usize) {
+        self.prefix_used += used;
+        while let Some(first) = self.chunks.front() {
+            if self.prefix_used < first.len() {
+                return;
             }
+            self.prefix_used -= first.len();
+            self.chunks.pop_front();
         }
+        self.prefix_used = 0;
     }
 
     /// Read data out of this object, passing it `wr`
@@ -140,6 +155,7 @@ impl ChunkVecBuffer {
         for (iov, chunk) in bufs.iter_mut().zip(self.chunks.iter()) {
             *iov = io::IoSlice::new(chunk);
         }
+        bufs[0] = io::IoSlice::new(&self.chunks[0][self.prefix_used..]);
         let len = cmp::min(bufs.len(), self.chunks.len());
         let used = wr.write_vectored(&bufs[..len])?;
         self.consume(used);
@@ -164,6 +180,25 @@ mod test {
         assert_eq!(buf.to_vec(), b"helloworldhe".to_vec());
     }
 
+    #[test]
+    fn partial_reads_and_consume() {
+        let mut cvb = ChunkVecBuffer::new(None);
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+
+        let mut buf = [0u8; 3];
+        assert_eq!(cvb.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        assert_eq!(cvb.len(), 7);
+        assert_eq!(cvb.chunk(), Some(&b"lo"[..]));
+
+        cvb.consume(3);
+        assert_eq!(cvb.chunk(), Some(&b"orld"[..]));
+        assert_eq!(cvb.pop(), Some(b"orld".to_vec()));
+        assert!(cvb.is_empty());
+        assert_eq!(cvb.chunk(), None);
+    }
+
     #[cfg(read_buf)]
     #[test]
     fn read_buf() {
@@ -1,6 +1,7 @@
+use core::any::Any;
 use core::fmt;
 
-use crate::anchors::{OwnedTrustAnchor, RootCertStore};
+use crate::anchors::{OwnedTrustAnchor, RootCertStore, SharedRootCertStore};
 use crate::client::ServerName;
 use crate::enums::SignatureScheme;
 use crate::error::{
@@ -8,12 +9,14 @@ use crate::error::{
 };
 use crate::key::{Certificate, ParsedCertificate};
 #[cfg(feature = "logging")]
-use crate::log::trace;
+use crate::log::{debug, trace};
 use crate::msgs::base::PayloadU16;
 use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::enums::NamedGroup;
 use crate::msgs::handshake::DistinguishedName;
+use crate::x509::{self, PublicKey};
 
-use ring::digest::Digest;
+use crate::crypto::hash;
 
 use alloc::sync::Arc;
 use std::time::SystemTime;
@@ -120,6 +123,57 @@ pub trait ServerCertVerifier: Send + Sync {
         now: SystemTime,
     ) -> Result<ServerCertVerified, Error>;
 
+    /// Like [`ServerCertVerifier::verify_server_cert`], but also given the
+    /// value attached to the connection with [`CommonState::set_user_data`].
+    ///
+    /// This is what rustls calls.  The default implementation ignores
+    /// `user_data` and calls [`ServerCertVerifier::verify_server_cert`].
+    ///
+    /// [`CommonState::set_user_data`]: crate::CommonState::set_user_data
+    fn verify_server_cert_with_user_data(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: SystemTime,
+        user_data: Option<&(dyn Any + Send + Sync)>,
+    ) -> Result<ServerCertVerified, Error> {
+        let _ = user_data;
+        self.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    /// Look at a certificate from the server's chain as soon as it
+    /// arrives, before the rest of the chain.
+    ///
+    /// `index` is the certificate's position in the chain: 0 for the
+    /// end-entity certificate.  This is called once for each certificate,
+    /// in order.  Returning an error aborts the handshake without waiting
+    /// for the rest of a long chain; otherwise
+    /// [`ServerCertVerifier::verify_server_cert`] is still called with the
+    /// whole chain once it has arrived.
+    ///
+    /// The default implementation accepts every certificate.
+    fn precheck_certificate(&self, index: usize, certificate: &Certificate) -> Result<(), Error> {
+        let _ = (index, certificate);
+        Ok(())
+    }
+
+    /// The trust anchors that [`ServerCertVerifier::verify_server_cert`]
+    /// accepts chains to, if there is a fixed set of them.
+    ///
+    /// This is used to find the issuer of a server's certificate when the
+    /// server does not send it, which is normal when the issuer is a trust
+    /// anchor.  In particular, [`ClientConfig::require_ocsp_staple`] needs
+    /// the issuer to check the stapled response.
+    ///
+    /// The default implementation returns `None`.
+    ///
+    /// [`ClientConfig::require_ocsp_staple`]: crate::ClientConfig::require_ocsp_staple
+    fn trust_anchors(&self) -> Option<Arc<RootCertStore>> {
+        None
+    }
+
     /// Verify a signature allegedly by the given server certificate.
     ///
     /// `message` is not hashed, and needs hashing during the verification.
@@ -219,7 +273,10 @@ pub trait ClientCertVerifier: Send + Sync {
     /// [`CertificateRequest`]: https://datatracker.ietf.org/doc/html/rfc8446#section-4.3.2
     /// [`certificate_authorities`]: https://datatracker.ietf.org/doc/html/rfc8446#section-4.2.4
     ///
-    /// If the return value is empty, no CertificateRequest message will be sent.
+    /// If the return value is empty, no names are sent.  This can be
+    /// overridden with [`ServerConfig::client_auth_root_hints`].
+    ///
+    /// [`ServerConfig::client_auth_root_hints`]: crate::ServerConfig::client_auth_root_hints
     fn client_auth_root_subjects(&self) -> &[DistinguishedName];
 
     /// Verify the end-entity certificate `end_entity` is valid, acceptable,
@@ -243,6 +300,40 @@ pub trait ClientCertVerifier: Send + Sync {
         now: SystemTime,
     ) -> Result<ClientCertVerified, Error>;
 
+    /// Like [`ClientCertVerifier::verify_client_cert`], but also given the
+    /// value attached to the connection with [`CommonState::set_user_data`].
+    ///
+    /// This is what rustls calls.  The default implementation ignores
+    /// `user_data` and calls [`ClientCertVerifier::verify_client_cert`].
+    ///
+    /// [`CommonState::set_user_data`]: crate::CommonState::set_user_data
+    fn verify_client_cert_with_user_data(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+        user_data: Option<&(dyn Any + Send + Sync)>,
+    ) -> Result<ClientCertVerified, Error> {
+        let _ = user_data;
+        self.verify_client_cert(end_entity, intermediates, now)
+    }
+
+    /// Look at a certificate from the client's chain as soon as it
+    /// arrives, before the rest of the chain.
+    ///
+    /// `index` is the certificate's position in the chain: 0 for the
+    /// end-entity certificate.  This is called once for each certificate,
+    /// in order.  Returning an error aborts the handshake without waiting
+    /// for the rest of a long chain; otherwise
+    /// [`ClientCertVerifier::verify_client_cert`] is still called with the
+    /// whole chain once it has arrived.
+    ///
+    /// The default implementation accepts every certificate.
+    fn precheck_certificate(&self, index: usize, certificate: &Certificate) -> Result<(), Error> {
+        let _ = (index, certificate);
+        Ok(())
+    }
+
     /// Verify a signature allegedly by the given client certificate.
     ///
     /// `message` is not hashed, and needs hashing during the verification.
@@ -323,9 +414,21 @@ pub fn verify_server_cert_signed_by_trust_anchor(
     roots: &RootCertStore,
     intermediates: &[Certificate],
     now: SystemTime,
+) -> Result<(), Error> {
+    verify_server_cert_chain(cert, roots, intermediates, now, &[])
+}
+
+/// Like [`verify_server_cert_signed_by_trust_anchor`], also checking
+/// revocation against `crls`.
+fn verify_server_cert_chain(
+    cert: &ParsedCertificate,
+    roots: &RootCertStore,
+    intermediates: &[Certificate],
+    now: SystemTime,
+    crls: &[webpki::OwnedCertRevocationList],
 ) -> Result<(), Error> {
     let chain = intermediate_chain(intermediates);
-    let trust_roots = trust_roots(roots);
+    let trust_roots = trust_roots(roots, cert);
     let webpki_now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
 
     cert.0
@@ -335,7 +438,7 @@ pub fn verify_server_cert_signed_by_trust_anchor(
             &chain,
             webpki_now,
             webpki::KeyUsage::server_auth(),
-            &[], // no CRLs
+            &crl_refs(crls),
         )
         .map_err(pki_error)
         .map(|_| ())
@@ -384,23 +487,87 @@ impl ServerCertVerifier for WebPkiVerifier {
         now: SystemTime,
     ) -> Result<ServerCertVerified, Error> {
         let cert = ParsedCertificate::try_from(end_entity)?;
+        let roots = self.roots.load();
+
+        match (
+            verify_server_cert_chain(&cert, &roots, intermediates, now, &self.crls),
+            &self.intermediate_fetcher,
+        ) {
+            (Err(Error::InvalidCertificate(CertificateError::UnknownIssuer)), Some(fetcher)) => {
+                verify_with_fetched_intermediates(
+                    fetcher.as_ref(),
+                    &cert,
+                    end_entity,
+                    &roots,
+                    intermediates,
+                    now,
+                    &self.crls,
+                )?
+            }
+            (result, _) => result?,
+        }
 
-        verify_server_cert_signed_by_trust_anchor(&cert, &self.roots, intermediates, now)?;
+        if let Some(requirements) = &self.requirements {
+            requirements.check(end_entity, intermediates, x509::ID_KP_SERVER_AUTH)?;
+        }
 
         if !ocsp_response.is_empty() {
             trace!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
         }
 
-        verify_server_name(&cert, server_name)?;
+        match &self.server_name_policy {
+            ServerNamePolicy::Verify => verify_server_name(&cert, server_name)?,
+            ServerNamePolicy::VerifyAnyOf(names) => {
+                if !names
+                    .iter()
+                    .any(|name| verify_server_name(&cert, name).is_ok())
+                {
+                    return Err(Error::InvalidCertificate(CertificateError::NotValidForName));
+                }
+            }
+            ServerNamePolicy::Skip => {}
+        }
+
+        if !self.spki_pins.is_empty()
+            && !core::iter::once(end_entity)
+                .chain(intermediates)
+                .filter_map(|cert| cert.details().ok())
+                .any(|details| {
+                    self.spki_pins
+                        .contains(&details.subject_public_key_info_sha256())
+                })
+        {
+            return Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        for policy in &self.policies {
+            policy.check(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+
         Ok(ServerCertVerified::assertion())
     }
+
+    fn trust_anchors(&self) -> Option<Arc<RootCertStore>> {
+        Some(self.roots.load())
+    }
 }
 
 /// Default `ServerCertVerifier`, see the trait impl for more information.
+///
+/// Use [`WebPkiVerifier::builder`] to add checks beyond standard path
+/// validation and name checking.
 #[allow(unreachable_pub)]
 #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
 pub struct WebPkiVerifier {
-    roots: RootCertStore,
+    roots: SharedRootCertStore,
+    intermediate_fetcher: Option<Arc<dyn FetchesIntermediates>>,
+    crls: Vec<webpki::OwnedCertRevocationList>,
+    requirements: Option<CertificateRequirements>,
+    server_name_policy: ServerNamePolicy,
+    spki_pins: Vec<[u8; 32]>,
+    policies: Vec<Arc<dyn ServerCertPolicy>>,
 }
 
 #[allow(unreachable_pub)]
@@ -409,7 +576,51 @@ impl WebPkiVerifier {
     ///
     /// `roots` is the set of trust anchors to trust for issuing server certs.
     pub fn new(roots: RootCertStore) -> Self {
-        Self { roots }
+        Self::new_with_shared_roots(SharedRootCertStore::new(roots))
+    }
+
+    /// Constructs a new `WebPkiVerifier` which trusts whatever `roots`
+    /// contains at the time each certificate is verified.
+    pub fn new_with_shared_roots(roots: SharedRootCertStore) -> Self {
+        Self::builder_with_shared_roots(roots).build()
+    }
+
+    /// Start building a `WebPkiVerifier` that trusts `roots`.
+    #[cfg_attr(not(feature = "dangerous_configuration"), allow(dead_code))]
+    pub fn builder(roots: RootCertStore) -> WebPkiVerifierBuilder {
+        Self::builder_with_shared_roots(SharedRootCertStore::new(roots))
+    }
+
+    /// Start building a `WebPkiVerifier` which trusts whatever `roots`
+    /// contains at the time each certificate is verified.
+    pub fn builder_with_shared_roots(roots: SharedRootCertStore) -> WebPkiVerifierBuilder {
+        WebPkiVerifierBuilder {
+            verifier: Self {
+                roots,
+                intermediate_fetcher: None,
+                crls: Vec::new(),
+                requirements: None,
+                server_name_policy: ServerNamePolicy::default(),
+                spki_pins: Vec::new(),
+                policies: Vec::new(),
+            },
+        }
+    }
+
+    /// Fetch missing intermediate certificates with `fetcher`.
+    ///
+    /// When a server's certificate does not chain to a trust anchor because
+    /// the server left out an intermediate, `fetcher` is given the
+    /// `caIssuers` URLs from the end-entity certificate, and the chain is
+    /// built again with the certificates it returns.  This repeats for the
+    /// fetched certificates' own URLs, up to a small limit on the number of
+    /// fetches.
+    ///
+    /// By default, nothing is fetched and such chains are rejected.
+    #[cfg_attr(not(feature = "dangerous_configuration"), allow(dead_code))]
+    pub fn with_intermediate_fetcher(mut self, fetcher: Arc<dyn FetchesIntermediates>) -> Self {
+        self.intermediate_fetcher = Some(fetcher);
+        self
     }
 
     /// Returns the signature verification methods supported by
@@ -429,6 +640,329 @@ impl WebPkiVerifier {
     }
 }
 
+/// Builds a [`WebPkiVerifier`] with checks layered over standard path
+/// validation.
+///
+/// Verification proceeds in this order, stopping at the first error:
+///
+/// 1. the chain is built to a trust anchor, fetching intermediates and
+///    checking revocation if configured;
+/// 2. the chain is checked against any [`CertificateRequirements`];
+/// 3. the certificate's names are checked according to the
+///    [`ServerNamePolicy`];
+/// 4. if any key pins are configured, one must match;
+/// 5. each [`ServerCertPolicy`] is checked, in the order added.
+///
+/// ```
+/// # #[cfg(feature = "dangerous_configuration")] {
+/// # use rustls::RootCertStore;
+/// use rustls::client::{ServerNamePolicy, WebPkiVerifier};
+///
+/// # let roots = RootCertStore::empty();
+/// let verifier = WebPkiVerifier::builder(roots)
+///     .with_server_name_policy(ServerNamePolicy::VerifyAnyOf(vec![
+///         "internal.example.com".try_into().unwrap(),
+///     ]))
+///     .build();
+/// # }
+/// ```
+#[allow(unreachable_pub)]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+pub struct WebPkiVerifierBuilder {
+    verifier: WebPkiVerifier,
+}
+
+#[allow(unreachable_pub)]
+#[cfg_attr(not(feature = "dangerous_configuration"), allow(dead_code))]
+impl WebPkiVerifierBuilder {
+    /// Fetch missing intermediate certificates with `fetcher`.
+    ///
+    /// See [`WebPkiVerifier::with_intermediate_fetcher`].
+    pub fn with_intermediate_fetcher(mut self, fetcher: Arc<dyn FetchesIntermediates>) -> Self {
+        self.verifier.intermediate_fetcher = Some(fetcher);
+        self
+    }
+
+    /// Check the revocation status of the server's chain against the
+    /// provided DER format unparsed certificate revocation lists (CRLs).
+    ///
+    /// Certificates whose issuer has no CRL here are not checked.
+    pub fn with_crls(
+        mut self,
+        crls: impl IntoIterator<Item = UnparsedCertRevocationList>,
+    ) -> Result<Self, CertRevocationListError> {
+        self.verifier.crls = parse_crls(crls)?;
+        Ok(self)
+    }
+
+    /// Apply `requirements` to the server's certificate chain.
+    ///
+    /// If [`CertificateRequirements::require_extended_key_usage`] is set,
+    /// the end-entity certificate must allow server authentication.
+    pub fn with_requirements(mut self, requirements: CertificateRequirements) -> Self {
+        self.verifier.requirements = Some(requirements);
+        self
+    }
+
+    /// Choose how the certificate's names are checked.  The default is
+    /// [`ServerNamePolicy::Verify`].
+    pub fn with_server_name_policy(mut self, policy: ServerNamePolicy) -> Self {
+        self.verifier.server_name_policy = policy;
+        self
+    }
+
+    /// Require the server to send a certificate whose key is pinned.
+    ///
+    /// Each pin is the SHA-256 hash of a DER-encoded `SubjectPublicKeyInfo`,
+    /// as from [`CertificateDetails::subject_public_key_info_sha256`].
+    /// Verification fails with [`CertificateError::ApplicationVerificationFailure`]
+    /// unless the end-entity certificate or one of the intermediates sent
+    /// by the server has one of these keys.  Trust anchors are not sent, so
+    /// pinning one has no effect.  This can be called more than once, to
+    /// add more pins.
+    ///
+    /// [`CertificateDetails::subject_public_key_info_sha256`]: crate::CertificateDetails::subject_public_key_info_sha256
+    pub fn with_spki_pins(mut self, pins: impl IntoIterator<Item = [u8; 32]>) -> Self {
+        self.verifier.spki_pins.extend(pins);
+        self
+    }
+
+    /// Add `policy` to the checks made on every certificate.
+    ///
+    /// This can be called more than once: policies are checked in the order
+    /// they were added.
+    pub fn with_policy(mut self, policy: Arc<dyn ServerCertPolicy>) -> Self {
+        self.verifier.policies.push(policy);
+        self
+    }
+
+    /// Finish building the verifier.
+    pub fn build(self) -> WebPkiVerifier {
+        self.verifier
+    }
+}
+
+/// Requirements on a peer's certificates beyond those of standard path
+/// validation, for enforcing a compliance profile.
+///
+/// These apply to the end-entity certificate and to every intermediate
+/// the peer sent, but not to trust anchors.  The default requires nothing
+/// extra; set the fields to enable each check.
+///
+/// ```
+/// use rustls::{CertificateRequirements, NamedGroup};
+///
+/// let mut requirements = CertificateRequirements::default();
+/// requirements.require_extended_key_usage = true;
+/// requirements.min_rsa_modulus_bits = 3072;
+/// requirements.ecdsa_curves = Some(vec![NamedGroup::secp384r1]);
+/// requirements.reject_sha1_signatures = true;
+/// ```
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+pub struct CertificateRequirements {
+    /// Require the end-entity certificate to have an extended key usage
+    /// extension that lists the purpose it is being used for: server or
+    /// client authentication.
+    ///
+    /// Otherwise, a certificate without this extension is accepted for any
+    /// purpose.  Failures are reported as [`CertificateError::InvalidPurpose`].
+    pub require_extended_key_usage: bool,
+
+    /// The smallest allowed RSA modulus, in bits.
+    ///
+    /// RSA keys below 2048 bits are never accepted for signatures, whatever
+    /// this is set to.  Failures are reported as
+    /// [`CertificateError::UnacceptableKey`].
+    pub min_rsa_modulus_bits: usize,
+
+    /// The curves allowed for ECDSA keys, or `None` to allow every curve
+    /// that is supported.
+    ///
+    /// Failures are reported as [`CertificateError::UnacceptableKey`].
+    pub ecdsa_curves: Option<Vec<NamedGroup>>,
+
+    /// Reject the chain if any certificate in it was signed using SHA-1.
+    ///
+    /// SHA-1 signatures are never accepted when building a path to a trust
+    /// anchor.  This also rejects a peer that sends such a certificate
+    /// outside of that path.  Failures are reported as
+    /// [`CertificateError::UnacceptableSignatureAlgorithm`].
+    pub reject_sha1_signatures: bool,
+}
+
+impl CertificateRequirements {
+    /// Check `end_entity` and `intermediates`, which have been validated for
+    /// the extended key usage `purpose`.
+    fn check(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        purpose: &[u8],
+    ) -> Result<(), Error> {
+        for (i, cert) in core::iter::once(end_entity)
+            .chain(intermediates)
+            .enumerate()
+        {
+            let details = cert.details()?;
+            if i == 0 && self.require_extended_key_usage && !details.has_extended_key_usage(purpose)
+            {
+                return Err(CertificateError::InvalidPurpose.into());
+            }
+
+            if self.reject_sha1_signatures && details.is_signed_with_sha1() {
+                return Err(CertificateError::UnacceptableSignatureAlgorithm.into());
+            }
+
+            let acceptable = match details.public_key() {
+                Some(PublicKey::Rsa { modulus_bits }) => modulus_bits >= self.min_rsa_modulus_bits,
+                Some(PublicKey::Ecdsa { curve }) => match (&self.ecdsa_curves, curve) {
+                    (None, _) => true,
+                    (Some(allowed), Some(curve)) => allowed.contains(&curve),
+                    (Some(_), None) => false,
+                },
+                Some(PublicKey::Other) => true,
+                None => return Err(CertificateError::BadEncoding.into()),
+            };
+            if !acceptable {
+                return Err(CertificateError::UnacceptableKey.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How a [`WebPkiVerifier`] checks the names in a server's certificate.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+#[allow(unreachable_pub)]
+#[cfg_attr(not(feature = "dangerous_configuration"), allow(dead_code))]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+pub enum ServerNamePolicy {
+    /// The certificate must be valid for the name the client connected to.
+    Verify,
+
+    /// The certificate must be valid for one of these names, and need not
+    /// be valid for the name the client connected to.
+    ///
+    /// This suits servers reached by an address, or through a name that
+    /// differs from the one their certificate was issued for.
+    VerifyAnyOf(Vec<ServerName>),
+
+    /// The certificate's names are not checked at all.
+    ///
+    /// Any certificate that chains to a trust anchor is accepted for any
+    /// server, so this should only be used with trust anchors (or key pins)
+    /// that are specific to the server being connected to.
+    Skip,
+}
+
+impl Default for ServerNamePolicy {
+    fn default() -> Self {
+        Self::Verify
+    }
+}
+
+/// A check made on a server's certificate by a [`WebPkiVerifier`], after
+/// its chain and names have been verified.
+///
+/// Policies are added with [`WebPkiVerifierBuilder::with_policy`].  This
+/// allows requirements such as an OCSP stapling or Certificate Transparency
+/// policy to be layered over the standard verification, rather than
+/// needing a complete [`ServerCertVerifier`] for every tweak.
+#[allow(unreachable_pub)]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+pub trait ServerCertPolicy: Send + Sync {
+    /// Check the server's certificate.
+    ///
+    /// The arguments are those given to [`ServerCertVerifier::verify_server_cert`]:
+    /// `intermediates` are as sent by the server.  Return an error to fail
+    /// verification.
+    fn check(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<(), Error>;
+}
+
+/// Fetches intermediate certificates that a peer did not send, from the
+/// locations in the authority information access extension of the
+/// certificates it did.
+///
+/// This is called during the handshake, which cannot continue until it
+/// returns, so implementations should bound the time each fetch may take.
+/// See [`WebPkiVerifier::with_intermediate_fetcher`].
+#[allow(unreachable_pub)]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+pub trait FetchesIntermediates: Send + Sync {
+    /// Fetch the certificates at `url`, a `caIssuers` location.
+    ///
+    /// This is usually a single DER-encoded certificate, but may be a PKCS#7
+    /// "certs-only" bundle.  Return the certificates found, or an empty
+    /// `Vec` if there are none or the fetch failed.  They need not be
+    /// trustworthy: they are only used to build a chain to a trust anchor.
+    fn fetch(&self, url: &str) -> Vec<Certificate>;
+}
+
+/// The most `caIssuers` URLs fetched while verifying one certificate.
+const MAX_INTERMEDIATE_FETCHES: usize = 4;
+
+/// Retry chain building with the issuers of `end_entity` fetched by
+/// `fetcher`, then with the issuers of those, and so on.
+fn verify_with_fetched_intermediates(
+    fetcher: &dyn FetchesIntermediates,
+    cert: &ParsedCertificate,
+    end_entity: &Certificate,
+    roots: &RootCertStore,
+    intermediates: &[Certificate],
+    now: SystemTime,
+    crls: &[webpki::OwnedCertRevocationList],
+) -> Result<(), Error> {
+    let mut intermediates = intermediates.to_vec();
+    let mut chasing = vec![end_entity.clone()];
+    let mut fetches = 0;
+
+    while !chasing.is_empty() {
+        let mut fetched = Vec::new();
+        for url in chasing
+            .iter()
+            .filter_map(|cert| cert.details().ok())
+            .flat_map(|details| {
+                details
+                    .ca_issuer_urls()
+                    .unwrap_or_default()
+            })
+        {
+            if fetches == MAX_INTERMEDIATE_FETCHES {
+                break;
+            }
+            fetches += 1;
+            debug!("Fetching intermediates from {}", url);
+            for cert in fetcher.fetch(url) {
+                if !intermediates.contains(&cert) && !fetched.contains(&cert) {
+                    fetched.push(cert);
+                }
+            }
+        }
+
+        if fetched.is_empty() {
+            break;
+        }
+        intermediates.extend_from_slice(&fetched);
+        match verify_server_cert_chain(cert, roots, &intermediates, now, crls) {
+            Err(Error::InvalidCertificate(CertificateError::UnknownIssuer)) => {}
+            result => return result,
+        }
+        chasing = fetched;
+    }
+
+    Err(Error::InvalidCertificate(CertificateError::UnknownIssuer))
+}
+
 fn intermediate_chain(intermediates: &[Certificate]) -> Vec<&[u8]> {
     intermediates
         .iter()
@@ -436,10 +970,19 @@ fn intermediate_chain(intermediates: &[Certificate]) -> Vec<&[u8]> {
         .collect()
 }
 
-fn trust_roots(roots: &RootCertStore) -> Vec<webpki::TrustAnchor> {
+/// The trust anchors in `roots` that may issue `end_entity`.
+fn trust_roots<'a>(
+    roots: &'a RootCertStore,
+    end_entity: &ParsedCertificate,
+) -> Vec<webpki::TrustAnchor<'a>> {
     roots
         .roots
         .iter()
+        .filter(|anchor| match (anchor.distrust_after(), end_entity.1) {
+            (None, _) => true,
+            (Some(distrust_after), Some(not_before)) => not_before <= distrust_after,
+            (Some(_), None) => false,
+        })
         .map(OwnedTrustAnchor::to_trust_anchor)
         .collect()
 }
@@ -457,6 +1000,21 @@ impl UnparsedCertRevocationList {
     }
 }
 
+fn parse_crls(
+    crls: impl IntoIterator<Item = UnparsedCertRevocationList>,
+) -> Result<Vec<webpki::OwnedCertRevocationList>, CertRevocationListError> {
+    crls.into_iter()
+        .map(|der_crl| der_crl.parse())
+        .collect()
+}
+
+fn crl_refs(crls: &[webpki::OwnedCertRevocationList]) -> Vec<&dyn webpki::CertRevocationList> {
+    #[allow(trivial_casts)] // Cast to &dyn trait is required.
+    crls.iter()
+        .map(|crl| crl as &dyn webpki::CertRevocationList)
+        .collect()
+}
+
 /// A `ClientCertVerifier` that will ensure that every client provides a trusted
 /// certificate, without any name checking. Optionally, client certificates will
 /// have their revocation status checked using the DER encoded CRLs provided.
@@ -464,6 +1022,7 @@ pub struct AllowAnyAuthenticatedClient {
     roots: RootCertStore,
     subjects: Vec<DistinguishedName>,
     crls: Vec<webpki::OwnedCertRevocationList>,
+    requirements: Option<CertificateRequirements>,
 }
 
 impl AllowAnyAuthenticatedClient {
@@ -478,6 +1037,7 @@ impl AllowAnyAuthenticatedClient {
                 .map(|r| r.subject().clone())
                 .collect(),
             crls: Vec::new(),
+            requirements: None,
             roots,
         }
     }
@@ -489,14 +1049,31 @@ impl AllowAnyAuthenticatedClient {
         crls: impl IntoIterator<Item = UnparsedCertRevocationList>,
     ) -> Result<Self, CertRevocationListError> {
         Ok(Self {
-            crls: crls
-                .into_iter()
-                .map(|der_crl| der_crl.parse())
-                .collect::<Result<Vec<_>, CertRevocationListError>>()?,
+            crls: parse_crls(crls)?,
             ..self
         })
     }
 
+    /// Send `subjects` to clients as the acceptable certificate authorities,
+    /// instead of the subjects of the trust anchors.
+    ///
+    /// Clients use these to choose which certificate to present.  An empty
+    /// list sends no names, which hides which authorities are trusted.
+    pub fn with_root_hint_subjects(self, subjects: Vec<DistinguishedName>) -> Self {
+        Self { subjects, ..self }
+    }
+
+    /// Apply `requirements` to client certificate chains.
+    ///
+    /// If [`CertificateRequirements::require_extended_key_usage`] is set,
+    /// the end-entity certificate must allow client authentication.
+    pub fn with_requirements(self, requirements: CertificateRequirements) -> Self {
+        Self {
+            requirements: Some(requirements),
+            ..self
+        }
+    }
+
     /// Wrap this verifier in an [`Arc`] and coerce it to `dyn ClientCertVerifier`
     #[inline(always)]
     pub fn boxed(self) -> Arc<dyn ClientCertVerifier> {
@@ -523,16 +1100,9 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
     ) -> Result<ClientCertVerified, Error> {
         let cert = ParsedCertificate::try_from(end_entity)?;
         let chain = intermediate_chain(intermediates);
-        let trust_roots = trust_roots(&self.roots);
+        let trust_roots = trust_roots(&self.roots, &cert);
         let now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
 
-        #[allow(trivial_casts)] // Cast to &dyn trait is required.
-        let crls = self
-            .crls
-            .iter()
-            .map(|crl| crl as &dyn webpki::CertRevocationList)
-            .collect::<Vec<_>>();
-
         cert.0
             .verify_for_usage(
                 SUPPORTED_SIG_ALGS,
@@ -540,10 +1110,15 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
                 &chain,
                 now,
                 webpki::KeyUsage::client_auth(),
-                &crls,
+                &crl_refs(&self.crls),
             )
-            .map_err(pki_error)
-            .map(|_| ClientCertVerified::assertion())
+            .map_err(pki_error)?;
+
+        if let Some(requirements) = &self.requirements {
+            requirements.check(end_entity, intermediates, x509::ID_KP_CLIENT_AUTH)?;
+        }
+
+        Ok(ClientCertVerified::assertion())
     }
 }
 
@@ -578,6 +1153,28 @@ impl AllowAnyAnonymousOrAuthenticatedClient {
         })
     }
 
+    /// Send `subjects` to clients as the acceptable certificate authorities.
+    ///
+    /// See [`AllowAnyAuthenticatedClient::with_root_hint_subjects`].
+    pub fn with_root_hint_subjects(self, subjects: Vec<DistinguishedName>) -> Self {
+        Self {
+            inner: self
+                .inner
+                .with_root_hint_subjects(subjects),
+        }
+    }
+
+    /// Apply `requirements` to client certificate chains.
+    ///
+    /// See [`AllowAnyAuthenticatedClient::with_requirements`].
+    pub fn with_requirements(self, requirements: CertificateRequirements) -> Self {
+        Self {
+            inner: self
+                .inner
+                .with_requirements(requirements),
+        }
+    }
+
     /// Wrap this verifier in an [`Arc`] and coerce it to `dyn ClientCertVerifier`
     #[inline(always)]
     pub fn boxed(self) -> Arc<dyn ClientCertVerifier> {
@@ -609,6 +1206,22 @@ impl ClientCertVerifier for AllowAnyAnonymousOrAuthenticatedClient {
         self.inner
             .verify_client_cert(end_entity, intermediates, now)
     }
+
+    fn verify_client_cert_with_user_data(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+        user_data: Option<&(dyn Any + Send + Sync)>,
+    ) -> Result<ClientCertVerified, Error> {
+        self.inner
+            .verify_client_cert_with_user_data(end_entity, intermediates, now, user_data)
+    }
+
+    fn precheck_certificate(&self, index: usize, certificate: &Certificate) -> Result<(), Error> {
+        self.inner
+            .precheck_certificate(index, certificate)
+    }
 }
 
 pub(crate) fn pki_error(error: webpki::Error) -> Error {
@@ -758,7 +1371,8 @@ fn verify_sig_using_any_alg(
     Err(webpki::Error::UnsupportedSignatureAlgorithmForPublicKey)
 }
 
-fn verify_signed_struct(
+/// Verify `dss`, a signature over `message` made with the key of `cert`.
+pub(crate) fn verify_signed_struct(
     message: &[u8],
     cert: &Certificate,
     dss: &DigitallySignedStruct,
@@ -787,18 +1401,81 @@ fn convert_alg_tls13(
     }
 }
 
+/// Keep only the schemes in `schemes` that are in `allowed`.
+///
+/// `allowed` is `None` if the config does not restrict signature schemes.
+pub(crate) fn allowed_signature_schemes(
+    allowed: Option<&[SignatureScheme]>,
+    mut schemes: Vec<SignatureScheme>,
+) -> Vec<SignatureScheme> {
+    if let Some(allowed) = allowed {
+        schemes.retain(|scheme| allowed.contains(scheme));
+    }
+    schemes
+}
+
+/// Check the peer signed the handshake with a scheme in `allowed`.
+pub(crate) fn check_signature_scheme(
+    allowed: Option<&[SignatureScheme]>,
+    scheme: SignatureScheme,
+) -> Result<(), Error> {
+    match allowed {
+        Some(allowed) if !allowed.contains(&scheme) => {
+            Err(PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check the certificates in a peer's chain were signed with schemes
+/// in `allowed`.
+///
+/// Self-signed certificates are not checked: their signatures are not
+/// relied upon, so they are usually trust anchors.
+pub(crate) fn check_certificate_signatures(
+    allowed: Option<&[SignatureScheme]>,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+) -> Result<(), Error> {
+    let allowed = match allowed {
+        Some(allowed) => allowed,
+        None => return Ok(()),
+    };
+
+    for cert in Some(end_entity)
+        .into_iter()
+        .chain(intermediates)
+    {
+        let details = x509::CertificateDetails::parse(&cert.0)?;
+        if details.issuer().as_ref() == details.subject().as_ref() {
+            continue;
+        }
+
+        match details.signature_scheme() {
+            Some(scheme) if allowed.contains(&scheme) => {}
+            _ => {
+                return Err(Error::InvalidCertificate(
+                    CertificateError::UnacceptableSignatureAlgorithm,
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
-pub(crate) fn construct_tls13_client_verify_message(handshake_hash: &Digest) -> Vec<u8> {
+pub(crate) fn construct_tls13_client_verify_message(handshake_hash: &hash::Output) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, client CertificateVerify\x00")
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
-pub(crate) fn construct_tls13_server_verify_message(handshake_hash: &Digest) -> Vec<u8> {
+pub(crate) fn construct_tls13_server_verify_message(handshake_hash: &hash::Output) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, server CertificateVerify\x00")
 }
 
 fn construct_tls13_verify_message(
-    handshake_hash: &Digest,
+    handshake_hash: &hash::Output,
     context_string_with_0: &[u8],
 ) -> Vec<u8> {
     let mut msg = Vec::new();
@@ -846,6 +1523,73 @@ mod tests {
         );
     }
 
+    struct Fetcher {
+        fetched: std::sync::Mutex<Vec<String>>,
+        intermediate: Option<Certificate>,
+    }
+
+    impl FetchesIntermediates for Fetcher {
+        fn fetch(&self, url: &str) -> Vec<Certificate> {
+            self.fetched
+                .lock()
+                .unwrap()
+                .push(url.to_string());
+            self.intermediate
+                .iter()
+                .cloned()
+                .collect()
+        }
+    }
+
+    #[test]
+    fn missing_intermediates_are_fetched() {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(
+            webpki_roots::TLS_SERVER_ROOTS
+                .iter()
+                .map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }),
+        );
+        let end_entity = Certificate(include_bytes!("testdata/cert-arstechnica.0.der").to_vec());
+        let intermediate = Certificate(include_bytes!("testdata/cert-arstechnica.1.der").to_vec());
+        let server_name = ServerName::try_from("arstechnica.com").unwrap();
+        let now = SystemTime::UNIX_EPOCH + core::time::Duration::from_secs(1_640_870_720);
+        let verify = |verifier: &WebPkiVerifier| {
+            verifier
+                .verify_server_cert(&end_entity, &[], &server_name, &[], now)
+                .map(|_| ())
+        };
+
+        assert_eq!(
+            verify(&WebPkiVerifier::new(roots.clone())),
+            Err(Error::InvalidCertificate(CertificateError::UnknownIssuer))
+        );
+
+        for intermediate in [None, Some(intermediate)] {
+            let expected = match intermediate {
+                Some(_) => Ok(()),
+                None => Err(Error::InvalidCertificate(CertificateError::UnknownIssuer)),
+            };
+            let fetcher = Arc::new(Fetcher {
+                fetched: std::sync::Mutex::new(Vec::new()),
+                intermediate,
+            });
+            let verifier =
+                WebPkiVerifier::new(roots.clone())
+                    .with_intermediate_fetcher(Arc::<Fetcher>::clone(&fetcher));
+            assert_eq!(verify(&verifier), expected);
+            assert_eq!(
+                *fetcher.fetched.lock().unwrap(),
+                vec!["http://crt.sca1b.amazontrust.com/sca1b.crt".to_string()]
+            );
+        }
+    }
+
     #[test]
     fn pki_crl_errors() {
         // CRL signature errors should be turned into BadSignature.
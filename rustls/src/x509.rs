@@ -1,5 +1,16 @@
 // Additional x509/asn1 functions to those provided in webpki/ring.
 
+use crate::enums::SignatureScheme;
+use crate::error::{CertificateError, Error};
+use crate::msgs::enums::NamedGroup;
+use crate::msgs::handshake::DistinguishedName;
+
+use ring::{digest, signature};
+
+use core::time::Duration;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
 pub(crate) fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     let len = bytes.len();
 
@@ -20,10 +31,866 @@ pub(crate) fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
 /// Prepend stuff to `bytes` to put it in a DER SEQUENCE.
 pub(crate) fn wrap_in_sequence(bytes: &mut Vec<u8>) {
     wrap_in_asn1_len(bytes);
-    bytes.insert(0, DER_SEQUENCE_TAG);
+    bytes.insert(0, SEQUENCE);
+}
+
+/// Commonly-needed fields of an X.509 certificate.
+///
+/// Obtained from [`Certificate::details`], which parses the certificate
+/// each time it is called.  This does no validation beyond checking the
+/// certificate is well-formed enough to find these fields: that is the
+/// job of the certificate verifier.
+///
+/// [`Certificate::details`]: crate::Certificate::details
+#[derive(Clone, Debug)]
+pub struct CertificateDetails<'a> {
+    /// The whole DER encoding of `tbsCertificate`, which is what is signed.
+    tbs_certificate: &'a [u8],
+    signature_value: &'a [u8],
+    serial_number: &'a [u8],
+    signature_algorithm: &'a [u8],
+    signature_parameters: &'a [u8],
+    issuer: &'a [u8],
+    not_before: SystemTime,
+    not_after: SystemTime,
+    subject: &'a [u8],
+    subject_public_key_info: &'a [u8],
+    subject_alt_names: Option<&'a [u8]>,
+    authority_info_access: Option<&'a [u8]>,
+    extended_key_usage: Option<&'a [u8]>,
+}
+
+impl<'a> CertificateDetails<'a> {
+    pub(crate) fn parse(cert: &'a [u8]) -> Result<Self, Error> {
+        parse_certificate(cert).ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))
+    }
+
+    /// The serial number, as the big-endian bytes of a DER `INTEGER`.
+    pub fn serial_number(&self) -> &'a [u8] {
+        self.serial_number
+    }
+
+    /// The issuer's distinguished name.
+    pub fn issuer(&self) -> DistinguishedName {
+        DistinguishedName::from(self.issuer.to_vec())
+    }
+
+    /// The subject's distinguished name.
+    ///
+    /// This is empty for certificates that identify their subject only by
+    /// [`subject_alt_names`](Self::subject_alt_names).
+    pub fn subject(&self) -> DistinguishedName {
+        DistinguishedName::from(self.subject.to_vec())
+    }
+
+    /// The DER encoding of the issuer's `Name`.
+    pub(crate) fn issuer_der(&self) -> &'a [u8] {
+        self.issuer
+    }
+
+    /// The DER encoding of the subject's `Name`.
+    pub(crate) fn subject_der(&self) -> &'a [u8] {
+        self.subject
+    }
+
+    /// The start of the certificate's validity period.
+    pub fn not_before(&self) -> SystemTime {
+        self.not_before
+    }
+
+    /// The end of the certificate's validity period.
+    pub fn not_after(&self) -> SystemTime {
+        self.not_after
+    }
+
+    /// The DER-encoded `SubjectPublicKeyInfo`.
+    pub fn subject_public_key_info(&self) -> &'a [u8] {
+        self.subject_public_key_info
+    }
+
+    /// The SHA-256 hash of [`subject_public_key_info`](Self::subject_public_key_info).
+    ///
+    /// This identifies the certificate's key independently of the
+    /// certificate, as is done for key pinning.
+    pub fn subject_public_key_info_sha256(&self) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(
+            digest::digest(&digest::SHA256, self.subject_public_key_info).as_ref(),
+        );
+        hash
+    }
+
+    /// The names in the subject alternative name extension, in order.
+    ///
+    /// Only the kinds of name in [`SubjectAltName`] are included.  This is
+    /// empty if the certificate has no such extension, and an error if the
+    /// extension is malformed.
+    pub fn subject_alt_names(&self) -> Result<Vec<SubjectAltName<'a>>, Error> {
+        let names = match self.subject_alt_names {
+            Some(names) => names,
+            None => return Ok(Vec::new()),
+        };
+        parse_subject_alt_names(names)
+            .ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))
+    }
+
+    /// The URLs of the `caIssuers` entries in the authority information
+    /// access extension, in order.
+    ///
+    /// These locate the certificate of the issuer, for when a peer has not
+    /// sent it.  This is empty if the certificate has no such extension, and
+    /// an error if the extension is malformed.
+    pub fn ca_issuer_urls(&self) -> Result<Vec<&'a str>, Error> {
+        let access = match self.authority_info_access {
+            Some(access) => access,
+            None => return Ok(Vec::new()),
+        };
+        parse_ca_issuer_urls(access).ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))
+    }
+
+    /// Whether the issuer signed the certificate using SHA-1.
+    pub(crate) fn is_signed_with_sha1(&self) -> bool {
+        matches!(
+            self.signature_algorithm,
+            SHA1_WITH_RSA_ENCRYPTION | ECDSA_WITH_SHA1 | DSA_WITH_SHA1
+        )
+    }
+
+    /// The TLS signature scheme matching the algorithm the issuer used to
+    /// sign the certificate, or `None` if there is no such scheme.
+    ///
+    /// ECDSA signatures map to a scheme by their hash alone, as in TLS1.2:
+    /// `ecdsa-with-SHA256` is `ECDSA_NISTP256_SHA256` whatever the curve.
+    pub(crate) fn signature_scheme(&self) -> Option<SignatureScheme> {
+        signature_scheme(self.signature_algorithm, self.signature_parameters)
+    }
+
+    /// Whether the key in the DER-encoded `SubjectPublicKeyInfo`
+    /// `issuer_spki` made the signature on this certificate.
+    ///
+    /// Nothing else about the issuer is checked: not its name, validity or
+    /// whether it may issue certificates.
+    pub(crate) fn is_signed_by(&self, issuer_spki: &[u8]) -> bool {
+        match self.signature_scheme() {
+            Some(scheme) => verify_signature(
+                issuer_spki,
+                scheme,
+                self.tbs_certificate,
+                self.signature_value,
+            ),
+            None => false,
+        }
+    }
+
+    /// Whether the extended key usage extension lists `purpose`.
+    ///
+    /// This is false if the certificate has no such extension, or it is
+    /// malformed.
+    pub(crate) fn has_extended_key_usage(&self, purpose: &[u8]) -> bool {
+        // ExtKeyUsageSyntax ::= SEQUENCE SIZE (1..MAX) OF KeyPurposeId
+        // KeyPurposeId ::= OBJECT IDENTIFIER
+        let mut purposes = match self
+            .extended_key_usage
+            .and_then(|usage| Der::new(usage).nested(SEQUENCE))
+        {
+            Some(purposes) => purposes,
+            None => return false,
+        };
+        while let Some(id) = purposes.read(OID) {
+            if id == purpose {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The kind and size of the certificate's public key, or `None` if
+    /// `SubjectPublicKeyInfo` is malformed.
+    pub(crate) fn public_key(&self) -> Option<PublicKey> {
+        // SubjectPublicKeyInfo ::= SEQUENCE {
+        //    algorithm            AlgorithmIdentifier,
+        //    subjectPublicKey     BIT STRING }
+        //
+        // AlgorithmIdentifier ::= SEQUENCE {
+        //    algorithm            OBJECT IDENTIFIER,
+        //    parameters           ANY DEFINED BY algorithm OPTIONAL }
+        let mut spki = Der::new(self.subject_public_key_info).nested(SEQUENCE)?;
+        let mut algorithm = spki.nested(SEQUENCE)?;
+        let key = spki.read(BIT_STRING)?;
+
+        Some(match algorithm.read(OID)? {
+            RSA_ENCRYPTION => {
+                // RSAPublicKey ::= SEQUENCE {
+                //    modulus            INTEGER,
+                //    publicExponent     INTEGER }
+                let key = match key.split_first() {
+                    Some((0, key)) => key,
+                    _ => return None,
+                };
+                let modulus = Der::new(key)
+                    .nested(SEQUENCE)?
+                    .read(INTEGER)?;
+                let start = modulus
+                    .iter()
+                    .position(|byte| *byte != 0)
+                    .unwrap_or(modulus.len());
+                let modulus = &modulus[start..];
+                PublicKey::Rsa {
+                    modulus_bits: modulus
+                        .first()
+                        .map(|top| modulus.len() * 8 - top.leading_zeros() as usize)
+                        .unwrap_or_default(),
+                }
+            }
+            ID_EC_PUBLIC_KEY => PublicKey::Ecdsa {
+                curve: match algorithm.read(OID) {
+                    Some(SECP256R1) => Some(NamedGroup::secp256r1),
+                    Some(SECP384R1) => Some(NamedGroup::secp384r1),
+                    Some(SECP521R1) => Some(NamedGroup::secp521r1),
+                    _ => None,
+                },
+            },
+            _ => PublicKey::Other,
+        })
+    }
+}
+
+/// The `subjectPublicKey` bits of the DER-encoded `SubjectPublicKeyInfo`
+/// `spki`, or `None` if it is malformed.
+pub(crate) fn subject_public_key(spki: &[u8]) -> Option<&[u8]> {
+    let mut spki = Der::new(spki).nested(SEQUENCE)?;
+    spki.read(SEQUENCE)?;
+    match spki.read(BIT_STRING)?.split_first() {
+        Some((0, key)) => Some(key),
+        _ => None,
+    }
+}
+
+/// Whether the key in the DER-encoded `SubjectPublicKeyInfo` `spki` made
+/// `signature` over `message` using `scheme`.
+///
+/// This is for keys that are not in a certificate webpki can parse, such
+/// as those of trust anchors.  The same algorithms are accepted as for
+/// certificate chains.
+pub(crate) fn verify_signature(
+    spki: &[u8],
+    scheme: SignatureScheme,
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    let algorithm = match verification_algorithm(spki, scheme) {
+        Some(algorithm) => algorithm,
+        None => return false,
+    };
+    match subject_public_key(spki) {
+        Some(key) => signature::UnparsedPublicKey::new(algorithm, key)
+            .verify(message, signature)
+            .is_ok(),
+        None => false,
+    }
+}
+
+/// The *ring* algorithm for verifying `scheme` signatures made by the key
+/// in `spki`, or `None` if they can't be.
+fn verification_algorithm(
+    spki: &[u8],
+    scheme: SignatureScheme,
+) -> Option<&'static dyn signature::VerificationAlgorithm> {
+    let mut algorithm = Der::new(spki)
+        .nested(SEQUENCE)?
+        .nested(SEQUENCE)?;
+    Some(match (algorithm.read(OID)?, scheme) {
+        (RSA_ENCRYPTION, SignatureScheme::RSA_PKCS1_SHA256) => {
+            &signature::RSA_PKCS1_2048_8192_SHA256
+        }
+        (RSA_ENCRYPTION, SignatureScheme::RSA_PKCS1_SHA384) => {
+            &signature::RSA_PKCS1_2048_8192_SHA384
+        }
+        (RSA_ENCRYPTION, SignatureScheme::RSA_PKCS1_SHA512) => {
+            &signature::RSA_PKCS1_2048_8192_SHA512
+        }
+        (RSA_ENCRYPTION, SignatureScheme::RSA_PSS_SHA256) => &signature::RSA_PSS_2048_8192_SHA256,
+        (RSA_ENCRYPTION, SignatureScheme::RSA_PSS_SHA384) => &signature::RSA_PSS_2048_8192_SHA384,
+        (RSA_ENCRYPTION, SignatureScheme::RSA_PSS_SHA512) => &signature::RSA_PSS_2048_8192_SHA512,
+        // As in TLS1.2, the curve is that of the key, not the scheme.
+        (ID_EC_PUBLIC_KEY, scheme) => match (algorithm.read(OID)?, scheme) {
+            (SECP256R1, SignatureScheme::ECDSA_NISTP256_SHA256) => {
+                &signature::ECDSA_P256_SHA256_ASN1
+            }
+            (SECP256R1, SignatureScheme::ECDSA_NISTP384_SHA384) => {
+                &signature::ECDSA_P256_SHA384_ASN1
+            }
+            (SECP384R1, SignatureScheme::ECDSA_NISTP256_SHA256) => {
+                &signature::ECDSA_P384_SHA256_ASN1
+            }
+            (SECP384R1, SignatureScheme::ECDSA_NISTP384_SHA384) => {
+                &signature::ECDSA_P384_SHA384_ASN1
+            }
+            _ => return None,
+        },
+        (ID_ED25519, SignatureScheme::ED25519) => &signature::ED25519,
+        _ => return None,
+    })
+}
+
+/// The kind of public key in a certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PublicKey {
+    Rsa {
+        modulus_bits: usize,
+    },
+    /// `curve` is `None` if the curve is not a named curve we know.
+    Ecdsa {
+        curve: Option<NamedGroup>,
+    },
+    Other,
+}
+
+/// A name from a certificate's subject alternative name extension.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubjectAltName<'a> {
+    /// A DNS name, which may include wildcard labels.
+    DnsName(&'a str),
+    /// An IP address.
+    IpAddress(IpAddr),
+    /// An email address.
+    Email(&'a str),
+    /// A URI.
+    Uri(&'a str),
+}
+
+/// The TLS signature scheme matching the `AlgorithmIdentifier` with OID
+/// `algorithm` and `parameters`, or `None` if there is no such scheme.
+///
+/// See [`CertificateDetails::signature_scheme`] about ECDSA.
+pub(crate) fn signature_scheme(algorithm: &[u8], parameters: &[u8]) -> Option<SignatureScheme> {
+    Some(match algorithm {
+        SHA1_WITH_RSA_ENCRYPTION => SignatureScheme::RSA_PKCS1_SHA1,
+        SHA256_WITH_RSA_ENCRYPTION => SignatureScheme::RSA_PKCS1_SHA256,
+        SHA384_WITH_RSA_ENCRYPTION => SignatureScheme::RSA_PKCS1_SHA384,
+        SHA512_WITH_RSA_ENCRYPTION => SignatureScheme::RSA_PKCS1_SHA512,
+        ID_RSASSA_PSS => {
+            // RSASSA-PSS-params ::= SEQUENCE {
+            //    hashAlgorithm      [0] HashAlgorithm DEFAULT sha1,
+            //    ... }
+            let hash = Der::new(parameters)
+                .nested(SEQUENCE)?
+                .nested(context(0))?
+                .nested(SEQUENCE)?
+                .read(OID)?;
+            match hash {
+                ID_SHA256 => SignatureScheme::RSA_PSS_SHA256,
+                ID_SHA384 => SignatureScheme::RSA_PSS_SHA384,
+                ID_SHA512 => SignatureScheme::RSA_PSS_SHA512,
+                _ => return None,
+            }
+        }
+        ECDSA_WITH_SHA1 => SignatureScheme::ECDSA_SHA1_Legacy,
+        ECDSA_WITH_SHA256 => SignatureScheme::ECDSA_NISTP256_SHA256,
+        ECDSA_WITH_SHA384 => SignatureScheme::ECDSA_NISTP384_SHA384,
+        ECDSA_WITH_SHA512 => SignatureScheme::ECDSA_NISTP521_SHA512,
+        ID_ED25519 => SignatureScheme::ED25519,
+        _ => return None,
+    })
+}
+
+fn parse_certificate(cert: &[u8]) -> Option<CertificateDetails<'_>> {
+    // Certificate ::= SEQUENCE {
+    //    tbsCertificate       TBSCertificate,
+    //    signatureAlgorithm   AlgorithmIdentifier,
+    //    signatureValue       BIT STRING }
+    let mut certificate = Der::new(cert).nested(SEQUENCE)?;
+    let tbs = certificate.read_element(SEQUENCE)?;
+    // `signatureAlgorithm` must be the same as `signature` in the
+    // `TBSCertificate`, which is the one used.
+    certificate.read(SEQUENCE)?;
+    let signature_value = match certificate
+        .read(BIT_STRING)?
+        .split_first()
+    {
+        Some((0, signature)) => signature,
+        _ => return None,
+    };
+    let mut tbs_certificate = Der::new(tbs).nested(SEQUENCE)?;
+
+    // TBSCertificate ::= SEQUENCE {
+    //    version         [0]  EXPLICIT Version DEFAULT v1,
+    //    serialNumber         CertificateSerialNumber,
+    //    signature            AlgorithmIdentifier,
+    //    issuer               Name,
+    //    validity             Validity,
+    //    subject              Name,
+    //    subjectPublicKeyInfo SubjectPublicKeyInfo,
+    //    issuerUniqueID  [1]  IMPLICIT UniqueIdentifier OPTIONAL,
+    //    subjectUniqueID [2]  IMPLICIT UniqueIdentifier OPTIONAL,
+    //    extensions      [3]  EXPLICIT Extensions OPTIONAL }
+    if tbs_certificate.peek() == Some(context(0)) {
+        tbs_certificate.read(context(0))?;
+    }
+    let serial_number = tbs_certificate.read(INTEGER)?;
+    let mut signature = tbs_certificate.nested(SEQUENCE)?;
+    let signature_algorithm = signature.read(OID)?;
+    let signature_parameters = signature.0;
+    let issuer = tbs_certificate.read_element(SEQUENCE)?;
+
+    // Validity ::= SEQUENCE {
+    //    notBefore      Time,
+    //    notAfter       Time }
+    let mut validity = tbs_certificate.nested(SEQUENCE)?;
+    let not_before = time(&mut validity)?;
+    let not_after = time(&mut validity)?;
+
+    let subject = tbs_certificate.read_element(SEQUENCE)?;
+    let subject_public_key_info = tbs_certificate.read_element(SEQUENCE)?;
+
+    let mut subject_alt_names = None;
+    let mut authority_info_access = None;
+    let mut extended_key_usage = None;
+    while let Some((tag, extensions)) = tbs_certificate.read_any() {
+        if tag != context(3) {
+            continue;
+        }
+
+        // Extension ::= SEQUENCE {
+        //    extnID      OBJECT IDENTIFIER,
+        //    critical    BOOLEAN DEFAULT FALSE,
+        //    extnValue   OCTET STRING }
+        let mut extensions = Der::new(extensions).nested(SEQUENCE)?;
+        while let Some(mut extension) = extensions.nested(SEQUENCE) {
+            let id = extension.read(OID)?;
+            let value = if id == ID_CE_SUBJECT_ALT_NAME {
+                &mut subject_alt_names
+            } else if id == ID_PE_AUTHORITY_INFO_ACCESS {
+                &mut authority_info_access
+            } else if id == ID_CE_EXT_KEY_USAGE {
+                &mut extended_key_usage
+            } else {
+                continue;
+            };
+            if extension.peek() == Some(BOOLEAN) {
+                extension.read(BOOLEAN)?;
+            }
+            *value = Some(extension.read(OCTET_STRING)?);
+        }
+    }
+
+    Some(CertificateDetails {
+        tbs_certificate: tbs,
+        signature_value,
+        serial_number,
+        signature_algorithm,
+        signature_parameters,
+        issuer,
+        not_before,
+        not_after,
+        subject,
+        subject_public_key_info,
+        subject_alt_names,
+        authority_info_access,
+        extended_key_usage,
+    })
+}
+
+fn parse_subject_alt_names(der: &[u8]) -> Option<Vec<SubjectAltName<'_>>> {
+    // SubjectAltName ::= GeneralNames
+    // GeneralNames ::= SEQUENCE SIZE (1..MAX) OF GeneralName
+    let mut general_names = Der::new(der).nested(SEQUENCE)?;
+    let mut names = Vec::new();
+    while let Some((tag, value)) = general_names.read_any() {
+        // GeneralName ::= CHOICE {
+        //    rfc822Name                      [1]     IA5String,
+        //    dNSName                         [2]     IA5String,
+        //    uniformResourceIdentifier       [6]     IA5String,
+        //    iPAddress                       [7]     OCTET STRING,
+        //    ... }
+        let name = match tag {
+            RFC822_NAME => SubjectAltName::Email(ia5_string(value)?),
+            DNS_NAME => SubjectAltName::DnsName(ia5_string(value)?),
+            URI => SubjectAltName::Uri(ia5_string(value)?),
+            IP_ADDRESS => match value.len() {
+                4 => {
+                    let mut octets = [0u8; 4];
+                    octets.copy_from_slice(value);
+                    SubjectAltName::IpAddress(IpAddr::from(octets))
+                }
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(value);
+                    SubjectAltName::IpAddress(IpAddr::from(octets))
+                }
+                _ => return None,
+            },
+            _ => continue,
+        };
+        names.push(name);
+    }
+
+    Some(names)
+}
+
+fn parse_ca_issuer_urls(der: &[u8]) -> Option<Vec<&str>> {
+    // AuthorityInfoAccessSyntax ::= SEQUENCE SIZE (1..MAX) OF AccessDescription
+    //
+    // AccessDescription ::= SEQUENCE {
+    //    accessMethod    OBJECT IDENTIFIER,
+    //    accessLocation  GeneralName }
+    let mut descriptions = Der::new(der).nested(SEQUENCE)?;
+    let mut urls = Vec::new();
+    while let Some(mut description) = descriptions.nested(SEQUENCE) {
+        if description.read(OID)? != ID_AD_CA_ISSUERS {
+            continue;
+        }
+        // Other kinds of location, such as directory names, cannot be fetched.
+        if let Some((URI, url)) = description.read_any() {
+            urls.push(ia5_string(url)?);
+        }
+    }
+
+    Some(urls)
+}
+
+fn ia5_string(value: &[u8]) -> Option<&str> {
+    match value.is_ascii() {
+        true => core::str::from_utf8(value).ok(),
+        false => None,
+    }
+}
+
+/// Read a `Time`, which is a `UTCTime` or a `GeneralizedTime`.
+fn time(der: &mut Der<'_>) -> Option<SystemTime> {
+    match der.read_any()? {
+        (UTC_TIME, value) => utc_time(value),
+        (GENERALIZED_TIME, value) => generalized_time(value),
+        _ => None,
+    }
+}
+
+/// A minimal reader for DER's tag-length-value encoding.
+pub(crate) struct Der<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Der<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    pub(crate) fn peek(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    pub(crate) fn read_any(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = match first {
+            0..=0x7f => (usize::from(first), rest),
+            0x81..=0x84 => {
+                let (len, rest) = split_at(rest, usize::from(first & 0x7f))?;
+                let len = len
+                    .iter()
+                    .fold(0usize, |acc, byte| acc << 8 | usize::from(*byte));
+                (len, rest)
+            }
+            _ => return None,
+        };
+        let (value, rest) = split_at(rest, len)?;
+        self.0 = rest;
+        Some((tag, value))
+    }
+
+    pub(crate) fn read(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read_any()? {
+            (actual, value) if actual == tag => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Like `read`, but returns the whole element including its tag and length.
+    pub(crate) fn read_element(&mut self, tag: u8) -> Option<&'a [u8]> {
+        let before = self.0;
+        self.read(tag)?;
+        Some(&before[..before.len() - self.0.len()])
+    }
+
+    pub(crate) fn nested(&mut self, tag: u8) -> Option<Self> {
+        self.read(tag).map(Self::new)
+    }
+}
+
+fn split_at(bytes: &[u8], mid: usize) -> Option<(&[u8], &[u8])> {
+    match mid <= bytes.len() {
+        true => Some(bytes.split_at(mid)),
+        false => None,
+    }
+}
+
+pub(crate) const fn context(number: u8) -> u8 {
+    0xa0 | number
+}
+
+pub(crate) const BOOLEAN: u8 = 0x01;
+pub(crate) const BIT_STRING: u8 = 0x03;
+pub(crate) const ENUMERATED: u8 = 0x0a;
+pub(crate) const GENERALIZED_TIME: u8 = 0x18;
+pub(crate) const INTEGER: u8 = 0x02;
+pub(crate) const OCTET_STRING: u8 = 0x04;
+pub(crate) const OID: u8 = 0x06;
+pub(crate) const SEQUENCE: u8 = 0x30;
+const UTC_TIME: u8 = 0x17;
+
+const RFC822_NAME: u8 = 0x81;
+pub(crate) const DNS_NAME: u8 = 0x82;
+const URI: u8 = 0x86;
+const IP_ADDRESS: u8 = 0x87;
+
+/// id-ce-subjectAltName, 2.5.29.17
+const ID_CE_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// id-pe-authorityInfoAccess, 1.3.6.1.5.5.7.1.1
+const ID_PE_AUTHORITY_INFO_ACCESS: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+
+/// id-ad-caIssuers, 1.3.6.1.5.5.7.48.2
+const ID_AD_CA_ISSUERS: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x02];
+
+/// id-ce-extKeyUsage, 2.5.29.37
+const ID_CE_EXT_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+
+/// id-kp-serverAuth, 1.3.6.1.5.5.7.3.1
+pub(crate) const ID_KP_SERVER_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+
+/// id-kp-clientAuth, 1.3.6.1.5.5.7.3.2
+pub(crate) const ID_KP_CLIENT_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02];
+
+/// rsaEncryption, 1.2.840.113549.1.1.1
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// sha1WithRSAEncryption, 1.2.840.113549.1.1.5
+const SHA1_WITH_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05];
+
+/// sha256WithRSAEncryption, 1.2.840.113549.1.1.11
+const SHA256_WITH_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+
+/// sha384WithRSAEncryption, 1.2.840.113549.1.1.12
+const SHA384_WITH_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+
+/// sha512WithRSAEncryption, 1.2.840.113549.1.1.13
+const SHA512_WITH_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+
+/// id-RSASSA-PSS, 1.2.840.113549.1.1.10
+const ID_RSASSA_PSS: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a];
+
+/// id-sha256, 2.16.840.1.101.3.4.2.1
+pub(crate) const ID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+
+/// id-sha384, 2.16.840.1.101.3.4.2.2
+const ID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+
+/// id-sha512, 2.16.840.1.101.3.4.2.3
+const ID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+/// id-ecPublicKey, 1.2.840.10045.2.1
+const ID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+
+/// ecdsa-with-SHA1, 1.2.840.10045.4.1
+const ECDSA_WITH_SHA1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01];
+
+/// ecdsa-with-SHA256, 1.2.840.10045.4.3.2
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+/// ecdsa-with-SHA384, 1.2.840.10045.4.3.3
+const ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+
+/// ecdsa-with-SHA512, 1.2.840.10045.4.3.4
+const ECDSA_WITH_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+
+/// id-Ed25519, 1.3.101.112
+const ID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+/// id-dsa-with-sha1, 1.2.840.10040.4.3
+const DSA_WITH_SHA1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x38, 0x04, 0x03];
+
+/// secp256r1, 1.2.840.10045.3.1.7
+const SECP256R1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+/// secp384r1, 1.3.132.0.34
+const SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// secp521r1, 1.3.132.0.35
+const SECP521R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+
+/// Parse a DER `GeneralizedTime`, which is always `YYYYMMDDHHMMSSZ`
+/// with optional fractional seconds before the `Z`.
+pub(crate) fn generalized_time(value: &[u8]) -> Option<SystemTime> {
+    let value = match value.split_last() {
+        Some((b'Z', value)) if value.len() >= 14 => value,
+        _ => return None,
+    };
+    let (value, fraction) = value.split_at(14);
+    if !(fraction.is_empty() || fraction.first() == Some(&b'.')) {
+        return None;
+    }
+
+    unix_time(digits(&value[..4])?, &value[4..])
+}
+
+/// Parse a DER `UTCTime`, which is always `YYMMDDHHMMSSZ`.
+///
+/// Two-digit years below 50 are in the 21st century, as RFC 5280 requires.
+fn utc_time(value: &[u8]) -> Option<SystemTime> {
+    let value = match value.split_last() {
+        Some((b'Z', value)) if value.len() == 12 => value,
+        _ => return None,
+    };
+
+    let year = match digits(&value[..2])? {
+        year @ 0..=49 => 2000 + year,
+        year => 1900 + year,
+    };
+    unix_time(year, &value[2..])
 }
 
-const DER_SEQUENCE_TAG: u8 = 0x30;
+/// Convert `MMDDHHMMSS` in `year` to a `SystemTime`.
+fn unix_time(year: u64, value: &[u8]) -> Option<SystemTime> {
+    let (month, day) = (digits(&value[0..2])?, digits(&value[2..4])?);
+    let (hour, minute, second) = (
+        digits(&value[4..6])?,
+        digits(&value[6..8])?,
+        digits(&value[8..10])?,
+    );
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_since_epoch(year, month, day);
+    let secs = ((days * 24 + hour) * 60 + minute) * 60 + second;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+fn digits(value: &[u8]) -> Option<u64> {
+    value
+        .iter()
+        .try_fold(0u64, |acc, digit| match digit {
+            b'0'..=b'9' => Some(acc * 10 + u64::from(digit - b'0')),
+            _ => None,
+        })
+}
+
+/// Days from 1970-01-01 to the given date in the proleptic Gregorian calendar.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Count years from March, so the leap day is the last day of the year.
+    let (year, month) = match month {
+        1 | 2 => (year - 1, month + 9),
+        _ => (year, month - 3),
+    };
+    let days_before_month = (153 * month + 2) / 5;
+    let days_before_year = year * 365 + year / 4 - year / 100 + year / 400;
+    // 719_468 is the day number of 1970-01-01 counting from 0000-03-01.
+    days_before_year + days_before_month + day - 1 - 719_468
+}
+
+#[test]
+fn test_certificate_details() {
+    let cert = include_bytes!("testdata/cert-arstechnica.0.der");
+    let details = CertificateDetails::parse(cert).unwrap();
+    assert!(details
+        .subject()
+        .as_ref()
+        .ends_with(b"*.arstechnica.com"));
+    assert!(details
+        .issuer()
+        .as_ref()
+        .ends_with(b"Amazon"));
+    // Nov 29 00:00:00 2021 GMT to Dec 27 23:59:59 2022 GMT
+    assert_eq!(
+        details.not_before(),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_638_144_000)
+    );
+    assert_eq!(
+        details.not_after(),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_672_185_599)
+    );
+    assert_eq!(
+        details.subject_alt_names().unwrap(),
+        vec![
+            SubjectAltName::DnsName("*.arstechnica.com"),
+            SubjectAltName::DnsName("arstechnica.com"),
+        ]
+    );
+    assert_eq!(
+        details.ca_issuer_urls().unwrap(),
+        vec!["http://crt.sca1b.amazontrust.com/sca1b.crt"]
+    );
+}
+
+#[test]
+fn test_certificate_key_and_signature() {
+    let cert = include_bytes!("testdata/cert-arstechnica.0.der");
+    let details = CertificateDetails::parse(cert).unwrap();
+    assert_eq!(
+        details.public_key(),
+        Some(PublicKey::Rsa { modulus_bits: 2048 })
+    );
+    assert!(!details.is_signed_with_sha1());
+    assert_eq!(
+        details.signature_scheme(),
+        Some(SignatureScheme::RSA_PKCS1_SHA256)
+    );
+    assert!(details.has_extended_key_usage(ID_KP_SERVER_AUTH));
+
+    let cert = include_bytes!("testdata/cert-sha1-selfsigned.der");
+    let details = CertificateDetails::parse(cert).unwrap();
+    assert_eq!(
+        details.public_key(),
+        Some(PublicKey::Ecdsa {
+            curve: Some(NamedGroup::secp256r1)
+        })
+    );
+    assert!(details.is_signed_with_sha1());
+    assert_eq!(
+        details.signature_scheme(),
+        Some(SignatureScheme::ECDSA_SHA1_Legacy)
+    );
+    assert!(!details.has_extended_key_usage(ID_KP_SERVER_AUTH));
+
+    let cert = include_bytes!("testdata/cert-pss-selfsigned.der");
+    let details = CertificateDetails::parse(cert).unwrap();
+    assert_eq!(
+        details.signature_scheme(),
+        Some(SignatureScheme::RSA_PSS_SHA384)
+    );
+}
+
+#[test]
+fn test_certificate_details_rejects_truncated_certificate() {
+    let cert = include_bytes!("testdata/cert-arstechnica.0.der");
+    for len in [0, 1, 10, cert.len() - 1] {
+        assert!(CertificateDetails::parse(&cert[..len]).is_err());
+    }
+}
+
+#[test]
+fn test_time_conversion() {
+    let unix_time = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+    assert_eq!(generalized_time(b"19700101000000Z"), unix_time(0));
+    assert_eq!(
+        generalized_time(b"20231114221320Z"),
+        unix_time(1_700_000_000)
+    );
+    assert_eq!(
+        generalized_time(b"20000229120000.5Z"),
+        unix_time(951_825_600)
+    );
+    assert_eq!(generalized_time(b"20231114221320"), None);
+    assert_eq!(generalized_time(b"20231314221320Z"), None);
+    assert_eq!(generalized_time(b"2023111422132Z"), None);
+
+    assert_eq!(utc_time(b"231114221320Z"), unix_time(1_700_000_000));
+    assert_eq!(utc_time(b"700101000000Z"), unix_time(0));
+    assert_eq!(utc_time(b"691231235959Z"), None);
+    assert_eq!(utc_time(b"20231114221320Z"), None);
+}
 
 #[test]
 fn test_empty() {
@@ -8,23 +8,27 @@ use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use rustls::client::{ResolvesClientCert, Resumption};
 use rustls::crypto::ring::Ring;
 use rustls::crypto::CryptoProvider;
 use rustls::internal::msgs::base::Payload;
 use rustls::internal::msgs::codec::Codec;
-use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ResolvesServerCert,
+};
 #[cfg(feature = "secret_extraction")]
 use rustls::ConnectionTrafficSecrets;
 use rustls::{
-    sign, CertificateError, ConnectionCommon, Error, KeyLog, PeerIncompatible, PeerMisbehaved,
-    SideData,
+    sign, CertificateError, ConnectionCommon, DistinguishedName, Error, KeyLog, PeerIncompatible,
+    PeerMisbehaved, SideData,
 };
-use rustls::{CipherSuite, ProtocolVersion, SignatureScheme};
+use rustls::{CipherSuite, HandshakeType, ProtocolVersion, SignatureScheme};
 use rustls::{ClientConfig, ClientConnection};
+use rustls::{NonBlockingStream, Stream, StreamOwned};
 use rustls::{ServerConfig, ServerConnection};
-use rustls::{Stream, StreamOwned};
 use rustls::{SupportedCipherSuite, ALL_CIPHER_SUITES};
 
 mod common;
@@ -288,6 +292,112 @@ fn config_builder_for_server_rejects_incompatible_cipher_suites() {
     );
 }
 
+#[test]
+fn config_builder_rejects_inverted_version_range() {
+    assert_eq!(
+        ClientConfig::<Ring>::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions_range(ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2)
+            .err(),
+        Some(Error::General(
+            "minimum protocol version TLSv1_3 is greater than maximum TLSv1_2".into()
+        ))
+    );
+}
+
+#[test]
+fn config_builder_rejects_unsupported_version_range() {
+    assert_eq!(
+        ServerConfig::<Ring>::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions_range(ProtocolVersion::TLSv1_0, ProtocolVersion::TLSv1_1)
+            .err(),
+        Some(Error::General(
+            "no supported protocol versions between TLSv1_0 and TLSv1_1".into()
+        ))
+    );
+}
+
+#[test]
+fn config_builder_rejects_non_tls_version_range() {
+    for (min, max, bad) in [
+        (
+            ProtocolVersion::TLSv1_2,
+            ProtocolVersion::DTLSv1_2,
+            "DTLSv1_2",
+        ),
+        (
+            ProtocolVersion::DTLSv1_0,
+            ProtocolVersion::TLSv1_3,
+            "DTLSv1_0",
+        ),
+        (
+            ProtocolVersion::TLSv1_2,
+            ProtocolVersion::Unknown(0x7f00),
+            "Unknown(32512)",
+        ),
+    ] {
+        assert_eq!(
+            ClientConfig::<Ring>::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions_range(min, max)
+                .err(),
+            Some(Error::General(format!(
+                "{} is not a TLS protocol version",
+                bad
+            )))
+        );
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn config_builder_rejects_version_range_incompatible_with_cipher_suites() {
+    assert_eq!(
+        ClientConfig::<Ring>::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions_range(ProtocolVersion::TLSv1_0, ProtocolVersion::TLSv1_2)
+            .err(),
+        Some(Error::General("no usable cipher suites configured".into()))
+    );
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn versions_from_range() {
+    let finish = |min, max| {
+        let client_config = finish_client_config(
+            KeyType::Rsa,
+            ClientConfig::<Ring>::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions_range(min, max)
+                .unwrap(),
+        );
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+        client.protocol_version()
+    };
+
+    assert_eq!(
+        finish(ProtocolVersion::TLSv1_0, ProtocolVersion::TLSv1_2),
+        Some(ProtocolVersion::TLSv1_2)
+    );
+    assert_eq!(
+        finish(ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3),
+        Some(ProtocolVersion::TLSv1_3)
+    );
+    assert_eq!(
+        finish(ProtocolVersion::TLSv1_3, ProtocolVersion::Unknown(0x0305)),
+        Some(ProtocolVersion::TLSv1_3)
+    );
+}
+
 #[test]
 fn buffered_client_data_sent() {
     let server_config = Arc::new(make_server_config(KeyType::Rsa));
@@ -377,6 +487,53 @@ fn client_can_get_server_cert() {
     }
 }
 
+#[test]
+fn client_can_get_server_cert_details() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+        do_handshake(&mut client, &mut server);
+
+        let certs = client.peer_certificates().unwrap();
+        let end_entity = certs[0].details().unwrap();
+        let intermediate = certs[1].details().unwrap();
+        assert!(end_entity
+            .subject()
+            .as_ref()
+            .ends_with(b"testserver.com"));
+        assert_eq!(
+            end_entity.issuer().as_ref(),
+            intermediate.subject().as_ref()
+        );
+        assert!(end_entity.not_before() < end_entity.not_after());
+        assert_eq!(
+            end_entity.subject_alt_names().unwrap(),
+            vec![
+                rustls::SubjectAltName::DnsName("testserver.com"),
+                rustls::SubjectAltName::IpAddress("198.51.100.1".parse().unwrap()),
+                rustls::SubjectAltName::DnsName("second.testserver.com"),
+                rustls::SubjectAltName::IpAddress("2001:db8::1".parse().unwrap()),
+                rustls::SubjectAltName::DnsName("localhost"),
+            ]
+        );
+        assert!(intermediate
+            .subject_alt_names()
+            .unwrap()
+            .is_empty());
+    }
+
+    let chain = KeyType::Rsa.get_chain();
+    let details = chain[0].details().unwrap();
+    assert_eq!(details.serial_number(), &[0x01, 0xc8]);
+    assert_eq!(
+        details.subject_public_key_info_sha256()[..4],
+        [0xbc, 0x7b, 0xb8, 0x0a]
+    );
+
+    assert!(rustls::Certificate(vec![0x30, 0x00])
+        .details()
+        .is_err());
+}
+
 #[test]
 fn client_can_get_server_cert_after_resumption() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -400,6 +557,352 @@ fn client_can_get_server_cert_after_resumption() {
     }
 }
 
+#[test]
+fn client_can_get_stapled_ocsp_response() {
+    let ocsp = include_bytes!("../src/testdata/ocsp-good.der");
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(
+        ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert_with_ocsp(kt.get_chain(), kt.get_key(), ocsp.to_vec())
+            .unwrap(),
+    );
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert_eq!(client.ocsp_response(), None);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(client.ocsp_response(), Some(&ocsp[..]));
+        let parsed = rustls::OcspResponse::parse(client.ocsp_response().unwrap()).unwrap();
+        assert_eq!(parsed.status(), rustls::OcspCertStatus::Good);
+        assert!(parsed.next_update() > Some(parsed.this_update()));
+
+        // no certificate, so no staple, when resuming
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.ocsp_response(), None);
+    }
+
+    let (mut client, mut server) = make_pair(kt);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.ocsp_response(), None);
+}
+
+fn server_config_with_ocsp(kt: KeyType, ocsp: Vec<u8>) -> Arc<ServerConfig<Ring>> {
+    Arc::new(
+        ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert_with_ocsp(kt.get_chain(), kt.get_key(), ocsp)
+            .unwrap(),
+    )
+}
+
+fn client_config_requiring_ocsp(
+    kt: KeyType,
+    version: &'static rustls::SupportedProtocolVersion,
+    policy: rustls::OcspStaplePolicy,
+) -> ClientConfig<Ring> {
+    let mut client_config = make_client_config_with_versions(kt, &[version]);
+    client_config.require_ocsp_staple = Some(policy);
+    client_config
+}
+
+#[test]
+fn client_accepts_required_ocsp_staple() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = server_config_with_ocsp(*kt, kt.get_ocsp_response());
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(client_config_requiring_ocsp(
+                *kt,
+                version,
+                rustls::OcspStaplePolicy::default(),
+            ));
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.ocsp_response(), Some(&kt.get_ocsp_response()[..]));
+
+            // resumption sends no certificate, so needs no staple
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.ocsp_response(), None);
+        }
+    }
+}
+
+#[test]
+fn client_rejects_unacceptable_ocsp_staple() {
+    let kt = KeyType::Ecdsa;
+    let mut stale = rustls::OcspStaplePolicy::default();
+    stale.max_age = Some(Duration::from_secs(24 * 60 * 60));
+    let cases = [
+        (
+            Vec::new(),
+            rustls::OcspStaplePolicy::default(),
+            CertificateError::MissingOcspResponse,
+            rustls::AlertDescription::BadCertificateStatusResponse,
+        ),
+        (
+            // stapled for a different certificate
+            KeyType::Rsa.get_ocsp_response(),
+            rustls::OcspStaplePolicy::default(),
+            CertificateError::InvalidOcspResponse,
+            rustls::AlertDescription::BadCertificateStatusResponse,
+        ),
+        (
+            include_bytes!("../src/testdata/ocsp-good.der").to_vec(),
+            rustls::OcspStaplePolicy::default(),
+            CertificateError::InvalidOcspResponse,
+            rustls::AlertDescription::BadCertificateStatusResponse,
+        ),
+        (
+            kt.get_revoked_ocsp_response(),
+            rustls::OcspStaplePolicy::default(),
+            CertificateError::Revoked,
+            rustls::AlertDescription::CertificateRevoked,
+        ),
+        (
+            kt.get_ocsp_response(),
+            stale,
+            CertificateError::StaleOcspResponse,
+            rustls::AlertDescription::BadCertificateStatusResponse,
+        ),
+    ];
+
+    for (ocsp, policy, error, alert) in cases {
+        let server_config = server_config_with_ocsp(kt, ocsp);
+        for version in rustls::ALL_VERSIONS {
+            // two days later, the response is still within its nextUpdate,
+            // but too old for `stale`
+            let time = Arc::new(AdvancingTimeProvider::default());
+            time.advance(2 * 24 * 60 * 60);
+            let mut client_config = client_config_requiring_ocsp(kt, version, policy);
+            client_config.time_provider = time;
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                    error.clone()
+                )))
+            );
+            assert_eq!(client.sent_fatal_alert(), Some(alert));
+        }
+    }
+}
+
+#[test]
+fn client_finds_ocsp_staple_issuer_among_trust_anchors() {
+    // Trusting the intermediate makes it the trust anchor that issued the
+    // end-entity certificate, and the server doesn't send it.
+    let kt = KeyType::Rsa;
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_parsable_certificates(
+        rustls_pemfile::certs(&mut io::BufReader::new(bytes_for("rsa", "inter.cert"))).unwrap(),
+    );
+    let server_config = Arc::new(
+        ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert_with_ocsp(
+                kt.get_chain()[..1].to_vec(),
+                kt.get_key(),
+                kt.get_ocsp_response(),
+            )
+            .unwrap(),
+    );
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = ClientConfig::<Ring>::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        client_config.require_ocsp_staple = Some(rustls::OcspStaplePolicy::default());
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.ocsp_response(), Some(&kt.get_ocsp_response()[..]));
+    }
+}
+
+#[test]
+fn client_checks_ocsp_staple_against_actual_issuer() {
+    // Same name as the intermediate, but a different key: it signed
+    // `end.impostor.ocsp`, but not the end-entity certificate.
+    let impostor = rustls_pemfile::certs(&mut io::BufReader::new(bytes_for(
+        "rsa",
+        "inter.impostor.cert",
+    )))
+    .unwrap()
+    .remove(0);
+    let kt = KeyType::Rsa;
+    let mut chain = kt.get_chain();
+    chain.insert(1, rustls::Certificate(impostor));
+
+    let cases = [
+        (bytes_for("rsa", "end.impostor.ocsp").to_vec(), false),
+        (kt.get_ocsp_response(), true),
+    ];
+    for (ocsp, accepted) in cases {
+        let server_config = Arc::new(
+            ServerConfig::<Ring>::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert_with_ocsp(chain.clone(), kt.get_key(), ocsp)
+                .unwrap(),
+        );
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(client_config_requiring_ocsp(
+                kt,
+                version,
+                rustls::OcspStaplePolicy::default(),
+            ));
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            let result = do_handshake_until_error(&mut client, &mut server);
+            match accepted {
+                true => assert_eq!(result, Ok(())),
+                false => assert_eq!(
+                    result,
+                    Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                        CertificateError::InvalidOcspResponse
+                    )))
+                ),
+            }
+        }
+    }
+}
+
+#[test]
+fn client_tolerates_huge_ocsp_staple_policy() {
+    let kt = KeyType::Rsa;
+    let server_config = server_config_with_ocsp(kt, kt.get_ocsp_response());
+    let mut policy = rustls::OcspStaplePolicy::default();
+    policy.max_age = Some(Duration::MAX);
+    policy.allowed_skew = Duration::MAX;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(client_config_requiring_ocsp(kt, version, policy));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn client_does_not_require_ocsp_staple_by_default() {
+    let kt = KeyType::Rsa;
+    let server_config = server_config_with_ocsp(kt, kt.get_revoked_ocsp_response());
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        assert_eq!(client_config.require_ocsp_staple, None);
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn client_can_get_server_scts() {
+    // A SignedCertificateTimestampList holding two made-up SCTs.
+    let mut sct = vec![0u8];
+    sct.extend_from_slice(&[0x42; 32]);
+    sct.extend_from_slice(&1_700_000_000_000u64.to_be_bytes());
+    sct.extend_from_slice(&[0x00, 0x00, 0x04, 0x03, 0x00, 0x01, 0xff]);
+    let mut sct_list = vec![0x00, 2 * (2 + sct.len() as u8)];
+    for _ in 0..2 {
+        sct_list.extend_from_slice(&[0x00, sct.len() as u8]);
+        sct_list.extend_from_slice(&sct);
+    }
+
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(
+        ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(), vec![], sct_list)
+            .unwrap(),
+    );
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert!(client
+            .signed_certificate_timestamps()
+            .is_empty());
+        do_handshake(&mut client, &mut server);
+
+        let scts = client.signed_certificate_timestamps();
+        assert_eq!(scts.len(), 2);
+        for received in scts {
+            assert_eq!(received.source(), rustls::SctSource::TlsExtension);
+            assert_eq!(received.bytes(), &sct[..]);
+            assert_eq!(received.log_id(), Some(&[0x42; 32][..]));
+            assert_eq!(
+                received.timestamp(),
+                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            );
+        }
+
+        // no certificate, so no SCTs, when resuming
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(client
+            .signed_certificate_timestamps()
+            .is_empty());
+    }
+}
+
+#[test]
+fn server_rejects_invalid_sct_list() {
+    let kt = KeyType::Rsa;
+    for sct_list in [
+        &[0x00, 0x00][..],
+        &[0x00, 0x02, 0x00, 0x00],
+        &[0x00, 0x03, 0x00, 0x02],
+    ] {
+        let result = ServerConfig::<Ring>::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert_with_ocsp_and_sct(
+                kt.get_chain(),
+                kt.get_key(),
+                vec![],
+                sct_list.to_vec(),
+            );
+        assert_eq!(
+            result.err(),
+            Some(Error::General("invalid SCT list".into()))
+        );
+    }
+}
+
+#[test]
+fn server_does_not_send_invalid_sct_list_from_resolver() {
+    let kt = KeyType::Rsa;
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let mut certified_key = sign::CertifiedKey::new(kt.get_chain(), Arc::new(signing_key));
+    // truncated: claims three bytes of SCTs, but has two
+    certified_key.sct_list = Some(vec![0x00, 0x03, 0x00, 0x02]);
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(ResolvesOnlyChain(Arc::new(certified_key)));
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(client
+            .signed_certificate_timestamps()
+            .is_empty());
+    }
+}
+
 #[test]
 fn server_can_get_client_cert() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -454,7 +957,7 @@ fn test_config_builders_debug() {
         .with_protocol_versions(&[&rustls::version::TLS13])
         .unwrap();
     let b = b.with_no_client_auth();
-    assert_eq!("ConfigBuilder<ServerConfig<Ring>, _> { state: WantsServerCert { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519], versions: [TLSv1_3], verifier: dyn ClientCertVerifier } }", format!("{:?}", b));
+    assert_eq!("ConfigBuilder<ServerConfig<Ring>, _> { state: WantsServerCert { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519], versions: [TLSv1_3], signature_schemes: None, verifier: dyn ClientCertVerifier } }", format!("{:?}", b));
 
     let b = ClientConfig::<Ring>::builder();
     assert_eq!(
@@ -468,7 +971,7 @@ fn test_config_builders_debug() {
     let b = b
         .with_protocol_versions(&[&rustls::version::TLS13])
         .unwrap();
-    assert_eq!("ConfigBuilder<ClientConfig<Ring>, _> { state: WantsVerifier { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519], versions: [TLSv1_3] } }", format!("{:?}", b));
+    assert_eq!("ConfigBuilder<ClientConfig<Ring>, _> { state: WantsVerifier { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519], versions: [TLSv1_3], signature_schemes: None } }", format!("{:?}", b));
 }
 
 /// Test that the server handles combination of `offer_client_auth()` returning true
@@ -685,6 +1188,7 @@ struct ServerCheckCertResolve {
     expected_sigalgs: Option<Vec<SignatureScheme>>,
     expected_alpn: Option<Vec<Vec<u8>>>,
     expected_cipher_suites: Option<Vec<CipherSuite>>,
+    expected_certificate_authorities: Option<Vec<Vec<u8>>>,
 }
 
 impl ResolvesServerCert for ServerCheckCertResolve {
@@ -735,6 +1239,16 @@ impl ResolvesServerCert for ServerCheckCertResolve {
             );
         }
 
+        if let Some(expected_cas) = &self.expected_certificate_authorities {
+            let cas = client_hello
+                .certificate_authorities()
+                .unwrap_or_default()
+                .iter()
+                .map(|name| name.as_ref().to_vec())
+                .collect::<Vec<_>>();
+            assert_eq!(expected_cas, &cas, "unexpected certificate authorities");
+        }
+
         None
     }
 }
@@ -782,9 +1296,63 @@ fn server_cert_resolve_with_alpn() {
 }
 
 #[test]
-fn client_trims_terminating_dot() {
+fn server_cert_resolve_with_certificate_authorities() {
     for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config(*kt);
+        let roots = get_client_root_store(*kt);
+        let names = roots
+            .subjects()
+            .iter()
+            .map(|name| name.as_ref().to_vec())
+            .collect::<Vec<_>>();
+
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.certificate_authorities = roots.subjects();
+
+            // The extension is only defined for TLS1.3.
+            let expected = match version.version {
+                ProtocolVersion::TLSv1_3 => names.clone(),
+                _ => vec![],
+            };
+
+            let mut server_config = make_server_config(*kt);
+            server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+                expected_certificate_authorities: Some(expected),
+                ..Default::default()
+            });
+
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), server_name("sni-value")).unwrap();
+            let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert!(err.is_err());
+        }
+    }
+}
+
+#[test]
+fn server_cert_resolve_without_certificate_authorities() {
+    let client_config = make_client_config(KeyType::Rsa);
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+        expected_certificate_authorities: Some(vec![]),
+        ..Default::default()
+    });
+
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), server_name("sni-value")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert!(err.is_err());
+}
+
+#[test]
+fn client_trims_terminating_dot() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config(*kt);
         let mut server_config = make_server_config(*kt);
 
         server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
@@ -895,6 +1463,66 @@ fn client_with_sni_disabled_does_not_send_sni() {
     }
 }
 
+#[derive(Default)]
+struct ServerRecordUserData(Mutex<Vec<Option<u32>>>);
+
+impl ResolvesServerCert for ServerRecordUserData {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        let user_data = client_hello
+            .user_data()
+            .and_then(|data| data.downcast_ref::<u32>());
+        self.0
+            .lock()
+            .unwrap()
+            .push(user_data.copied());
+        None
+    }
+}
+
+#[test]
+fn server_cert_resolver_receives_user_data() {
+    let resolver = Arc::new(ServerRecordUserData::default());
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = resolver.clone();
+    let server_config = Arc::new(server_config);
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    server.set_user_data(Box::new(1u32));
+    assert_eq!(
+        server
+            .user_data()
+            .and_then(|data| data.downcast_ref::<u32>()),
+        Some(&1)
+    );
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+
+    let mut client = ClientConnection::new(client_config, server_name("localhost")).unwrap();
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+    let mut acceptor = rustls::server::Acceptor::default();
+    acceptor
+        .read_tls(&mut &buf[..])
+        .unwrap();
+    let mut accepted = acceptor.accept().unwrap().unwrap();
+    accepted.set_user_data(Box::new(3u32));
+    assert_eq!(
+        accepted
+            .client_hello()
+            .user_data()
+            .and_then(|data| data.downcast_ref::<u32>()),
+        Some(&3)
+    );
+    assert!(accepted
+        .into_connection(server_config)
+        .is_err());
+
+    assert_eq!(*resolver.0.lock().unwrap(), [Some(1), None, Some(3)]);
+}
+
 #[test]
 fn client_checks_server_certificate_with_given_name() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -920,6 +1548,170 @@ fn client_checks_server_certificate_with_given_name() {
     }
 }
 
+#[test]
+fn client_uses_replaced_shared_root_certificates() {
+    let roots = rustls::SharedRootCertStore::new(rustls::RootCertStore::empty());
+    let mut client_config = ClientConfig::<Ring>::builder()
+        .with_safe_defaults()
+        .with_shared_root_certificates(roots.clone())
+        .with_no_client_auth();
+    client_config.resumption = Resumption::disabled();
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+            CertificateError::UnknownIssuer
+        )))
+    );
+
+    let previous = roots.replace(get_client_root_store(KeyType::Rsa));
+    assert!(previous.is_empty());
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    roots.replace(rustls::RootCertStore::empty());
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+fn client_config_with_anchors(
+    anchors: impl Iterator<Item = rustls::OwnedTrustAnchor>,
+) -> ClientConfig<Ring> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_server_trust_anchors(anchors);
+    ClientConfig::<Ring>::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+#[test]
+fn client_honours_trust_anchor_distrust_after() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let not_before = kt.get_chain()[0]
+            .details()
+            .unwrap()
+            .not_before();
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for (distrust_after, trusted) in [
+            (not_before, true),
+            (not_before - Duration::from_secs(1), false),
+        ] {
+            let roots = get_client_root_store(*kt);
+            let client_config = client_config_with_anchors(
+                roots
+                    .iter()
+                    .cloned()
+                    .map(|anchor| anchor.with_distrust_after(distrust_after)),
+            );
+            assert!(roots
+                .iter()
+                .all(|anchor| anchor.distrust_after().is_none()));
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            let result = do_handshake_until_error(&mut client, &mut server);
+            if trusted {
+                assert_eq!(result, Ok(()));
+            } else {
+                assert_eq!(
+                    result,
+                    Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                        CertificateError::UnknownIssuer
+                    )))
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn client_honours_trust_anchor_name_constraints() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for (domains, trusted) in [
+            (&["localhost", "testserver.com"][..], true),
+            (&["example.com"][..], false),
+        ] {
+            let client_config = client_config_with_anchors(
+                get_client_root_store(*kt)
+                    .iter()
+                    .cloned()
+                    .map(|anchor| anchor.with_permitted_dns_names(domains.iter().copied())),
+            );
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server).is_ok(),
+                trusted
+            );
+        }
+    }
+}
+
+#[test]
+fn root_cert_store_anchors_can_be_removed() {
+    let mut roots = get_client_root_store(KeyType::Rsa);
+    assert_eq!(roots.len(), 2);
+    let ca_subject = roots.roots[1]
+        .subject()
+        .as_ref()
+        .to_vec();
+
+    assert_eq!(
+        roots.retain(|anchor| anchor.subject().as_ref() == ca_subject),
+        1
+    );
+    assert_eq!(roots.len(), 1);
+    assert_eq!(
+        roots
+            .iter()
+            .map(|anchor| anchor.subject().as_ref().to_vec())
+            .collect::<Vec<_>>(),
+        vec![ca_subject]
+    );
+    assert_eq!(roots.retain(|_| true), 0);
+}
+
+#[test]
+fn client_completes_chain_from_known_intermediates() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let chain = kt.get_chain();
+        let server_config = Arc::new(
+            ServerConfig::<Ring>::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(chain[..1].to_vec(), kt.get_key())
+                .unwrap(),
+        );
+
+        let mut client_config = make_client_config(*kt);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config.clone()), &server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                CertificateError::UnknownIssuer
+            )))
+        );
+
+        let mut known = rustls::KnownIntermediates::empty();
+        assert_eq!(known.add_parsable_certificates(&chain[1..2]), (1, 0));
+        assert_eq!(known.add_parsable_certificates([&b"junk"[..]]), (0, 1));
+        assert_eq!(known.len(), 1);
+        client_config.known_intermediates = Arc::new(known);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.peer_certificates(), Some(&chain[..1]));
+    }
+}
+
 #[test]
 fn client_checks_server_certificate_with_given_ip_address() {
     fn check_server_name(
@@ -1009,7 +1801,7 @@ impl ResolvesClientCert for ClientCheckCertResolve {
         self.query_count
             .fetch_add(1, Ordering::SeqCst);
 
-        if acceptable_issuers.is_empty() {
+        if acceptable_issuers.is_empty() && !self.expect_issuers.is_empty() {
             panic!("no issuers offered by server");
         }
 
@@ -1028,6 +1820,32 @@ impl ResolvesClientCert for ClientCheckCertResolve {
     }
 }
 
+/// The signature schemes the server offers for client authentication.
+fn client_auth_sigschemes(version: ProtocolVersion) -> Vec<SignatureScheme> {
+    match version {
+        ProtocolVersion::TLSv1_2 => vec![
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PSS_SHA512,
+            SignatureScheme::RSA_PSS_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PKCS1_SHA512,
+            SignatureScheme::RSA_PKCS1_SHA384,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ],
+        ProtocolVersion::TLSv1_3 => vec![
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PSS_SHA512,
+            SignatureScheme::RSA_PSS_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+        ],
+        _ => unreachable!(),
+    }
+}
+
 #[test]
 fn client_cert_resolve() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -1049,36 +1867,13 @@ fn client_cert_resolve() {
         };
 
         for version in rustls::ALL_VERSIONS {
-            let expected_sigschemes = match version.version {
-                ProtocolVersion::TLSv1_2 => vec![
-                    SignatureScheme::ECDSA_NISTP384_SHA384,
-                    SignatureScheme::ECDSA_NISTP256_SHA256,
-                    SignatureScheme::ED25519,
-                    SignatureScheme::RSA_PSS_SHA512,
-                    SignatureScheme::RSA_PSS_SHA384,
-                    SignatureScheme::RSA_PSS_SHA256,
-                    SignatureScheme::RSA_PKCS1_SHA512,
-                    SignatureScheme::RSA_PKCS1_SHA384,
-                    SignatureScheme::RSA_PKCS1_SHA256,
-                ],
-                ProtocolVersion::TLSv1_3 => vec![
-                    SignatureScheme::ECDSA_NISTP384_SHA384,
-                    SignatureScheme::ECDSA_NISTP256_SHA256,
-                    SignatureScheme::ED25519,
-                    SignatureScheme::RSA_PSS_SHA512,
-                    SignatureScheme::RSA_PSS_SHA384,
-                    SignatureScheme::RSA_PSS_SHA256,
-                ],
-                _ => unreachable!(),
-            };
-
             println!("{:?} {:?}:", version.version, *kt);
 
             let mut client_config = make_client_config_with_versions(*kt, &[version]);
             client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(
                 1,
                 expected_issuers.clone(),
-                expected_sigschemes,
+                client_auth_sigschemes(version.version),
             ));
 
             let (mut client, mut server) =
@@ -1092,6 +1887,215 @@ fn client_cert_resolve() {
     }
 }
 
+/// Has no certificate, and refuses to continue without one.
+struct ClientRefusesWithoutCert {
+    notified: Mutex<Vec<(usize, Vec<SignatureScheme>)>>,
+}
+
+impl ResolvesClientCert for ClientRefusesWithoutCert {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        None
+    }
+
+    fn no_client_cert(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+        _user_data: Option<&(dyn std::any::Any + Send + Sync)>,
+    ) -> Result<(), Error> {
+        self.notified
+            .lock()
+            .unwrap()
+            .push((acceptable_issuers.len(), sigschemes.to_vec()));
+        Err(Error::General("smart card removed".into()))
+    }
+
+    fn has_certs(&self) -> bool {
+        false
+    }
+}
+
+#[test]
+fn client_can_refuse_to_continue_without_cert() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+
+    for version in rustls::ALL_VERSIONS {
+        let resolver = Arc::new(ClientRefusesWithoutCert {
+            notified: Mutex::new(Vec::new()),
+        });
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config.client_auth_cert_resolver = resolver.clone();
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::General(
+                "smart card removed".into()
+            )))
+        );
+        assert_eq!(
+            *resolver.notified.lock().unwrap(),
+            vec![(2, client_auth_sigschemes(version.version))]
+        );
+
+        transfer(&mut client, &mut server);
+        assert_eq!(
+            server.process_new_packets(),
+            Err(Error::AlertReceived(
+                rustls::AlertDescription::HandshakeFailure
+            ))
+        );
+    }
+}
+
+#[test]
+fn client_auth_root_hints_can_be_configured() {
+    let kt = KeyType::Rsa;
+    let hint = b"0\x101\x0e0\x0c\x06\x03U\x04\x03\x0c\x05hint!".to_vec();
+
+    for hints in [vec![hint], vec![]] {
+        let names = hints
+            .iter()
+            .cloned()
+            .map(DistinguishedName::from)
+            .collect::<Vec<_>>();
+
+        let mut by_config = make_server_config_with_mandatory_client_auth(kt);
+        by_config.client_auth_root_hints = Some(names.clone());
+
+        let verifier = AllowAnyAuthenticatedClient::new(get_client_root_store(kt))
+            .with_root_hint_subjects(names);
+        let by_verifier = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier.boxed())
+            .with_single_cert(kt.get_chain(), kt.get_key())
+            .unwrap();
+
+        for server_config in [by_config, by_verifier] {
+            let server_config = Arc::new(server_config);
+
+            for version in rustls::ALL_VERSIONS {
+                let mut client_config = make_client_config_with_versions(kt, &[version]);
+                client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(
+                    1,
+                    hints.clone(),
+                    client_auth_sigschemes(version.version),
+                ));
+
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+                assert_eq!(
+                    do_handshake_until_error(&mut client, &mut server),
+                    Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+                );
+            }
+        }
+    }
+}
+
+fn client_config_with_signature_schemes(
+    kt: KeyType,
+    version: &'static rustls::SupportedProtocolVersion,
+    schemes: &[SignatureScheme],
+) -> ClientConfig<Ring> {
+    let builder = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[version])
+        .unwrap()
+        .with_signature_schemes(schemes)
+        .unwrap();
+    finish_client_config_with_creds(kt, builder)
+}
+
+fn server_config_with_signature_schemes(
+    kt: KeyType,
+    schemes: &[SignatureScheme],
+) -> ServerConfig<Ring> {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_signature_schemes(schemes)
+        .unwrap()
+        .with_client_cert_verifier(
+            AllowAnyAuthenticatedClient::new(get_client_root_store(kt)).boxed(),
+        )
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap()
+}
+
+#[test]
+fn signature_schemes_can_be_restricted() {
+    let ecdsa = [
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+    ];
+    let rsa_pss = [
+        SignatureScheme::RSA_PSS_SHA256,
+        SignatureScheme::RSA_PSS_SHA384,
+        SignatureScheme::RSA_PSS_SHA512,
+    ];
+    let rsa_pkcs1_and_pss = [
+        SignatureScheme::RSA_PKCS1_SHA256,
+        SignatureScheme::RSA_PSS_SHA256,
+    ];
+
+    for version in rustls::ALL_VERSIONS {
+        // ECDSA test certificates are signed with ecdsa-with-SHA256, and the
+        // client's key is on P-384.
+        let client_config = client_config_with_signature_schemes(KeyType::Ecdsa, version, &ecdsa);
+        let server_config = server_config_with_signature_schemes(KeyType::Ecdsa, &ecdsa);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let client_config =
+            client_config_with_signature_schemes(KeyType::Rsa, version, &rsa_pkcs1_and_pss);
+        let server_config = server_config_with_signature_schemes(KeyType::Rsa, &rsa_pkcs1_and_pss);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        // RSA test certificates are signed with PKCS#1 signatures.
+        let client_config = client_config_with_signature_schemes(KeyType::Rsa, version, &rsa_pss);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                CertificateError::UnacceptableSignatureAlgorithm
+            )))
+        );
+
+        let client_config = make_client_config_with_versions_with_auth(KeyType::Rsa, &[version]);
+        let server_config = server_config_with_signature_schemes(KeyType::Rsa, &rsa_pss);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Server(Error::InvalidCertificate(
+                CertificateError::UnacceptableSignatureAlgorithm
+            )))
+        );
+    }
+}
+
+#[test]
+fn signature_schemes_must_include_a_supported_scheme() {
+    let result = ClientConfig::<Ring>::builder()
+        .with_safe_defaults()
+        .with_signature_schemes(&[SignatureScheme::ED448]);
+    assert!(result.is_err());
+
+    let result = ServerConfig::<Ring>::builder()
+        .with_safe_defaults()
+        .with_signature_schemes(&[]);
+    assert!(result.is_err());
+}
+
 #[test]
 fn client_auth_works() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -1107,7 +2111,64 @@ fn client_auth_works() {
 }
 
 #[test]
-fn client_mandatory_auth_revocation_works() {
+fn server_enforces_cert_chain_limits() {
+    let kt = KeyType::Rsa;
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config
+        .cert_chain_limits
+        .max_certificate_size = Some(64);
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions_with_auth(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Server(Error::InvalidCertificate(
+                CertificateError::ChainExceedsLimits
+            )))
+        );
+    }
+}
+
+#[test]
+fn client_auth_certificate_requirements() {
+    let mut require_eku = rustls::CertificateRequirements::default();
+    require_eku.require_extended_key_usage = true;
+    let mut require_rsa_3072 = rustls::CertificateRequirements::default();
+    require_rsa_3072.min_rsa_modulus_bits = 3072;
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for (requirements, expected) in [
+            (require_eku.clone(), Ok(())),
+            (
+                require_rsa_3072.clone(),
+                match kt {
+                    KeyType::Rsa => Err(ErrorFromPeer::Server(Error::InvalidCertificate(
+                        CertificateError::UnacceptableKey,
+                    ))),
+                    _ => Ok(()),
+                },
+            ),
+        ] {
+            let client_auth =
+                rustls::server::AllowAnyAuthenticatedClient::new(get_client_root_store(*kt))
+                    .with_requirements(requirements);
+            let server_config = ServerConfig::<Ring>::builder()
+                .with_safe_defaults()
+                .with_client_cert_verifier(Arc::new(client_auth))
+                .with_single_cert(kt.get_chain(), kt.get_key())
+                .unwrap();
+            let (mut client, mut server) =
+                make_pair_for_configs(make_client_config_with_auth(*kt), server_config);
+            assert_eq!(do_handshake_until_error(&mut client, &mut server), expected);
+        }
+    }
+}
+
+#[test]
+fn client_mandatory_auth_revocation_works() {
     for kt in ALL_KEY_TYPES.iter() {
         // Create a server configuration that includes a CRL that specifies the client certificate
         // is revoked.
@@ -1477,6 +2538,41 @@ fn client_read_returns_wouldblock_when_no_data() {
                      Err(err) if err.kind() == io::ErrorKind::WouldBlock));
 }
 
+#[test]
+fn client_reader_implements_bufread() {
+    use std::io::BufRead;
+
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    assert!(matches!(client.reader().fill_buf(),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+
+    server
+        .writer()
+        .write_all(b"hello\nwor")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"ld\n")
+        .unwrap();
+    server.send_close_notify();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    let mut reader = client.reader();
+    assert_eq!(reader.fill_buf().unwrap(), b"hello\nwor");
+    reader.consume(2);
+    assert_eq!(reader.fill_buf().unwrap(), b"llo\nwor");
+
+    let mut lines = Vec::new();
+    for line in reader.lines() {
+        lines.push(line.unwrap());
+    }
+    assert_eq!(lines, vec!["llo", "world"]);
+    assert_eq!(client.reader().fill_buf().unwrap(), b"");
+}
+
 #[test]
 fn new_server_returns_initial_io_state() {
     let (_, mut server) = make_pair(KeyType::Rsa);
@@ -1956,6 +3052,139 @@ fn server_streamowned_handshake_error() {
     );
 }
 
+/// One end of an in-memory transport that never blocks: it fails with
+/// `WouldBlock` when there is nothing to read, or its peer has `capacity`
+/// bytes unread.
+struct NonBlockingPipe {
+    incoming: std::rc::Rc<RefCell<Vec<u8>>>,
+    outgoing: std::rc::Rc<RefCell<Vec<u8>>>,
+    capacity: usize,
+}
+
+impl NonBlockingPipe {
+    fn pair(capacity: usize) -> (Self, Self) {
+        let (left, right) = Default::default();
+        (
+            Self {
+                incoming: std::rc::Rc::clone(&left),
+                outgoing: std::rc::Rc::clone(&right),
+                capacity,
+            },
+            Self {
+                incoming: right,
+                outgoing: left,
+                capacity,
+            },
+        )
+    }
+}
+
+impl io::Read for NonBlockingPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(incoming.len());
+        buf[..len].copy_from_slice(&incoming[..len]);
+        incoming.drain(..len);
+        Ok(len)
+    }
+}
+
+impl io::Write for NonBlockingPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.outgoing.borrow_mut();
+        let len = buf
+            .len()
+            .min(self.capacity - outgoing.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        outgoing.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn would_block<T: fmt::Debug>(result: io::Result<T>) -> bool {
+    match result {
+        Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+        Ok(_) => false,
+    }
+}
+
+#[test]
+fn nonblocking_stream_reports_wouldblock() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, server) = make_pair(*kt);
+        let (client_sock, server_sock) = NonBlockingPipe::pair(100);
+        let mut client = NonBlockingStream::new(client, client_sock);
+        let mut server = NonBlockingStream::new(server, server_sock);
+
+        // Neither side can finish without the other, and the transport
+        // fills up far below the size of a handshake flight.
+        assert!(would_block(client.handshake()));
+        assert!(client.wants_write());
+        assert!(!client.wants_read());
+        assert!(would_block(server.read(&mut [0u8; 1])));
+
+        let mut rounds = 0;
+        while would_block(client.handshake()) | would_block(server.handshake()) {
+            // The server's session tickets can only be written as the
+            // client reads.
+            assert!(would_block(client.read(&mut [0u8; 1])));
+            rounds += 1;
+            assert!(rounds < 1000, "handshake made no progress");
+        }
+        assert!(!client.conn.is_handshaking());
+        assert!(!server.conn.is_handshaking());
+
+        // Plaintext is accepted before it can all be sent...
+        assert_eq!(client.write(&[b'x'; 1000]).unwrap(), 1000);
+        assert!(client.wants_write());
+        assert!(would_block(client.flush()));
+
+        // ...and arrives as the transport allows.
+        let mut received = Vec::new();
+        while received.len() < 1000 {
+            let mut buf = [0u8; 64];
+            match server.read(&mut buf) {
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let _ = client.flush();
+                }
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+        }
+        assert_eq!(received, vec![b'x'; 1000]);
+        client.flush().unwrap();
+        assert!(would_block(server.read(&mut [0u8; 1])));
+    }
+}
+
+#[test]
+fn nonblocking_stream_reports_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (client_sock, server_sock) = NonBlockingPipe::pair(4096);
+    let mut client = NonBlockingStream::new(client, client_sock);
+    let mut server = NonBlockingStream::new(server, server_sock);
+
+    assert!(would_block(client.handshake()));
+    assert_eq!(
+        format!("{:?}", server.handshake()),
+        "Err(Custom { kind: InvalidData, error: PeerIncompatible(NoCipherSuitesInCommon) })"
+    );
+    assert_eq!(
+        format!("{:?}", client.handshake()),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
+}
+
 #[test]
 fn server_config_is_clone() {
     let _ = make_server_config(KeyType::Rsa);
@@ -2061,6 +3290,84 @@ fn server_exposes_offered_sni_even_if_resolver_fails() {
     }
 }
 
+#[test]
+fn server_can_require_sni() {
+    let kt = KeyType::Rsa;
+    let mut server_config = make_server_config(kt);
+    server_config.require_sni = true;
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config.enable_sni = false;
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Server(Error::PeerIncompatible(
+                PeerIncompatible::ServerNameExtensionRequired
+            )))
+        );
+        transfer(&mut server, &mut client);
+        assert_eq!(
+            client.process_new_packets(),
+            Err(Error::AlertReceived(
+                rustls::AlertDescription::HandshakeFailure
+            ))
+        );
+
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn server_can_refuse_server_names() {
+    let kt = KeyType::Rsa;
+    let mut server_config = make_server_config(kt);
+    server_config.server_names =
+        Some(rustls::server::ServerNameAllowList::new(["localhost", "*.testserver.com"]).unwrap());
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        for name in ["localhost", "second.testserver.com"] {
+            let client_config = make_client_config_with_versions(kt, &[version]);
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), server_name(name)).unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            do_handshake(&mut client, &mut server);
+        }
+
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), server_name("testserver.com")).unwrap();
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Server(Error::PeerIncompatible(
+                PeerIncompatible::ServerNameRefused
+            )))
+        );
+        transfer(&mut server, &mut client);
+        assert_eq!(
+            client.process_new_packets(),
+            Err(Error::AlertReceived(
+                rustls::AlertDescription::UnrecognisedName
+            ))
+        );
+        assert_eq!(server.server_name(), Some("testserver.com"));
+    }
+
+    // clients without SNI are not affected
+    let mut client_config = make_client_config(kt);
+    client_config.enable_sni = false;
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    do_handshake(&mut client, &mut server);
+}
+
 #[test]
 fn sni_resolver_works() {
     let kt = KeyType::Rsa;
@@ -2083,6 +3390,10 @@ fn sni_resolver_works() {
         ClientConnection::new(Arc::new(make_client_config(kt)), server_name("localhost")).unwrap();
     let err = do_handshake_until_error(&mut client1, &mut server1);
     assert_eq!(err, Ok(()));
+    assert_eq!(
+        server1.cert_selection().unwrap().reason,
+        rustls::server::CertSelectionReason::ServerName
+    );
 
     let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
     let mut client2 = ClientConnection::new(
@@ -2099,6 +3410,40 @@ fn sni_resolver_works() {
     );
 }
 
+struct ResolvesOnlyChain(Arc<sign::CertifiedKey>);
+
+impl ResolvesServerCert for ResolvesOnlyChain {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        Some(Arc::clone(&self.0))
+    }
+}
+
+#[test]
+fn server_reports_cert_selection() {
+    use rustls::server::CertSelectionReason;
+
+    let kt = KeyType::Rsa;
+    let (mut client, mut server) = make_pair(kt);
+    assert!(server.cert_selection().is_none());
+    do_handshake(&mut client, &mut server);
+    let selection = server.cert_selection().unwrap();
+    assert_eq!(selection.reason, CertSelectionReason::Fallback);
+    assert_eq!(selection.certified_key.cert, kt.get_chain());
+
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(ResolvesOnlyChain(Arc::new(sign::CertifiedKey::new(
+        kt.get_chain(),
+        Arc::new(signing_key),
+    ))));
+    let (mut client, mut server) = make_pair_for_configs(make_client_config(kt), server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        server.cert_selection().unwrap().reason,
+        CertSelectionReason::Unspecified
+    );
+}
+
 #[test]
 fn sni_resolver_rejects_wrong_names() {
     let kt = KeyType::Rsa;
@@ -2250,6 +3595,33 @@ fn do_exporter_test(
     assert_eq!(client_secret.to_vec(), server_secret.to_vec());
 }
 
+#[test]
+fn connection_id_is_shared_and_distinct() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+        let client_config = Arc::new(client_config);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert_eq!(client.connection_id(), Err(Error::HandshakeNotComplete));
+        assert_eq!(server.connection_id(), Err(Error::HandshakeNotComplete));
+        do_handshake(&mut client, &mut server);
+
+        let id = client.connection_id().unwrap();
+        assert_eq!(server.connection_id(), Ok(id));
+        assert_eq!(id.to_string().len(), 16);
+
+        // The next connection resumes this one, but gets a new identifier.
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        let resumed = rustls::Connection::from(client)
+            .connection_id()
+            .unwrap();
+        assert_eq!(server.connection_id(), Ok(resumed));
+        assert_ne!(resumed, id);
+    }
+}
+
 #[cfg(feature = "tls12")]
 #[test]
 fn test_tls12_exporter() {
@@ -3107,473 +4479,1251 @@ fn tls13_stateless_resumption() {
     );
 }
 
-#[test]
-fn early_data_not_available() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(client.early_data().is_none());
+#[derive(Default)]
+struct ResumptionLog(Mutex<Vec<String>>);
+
+impl ResumptionLog {
+    fn push(&self, event: String) {
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn take(&self) -> Vec<String> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
 }
 
-fn early_data_configs() -> (Arc<ClientConfig<Ring>>, Arc<ServerConfig<Ring>>) {
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config(kt);
-    client_config.enable_early_data = true;
-    client_config.resumption = Resumption::store(Arc::new(ClientStorage::new()));
+impl rustls::client::ObservesClientResumption for ResumptionLog {
+    fn session_stored(&self, _server_name: &rustls::ServerName, version: ProtocolVersion) {
+        self.push(format!("stored {:?}", version));
+    }
 
-    let mut server_config = make_server_config(kt);
-    server_config.max_early_data_size = 1234;
-    (Arc::new(client_config), Arc::new(server_config))
+    fn resumption_not_attempted(
+        &self,
+        _server_name: &rustls::ServerName,
+        reason: rustls::client::ResumptionNotAttempted,
+    ) {
+        self.push(format!("not attempted {:?}", reason));
+    }
+
+    fn resumption_attempted(&self, _server_name: &rustls::ServerName, version: ProtocolVersion) {
+        self.push(format!("attempted {:?}", version));
+    }
+
+    fn resumption_accepted(&self, _server_name: &rustls::ServerName, version: ProtocolVersion) {
+        self.push(format!("accepted {:?}", version));
+    }
+
+    fn resumption_rejected(
+        &self,
+        _server_name: &rustls::ServerName,
+        reason: rustls::client::ResumptionRejected,
+    ) {
+        self.push(format!("rejected {:?}", reason));
+    }
+}
+
+impl rustls::server::ObservesServerResumption for ResumptionLog {
+    fn ticket_issued(&self, server_name: Option<&str>, version: ProtocolVersion) {
+        assert_eq!(server_name, Some("localhost"));
+        self.push(format!("issued {:?}", version));
+    }
+
+    fn ticket_redeemed(&self, _server_name: Option<&str>, version: ProtocolVersion) {
+        self.push(format!("redeemed {:?}", version));
+    }
+
+    fn ticket_rejected(
+        &self,
+        _server_name: Option<&str>,
+        version: ProtocolVersion,
+        reason: rustls::server::TicketRejected,
+    ) {
+        self.push(format!("rejected {:?} {:?}", version, reason));
+    }
 }
 
 #[test]
-fn early_data_is_available_on_resumption() {
-    let (client_config, server_config) = early_data_configs();
+fn tls13_resumption_is_observed() {
+    let kt = KeyType::Rsa;
+    let client_log = Arc::new(ResumptionLog::default());
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.resumption = Resumption::in_memory_sessions(32).observer(client_log.clone());
+    let client_config = Arc::new(client_config);
+
+    let server_log = Arc::new(ResumptionLog::default());
+    let mut server_config = make_server_config(kt);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    server_config.send_tls13_tickets = 2;
+    server_config.resumption_observer = server_log.clone();
+    let server_config = Arc::new(server_config);
 
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
     do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client_log.take(),
+        vec![
+            "not attempted NoStoredSession",
+            "stored TLSv1_3",
+            "stored TLSv1_3"
+        ]
+    );
+    assert_eq!(server_log.take(), vec!["issued TLSv1_3", "issued TLSv1_3"]);
 
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    assert!(client.early_data().is_some());
+    do_handshake(&mut client, &mut server);
     assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .bytes_left(),
-        1234
+        client_log.take(),
+        vec![
+            "attempted TLSv1_3",
+            "accepted TLSv1_3",
+            "stored TLSv1_3",
+            "stored TLSv1_3"
+        ]
     );
-    client
-        .early_data()
-        .unwrap()
-        .flush()
-        .unwrap();
     assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .write(b"hello")
-            .unwrap(),
-        5
+        server_log.take(),
+        vec!["redeemed TLSv1_3", "issued TLSv1_3", "issued TLSv1_3"]
     );
-    do_handshake(&mut client, &mut server);
 
-    let mut received_early_data = [0u8; 5];
+    // a server with different ticket keys can't use the client's ticket
+    let mut other_server_config = (*server_config).clone();
+    other_server_config.ticketer = rustls::Ticketer::new().unwrap();
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&client_config, &Arc::new(other_server_config));
+    do_handshake(&mut client, &mut server);
     assert_eq!(
-        server
-            .early_data()
-            .expect("early_data didn't happen")
-            .read(&mut received_early_data)
-            .expect("early_data failed unexpectedly"),
-        5
+        client_log.take(),
+        vec![
+            "attempted TLSv1_3",
+            "rejected ServerDeclined",
+            "stored TLSv1_3",
+            "stored TLSv1_3"
+        ]
+    );
+    assert_eq!(
+        server_log.take(),
+        vec![
+            "rejected TLSv1_3 Unrecognised",
+            "issued TLSv1_3",
+            "issued TLSv1_3"
+        ]
     );
-    assert_eq!(&received_early_data[..], b"hello");
-}
-
-#[test]
-fn early_data_not_available_on_server_before_client_hello() {
-    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
-    assert!(server.early_data().is_none());
 }
 
 #[test]
-fn early_data_can_be_rejected_by_server() {
-    let (client_config, server_config) = early_data_configs();
+fn tls13_stateless_tickets_can_be_single_use() {
+    let kt = KeyType::Rsa;
+    let client_config = Arc::new(make_client_config_with_versions(
+        kt,
+        &[&rustls::version::TLS13],
+    ));
+
+    let server_log = Arc::new(ResumptionLog::default());
+    let mut server_config = make_server_config(kt);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    server_config.single_use_tickets = Some(rustls::server::UsedTicketMemoryCache::new(
+        32,
+        server_config.ticketer.lifetime(),
+    ));
+    server_config.resumption_observer = server_log.clone();
+    let server_config = Arc::new(server_config);
 
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
     do_handshake(&mut client, &mut server);
+    server_log.take();
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    assert!(client.early_data().is_some());
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .bytes_left(),
-        1234
-    );
+    // replay the same resuming ClientHello to two servers
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let mut client_hello = Vec::new();
     client
-        .early_data()
-        .unwrap()
-        .flush()
+        .write_tls(&mut client_hello)
         .unwrap();
+
+    for expected in ["redeemed TLSv1_3", "rejected TLSv1_3 Reused"] {
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        server
+            .read_tls(&mut &client_hello[..])
+            .unwrap();
+        server.process_new_packets().unwrap();
+        assert_eq!(server_log.take()[0], expected);
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_resumption_is_observed() {
+    let kt = KeyType::Rsa;
+    let client_log = Arc::new(ResumptionLog::default());
+    let mut client_config = make_client_config(kt);
+    client_config.resumption = Resumption::in_memory_sessions(32).observer(client_log.clone());
+    let client_config = Arc::new(client_config);
+
+    let server_log = Arc::new(ResumptionLog::default());
+    let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS12]);
+    server_config.resumption_observer = server_log.clone();
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
     assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .write(b"hello")
-            .unwrap(),
-        5
+        client_log.take(),
+        vec!["not attempted NoStoredSession", "stored TLSv1_2"]
     );
-    server.reject_early_data();
+    assert_eq!(server_log.take(), vec!["issued TLSv1_2"]);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
     do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client_log.take(),
+        vec!["attempted TLSv1_2", "accepted TLSv1_2", "stored TLSv1_2"]
+    );
+    assert_eq!(server_log.take(), vec!["redeemed TLSv1_2"]);
 
-    assert!(!client.is_early_data_accepted());
+    // a server that only does TLS1.3 can't resume a TLS1.2 session
+    let tls13_server_config = Arc::new(make_server_config_with_versions(
+        kt,
+        &[&rustls::version::TLS13],
+    ));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &tls13_server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client_log.take()[..2],
+        ["attempted TLSv1_2", "rejected VersionMismatch"]
+    );
 }
 
-#[cfg(feature = "quic")]
-mod test_quic {
-    use super::*;
-    use rustls::quic::{self, ConnectionCommon};
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_resumption_is_not_observed_as_accepted_when_aborted() {
+    use rustls::internal::msgs::codec::Reader;
+    use rustls::internal::msgs::enums::ExtensionType;
+    use rustls::internal::msgs::message::{OpaqueMessage, PlainMessage};
 
-    // Returns the sender's next secrets to use, or the receiver's error.
-    fn step<L: SideData, R: SideData>(
-        send: &mut ConnectionCommon<L>,
-        recv: &mut ConnectionCommon<R>,
-    ) -> Result<Option<quic::KeyChange>, Error> {
-        let mut buf = Vec::new();
-        let change = loop {
-            let prev = buf.len();
-            if let Some(x) = send.write_hs(&mut buf) {
-                break Some(x);
-            }
-            if prev == buf.len() {
-                break None;
+    fn vary_suite(msg: &mut Message) {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+                sh.cipher_suite = match sh.cipher_suite {
+                    CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 => {
+                        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+                    }
+                    _ => CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                };
+                *encoded = Payload::new(parsed.get_encoding());
             }
-        };
-        if let Err(e) = recv.read_hs(&buf) {
-            return Err(e);
-        } else {
-            assert_eq!(recv.alert(), None);
         }
-
-        Ok(change)
     }
 
-    #[test]
-    fn test_quic_handshake() {
-        fn equal_packet_keys(x: &quic::PacketKey, y: &quic::PacketKey) -> bool {
-            // Check that these two sets of keys are equal.
-            let mut buf = vec![0; 32];
-            let (header, payload_tag) = buf.split_at_mut(8);
-            let (payload, tag_buf) = payload_tag.split_at_mut(8);
-            let tag = x
-                .encrypt_in_place(42, &*header, payload)
-                .unwrap();
-            tag_buf.copy_from_slice(tag.as_ref());
-
-            let result = y.decrypt_in_place(42, &*header, payload_tag);
-            match result {
-                Ok(payload) => payload == &[0; 8],
-                Err(_) => false,
+    fn strip_ems_ack(msg: &mut Message) {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+                sh.extensions
+                    .retain(|ext| ext.get_type() != ExtensionType::ExtendedMasterSecret);
+                *encoded = Payload::new(parsed.get_encoding());
             }
         }
+    }
 
-        fn compatible_keys(x: &quic::KeyChange, y: &quic::KeyChange) -> bool {
-            fn keys(kc: &quic::KeyChange) -> &quic::Keys {
-                match kc {
-                    quic::KeyChange::Handshake { keys } => keys,
-                    quic::KeyChange::OneRtt { keys, .. } => keys,
-                }
-            }
-
-            let (x, y) = (keys(x), keys(y));
-            equal_packet_keys(&x.local.packet, &y.remote.packet)
-                && equal_packet_keys(&x.remote.packet, &y.local.packet)
-        }
+    let cases = [
+        (
+            vary_suite as fn(&mut Message),
+            PeerMisbehaved::ResumptionOfferedWithVariedCipherSuite,
+        ),
+        (
+            strip_ems_ack,
+            PeerMisbehaved::ResumptionOfferedWithVariedEms,
+        ),
+    ];
 
+    for (alteration, expected) in cases {
         let kt = KeyType::Rsa;
-        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-        client_config.enable_early_data = true;
+        let client_log = Arc::new(ResumptionLog::default());
+        let mut client_config = make_client_config(kt);
+        client_config.resumption = Resumption::in_memory_sessions(32).observer(client_log.clone());
         let client_config = Arc::new(client_config);
-        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
-        server_config.max_early_data_size = 0xffffffff;
-        let server_config = Arc::new(server_config);
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
+        let server_config = Arc::new(make_server_config_with_versions(
+            kt,
+            &[&rustls::version::TLS12],
+        ));
 
-        // full handshake
-        let mut client = quic::ClientConnection::new(
-            Arc::clone(&client_config),
-            quic::Version::V1,
-            server_name("localhost"),
-            client_params.into(),
-        )
-        .unwrap();
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        client_log.take();
 
-        let mut server = quic::ServerConnection::new(
-            Arc::clone(&server_config),
-            quic::Version::V1,
-            server_params.into(),
-        )
-        .unwrap();
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
 
-        let client_initial = step(&mut client, &mut server).unwrap();
-        assert!(client_initial.is_none());
-        assert!(client.zero_rtt_keys().is_none());
-        assert_eq!(server.quic_transport_parameters(), Some(client_params));
-        let server_hs = step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(server.zero_rtt_keys().is_none());
-        let client_hs = step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        assert!(compatible_keys(&server_hs, &client_hs));
-        assert!(client.is_handshaking());
-        let server_1rtt = step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(!client.is_handshaking());
-        assert_eq!(client.quic_transport_parameters(), Some(server_params));
-        assert!(server.is_handshaking());
-        let client_1rtt = step(&mut client, &mut server)
-            .unwrap()
+        // the abbreviated handshake's Finished is encrypted, so alter and
+        // deliver just the ServerHello
+        let mut buf = Vec::new();
+        server.write_tls(&mut buf).unwrap();
+        let server_hello = OpaqueMessage::read(&mut Reader::init(&buf)).unwrap();
+        let mut server_hello = Message::try_from(server_hello.into_plain_message()).unwrap();
+        alteration(&mut server_hello);
+        let server_hello = PlainMessage::from(server_hello)
+            .into_unencrypted_opaque()
+            .encode();
+        client
+            .read_tls(&mut &server_hello[..])
             .unwrap();
-        assert!(!server.is_handshaking());
-        assert!(compatible_keys(&server_1rtt, &client_1rtt));
-        assert!(!compatible_keys(&server_hs, &server_1rtt));
-        assert!(step(&mut client, &mut server)
-            .unwrap()
-            .is_none());
-        assert!(step(&mut server, &mut client)
-            .unwrap()
-            .is_none());
-
-        // 0-RTT handshake
-        let mut client = quic::ClientConnection::new(
-            Arc::clone(&client_config),
-            quic::Version::V1,
-            server_name("localhost"),
-            client_params.into(),
-        )
-        .unwrap();
-        assert!(client
-            .negotiated_cipher_suite()
-            .is_some());
+        assert_eq!(
+            client.process_new_packets(),
+            Err(Error::PeerMisbehaved(expected))
+        );
+        assert_eq!(client_log.take(), vec!["attempted TLSv1_2"]);
+    }
+}
 
-        let mut server = quic::ServerConnection::new(
-            Arc::clone(&server_config),
-            quic::Version::V1,
-            server_params.into(),
-        )
-        .unwrap();
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_session_ids_can_be_disabled_on_server() {
+    let kt = KeyType::Rsa;
+    let client_log = Arc::new(ResumptionLog::default());
+    let mut client_config = make_client_config(kt);
+    client_config.resumption = Resumption::in_memory_sessions(32).observer(client_log.clone());
+    let client_config = Arc::new(client_config);
 
-        step(&mut client, &mut server).unwrap();
-        assert_eq!(client.quic_transport_parameters(), Some(server_params));
-        {
-            let client_early = client.zero_rtt_keys().unwrap();
-            let server_early = server.zero_rtt_keys().unwrap();
-            assert!(equal_packet_keys(
-                &client_early.packet,
-                &server_early.packet
-            ));
-        }
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(client.is_early_data_accepted());
+    let server_log = Arc::new(ResumptionLog::default());
+    let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS12]);
+    server_config.tls12_session_storage = Some(Arc::new(rustls::server::NoServerSessionStorage {}));
+    server_config.resumption_observer = server_log.clone();
+    let server_config = Arc::new(server_config);
 
-        // 0-RTT rejection
-        {
-            let client_config = (*client_config).clone();
-            let mut client = quic::ClientConnection::new(
-                Arc::new(client_config),
-                quic::Version::V1,
-                server_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap();
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client_log.take(), vec!["not attempted NoStoredSession"]);
+        assert!(server_log.take().is_empty());
+    }
 
-            let mut server = quic::ServerConnection::new(
-                Arc::clone(&server_config),
-                quic::Version::V1,
-                server_params.into(),
-            )
-            .unwrap();
-            server.reject_early_data();
+    // tickets still work
+    let mut server_config = (*server_config).clone();
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    let server_config = Arc::new(server_config);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client_log.take(),
+        vec!["not attempted NoStoredSession", "stored TLSv1_2"]
+    );
+    assert_eq!(server_log.take(), vec!["issued TLSv1_2"]);
 
-            step(&mut client, &mut server).unwrap();
-            assert_eq!(client.quic_transport_parameters(), Some(server_params));
-            assert!(client.zero_rtt_keys().is_some());
-            assert!(server.zero_rtt_keys().is_none());
-            step(&mut server, &mut client)
-                .unwrap()
-                .unwrap();
-            step(&mut client, &mut server)
-                .unwrap()
-                .unwrap();
-            step(&mut server, &mut client)
-                .unwrap()
-                .unwrap();
-            assert!(!client.is_early_data_accepted());
-        }
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client_log.take()[..2],
+        ["attempted TLSv1_2", "accepted TLSv1_2"]
+    );
+    assert_eq!(server_log.take()[..1], ["redeemed TLSv1_2"]);
+}
 
-        // failed handshake
-        let mut client = quic::ClientConnection::new(
-            client_config,
-            quic::Version::V1,
-            server_name("example.com"),
-            client_params.into(),
-        )
-        .unwrap();
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_session_ids_can_have_separate_storage() {
+    let kt = KeyType::Rsa;
+    let client_config = Arc::new(make_client_config(kt));
+    let server_log = Arc::new(ResumptionLog::default());
+    let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS12]);
+    server_config.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+    server_config.tls12_session_storage = Some(rustls::server::ServerSessionMemoryCache::new(4));
+    server_config.resumption_observer = server_log.clone();
+    let server_config = Arc::new(server_config);
 
-        let mut server =
-            quic::ServerConnection::new(server_config, quic::Version::V1, server_params.into())
-                .unwrap();
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server_log.take(), vec!["issued TLSv1_2"]);
 
-        step(&mut client, &mut server).unwrap();
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(step(&mut server, &mut client).is_err());
-        assert_eq!(
-            client.alert(),
-            Some(rustls::AlertDescription::BadCertificate)
-        );
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server_log.take(), vec!["redeemed TLSv1_2"]);
+}
 
-        // Key updates
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_client_can_resume_with_tickets_only() {
+    let kt = KeyType::Rsa;
+    let client_log = Arc::new(ResumptionLog::default());
+    let mut client_config = make_client_config(kt);
+    client_config.resumption = Resumption::in_memory_sessions(32)
+        .tls12_resumption(rustls::client::Tls12Resumption::TicketsOnly)
+        .observer(client_log.clone());
+    let client_config = Arc::new(client_config);
 
-        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
-            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
-                (c, s)
-            }
-            _ => unreachable!(),
-        };
+    // the server only issues session ids, which are not stored
+    let server_config = Arc::new(make_server_config_with_versions(
+        kt,
+        &[&rustls::version::TLS12],
+    ));
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client_log.take(), vec!["not attempted NoStoredSession"]);
+    }
 
-        let mut client_next = client_secrets.next_packet_keys();
-        let mut server_next = server_secrets.next_packet_keys();
-        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
-        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+    let mut server_config = (*server_config).clone();
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    let server_config = Arc::new(server_config);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client_log.take(),
+        vec!["not attempted NoStoredSession", "stored TLSv1_2"]
+    );
 
-        client_next = client_secrets.next_packet_keys();
-        server_next = server_secrets.next_packet_keys();
-        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
-        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
-    }
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client_log.take(),
+        vec!["attempted TLSv1_2", "accepted TLSv1_2", "stored TLSv1_2"]
+    );
+}
 
-    #[test]
-    fn test_quic_rejects_missing_alpn() {
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_client_does_not_offer_disabled_mechanism() {
+    let kt = KeyType::Rsa;
+    let store = Arc::new(rustls::client::ClientSessionMemoryCache::new(32));
+    let mut client_config = make_client_config(kt);
+    client_config.resumption = Resumption::store(store.clone());
+    let server_config = Arc::new(make_server_config_with_versions(
+        kt,
+        &[&rustls::version::TLS12],
+    ));
 
-        for &kt in ALL_KEY_TYPES.iter() {
-            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-            let client_config = Arc::new(client_config);
+    // store a session id based session, then stop allowing those
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config.clone()), &server_config);
+    do_handshake(&mut client, &mut server);
 
-            let mut server_config =
-                make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
-            server_config.alpn_protocols = vec!["foo".into()];
-            let server_config = Arc::new(server_config);
+    let client_log = Arc::new(ResumptionLog::default());
+    client_config.resumption = Resumption::store(store)
+        .tls12_resumption(rustls::client::Tls12Resumption::TicketsOnly)
+        .observer(client_log.clone());
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client_log.take(),
+        vec!["not attempted Tls12MechanismDisabled"]
+    );
+}
 
-            let mut client = quic::ClientConnection::new(
-                client_config,
-                quic::Version::V1,
-                server_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap();
-            let mut server =
-                quic::ServerConnection::new(server_config, quic::Version::V1, server_params.into())
-                    .unwrap();
+/// Counts how many times a session has been resumed.
+struct ResumptionCounter;
 
-            assert_eq!(
-                step(&mut client, &mut server)
-                    .err()
-                    .unwrap(),
-                Error::NoApplicationProtocol
-            );
+impl rustls::server::ProducesResumptionData for ResumptionCounter {
+    fn resumption_data(
+        &self,
+        server_name: Option<&str>,
+        _alpn_protocol: Option<&[u8]>,
+        received: Option<&[u8]>,
+    ) -> Option<Vec<u8>> {
+        assert_eq!(server_name, Some("localhost"));
+        Some(vec![received.map_or(0, |data| data[0] + 1)])
+    }
+}
 
-            assert_eq!(
-                server.alert(),
-                Some(rustls::AlertDescription::NoApplicationProtocol)
-            );
+#[test]
+fn resumption_data_is_carried_by_tickets() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        for stateless in [false, true] {
+            let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+            let mut server_config = make_server_config(kt);
+            if stateless {
+                server_config.ticketer = rustls::Ticketer::new().unwrap();
+            }
+            server_config.resumption_data = Arc::new(ResumptionCounter);
+            let server_config = Arc::new(server_config);
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.received_resumption_data(), None);
+
+            // TLS1.2 sessions resumed by session id are not stored again.
+            let reissued = stateless || version.version == ProtocolVersion::TLSv1_3;
+            for resumptions in 0..2 {
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&client_config, &server_config);
+                do_handshake(&mut client, &mut server);
+                let expected = if reissued { resumptions } else { 0 };
+                assert_eq!(server.received_resumption_data(), Some(&[expected][..]));
+            }
         }
     }
+}
 
-    #[cfg(feature = "tls12")]
-    #[test]
-    fn test_quic_no_tls13_error() {
-        let mut client_config =
-            make_client_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
-        client_config.alpn_protocols = vec!["foo".into()];
-        let client_config = Arc::new(client_config);
+#[test]
+fn connection_resumption_data_is_used_by_default() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let server_config = Arc::new(make_server_config(kt));
 
-        assert!(quic::ClientConnection::new(
-            client_config,
-            quic::Version::V1,
-            server_name("localhost"),
-            b"client params".to_vec(),
-        )
-        .is_err());
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        server.set_resumption_data(b"tenant");
+        do_handshake(&mut client, &mut server);
 
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.received_resumption_data(), Some(&b"tenant"[..]));
+    }
+}
 
-        assert!(quic::ServerConnection::new(
-            server_config,
-            quic::Version::V1,
-            b"server params".to_vec(),
-        )
-        .is_err());
+/// Resumes only sessions established for a given tenant.
+struct TenantValidator(&'static [u8]);
+
+impl rustls::server::ValidatesResumption for TenantValidator {
+    fn validate(
+        &self,
+        client_hello: &ClientHello<'_>,
+        session: &rustls::server::ResumingSession<'_>,
+    ) -> bool {
+        assert_eq!(session.server_name(), client_hello.server_name());
+        assert_eq!(session.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(session.peer_certificates(), None);
+        session.resumption_data() == self.0
     }
+}
 
-    #[test]
-    fn test_quic_invalid_early_data_size() {
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        server_config.alpn_protocols = vec!["foo".into()];
+#[test]
+fn stateless_resumption_can_be_validated() {
+    let kt = KeyType::Rsa;
+    let ticketer = rustls::Ticketer::new().unwrap();
+    for version in rustls::ALL_VERSIONS {
+        for (tenant, resumed) in [(&b"tenant-a"[..], true), (b"tenant-b", false)] {
+            let mut client_config = make_client_config_with_versions(kt, &[version]);
+            client_config.alpn_protocols = vec![b"h2".to_vec()];
+            let client_config = Arc::new(client_config);
 
-        let cases = [
-            (None, true),
-            (Some(0u32), true),
-            (Some(5), false),
-            (Some(0xffff_ffff), true),
-        ];
+            let mut issuing_config = make_server_config(kt);
+            issuing_config.ticketer = Arc::clone(&ticketer);
+            issuing_config.alpn_protocols = vec![b"h2".to_vec()];
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &Arc::new(issuing_config));
+            server.set_resumption_data(b"tenant-a");
+            do_handshake(&mut client, &mut server);
 
-        for &(size, ok) in cases.iter() {
-            println!("early data size case: {:?}", size);
-            if let Some(new) = size {
-                server_config.max_early_data_size = new;
-            }
+            // another server, sharing only the ticket keys, resumes the session
+            let server_log = Arc::new(ResumptionLog::default());
+            let mut server_config = make_server_config(kt);
+            server_config.ticketer = Arc::clone(&ticketer);
+            server_config.alpn_protocols = vec![b"h2".to_vec()];
+            server_config.resumption_validator = Some(Arc::new(TenantValidator(tenant)));
+            server_config.resumption_observer = server_log.clone();
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &Arc::new(server_config));
+            do_handshake(&mut client, &mut server);
 
-            let wrapped = Arc::new(server_config.clone());
-            assert_eq!(
-                quic::ServerConnection::new(wrapped, quic::Version::V1, b"server params".to_vec(),)
-                    .is_ok(),
-                ok
-            );
+            let log = server_log.take();
+            if resumed {
+                assert_eq!(log[0], format!("redeemed {:?}", version.version));
+                assert_eq!(server.received_resumption_data(), Some(&b"tenant-a"[..]));
+            } else {
+                assert_eq!(log[0], format!("rejected {:?} Refused", version.version));
+                assert_eq!(server.received_resumption_data(), None);
+            }
         }
     }
+}
 
-    #[test]
-    fn test_quic_server_no_params_received() {
-        let server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        let server_config = Arc::new(server_config);
+#[derive(Debug, Default)]
+struct AdvancingTimeProvider(std::sync::atomic::AtomicU64);
 
-        let mut server = quic::ServerConnection::new(
-            server_config,
-            quic::Version::V1,
-            b"server params".to_vec(),
-        )
-        .unwrap();
+impl AdvancingTimeProvider {
+    fn advance(&self, secs: u64) {
+        self.0.fetch_add(secs, Ordering::SeqCst);
+    }
+}
 
-        use ring::rand::SecureRandom;
-        use rustls::internal::msgs::base::PayloadU16;
-        use rustls::internal::msgs::enums::{Compression, NamedGroup};
-        use rustls::internal::msgs::handshake::{
-            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionId,
-        };
-        use rustls::{CipherSuite, HandshakeType, SignatureScheme};
+impl rustls::TimeProvider for AdvancingTimeProvider {
+    fn current_time(&self) -> Option<SystemTime> {
+        Some(SystemTime::now() + Duration::from_secs(self.0.load(Ordering::SeqCst)))
+    }
+}
 
-        let rng = ring::rand::SystemRandom::new();
-        let mut random = [0; 32];
-        rng.fill(&mut random).unwrap();
-        let random = Random::from(random);
+#[test]
+fn client_does_not_offer_sessions_older_than_max_age() {
+    let kt = KeyType::Rsa;
+    let time = Arc::new(AdvancingTimeProvider::default());
+    let client_log = Arc::new(ResumptionLog::default());
+    let mut client_config = make_client_config(kt);
+    client_config.time_provider = time.clone();
+    client_config.resumption = Resumption::in_memory_sessions(32)
+        .max_session_age(Duration::from_secs(60))
+        .observer(client_log.clone());
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(kt));
 
-        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .unwrap()
-            .compute_public_key()
-            .unwrap();
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+    }
+    assert!(client_log
+        .take()
+        .contains(&"accepted TLSv1_3".to_string()));
 
-        let client_hello = MessagePayload::handshake(HandshakeMessagePayload {
-            typ: HandshakeType::ClientHello,
+    time.advance(61);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client_log.take()[0], "not attempted Expired");
+}
+
+#[test]
+fn server_fails_handshake_after_deadline() {
+    let kt = KeyType::Rsa;
+    let time = Arc::new(AdvancingTimeProvider::default());
+    let mut server_config = make_server_config(kt);
+    server_config.time_provider = time.clone();
+    server_config.handshake_timeout = Some(Duration::from_secs(10));
+    let (mut client, mut server) = make_pair_for_configs(make_client_config(kt), server_config);
+    assert!(server.handshake_deadline().is_some());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    time.advance(11);
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::HandshakeTimedOut)
+    );
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::HandshakeTimedOut)
+    );
+}
+
+#[test]
+fn client_fails_handshake_after_deadline() {
+    let kt = KeyType::Rsa;
+    let time = Arc::new(AdvancingTimeProvider::default());
+    let mut client_config = make_client_config(kt);
+    client_config.time_provider = time.clone();
+    client_config.handshake_timeout = Some(Duration::from_secs(10));
+    let (mut client, _) = make_pair_for_configs(client_config, make_server_config(kt));
+
+    client.process_new_packets().unwrap();
+    time.advance(11);
+    assert_eq!(
+        client.process_new_packets().err(),
+        Some(Error::HandshakeTimedOut)
+    );
+}
+
+#[test]
+fn handshake_deadline_does_not_apply_after_handshake() {
+    let kt = KeyType::Rsa;
+    let time = Arc::new(AdvancingTimeProvider::default());
+    let mut server_config = make_server_config(kt);
+    server_config.time_provider = time.clone();
+    server_config.handshake_timeout = Some(Duration::from_secs(10));
+    let (mut client, mut server) = make_pair_for_configs(make_client_config(kt), server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.handshake_deadline(), None);
+
+    time.advance(11);
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[test]
+fn client_does_not_offer_sessions_for_unconfigured_alpn_protocol() {
+    let kt = KeyType::Rsa;
+    let mut server_config = make_server_config(kt);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let server_config = Arc::new(server_config);
+
+    for (require_alpn_match, expected) in [
+        (false, "attempted TLSv1_3"),
+        (true, "not attempted AlpnMismatch"),
+    ] {
+        let client_log = Arc::new(ResumptionLog::default());
+        let mut client_config = make_client_config(kt);
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+        client_config.resumption = Resumption::in_memory_sessions(32)
+            .require_alpn_match(require_alpn_match)
+            .observer(client_log.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config.clone()), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.alpn_protocol(), Some(&b"h2"[..]));
+        client_log.take();
+
+        // The sessions are shared with this config, which no longer offers h2.
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client_log.take()[0], expected);
+        assert_eq!(client.alpn_protocol(), Some(&b"http/1.1"[..]));
+    }
+}
+
+#[test]
+fn early_data_not_available() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(client.early_data().is_none());
+}
+
+fn early_data_configs() -> (Arc<ClientConfig<Ring>>, Arc<ServerConfig<Ring>>) {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config(kt);
+    client_config.enable_early_data = true;
+    client_config.resumption = Resumption::store(Arc::new(ClientStorage::new()));
+
+    let mut server_config = make_server_config(kt);
+    server_config.max_early_data_size = 1234;
+    (Arc::new(client_config), Arc::new(server_config))
+}
+
+#[test]
+fn early_data_is_available_on_resumption() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(client.early_data().is_some());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1234
+    );
+    client
+        .early_data()
+        .unwrap()
+        .flush()
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    do_handshake(&mut client, &mut server);
+
+    let mut received_early_data = [0u8; 5];
+    assert_eq!(
+        server
+            .early_data()
+            .expect("early_data didn't happen")
+            .read(&mut received_early_data)
+            .expect("early_data failed unexpectedly"),
+        5
+    );
+    assert_eq!(&received_early_data[..], b"hello");
+}
+
+#[test]
+fn early_data_not_available_on_server_before_client_hello() {
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+    assert!(server.early_data().is_none());
+}
+
+#[test]
+fn early_data_can_be_rejected_by_server() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(client.early_data().is_some());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1234
+    );
+    client
+        .early_data()
+        .unwrap()
+        .flush()
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    server.reject_early_data();
+    do_handshake(&mut client, &mut server);
+
+    assert!(!client.is_early_data_accepted());
+}
+
+/// Treats complete `GET` lines as safe to process early.
+struct GetLinesAreSafe;
+
+impl rustls::server::GatesEarlyData for GetLinesAreSafe {
+    fn classify(&self, data: &[u8]) -> rustls::server::EarlyDataClass {
+        use rustls::server::EarlyDataClass;
+        match data.iter().position(|b| *b == b'\n') {
+            Some(end) if data.starts_with(b"GET ") => EarlyDataClass::Safe(end + 1),
+            Some(_) => EarlyDataClass::Unsafe,
+            None => EarlyDataClass::Safe(0),
+        }
+    }
+}
+
+#[test]
+fn early_data_can_be_gated() {
+    let (client_config, server_config) = early_data_configs();
+    let mut server_config = (*server_config).clone();
+    server_config.early_data_gate = Some(Arc::new(GetLinesAreSafe));
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    client
+        .early_data()
+        .unwrap()
+        .write_all(b"GET /a\nGET /b\nPOST /c\nGET /d\nGET")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    assert!(server.is_handshaking());
+    let mut early = Vec::new();
+    server
+        .early_data()
+        .unwrap()
+        .read_to_end(&mut early)
+        .unwrap();
+    assert_eq!(early, b"GET /a\nGET /b\n");
+    check_read_err(&mut server.reader(), io::ErrorKind::WouldBlock);
+
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+    check_read(&mut server.reader(), b"POST /c\nGET /d\nGET");
+}
+
+#[test]
+fn early_data_gate_waits_for_complete_requests() {
+    let (client_config, server_config) = early_data_configs();
+    let mut server_config = (*server_config).clone();
+    server_config.early_data_gate = Some(Arc::new(GetLinesAreSafe));
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    client
+        .early_data()
+        .unwrap()
+        .write_all(b"GET /a")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(
+        server
+            .early_data()
+            .unwrap()
+            .read(&mut [0u8; 8])
+            .unwrap(),
+        0
+    );
+
+    client
+        .early_data()
+        .unwrap()
+        .write_all(b"\n")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.early_data().unwrap(), b"GET /a\n");
+
+    do_handshake(&mut client, &mut server);
+    check_read_err(&mut server.reader(), io::ErrorKind::WouldBlock);
+}
+
+#[cfg(feature = "quic")]
+mod test_quic {
+    use super::*;
+    use rustls::quic::{self, ConnectionCommon};
+
+    // Returns the sender's next secrets to use, or the receiver's error.
+    fn step<L: SideData, R: SideData>(
+        send: &mut ConnectionCommon<L>,
+        recv: &mut ConnectionCommon<R>,
+    ) -> Result<Option<quic::KeyChange>, Error> {
+        let mut buf = Vec::new();
+        let change = loop {
+            let prev = buf.len();
+            if let Some(x) = send.write_hs(&mut buf) {
+                break Some(x);
+            }
+            if prev == buf.len() {
+                break None;
+            }
+        };
+        if let Err(e) = recv.read_hs(&buf) {
+            return Err(e);
+        } else {
+            assert_eq!(recv.alert(), None);
+        }
+
+        Ok(change)
+    }
+
+    #[test]
+    fn test_quic_handshake() {
+        fn equal_packet_keys(x: &quic::PacketKey, y: &quic::PacketKey) -> bool {
+            // Check that these two sets of keys are equal.
+            let mut buf = vec![0; 32];
+            let (header, payload_tag) = buf.split_at_mut(8);
+            let (payload, tag_buf) = payload_tag.split_at_mut(8);
+            let tag = x
+                .encrypt_in_place(42, &*header, payload)
+                .unwrap();
+            tag_buf.copy_from_slice(tag.as_ref());
+
+            let result = y.decrypt_in_place(42, &*header, payload_tag);
+            match result {
+                Ok(payload) => payload == &[0; 8],
+                Err(_) => false,
+            }
+        }
+
+        fn compatible_keys(x: &quic::KeyChange, y: &quic::KeyChange) -> bool {
+            fn keys(kc: &quic::KeyChange) -> &quic::Keys {
+                match kc {
+                    quic::KeyChange::Handshake { keys } => keys,
+                    quic::KeyChange::OneRtt { keys, .. } => keys,
+                }
+            }
+
+            let (x, y) = (keys(x), keys(y));
+            equal_packet_keys(&x.local.packet, &y.remote.packet)
+                && equal_packet_keys(&x.remote.packet, &y.local.packet)
+        }
+
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+        client_config.enable_early_data = true;
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+        server_config.max_early_data_size = 0xffffffff;
+        let server_config = Arc::new(server_config);
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        // full handshake
+        let mut client = quic::ClientConnection::new(
+            Arc::clone(&client_config),
+            quic::Version::V1,
+            server_name("localhost"),
+            client_params.into(),
+        )
+        .unwrap();
+
+        let mut server = quic::ServerConnection::new(
+            Arc::clone(&server_config),
+            quic::Version::V1,
+            server_params.into(),
+        )
+        .unwrap();
+
+        let client_initial = step(&mut client, &mut server).unwrap();
+        assert!(client_initial.is_none());
+        assert!(client.zero_rtt_keys().is_none());
+        assert_eq!(server.quic_transport_parameters(), Some(client_params));
+        let server_hs = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(server.zero_rtt_keys().is_none());
+        let client_hs = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(compatible_keys(&server_hs, &client_hs));
+        assert!(client.is_handshaking());
+        let server_1rtt = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(!client.is_handshaking());
+        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        assert!(server.is_handshaking());
+        let client_1rtt = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(!server.is_handshaking());
+        assert!(compatible_keys(&server_1rtt, &client_1rtt));
+        assert!(!compatible_keys(&server_hs, &server_1rtt));
+        assert!(step(&mut client, &mut server)
+            .unwrap()
+            .is_none());
+        assert!(step(&mut server, &mut client)
+            .unwrap()
+            .is_none());
+
+        // 0-RTT handshake
+        let mut client = quic::ClientConnection::new(
+            Arc::clone(&client_config),
+            quic::Version::V1,
+            server_name("localhost"),
+            client_params.into(),
+        )
+        .unwrap();
+        assert!(client
+            .negotiated_cipher_suite()
+            .is_some());
+
+        let mut server = quic::ServerConnection::new(
+            Arc::clone(&server_config),
+            quic::Version::V1,
+            server_params.into(),
+        )
+        .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        {
+            let client_early = client.zero_rtt_keys().unwrap();
+            let server_early = server.zero_rtt_keys().unwrap();
+            assert!(equal_packet_keys(
+                &client_early.packet,
+                &server_early.packet
+            ));
+        }
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(client.is_early_data_accepted());
+
+        // 0-RTT rejection
+        {
+            let client_config = (*client_config).clone();
+            let mut client = quic::ClientConnection::new(
+                Arc::new(client_config),
+                quic::Version::V1,
+                server_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+
+            let mut server = quic::ServerConnection::new(
+                Arc::clone(&server_config),
+                quic::Version::V1,
+                server_params.into(),
+            )
+            .unwrap();
+            server.reject_early_data();
+
+            step(&mut client, &mut server).unwrap();
+            assert_eq!(client.quic_transport_parameters(), Some(server_params));
+            assert!(client.zero_rtt_keys().is_some());
+            assert!(server.zero_rtt_keys().is_none());
+            step(&mut server, &mut client)
+                .unwrap()
+                .unwrap();
+            step(&mut client, &mut server)
+                .unwrap()
+                .unwrap();
+            step(&mut server, &mut client)
+                .unwrap()
+                .unwrap();
+            assert!(!client.is_early_data_accepted());
+        }
+
+        // failed handshake
+        let mut client = quic::ClientConnection::new(
+            client_config,
+            quic::Version::V1,
+            server_name("example.com"),
+            client_params.into(),
+        )
+        .unwrap();
+
+        let mut server =
+            quic::ServerConnection::new(server_config, quic::Version::V1, server_params.into())
+                .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(step(&mut server, &mut client).is_err());
+        assert_eq!(
+            client.alert(),
+            Some(rustls::AlertDescription::BadCertificate)
+        );
+
+        // Key updates
+
+        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
+            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
+                (c, s)
+            }
+            _ => unreachable!(),
+        };
+
+        let mut client_next = client_secrets.next_packet_keys();
+        let mut server_next = server_secrets.next_packet_keys();
+        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+
+        client_next = client_secrets.next_packet_keys();
+        server_next = server_secrets.next_packet_keys();
+        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+    }
+
+    #[test]
+    fn test_quic_rejects_missing_alpn() {
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        for &kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+            let client_config = Arc::new(client_config);
+
+            let mut server_config =
+                make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+            server_config.alpn_protocols = vec!["foo".into()];
+            let server_config = Arc::new(server_config);
+
+            let mut client = quic::ClientConnection::new(
+                client_config,
+                quic::Version::V1,
+                server_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+            let mut server =
+                quic::ServerConnection::new(server_config, quic::Version::V1, server_params.into())
+                    .unwrap();
+
+            assert_eq!(
+                step(&mut client, &mut server)
+                    .err()
+                    .unwrap(),
+                Error::NoApplicationProtocol
+            );
+
+            assert_eq!(
+                server.alert(),
+                Some(rustls::AlertDescription::NoApplicationProtocol)
+            );
+        }
+    }
+
+    #[cfg(feature = "tls12")]
+    #[test]
+    fn test_quic_no_tls13_error() {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
+        client_config.alpn_protocols = vec!["foo".into()];
+        let client_config = Arc::new(client_config);
+
+        assert!(quic::ClientConnection::new(
+            client_config,
+            quic::Version::V1,
+            server_name("localhost"),
+            b"client params".to_vec(),
+        )
+        .is_err());
+
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+
+        assert!(quic::ServerConnection::new(
+            server_config,
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_quic_invalid_early_data_size() {
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        server_config.alpn_protocols = vec!["foo".into()];
+
+        let cases = [
+            (None, true),
+            (Some(0u32), true),
+            (Some(5), false),
+            (Some(0xffff_ffff), true),
+        ];
+
+        for &(size, ok) in cases.iter() {
+            println!("early data size case: {:?}", size);
+            if let Some(new) = size {
+                server_config.max_early_data_size = new;
+            }
+
+            let wrapped = Arc::new(server_config.clone());
+            assert_eq!(
+                quic::ServerConnection::new(wrapped, quic::Version::V1, b"server params".to_vec(),)
+                    .is_ok(),
+                ok
+            );
+        }
+    }
+
+    #[test]
+    fn test_quic_server_no_params_received() {
+        let server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        let server_config = Arc::new(server_config);
+
+        let mut server = quic::ServerConnection::new(
+            server_config,
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .unwrap();
+
+        use ring::rand::SecureRandom;
+        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::enums::{Compression, NamedGroup};
+        use rustls::internal::msgs::handshake::{
+            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionId,
+        };
+        use rustls::{CipherSuite, HandshakeType, SignatureScheme};
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut random = [0; 32];
+        rng.fill(&mut random).unwrap();
+        let random = Random::from(random);
+
+        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+            .unwrap()
+            .compute_public_key()
+            .unwrap();
+
+        let client_hello = MessagePayload::handshake(HandshakeMessagePayload {
+            typ: HandshakeType::ClientHello,
             payload: HandshakePayload::ClientHello(ClientHelloPayload {
                 client_version: ProtocolVersion::TLSv1_3,
                 random,
@@ -3592,1290 +5742,3415 @@ mod test_quic {
             }),
         });
 
-        let mut buf = Vec::with_capacity(512);
-        client_hello.encode(&mut buf);
-        assert_eq!(
-            server
-                .read_hs(&mut buf.as_slice())
-                .err(),
-            Some(Error::PeerMisbehaved(
-                PeerMisbehaved::MissingQuicTransportParameters
-            ))
-        );
+        let mut buf = Vec::with_capacity(512);
+        client_hello.encode(&mut buf);
+        assert_eq!(
+            server
+                .read_hs(&mut buf.as_slice())
+                .err(),
+            Some(Error::PeerMisbehaved(
+                PeerMisbehaved::MissingQuicTransportParameters
+            ))
+        );
+    }
+
+    #[test]
+    fn test_quic_server_no_tls12() {
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+
+        use ring::rand::SecureRandom;
+        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::enums::{Compression, NamedGroup};
+        use rustls::internal::msgs::handshake::{
+            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionId,
+        };
+        use rustls::{CipherSuite, HandshakeType, SignatureScheme};
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut random = [0; 32];
+        rng.fill(&mut random).unwrap();
+        let random = Random::from(random);
+
+        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+            .unwrap()
+            .compute_public_key()
+            .unwrap();
+
+        let mut server = quic::ServerConnection::new(
+            server_config,
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .unwrap();
+
+        let client_hello = MessagePayload::handshake(HandshakeMessagePayload {
+            typ: HandshakeType::ClientHello,
+            payload: HandshakePayload::ClientHello(ClientHelloPayload {
+                client_version: ProtocolVersion::TLSv1_2,
+                random: random.clone(),
+                session_id: SessionId::random::<Ring>().unwrap(),
+                cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
+                compression_methods: vec![Compression::Null],
+                extensions: vec![
+                    ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
+                    ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
+                    ClientExtension::KeyShare(vec![KeyShareEntry {
+                        group: NamedGroup::X25519,
+                        payload: PayloadU16::new(kx.as_ref().to_vec()),
+                    }]),
+                ],
+            }),
+        });
+
+        let mut buf = Vec::with_capacity(512);
+        client_hello.encode(&mut buf);
+        assert_eq!(
+            server
+                .read_hs(&mut buf.as_slice())
+                .err(),
+            Some(Error::PeerIncompatible(
+                PeerIncompatible::SupportedVersionsExtensionRequired
+            )),
+        );
+    }
+
+    #[test]
+    fn packet_key_api() {
+        use rustls::quic::{Keys, Version};
+        use rustls::Side;
+
+        // Test vectors: https://www.rfc-editor.org/rfc/rfc9001.html#name-client-initial
+        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        const PACKET_NUMBER: u64 = 2;
+        const PLAIN_HEADER: &[u8] = &[
+            0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
+            0x00, 0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
+        ];
+
+        const PAYLOAD: &[u8] = &[
+            0x06, 0x00, 0x40, 0xf1, 0x01, 0x00, 0x00, 0xed, 0x03, 0x03, 0xeb, 0xf8, 0xfa, 0x56,
+            0xf1, 0x29, 0x39, 0xb9, 0x58, 0x4a, 0x38, 0x96, 0x47, 0x2e, 0xc4, 0x0b, 0xb8, 0x63,
+            0xcf, 0xd3, 0xe8, 0x68, 0x04, 0xfe, 0x3a, 0x47, 0xf0, 0x6a, 0x2b, 0x69, 0x48, 0x4c,
+            0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00,
+            0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+            0x63, 0x6f, 0x6d, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x06,
+            0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x10, 0x00, 0x07, 0x00, 0x05, 0x04, 0x61,
+            0x6c, 0x70, 0x6e, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33,
+            0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x93, 0x70, 0xb2, 0xc9, 0xca, 0xa4,
+            0x7f, 0xba, 0xba, 0xf4, 0x55, 0x9f, 0xed, 0xba, 0x75, 0x3d, 0xe1, 0x71, 0xfa, 0x71,
+            0xf5, 0x0f, 0x1c, 0xe1, 0x5d, 0x43, 0xe9, 0x94, 0xec, 0x74, 0xd7, 0x48, 0x00, 0x2b,
+            0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x10, 0x00, 0x0e, 0x04, 0x03, 0x05,
+            0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x00, 0x2d, 0x00,
+            0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01, 0x00, 0x39, 0x00, 0x32, 0x04,
+            0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x05, 0x04, 0x80, 0x00, 0xff,
+            0xff, 0x07, 0x04, 0x80, 0x00, 0xff, 0xff, 0x08, 0x01, 0x10, 0x01, 0x04, 0x80, 0x00,
+            0x75, 0x30, 0x09, 0x01, 0x10, 0x0f, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57,
+            0x08, 0x06, 0x04, 0x80, 0x00, 0xff, 0xff,
+        ];
+
+        let client_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Client);
+        assert_eq!(
+            client_keys
+                .local
+                .packet
+                .confidentiality_limit(),
+            2u64.pow(23)
+        );
+        assert_eq!(
+            client_keys
+                .local
+                .packet
+                .integrity_limit(),
+            2u64.pow(52)
+        );
+        assert_eq!(client_keys.local.packet.tag_len(), 16);
+
+        let mut buf = Vec::new();
+        buf.extend(PLAIN_HEADER);
+        buf.extend(PAYLOAD);
+        let header_len = PLAIN_HEADER.len();
+        let tag_len = client_keys.local.packet.tag_len();
+        let padding_len = 1200 - header_len - PAYLOAD.len() - tag_len;
+        buf.extend(std::iter::repeat(0).take(padding_len));
+        let (header, payload) = buf.split_at_mut(header_len);
+        let tag = client_keys
+            .local
+            .packet
+            .encrypt_in_place(PACKET_NUMBER, &*header, payload)
+            .unwrap();
+
+        let sample_len = client_keys.local.header.sample_len();
+        let sample = &payload[..sample_len];
+        let (first, rest) = header.split_at_mut(1);
+        client_keys
+            .local
+            .header
+            .encrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+            .unwrap();
+        buf.extend_from_slice(tag.as_ref());
+
+        const PROTECTED: &[u8] = &[
+            0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
+            0x00, 0x00, 0x44, 0x9e, 0x7b, 0x9a, 0xec, 0x34, 0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68,
+            0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc, 0x9b, 0xd8, 0xba, 0xb9, 0x36,
+            0xb4, 0x7d, 0x92, 0xec, 0x35, 0x6c, 0x0b, 0xab, 0x7d, 0xf5, 0x97, 0x6d, 0x27, 0xcd,
+            0x44, 0x9f, 0x63, 0x30, 0x00, 0x99, 0xf3, 0x99, 0x1c, 0x26, 0x0e, 0xc4, 0xc6, 0x0d,
+            0x17, 0xb3, 0x1f, 0x84, 0x29, 0x15, 0x7b, 0xb3, 0x5a, 0x12, 0x82, 0xa6, 0x43, 0xa8,
+            0xd2, 0x26, 0x2c, 0xad, 0x67, 0x50, 0x0c, 0xad, 0xb8, 0xe7, 0x37, 0x8c, 0x8e, 0xb7,
+            0x53, 0x9e, 0xc4, 0xd4, 0x90, 0x5f, 0xed, 0x1b, 0xee, 0x1f, 0xc8, 0xaa, 0xfb, 0xa1,
+            0x7c, 0x75, 0x0e, 0x2c, 0x7a, 0xce, 0x01, 0xe6, 0x00, 0x5f, 0x80, 0xfc, 0xb7, 0xdf,
+            0x62, 0x12, 0x30, 0xc8, 0x37, 0x11, 0xb3, 0x93, 0x43, 0xfa, 0x02, 0x8c, 0xea, 0x7f,
+            0x7f, 0xb5, 0xff, 0x89, 0xea, 0xc2, 0x30, 0x82, 0x49, 0xa0, 0x22, 0x52, 0x15, 0x5e,
+            0x23, 0x47, 0xb6, 0x3d, 0x58, 0xc5, 0x45, 0x7a, 0xfd, 0x84, 0xd0, 0x5d, 0xff, 0xfd,
+            0xb2, 0x03, 0x92, 0x84, 0x4a, 0xe8, 0x12, 0x15, 0x46, 0x82, 0xe9, 0xcf, 0x01, 0x2f,
+            0x90, 0x21, 0xa6, 0xf0, 0xbe, 0x17, 0xdd, 0xd0, 0xc2, 0x08, 0x4d, 0xce, 0x25, 0xff,
+            0x9b, 0x06, 0xcd, 0xe5, 0x35, 0xd0, 0xf9, 0x20, 0xa2, 0xdb, 0x1b, 0xf3, 0x62, 0xc2,
+            0x3e, 0x59, 0x6d, 0x11, 0xa4, 0xf5, 0xa6, 0xcf, 0x39, 0x48, 0x83, 0x8a, 0x3a, 0xec,
+            0x4e, 0x15, 0xda, 0xf8, 0x50, 0x0a, 0x6e, 0xf6, 0x9e, 0xc4, 0xe3, 0xfe, 0xb6, 0xb1,
+            0xd9, 0x8e, 0x61, 0x0a, 0xc8, 0xb7, 0xec, 0x3f, 0xaf, 0x6a, 0xd7, 0x60, 0xb7, 0xba,
+            0xd1, 0xdb, 0x4b, 0xa3, 0x48, 0x5e, 0x8a, 0x94, 0xdc, 0x25, 0x0a, 0xe3, 0xfd, 0xb4,
+            0x1e, 0xd1, 0x5f, 0xb6, 0xa8, 0xe5, 0xeb, 0xa0, 0xfc, 0x3d, 0xd6, 0x0b, 0xc8, 0xe3,
+            0x0c, 0x5c, 0x42, 0x87, 0xe5, 0x38, 0x05, 0xdb, 0x05, 0x9a, 0xe0, 0x64, 0x8d, 0xb2,
+            0xf6, 0x42, 0x64, 0xed, 0x5e, 0x39, 0xbe, 0x2e, 0x20, 0xd8, 0x2d, 0xf5, 0x66, 0xda,
+            0x8d, 0xd5, 0x99, 0x8c, 0xca, 0xbd, 0xae, 0x05, 0x30, 0x60, 0xae, 0x6c, 0x7b, 0x43,
+            0x78, 0xe8, 0x46, 0xd2, 0x9f, 0x37, 0xed, 0x7b, 0x4e, 0xa9, 0xec, 0x5d, 0x82, 0xe7,
+            0x96, 0x1b, 0x7f, 0x25, 0xa9, 0x32, 0x38, 0x51, 0xf6, 0x81, 0xd5, 0x82, 0x36, 0x3a,
+            0xa5, 0xf8, 0x99, 0x37, 0xf5, 0xa6, 0x72, 0x58, 0xbf, 0x63, 0xad, 0x6f, 0x1a, 0x0b,
+            0x1d, 0x96, 0xdb, 0xd4, 0xfa, 0xdd, 0xfc, 0xef, 0xc5, 0x26, 0x6b, 0xa6, 0x61, 0x17,
+            0x22, 0x39, 0x5c, 0x90, 0x65, 0x56, 0xbe, 0x52, 0xaf, 0xe3, 0xf5, 0x65, 0x63, 0x6a,
+            0xd1, 0xb1, 0x7d, 0x50, 0x8b, 0x73, 0xd8, 0x74, 0x3e, 0xeb, 0x52, 0x4b, 0xe2, 0x2b,
+            0x3d, 0xcb, 0xc2, 0xc7, 0x46, 0x8d, 0x54, 0x11, 0x9c, 0x74, 0x68, 0x44, 0x9a, 0x13,
+            0xd8, 0xe3, 0xb9, 0x58, 0x11, 0xa1, 0x98, 0xf3, 0x49, 0x1d, 0xe3, 0xe7, 0xfe, 0x94,
+            0x2b, 0x33, 0x04, 0x07, 0xab, 0xf8, 0x2a, 0x4e, 0xd7, 0xc1, 0xb3, 0x11, 0x66, 0x3a,
+            0xc6, 0x98, 0x90, 0xf4, 0x15, 0x70, 0x15, 0x85, 0x3d, 0x91, 0xe9, 0x23, 0x03, 0x7c,
+            0x22, 0x7a, 0x33, 0xcd, 0xd5, 0xec, 0x28, 0x1c, 0xa3, 0xf7, 0x9c, 0x44, 0x54, 0x6b,
+            0x9d, 0x90, 0xca, 0x00, 0xf0, 0x64, 0xc9, 0x9e, 0x3d, 0xd9, 0x79, 0x11, 0xd3, 0x9f,
+            0xe9, 0xc5, 0xd0, 0xb2, 0x3a, 0x22, 0x9a, 0x23, 0x4c, 0xb3, 0x61, 0x86, 0xc4, 0x81,
+            0x9e, 0x8b, 0x9c, 0x59, 0x27, 0x72, 0x66, 0x32, 0x29, 0x1d, 0x6a, 0x41, 0x82, 0x11,
+            0xcc, 0x29, 0x62, 0xe2, 0x0f, 0xe4, 0x7f, 0xeb, 0x3e, 0xdf, 0x33, 0x0f, 0x2c, 0x60,
+            0x3a, 0x9d, 0x48, 0xc0, 0xfc, 0xb5, 0x69, 0x9d, 0xbf, 0xe5, 0x89, 0x64, 0x25, 0xc5,
+            0xba, 0xc4, 0xae, 0xe8, 0x2e, 0x57, 0xa8, 0x5a, 0xaf, 0x4e, 0x25, 0x13, 0xe4, 0xf0,
+            0x57, 0x96, 0xb0, 0x7b, 0xa2, 0xee, 0x47, 0xd8, 0x05, 0x06, 0xf8, 0xd2, 0xc2, 0x5e,
+            0x50, 0xfd, 0x14, 0xde, 0x71, 0xe6, 0xc4, 0x18, 0x55, 0x93, 0x02, 0xf9, 0x39, 0xb0,
+            0xe1, 0xab, 0xd5, 0x76, 0xf2, 0x79, 0xc4, 0xb2, 0xe0, 0xfe, 0xb8, 0x5c, 0x1f, 0x28,
+            0xff, 0x18, 0xf5, 0x88, 0x91, 0xff, 0xef, 0x13, 0x2e, 0xef, 0x2f, 0xa0, 0x93, 0x46,
+            0xae, 0xe3, 0x3c, 0x28, 0xeb, 0x13, 0x0f, 0xf2, 0x8f, 0x5b, 0x76, 0x69, 0x53, 0x33,
+            0x41, 0x13, 0x21, 0x19, 0x96, 0xd2, 0x00, 0x11, 0xa1, 0x98, 0xe3, 0xfc, 0x43, 0x3f,
+            0x9f, 0x25, 0x41, 0x01, 0x0a, 0xe1, 0x7c, 0x1b, 0xf2, 0x02, 0x58, 0x0f, 0x60, 0x47,
+            0x47, 0x2f, 0xb3, 0x68, 0x57, 0xfe, 0x84, 0x3b, 0x19, 0xf5, 0x98, 0x40, 0x09, 0xdd,
+            0xc3, 0x24, 0x04, 0x4e, 0x84, 0x7a, 0x4f, 0x4a, 0x0a, 0xb3, 0x4f, 0x71, 0x95, 0x95,
+            0xde, 0x37, 0x25, 0x2d, 0x62, 0x35, 0x36, 0x5e, 0x9b, 0x84, 0x39, 0x2b, 0x06, 0x10,
+            0x85, 0x34, 0x9d, 0x73, 0x20, 0x3a, 0x4a, 0x13, 0xe9, 0x6f, 0x54, 0x32, 0xec, 0x0f,
+            0xd4, 0xa1, 0xee, 0x65, 0xac, 0xcd, 0xd5, 0xe3, 0x90, 0x4d, 0xf5, 0x4c, 0x1d, 0xa5,
+            0x10, 0xb0, 0xff, 0x20, 0xdc, 0xc0, 0xc7, 0x7f, 0xcb, 0x2c, 0x0e, 0x0e, 0xb6, 0x05,
+            0xcb, 0x05, 0x04, 0xdb, 0x87, 0x63, 0x2c, 0xf3, 0xd8, 0xb4, 0xda, 0xe6, 0xe7, 0x05,
+            0x76, 0x9d, 0x1d, 0xe3, 0x54, 0x27, 0x01, 0x23, 0xcb, 0x11, 0x45, 0x0e, 0xfc, 0x60,
+            0xac, 0x47, 0x68, 0x3d, 0x7b, 0x8d, 0x0f, 0x81, 0x13, 0x65, 0x56, 0x5f, 0xd9, 0x8c,
+            0x4c, 0x8e, 0xb9, 0x36, 0xbc, 0xab, 0x8d, 0x06, 0x9f, 0xc3, 0x3b, 0xd8, 0x01, 0xb0,
+            0x3a, 0xde, 0xa2, 0xe1, 0xfb, 0xc5, 0xaa, 0x46, 0x3d, 0x08, 0xca, 0x19, 0x89, 0x6d,
+            0x2b, 0xf5, 0x9a, 0x07, 0x1b, 0x85, 0x1e, 0x6c, 0x23, 0x90, 0x52, 0x17, 0x2f, 0x29,
+            0x6b, 0xfb, 0x5e, 0x72, 0x40, 0x47, 0x90, 0xa2, 0x18, 0x10, 0x14, 0xf3, 0xb9, 0x4a,
+            0x4e, 0x97, 0xd1, 0x17, 0xb4, 0x38, 0x13, 0x03, 0x68, 0xcc, 0x39, 0xdb, 0xb2, 0xd1,
+            0x98, 0x06, 0x5a, 0xe3, 0x98, 0x65, 0x47, 0x92, 0x6c, 0xd2, 0x16, 0x2f, 0x40, 0xa2,
+            0x9f, 0x0c, 0x3c, 0x87, 0x45, 0xc0, 0xf5, 0x0f, 0xba, 0x38, 0x52, 0xe5, 0x66, 0xd4,
+            0x45, 0x75, 0xc2, 0x9d, 0x39, 0xa0, 0x3f, 0x0c, 0xda, 0x72, 0x19, 0x84, 0xb6, 0xf4,
+            0x40, 0x59, 0x1f, 0x35, 0x5e, 0x12, 0xd4, 0x39, 0xff, 0x15, 0x0a, 0xab, 0x76, 0x13,
+            0x49, 0x9d, 0xbd, 0x49, 0xad, 0xab, 0xc8, 0x67, 0x6e, 0xef, 0x02, 0x3b, 0x15, 0xb6,
+            0x5b, 0xfc, 0x5c, 0xa0, 0x69, 0x48, 0x10, 0x9f, 0x23, 0xf3, 0x50, 0xdb, 0x82, 0x12,
+            0x35, 0x35, 0xeb, 0x8a, 0x74, 0x33, 0xbd, 0xab, 0xcb, 0x90, 0x92, 0x71, 0xa6, 0xec,
+            0xbc, 0xb5, 0x8b, 0x93, 0x6a, 0x88, 0xcd, 0x4e, 0x8f, 0x2e, 0x6f, 0xf5, 0x80, 0x01,
+            0x75, 0xf1, 0x13, 0x25, 0x3d, 0x8f, 0xa9, 0xca, 0x88, 0x85, 0xc2, 0xf5, 0x52, 0xe6,
+            0x57, 0xdc, 0x60, 0x3f, 0x25, 0x2e, 0x1a, 0x8e, 0x30, 0x8f, 0x76, 0xf0, 0xbe, 0x79,
+            0xe2, 0xfb, 0x8f, 0x5d, 0x5f, 0xbb, 0xe2, 0xe3, 0x0e, 0xca, 0xdd, 0x22, 0x07, 0x23,
+            0xc8, 0xc0, 0xae, 0xa8, 0x07, 0x8c, 0xdf, 0xcb, 0x38, 0x68, 0x26, 0x3f, 0xf8, 0xf0,
+            0x94, 0x00, 0x54, 0xda, 0x48, 0x78, 0x18, 0x93, 0xa7, 0xe4, 0x9a, 0xd5, 0xaf, 0xf4,
+            0xaf, 0x30, 0x0c, 0xd8, 0x04, 0xa6, 0xb6, 0x27, 0x9a, 0xb3, 0xff, 0x3a, 0xfb, 0x64,
+            0x49, 0x1c, 0x85, 0x19, 0x4a, 0xab, 0x76, 0x0d, 0x58, 0xa6, 0x06, 0x65, 0x4f, 0x9f,
+            0x44, 0x00, 0xe8, 0xb3, 0x85, 0x91, 0x35, 0x6f, 0xbf, 0x64, 0x25, 0xac, 0xa2, 0x6d,
+            0xc8, 0x52, 0x44, 0x25, 0x9f, 0xf2, 0xb1, 0x9c, 0x41, 0xb9, 0xf9, 0x6f, 0x3c, 0xa9,
+            0xec, 0x1d, 0xde, 0x43, 0x4d, 0xa7, 0xd2, 0xd3, 0x92, 0xb9, 0x05, 0xdd, 0xf3, 0xd1,
+            0xf9, 0xaf, 0x93, 0xd1, 0xaf, 0x59, 0x50, 0xbd, 0x49, 0x3f, 0x5a, 0xa7, 0x31, 0xb4,
+            0x05, 0x6d, 0xf3, 0x1b, 0xd2, 0x67, 0xb6, 0xb9, 0x0a, 0x07, 0x98, 0x31, 0xaa, 0xf5,
+            0x79, 0xbe, 0x0a, 0x39, 0x01, 0x31, 0x37, 0xaa, 0xc6, 0xd4, 0x04, 0xf5, 0x18, 0xcf,
+            0xd4, 0x68, 0x40, 0x64, 0x7e, 0x78, 0xbf, 0xe7, 0x06, 0xca, 0x4c, 0xf5, 0xe9, 0xc5,
+            0x45, 0x3e, 0x9f, 0x7c, 0xfd, 0x2b, 0x8b, 0x4c, 0x8d, 0x16, 0x9a, 0x44, 0xe5, 0x5c,
+            0x88, 0xd4, 0xa9, 0xa7, 0xf9, 0x47, 0x42, 0x41, 0xe2, 0x21, 0xaf, 0x44, 0x86, 0x00,
+            0x18, 0xab, 0x08, 0x56, 0x97, 0x2e, 0x19, 0x4c, 0xd9, 0x34,
+        ];
+
+        assert_eq!(&buf, PROTECTED);
+
+        let (header, payload) = buf.split_at_mut(header_len);
+        let (first, rest) = header.split_at_mut(1);
+        let sample = &payload[..sample_len];
+
+        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Server);
+        server_keys
+            .remote
+            .header
+            .decrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+            .unwrap();
+        let payload = server_keys
+            .remote
+            .packet
+            .decrypt_in_place(PACKET_NUMBER, &*header, payload)
+            .unwrap();
+
+        assert_eq!(&payload[..PAYLOAD.len()], PAYLOAD);
+        assert_eq!(payload.len(), buf.len() - header_len - tag_len);
+    }
+
+    #[test]
+    fn test_quic_exporter() {
+        for &kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+            let server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+
+            do_exporter_test(client_config, server_config);
+        }
+    }
+} // mod test_quic
+
+#[test]
+fn test_client_does_not_offer_sha1() {
+    use rustls::internal::msgs::{
+        codec::Reader, handshake::HandshakePayload, message::MessagePayload, message::OpaqueMessage,
+    };
+    use rustls::HandshakeType;
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, _) = make_pair_for_configs(client_config, make_server_config(*kt));
+
+            assert!(client.wants_write());
+            let mut buf = [0u8; 262144];
+            let sz = client
+                .write_tls(&mut buf.as_mut())
+                .unwrap();
+            let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
+            let msg = Message::try_from(msg.into_plain_message()).unwrap();
+            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+
+            let client_hello = match msg.payload {
+                MessagePayload::Handshake { parsed, .. } => match parsed.payload {
+                    HandshakePayload::ClientHello(ch) => ch,
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            };
+
+            let sigalgs = client_hello
+                .get_sigalgs_extension()
+                .unwrap();
+            assert!(
+                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
+                "sha1 unexpectedly offered"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_client_config_keyshare() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn test_client_config_keyshare_mismatch() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+#[derive(Default)]
+struct RecordHelloRetries {
+    requested: Mutex<Vec<rustls::NamedGroup>>,
+}
+
+impl rustls::server::LimitsHelloRetries for RecordHelloRetries {
+    fn permit(&self, _server_name: Option<&str>, group: rustls::NamedGroup) -> bool {
+        self.requested
+            .lock()
+            .unwrap()
+            .push(group);
+        true
+    }
+}
+
+fn hello_retries_for(
+    client_groups: &[&'static rustls::SupportedKxGroup],
+    server_groups: &[&'static rustls::SupportedKxGroup],
+    policy: rustls::server::HelloRetryPolicy,
+) -> Vec<rustls::NamedGroup> {
+    let client_config = make_client_config_with_kx_groups(KeyType::Rsa, client_groups);
+    let mut server_config = make_server_config_with_kx_groups(KeyType::Rsa, server_groups);
+    let recorder = Arc::new(RecordHelloRetries::default());
+    server_config.hello_retry = policy;
+    server_config.hello_retry_limit = Some(recorder.clone());
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+    let requested = recorder
+        .requested
+        .lock()
+        .unwrap()
+        .clone();
+    requested
+}
+
+#[test]
+fn test_server_accepts_any_key_share_by_default() {
+    // the client only sends a secp384r1 key share, which the server accepts
+    // even though it prefers x25519
+    let requested = hello_retries_for(
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+        &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1],
+        rustls::server::HelloRetryPolicy::default(),
+    );
+    assert_eq!(requested, vec![]);
+}
+
+#[test]
+fn test_server_can_insist_on_preferred_key_share() {
+    let mut policy = rustls::server::HelloRetryPolicy::default();
+    policy.accept_any_key_share = false;
+    let requested = hello_retries_for(
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+        &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1],
+        policy.clone(),
+    );
+    assert_eq!(requested, vec![rustls::NamedGroup::X25519]);
+
+    // no retry is needed if the client sent the preferred share
+    let requested = hello_retries_for(
+        &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1],
+        &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1],
+        policy,
+    );
+    assert_eq!(requested, vec![]);
+}
+
+#[test]
+fn test_server_hello_retry_group_is_configurable() {
+    let client_groups = [
+        &rustls::kx_group::SECP384R1,
+        &rustls::kx_group::SECP256R1,
+        &rustls::kx_group::X25519,
+    ];
+    let server_groups = [&rustls::kx_group::X25519, &rustls::kx_group::SECP256R1];
+
+    let requested = hello_retries_for(
+        &client_groups,
+        &server_groups,
+        rustls::server::HelloRetryPolicy::default(),
+    );
+    assert_eq!(requested, vec![rustls::NamedGroup::X25519]);
+
+    let mut policy = rustls::server::HelloRetryPolicy::default();
+    policy.retry_groups = vec![
+        rustls::NamedGroup::secp384r1, // not supported by the server
+        rustls::NamedGroup::secp256r1,
+    ];
+    let requested = hello_retries_for(&client_groups, &server_groups, policy);
+    assert_eq!(requested, vec![rustls::NamedGroup::secp256r1]);
+}
+
+#[test]
+fn test_server_hello_retry_limit() {
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+
+    // a refused retry falls back to a key share the client sent
+    let mut server_config = make_server_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1],
+    );
+    let counter = rustls::server::HelloRetryCounter::with_limit(0);
+    server_config
+        .hello_retry
+        .accept_any_key_share = false;
+    server_config.hello_retry_limit = Some(counter.clone());
+    let (mut client, mut server) = make_pair_for_configs(client_config.clone(), server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+    assert_eq!(counter.sent(), 0);
+    assert_eq!(counter.refused(), 1);
+
+    // ... and otherwise the handshake fails
+    let mut server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    server_config.hello_retry_limit = Some(counter.clone());
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatible(
+            PeerIncompatible::UsableKeyShareRequired
+        )))
+    );
+    assert_eq!(counter.refused(), 2);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_client_sends_helloretryrequest() {
+    // client sends a secp384r1 key share
+    let mut client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+
+    let storage = Arc::new(ClientStorage::new());
+    client_config.resumption = Resumption::store(storage.clone());
+
+    // but server only accepts x25519, so a HRR is required
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    // client sends hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 1);
+    }
+
+    // server sends HRR
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen < 100); // just the hello retry request
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+    }
+
+    // client sends fixed hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200); // just the client hello retry
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+    }
+
+    // server completes handshake
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
+    }
+
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+
+    // client only did following storage queries:
+    println!("storage {:#?}", storage.ops());
+    assert_eq!(storage.ops().len(), 9);
+    assert!(matches!(
+        storage.ops()[0],
+        ClientStorageOp::TakeTls13Ticket(_, false)
+    ));
+    assert!(matches!(
+        storage.ops()[1],
+        ClientStorageOp::GetTls12Session(_, false)
+    ));
+    assert!(matches!(
+        storage.ops()[2],
+        ClientStorageOp::GetKxHint(_, None)
+    ));
+    assert!(matches!(
+        storage.ops()[3],
+        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::X25519)
+    ));
+    assert!(matches!(
+        storage.ops()[4],
+        ClientStorageOp::RemoveTls12Session(_)
+    ));
+    // server sends 4 tickets by default
+    assert!(matches!(
+        storage.ops()[5],
+        ClientStorageOp::InsertTls13Ticket(_)
+    ));
+    assert!(matches!(
+        storage.ops()[6],
+        ClientStorageOp::InsertTls13Ticket(_)
+    ));
+    assert!(matches!(
+        storage.ops()[7],
+        ClientStorageOp::InsertTls13Ticket(_)
+    ));
+    assert!(matches!(
+        storage.ops()[8],
+        ClientStorageOp::InsertTls13Ticket(_)
+    ));
+}
+
+#[test]
+fn test_client_rejects_hrr_with_varied_session_id() {
+    use rustls::internal::msgs::handshake::SessionId;
+    let different_session_id = SessionId::random::<Ring>().unwrap();
+
+    let assert_client_sends_hello_with_secp384 = |msg: &mut Message| -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                let keyshares = ch
+                    .get_keyshare_extension()
+                    .expect("missing key share extension");
+                assert_eq!(keyshares.len(), 1);
+                assert_eq!(keyshares[0].group, rustls::NamedGroup::secp384r1);
+
+                ch.session_id = different_session_id;
+                *encoded = Payload::new(parsed.get_encoding());
+            }
+        }
+        Altered::InPlace
+    };
+
+    let assert_server_requests_retry_and_echoes_session_id = |msg: &mut Message| -> Altered {
+        if let MessagePayload::Handshake { parsed, .. } = &mut msg.payload {
+            if let HandshakePayload::HelloRetryRequest(hrr) = &mut parsed.payload {
+                let group = hrr.get_requested_key_share_group();
+                assert_eq!(group, Some(rustls::NamedGroup::X25519));
+
+                assert_eq!(hrr.session_id, different_session_id);
+            }
+        }
+        Altered::InPlace
+    };
+
+    // client prefers a secp384r1 key share, server only accepts x25519
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(
+        &mut client,
+        assert_client_sends_hello_with_secp384,
+        &mut server,
+    );
+    server.process_new_packets().unwrap();
+    transfer_altered(
+        &mut server,
+        assert_server_requests_retry_and_echoes_session_id,
+        &mut client,
+    );
+    assert_eq!(
+        client.process_new_packets(),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::IllegalHelloRetryRequestWithWrongSessionId
+        ))
+    );
+}
+
+#[test]
+fn extended_master_secret_is_reported() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert_eq!(client.extended_master_secret(), None);
+        assert_eq!(server.extended_master_secret(), None);
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.extended_master_secret(), Some(true));
+        assert_eq!(server.extended_master_secret(), Some(true));
+    }
+}
+
+#[test]
+fn negotiated_parameters_are_reported() {
+    // client prefers a secp384r1 key share, server only accepts x25519
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    let negotiated = client.negotiated();
+    assert_eq!(negotiated.version, None);
+    assert_eq!(negotiated.kx_group, None);
+    assert!(!negotiated.hello_retried);
+    assert!(negotiated.peer_certificates.is_none());
+
+    do_handshake(&mut client, &mut server);
+    let (client, server) = (
+        rustls::Connection::from(client),
+        rustls::Connection::from(server),
+    );
+    for (negotiated, server_name) in [
+        (client.negotiated(), None),
+        (server.negotiated(), Some("localhost")),
+    ] {
+        assert_eq!(negotiated.version, Some(ProtocolVersion::TLSv1_3));
+        assert_eq!(
+            negotiated
+                .cipher_suite
+                .map(|suite| suite.suite()),
+            Some(CipherSuite::TLS13_AES_256_GCM_SHA384)
+        );
+        assert_eq!(negotiated.kx_group, Some(rustls::NamedGroup::X25519));
+        assert_eq!(negotiated.alpn_protocol, None);
+        assert_eq!(negotiated.server_name, server_name);
+        assert!(!negotiated.resumed);
+        assert!(negotiated.hello_retried);
+        assert_eq!(negotiated.extended_master_secret, Some(true));
+    }
+    assert!(client
+        .negotiated()
+        .peer_certificates
+        .is_some());
+    assert!(server
+        .negotiated()
+        .peer_certificates
+        .is_none());
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn negotiated_parameters_report_resumption() {
+    let client_config = Arc::new(make_client_config_with_versions(
+        KeyType::Rsa,
+        &[&rustls::version::TLS12],
+    ));
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(!client.negotiated().resumed);
+    assert!(!server.negotiated().resumed);
+    assert_eq!(
+        client.negotiated().kx_group,
+        Some(rustls::NamedGroup::X25519)
+    );
+    assert!(!server.negotiated().hello_retried);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    for negotiated in [client.negotiated(), server.negotiated()] {
+        assert_eq!(negotiated.version, Some(ProtocolVersion::TLSv1_2));
+        assert!(negotiated.resumed);
+        assert_eq!(negotiated.kx_group, None);
+        assert_eq!(negotiated.extended_master_secret, Some(true));
+    }
+}
+
+#[test]
+fn sessions_can_be_moved_between_client_configs() {
+    for version in rustls::ALL_VERSIONS {
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+        let old_config = Arc::new(make_client_config_with_versions(KeyType::Rsa, &[version]));
+        let (mut client, mut server) = make_pair_for_arc_configs(&old_config, &server_config);
+        do_handshake(&mut client, &mut server);
+
+        let new_config = Arc::new(make_client_config_with_versions(KeyType::Rsa, &[version]));
+        let exported = old_config.export_sessions();
+        assert_eq!(exported.len(), 1);
+        assert_eq!(
+            exported[0].kx_hint.is_some(),
+            version.version == ProtocolVersion::TLSv1_3
+        );
+        assert!(new_config.import_sessions(exported) > 0);
+
+        // TLS1.3 tickets were moved; TLS1.2 sessions were copied.
+        let remaining = old_config.export_sessions();
+        assert!(remaining[0].tls13_tickets.is_empty());
+        assert_eq!(
+            remaining[0].tls12_session.is_some(),
+            version.version == ProtocolVersion::TLSv1_2
+        );
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&new_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(client.negotiated().resumed);
+        assert!(server.negotiated().resumed);
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_config_only_imports_sessions_it_can_resume() {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let tls13_config = Arc::new(make_client_config_with_versions(
+        KeyType::Rsa,
+        &[&rustls::version::TLS13],
+    ));
+    let (mut client, mut server) = make_pair_for_arc_configs(&tls13_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let tls12_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    assert_eq!(
+        tls12_config.import_sessions(tls13_config.export_sessions()),
+        0
+    );
+    assert!(tls12_config.export_sessions()[0]
+        .tls13_tickets
+        .is_empty());
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn server_can_require_extended_master_secret() {
+    use rustls::internal::msgs::enums::ExtensionType;
+
+    let strip_ems_request = |msg: &mut Message| -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                ch.extensions
+                    .retain(|ext| ext.get_type() != ExtensionType::ExtendedMasterSecret);
+                *encoded = Payload::new(parsed.get_encoding());
+            }
+        }
+        Altered::InPlace
+    };
+
+    for require_ems in [false, true] {
+        let client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.require_ems = require_ems;
+
+        let (client, server) = make_pair_for_configs(client_config, server_config);
+        let (mut client, mut server): (rustls::Connection, rustls::Connection) =
+            (client.into(), server.into());
+        transfer_altered(&mut client, strip_ems_request, &mut server);
+
+        if require_ems {
+            assert_eq!(
+                server.process_new_packets(),
+                Err(Error::PeerIncompatible(
+                    PeerIncompatible::ExtendedMasterSecretExtensionRequired
+                ))
+            );
+        } else {
+            server.process_new_packets().unwrap();
+            assert_eq!(server.extended_master_secret(), Some(false));
+        }
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_can_require_extended_master_secret() {
+    use rustls::internal::msgs::enums::ExtensionType;
+
+    let strip_ems_ack = |msg: &mut Message| -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+                sh.extensions
+                    .retain(|ext| ext.get_type() != ExtensionType::ExtendedMasterSecret);
+                *encoded = Payload::new(parsed.get_encoding());
+            }
+        }
+        Altered::InPlace
+    };
+
+    for require_ems in [false, true] {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+        client_config.require_ems = require_ems;
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        let (mut client, mut server): (rustls::Connection, rustls::Connection) =
+            (client.into(), server.into());
+        transfer_altered(&mut server, strip_ems_ack, &mut client);
+
+        if require_ems {
+            assert_eq!(
+                client.process_new_packets(),
+                Err(Error::PeerIncompatible(
+                    PeerIncompatible::ExtendedMasterSecretExtensionRequired
+                ))
+            );
+        } else {
+            client.process_new_packets().unwrap();
+            assert_eq!(client.extended_master_secret(), Some(false));
+        }
+    }
+}
+
+fn make_pair_needing_retry_with_cookies(
+    cookies: Arc<dyn rustls::server::ProducesTickets>,
+) -> (ClientConnection, ServerConnection, Arc<ServerConfig<Ring>>) {
+    // client prefers a secp384r1 key share, server only accepts x25519
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+
+    let mut server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    server_config.hello_retry_cookies = cookies;
+    let server_config = Arc::new(server_config);
+
+    let (client, server) = make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    (client, server, server_config)
+}
+
+#[test]
+fn test_stateless_hello_retry_request() {
+    let (mut client, mut server, server_config) =
+        make_pair_needing_retry_with_cookies(rustls::Ticketer::new().unwrap());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    // the connection that sent the HelloRetryRequest is not needed to finish
+    drop(server);
+    let mut server = ServerConnection::new(server_config).unwrap();
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[test]
+fn test_hello_retry_request_with_cookie_completes_on_same_connection() {
+    let (mut client, mut server, _) =
+        make_pair_needing_retry_with_cookies(rustls::Ticketer::new().unwrap());
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn test_stateless_hello_retry_request_rejects_foreign_cookie() {
+    let (mut client, mut server, server_config) =
+        make_pair_needing_retry_with_cookies(rustls::Ticketer::new().unwrap());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    // a server with different cookie keys cannot continue
+    let mut other_config = ServerConfig::clone(&server_config);
+    other_config.hello_retry_cookies = rustls::Ticketer::new().unwrap();
+    let mut server = ServerConnection::new(Arc::new(other_config)).unwrap();
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::PeerMisbehaved(
+            PeerMisbehaved::InvalidHelloRetryCookie
+        ))
+    );
+}
+
+#[test]
+fn test_stateless_hello_retry_request_rejects_expired_cookie() {
+    let time = Arc::new(AdvancingTimeProvider::default());
+    let mut server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    server_config.hello_retry_cookies = rustls::Ticketer::new().unwrap();
+    server_config.time_provider = time.clone();
+    let server_config = Arc::new(server_config);
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    // the client takes too long to answer
+    time.advance(60);
+    let mut server = ServerConnection::new(server_config).unwrap();
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::PeerMisbehaved(
+            PeerMisbehaved::InvalidHelloRetryCookie
+        ))
+    );
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_client_attempts_to_use_unsupported_kx_group() {
+    // common to both client configs
+    let shared_storage = Arc::new(ClientStorage::new());
+
+    // first, client sends a x25519 and server agrees. x25519 is inserted
+    //   into kx group cache.
+    let mut client_config_1 =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    client_config_1.resumption = Resumption::store(shared_storage.clone());
+
+    // second, client only supports secp-384 and so kx group cache
+    //   contains an unusable value.
+    let mut client_config_2 =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    client_config_2.resumption = Resumption::store(shared_storage.clone());
+
+    let server_config = make_server_config(KeyType::Rsa);
+
+    // first handshake
+    let (mut client_1, mut server) = make_pair_for_configs(client_config_1, server_config.clone());
+    do_handshake_until_error(&mut client_1, &mut server).unwrap();
+
+    let ops = shared_storage.ops();
+    println!("storage {:#?}", ops);
+    assert_eq!(ops.len(), 9);
+    assert!(matches!(
+        ops[3],
+        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::X25519)
+    ));
+
+    // second handshake
+    let (mut client_2, mut server) = make_pair_for_configs(client_config_2, server_config);
+    do_handshake_until_error(&mut client_2, &mut server).unwrap();
+
+    let ops = shared_storage.ops();
+    println!("storage {:?} {:#?}", ops.len(), ops);
+    assert_eq!(ops.len(), 17);
+    assert!(matches!(ops[9], ClientStorageOp::TakeTls13Ticket(_, true)));
+    assert!(matches!(
+        ops[10],
+        ClientStorageOp::GetKxHint(_, Some(rustls::NamedGroup::X25519))
+    ));
+    assert!(matches!(
+        ops[11],
+        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::secp384r1)
+    ));
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls13_client_resumption_does_not_reuse_tickets() {
+    let shared_storage = Arc::new(ClientStorage::new());
+
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.resumption = Resumption::store(shared_storage.clone());
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.send_tls13_tickets = 5;
+    let server_config = Arc::new(server_config);
+
+    // first handshake: client obtains 5 tickets from server.
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+
+    let ops = shared_storage.ops_and_reset();
+    println!("storage {:#?}", ops);
+    assert_eq!(ops.len(), 10);
+    assert!(matches!(ops[5], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(matches!(ops[6], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(matches!(ops[7], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(matches!(ops[8], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(matches!(ops[9], ClientStorageOp::InsertTls13Ticket(_)));
+
+    // 5 subsequent handshakes: all are resumptions
+
+    // Note: we don't do complete the handshakes, because that means
+    // we get five additional tickets per connection which is unhelpful
+    // in this test.  It also acts to record a "Happy Eyeballs"-type use
+    // case, where a client speculatively makes many connection attempts
+    // in parallel without knowledge of which will work due to underlying
+    // connectivity uncertainty.
+    for _ in 0..5 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
+        let ops = shared_storage.ops_and_reset();
+        assert!(matches!(ops[0], ClientStorageOp::TakeTls13Ticket(_, true)));
+    }
+
+    // 6th subsequent handshake: cannot be resumed; we ran out of tickets
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let ops = shared_storage.ops_and_reset();
+    println!("last {:?}", ops);
+    assert!(matches!(ops[0], ClientStorageOp::TakeTls13Ticket(_, false)));
+}
+
+#[test]
+fn test_client_mtu_reduction() {
+    struct CollectWrites {
+        writevs: Vec<Vec<usize>>,
+    }
+
+    impl io::Write for CollectWrites {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            panic!()
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            panic!()
+        }
+        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+            let writes = b
+                .iter()
+                .map(|slice| slice.len())
+                .collect::<Vec<usize>>();
+            let len = writes.iter().sum();
+            self.writevs.push(writes);
+            Ok(len)
+        }
+    }
+
+    fn collect_write_lengths(client: &mut ClientConnection) -> Vec<usize> {
+        let mut collector = CollectWrites { writevs: vec![] };
+
+        client
+            .write_tls(&mut collector)
+            .unwrap();
+        assert_eq!(collector.writevs.len(), 1);
+        collector.writevs[0].clone()
+    }
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.max_fragment_size = Some(64);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
+        let writes = collect_write_lengths(&mut client);
+        println!("writes at mtu=64: {:?}", writes);
+        assert!(writes.iter().all(|x| *x <= 64));
+        assert!(writes.len() > 1);
+    }
+}
+
+#[test]
+fn test_server_mtu_reduction() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.max_fragment_size = Some(64);
+    server_config.send_half_rtt_data = true;
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+
+    let big_data = [0u8; 2048];
+    server
+        .writer()
+        .write_all(&big_data)
+        .unwrap();
+
+    let encryption_overhead = 20; // FIXME: see issue #991
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0]
+            .iter()
+            .all(|x| *x <= 64 + encryption_overhead));
+    }
+
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0]
+            .iter()
+            .all(|x| *x <= 64 + encryption_overhead));
+    }
+
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), &big_data);
+}
+
+fn check_client_max_fragment_size(size: usize) -> Option<Error> {
+    let mut client_config = make_client_config(KeyType::Ed25519);
+    client_config.max_fragment_size = Some(size);
+    ClientConnection::new(Arc::new(client_config), server_name("localhost")).err()
+}
+
+#[test]
+fn bad_client_max_fragment_sizes() {
+    assert_eq!(
+        check_client_max_fragment_size(31),
+        Some(Error::BadMaxFragmentSize)
+    );
+    assert_eq!(check_client_max_fragment_size(32), None);
+    assert_eq!(check_client_max_fragment_size(64), None);
+    assert_eq!(check_client_max_fragment_size(1460), None);
+    assert_eq!(check_client_max_fragment_size(0x4000), None);
+    assert_eq!(check_client_max_fragment_size(0x4005), None);
+    assert_eq!(
+        check_client_max_fragment_size(0x4006),
+        Some(Error::BadMaxFragmentSize)
+    );
+    assert_eq!(
+        check_client_max_fragment_size(0xffff),
+        Some(Error::BadMaxFragmentSize)
+    );
+}
+
+fn assert_lt(left: usize, right: usize) {
+    if left >= right {
+        panic!("expected {} < {}", left, right);
+    }
+}
+
+#[test]
+fn connection_types_are_not_huge() {
+    // Arbitrary sizes
+    assert_lt(mem::size_of::<ServerConnection>(), 1600);
+    assert_lt(mem::size_of::<ClientConnection>(), 1600);
+}
+
+use rustls::internal::msgs::{
+    handshake::ClientExtension, handshake::HandshakePayload, message::Message,
+    message::MessagePayload,
+};
+
+#[test]
+fn test_server_rejects_duplicate_sni_names() {
+    fn duplicate_sni_payload(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = &mut ext {
+                        snr.push(snr[0].clone());
+                    }
+                }
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    }
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, duplicate_sni_payload, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::DuplicateServerNameTypes
+        ))
+    );
+}
+
+fn duplicate_client_extension(typ: rustls::ExtensionType) -> impl Fn(&mut Message) -> Altered {
+    move |msg: &mut Message| {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                let ext = ch
+                    .extensions
+                    .iter()
+                    .find(|ext| ext.get_type() == typ)
+                    .expect("extension not sent")
+                    .clone();
+                ch.extensions.insert(0, ext);
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    }
+}
+
+#[test]
+fn test_server_applies_duplicate_extension_policy() {
+    use rustls::{DuplicateExtensionPolicy, ExtensionType};
+
+    let cases = [
+        (DuplicateExtensionPolicy::Reject, false),
+        (
+            DuplicateExtensionPolicy::Tolerate(vec![ExtensionType::ALProtocolNegotiation]),
+            false,
+        ),
+        (
+            DuplicateExtensionPolicy::Tolerate(vec![ExtensionType::ExtendedMasterSecret]),
+            true,
+        ),
+    ];
+
+    for (policy, tolerated) in cases {
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.duplicate_extensions = policy;
+        let (client, server) =
+            make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+        let (mut client, mut server) = (client.into(), server.into());
+        transfer_altered(
+            &mut client,
+            duplicate_client_extension(ExtensionType::ExtendedMasterSecret),
+            &mut server,
+        );
+
+        let result = server.process_new_packets().map(|_| ());
+        match tolerated {
+            true => assert_eq!(result, Ok(())),
+            false => assert_eq!(
+                result,
+                Err(Error::PeerMisbehaved(
+                    PeerMisbehaved::DuplicateClientHelloExtensions
+                ))
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_acceptor_applies_duplicate_extension_policy() {
+    use rustls::internal::msgs::codec::Reader;
+    use rustls::internal::msgs::message::{OpaqueMessage, PlainMessage};
+    use rustls::server::Acceptor;
+    use rustls::{DuplicateExtensionPolicy, ExtensionType};
+
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+    let client_hello = OpaqueMessage::read(&mut Reader::init(&buf)).unwrap();
+    let mut client_hello = Message::try_from(client_hello.into_plain_message()).unwrap();
+    duplicate_client_extension(ExtensionType::ExtendedMasterSecret)(&mut client_hello);
+    let client_hello = PlainMessage::from(client_hello)
+        .into_unencrypted_opaque()
+        .encode();
+
+    // rejected before an `Accepted` (and so any config or resolver) is seen
+    let mut acceptor = Acceptor::default();
+    acceptor
+        .read_tls(&mut &client_hello[..])
+        .unwrap();
+    assert_eq!(
+        acceptor.accept().err(),
+        Some(Error::PeerMisbehaved(
+            PeerMisbehaved::DuplicateClientHelloExtensions
+        ))
+    );
+
+    let mut acceptor =
+        Acceptor::with_duplicate_extensions(DuplicateExtensionPolicy::Tolerate(vec![
+            ExtensionType::ExtendedMasterSecret,
+        ]));
+    acceptor
+        .read_tls(&mut &client_hello[..])
+        .unwrap();
+    let accepted = acceptor.accept().unwrap().unwrap();
+    accepted
+        .into_connection(Arc::new(make_server_config(KeyType::Rsa)))
+        .unwrap();
+}
+
+#[test]
+fn rejected_peer_input_reports_code_and_alert() {
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(
+        &mut client,
+        duplicate_client_extension(rustls::ExtensionType::ExtendedMasterSecret),
+        &mut server,
+    );
+
+    let err = server
+        .process_new_packets()
+        .unwrap_err();
+    assert_eq!(err.code(), Some(2004));
+    assert_eq!(
+        server.sent_fatal_alert(),
+        Some(rustls::AlertDescription::DecodeError)
+    );
+    assert_eq!(client.sent_fatal_alert(), None);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_client_applies_duplicate_extension_policy() {
+    use rustls::internal::msgs::handshake::ServerExtension;
+    use rustls::{DuplicateExtensionPolicy, ExtensionType};
+
+    fn duplicate_ems_ack(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut parsed.payload {
+                sh.extensions
+                    .push(ServerExtension::ExtendedMasterSecretAck);
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    }
+
+    let cases = [
+        (DuplicateExtensionPolicy::Reject, false),
+        (
+            DuplicateExtensionPolicy::Tolerate(vec![ExtensionType::ExtendedMasterSecret]),
+            true,
+        ),
+    ];
+
+    for (policy, tolerated) in cases {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+        client_config.duplicate_extensions = policy;
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        let (mut client, mut server) = (client.into(), server.into());
+        transfer_altered(&mut server, duplicate_ems_ack, &mut client);
+
+        let result = client.process_new_packets().map(|_| ());
+        match tolerated {
+            true => assert_eq!(result, Ok(())),
+            false => assert_eq!(
+                result,
+                Err(Error::PeerMisbehaved(
+                    PeerMisbehaved::DuplicateServerHelloExtensions
+                ))
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_server_rejects_empty_sni_extension() {
+    fn empty_sni_payload(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = &mut ext {
+                        snr.clear();
+                    }
+                }
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+
+        Altered::InPlace
+    }
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, empty_sni_payload, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::ServerNameMustContainOneHostName
+        ))
+    );
+}
+
+#[test]
+fn test_server_rejects_clients_without_any_kx_group_overlap() {
+    fn different_kx_group(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::NamedGroups(ngs) = &mut ext {
+                        ngs.clear();
+                    }
+                    if let ClientExtension::KeyShare(ks) = &mut ext {
+                        ks.clear();
+                    }
+                }
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
+    }
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, different_kx_group, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerIncompatible(
+            PeerIncompatible::NoKxGroupsInCommon
+        ))
+    );
+}
+
+#[test]
+fn test_client_rejects_illegal_tls13_ccs() {
+    fn corrupt_ccs(msg: &mut Message) -> Altered {
+        if let MessagePayload::ChangeCipherSpec(_) = &mut msg.payload {
+            println!("seen CCS {:?}", msg);
+            return Altered::Raw(vec![0x14, 0x03, 0x03, 0x00, 0x02, 0x01, 0x02]);
+        }
+        Altered::InPlace
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let (mut server, mut client) = (server.into(), client.into());
+
+    transfer_altered(&mut server, corrupt_ccs, &mut client);
+    assert_eq!(
+        client.process_new_packets(),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::IllegalMiddleboxChangeCipherSpec
+        ))
+    );
+}
+
+/// https://github.com/rustls/rustls/issues/797
+#[cfg(feature = "tls12")]
+#[test]
+fn test_client_tls12_no_resume_after_server_downgrade() {
+    let mut client_config = common::make_client_config(KeyType::Ed25519);
+    let client_storage = Arc::new(ClientStorage::new());
+    client_config.resumption = Resumption::store(client_storage.clone());
+    let client_config = Arc::new(client_config);
+
+    let server_config_1 = Arc::new(common::finish_server_config(
+        KeyType::Ed25519,
+        ServerConfig::<Ring>::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap(),
+    ));
+
+    let mut server_config_2 = common::finish_server_config(
+        KeyType::Ed25519,
+        ServerConfig::<Ring>::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap(),
+    );
+    server_config_2.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+
+    dbg!("handshake 1");
+    let mut client_1 =
+        ClientConnection::new(client_config.clone(), "localhost".try_into().unwrap()).unwrap();
+    let mut server_1 = ServerConnection::new(server_config_1).unwrap();
+    common::do_handshake(&mut client_1, &mut server_1);
+
+    assert_eq!(client_storage.ops().len(), 9);
+    println!("hs1 storage ops: {:#?}", client_storage.ops());
+    assert!(matches!(
+        client_storage.ops()[3],
+        ClientStorageOp::SetKxHint(_, _)
+    ));
+    assert!(matches!(
+        client_storage.ops()[4],
+        ClientStorageOp::RemoveTls12Session(_)
+    ));
+    assert!(matches!(
+        client_storage.ops()[5],
+        ClientStorageOp::InsertTls13Ticket(_)
+    ));
+
+    dbg!("handshake 2");
+    let mut client_2 =
+        ClientConnection::new(client_config, "localhost".try_into().unwrap()).unwrap();
+    let mut server_2 = ServerConnection::new(Arc::new(server_config_2)).unwrap();
+    common::do_handshake(&mut client_2, &mut server_2);
+    println!("hs2 storage ops: {:#?}", client_storage.ops());
+    assert_eq!(client_storage.ops().len(), 11);
+
+    // attempt consumes a TLS1.3 ticket
+    assert!(matches!(
+        client_storage.ops()[9],
+        ClientStorageOp::TakeTls13Ticket(_, true)
+    ));
+
+    // but ends up with TLS1.2
+    assert_eq!(
+        client_2.protocol_version(),
+        Some(rustls::ProtocolVersion::TLSv1_2)
+    );
+}
+
+#[test]
+fn test_acceptor() {
+    use rustls::server::Acceptor;
+
+    let client_config = Arc::new(make_client_config(KeyType::Ed25519));
+    let mut client = ClientConnection::new(client_config, server_name("localhost")).unwrap();
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+
+    let server_config = Arc::new(make_server_config(KeyType::Ed25519));
+    let mut acceptor = Acceptor::default();
+    acceptor
+        .read_tls(&mut buf.as_slice())
+        .unwrap();
+    let accepted = acceptor.accept().unwrap().unwrap();
+    let ch = accepted.client_hello();
+    assert_eq!(ch.server_name(), Some("localhost"));
+
+    let server = accepted
+        .into_connection(server_config)
+        .unwrap();
+    assert!(server.wants_write());
+
+    // Reusing an acceptor is not allowed
+    assert_eq!(
+        acceptor
+            .read_tls(&mut [0u8].as_ref())
+            .err()
+            .unwrap()
+            .kind(),
+        io::ErrorKind::Other,
+    );
+    assert_eq!(
+        acceptor.accept().err(),
+        Some(Error::General("Acceptor polled after completion".into()))
+    );
+
+    let mut acceptor = Acceptor::default();
+    assert!(acceptor.accept().unwrap().is_none());
+    acceptor
+        .read_tls(&mut &buf[..3])
+        .unwrap(); // incomplete message
+    assert!(acceptor.accept().unwrap().is_none());
+    acceptor
+        .read_tls(&mut [0x80, 0x00].as_ref())
+        .unwrap(); // invalid message (len = 32k bytes)
+    assert!(acceptor.accept().is_err());
+
+    let mut acceptor = Acceptor::default();
+    // Minimal valid 1-byte application data message is not a handshake message
+    acceptor
+        .read_tls(&mut [0x17, 0x03, 0x03, 0x00, 0x01, 0x00].as_ref())
+        .unwrap();
+    assert!(acceptor.accept().is_err());
+
+    let mut acceptor = Acceptor::default();
+    // Minimal 1-byte ClientHello message is not a legal handshake message
+    acceptor
+        .read_tls(&mut [0x16, 0x03, 0x03, 0x00, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00].as_ref())
+        .unwrap();
+    assert!(acceptor.accept().is_err());
+}
+
+fn accept_client_hello(client: &mut ClientConnection) -> rustls::server::Accepted {
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+    let mut acceptor = rustls::server::Acceptor::default();
+    acceptor
+        .read_tls(&mut buf.as_slice())
+        .unwrap();
+    acceptor.accept().unwrap().unwrap()
+}
+
+#[test]
+fn test_acceptor_preview_matches_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(make_client_config_with_versions(*kt, &[version]));
+            let server_config = Arc::new(make_server_config(*kt));
+
+            let mut client =
+                ClientConnection::new(client_config, server_name("localhost")).unwrap();
+            let accepted = accept_client_hello(&mut client);
+            let preview = accepted
+                .preview(&server_config)
+                .unwrap();
+            assert_eq!(preview.version, version.version);
+            assert_eq!(preview.kx_group, rustls::NamedGroup::X25519);
+            assert!(!preview.hello_retry);
+            assert!(!preview.resumption);
+            assert_eq!(preview.certified_key.cert, kt.get_chain());
+
+            let mut server = accepted
+                .into_connection(server_config)
+                .unwrap();
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.protocol_version(), Some(preview.version));
+            assert_eq!(server.negotiated_cipher_suite(), Some(preview.cipher_suite));
+        }
+    }
+}
+
+#[test]
+fn test_acceptor_preview_reports_hello_retry() {
+    let client_config = Arc::new(make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    ));
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
+    let mut client = ClientConnection::new(client_config, server_name("localhost")).unwrap();
+    let preview = accept_client_hello(&mut client)
+        .preview(&server_config)
+        .unwrap();
+    assert_eq!(preview.version, ProtocolVersion::TLSv1_3);
+    assert_eq!(preview.kx_group, rustls::NamedGroup::X25519);
+    assert!(preview.hello_retry);
+}
+
+#[test]
+fn test_acceptor_preview_reports_resumption() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let server_config = Arc::new(make_server_config(kt));
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+
+        let mut client =
+            ClientConnection::new(Arc::clone(&client_config), server_name("localhost")).unwrap();
+        let accepted = accept_client_hello(&mut client);
+        assert!(
+            accepted
+                .preview(&server_config)
+                .unwrap()
+                .resumption
+        );
+
+        // the preview did not use up the session
+        let mut server = accepted
+            .into_connection(server_config)
+            .unwrap();
+        do_handshake(&mut client, &mut server);
+        assert!(server
+            .received_resumption_data()
+            .is_some());
+    }
+}
+
+#[test]
+fn test_acceptor_preview_applies_sni_policy() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.enable_sni = false;
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.require_sni = true;
+
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
+    assert_eq!(
+        accept_client_hello(&mut client)
+            .preview(&server_config)
+            .err(),
+        Some(Error::PeerIncompatible(
+            PeerIncompatible::ServerNameExtensionRequired
+        ))
+    );
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_acceptor_preview_reports_errors() {
+    let client_config = Arc::new(make_client_config_with_versions(
+        KeyType::Rsa,
+        &[&rustls::version::TLS13],
+    ));
+    let server_config = make_server_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+
+    let mut client = ClientConnection::new(client_config, server_name("localhost")).unwrap();
+    assert_eq!(
+        accept_client_hello(&mut client)
+            .preview(&server_config)
+            .err(),
+        Some(Error::PeerIncompatible(
+            PeerIncompatible::Tls12NotOfferedOrEnabled
+        ))
+    );
+}
+
+#[derive(Default, Debug)]
+struct LogCounts {
+    trace: usize,
+    debug: usize,
+    info: usize,
+    warn: usize,
+    error: usize,
+}
+
+impl LogCounts {
+    fn new() -> Self {
+        Self {
+            ..Default::default()
+        }
     }
 
-    #[test]
-    fn test_quic_server_no_tls12() {
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
 
-        use ring::rand::SecureRandom;
-        use rustls::internal::msgs::base::PayloadU16;
-        use rustls::internal::msgs::enums::{Compression, NamedGroup};
-        use rustls::internal::msgs::handshake::{
-            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionId,
-        };
-        use rustls::{CipherSuite, HandshakeType, SignatureScheme};
+    fn add(&mut self, level: log::Level) {
+        match level {
+            log::Level::Trace => self.trace += 1,
+            log::Level::Debug => self.debug += 1,
+            log::Level::Info => self.info += 1,
+            log::Level::Warn => self.warn += 1,
+            log::Level::Error => self.error += 1,
+        }
+    }
+}
 
-        let rng = ring::rand::SystemRandom::new();
-        let mut random = [0; 32];
-        rng.fill(&mut random).unwrap();
-        let random = Random::from(random);
+thread_local!(static COUNTS: RefCell<LogCounts> = RefCell::new(LogCounts::new()));
 
-        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .unwrap()
-            .compute_public_key()
-            .unwrap();
+struct CountingLogger;
 
-        let mut server = quic::ServerConnection::new(
-            server_config,
-            quic::Version::V1,
-            b"server params".to_vec(),
-        )
-        .unwrap();
+static LOGGER: CountingLogger = CountingLogger;
 
-        let client_hello = MessagePayload::handshake(HandshakeMessagePayload {
-            typ: HandshakeType::ClientHello,
-            payload: HandshakePayload::ClientHello(ClientHelloPayload {
-                client_version: ProtocolVersion::TLSv1_2,
-                random: random.clone(),
-                session_id: SessionId::random::<Ring>().unwrap(),
-                cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
-                compression_methods: vec![Compression::Null],
-                extensions: vec![
-                    ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
-                    ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
-                    ClientExtension::KeyShare(vec![KeyShareEntry {
-                        group: NamedGroup::X25519,
-                        payload: PayloadU16::new(kx.as_ref().to_vec()),
-                    }]),
-                ],
-            }),
+impl CountingLogger {
+    fn install() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+
+    fn reset() {
+        COUNTS.with(|c| {
+            c.borrow_mut().reset();
         });
+    }
+}
 
-        let mut buf = Vec::with_capacity(512);
-        client_hello.encode(&mut buf);
-        assert_eq!(
-            server
-                .read_hs(&mut buf.as_slice())
-                .err(),
-            Some(Error::PeerIncompatible(
-                PeerIncompatible::SupportedVersionsExtensionRequired
-            )),
-        );
+impl log::Log for CountingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
     }
 
-    #[test]
-    fn packet_key_api() {
-        use rustls::quic::{Keys, Version};
-        use rustls::Side;
+    fn log(&self, record: &log::Record) {
+        println!("logging at {:?}: {:?}", record.level(), record.args());
 
-        // Test vectors: https://www.rfc-editor.org/rfc/rfc9001.html#name-client-initial
-        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
-        const PACKET_NUMBER: u64 = 2;
-        const PLAIN_HEADER: &[u8] = &[
-            0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
-            0x00, 0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
-        ];
+        COUNTS.with(|c| {
+            c.borrow_mut().add(record.level());
+        });
+    }
 
-        const PAYLOAD: &[u8] = &[
-            0x06, 0x00, 0x40, 0xf1, 0x01, 0x00, 0x00, 0xed, 0x03, 0x03, 0xeb, 0xf8, 0xfa, 0x56,
-            0xf1, 0x29, 0x39, 0xb9, 0x58, 0x4a, 0x38, 0x96, 0x47, 0x2e, 0xc4, 0x0b, 0xb8, 0x63,
-            0xcf, 0xd3, 0xe8, 0x68, 0x04, 0xfe, 0x3a, 0x47, 0xf0, 0x6a, 0x2b, 0x69, 0x48, 0x4c,
-            0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00,
-            0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
-            0x63, 0x6f, 0x6d, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x06,
-            0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x10, 0x00, 0x07, 0x00, 0x05, 0x04, 0x61,
-            0x6c, 0x70, 0x6e, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33,
-            0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x93, 0x70, 0xb2, 0xc9, 0xca, 0xa4,
-            0x7f, 0xba, 0xba, 0xf4, 0x55, 0x9f, 0xed, 0xba, 0x75, 0x3d, 0xe1, 0x71, 0xfa, 0x71,
-            0xf5, 0x0f, 0x1c, 0xe1, 0x5d, 0x43, 0xe9, 0x94, 0xec, 0x74, 0xd7, 0x48, 0x00, 0x2b,
-            0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x10, 0x00, 0x0e, 0x04, 0x03, 0x05,
-            0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x00, 0x2d, 0x00,
-            0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01, 0x00, 0x39, 0x00, 0x32, 0x04,
-            0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x05, 0x04, 0x80, 0x00, 0xff,
-            0xff, 0x07, 0x04, 0x80, 0x00, 0xff, 0xff, 0x08, 0x01, 0x10, 0x01, 0x04, 0x80, 0x00,
-            0x75, 0x30, 0x09, 0x01, 0x10, 0x0f, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57,
-            0x08, 0x06, 0x04, 0x80, 0x00, 0xff, 0xff,
-        ];
+    fn flush(&self) {}
+}
 
-        let client_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Client);
-        assert_eq!(
-            client_keys
-                .local
-                .packet
-                .confidentiality_limit(),
-            2u64.pow(23)
-        );
-        assert_eq!(
-            client_keys
-                .local
-                .packet
-                .integrity_limit(),
-            2u64.pow(52)
-        );
-        assert_eq!(client_keys.local.packet.tag_len(), 16);
+#[test]
+fn test_no_warning_logging_during_successful_sessions() {
+    CountingLogger::install();
+    CountingLogger::reset();
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(*kt));
+            do_handshake(&mut client, &mut server);
+        }
+    }
+
+    if cfg!(feature = "logging") {
+        COUNTS.with(|c| {
+            println!("After tests: {:?}", c.borrow());
+            assert_eq!(c.borrow().warn, 0);
+            assert_eq!(c.borrow().error, 0);
+            assert_eq!(c.borrow().info, 0);
+            assert!(c.borrow().trace > 0);
+            assert!(c.borrow().debug > 0);
+        });
+    } else {
+        COUNTS.with(|c| {
+            println!("After tests: {:?}", c.borrow());
+            assert_eq!(c.borrow().warn, 0);
+            assert_eq!(c.borrow().error, 0);
+            assert_eq!(c.borrow().info, 0);
+            assert_eq!(c.borrow().trace, 0);
+            assert_eq!(c.borrow().debug, 0);
+        });
+    }
+}
+
+/// Test that secrets can be extracted and used for encryption/decryption.
+#[cfg(feature = "secret_extraction")]
+#[test]
+fn test_secret_extraction_enabled() {
+    // Normally, secret extraction would be used to configure kTLS (TLS offload
+    // to the kernel). We want this test to run on any platform, though, so
+    // instead we just compare secrets for equality.
+
+    // TLS 1.2 and 1.3 have different mechanisms for key exchange and handshake,
+    // and secrets are stored/extracted differently, so we want to test them both.
+    // We support 3 different AEAD algorithms (AES-128-GCM mode, AES-256-GCM, and
+    // Chacha20Poly1305), so that's 2*3 = 6 combinations to test.
+    let kt = KeyType::Rsa;
+    for suite in [
+        rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+        rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+        rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ] {
+        let version = suite.version();
+        println!("Testing suite {:?}", suite.suite().as_str());
+
+        // Only offer the cipher suite (and protocol version) that we're testing
+        let mut server_config = ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[suite])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(kt.get_chain(), kt.get_key())
+            .unwrap();
+        // Opt into secret extraction from both sides
+        server_config.enable_secret_extraction = true;
+        let server_config = Arc::new(server_config);
+
+        let mut client_config = make_client_config(kt);
+        client_config.enable_secret_extraction = true;
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+        do_handshake(&mut client, &mut server);
+
+        // The handshake is finished, we're now able to extract traffic secrets
+        let client_secrets = client.extract_secrets().unwrap();
+        let server_secrets = server.extract_secrets().unwrap();
+
+        // Comparing secrets for equality is something you should never have to
+        // do in production code, so ConnectionTrafficSecrets doesn't implement
+        // PartialEq/Eq on purpose. Instead, we have to get creative.
+        fn explode_secrets(s: &ConnectionTrafficSecrets) -> (&[u8], &[u8], &[u8]) {
+            match s {
+                ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv } => (key, salt, iv),
+                ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv } => (key, salt, iv),
+                ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => (key, &[], iv),
+                _ => panic!("unexpected secret type"),
+            }
+        }
 
-        let mut buf = Vec::new();
-        buf.extend(PLAIN_HEADER);
-        buf.extend(PAYLOAD);
-        let header_len = PLAIN_HEADER.len();
-        let tag_len = client_keys.local.packet.tag_len();
-        let padding_len = 1200 - header_len - PAYLOAD.len() - tag_len;
-        buf.extend(std::iter::repeat(0).take(padding_len));
-        let (header, payload) = buf.split_at_mut(header_len);
-        let tag = client_keys
-            .local
-            .packet
-            .encrypt_in_place(PACKET_NUMBER, &*header, payload)
-            .unwrap();
+        fn assert_secrets_equal(
+            (l_seq, l_sec): (u64, ConnectionTrafficSecrets),
+            (r_seq, r_sec): (u64, ConnectionTrafficSecrets),
+        ) {
+            assert_eq!(l_seq, r_seq);
+            assert_eq!(explode_secrets(&l_sec), explode_secrets(&r_sec));
+        }
 
-        let sample_len = client_keys.local.header.sample_len();
-        let sample = &payload[..sample_len];
-        let (first, rest) = header.split_at_mut(1);
-        client_keys
-            .local
-            .header
-            .encrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+        assert_secrets_equal(client_secrets.tx, server_secrets.rx);
+        assert_secrets_equal(client_secrets.rx, server_secrets.tx);
+    }
+}
+
+/// Test that secrets cannot be extracted unless explicitly enabled, and until
+/// the handshake is done.
+#[cfg(feature = "secret_extraction")]
+#[test]
+fn test_secret_extraction_disabled_or_too_early() {
+    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
+    let kt = KeyType::Rsa;
+
+    for (server_enable, client_enable) in [(true, false), (false, true)] {
+        let mut server_config = ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[suite])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(kt.get_chain(), kt.get_key())
             .unwrap();
-        buf.extend_from_slice(tag.as_ref());
+        server_config.enable_secret_extraction = server_enable;
+        let server_config = Arc::new(server_config);
 
-        const PROTECTED: &[u8] = &[
-            0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
-            0x00, 0x00, 0x44, 0x9e, 0x7b, 0x9a, 0xec, 0x34, 0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68,
-            0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc, 0x9b, 0xd8, 0xba, 0xb9, 0x36,
-            0xb4, 0x7d, 0x92, 0xec, 0x35, 0x6c, 0x0b, 0xab, 0x7d, 0xf5, 0x97, 0x6d, 0x27, 0xcd,
-            0x44, 0x9f, 0x63, 0x30, 0x00, 0x99, 0xf3, 0x99, 0x1c, 0x26, 0x0e, 0xc4, 0xc6, 0x0d,
-            0x17, 0xb3, 0x1f, 0x84, 0x29, 0x15, 0x7b, 0xb3, 0x5a, 0x12, 0x82, 0xa6, 0x43, 0xa8,
-            0xd2, 0x26, 0x2c, 0xad, 0x67, 0x50, 0x0c, 0xad, 0xb8, 0xe7, 0x37, 0x8c, 0x8e, 0xb7,
-            0x53, 0x9e, 0xc4, 0xd4, 0x90, 0x5f, 0xed, 0x1b, 0xee, 0x1f, 0xc8, 0xaa, 0xfb, 0xa1,
-            0x7c, 0x75, 0x0e, 0x2c, 0x7a, 0xce, 0x01, 0xe6, 0x00, 0x5f, 0x80, 0xfc, 0xb7, 0xdf,
-            0x62, 0x12, 0x30, 0xc8, 0x37, 0x11, 0xb3, 0x93, 0x43, 0xfa, 0x02, 0x8c, 0xea, 0x7f,
-            0x7f, 0xb5, 0xff, 0x89, 0xea, 0xc2, 0x30, 0x82, 0x49, 0xa0, 0x22, 0x52, 0x15, 0x5e,
-            0x23, 0x47, 0xb6, 0x3d, 0x58, 0xc5, 0x45, 0x7a, 0xfd, 0x84, 0xd0, 0x5d, 0xff, 0xfd,
-            0xb2, 0x03, 0x92, 0x84, 0x4a, 0xe8, 0x12, 0x15, 0x46, 0x82, 0xe9, 0xcf, 0x01, 0x2f,
-            0x90, 0x21, 0xa6, 0xf0, 0xbe, 0x17, 0xdd, 0xd0, 0xc2, 0x08, 0x4d, 0xce, 0x25, 0xff,
-            0x9b, 0x06, 0xcd, 0xe5, 0x35, 0xd0, 0xf9, 0x20, 0xa2, 0xdb, 0x1b, 0xf3, 0x62, 0xc2,
-            0x3e, 0x59, 0x6d, 0x11, 0xa4, 0xf5, 0xa6, 0xcf, 0x39, 0x48, 0x83, 0x8a, 0x3a, 0xec,
-            0x4e, 0x15, 0xda, 0xf8, 0x50, 0x0a, 0x6e, 0xf6, 0x9e, 0xc4, 0xe3, 0xfe, 0xb6, 0xb1,
-            0xd9, 0x8e, 0x61, 0x0a, 0xc8, 0xb7, 0xec, 0x3f, 0xaf, 0x6a, 0xd7, 0x60, 0xb7, 0xba,
-            0xd1, 0xdb, 0x4b, 0xa3, 0x48, 0x5e, 0x8a, 0x94, 0xdc, 0x25, 0x0a, 0xe3, 0xfd, 0xb4,
-            0x1e, 0xd1, 0x5f, 0xb6, 0xa8, 0xe5, 0xeb, 0xa0, 0xfc, 0x3d, 0xd6, 0x0b, 0xc8, 0xe3,
-            0x0c, 0x5c, 0x42, 0x87, 0xe5, 0x38, 0x05, 0xdb, 0x05, 0x9a, 0xe0, 0x64, 0x8d, 0xb2,
-            0xf6, 0x42, 0x64, 0xed, 0x5e, 0x39, 0xbe, 0x2e, 0x20, 0xd8, 0x2d, 0xf5, 0x66, 0xda,
-            0x8d, 0xd5, 0x99, 0x8c, 0xca, 0xbd, 0xae, 0x05, 0x30, 0x60, 0xae, 0x6c, 0x7b, 0x43,
-            0x78, 0xe8, 0x46, 0xd2, 0x9f, 0x37, 0xed, 0x7b, 0x4e, 0xa9, 0xec, 0x5d, 0x82, 0xe7,
-            0x96, 0x1b, 0x7f, 0x25, 0xa9, 0x32, 0x38, 0x51, 0xf6, 0x81, 0xd5, 0x82, 0x36, 0x3a,
-            0xa5, 0xf8, 0x99, 0x37, 0xf5, 0xa6, 0x72, 0x58, 0xbf, 0x63, 0xad, 0x6f, 0x1a, 0x0b,
-            0x1d, 0x96, 0xdb, 0xd4, 0xfa, 0xdd, 0xfc, 0xef, 0xc5, 0x26, 0x6b, 0xa6, 0x61, 0x17,
-            0x22, 0x39, 0x5c, 0x90, 0x65, 0x56, 0xbe, 0x52, 0xaf, 0xe3, 0xf5, 0x65, 0x63, 0x6a,
-            0xd1, 0xb1, 0x7d, 0x50, 0x8b, 0x73, 0xd8, 0x74, 0x3e, 0xeb, 0x52, 0x4b, 0xe2, 0x2b,
-            0x3d, 0xcb, 0xc2, 0xc7, 0x46, 0x8d, 0x54, 0x11, 0x9c, 0x74, 0x68, 0x44, 0x9a, 0x13,
-            0xd8, 0xe3, 0xb9, 0x58, 0x11, 0xa1, 0x98, 0xf3, 0x49, 0x1d, 0xe3, 0xe7, 0xfe, 0x94,
-            0x2b, 0x33, 0x04, 0x07, 0xab, 0xf8, 0x2a, 0x4e, 0xd7, 0xc1, 0xb3, 0x11, 0x66, 0x3a,
-            0xc6, 0x98, 0x90, 0xf4, 0x15, 0x70, 0x15, 0x85, 0x3d, 0x91, 0xe9, 0x23, 0x03, 0x7c,
-            0x22, 0x7a, 0x33, 0xcd, 0xd5, 0xec, 0x28, 0x1c, 0xa3, 0xf7, 0x9c, 0x44, 0x54, 0x6b,
-            0x9d, 0x90, 0xca, 0x00, 0xf0, 0x64, 0xc9, 0x9e, 0x3d, 0xd9, 0x79, 0x11, 0xd3, 0x9f,
-            0xe9, 0xc5, 0xd0, 0xb2, 0x3a, 0x22, 0x9a, 0x23, 0x4c, 0xb3, 0x61, 0x86, 0xc4, 0x81,
-            0x9e, 0x8b, 0x9c, 0x59, 0x27, 0x72, 0x66, 0x32, 0x29, 0x1d, 0x6a, 0x41, 0x82, 0x11,
-            0xcc, 0x29, 0x62, 0xe2, 0x0f, 0xe4, 0x7f, 0xeb, 0x3e, 0xdf, 0x33, 0x0f, 0x2c, 0x60,
-            0x3a, 0x9d, 0x48, 0xc0, 0xfc, 0xb5, 0x69, 0x9d, 0xbf, 0xe5, 0x89, 0x64, 0x25, 0xc5,
-            0xba, 0xc4, 0xae, 0xe8, 0x2e, 0x57, 0xa8, 0x5a, 0xaf, 0x4e, 0x25, 0x13, 0xe4, 0xf0,
-            0x57, 0x96, 0xb0, 0x7b, 0xa2, 0xee, 0x47, 0xd8, 0x05, 0x06, 0xf8, 0xd2, 0xc2, 0x5e,
-            0x50, 0xfd, 0x14, 0xde, 0x71, 0xe6, 0xc4, 0x18, 0x55, 0x93, 0x02, 0xf9, 0x39, 0xb0,
-            0xe1, 0xab, 0xd5, 0x76, 0xf2, 0x79, 0xc4, 0xb2, 0xe0, 0xfe, 0xb8, 0x5c, 0x1f, 0x28,
-            0xff, 0x18, 0xf5, 0x88, 0x91, 0xff, 0xef, 0x13, 0x2e, 0xef, 0x2f, 0xa0, 0x93, 0x46,
-            0xae, 0xe3, 0x3c, 0x28, 0xeb, 0x13, 0x0f, 0xf2, 0x8f, 0x5b, 0x76, 0x69, 0x53, 0x33,
-            0x41, 0x13, 0x21, 0x19, 0x96, 0xd2, 0x00, 0x11, 0xa1, 0x98, 0xe3, 0xfc, 0x43, 0x3f,
-            0x9f, 0x25, 0x41, 0x01, 0x0a, 0xe1, 0x7c, 0x1b, 0xf2, 0x02, 0x58, 0x0f, 0x60, 0x47,
-            0x47, 0x2f, 0xb3, 0x68, 0x57, 0xfe, 0x84, 0x3b, 0x19, 0xf5, 0x98, 0x40, 0x09, 0xdd,
-            0xc3, 0x24, 0x04, 0x4e, 0x84, 0x7a, 0x4f, 0x4a, 0x0a, 0xb3, 0x4f, 0x71, 0x95, 0x95,
-            0xde, 0x37, 0x25, 0x2d, 0x62, 0x35, 0x36, 0x5e, 0x9b, 0x84, 0x39, 0x2b, 0x06, 0x10,
-            0x85, 0x34, 0x9d, 0x73, 0x20, 0x3a, 0x4a, 0x13, 0xe9, 0x6f, 0x54, 0x32, 0xec, 0x0f,
-            0xd4, 0xa1, 0xee, 0x65, 0xac, 0xcd, 0xd5, 0xe3, 0x90, 0x4d, 0xf5, 0x4c, 0x1d, 0xa5,
-            0x10, 0xb0, 0xff, 0x20, 0xdc, 0xc0, 0xc7, 0x7f, 0xcb, 0x2c, 0x0e, 0x0e, 0xb6, 0x05,
-            0xcb, 0x05, 0x04, 0xdb, 0x87, 0x63, 0x2c, 0xf3, 0xd8, 0xb4, 0xda, 0xe6, 0xe7, 0x05,
-            0x76, 0x9d, 0x1d, 0xe3, 0x54, 0x27, 0x01, 0x23, 0xcb, 0x11, 0x45, 0x0e, 0xfc, 0x60,
-            0xac, 0x47, 0x68, 0x3d, 0x7b, 0x8d, 0x0f, 0x81, 0x13, 0x65, 0x56, 0x5f, 0xd9, 0x8c,
-            0x4c, 0x8e, 0xb9, 0x36, 0xbc, 0xab, 0x8d, 0x06, 0x9f, 0xc3, 0x3b, 0xd8, 0x01, 0xb0,
-            0x3a, 0xde, 0xa2, 0xe1, 0xfb, 0xc5, 0xaa, 0x46, 0x3d, 0x08, 0xca, 0x19, 0x89, 0x6d,
-            0x2b, 0xf5, 0x9a, 0x07, 0x1b, 0x85, 0x1e, 0x6c, 0x23, 0x90, 0x52, 0x17, 0x2f, 0x29,
-            0x6b, 0xfb, 0x5e, 0x72, 0x40, 0x47, 0x90, 0xa2, 0x18, 0x10, 0x14, 0xf3, 0xb9, 0x4a,
-            0x4e, 0x97, 0xd1, 0x17, 0xb4, 0x38, 0x13, 0x03, 0x68, 0xcc, 0x39, 0xdb, 0xb2, 0xd1,
-            0x98, 0x06, 0x5a, 0xe3, 0x98, 0x65, 0x47, 0x92, 0x6c, 0xd2, 0x16, 0x2f, 0x40, 0xa2,
-            0x9f, 0x0c, 0x3c, 0x87, 0x45, 0xc0, 0xf5, 0x0f, 0xba, 0x38, 0x52, 0xe5, 0x66, 0xd4,
-            0x45, 0x75, 0xc2, 0x9d, 0x39, 0xa0, 0x3f, 0x0c, 0xda, 0x72, 0x19, 0x84, 0xb6, 0xf4,
-            0x40, 0x59, 0x1f, 0x35, 0x5e, 0x12, 0xd4, 0x39, 0xff, 0x15, 0x0a, 0xab, 0x76, 0x13,
-            0x49, 0x9d, 0xbd, 0x49, 0xad, 0xab, 0xc8, 0x67, 0x6e, 0xef, 0x02, 0x3b, 0x15, 0xb6,
-            0x5b, 0xfc, 0x5c, 0xa0, 0x69, 0x48, 0x10, 0x9f, 0x23, 0xf3, 0x50, 0xdb, 0x82, 0x12,
-            0x35, 0x35, 0xeb, 0x8a, 0x74, 0x33, 0xbd, 0xab, 0xcb, 0x90, 0x92, 0x71, 0xa6, 0xec,
-            0xbc, 0xb5, 0x8b, 0x93, 0x6a, 0x88, 0xcd, 0x4e, 0x8f, 0x2e, 0x6f, 0xf5, 0x80, 0x01,
-            0x75, 0xf1, 0x13, 0x25, 0x3d, 0x8f, 0xa9, 0xca, 0x88, 0x85, 0xc2, 0xf5, 0x52, 0xe6,
-            0x57, 0xdc, 0x60, 0x3f, 0x25, 0x2e, 0x1a, 0x8e, 0x30, 0x8f, 0x76, 0xf0, 0xbe, 0x79,
-            0xe2, 0xfb, 0x8f, 0x5d, 0x5f, 0xbb, 0xe2, 0xe3, 0x0e, 0xca, 0xdd, 0x22, 0x07, 0x23,
-            0xc8, 0xc0, 0xae, 0xa8, 0x07, 0x8c, 0xdf, 0xcb, 0x38, 0x68, 0x26, 0x3f, 0xf8, 0xf0,
-            0x94, 0x00, 0x54, 0xda, 0x48, 0x78, 0x18, 0x93, 0xa7, 0xe4, 0x9a, 0xd5, 0xaf, 0xf4,
-            0xaf, 0x30, 0x0c, 0xd8, 0x04, 0xa6, 0xb6, 0x27, 0x9a, 0xb3, 0xff, 0x3a, 0xfb, 0x64,
-            0x49, 0x1c, 0x85, 0x19, 0x4a, 0xab, 0x76, 0x0d, 0x58, 0xa6, 0x06, 0x65, 0x4f, 0x9f,
-            0x44, 0x00, 0xe8, 0xb3, 0x85, 0x91, 0x35, 0x6f, 0xbf, 0x64, 0x25, 0xac, 0xa2, 0x6d,
-            0xc8, 0x52, 0x44, 0x25, 0x9f, 0xf2, 0xb1, 0x9c, 0x41, 0xb9, 0xf9, 0x6f, 0x3c, 0xa9,
-            0xec, 0x1d, 0xde, 0x43, 0x4d, 0xa7, 0xd2, 0xd3, 0x92, 0xb9, 0x05, 0xdd, 0xf3, 0xd1,
-            0xf9, 0xaf, 0x93, 0xd1, 0xaf, 0x59, 0x50, 0xbd, 0x49, 0x3f, 0x5a, 0xa7, 0x31, 0xb4,
-            0x05, 0x6d, 0xf3, 0x1b, 0xd2, 0x67, 0xb6, 0xb9, 0x0a, 0x07, 0x98, 0x31, 0xaa, 0xf5,
-            0x79, 0xbe, 0x0a, 0x39, 0x01, 0x31, 0x37, 0xaa, 0xc6, 0xd4, 0x04, 0xf5, 0x18, 0xcf,
-            0xd4, 0x68, 0x40, 0x64, 0x7e, 0x78, 0xbf, 0xe7, 0x06, 0xca, 0x4c, 0xf5, 0xe9, 0xc5,
-            0x45, 0x3e, 0x9f, 0x7c, 0xfd, 0x2b, 0x8b, 0x4c, 0x8d, 0x16, 0x9a, 0x44, 0xe5, 0x5c,
-            0x88, 0xd4, 0xa9, 0xa7, 0xf9, 0x47, 0x42, 0x41, 0xe2, 0x21, 0xaf, 0x44, 0x86, 0x00,
-            0x18, 0xab, 0x08, 0x56, 0x97, 0x2e, 0x19, 0x4c, 0xd9, 0x34,
-        ];
+        let mut client_config = make_client_config(kt);
+        client_config.enable_secret_extraction = client_enable;
 
-        assert_eq!(&buf, PROTECTED);
+        let client_config = Arc::new(client_config);
 
-        let (header, payload) = buf.split_at_mut(header_len);
-        let (first, rest) = header.split_at_mut(1);
-        let sample = &payload[..sample_len];
+        let (client, server) = make_pair_for_arc_configs(&client_config, &server_config);
 
-        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Server);
-        server_keys
-            .remote
-            .header
-            .decrypt_in_place(sample, &mut first[0], &mut rest[17..21])
-            .unwrap();
-        let payload = server_keys
-            .remote
-            .packet
-            .decrypt_in_place(PACKET_NUMBER, &*header, payload)
-            .unwrap();
+        assert!(
+            client.extract_secrets().is_err(),
+            "extraction should fail until handshake completes"
+        );
+        assert!(
+            server.extract_secrets().is_err(),
+            "extraction should fail until handshake completes"
+        );
 
-        assert_eq!(&payload[..PAYLOAD.len()], PAYLOAD);
-        assert_eq!(payload.len(), buf.len() - header_len - tag_len);
-    }
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
 
-    #[test]
-    fn test_quic_exporter() {
-        for &kt in ALL_KEY_TYPES.iter() {
-            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-            let server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+        do_handshake(&mut client, &mut server);
 
-            do_exporter_test(client_config, server_config);
-        }
+        assert_eq!(server_enable, server.extract_secrets().is_ok());
+        assert_eq!(client_enable, client.extract_secrets().is_ok());
     }
-} // mod test_quic
+}
 
+#[cfg(feature = "dangerous_configuration")]
 #[test]
-fn test_client_does_not_offer_sha1() {
-    use rustls::internal::msgs::{
-        codec::Reader, handshake::HandshakePayload, message::MessagePayload, message::OpaqueMessage,
-    };
-    use rustls::HandshakeType;
+fn exported_connections_carry_on_after_import() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let mut client_config = finish_client_config(
+            kt,
+            ClientConfig::<Ring>::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+        client_config.alpn_protocols = vec![b"proto".to_vec()];
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config(kt);
+        server_config.alpn_protocols = vec![b"proto".to_vec()];
+        let server_config = Arc::new(server_config);
 
-    for kt in ALL_KEY_TYPES.iter() {
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let (mut client, _) = make_pair_for_configs(client_config, make_server_config(*kt));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
 
-            assert!(client.wants_write());
-            let mut buf = [0u8; 262144];
-            let sz = client
-                .write_tls(&mut buf.as_mut())
-                .unwrap();
-            let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
-            let msg = Message::try_from(msg.into_plain_message()).unwrap();
-            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+        // Leave plaintext unread by the client, and TLS data unprocessed
+        // by the server.
+        server
+            .writer()
+            .write_all(b"from server")
+            .unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        client
+            .writer()
+            .write_all(b"from client")
+            .unwrap();
+        transfer(&mut client, &mut server);
 
-            let client_hello = match msg.payload {
-                MessagePayload::Handshake { parsed, .. } => match parsed.payload {
-                    HandshakePayload::ClientHello(ch) => ch,
-                    _ => unreachable!(),
-                },
-                _ => unreachable!(),
-            };
+        let mut client_keying_material = [0u8; 32];
+        client
+            .export_keying_material(&mut client_keying_material, b"label", None)
+            .unwrap();
 
-            let sigalgs = client_hello
-                .get_sigalgs_extension()
-                .unwrap();
-            assert!(
-                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
-                "sha1 unexpectedly offered"
-            );
-        }
+        let client_export = rustls::Connection::Client(client)
+            .dangerous_export()
+            .unwrap();
+        let server_export = server.dangerous_export().unwrap();
+        let mut client =
+            ClientConnection::dangerous_import(Arc::clone(&client_config), &client_export).unwrap();
+        let mut server =
+            ServerConnection::dangerous_import(Arc::clone(&server_config), &server_export).unwrap();
+
+        assert!(!client.is_handshaking());
+        assert_eq!(client.negotiated_cipher_suite(), Some(scs));
+        assert_eq!(client.alpn_protocol(), Some(&b"proto"[..]));
+        assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+        assert_eq!(server.server_name(), Some("localhost"));
+        assert_eq!(client.extended_master_secret(), Some(true));
+        assert_eq!(server.extended_master_secret(), Some(true));
+
+        let mut server_keying_material = [0u8; 32];
+        server
+            .export_keying_material(&mut server_keying_material, b"label", None)
+            .unwrap();
+        assert_eq!(client_keying_material, server_keying_material);
+
+        check_read(&mut client.reader(), b"from server");
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"from client");
+
+        server
+            .writer()
+            .write_all(b"more")
+            .unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"more");
+
+        client.send_close_notify();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read_and_close(&mut server.reader(), b"");
     }
 }
 
+#[cfg(feature = "dangerous_configuration")]
 #[test]
-fn test_client_config_keyshare() {
-    let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+fn connections_cannot_be_exported_during_handshake() {
+    let (client, server) = make_pair(KeyType::Rsa);
+    assert_eq!(client.dangerous_export(), Err(Error::HandshakeNotComplete));
+    assert_eq!(server.dangerous_export(), Err(Error::HandshakeNotComplete));
 }
 
+#[cfg(feature = "dangerous_configuration")]
 #[test]
-fn test_client_config_keyshare_mismatch() {
-    let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
-}
+fn exported_connections_are_imported_on_the_same_side() {
+    let kt = KeyType::Rsa;
+    let (mut client, mut server) = make_pair(kt);
+    do_handshake(&mut client, &mut server);
 
-#[cfg(feature = "tls12")]
-#[test]
-fn test_client_sends_helloretryrequest() {
-    // client sends a secp384r1 key share
-    let mut client_config = make_client_config_with_kx_groups(
-        KeyType::Rsa,
-        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    let client_export = client.dangerous_export().unwrap();
+    let server_export = server.dangerous_export().unwrap();
+    assert!(
+        ServerConnection::dangerous_import(Arc::new(make_server_config(kt)), &client_export)
+            .is_err()
     );
+    assert!(
+        ClientConnection::dangerous_import(Arc::new(make_client_config(kt)), &server_export)
+            .is_err()
+    );
+    assert!(ClientConnection::dangerous_import(
+        Arc::new(make_client_config(kt)),
+        &client_export[..client_export.len() - 1]
+    )
+    .is_err());
+}
 
-    let storage = Arc::new(ClientStorage::new());
-    client_config.resumption = Resumption::store(storage.clone());
-
-    // but server only accepts x25519, so a HRR is required
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+#[test]
+fn test_received_plaintext_backpressure() {
+    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
+    let kt = KeyType::Rsa;
 
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    let server_config = Arc::new(
+        ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[suite])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(kt.get_chain(), kt.get_key())
+            .unwrap(),
+    );
 
-    // client sends hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 1);
-    }
+    let client_config = Arc::new(make_client_config(kt));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
 
-    // server sends HRR
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen < 100); // just the hello retry request
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+    // Fill the server's received plaintext buffer with 16k bytes
+    let client_buf = [0; 16_385];
+    dbg!(client
+        .writer()
+        .write(&client_buf)
+        .unwrap());
+    let mut network_buf = Vec::with_capacity(32_768);
+    let sent = dbg!(client
+        .write_tls(&mut network_buf)
+        .unwrap());
+    let mut read = 0;
+    while read < sent {
+        let new = dbg!(server
+            .read_tls(&mut &network_buf[read..sent])
+            .unwrap());
+        if new == 4096 {
+            read += new;
+        } else {
+            break;
+        }
     }
+    server.process_new_packets().unwrap();
 
-    // client sends fixed hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200); // just the client hello retry
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
-    }
+    // Send two more bytes from client to server
+    dbg!(client
+        .writer()
+        .write(&client_buf[..2])
+        .unwrap());
+    let sent = dbg!(client
+        .write_tls(&mut network_buf)
+        .unwrap());
 
-    // server completes handshake
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
-    }
+    // Get an error because the received plaintext buffer is full
+    assert!(server
+        .read_tls(&mut &network_buf[..sent])
+        .is_err());
 
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+    // Read out some of the plaintext
+    server
+        .reader()
+        .read_exact(&mut [0; 2])
+        .unwrap();
 
-    // client only did following storage queries:
-    println!("storage {:#?}", storage.ops());
-    assert_eq!(storage.ops().len(), 9);
-    assert!(matches!(
-        storage.ops()[0],
-        ClientStorageOp::TakeTls13Ticket(_, false)
-    ));
-    assert!(matches!(
-        storage.ops()[1],
-        ClientStorageOp::GetTls12Session(_, false)
-    ));
-    assert!(matches!(
-        storage.ops()[2],
-        ClientStorageOp::GetKxHint(_, None)
-    ));
-    assert!(matches!(
-        storage.ops()[3],
-        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::X25519)
-    ));
-    assert!(matches!(
-        storage.ops()[4],
-        ClientStorageOp::RemoveTls12Session(_)
-    ));
-    // server sends 4 tickets by default
-    assert!(matches!(
-        storage.ops()[5],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
-    assert!(matches!(
-        storage.ops()[6],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
-    assert!(matches!(
-        storage.ops()[7],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
-    assert!(matches!(
-        storage.ops()[8],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
+    // Now there's room again in the plaintext buffer
+    assert_eq!(
+        server
+            .read_tls(&mut &network_buf[..sent])
+            .unwrap(),
+        24
+    );
 }
 
 #[test]
-fn test_client_rejects_hrr_with_varied_session_id() {
-    use rustls::internal::msgs::handshake::SessionId;
-    let different_session_id = SessionId::random::<Ring>().unwrap();
-
-    let assert_client_sends_hello_with_secp384 = |msg: &mut Message| -> Altered {
-        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
-                let keyshares = ch
-                    .get_keyshare_extension()
-                    .expect("missing key share extension");
-                assert_eq!(keyshares.len(), 1);
-                assert_eq!(keyshares[0].group, rustls::NamedGroup::secp384r1);
+fn test_debug_server_name_from_ip() {
+    assert_eq!(
+        format!(
+            "{:?}",
+            rustls::ServerName::IpAddress("127.0.0.1".parse().unwrap())
+        ),
+        "IpAddress(127.0.0.1)"
+    )
+}
 
-                ch.session_id = different_session_id;
-                *encoded = Payload::new(parsed.get_encoding());
-            }
-        }
-        Altered::InPlace
-    };
+#[test]
+fn test_debug_server_name_from_string() {
+    assert_eq!(
+        format!("{:?}", rustls::ServerName::try_from("a.com").unwrap()),
+        "DnsName(\"a.com\")"
+    )
+}
 
-    let assert_server_requests_retry_and_echoes_session_id = |msg: &mut Message| -> Altered {
-        if let MessagePayload::Handshake { parsed, .. } = &mut msg.payload {
-            if let HandshakePayload::HelloRetryRequest(hrr) = &mut parsed.payload {
-                let group = hrr.get_requested_key_share_group();
-                assert_eq!(group, Some(rustls::NamedGroup::X25519));
+#[cfg(feature = "deterministic_testing")]
+#[test]
+fn test_deterministic_provider_repeats_hellos() {
+    use rustls::crypto::deterministic::{Deterministic, FixedTimeProvider};
 
-                assert_eq!(hrr.session_id, different_session_id);
-            }
-        }
-        Altered::InPlace
-    };
+    let now = Arc::new(FixedTimeProvider(
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+    ));
 
-    // client prefers a secp384r1 key share, server only accepts x25519
-    let client_config = make_client_config_with_kx_groups(
-        KeyType::Rsa,
-        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    let kt = KeyType::Ecdsa;
+    let mut client_config = finish_client_config(
+        kt,
+        ClientConfig::<Deterministic>::builder().with_safe_defaults(),
+    );
+    client_config.time_provider = now.clone();
+    let client_config = Arc::new(client_config);
+    let mut server_config = finish_server_config(
+        kt,
+        ServerConfig::<Deterministic>::builder().with_safe_defaults(),
     );
+    server_config.time_provider = now;
+    let server_config = Arc::new(server_config);
 
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let hellos = || {
+        Deterministic::reseed(b"test_deterministic_provider_repeats_hellos");
+        let mut client =
+            ClientConnection::new(Arc::clone(&client_config), server_name("localhost")).unwrap();
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
 
-    let (client, server) = make_pair_for_configs(client_config, server_config);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(
-        &mut client,
-        assert_client_sends_hello_with_secp384,
-        &mut server,
-    );
-    server.process_new_packets().unwrap();
-    transfer_altered(
-        &mut server,
-        assert_server_requests_retry_and_echoes_session_id,
-        &mut client,
-    );
-    assert_eq!(
-        client.process_new_packets(),
-        Err(Error::PeerMisbehaved(
-            PeerMisbehaved::IllegalHelloRetryRequestWithWrongSessionId
-        ))
-    );
-}
+        let mut client_hello = Vec::new();
+        client
+            .write_tls(&mut client_hello)
+            .unwrap();
+        server
+            .read_tls(&mut &client_hello[..])
+            .unwrap();
+        server.process_new_packets().unwrap();
 
-#[cfg(feature = "tls12")]
-#[test]
-fn test_client_attempts_to_use_unsupported_kx_group() {
-    // common to both client configs
-    let shared_storage = Arc::new(ClientStorage::new());
+        let mut server_flight = Vec::new();
+        server
+            .write_tls(&mut server_flight)
+            .unwrap();
 
-    // first, client sends a x25519 and server agrees. x25519 is inserted
-    //   into kx group cache.
-    let mut client_config_1 =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
-    client_config_1.resumption = Resumption::store(shared_storage.clone());
+        // Only the ServerHello record: the server's signature uses OS randomness.
+        let len = u16::from_be_bytes([server_flight[3], server_flight[4]]) as usize;
+        server_flight.truncate(5 + len);
+        (client_hello, server_flight)
+    };
 
-    // second, client only supports secp-384 and so kx group cache
-    //   contains an unusable value.
-    let mut client_config_2 =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    client_config_2.resumption = Resumption::store(shared_storage.clone());
+    let (client_hello, server_hello) = hellos();
+    assert_eq!(server_hello[0], 0x16);
+    assert_eq!(hellos(), (client_hello, server_hello));
+}
 
-    let server_config = make_server_config(KeyType::Rsa);
+fn captured_types(
+    conn: &ConnectionCommon<impl SideData>,
+    direction: rustls::MessageDirection,
+) -> Vec<HandshakeType> {
+    conn.handshake_capture()
+        .unwrap()
+        .iter()
+        .filter(|msg| msg.direction() == direction)
+        .map(|msg| msg.typ())
+        .collect()
+}
 
-    // first handshake
-    let (mut client_1, mut server) = make_pair_for_configs(client_config_1, server_config.clone());
-    do_handshake_until_error(&mut client_1, &mut server).unwrap();
+#[test]
+fn test_handshake_capture() {
+    use rustls::MessageDirection::{Received, Sent};
 
-    let ops = shared_storage.ops();
-    println!("storage {:#?}", ops);
-    assert_eq!(ops.len(), 9);
-    assert!(matches!(
-        ops[3],
-        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::X25519)
-    ));
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.capture_handshake = true;
+        let mut server_config = make_server_config(*kt);
+        server_config.capture_handshake = true;
 
-    // second handshake
-    let (mut client_2, mut server) = make_pair_for_configs(client_config_2, server_config);
-    do_handshake_until_error(&mut client_2, &mut server).unwrap();
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
 
-    let ops = shared_storage.ops();
-    println!("storage {:?} {:#?}", ops.len(), ops);
-    assert_eq!(ops.len(), 17);
-    assert!(matches!(ops[9], ClientStorageOp::TakeTls13Ticket(_, true)));
-    assert!(matches!(
-        ops[10],
-        ClientStorageOp::GetKxHint(_, Some(rustls::NamedGroup::X25519))
-    ));
-    assert!(matches!(
-        ops[11],
-        ClientStorageOp::SetKxHint(_, rustls::NamedGroup::secp384r1)
-    ));
+        let client_sent = captured_types(&client, Sent);
+        assert_eq!(
+            client_sent,
+            vec![HandshakeType::ClientHello, HandshakeType::Finished]
+        );
+        assert_eq!(client_sent, captured_types(&server, Received));
+        assert_eq!(
+            captured_types(&client, Received),
+            captured_types(&server, Sent)
+        );
+
+        let client_capture = client.handshake_capture().unwrap();
+        let server_capture = server.handshake_capture().unwrap();
+        assert_eq!(client_capture[0].bytes(), server_capture[0].bytes());
+        assert_eq!(client_capture[0].bytes()[0], 0x01);
+        assert!(client_capture[0].time().is_some());
+    }
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn test_tls13_client_resumption_does_not_reuse_tickets() {
-    let shared_storage = Arc::new(ClientStorage::new());
-
-    let mut client_config = make_client_config(KeyType::Rsa);
-    client_config.resumption = Resumption::store(shared_storage.clone());
-    let client_config = Arc::new(client_config);
+fn test_handshake_capture_is_off_by_default() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+    assert!(client.handshake_capture().is_none());
+    assert!(server.handshake_capture().is_none());
+}
 
-    let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.send_tls13_tickets = 5;
-    let server_config = Arc::new(server_config);
+#[test]
+fn test_handshake_capture_survives_failure() {
+    use rustls::MessageDirection::{Received, Sent};
 
-    // first handshake: client obtains 5 tickets from server.
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.capture_handshake = true;
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Ecdsa));
 
-    let ops = shared_storage.ops_and_reset();
-    println!("storage {:#?}", ops);
-    assert_eq!(ops.len(), 10);
-    assert!(matches!(ops[5], ClientStorageOp::InsertTls13Ticket(_)));
-    assert!(matches!(ops[6], ClientStorageOp::InsertTls13Ticket(_)));
-    assert!(matches!(ops[7], ClientStorageOp::InsertTls13Ticket(_)));
-    assert!(matches!(ops[8], ClientStorageOp::InsertTls13Ticket(_)));
-    assert!(matches!(ops[9], ClientStorageOp::InsertTls13Ticket(_)));
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+    assert_eq!(
+        captured_types(&client, Sent),
+        vec![HandshakeType::ClientHello]
+    );
+    assert_eq!(
+        captured_types(&client, Received),
+        vec![
+            HandshakeType::ServerHello,
+            HandshakeType::EncryptedExtensions,
+            HandshakeType::Certificate,
+            HandshakeType::CertificateVerify,
+        ]
+    );
+}
 
-    // 5 subsequent handshakes: all are resumptions
+#[test]
+fn test_server_report() {
+    let ocsp = include_bytes!("../src/testdata/ocsp-good.der");
+    let kt = KeyType::Ecdsa;
+    let mut server_config = ServerConfig::<Ring>::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert_with_ocsp(kt.get_chain(), kt.get_key(), ocsp.to_vec())
+        .unwrap();
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    let server_config = Arc::new(server_config);
 
-    // Note: we don't do complete the handshakes, because that means
-    // we get five additional tickets per connection which is unhelpful
-    // in this test.  It also acts to record a "Happy Eyeballs"-type use
-    // case, where a client speculatively makes many connection attempts
-    // in parallel without knowledge of which will work due to underlying
-    // connectivity uncertainty.
-    for _ in 0..5 {
-        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-        transfer(&mut client, &mut server);
-        server.process_new_packets().unwrap();
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config.capture_handshake = true;
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec(), b"h2".to_vec()];
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
 
-        let ops = shared_storage.ops_and_reset();
-        assert!(matches!(ops[0], ClientStorageOp::TakeTls13Ticket(_, true)));
+        let report = client.server_report().unwrap();
+        assert_eq!(report.version, Some(version.version));
+        assert_eq!(
+            report.cipher_suite,
+            client
+                .negotiated_cipher_suite()
+                .map(|suite| suite.suite())
+        );
+        assert_eq!(report.kx_group, Some(rustls::NamedGroup::X25519));
+        assert_eq!(report.hello_retry_group, None);
+        assert_eq!(report.alpn_protocol, Some(b"h2".to_vec()));
+        assert_eq!(report.cert_chain, kt.get_chain());
+        assert_eq!(report.ocsp_response, Some(ocsp.to_vec()));
+        assert!(report.sct_list.is_empty());
+        assert!(!report.client_auth_requested);
     }
 
-    // 6th subsequent handshake: cannot be resumed; we ran out of tickets
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-
-    let ops = shared_storage.ops_and_reset();
-    println!("last {:?}", ops);
-    assert!(matches!(ops[0], ClientStorageOp::TakeTls13Ticket(_, false)));
+    let (client, _) = make_pair(kt);
+    assert!(client.server_report().is_none());
 }
 
 #[test]
-fn test_client_mtu_reduction() {
-    struct CollectWrites {
-        writevs: Vec<Vec<usize>>,
-    }
+fn test_server_report_survives_verification_failure() {
+    let mut client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    client_config.capture_handshake = true;
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Ecdsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    impl io::Write for CollectWrites {
-        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-            panic!()
-        }
-        fn flush(&mut self) -> io::Result<()> {
-            panic!()
-        }
-        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-            let writes = b
-                .iter()
-                .map(|slice| slice.len())
-                .collect::<Vec<usize>>();
-            let len = writes.iter().sum();
-            self.writevs.push(writes);
-            Ok(len)
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+    let report = client.server_report().unwrap();
+    assert_eq!(report.version, Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(report.hello_retry_group, Some(rustls::NamedGroup::X25519));
+    assert_eq!(report.kx_group, Some(rustls::NamedGroup::X25519));
+    assert_eq!(report.cert_chain, KeyType::Ecdsa.get_chain());
+}
+
+/// Like `do_handshake`, but runs any verification the client offloads on
+/// another thread.
+fn do_handshake_with_offload(
+    client: &mut ClientConnection,
+    server: &mut ServerConnection,
+) -> Result<usize, Error> {
+    let mut offloaded = 0;
+    while server.is_handshaking() || client.is_handshaking() {
+        transfer(client, server);
+        server.process_new_packets()?;
+        transfer(server, client);
+        client.process_new_packets()?;
+
+        if let Some(pending) = client.take_pending_verification() {
+            assert!(client
+                .take_pending_verification()
+                .is_none());
+            let completed = std::thread::spawn(move || pending.verify())
+                .join()
+                .unwrap();
+            client.complete_verification(completed)?;
+            offloaded += 1;
         }
     }
+    Ok(offloaded)
+}
 
-    fn collect_write_lengths(client: &mut ClientConnection) -> Vec<usize> {
-        let mut collector = CollectWrites { writevs: vec![] };
+#[test]
+fn test_client_verification_can_be_offloaded() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.offload_verification = true;
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(*kt));
 
-        client
-            .write_tls(&mut collector)
-            .unwrap();
-        assert_eq!(collector.writevs.len(), 1);
-        collector.writevs[0].clone()
+            assert!(client
+                .take_pending_verification()
+                .is_none());
+            assert_eq!(do_handshake_with_offload(&mut client, &mut server), Ok(1));
+            assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+
+            client
+                .writer()
+                .write_all(b"hello")
+                .unwrap();
+            transfer(&mut client, &mut server);
+            server.process_new_packets().unwrap();
+            check_read(&mut server.reader(), b"hello");
+        }
     }
+}
 
-    for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        client_config.max_fragment_size = Some(64);
+#[test]
+fn test_client_verification_is_inline_by_default() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    assert_eq!(do_handshake_with_offload(&mut client, &mut server), Ok(0));
+}
+
+#[test]
+fn test_offloaded_verification_failure() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.offload_verification = true;
         let mut client =
-            ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap();
-        let writes = collect_write_lengths(&mut client);
-        println!("writes at mtu=64: {:?}", writes);
-        assert!(writes.iter().all(|x| *x <= 64));
-        assert!(writes.len() > 1);
+            ClientConnection::new(Arc::new(client_config), server_name("not-localhost")).unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+
+        let expected = Error::InvalidCertificate(CertificateError::NotValidForName);
+        assert_eq!(
+            do_handshake_with_offload(&mut client, &mut server),
+            Err(expected.clone())
+        );
+        assert!(client
+            .take_pending_verification()
+            .is_none());
+        assert_eq!(client.process_new_packets().err(), Some(expected));
+
+        transfer(&mut client, &mut server);
+        assert_eq!(
+            server.process_new_packets(),
+            Err(Error::AlertReceived(
+                rustls::AlertDescription::BadCertificate
+            ))
+        );
     }
 }
 
 #[test]
-fn test_server_mtu_reduction() {
-    let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.max_fragment_size = Some(64);
-    server_config.send_half_rtt_data = true;
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+fn test_offloaded_verification_belongs_to_its_connection() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.offload_verification = true;
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
 
-    let big_data = [0u8; 2048];
-    server
-        .writer()
-        .write_all(&big_data)
-        .unwrap();
+    let mut pending = Vec::new();
+    let mut pairs = Vec::new();
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        pending.push(
+            client
+                .take_pending_verification()
+                .unwrap(),
+        );
+        pairs.push((client, server));
+    }
 
-    let encryption_overhead = 20; // FIXME: see issue #991
+    let completed = pending.pop().unwrap().verify();
+    assert!(completed.error().is_none());
+    let (client, _) = &mut pairs[0];
+    assert_eq!(
+        client.complete_verification(completed),
+        Err(Error::General(
+            "verification completed for another connection".into()
+        ))
+    );
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        server.write_tls(&mut pipe).unwrap();
+#[test]
+fn test_key_shares_come_from_pool() {
+    use rustls::crypto::{ring, KeySharePool};
 
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0]
-            .iter()
-            .all(|x| *x <= 64 + encryption_overhead));
-    }
+    for version in rustls::ALL_VERSIONS {
+        let client_pool = KeySharePool::<ring::KeyExchange>::new(&[&ring::X25519], 2);
+        let server_pool = KeySharePool::<ring::KeyExchange>::new(&[&ring::X25519], 2);
+        assert_eq!(client_pool.refill().unwrap(), 2);
+        assert_eq!(server_pool.refill().unwrap(), 2);
 
-    client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        server.write_tls(&mut pipe).unwrap();
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0]
-            .iter()
-            .all(|x| *x <= 64 + encryption_overhead));
-    }
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.key_share_pool = Some(client_pool.clone());
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.key_share_pool = Some(server_pool.clone());
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
 
-    client.process_new_packets().unwrap();
-    check_read(&mut client.reader(), &big_data);
+        assert_eq!(client_pool.available(rustls::NamedGroup::X25519), 1);
+        assert_eq!(server_pool.available(rustls::NamedGroup::X25519), 1);
+    }
 }
 
-fn check_client_max_fragment_size(size: usize) -> Option<Error> {
-    let mut client_config = make_client_config(KeyType::Ed25519);
-    client_config.max_fragment_size = Some(size);
-    ClientConnection::new(Arc::new(client_config), server_name("localhost")).err()
+#[test]
+fn test_empty_key_share_pool_is_not_needed() {
+    use rustls::crypto::{ring, KeySharePool};
+
+    let pool = KeySharePool::<ring::KeyExchange>::new(&[&ring::X25519], 2);
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.key_share_pool = Some(pool.clone());
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.key_share_pool = Some(pool.clone());
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(pool.available(rustls::NamedGroup::X25519), 0);
 }
 
 #[test]
-fn bad_client_max_fragment_sizes() {
-    assert_eq!(
-        check_client_max_fragment_size(31),
-        Some(Error::BadMaxFragmentSize)
-    );
-    assert_eq!(check_client_max_fragment_size(32), None);
-    assert_eq!(check_client_max_fragment_size(64), None);
-    assert_eq!(check_client_max_fragment_size(1460), None);
-    assert_eq!(check_client_max_fragment_size(0x4000), None);
-    assert_eq!(check_client_max_fragment_size(0x4005), None);
-    assert_eq!(
-        check_client_max_fragment_size(0x4006),
-        Some(Error::BadMaxFragmentSize)
-    );
+fn test_handshake_capture_with_acceptor() {
+    use rustls::server::Acceptor;
+    use rustls::MessageDirection::Received;
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.capture_handshake = true;
+
+    let mut client = ClientConnection::new(
+        Arc::new(make_client_config(KeyType::Rsa)),
+        server_name("localhost"),
+    )
+    .unwrap();
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+
+    let mut acceptor = Acceptor::default();
+    acceptor
+        .read_tls(&mut buf.as_slice())
+        .unwrap();
+    let accepted = acceptor.accept().unwrap().unwrap();
+    let server = accepted
+        .into_connection(Arc::new(server_config))
+        .unwrap();
+
     assert_eq!(
-        check_client_max_fragment_size(0xffff),
-        Some(Error::BadMaxFragmentSize)
+        captured_types(&server, Received),
+        vec![HandshakeType::ClientHello]
     );
 }
 
-fn assert_lt(left: usize, right: usize) {
-    if left >= right {
-        panic!("expected {} < {}", left, right);
-    }
+#[cfg(feature = "tls12")]
+fn tls12_pair_with_record_limits(
+    limits: rustls::RecordLimits,
+) -> (ClientConnection, ServerConnection) {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_limits = limits;
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]),
+        server_config,
+    );
+    do_handshake(&mut client, &mut server);
+    (client, server)
 }
 
-#[test]
-fn connection_types_are_not_huge() {
-    // Arbitrary sizes
-    assert_lt(mem::size_of::<ServerConnection>(), 1600);
-    assert_lt(mem::size_of::<ClientConnection>(), 1600);
+#[cfg(feature = "tls12")]
+fn inject_record(
+    server: &mut ServerConnection,
+    typ: rustls::ContentType,
+    payload: &[u8],
+) -> Result<(), Error> {
+    use rustls::internal::injection::inject_message;
+    use rustls::internal::msgs::message::PlainMessage;
+
+    inject_message(
+        server,
+        PlainMessage {
+            typ,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(payload.to_vec()),
+        },
+    )
+    .map(|_| ())
 }
 
-use rustls::internal::msgs::{
-    handshake::ClientExtension, handshake::HandshakePayload, message::Message,
-    message::MessagePayload,
-};
+#[cfg(feature = "tls12")]
+const WARNING_NO_RENEGOTIATION: &[u8] = &[1, 100];
 
+#[cfg(feature = "tls12")]
 #[test]
-fn test_server_rejects_duplicate_sni_names() {
-    fn duplicate_sni_payload(msg: &mut Message) -> Altered {
-        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::ServerName(snr) = &mut ext {
-                        snr.push(snr[0].clone());
-                    }
-                }
-            }
-
-            *encoded = Payload::new(parsed.get_encoding());
-        }
-        Altered::InPlace
+fn test_records_are_unlimited_by_default() {
+    let (_, mut server) = tls12_pair_with_record_limits(rustls::RecordLimits::default());
+    for _ in 0..100 {
+        inject_record(
+            &mut server,
+            rustls::ContentType::Alert,
+            WARNING_NO_RENEGOTIATION,
+        )
+        .unwrap();
+        inject_record(&mut server, rustls::ContentType::ApplicationData, &[]).unwrap();
     }
+}
 
-    let (client, server) = make_pair(KeyType::Rsa);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, duplicate_sni_payload, &mut server);
+#[cfg(feature = "tls12")]
+#[test]
+fn test_warning_alert_limit() {
+    let (mut client, mut server) = tls12_pair_with_record_limits(rustls::RecordLimits::hardened());
+    for _ in 0..4 {
+        inject_record(
+            &mut server,
+            rustls::ContentType::Alert,
+            WARNING_NO_RENEGOTIATION,
+        )
+        .unwrap();
+        inject_record(&mut server, rustls::ContentType::ApplicationData, b"x").unwrap();
+    }
     assert_eq!(
-        server.process_new_packets(),
+        inject_record(
+            &mut server,
+            rustls::ContentType::Alert,
+            WARNING_NO_RENEGOTIATION
+        ),
         Err(Error::PeerMisbehaved(
-            PeerMisbehaved::DuplicateServerNameTypes
+            PeerMisbehaved::TooManyWarningAlertsReceived
+        ))
+    );
+
+    transfer(&mut server, &mut client);
+    assert_eq!(
+        client.process_new_packets(),
+        Err(Error::AlertReceived(
+            rustls::AlertDescription::UnexpectedMessage
         ))
     );
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn test_server_rejects_empty_sni_extension() {
-    fn empty_sni_payload(msg: &mut Message) -> Altered {
-        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::ServerName(snr) = &mut ext {
-                        snr.clear();
-                    }
-                }
-            }
-
-            *encoded = Payload::new(parsed.get_encoding());
-        }
-
-        Altered::InPlace
+fn test_empty_record_limit() {
+    let mut limits = rustls::RecordLimits::default();
+    limits.max_empty_records = Some(3);
+    let (_, mut server) = tls12_pair_with_record_limits(limits);
+    for _ in 0..3 {
+        inject_record(&mut server, rustls::ContentType::ApplicationData, &[]).unwrap();
+        inject_record(&mut server, rustls::ContentType::ApplicationData, b"x").unwrap();
     }
-
-    let (client, server) = make_pair(KeyType::Rsa);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, empty_sni_payload, &mut server);
     assert_eq!(
-        server.process_new_packets(),
-        Err(Error::PeerMisbehaved(
-            PeerMisbehaved::ServerNameMustContainOneHostName
-        ))
+        inject_record(&mut server, rustls::ContentType::ApplicationData, &[]),
+        Err(Error::PeerMisbehaved(PeerMisbehaved::TooManyEmptyRecords))
     );
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn test_server_rejects_clients_without_any_kx_group_overlap() {
-    fn different_kx_group(msg: &mut Message) -> Altered {
-        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::NamedGroups(ngs) = &mut ext {
-                        ngs.clear();
-                    }
-                    if let ClientExtension::KeyShare(ks) = &mut ext {
-                        ks.clear();
-                    }
-                }
-            }
-
-            *encoded = Payload::new(parsed.get_encoding());
-        }
-        Altered::InPlace
+fn test_consecutive_non_advancing_record_limit() {
+    let mut limits = rustls::RecordLimits::default();
+    limits.max_consecutive_non_advancing = Some(3);
+    let (_, mut server) = tls12_pair_with_record_limits(limits);
+    for _ in 0..10 {
+        inject_record(&mut server, rustls::ContentType::ApplicationData, &[]).unwrap();
+        inject_record(
+            &mut server,
+            rustls::ContentType::Alert,
+            WARNING_NO_RENEGOTIATION,
+        )
+        .unwrap();
+        inject_record(&mut server, rustls::ContentType::ApplicationData, &[]).unwrap();
+        inject_record(&mut server, rustls::ContentType::ApplicationData, b"x").unwrap();
     }
 
-    let (client, server) = make_pair(KeyType::Rsa);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, different_kx_group, &mut server);
+    for _ in 0..3 {
+        inject_record(&mut server, rustls::ContentType::ApplicationData, &[]).unwrap();
+    }
     assert_eq!(
-        server.process_new_packets(),
-        Err(Error::PeerIncompatible(
-            PeerIncompatible::NoKxGroupsInCommon
+        inject_record(
+            &mut server,
+            rustls::ContentType::Alert,
+            WARNING_NO_RENEGOTIATION
+        ),
+        Err(Error::PeerMisbehaved(
+            PeerMisbehaved::TooManyRecordsWithoutProgress
         ))
     );
 }
 
 #[test]
-fn test_client_rejects_illegal_tls13_ccs() {
-    fn corrupt_ccs(msg: &mut Message) -> Altered {
-        if let MessagePayload::ChangeCipherSpec(_) = &mut msg.payload {
-            println!("seen CCS {:?}", msg);
-            return Altered::Raw(vec![0x14, 0x03, 0x03, 0x00, 0x02, 0x01, 0x02]);
-        }
-        Altered::InPlace
-    }
+fn test_memory_budget_tracks_connections() {
+    let budget = rustls::MemoryBudget::new(1 << 20);
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.memory_budget = Some(budget.clone());
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.memory_budget = Some(budget.clone());
 
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(budget.connections(), 2);
+    let initial = budget.used();
+    assert!(initial > 0);
 
-    let (mut server, mut client) = (server.into(), client.into());
+    do_handshake(&mut client, &mut server);
+    client
+        .writer()
+        .write_all(&[0u8; 1000])
+        .unwrap();
+    client
+        .write_tls(&mut io::sink())
+        .unwrap();
+    assert!(budget.used() > initial);
 
-    transfer_altered(&mut server, corrupt_ccs, &mut client);
-    assert_eq!(
-        client.process_new_packets(),
-        Err(Error::PeerMisbehaved(
-            PeerMisbehaved::IllegalMiddleboxChangeCipherSpec
-        ))
-    );
+    drop(client);
+    drop(server);
+    assert_eq!(budget.used(), 0);
+    assert_eq!(budget.connections(), 0);
 }
 
-/// https://github.com/rustls/rustls/issues/797
-#[cfg(feature = "tls12")]
 #[test]
-fn test_client_tls12_no_resume_after_server_downgrade() {
-    let mut client_config = common::make_client_config(KeyType::Ed25519);
-    let client_storage = Arc::new(ClientStorage::new());
-    client_config.resumption = Resumption::store(client_storage.clone());
-    let client_config = Arc::new(client_config);
+fn test_memory_budget_refuses_new_connections() {
+    let budget = rustls::MemoryBudget::new(1);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.memory_budget = Some(budget.clone());
+    let server_config = Arc::new(server_config);
 
-    let server_config_1 = Arc::new(common::finish_server_config(
-        KeyType::Ed25519,
-        ServerConfig::<Ring>::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(&[&rustls::version::TLS13])
-            .unwrap(),
-    ));
+    let first = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    assert_eq!(
+        ServerConnection::new(Arc::clone(&server_config)).err(),
+        Some(Error::MemoryBudgetExceeded)
+    );
 
-    let mut server_config_2 = common::finish_server_config(
-        KeyType::Ed25519,
-        ServerConfig::<Ring>::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(&[&rustls::version::TLS12])
-            .unwrap(),
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.memory_budget = Some(budget.clone());
+    assert_eq!(
+        ClientConnection::new(Arc::new(client_config), server_name("localhost")).err(),
+        Some(Error::MemoryBudgetExceeded)
     );
-    server_config_2.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
 
-    dbg!("handshake 1");
-    let mut client_1 =
-        ClientConnection::new(client_config.clone(), "localhost".try_into().unwrap()).unwrap();
-    let mut server_1 = ServerConnection::new(server_config_1).unwrap();
-    common::do_handshake(&mut client_1, &mut server_1);
+    drop(first);
+    assert!(ServerConnection::new(server_config).is_ok());
+}
 
-    assert_eq!(client_storage.ops().len(), 9);
-    println!("hs1 storage ops: {:#?}", client_storage.ops());
-    assert!(matches!(
-        client_storage.ops()[3],
-        ClientStorageOp::SetKxHint(_, _)
-    ));
-    assert!(matches!(
-        client_storage.ops()[4],
-        ClientStorageOp::RemoveTls12Session(_)
-    ));
-    assert!(matches!(
-        client_storage.ops()[5],
-        ClientStorageOp::InsertTls13Ticket(_)
-    ));
+#[test]
+fn test_memory_budget_sheds_idle_buffers() {
+    let plenty = rustls::MemoryBudget::new(1 << 20);
+    let scarce = rustls::MemoryBudget::new(1);
+    let mut used = Vec::new();
+
+    for budget in [&plenty, &scarce] {
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.memory_budget = Some(Arc::clone(budget));
+        let (mut client, mut server) =
+            make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+        do_handshake(&mut client, &mut server);
+        used.push(budget.used());
+    }
 
-    dbg!("handshake 2");
-    let mut client_2 =
-        ClientConnection::new(client_config, "localhost".try_into().unwrap()).unwrap();
-    let mut server_2 = ServerConnection::new(Arc::new(server_config_2)).unwrap();
-    common::do_handshake(&mut client_2, &mut server_2);
-    println!("hs2 storage ops: {:#?}", client_storage.ops());
-    assert_eq!(client_storage.ops().len(), 11);
+    assert!(used[1] < used[0]);
+}
 
-    // attempt consumes a TLS1.3 ticket
-    assert!(matches!(
-        client_storage.ops()[9],
-        ClientStorageOp::TakeTls13Ticket(_, true)
-    ));
+struct FixedPacer {
+    allowance: usize,
+    stalls: AtomicUsize,
+}
+
+impl rustls::WritePacer for FixedPacer {
+    fn write_allowance(&self) -> Option<usize> {
+        Some(self.allowance)
+    }
+
+    fn peer_stalled(&self, _pending: usize) {
+        self.stalls
+            .fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_write_pacer_limits_data_per_write_tls() {
+    let pacer = Arc::new(FixedPacer {
+        allowance: 10,
+        stalls: AtomicUsize::new(0),
+    });
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.write_pacer = Some(pacer.clone());
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
 
-    // but ends up with TLS1.2
     assert_eq!(
-        client_2.protocol_version(),
-        Some(rustls::ProtocolVersion::TLSv1_2)
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        10
+    );
+    assert_eq!(client.writer().write(b"0123").unwrap(), 0);
+
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        client
+            .writer()
+            .write(b"0123456789")
+            .unwrap(),
+        10
     );
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"01234567890123456789");
+    assert_eq!(pacer.stalls.load(Ordering::SeqCst), 0);
 }
 
 #[test]
-fn test_acceptor() {
-    use rustls::server::Acceptor;
-
-    let client_config = Arc::new(make_client_config(KeyType::Ed25519));
-    let mut client = ClientConnection::new(client_config, server_name("localhost")).unwrap();
-    let mut buf = Vec::new();
-    client.write_tls(&mut buf).unwrap();
+fn test_write_pacer_hears_of_stalled_peer() {
+    let pacer = Arc::new(FixedPacer {
+        allowance: 10,
+        stalls: AtomicUsize::new(0),
+    });
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.write_pacer = Some(pacer.clone());
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+    do_handshake(&mut client, &mut server);
 
-    let server_config = Arc::new(make_server_config(KeyType::Ed25519));
-    let mut acceptor = Acceptor::default();
-    acceptor
-        .read_tls(&mut buf.as_slice())
+    server
+        .write_tls(&mut io::sink())
         .unwrap();
-    let accepted = acceptor.accept().unwrap().unwrap();
-    let ch = accepted.client_hello();
-    assert_eq!(ch.server_name(), Some("localhost"));
+    assert_eq!(pacer.stalls.load(Ordering::SeqCst), 0);
 
-    let server = accepted
-        .into_connection(server_config)
+    server
+        .writer()
+        .write_all(b"hello")
         .unwrap();
-    assert!(server.wants_write());
-
-    // Reusing an acceptor is not allowed
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::WouldBlock,
+        after: 0,
+    };
+    assert!(server.write_tls(&mut pipe).is_err());
+    assert_eq!(pacer.stalls.load(Ordering::SeqCst), 1);
     assert_eq!(
-        acceptor
-            .read_tls(&mut [0u8].as_ref())
-            .err()
-            .unwrap()
-            .kind(),
-        io::ErrorKind::Other,
+        server
+            .writer()
+            .write(b"0123456789")
+            .unwrap(),
+        10
     );
+}
+
+#[test]
+fn test_injected_client_hello_is_processed() {
+    use rustls::internal::injection::{inject_message, record_sent_messages, take_sent_messages};
+    use rustls::internal::msgs::codec::Reader;
+    use rustls::internal::msgs::message::{Message, MessagePayload, OpaqueMessage};
+
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+    let client_hello = OpaqueMessage::read(&mut Reader::init(&buf))
+        .unwrap()
+        .into_plain_message();
+
+    record_sent_messages(&mut server);
+    inject_message(&mut server, client_hello).unwrap();
+
+    let sent: Vec<_> = take_sent_messages(&mut server)
+        .into_iter()
+        .map(|m| Message::try_from(m).unwrap())
+        .filter_map(|m| match m.payload {
+            MessagePayload::Handshake { parsed, .. } => Some(parsed.typ),
+            _ => None,
+        })
+        .collect();
     assert_eq!(
-        acceptor.accept().err(),
-        Some(Error::General("Acceptor polled after completion".into()))
+        sent,
+        vec![
+            HandshakeType::ServerHello,
+            HandshakeType::EncryptedExtensions,
+            HandshakeType::Certificate,
+            HandshakeType::CertificateVerify,
+            HandshakeType::Finished,
+        ]
     );
+    assert!(take_sent_messages(&mut server).is_empty());
+}
 
-    let mut acceptor = Acceptor::default();
-    assert!(acceptor.accept().unwrap().is_none());
-    acceptor
-        .read_tls(&mut &buf[..3])
-        .unwrap(); // incomplete message
-    assert!(acceptor.accept().unwrap().is_none());
-    acceptor
-        .read_tls(&mut [0x80, 0x00].as_ref())
-        .unwrap(); // invalid message (len = 32k bytes)
-    assert!(acceptor.accept().is_err());
+#[test]
+fn test_injected_unexpected_message_is_rejected() {
+    use rustls::internal::injection::{inject_message, record_sent_messages, take_sent_messages};
+    use rustls::internal::msgs::enums::AlertLevel;
+    use rustls::internal::msgs::message::{Message, MessagePayload, PlainMessage};
+    use rustls::ContentType;
 
-    let mut acceptor = Acceptor::default();
-    // Minimal valid 1-byte application data message is not a handshake message
-    acceptor
-        .read_tls(&mut [0x17, 0x03, 0x03, 0x00, 0x01, 0x00].as_ref())
-        .unwrap();
-    assert!(acceptor.accept().is_err());
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+    record_sent_messages(&mut client);
 
-    let mut acceptor = Acceptor::default();
-    // Minimal 1-byte ClientHello message is not a legal handshake message
-    acceptor
-        .read_tls(&mut [0x16, 0x03, 0x03, 0x00, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00].as_ref())
-        .unwrap();
-    assert!(acceptor.accept().is_err());
+    let bogus = PlainMessage {
+        typ: ContentType::Handshake,
+        version: ProtocolVersion::TLSv1_3,
+        payload: Payload::new(vec![HandshakeType::ServerHello.get_u8(), 0, 0, 0]),
+    };
+    let err = inject_message(&mut client, bogus.clone()).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::InappropriateHandshakeMessage { .. } | Error::InvalidMessage(_)
+    ));
+    assert_eq!(inject_message(&mut client, bogus).unwrap_err(), err);
+
+    let sent = take_sent_messages(&mut client);
+    assert_eq!(sent.len(), 1);
+    match Message::try_from(sent[0].clone())
+        .unwrap()
+        .payload
+    {
+        MessagePayload::Alert(alert) => assert_eq!(alert.level, AlertLevel::Fatal),
+        other => panic!("unexpected message {:?}", other),
+    }
 }
 
-#[derive(Default, Debug)]
-struct LogCounts {
-    trace: usize,
-    debug: usize,
-    info: usize,
-    warn: usize,
-    error: usize,
+#[cfg(feature = "tls12")]
+fn sent_alerts(sent: Vec<rustls::internal::msgs::message::PlainMessage>) -> Vec<(u8, u8)> {
+    sent.into_iter()
+        .filter(|m| m.typ == rustls::ContentType::Alert)
+        .map(|m| (m.payload.0[0], m.payload.0[1]))
+        .collect()
 }
 
-impl LogCounts {
-    fn new() -> Self {
-        Self {
-            ..Default::default()
+#[cfg(feature = "tls12")]
+#[test]
+fn test_renegotiation_policy() {
+    use rustls::internal::injection::{inject_message, record_sent_messages, take_sent_messages};
+    use rustls::internal::msgs::message::PlainMessage;
+    use rustls::{AlertDescription, ContentType, RenegotiationPolicy};
+
+    let hello_request = PlainMessage {
+        typ: ContentType::Handshake,
+        version: ProtocolVersion::TLSv1_2,
+        payload: Payload::new(vec![HandshakeType::HelloRequest.get_u8(), 0, 0, 0]),
+    };
+    let client_hello = {
+        let (mut client, _) = make_pair(KeyType::Rsa);
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+        PlainMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(buf[5..].to_vec()),
         }
-    }
+    };
 
-    fn reset(&mut self) {
-        *self = Self::new();
+    let no_renegotiation = (1, AlertDescription::NoRenegotiation.get_u8());
+    let handshake_failure = (2, AlertDescription::HandshakeFailure.get_u8());
+    for (policy, expected_alerts) in [
+        (RenegotiationPolicy::Ignore, vec![]),
+        (RenegotiationPolicy::Reject, vec![no_renegotiation]),
+        (RenegotiationPolicy::Close, vec![handshake_failure]),
+    ] {
+        let expected_result = match policy {
+            RenegotiationPolicy::Close => Err(Error::PeerIncompatible(
+                PeerIncompatible::RenegotiationRequested,
+            )),
+            _ => Ok(()),
+        };
+
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+        client_config.renegotiation = policy;
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.renegotiation = policy;
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        record_sent_messages(&mut client);
+        assert_eq!(
+            inject_message(&mut client, hello_request.clone()).map(|_| ()),
+            expected_result
+        );
+        assert_eq!(
+            sent_alerts(take_sent_messages(&mut client)),
+            expected_alerts
+        );
+
+        record_sent_messages(&mut server);
+        assert_eq!(
+            inject_message(&mut server, client_hello.clone()).map(|_| ()),
+            expected_result
+        );
+        assert_eq!(
+            sent_alerts(take_sent_messages(&mut server)),
+            expected_alerts
+        );
     }
+}
 
-    fn add(&mut self, level: log::Level) {
-        match level {
-            log::Level::Trace => self.trace += 1,
-            log::Level::Debug => self.debug += 1,
-            log::Level::Info => self.info += 1,
-            log::Level::Warn => self.warn += 1,
-            log::Level::Error => self.error += 1,
-        }
+#[test]
+fn test_secure_renegotiation_is_reported() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert_eq!(client.secure_renegotiation(), None);
+        assert_eq!(server.secure_renegotiation(), None);
+
+        do_handshake(&mut client, &mut server);
+        let expected = match version.version {
+            ProtocolVersion::TLSv1_3 => None,
+            _ => Some(true),
+        };
+        assert_eq!(client.secure_renegotiation(), expected);
+        assert_eq!(server.secure_renegotiation(), expected);
     }
 }
 
-thread_local!(static COUNTS: RefCell<LogCounts> = RefCell::new(LogCounts::new()));
+#[cfg(feature = "tls12")]
+#[test]
+fn test_fallback_scsv_rejected_when_higher_version_available() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.send_fallback_scsv = true;
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
 
-struct CountingLogger;
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerMisbehaved(
+            PeerMisbehaved::InappropriateFallback
+        )))
+    );
+    transfer(&mut server, &mut client);
+    assert_eq!(
+        client.process_new_packets(),
+        Err(Error::AlertReceived(
+            rustls::AlertDescription::InappropriateFallback
+        ))
+    );
+}
 
-static LOGGER: CountingLogger = CountingLogger;
+#[cfg(feature = "tls12")]
+#[test]
+fn test_fallback_scsv_accepted_at_highest_version() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.send_fallback_scsv = true;
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config.clone(), make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
 
-impl CountingLogger {
-    fn install() {
-        log::set_logger(&LOGGER).unwrap();
-        log::set_max_level(log::LevelFilter::Trace);
+    let server_config = make_server_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_2));
+}
+
+/// Completes a handshake, returning the content types of the records
+/// sent by the client and server respectively.
+fn handshake_record_types(
+    client: &mut ClientConnection,
+    server: &mut ServerConnection,
+) -> (Vec<u8>, Vec<u8>) {
+    fn pump(
+        from: &mut ConnectionCommon<impl SideData>,
+        to: &mut ConnectionCommon<impl SideData>,
+        types: &mut Vec<u8>,
+    ) {
+        let mut buf = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buf).unwrap();
+        }
+        let mut rest = &buf[..];
+        while !rest.is_empty() {
+            types.push(rest[0]);
+            let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            rest = &rest[5 + len..];
+        }
+        let mut rest = &buf[..];
+        while !rest.is_empty() {
+            to.read_tls(&mut rest).unwrap();
+        }
+        to.process_new_packets().unwrap();
     }
 
-    fn reset() {
-        COUNTS.with(|c| {
-            c.borrow_mut().reset();
-        });
+    let (mut client_types, mut server_types) = (Vec::new(), Vec::new());
+    while client.is_handshaking() || server.is_handshaking() {
+        pump(client, server, &mut client_types);
+        pump(server, client, &mut server_types);
     }
+    (client_types, server_types)
 }
 
-impl log::Log for CountingLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
-    }
+#[test]
+fn test_middlebox_compat_default() {
+    const CCS: u8 = 0x14;
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    assert_eq!(hello[43], 32, "session id length");
+    server
+        .read_tls(&mut &hello[..])
+        .unwrap();
+    server.process_new_packets().unwrap();
 
-    fn log(&self, record: &log::Record) {
-        println!("logging at {:?}: {:?}", record.level(), record.args());
+    let (client_types, server_types) = handshake_record_types(&mut client, &mut server);
+    assert!(client_types.contains(&CCS));
+    assert!(server_types.contains(&CCS));
+}
 
-        COUNTS.with(|c| {
-            c.borrow_mut().add(record.level());
-        });
+#[test]
+fn test_middlebox_compat_disabled() {
+    const CCS: u8 = 0x14;
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.enable_middlebox_compat = false;
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.enable_middlebox_compat = false;
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    assert_eq!(hello[43], 0, "session id length");
+    server
+        .read_tls(&mut &hello[..])
+        .unwrap();
+    server.process_new_packets().unwrap();
+
+    let (client_types, server_types) = handshake_record_types(&mut client, &mut server);
+    assert!(!client_types.contains(&CCS));
+    assert!(!server_types.contains(&CCS));
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+}
+
+#[test]
+fn test_middlebox_compat_disabled_on_one_side() {
+    for client_compat in [true, false] {
+        let mut client_config = make_client_config(KeyType::Rsa);
+        client_config.enable_middlebox_compat = client_compat;
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.enable_middlebox_compat = !client_compat;
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
     }
+}
 
-    fn flush(&self) {}
+/// Delivers the client's first flight to the server, returning the
+/// length of the `ClientHello` it contains.
+fn deliver_client_hello(client: &mut ClientConnection, server: &mut ServerConnection) -> usize {
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+    let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    assert_eq!(buf.len(), 5 + len);
+    server.read_tls(&mut &buf[..]).unwrap();
+    server.process_new_packets().unwrap();
+    len
 }
 
 #[test]
-fn test_no_warning_logging_during_successful_sessions() {
-    CountingLogger::install();
-    CountingLogger::reset();
+fn test_client_hello_padding() {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let padded_pair = |target| {
+        let mut client_config = make_client_config(KeyType::Rsa);
+        client_config.pad_client_hello_to = target;
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config)
+    };
 
-    for kt in ALL_KEY_TYPES.iter() {
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_configs(client_config, make_server_config(*kt));
-            do_handshake(&mut client, &mut server);
-        }
-    }
+    let (mut client, mut server) = padded_pair(None);
+    let unpadded = deliver_client_hello(&mut client, &mut server);
 
-    if cfg!(feature = "logging") {
-        COUNTS.with(|c| {
-            println!("After tests: {:?}", c.borrow());
-            assert_eq!(c.borrow().warn, 0);
-            assert_eq!(c.borrow().error, 0);
-            assert_eq!(c.borrow().info, 0);
-            assert!(c.borrow().trace > 0);
-            assert!(c.borrow().debug > 0);
-        });
-    } else {
-        COUNTS.with(|c| {
-            println!("After tests: {:?}", c.borrow());
-            assert_eq!(c.borrow().warn, 0);
-            assert_eq!(c.borrow().error, 0);
-            assert_eq!(c.borrow().info, 0);
-            assert_eq!(c.borrow().trace, 0);
-            assert_eq!(c.borrow().debug, 0);
-        });
+    for (target, expected) in [
+        (1024, 1024),
+        (unpadded + 4, unpadded + 4),
+        (unpadded + 3, unpadded + 4),
+        (unpadded + 1, unpadded + 4),
+        (unpadded, unpadded),
+        (64, unpadded),
+    ] {
+        let (mut client, mut server) = padded_pair(Some(target));
+        assert_eq!(
+            deliver_client_hello(&mut client, &mut server),
+            expected,
+            "target {}",
+            target
+        );
+        do_handshake(&mut client, &mut server);
     }
 }
 
-/// Test that secrets can be extracted and used for encryption/decryption.
-#[cfg(feature = "secret_extraction")]
 #[test]
-fn test_secret_extraction_enabled() {
-    // Normally, secret extraction would be used to configure kTLS (TLS offload
-    // to the kernel). We want this test to run on any platform, though, so
-    // instead we just compare secrets for equality.
-
-    // TLS 1.2 and 1.3 have different mechanisms for key exchange and handshake,
-    // and secrets are stored/extracted differently, so we want to test them both.
-    // We support 3 different AEAD algorithms (AES-128-GCM mode, AES-256-GCM, and
-    // Chacha20Poly1305), so that's 2*3 = 6 combinations to test.
+fn test_client_hello_padding_with_resumption() {
     let kt = KeyType::Rsa;
-    for suite in [
-        rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
-        rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
-        rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
-        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
-        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-    ] {
-        let version = suite.version();
-        println!("Testing suite {:?}", suite.suite().as_str());
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.pad_client_hello_to = Some(1024);
+    let client_config = Arc::new(client_config);
 
-        // Only offer the cipher suite (and protocol version) that we're testing
-        let mut server_config = ServerConfig::<Ring>::builder()
-            .with_cipher_suites(&[suite])
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(&[version])
-            .unwrap()
-            .with_no_client_auth()
-            .with_single_cert(kt.get_chain(), kt.get_key())
-            .unwrap();
-        // Opt into secret extraction from both sides
-        server_config.enable_secret_extraction = true;
-        let server_config = Arc::new(server_config);
+    let mut server_config = make_server_config(kt);
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
 
-        let mut client_config = make_client_config(kt);
-        client_config.enable_secret_extraction = true;
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(storage.takes(), 0);
 
-        let (mut client, mut server) =
-            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    // The PSK binder covers the padding, so must be computed after it is added.
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(deliver_client_hello(&mut client, &mut server), 1024);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(storage.takes(), 1);
+}
 
-        do_handshake(&mut client, &mut server);
+#[test]
+fn test_client_hello_spanning_records() {
+    // (padded hello length, client's max_fragment_size)
+    for (pad, fragment) in [(40_000, None), (60_000, None), (1_000, Some(64))] {
+        let mut client_config = make_client_config(KeyType::Rsa);
+        client_config.pad_client_hello_to = Some(pad);
+        client_config.max_fragment_size = fragment;
+        let client_config = Arc::new(client_config);
 
-        // The handshake is finished, we're now able to extract traffic secrets
-        let client_secrets = client.extract_secrets().unwrap();
-        let server_secrets = server.extract_secrets().unwrap();
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
 
-        // Comparing secrets for equality is something you should never have to
-        // do in production code, so ConnectionTrafficSecrets doesn't implement
-        // PartialEq/Eq on purpose. Instead, we have to get creative.
-        fn explode_secrets(s: &ConnectionTrafficSecrets) -> (&[u8], &[u8], &[u8]) {
-            match s {
-                ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv } => (key, salt, iv),
-                ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv } => (key, salt, iv),
-                ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => (key, &[], iv),
-                _ => panic!("unexpected secret type"),
-            }
-        }
+        let mut client =
+            ClientConnection::new(Arc::clone(&client_config), server_name("localhost")).unwrap();
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+        assert!(buf.len() > pad);
 
-        fn assert_secrets_equal(
-            (l_seq, l_sec): (u64, ConnectionTrafficSecrets),
-            (r_seq, r_sec): (u64, ConnectionTrafficSecrets),
-        ) {
-            assert_eq!(l_seq, r_seq);
-            assert_eq!(explode_secrets(&l_sec), explode_secrets(&r_sec));
+        let mut acceptor = rustls::server::Acceptor::default();
+        let mut rd = &buf[..];
+        while !rd.is_empty() {
+            acceptor.read_tls(&mut rd).unwrap();
         }
-
-        assert_secrets_equal(client_secrets.tx, server_secrets.rx);
-        assert_secrets_equal(client_secrets.rx, server_secrets.tx);
+        let accepted = acceptor.accept().unwrap().unwrap();
+        let mut server = accepted
+            .into_connection(Arc::clone(&server_config))
+            .unwrap();
+        do_handshake(&mut client, &mut server);
     }
 }
 
-/// Test that secrets cannot be extracted unless explicitly enabled, and until
-/// the handshake is done.
-#[cfg(feature = "secret_extraction")]
 #[test]
-fn test_secret_extraction_disabled_or_too_early() {
-    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
-    let kt = KeyType::Rsa;
+fn test_client_hello_too_large() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.pad_client_hello_to = Some(0x10000 + 4);
+    let err = ClientConnection::new(Arc::new(client_config), server_name("localhost")).unwrap_err();
+    assert_eq!(
+        err,
+        Error::General("ClientHello exceeds the maximum handshake message size".into())
+    );
+}
 
-    for (server_enable, client_enable) in [(true, false), (false, true)] {
-        let mut server_config = ServerConfig::<Ring>::builder()
-            .with_cipher_suites(&[suite])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap()
-            .with_no_client_auth()
-            .with_single_cert(kt.get_chain(), kt.get_key())
-            .unwrap();
-        server_config.enable_secret_extraction = server_enable;
-        let server_config = Arc::new(server_config);
+#[test]
+fn transcript_hash_comes_from_provider() {
+    use rustls::crypto::hash::{Hash, HashAlgorithm};
 
-        let mut client_config = make_client_config(kt);
-        client_config.enable_secret_extraction = client_enable;
+    static SHA256_USES: AtomicUsize = AtomicUsize::new(0);
 
-        let client_config = Arc::new(client_config);
+    /// A provider which only offers SHA256, and counts how often it is used.
+    #[derive(Debug)]
+    struct Sha256Only;
 
-        let (client, server) = make_pair_for_arc_configs(&client_config, &server_config);
+    impl CryptoProvider for Sha256Only {
+        type KeyExchange = <Ring as CryptoProvider>::KeyExchange;
 
-        assert!(
-            client.extract_secrets().is_err(),
-            "extraction should fail until handshake completes"
-        );
-        assert!(
-            server.extract_secrets().is_err(),
-            "extraction should fail until handshake completes"
-        );
+        fn fill_random(buf: &mut [u8]) -> Result<(), rustls::crypto::GetRandomFailed> {
+            Ring::fill_random(buf)
+        }
 
-        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        fn transcript_hash(algorithm: HashAlgorithm) -> Option<&'static dyn Hash> {
+            match algorithm {
+                HashAlgorithm::SHA256 => {
+                    SHA256_USES.fetch_add(1, Ordering::SeqCst);
+                    Ring::transcript_hash(algorithm)
+                }
+                _ => None,
+            }
+        }
 
-        do_handshake(&mut client, &mut server);
+        fn hmac(algorithm: HashAlgorithm) -> Option<&'static dyn rustls::crypto::hmac::Hmac> {
+            Ring::hmac(algorithm)
+        }
 
-        assert_eq!(server_enable, server.extract_secrets().is_ok());
-        assert_eq!(client_enable, client.extract_secrets().is_ok());
+        fn ticket_encrypter(
+        ) -> Result<Box<dyn rustls::server::ProducesTickets>, rustls::crypto::GetRandomFailed>
+        {
+            Ring::ticket_encrypter()
+        }
     }
-}
 
-#[test]
-fn test_received_plaintext_backpressure() {
-    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
     let kt = KeyType::Rsa;
+    let client_config = Arc::new(finish_client_config(
+        kt,
+        ClientConfig::<Sha256Only>::builder().with_safe_defaults(),
+    ));
 
-    let server_config = Arc::new(
+    let server_config = finish_server_config(
+        kt,
         ServerConfig::<Ring>::builder()
-            .with_cipher_suites(&[suite])
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
             .with_safe_default_kx_groups()
             .with_safe_default_protocol_versions()
-            .unwrap()
-            .with_no_client_auth()
-            .with_single_cert(kt.get_chain(), kt.get_key())
             .unwrap(),
     );
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&client_config, &Arc::new(server_config));
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::General(
+            "crypto provider has no SHA384 transcript hash".into()
+        )))
+    );
 
-    let client_config = Arc::new(make_client_config(kt));
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let server_config = finish_server_config(
+        kt,
+        ServerConfig::<Ring>::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_128_GCM_SHA256])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&client_config, &Arc::new(server_config));
+    let before = SHA256_USES.load(Ordering::SeqCst);
     do_handshake(&mut client, &mut server);
+    assert!(SHA256_USES.load(Ordering::SeqCst) > before);
+}
 
-    // Fill the server's received plaintext buffer with 16k bytes
-    let client_buf = [0; 16_385];
-    dbg!(client
-        .writer()
-        .write(&client_buf)
-        .unwrap());
-    let mut network_buf = Vec::with_capacity(32_768);
-    let sent = dbg!(client
-        .write_tls(&mut network_buf)
-        .unwrap());
-    let mut read = 0;
-    while read < sent {
-        let new = dbg!(server
-            .read_tls(&mut &network_buf[read..sent])
-            .unwrap());
-        if new == 4096 {
-            read += new;
-        } else {
-            break;
+#[test]
+fn key_derivation_comes_from_provider() {
+    use rustls::crypto::hash::{Hash, HashAlgorithm};
+    use rustls::crypto::hmac::Hmac;
+    use rustls::server::ProducesTickets;
+
+    /// A provider which only offers HMAC-SHA256.
+    #[derive(Debug)]
+    struct HmacSha256Only;
+
+    impl CryptoProvider for HmacSha256Only {
+        type KeyExchange = <Ring as CryptoProvider>::KeyExchange;
+
+        fn fill_random(buf: &mut [u8]) -> Result<(), rustls::crypto::GetRandomFailed> {
+            Ring::fill_random(buf)
         }
-    }
-    server.process_new_packets().unwrap();
 
-    // Send two more bytes from client to server
-    dbg!(client
-        .writer()
-        .write(&client_buf[..2])
-        .unwrap());
-    let sent = dbg!(client
-        .write_tls(&mut network_buf)
-        .unwrap());
+        fn transcript_hash(algorithm: HashAlgorithm) -> Option<&'static dyn Hash> {
+            Ring::transcript_hash(algorithm)
+        }
 
-    // Get an error because the received plaintext buffer is full
-    assert!(server
-        .read_tls(&mut &network_buf[..sent])
-        .is_err());
+        fn hmac(algorithm: HashAlgorithm) -> Option<&'static dyn Hmac> {
+            match algorithm {
+                HashAlgorithm::SHA256 => Ring::hmac(algorithm),
+                _ => None,
+            }
+        }
 
-    // Read out some of the plaintext
-    server
-        .reader()
-        .read_exact(&mut [0; 2])
-        .unwrap();
+        fn ticket_encrypter() -> Result<Box<dyn ProducesTickets>, rustls::crypto::GetRandomFailed> {
+            Ring::ticket_encrypter()
+        }
+    }
 
-    // Now there's room again in the plaintext buffer
-    assert_eq!(
-        server
-            .read_tls(&mut &network_buf[..sent])
-            .unwrap(),
-        24
-    );
-}
+    let server_config_for = |suite| {
+        finish_server_config(
+            KeyType::Rsa,
+            ServerConfig::<Ring>::builder()
+                .with_cipher_suites(&[suite])
+                .with_safe_default_kx_groups()
+                .with_safe_default_protocol_versions()
+                .unwrap(),
+        )
+    };
+    let client_config = Arc::new(finish_client_config(
+        KeyType::Rsa,
+        ClientConfig::<HmacSha256Only>::builder().with_safe_defaults(),
+    ));
 
-#[test]
-fn test_debug_server_name_from_ip() {
-    assert_eq!(
-        format!(
+    let suites = [
+        rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+        #[cfg(feature = "tls12")]
+        rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    ];
+    for suite in suites {
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&client_config, &Arc::new(server_config_for(suite)));
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::General(
+                "crypto provider has no HMAC-SHA384".into()
+            ))),
             "{:?}",
-            rustls::ServerName::IpAddress("127.0.0.1".parse().unwrap())
-        ),
-        "IpAddress(127.0.0.1)"
-    )
-}
+            suite
+        );
+    }
 
-#[test]
-fn test_debug_server_name_from_string() {
+    let (mut client, mut server) = make_pair_for_arc_configs(
+        &client_config,
+        &Arc::new(server_config_for(
+            rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+        )),
+    );
+    do_handshake(&mut client, &mut server);
+
+    let server_config = Arc::new(finish_server_config(
+        KeyType::Rsa,
+        ServerConfig::<HmacSha256Only>::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    ));
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(make_client_config(KeyType::Rsa)), &server_config);
     assert_eq!(
-        format!("{:?}", rustls::ServerName::try_from("a.com").unwrap()),
-        "DnsName(\"a.com\")"
-    )
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::General(
+            "crypto provider has no HMAC-SHA384".into()
+        )))
+    );
 }
@@ -0,0 +1,84 @@
+//! Tests for driving connections with [`rustls::async_io::TlsStream`].
+
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use crate::common::{
+    make_client_config, make_pair, make_pair_for_configs, make_server_config, KeyType,
+    ALL_KEY_TYPES,
+};
+use rustls::async_io::TlsStream;
+
+use std::future::Future;
+use std::io;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+#[test]
+fn tokio_stream_exchanges_data() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, server) = make_pair(*kt);
+        // A small transport buffer, so that reads and writes return
+        // `Poll::Pending` part way through.
+        let (client_io, server_io) = duplex(64);
+
+        block_on(async move {
+            let server = tokio::spawn(async move {
+                let mut server = TlsStream::new(server, server_io);
+                let mut request = [0u8; 5];
+                server.read_exact(&mut request).await?;
+                server
+                    .write_all(&[b'x'; 10_000])
+                    .await?;
+                server.shutdown().await?;
+                Ok::<_, io::Error>(request)
+            });
+
+            let mut client = TlsStream::new(client, client_io);
+            client.flush().await.unwrap();
+            assert!(!client.get_ref().is_handshaking());
+
+            client
+                .write_all(b"hello")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            client
+                .read_to_end(&mut response)
+                .await
+                .unwrap();
+
+            assert_eq!(&server.await.unwrap().unwrap(), b"hello");
+            assert_eq!(response, vec![b'x'; 10_000]);
+        });
+    }
+}
+
+#[test]
+fn tokio_stream_reports_handshake_failure() {
+    let client_config = make_client_config(KeyType::Rsa);
+    let server_config = make_server_config(KeyType::Ecdsa);
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (client_io, server_io) = duplex(4096);
+
+    block_on(async move {
+        let server = tokio::spawn(async move {
+            let mut server = TlsStream::new(server, server_io);
+            server.flush().await
+        });
+
+        let mut client = TlsStream::new(client, client_io);
+        let err = client.flush().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        drop(client);
+
+        assert!(server.await.unwrap().is_err());
+    });
+}
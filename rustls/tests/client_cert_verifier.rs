@@ -7,12 +7,16 @@ mod common;
 use crate::common::{
     do_handshake_until_both_error, do_handshake_until_error, get_client_root_store,
     make_client_config_with_versions, make_client_config_with_versions_with_auth,
-    make_pair_for_arc_configs, server_name, ErrorFromPeer, KeyType, ALL_KEY_TYPES,
+    make_pair_for_arc_configs, make_server_config, server_name, ErrorFromPeer, KeyType,
+    ALL_KEY_TYPES,
 };
 use rustls::client::WebPkiVerifier;
 use rustls::crypto::ring::Ring;
 use rustls::internal::msgs::handshake::DistinguishedName;
-use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{
+    AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier, ClientHello,
+    ResolvesClientCertVerifier,
+};
 use rustls::{
     AlertDescription, Certificate, ClientConnection, Error, InvalidMessage, ServerConfig,
     ServerConnection, SignatureScheme,
@@ -175,6 +179,56 @@ fn client_verifier_fails_properly() {
     }
 }
 
+struct ClientAuthBySni {
+    mandatory_for: &'static str,
+    verifier: Arc<dyn ClientCertVerifier>,
+}
+
+impl ResolvesClientCertVerifier for ClientAuthBySni {
+    fn resolve(&self, client_hello: &ClientHello) -> Option<Arc<dyn ClientCertVerifier>> {
+        match client_hello.server_name() {
+            Some(name) if name == self.mandatory_for => Some(Arc::clone(&self.verifier)),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn client_auth_can_be_chosen_per_server_name() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config(*kt);
+        server_config.client_auth_resolver = Some(Arc::new(ClientAuthBySni {
+            mandatory_for: "testserver.com",
+            verifier: AllowAnyAuthenticatedClient::new(get_client_root_store(*kt)).boxed(),
+        }));
+        let server_config = Arc::new(server_config);
+
+        for version in rustls::ALL_VERSIONS {
+            for (name, with_cert, expected) in [
+                ("localhost", false, Ok(false)),
+                ("localhost", true, Ok(false)),
+                ("testserver.com", true, Ok(true)),
+                (
+                    "testserver.com",
+                    false,
+                    Err(ErrorFromPeer::Server(Error::NoCertificatesPresented)),
+                ),
+            ] {
+                let client_config = match with_cert {
+                    true => make_client_config_with_versions_with_auth(*kt, &[version]),
+                    false => make_client_config_with_versions(*kt, &[version]),
+                };
+                let mut client =
+                    ClientConnection::new(Arc::new(client_config), server_name(name)).unwrap();
+                let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+                let result = do_handshake_until_error(&mut client, &mut server)
+                    .map(|_| server.peer_certificates().is_some());
+                assert_eq!(result, expected, "{} {:?}", name, version);
+            }
+        }
+    }
+}
+
 pub struct MockClientVerifier {
     pub verified: fn() -> Result<ClientCertVerified, Error>,
     pub subjects: Vec<DistinguishedName>,
@@ -54,6 +54,8 @@ embed_files! {
     (ECDSA_END_CHAIN, "ecdsa", "end.chain");
     (ECDSA_END_FULLCHAIN, "ecdsa", "end.fullchain");
     (ECDSA_END_KEY, "ecdsa", "end.key");
+    (ECDSA_END_OCSP, "ecdsa", "end.ocsp");
+    (ECDSA_END_REVOKED_OCSP, "ecdsa", "end.revoked.ocsp");
     (ECDSA_END_REQ, "ecdsa", "end.req");
     (ECDSA_INTER_CERT, "ecdsa", "inter.cert");
     (ECDSA_INTER_KEY, "ecdsa", "inter.key");
@@ -74,6 +76,8 @@ embed_files! {
     (EDDSA_END_CHAIN, "eddsa", "end.chain");
     (EDDSA_END_FULLCHAIN, "eddsa", "end.fullchain");
     (EDDSA_END_KEY, "eddsa", "end.key");
+    (EDDSA_END_OCSP, "eddsa", "end.ocsp");
+    (EDDSA_END_REVOKED_OCSP, "eddsa", "end.revoked.ocsp");
     (EDDSA_END_REQ, "eddsa", "end.req");
     (EDDSA_INTER_CERT, "eddsa", "inter.cert");
     (EDDSA_INTER_KEY, "eddsa", "inter.key");
@@ -92,10 +96,14 @@ embed_files! {
     (RSA_END_CERT, "rsa", "end.cert");
     (RSA_END_CHAIN, "rsa", "end.chain");
     (RSA_END_FULLCHAIN, "rsa", "end.fullchain");
+    (RSA_END_IMPOSTOR_OCSP, "rsa", "end.impostor.ocsp");
     (RSA_END_KEY, "rsa", "end.key");
+    (RSA_END_OCSP, "rsa", "end.ocsp");
+    (RSA_END_REVOKED_OCSP, "rsa", "end.revoked.ocsp");
     (RSA_END_REQ, "rsa", "end.req");
     (RSA_END_RSA, "rsa", "end.rsa");
     (RSA_INTER_CERT, "rsa", "inter.cert");
+    (RSA_INTER_IMPOSTOR_CERT, "rsa", "inter.impostor.cert");
     (RSA_INTER_KEY, "rsa", "inter.key");
     (RSA_INTER_REQ, "rsa", "inter.req");
 }
@@ -217,6 +225,17 @@ impl KeyType {
         )
     }
 
+    /// An OCSP response for the end-entity certificate, signed by its issuer.
+    pub fn get_ocsp_response(&self) -> Vec<u8> {
+        self.bytes_for("end.ocsp").to_vec()
+    }
+
+    /// Like `get_ocsp_response`, but saying the certificate is revoked.
+    pub fn get_revoked_ocsp_response(&self) -> Vec<u8> {
+        self.bytes_for("end.revoked.ocsp")
+            .to_vec()
+    }
+
     pub fn get_client_chain(&self) -> Vec<Certificate> {
         rustls_pemfile::certs(&mut io::BufReader::new(self.bytes_for("client.fullchain")))
             .unwrap()
@@ -4,15 +4,24 @@
 
 mod common;
 use crate::common::{
-    do_handshake, do_handshake_until_both_error, make_client_config_with_versions,
-    make_pair_for_arc_configs, make_server_config, ErrorFromPeer, ALL_KEY_TYPES,
+    do_handshake, do_handshake_until_both_error, do_handshake_until_error, get_client_root_store,
+    make_client_config_with_versions, make_pair_for_arc_configs, make_server_config, server_name,
+    ErrorFromPeer, KeyType, ALL_KEY_TYPES,
 };
 use rustls::client::{
-    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
+    ClientConfig, HandshakeSignatureValid, Resumption, ServerCertPolicy, ServerCertVerified,
+    ServerCertVerifier, ServerNamePolicy, Unverified, WebPkiVerifier,
 };
+use rustls::crypto::ring::Ring;
 use rustls::DigitallySignedStruct;
-use rustls::{AlertDescription, Certificate, Error, InvalidMessage, SignatureScheme};
+use rustls::{
+    AlertDescription, Certificate, CertificateError, CertificateRequirements, ClientConnection,
+    Error, InvalidMessage, NamedGroup, ServerConnection, ServerName, SignatureScheme,
+};
+use std::any::Any;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 #[test]
 fn client_can_override_certificate_verification() {
@@ -65,6 +74,43 @@ fn client_can_override_certificate_verification_and_reject_certificate() {
     }
 }
 
+#[test]
+fn client_certificate_verifier_receives_user_data() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let verifier = Arc::new(MockServerVerifier::expects_user_data("connection 1"));
+
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config
+                .dangerous()
+                .set_certificate_verifier(verifier.clone());
+            client_config.resumption = Resumption::disabled();
+            let client_config = Arc::new(client_config);
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            client.set_user_data(Box::new("connection 1"));
+            do_handshake(&mut client, &mut server);
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            client.set_user_data(Box::new("connection 2"));
+            let errs = do_handshake_until_both_error(&mut client, &mut server);
+            assert_eq!(
+                errs,
+                Err(vec![
+                    ErrorFromPeer::Client(Error::General(
+                        "unexpected user data Some(\"connection 2\")".into()
+                    )),
+                    ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::HandshakeFailure)),
+                ]),
+            );
+        }
+    }
+}
+
 #[cfg(feature = "tls12")]
 #[test]
 fn client_can_override_certificate_verification_and_reject_tls12_signatures() {
@@ -153,11 +199,384 @@ fn client_can_override_certificate_verification_and_offer_no_signature_schemes()
     }
 }
 
+fn handshake_with_verifier(
+    kt: KeyType,
+    verifier: WebPkiVerifier,
+    name: &'static str,
+) -> Result<(), ErrorFromPeer> {
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(verifier));
+    let mut client = ClientConnection::new(Arc::new(client_config), server_name(name)).unwrap();
+    let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+    do_handshake_until_error(&mut client, &mut server)
+}
+
+#[test]
+fn webpki_verifier_applies_server_name_policy() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let verifier = |policy| {
+            WebPkiVerifier::builder(get_client_root_store(*kt))
+                .with_server_name_policy(policy)
+                .build()
+        };
+
+        assert_eq!(
+            handshake_with_verifier(*kt, verifier(ServerNamePolicy::Verify), "example.com"),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                CertificateError::NotValidForName
+            )))
+        );
+        assert_eq!(
+            handshake_with_verifier(
+                *kt,
+                verifier(ServerNamePolicy::VerifyAnyOf(vec![
+                    server_name("example.com"),
+                    server_name("testserver.com"),
+                ])),
+                "example.com"
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            handshake_with_verifier(
+                *kt,
+                verifier(ServerNamePolicy::VerifyAnyOf(vec![server_name(
+                    "example.com"
+                )])),
+                "localhost"
+            ),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                CertificateError::NotValidForName
+            )))
+        );
+        assert_eq!(
+            handshake_with_verifier(*kt, verifier(ServerNamePolicy::Skip), "example.com"),
+            Ok(())
+        );
+    }
+}
+
+#[test]
+fn webpki_verifier_applies_spki_pins() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let chain = kt.get_chain();
+        let other_chain = match kt {
+            KeyType::Rsa => KeyType::Ecdsa,
+            _ => KeyType::Rsa,
+        }
+        .get_chain();
+        let pin = |cert: &Certificate| {
+            cert.details()
+                .unwrap()
+                .subject_public_key_info_sha256()
+        };
+
+        for (pins, accepted) in [
+            (vec![pin(&chain[0])], true),
+            (vec![[0u8; 32], pin(&chain[1])], true),
+            (vec![pin(&other_chain[0])], false),
+        ] {
+            let verifier = WebPkiVerifier::builder(get_client_root_store(*kt))
+                .with_spki_pins(pins)
+                .build();
+            let result = handshake_with_verifier(*kt, verifier, "localhost");
+            if accepted {
+                assert_eq!(result, Ok(()));
+            } else {
+                assert_eq!(
+                    result,
+                    Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                        CertificateError::ApplicationVerificationFailure
+                    )))
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn webpki_verifier_applies_certificate_requirements() {
+    let rejected = |error| Err(ErrorFromPeer::Client(Error::InvalidCertificate(error)));
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut requirements = CertificateRequirements::default();
+        requirements.reject_sha1_signatures = true;
+        requirements.min_rsa_modulus_bits = 2048;
+        requirements.ecdsa_curves = Some(vec![NamedGroup::secp256r1, NamedGroup::secp384r1]);
+        let mut require_eku = requirements.clone();
+        require_eku.require_extended_key_usage = true;
+        let mut require_rsa_3072 = requirements.clone();
+        require_rsa_3072.min_rsa_modulus_bits = 3072;
+        let mut require_p384 = requirements.clone();
+        require_p384.ecdsa_curves = Some(vec![NamedGroup::secp384r1]);
+
+        for (requirements, expected) in [
+            (requirements, Ok(())),
+            // the test server certificates have no extended key usage extension
+            (require_eku, rejected(CertificateError::InvalidPurpose)),
+            (
+                require_rsa_3072,
+                match kt {
+                    KeyType::Rsa => rejected(CertificateError::UnacceptableKey),
+                    _ => Ok(()),
+                },
+            ),
+            (
+                require_p384,
+                match kt {
+                    KeyType::Ecdsa => rejected(CertificateError::UnacceptableKey),
+                    _ => Ok(()),
+                },
+            ),
+        ] {
+            let verifier = WebPkiVerifier::builder(get_client_root_store(*kt))
+                .with_requirements(requirements)
+                .build();
+            assert_eq!(
+                handshake_with_verifier(*kt, verifier, "localhost"),
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn webpki_verifier_checks_crls() {
+    for kt in ALL_KEY_TYPES.iter() {
+        // the CRL revokes a client certificate, not the server's
+        let verifier = WebPkiVerifier::builder(get_client_root_store(*kt))
+            .with_crls([kt.client_crl()])
+            .unwrap()
+            .build();
+        assert_eq!(handshake_with_verifier(*kt, verifier, "localhost"), Ok(()));
+    }
+
+    assert!(WebPkiVerifier::builder(get_client_root_store(KeyType::Rsa))
+        .with_crls([rustls::server::UnparsedCertRevocationList(vec![0x30, 0x00])])
+        .is_err());
+}
+
+#[derive(Default)]
+struct CountingPolicy {
+    checked: AtomicUsize,
+    reject: bool,
+}
+
+impl ServerCertPolicy for CountingPolicy {
+    fn check(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<(), Error> {
+        assert_eq!(name, &server_name("localhost"));
+        self.checked
+            .fetch_add(1, Ordering::SeqCst);
+        match self.reject {
+            true => Err(Error::General("rejected by policy".into())),
+            false => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn webpki_verifier_applies_policies_in_order() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let first = Arc::new(CountingPolicy::default());
+        let second = Arc::new(CountingPolicy {
+            reject: true,
+            ..Default::default()
+        });
+        let third = Arc::new(CountingPolicy::default());
+
+        let verifier = WebPkiVerifier::builder(get_client_root_store(*kt))
+            .with_policy(first.clone())
+            .with_policy(second.clone())
+            .with_policy(third.clone())
+            .build();
+        assert_eq!(
+            handshake_with_verifier(*kt, verifier, "localhost"),
+            Err(ErrorFromPeer::Client(Error::General(
+                "rejected by policy".into()
+            )))
+        );
+        assert_eq!(first.checked.load(Ordering::SeqCst), 1);
+        assert_eq!(second.checked.load(Ordering::SeqCst), 1);
+        assert_eq!(third.checked.load(Ordering::SeqCst), 0);
+
+        // policies are not consulted for certificates that fail path validation
+        let verifier = WebPkiVerifier::builder(get_client_root_store(*kt))
+            .with_policy(first.clone())
+            .build();
+        assert!(handshake_with_verifier(*kt, verifier, "example.com").is_err());
+        assert_eq!(first.checked.load(Ordering::SeqCst), 1);
+    }
+}
+
+/// Accepts any certificate, but rejects the chain as it arrives once it
+/// reaches the certificate at `reject_at`.
+struct PrecheckingVerifier {
+    reject_at: usize,
+    prechecked: AtomicUsize,
+}
+
+impl ServerCertVerifier for PrecheckingVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn precheck_certificate(&self, index: usize, _certificate: &Certificate) -> Result<(), Error> {
+        assert_eq!(
+            self.prechecked
+                .fetch_add(1, Ordering::SeqCst),
+            index
+        );
+        match index == self.reject_at {
+            true => Err(Error::General("rejected early".into())),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Feed the server's first flight to the client in small pieces, and
+/// return how many bytes it took before the client failed.
+fn bytes_until_precheck_fails(
+    version: &'static rustls::SupportedProtocolVersion,
+    reject_at: usize,
+) -> usize {
+    let kt = KeyType::Rsa;
+    let verifier = Arc::new(PrecheckingVerifier {
+        reject_at,
+        prechecked: AtomicUsize::new(0),
+    });
+    let mut client_config = make_client_config_with_versions(kt, &[version]);
+    client_config
+        .dangerous()
+        .set_certificate_verifier(verifier.clone());
+    let mut server_config = make_server_config(kt);
+    server_config.max_fragment_size = Some(256);
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &Arc::new(server_config));
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    server
+        .read_tls(&mut &hello[..])
+        .unwrap();
+    server.process_new_packets().unwrap();
+    let mut flight = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut flight).unwrap();
+    }
+
+    for (i, chunk) in flight.chunks(64).enumerate() {
+        client
+            .read_tls(&mut &chunk[..])
+            .unwrap();
+        if let Err(err) = client.process_new_packets() {
+            assert_eq!(err, Error::General("rejected early".into()));
+            assert_eq!(
+                verifier
+                    .prechecked
+                    .load(Ordering::SeqCst),
+                reject_at + 1
+            );
+            return (i + 1) * 64;
+        }
+    }
+    panic!("certificate was not rejected");
+}
+
+#[test]
+fn client_prechecks_certificates_as_they_arrive() {
+    for version in rustls::ALL_VERSIONS {
+        let first = bytes_until_precheck_fails(version, 0);
+        let last = bytes_until_precheck_fails(version, 2);
+        assert!(first < last, "{:?}: {} >= {}", version, first, last);
+    }
+}
+
+#[test]
+fn client_enforces_cert_chain_limits() {
+    for version in rustls::ALL_VERSIONS {
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config
+            .cert_chain_limits
+            .max_certificates = Some(2);
+        let server_config = Arc::new(make_server_config(kt));
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                CertificateError::ChainExceedsLimits
+            )))
+        );
+
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config
+            .cert_chain_limits
+            .max_certificates = Some(3);
+        client_config
+            .cert_chain_limits
+            .max_certificate_size = Some(4096);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn client_without_verifier_connects_to_any_server() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
+        for version in rustls::ALL_VERSIONS {
+            let client_config = ClientConfig::<Ring>::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap()
+                .with_dangerous_no_verifier()
+                .with_no_client_auth()
+                .into_shared();
+
+            let mut client: Unverified<ClientConnection> = client_config
+                .connect(server_name("not-the-server.example"))
+                .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            do_handshake(client.get_unverified_mut(), &mut server);
+            assert_eq!(
+                client
+                    .get_unverified()
+                    .peer_certificates(),
+                Some(&kt.get_chain()[..])
+            );
+
+            let client: ClientConnection = client.into_inner();
+            assert!(!client.is_handshaking());
+        }
+    }
+}
+
 pub struct MockServerVerifier {
     cert_rejection_error: Option<Error>,
     tls12_signature_error: Option<Error>,
     tls13_signature_error: Option<Error>,
     signature_schemes: Vec<SignatureScheme>,
+    expected_user_data: Option<&'static str>,
 }
 
 impl ServerCertVerifier for MockServerVerifier {
@@ -180,6 +599,27 @@ impl ServerCertVerifier for MockServerVerifier {
         }
     }
 
+    fn verify_server_cert_with_user_data(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::ServerName,
+        oscp_response: &[u8],
+        now: std::time::SystemTime,
+        user_data: Option<&(dyn Any + Send + Sync)>,
+    ) -> Result<ServerCertVerified, Error> {
+        if let Some(expected) = self.expected_user_data {
+            let user_data = user_data.and_then(|data| data.downcast_ref::<&'static str>());
+            if user_data != Some(&expected) {
+                return Err(Error::General(format!(
+                    "unexpected user data {:?}",
+                    user_data
+                )));
+            }
+        }
+        self.verify_server_cert(end_entity, intermediates, server_name, oscp_response, now)
+    }
+
     fn verify_tls12_signature(
         &self,
         message: &[u8],
@@ -227,6 +667,13 @@ impl MockServerVerifier {
         }
     }
 
+    pub fn expects_user_data(expected: &'static str) -> Self {
+        MockServerVerifier {
+            expected_user_data: Some(expected),
+            ..Default::default()
+        }
+    }
+
     pub fn rejects_certificate(err: Error) -> Self {
         MockServerVerifier {
             cert_rejection_error: Some(err),
@@ -263,6 +710,7 @@ impl Default for MockServerVerifier {
             tls12_signature_error: None,
             tls13_signature_error: None,
             signature_schemes: WebPkiVerifier::verification_schemes(),
+            expected_user_data: None,
         }
     }
 }
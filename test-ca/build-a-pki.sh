@@ -193,6 +193,44 @@ for kt in rsa ecdsa eddsa ; do
             -crldays 7 \
             -out $kt/client.revoked.crl.pem
 
+  # OCSP responses for the end-entity certificate, signed directly by its
+  # issuer, first while it is valid and then once it is revoked.
+  echo -n '' > index.txt
+  openssl ca \
+            -config ./crl-openssl.cnf \
+            -keyfile $kt/inter.key \
+            -cert $kt/inter.cert \
+            -valid $kt/end.cert
+
+  openssl ocsp \
+            -index index.txt \
+            -rsigner $kt/inter.cert \
+            -rkey $kt/inter.key \
+            -CA $kt/inter.cert \
+            -issuer $kt/inter.cert \
+            -cert $kt/end.cert \
+            -resp_no_certs \
+            -ndays 3650 \
+            -respout $kt/end.ocsp
+
+  openssl ca \
+            -config ./crl-openssl.cnf \
+            -keyfile $kt/inter.key \
+            -cert $kt/inter.cert \
+            -revoke $kt/end.cert \
+            -crl_reason keyCompromise
+
+  openssl ocsp \
+            -index index.txt \
+            -rsigner $kt/inter.cert \
+            -rkey $kt/inter.key \
+            -CA $kt/inter.cert \
+            -issuer $kt/inter.cert \
+            -cert $kt/end.cert \
+            -resp_no_certs \
+            -ndays 3650 \
+            -respout $kt/end.revoked.ocsp
+
   cat $kt/inter.cert $kt/ca.cert > $kt/end.chain
   cat $kt/end.cert $kt/inter.cert $kt/ca.cert > $kt/end.fullchain
 
@@ -202,6 +240,36 @@ for kt in rsa ecdsa eddsa ; do
   openssl asn1parse -in $kt/ca.cert -out $kt/ca.der > /dev/null
 done
 
+# An impostor with the same name as the RSA intermediate but a different
+# key, and an OCSP response for the end-entity certificate signed by it.
+openssl req -nodes \
+          -x509 \
+          -days 3650 \
+          -newkey rsa:3072 \
+          -keyout rsa/inter.impostor.key \
+          -out rsa/inter.impostor.cert \
+          -sha256 \
+          -batch \
+          -subj "/CN=ponytown RSA level 2 intermediate"
+
+echo -n '' > index.txt
+openssl ca \
+          -config ./crl-openssl.cnf \
+          -keyfile rsa/inter.impostor.key \
+          -cert rsa/inter.impostor.cert \
+          -valid rsa/end.cert
+
+openssl ocsp \
+          -index index.txt \
+          -rsigner rsa/inter.impostor.cert \
+          -rkey rsa/inter.impostor.key \
+          -CA rsa/inter.impostor.cert \
+          -issuer rsa/inter.impostor.cert \
+          -cert rsa/end.cert \
+          -resp_no_certs \
+          -ndays 3650 \
+          -respout rsa/end.impostor.ocsp
+
 # Tidy up openssl CA state.
 rm index.txt* || true
 rm crlnumber* || true